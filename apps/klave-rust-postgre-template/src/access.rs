@@ -0,0 +1,72 @@
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{audit, database::{self, ClientRole}, notify};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AccessInput {
+    pub database_id: String,
+    pub grantee_client_id: String,
+    pub role: ClientRole,
+}
+
+fn load_client(database_id: &str) -> Option<database::Client> {
+    match database::Client::load(database_id.to_string()) {
+        Ok(c) => Some(c),
+        Err(err) => {
+            notify::error_with_code(&format!("Failed to load client: {}", err), err.code());
+            None
+        }
+    }
+}
+
+fn access_result(client: &database::Client) -> serde_json::Value {
+    json!({ "database_id": client.database_id(), "owners": client.owners(), "readers": client.readers() })
+}
+
+// Shares a database with another identity: readers query it, owners also change it and its grants
+pub fn grant_db_access(cmd: String) {
+    let input: AccessInput = match notify::parse_input(&cmd) {
+        Some(input) => input,
+        None => return,
+    };
+    let mut client = match load_client(&input.database_id) {
+        Some(c) => c,
+        None => return,
+    };
+    let details = json!({ "grantee_client_id": input.grantee_client_id, "role": input.role });
+    match client.grant_access(&input.grantee_client_id, input.role) {
+        Ok(_) => {
+            audit::record("grant_db_access", Some(&input.database_id), "success", details);
+            notify::result(&access_result(&client));
+        },
+        Err(err) => {
+            audit::record("grant_db_access", Some(&input.database_id), "failure", details);
+            notify::error_with_code(&format!("Failed to grant access: {}", err), err.code());
+        }
+    }
+}
+
+// Takes a role back; a database always keeps one owner
+pub fn revoke_db_access(cmd: String) {
+    let input: AccessInput = match notify::parse_input(&cmd) {
+        Some(input) => input,
+        None => return,
+    };
+    let mut client = match load_client(&input.database_id) {
+        Some(c) => c,
+        None => return,
+    };
+    let details = json!({ "grantee_client_id": input.grantee_client_id, "role": input.role });
+    match client.revoke_access(&input.grantee_client_id, input.role) {
+        Ok(_) => {
+            audit::record("revoke_db_access", Some(&input.database_id), "success", details);
+            notify::result(&access_result(&client));
+        },
+        Err(err) => {
+            audit::record("revoke_db_access", Some(&input.database_id), "failure", details);
+            notify::error_with_code(&format!("Failed to revoke access: {}", err), err.code());
+        }
+    }
+}
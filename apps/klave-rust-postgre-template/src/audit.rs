@@ -0,0 +1,59 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{notify, storage, utils::{get_client_id, get_trusted_time}};
+
+pub(crate) const AUDIT_LOG_TABLE: &str = "AuditLogTable";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub id: String,
+    pub timestamp: u64,
+    pub sender: String,
+    pub route: String,
+    pub database_id: Option<String>,
+    pub outcome: String,
+    pub details: Value,
+}
+
+impl AuditEntry {
+    pub fn new(route: &str, database_id: Option<&str>, outcome: &str, details: Value) -> Self {
+        let timestamp = get_trusted_time();
+        let suffix = crate::runtime::random::get_random_bytes(8).map(hex::encode).unwrap_or_default();
+        Self {
+            // Zero-padded timestamp first so that ledger keys sort chronologically
+            id: format!("{:020}-{}", timestamp, suffix),
+            timestamp,
+            sender: get_client_id(),
+            route: route.to_string(),
+            database_id: database_id.map(|id| id.to_string()),
+            outcome: outcome.to_string(),
+            details,
+        }
+    }
+
+    pub fn save(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let serialized = serde_json::to_string(self)?;
+        storage::ledger_set(AUDIT_LOG_TABLE, &self.id, serialized.as_bytes())
+    }
+}
+
+// Every readable entry of the audit log, in no particular order
+pub fn load_entries() -> Result<Vec<AuditEntry>, Box<dyn std::error::Error>> {
+    let ledger = crate::runtime::ledger::get_table(AUDIT_LOG_TABLE);
+    let mut entries = Vec::new();
+    for key in ledger.list_keys()? {
+        if let Ok(entry) = serde_json::from_slice::<AuditEntry>(&ledger.get(&key)?) {
+            entries.push(entry);
+        }
+    }
+    Ok(entries)
+}
+
+// Records an audit entry; failures are reported but never abort the calling route.
+pub fn record(route: &str, database_id: Option<&str>, outcome: &str, details: Value) {
+    let entry = AuditEntry::new(route, database_id, outcome, details);
+    if let Err(err) = entry.save() {
+        notify::warning(&format!("Failed to write audit entry: {}", err));
+    }
+}
@@ -0,0 +1,181 @@
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::{audit, database::{Client, DatabaseError}, manifest::EncryptionManifest, notify, settings::AccessLevel, statement, utils::{get_trusted_time, is_plain_identifier}};
+
+// Plaintext timestamps maintained by the template on the rows it writes, database triggers cannot
+// compute anything from encrypted columns
+pub const CREATED_AT_COLUMN: &str = "_klave_created_at";
+pub const UPDATED_AT_COLUMN: &str = "_klave_updated_at";
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+const SECONDS_PER_DAY: u64 = 24 * 3600;
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ManageAuditColumnsInput {
+    pub database_id: String,
+    pub table: String,
+    // Timestamp given to existing rows, e.g. "2020-01-01 00:00:00+00"; the time of the call when absent
+    #[serde(default)]
+    pub created_at_default: Option<String>,
+    // Stops maintaining the columns and drops them
+    #[serde(default)]
+    pub remove: bool,
+}
+
+// UTC timestamp in the text form PostgreSQL reads into a timestamptz, microsecond precision
+pub fn format_timestamp(nanos: u64) -> String {
+    let seconds = nanos / NANOS_PER_SECOND;
+    let micros = (nanos % NANOS_PER_SECOND) / 1_000;
+    let (days, time) = (seconds / SECONDS_PER_DAY, seconds % SECONDS_PER_DAY);
+    // Civil date from days since 1970-01-01, after Howard Hinnant's days_from_civil inverse
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02}.{:06}+00", year, month, day, time / 3600, time % 3600 / 60, time % 60, micros)
+}
+
+// Called before columns are registered for encryption
+pub fn reject_encrypted(columns: &[String]) -> Result<(), String> {
+    match columns.iter().find(|c| c.as_str() == CREATED_AT_COLUMN || c.as_str() == UPDATED_AT_COLUMN) {
+        Some(column) => Err(format!("Column {} is maintained by manage_audit_columns and cannot be encrypted", column)),
+        None => Ok(()),
+    }
+}
+
+// Sets the updated timestamp of rows about to be written, and the created one unless the row carries it
+// (a restored row keeps its own). On conflict updates must leave CREATED_AT_COLUMN alone.
+pub fn stamp_rows(columns: &mut Vec<String>, rows: &mut [Vec<Value>], now: &str) {
+    for column in [CREATED_AT_COLUMN, UPDATED_AT_COLUMN] {
+        let index = match columns.iter().position(|c| c == column) {
+            Some(index) => index,
+            None => {
+                columns.push(column.to_string());
+                for row in rows.iter_mut() {
+                    row.push(Value::Null);
+                }
+                columns.len() - 1
+            }
+        };
+        for row in rows.iter_mut() {
+            if column == UPDATED_AT_COLUMN || row[index].is_null() {
+                row[index] = Value::String(now.to_string());
+            }
+        }
+    }
+}
+
+fn backfill_value(created_at_default: Option<&str>, now: u64) -> Result<String, String> {
+    let text = match created_at_default {
+        Some(text) => text.to_string(),
+        None => format_timestamp(now),
+    };
+    Ok(format!("{}::timestamptz", statement::parameter_literal(&Value::String(text)).map_err(|e| format!("created_at_default {}", e))?))
+}
+
+fn add_columns_sql(table: &str) -> String {
+    format!("ALTER TABLE {} ADD COLUMN IF NOT EXISTS {} timestamptz, ADD COLUMN IF NOT EXISTS {} timestamptz", table, CREATED_AT_COLUMN, UPDATED_AT_COLUMN)
+}
+
+fn backfill_sql(table: &str, value: &str) -> String {
+    format!("UPDATE {} SET {} = {} WHERE {} IS NULL", table, CREATED_AT_COLUMN, value, CREATED_AT_COLUMN)
+}
+
+fn drop_columns_sql(table: &str) -> String {
+    format!("ALTER TABLE {} DROP COLUMN IF EXISTS {}, DROP COLUMN IF EXISTS {}", table, CREATED_AT_COLUMN, UPDATED_AT_COLUMN)
+}
+
+// The flag is recorded once the columns exist and cleared before they are dropped, so that writes never
+// name a missing column. Returns the number of backfilled rows.
+fn apply(client: &Client, input: &ManageAuditColumnsInput) -> Result<u64, Box<dyn std::error::Error>> {
+    if !is_plain_identifier(&input.table) {
+        return Err(format!("Invalid input: table name '{}'", input.table).into());
+    }
+    if input.remove {
+        EncryptionManifest::update(client.database_id(), |manifest| {
+            manifest.set_audit_columns(&input.table, false);
+            Ok(())
+        })?;
+        client.execute_ddl(&drop_columns_sql(&input.table))?;
+        return Ok(0);
+    }
+    let value = backfill_value(input.created_at_default.as_deref(), get_trusted_time()).map_err(|e| format!("Invalid input: {}", e))?;
+    client.execute_ddl(&add_columns_sql(&input.table))?;
+    let backfilled = client.execute(&backfill_sql(&input.table, &value))?.rows_affected.unwrap_or(0);
+    EncryptionManifest::update(client.database_id(), |manifest| {
+        manifest.set_audit_columns(&input.table, true);
+        Ok(())
+    })?;
+    Ok(backfilled)
+}
+
+// Adds and backfills the audit columns of a table; rows written by the template keep them up to date from then on
+pub fn manage_audit_columns(cmd: String) {
+    let input: ManageAuditColumnsInput = match notify::parse_input(&cmd) {
+        Some(input) => input,
+        None => return,
+    };
+    let mut client = match Client::load(input.database_id.clone()) {
+        Ok(c) => c,
+        Err(err) => {
+            notify::error_with_code(&format!("Failed to load client: {}", err), err.code());
+            return;
+        }
+    };
+    if client.access() == AccessLevel::ReportingOnly {
+        notify::error("Reporting-only callers cannot change audit columns");
+        return;
+    }
+    if let Err(err) = client.connect() {
+        notify::error_with_code(&format!("Failed to connect to client: {}", err), err.code());
+        return;
+    }
+
+    match apply(&client, &input) {
+        Ok(backfilled) => {
+            audit::record("manage_audit_columns", Some(&input.database_id), "success", json!({ "table": input.table, "removed": input.remove, "backfilled": backfilled }));
+            notify::result(&json!({ "table": input.table, "managed": !input.remove, "columns": [CREATED_AT_COLUMN, UPDATED_AT_COLUMN], "backfilled": backfilled }));
+        },
+        Err(err) => {
+            audit::record("manage_audit_columns", Some(&input.database_id), "failure", json!({ "table": input.table, "removed": input.remove }));
+            notify::error_with_code(&format!("Failed to manage audit columns: {}", err), DatabaseError::code_of(err.as_ref()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_timestamp() {
+        assert_eq!(format_timestamp(0), "1970-01-01 00:00:00.000000+00");
+        assert_eq!(format_timestamp(951_782_400 * NANOS_PER_SECOND + 1_500), "2000-02-29 00:00:00.000001+00");
+        assert_eq!(format_timestamp(1_792_065_599 * NANOS_PER_SECOND + 250_000_000), "2026-10-15 11:59:59.250000+00");
+    }
+
+    #[test]
+    fn test_stamp_rows() {
+        let mut columns = vec!["id".to_string(), CREATED_AT_COLUMN.to_string()];
+        let mut rows = vec![vec![json!(1), json!("2020-01-01")], vec![json!(2), Value::Null]];
+        stamp_rows(&mut columns, &mut rows, "now");
+        assert_eq!(columns, vec!["id", CREATED_AT_COLUMN, UPDATED_AT_COLUMN]);
+        assert_eq!(rows, vec![vec![json!(1), json!("2020-01-01"), json!("now")], vec![json!(2), json!("now"), json!("now")]]);
+    }
+
+    #[test]
+    fn test_statements() {
+        assert_eq!(reject_encrypted(&["email".to_string(), UPDATED_AT_COLUMN.to_string()]).unwrap_err(),
+            "Column _klave_updated_at is maintained by manage_audit_columns and cannot be encrypted");
+        assert_eq!(backfill_value(Some("2020-01-01 O'Clock"), 0).unwrap(), "'2020-01-01 O''Clock'::timestamptz");
+        assert_eq!(backfill_value(None, 0).unwrap(), "'1970-01-01 00:00:00.000000+00'::timestamptz");
+        assert_eq!(backfill_sql("users", "now()"), "UPDATE users SET _klave_created_at = now() WHERE _klave_created_at IS NULL");
+    }
+}
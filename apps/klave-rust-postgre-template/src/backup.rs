@@ -0,0 +1,337 @@
+use crate::runtime::subtle::{decrypt, encrypt, AesGcmParams, CryptoKey, EncryptAlgorithm};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+
+use crate::{
+    audit,
+    crypto::{compute_sha256_hex_string, derive_backup_key, AES_GCM_IV_SIZE},
+    database::{Client, DatabaseError},
+    manifest::{EncryptionManifest, ROW_MAC_COLUMN},
+    notify,
+    primary_key::PrimaryKey,
+    settings::{AccessLevel, DeploymentSettings},
+    statement::{leading_keyword, write_target},
+    storage,
+    utils::{get_trusted_time, ledger_get, ledger_key, remove_legacy_key},
+};
+
+pub(crate) const ROW_BACKUP_TABLE: &str = "RowBackupTable";
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+// Rows a single statement may back up; larger writes are refused rather than run without a backup
+const MAX_BACKUP_ROWS: usize = 1_000;
+
+// Row as it was before an UPDATE or DELETE, sealed under a backup key derived from the master key
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RowBackup {
+    pub backup_id: String,
+    pub database_id: String,
+    pub table: String,
+    pub primary_key: PrimaryKey,
+    pub key: Value,
+    pub statement_hash: String,
+    pub created_at: u64,
+    pub expires_at: u64,
+    pub iv: String,
+    pub sealed: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BackupIndexEntry {
+    pub backup_id: String,
+    pub expires_at: u64,
+    pub size: u64,
+}
+
+// Backups of a client, kept next to them so that the quota and pruning do not scan the ledger table
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct BackupIndex {
+    pub entries: Vec<BackupIndexEntry>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RestoreRowBackupInput {
+    pub database_id: String,
+    pub backup_id: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PruneRowBackupsInput {
+    pub database_id: String,
+}
+
+// Ledger key of a backup: database, table, primary key and time, with the statement index telling apart
+// backups of the same row taken by one script
+pub fn backup_id(database_id: &str, table: &str, key: &Value, created_at: u64, statement_index: usize) -> String {
+    let key = match key {
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    };
+    ledger_key(&[database_id, table, &key, &format!("{:020}.{}", created_at, statement_index)])
+}
+
+fn index_key(database_id: &str) -> String {
+    ledger_key(&["index", database_id])
+}
+
+fn legacy_index_key(database_id: &str) -> String {
+    format!("index/{}", database_id)
+}
+
+fn backup_aad(database_id: &str, backup_id: &str) -> Vec<u8> {
+    format!("klave-row-backup/{}/{}", database_id, backup_id).into_bytes()
+}
+
+impl BackupIndex {
+    pub fn load(database_id: &str) -> Result<BackupIndex, Box<dyn std::error::Error>> {
+        match ledger_get(ROW_BACKUP_TABLE, &index_key(database_id), &legacy_index_key(database_id)) {
+            Some(v) => Ok(serde_json::from_slice(&v)?),
+            None => Ok(BackupIndex::default()),
+        }
+    }
+
+    pub fn save(&self, database_id: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let serialized = serde_json::to_string(self)?;
+        storage::ledger_set(ROW_BACKUP_TABLE, &index_key(database_id), serialized.as_bytes())?;
+        remove_legacy_key(ROW_BACKUP_TABLE, &index_key(database_id), &legacy_index_key(database_id));
+        Ok(())
+    }
+
+    pub fn used_bytes(&self) -> u64 {
+        self.entries.iter().map(|e| e.size).sum()
+    }
+
+    pub fn check_quota(&self, additional: u64, quota: u64) -> Result<(), String> {
+        if self.used_bytes().saturating_add(additional) > quota {
+            return Err(format!("Row backups would use {} of the {} bytes allowed, prune or restore backups first", self.used_bytes().saturating_add(additional), quota));
+        }
+        Ok(())
+    }
+
+    // Removes and returns the entries expired at the given time
+    pub fn take_expired(&mut self, now: u64) -> Vec<BackupIndexEntry> {
+        let (expired, kept) = std::mem::take(&mut self.entries).into_iter().partition(|e| e.expires_at <= now);
+        self.entries = kept;
+        expired
+    }
+}
+
+fn seal(key: &CryptoKey, aad: Vec<u8>, row: &Map<String, Value>) -> Result<(String, String), Box<dyn std::error::Error>> {
+    let iv = crate::runtime::random::get_random_bytes(AES_GCM_IV_SIZE as i32)?;
+    let params = AesGcmParams { iv: iv.clone(), additional_data: aad, tag_length: 128 };
+    let sealed = encrypt(&EncryptAlgorithm::AesGcm(params), key, &serde_json::to_vec(row)?)?;
+    Ok((hex::encode(iv), hex::encode(sealed)))
+}
+
+fn open(key: &CryptoKey, backup: &RowBackup) -> Result<Map<String, Value>, Box<dyn std::error::Error>> {
+    let params = AesGcmParams { iv: hex::decode(&backup.iv)?, additional_data: backup_aad(&backup.database_id, &backup.backup_id), tag_length: 128 };
+    let plaintext = decrypt(&EncryptAlgorithm::AesGcm(params), key, &hex::decode(&backup.sealed)?)?;
+    Ok(serde_json::from_slice(&plaintext)?)
+}
+
+// Backs up the rows an UPDATE or DELETE is about to change and returns the backup ids. Other writes
+// change no existing row, except the ones whose rows cannot be told from the statement, which are refused.
+pub fn backup_statement_rows(client: &Client, sql: &str, statement_index: usize) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let target = match write_target(sql) {
+        Some(target) => target,
+        None => match leading_keyword(sql).as_deref() {
+            Some("UPDATE") | Some("DELETE") | Some("MERGE") | Some("TRUNCATE") | Some("DROP") => {
+                return Err("the rows this statement changes cannot be backed up, split it into single-table UPDATE or DELETE statements".into());
+            }
+            _ => return Ok(Vec::new()),
+        },
+    };
+    let manifest = EncryptionManifest::load(client.database_id())?;
+    let entry = manifest.table(&target.table).ok_or(format!("table {} has no encrypted columns, its rows are not backed up", target.table))?;
+    let settings = DeploymentSettings::load()?;
+    let master_key = client.load_master_key()?;
+
+    let columns: Vec<String> = client.get_table_columns(&target.table)?.into_iter().filter(|c| c != ROW_MAC_COLUMN).collect();
+    let extra: Vec<&str> = columns.iter().map(|c| c.as_str()).collect();
+    let rows = client.read_decrypted_rows(&master_key, &manifest, &target.table, &extra, &format!("{} LIMIT {}", target.filter, MAX_BACKUP_ROWS + 1))?;
+    if rows.len() > MAX_BACKUP_ROWS {
+        return Err(format!("the statement changes more than {} rows, too many to back up", MAX_BACKUP_ROWS).into());
+    }
+
+    let backup_key = derive_backup_key(&master_key, &target.table)?;
+    let created_at = get_trusted_time();
+    let expires_at = created_at.saturating_add(settings.row_backup_retention_seconds.saturating_mul(NANOS_PER_SECOND));
+    let statement_hash = compute_sha256_hex_string(sql.as_bytes());
+    let mut backups = Vec::new();
+    for row in rows.iter() {
+        let mut values = row.values.clone();
+        values.remove(ROW_MAC_COLUMN);
+        let key = entry.primary_key.value(&values);
+        let id = backup_id(client.database_id(), &target.table, &key, created_at, statement_index);
+        let (iv, sealed) = seal(&backup_key, backup_aad(client.database_id(), &id), &values)?;
+        backups.push(RowBackup {
+            backup_id: id,
+            database_id: client.database_id().to_string(),
+            table: target.table.clone(),
+            primary_key: entry.primary_key.clone(),
+            key,
+            statement_hash: statement_hash.clone(),
+            created_at,
+            expires_at,
+            iv,
+            sealed,
+        });
+    }
+
+    let mut index = BackupIndex::load(client.database_id())?;
+    let size: u64 = backups.iter().map(|b| b.sealed.len() as u64 / 2).sum();
+    index.check_quota(size, settings.row_backup_quota_bytes)?;
+    storage::check_quota(size)?;
+    for backup in backups.iter() {
+        storage::ledger_set(ROW_BACKUP_TABLE, &backup.backup_id, serde_json::to_string(backup)?.as_bytes())?;
+        index.entries.push(BackupIndexEntry { backup_id: backup.backup_id.clone(), expires_at, size: backup.sealed.len() as u64 / 2 });
+    }
+    index.save(client.database_id())?;
+    Ok(backups.into_iter().map(|b| b.backup_id).collect())
+}
+
+fn load_backup(database_id: &str, backup_id: &str) -> Result<RowBackup, Box<dyn std::error::Error>> {
+    let record = crate::runtime::ledger::get_table(ROW_BACKUP_TABLE).get(backup_id).map_err(|_| format!("Backup {} not found", backup_id))?;
+    let backup: RowBackup = serde_json::from_slice(&record)?;
+    // Backups of other clients are reported as missing
+    if backup.database_id != database_id || backup.backup_id != backup_id {
+        return Err(format!("Backup {} not found", backup_id).into());
+    }
+    if backup.expires_at <= get_trusted_time() {
+        return Err(format!("Backup {} expired", backup_id).into());
+    }
+    Ok(backup)
+}
+
+fn restore(client: &mut Client, backup_id: &str) -> Result<RowBackup, Box<dyn std::error::Error>> {
+    let backup = load_backup(client.database_id(), backup_id)?;
+    let master_key = client.load_master_key()?;
+    let row = open(&derive_backup_key(&master_key, &backup.table)?, &backup)?;
+    client.connect()?;
+    client.upsert_row(&backup.table, &backup.primary_key, &row)?;
+    Ok(backup)
+}
+
+// Writes a backed-up row back, re-encrypting its registered columns. The backup is kept until it expires.
+pub fn restore_row_backup(cmd: String) {
+    let input: RestoreRowBackupInput = match notify::parse_input(&cmd) {
+        Some(input) => input,
+        None => return,
+    };
+    let mut client = match Client::load(input.database_id.clone()) {
+        Ok(c) => c,
+        Err(err) => {
+            notify::error_with_code(&format!("Failed to load client: {}", err), err.code());
+            return;
+        }
+    };
+    match restore(&mut client, &input.backup_id) {
+        Ok(backup) => {
+            audit::record("restore_row_backup", Some(&input.database_id), "success", json!({ "backup_id": input.backup_id, "table": backup.table }));
+            notify::result(&json!({ "backup_id": input.backup_id, "table": backup.table, "primary_key": backup.primary_key, "key": backup.key }));
+        },
+        Err(err) => {
+            audit::record("restore_row_backup", Some(&input.database_id), "failure", json!({ "backup_id": input.backup_id }));
+            notify::error_with_code(&format!("Failed to restore row backup: {}", err), DatabaseError::code_of(err.as_ref()));
+        }
+    }
+}
+
+// Seals the live backups of a client again under the backup keys of a new master key, as part of a key
+// rotation. Backups an earlier call already resealed are left as they are. Returns the number resealed.
+pub(crate) fn reseal_backups(database_id: &str, old_key: &CryptoKey, new_key: &CryptoKey) -> Result<u64, Box<dyn std::error::Error>> {
+    let index = BackupIndex::load(database_id)?;
+    let now = get_trusted_time();
+    let mut resealed = 0;
+    for entry in index.entries.iter().filter(|e| e.expires_at > now) {
+        let mut backup = match load_backup(database_id, &entry.backup_id) {
+            Ok(backup) => backup,
+            Err(_) => continue,
+        };
+        let new_backup_key = derive_backup_key(new_key, &backup.table)?;
+        let row = match open(&derive_backup_key(old_key, &backup.table)?, &backup) {
+            Ok(row) => row,
+            Err(_) => {
+                if open(&new_backup_key, &backup).is_err() {
+                    notify::warning(&format!("Backup {} opens under neither key and was not resealed", backup.backup_id));
+                }
+                continue;
+            }
+        };
+        // The row keeps its size, the index stays accurate
+        let (iv, sealed) = seal(&new_backup_key, backup_aad(database_id, &backup.backup_id), &row)?;
+        backup.iv = iv;
+        backup.sealed = sealed;
+        storage::ledger_set(ROW_BACKUP_TABLE, &backup.backup_id, serde_json::to_string(&backup)?.as_bytes())?;
+        resealed += 1;
+    }
+    Ok(resealed)
+}
+
+fn prune(database_id: &str) -> Result<(usize, BackupIndex), Box<dyn std::error::Error>> {
+    let mut index = BackupIndex::load(database_id)?;
+    let expired = index.take_expired(get_trusted_time());
+    for entry in expired.iter() {
+        storage::ledger_remove(ROW_BACKUP_TABLE, &entry.backup_id)?;
+    }
+    index.save(database_id)?;
+    Ok((expired.len(), index))
+}
+
+// Maintenance: drops the backups of a client past their retention window
+pub fn prune_row_backups(cmd: String) {
+    let input: PruneRowBackupsInput = match notify::parse_input(&cmd) {
+        Some(input) => input,
+        None => return,
+    };
+    let client = match Client::load(input.database_id.clone()) {
+        Ok(c) => c,
+        Err(err) => {
+            notify::error_with_code(&format!("Failed to load client: {}", err), err.code());
+            return;
+        }
+    };
+    if client.access() == AccessLevel::ReportingOnly {
+        notify::error("Reporting-only callers cannot prune row backups");
+        return;
+    }
+    match prune(&input.database_id) {
+        Ok((pruned, index)) => {
+            audit::record("prune_row_backups", Some(&input.database_id), "success", json!({ "pruned": pruned }));
+            notify::result(&json!({ "pruned": pruned, "remaining": index.entries.len(), "used_bytes": index.used_bytes() }));
+        },
+        Err(err) => {
+            audit::record("prune_row_backups", Some(&input.database_id), "failure", json!({}));
+            notify::error_with_code(&format!("Failed to prune row backups: {}", err), DatabaseError::code_of(err.as_ref()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(backup_id: &str, expires_at: u64, size: u64) -> BackupIndexEntry {
+        BackupIndexEntry { backup_id: backup_id.to_string(), expires_at, size }
+    }
+
+    #[test]
+    fn test_backup_index() {
+        let mut index = BackupIndex { entries: vec![entry("a", 10, 100), entry("b", 30, 50)] };
+        assert_eq!(index.used_bytes(), 150);
+        assert!(index.check_quota(50, 200).is_ok());
+        assert!(index.check_quota(51, 200).is_err());
+        assert_eq!(index.take_expired(10), vec![entry("a", 10, 100)]);
+        assert_eq!(index.entries, vec![entry("b", 30, 50)]);
+    }
+
+    #[test]
+    fn test_backup_id() {
+        assert_eq!(backup_id("db", "users", &json!("7"), 42, 3), "db/users/7/00000000000000000042.3");
+        assert_ne!(backup_id("db", "users", &json!(7), 42, 3), backup_id("db", "users", &json!(7), 42, 4));
+        // A key holding the separator cannot pass for another table's backup, nor for an index record
+        assert_ne!(backup_id("db", "users", &json!("7/x"), 42, 3), backup_id("db", "users/7", &json!("x"), 42, 3));
+        assert_ne!(backup_id("index", "db", &json!(""), 0, 0).split('/').count(), index_key("db").split('/').count());
+    }
+}
@@ -17,10 +17,15 @@ pub unsafe fn _export_db_setup_cabi<T: Guest>(arg0: *mut u8, arg1: usize) {
 }
 #[doc(hidden)]
 #[allow(non_snake_case)]
-pub unsafe fn _export_execute_table_encryption_cabi<T: Guest>(
-    arg0: *mut u8,
-    arg1: usize,
-) {
+pub unsafe fn _export_sql_delete_cabi<T: Guest>(arg0: *mut u8, arg1: usize) {
+    #[cfg(target_arch = "wasm32")] _rt::run_ctors_once();
+    let len0 = arg1;
+    let bytes0 = _rt::Vec::from_raw_parts(arg0.cast(), len0, len0);
+    T::sql_delete(_rt::string_lift(bytes0));
+}
+#[doc(hidden)]
+#[allow(non_snake_case)]
+pub unsafe fn _export_execute_table_encryption_cabi<T: Guest>(arg0: *mut u8, arg1: usize) {
     #[cfg(target_arch = "wasm32")] _rt::run_ctors_once();
     let len0 = arg1;
     let bytes0 = _rt::Vec::from_raw_parts(arg0.cast(), len0, len0);
@@ -28,10 +33,479 @@ pub unsafe fn _export_execute_table_encryption_cabi<T: Guest>(
 }
 #[doc(hidden)]
 #[allow(non_snake_case)]
-pub unsafe fn _export_read_encrypted_data_per_user_cabi<T: Guest>(
-    arg0: *mut u8,
-    arg1: usize,
-) {
+pub unsafe fn _export_sql_script_cabi<T: Guest>(arg0: *mut u8, arg1: usize) {
+    #[cfg(target_arch = "wasm32")] _rt::run_ctors_once();
+    let len0 = arg1;
+    let bytes0 = _rt::Vec::from_raw_parts(arg0.cast(), len0, len0);
+    T::sql_script(_rt::string_lift(bytes0));
+}
+#[doc(hidden)]
+#[allow(non_snake_case)]
+pub unsafe fn _export_decrypt_value_cabi<T: Guest>(arg0: *mut u8, arg1: usize) {
+    #[cfg(target_arch = "wasm32")] _rt::run_ctors_once();
+    let len0 = arg1;
+    let bytes0 = _rt::Vec::from_raw_parts(arg0.cast(), len0, len0);
+    T::decrypt_value(_rt::string_lift(bytes0));
+}
+#[doc(hidden)]
+#[allow(non_snake_case)]
+pub unsafe fn _export_diagnose_keys_cabi<T: Guest>(arg0: *mut u8, arg1: usize) {
+    #[cfg(target_arch = "wasm32")] _rt::run_ctors_once();
+    let len0 = arg1;
+    let bytes0 = _rt::Vec::from_raw_parts(arg0.cast(), len0, len0);
+    T::diagnose_keys(_rt::string_lift(bytes0));
+}
+#[doc(hidden)]
+#[allow(non_snake_case)]
+pub unsafe fn _export_consistent_read_session_cabi<T: Guest>(arg0: *mut u8, arg1: usize) {
+    #[cfg(target_arch = "wasm32")] _rt::run_ctors_once();
+    let len0 = arg1;
+    let bytes0 = _rt::Vec::from_raw_parts(arg0.cast(), len0, len0);
+    T::consistent_read_session(_rt::string_lift(bytes0));
+}
+#[doc(hidden)]
+#[allow(non_snake_case)]
+pub unsafe fn _export_create_search_index_cabi<T: Guest>(arg0: *mut u8, arg1: usize) {
+    #[cfg(target_arch = "wasm32")] _rt::run_ctors_once();
+    let len0 = arg1;
+    let bytes0 = _rt::Vec::from_raw_parts(arg0.cast(), len0, len0);
+    T::create_search_index(_rt::string_lift(bytes0));
+}
+#[doc(hidden)]
+#[allow(non_snake_case)]
+pub unsafe fn _export_drop_search_index_cabi<T: Guest>(arg0: *mut u8, arg1: usize) {
+    #[cfg(target_arch = "wasm32")] _rt::run_ctors_once();
+    let len0 = arg1;
+    let bytes0 = _rt::Vec::from_raw_parts(arg0.cast(), len0, len0);
+    T::drop_search_index(_rt::string_lift(bytes0));
+}
+#[doc(hidden)]
+#[allow(non_snake_case)]
+pub unsafe fn _export_search_index_progress_cabi<T: Guest>(arg0: *mut u8, arg1: usize) {
+    #[cfg(target_arch = "wasm32")] _rt::run_ctors_once();
+    let len0 = arg1;
+    let bytes0 = _rt::Vec::from_raw_parts(arg0.cast(), len0, len0);
+    T::search_index_progress(_rt::string_lift(bytes0));
+}
+#[doc(hidden)]
+#[allow(non_snake_case)]
+pub unsafe fn _export_find_duplicate_values_cabi<T: Guest>(arg0: *mut u8, arg1: usize) {
+    #[cfg(target_arch = "wasm32")] _rt::run_ctors_once();
+    let len0 = arg1;
+    let bytes0 = _rt::Vec::from_raw_parts(arg0.cast(), len0, len0);
+    T::find_duplicate_values(_rt::string_lift(bytes0));
+}
+#[doc(hidden)]
+#[allow(non_snake_case)]
+pub unsafe fn _export_export_state_snapshot_cabi<T: Guest>(arg0: *mut u8, arg1: usize) {
+    #[cfg(target_arch = "wasm32")] _rt::run_ctors_once();
+    let len0 = arg1;
+    let bytes0 = _rt::Vec::from_raw_parts(arg0.cast(), len0, len0);
+    T::export_state_snapshot(_rt::string_lift(bytes0));
+}
+#[doc(hidden)]
+#[allow(non_snake_case)]
+pub unsafe fn _export_import_state_snapshot_cabi<T: Guest>(arg0: *mut u8, arg1: usize) {
+    #[cfg(target_arch = "wasm32")] _rt::run_ctors_once();
+    let len0 = arg1;
+    let bytes0 = _rt::Vec::from_raw_parts(arg0.cast(), len0, len0);
+    T::import_state_snapshot(_rt::string_lift(bytes0));
+}
+#[doc(hidden)]
+#[allow(non_snake_case)]
+pub unsafe fn _export_bulk_operation_cabi<T: Guest>(arg0: *mut u8, arg1: usize) {
+    #[cfg(target_arch = "wasm32")] _rt::run_ctors_once();
+    let len0 = arg1;
+    let bytes0 = _rt::Vec::from_raw_parts(arg0.cast(), len0, len0);
+    T::bulk_operation(_rt::string_lift(bytes0));
+}
+#[doc(hidden)]
+#[allow(non_snake_case)]
+pub unsafe fn _export_set_column_rules_cabi<T: Guest>(arg0: *mut u8, arg1: usize) {
+    #[cfg(target_arch = "wasm32")] _rt::run_ctors_once();
+    let len0 = arg1;
+    let bytes0 = _rt::Vec::from_raw_parts(arg0.cast(), len0, len0);
+    T::set_column_rules(_rt::string_lift(bytes0));
+}
+#[doc(hidden)]
+#[allow(non_snake_case)]
+pub unsafe fn _export_freeze_database_cabi<T: Guest>(arg0: *mut u8, arg1: usize) {
+    #[cfg(target_arch = "wasm32")] _rt::run_ctors_once();
+    let len0 = arg1;
+    let bytes0 = _rt::Vec::from_raw_parts(arg0.cast(), len0, len0);
+    T::freeze_database(_rt::string_lift(bytes0));
+}
+#[doc(hidden)]
+#[allow(non_snake_case)]
+pub unsafe fn _export_unfreeze_database_cabi<T: Guest>(arg0: *mut u8, arg1: usize) {
+    #[cfg(target_arch = "wasm32")] _rt::run_ctors_once();
+    let len0 = arg1;
+    let bytes0 = _rt::Vec::from_raw_parts(arg0.cast(), len0, len0);
+    T::unfreeze_database(_rt::string_lift(bytes0));
+}
+#[doc(hidden)]
+#[allow(non_snake_case)]
+pub unsafe fn _export_generate_support_bundle_cabi<T: Guest>(arg0: *mut u8, arg1: usize) {
+    #[cfg(target_arch = "wasm32")] _rt::run_ctors_once();
+    let len0 = arg1;
+    let bytes0 = _rt::Vec::from_raw_parts(arg0.cast(), len0, len0);
+    T::generate_support_bundle(_rt::string_lift(bytes0));
+}
+#[doc(hidden)]
+#[allow(non_snake_case)]
+pub unsafe fn _export_restore_row_backup_cabi<T: Guest>(arg0: *mut u8, arg1: usize) {
+    #[cfg(target_arch = "wasm32")] _rt::run_ctors_once();
+    let len0 = arg1;
+    let bytes0 = _rt::Vec::from_raw_parts(arg0.cast(), len0, len0);
+    T::restore_row_backup(_rt::string_lift(bytes0));
+}
+#[doc(hidden)]
+#[allow(non_snake_case)]
+pub unsafe fn _export_prune_row_backups_cabi<T: Guest>(arg0: *mut u8, arg1: usize) {
+    #[cfg(target_arch = "wasm32")] _rt::run_ctors_once();
+    let len0 = arg1;
+    let bytes0 = _rt::Vec::from_raw_parts(arg0.cast(), len0, len0);
+    T::prune_row_backups(_rt::string_lift(bytes0));
+}
+#[doc(hidden)]
+#[allow(non_snake_case)]
+pub unsafe fn _export_set_strict_encrypted_access_cabi<T: Guest>(arg0: *mut u8, arg1: usize) {
+    #[cfg(target_arch = "wasm32")] _rt::run_ctors_once();
+    let len0 = arg1;
+    let bytes0 = _rt::Vec::from_raw_parts(arg0.cast(), len0, len0);
+    T::set_strict_encrypted_access(_rt::string_lift(bytes0));
+}
+#[doc(hidden)]
+#[allow(non_snake_case)]
+pub unsafe fn _export_issue_strict_bypass_token_cabi<T: Guest>(arg0: *mut u8, arg1: usize) {
+    #[cfg(target_arch = "wasm32")] _rt::run_ctors_once();
+    let len0 = arg1;
+    let bytes0 = _rt::Vec::from_raw_parts(arg0.cast(), len0, len0);
+    T::issue_strict_bypass_token(_rt::string_lift(bytes0));
+}
+#[doc(hidden)]
+#[allow(non_snake_case)]
+pub unsafe fn _export_prune_history_cabi<T: Guest>(arg0: *mut u8, arg1: usize) {
+    #[cfg(target_arch = "wasm32")] _rt::run_ctors_once();
+    let len0 = arg1;
+    let bytes0 = _rt::Vec::from_raw_parts(arg0.cast(), len0, len0);
+    T::prune_history(_rt::string_lift(bytes0));
+}
+#[doc(hidden)]
+#[allow(non_snake_case)]
+pub unsafe fn _export_get_audit_log_cabi<T: Guest>(arg0: *mut u8, arg1: usize) {
+    #[cfg(target_arch = "wasm32")] _rt::run_ctors_once();
+    let len0 = arg1;
+    let bytes0 = _rt::Vec::from_raw_parts(arg0.cast(), len0, len0);
+    T::get_audit_log(_rt::string_lift(bytes0));
+}
+#[doc(hidden)]
+#[allow(non_snake_case)]
+pub unsafe fn _export_bootstrap_cabi<T: Guest>(arg0: *mut u8, arg1: usize) {
+    #[cfg(target_arch = "wasm32")] _rt::run_ctors_once();
+    let len0 = arg1;
+    let bytes0 = _rt::Vec::from_raw_parts(arg0.cast(), len0, len0);
+    T::bootstrap(_rt::string_lift(bytes0));
+}
+#[doc(hidden)]
+#[allow(non_snake_case)]
+pub unsafe fn _export_create_encrypted_view_cabi<T: Guest>(arg0: *mut u8, arg1: usize) {
+    #[cfg(target_arch = "wasm32")] _rt::run_ctors_once();
+    let len0 = arg1;
+    let bytes0 = _rt::Vec::from_raw_parts(arg0.cast(), len0, len0);
+    T::create_encrypted_view(_rt::string_lift(bytes0));
+}
+#[doc(hidden)]
+#[allow(non_snake_case)]
+pub unsafe fn _export_refresh_encrypted_view_cabi<T: Guest>(arg0: *mut u8, arg1: usize) {
+    #[cfg(target_arch = "wasm32")] _rt::run_ctors_once();
+    let len0 = arg1;
+    let bytes0 = _rt::Vec::from_raw_parts(arg0.cast(), len0, len0);
+    T::refresh_encrypted_view(_rt::string_lift(bytes0));
+}
+#[doc(hidden)]
+#[allow(non_snake_case)]
+pub unsafe fn _export_v2_db_setup_cabi<T: Guest>(arg0: *mut u8, arg1: usize) {
+    #[cfg(target_arch = "wasm32")] _rt::run_ctors_once();
+    let len0 = arg1;
+    let bytes0 = _rt::Vec::from_raw_parts(arg0.cast(), len0, len0);
+    T::v2_db_setup(_rt::string_lift(bytes0));
+}
+#[doc(hidden)]
+#[allow(non_snake_case)]
+pub unsafe fn _export_v2_sql_script_cabi<T: Guest>(arg0: *mut u8, arg1: usize) {
+    #[cfg(target_arch = "wasm32")] _rt::run_ctors_once();
+    let len0 = arg1;
+    let bytes0 = _rt::Vec::from_raw_parts(arg0.cast(), len0, len0);
+    T::v2_sql_script(_rt::string_lift(bytes0));
+}
+#[doc(hidden)]
+#[allow(non_snake_case)]
+pub unsafe fn _export_route_usage_cabi<T: Guest>(arg0: *mut u8, arg1: usize) {
+    #[cfg(target_arch = "wasm32")] _rt::run_ctors_once();
+    let len0 = arg1;
+    let bytes0 = _rt::Vec::from_raw_parts(arg0.cast(), len0, len0);
+    T::route_usage(_rt::string_lift(bytes0));
+}
+#[doc(hidden)]
+#[allow(non_snake_case)]
+pub unsafe fn _export_encryption_progress_cabi<T: Guest>(arg0: *mut u8, arg1: usize) {
+    #[cfg(target_arch = "wasm32")] _rt::run_ctors_once();
+    let len0 = arg1;
+    let bytes0 = _rt::Vec::from_raw_parts(arg0.cast(), len0, len0);
+    T::encryption_progress(_rt::string_lift(bytes0));
+}
+#[doc(hidden)]
+#[allow(non_snake_case)]
+pub unsafe fn _export_migrate_ciphertext_versions_cabi<T: Guest>(arg0: *mut u8, arg1: usize) {
+    #[cfg(target_arch = "wasm32")] _rt::run_ctors_once();
+    let len0 = arg1;
+    let bytes0 = _rt::Vec::from_raw_parts(arg0.cast(), len0, len0);
+    T::migrate_ciphertext_versions(_rt::string_lift(bytes0));
+}
+#[doc(hidden)]
+#[allow(non_snake_case)]
+pub unsafe fn _export_test_credentials_cabi<T: Guest>(arg0: *mut u8, arg1: usize) {
+    #[cfg(target_arch = "wasm32")] _rt::run_ctors_once();
+    let len0 = arg1;
+    let bytes0 = _rt::Vec::from_raw_parts(arg0.cast(), len0, len0);
+    T::test_credentials(_rt::string_lift(bytes0));
+}
+#[doc(hidden)]
+#[allow(non_snake_case)]
+pub unsafe fn _export_reap_stale_jobs_cabi<T: Guest>(arg0: *mut u8, arg1: usize) {
+    #[cfg(target_arch = "wasm32")] _rt::run_ctors_once();
+    let len0 = arg1;
+    let bytes0 = _rt::Vec::from_raw_parts(arg0.cast(), len0, len0);
+    T::reap_stale_jobs(_rt::string_lift(bytes0));
+}
+#[doc(hidden)]
+#[allow(non_snake_case)]
+pub unsafe fn _export_probe_host_capabilities_cabi<T: Guest>(arg0: *mut u8, arg1: usize) {
+    #[cfg(target_arch = "wasm32")] _rt::run_ctors_once();
+    let len0 = arg1;
+    let bytes0 = _rt::Vec::from_raw_parts(arg0.cast(), len0, len0);
+    T::probe_host_capabilities(_rt::string_lift(bytes0));
+}
+#[doc(hidden)]
+#[allow(non_snake_case)]
+pub unsafe fn _export_recover_incomplete_operations_cabi<T: Guest>(arg0: *mut u8, arg1: usize) {
+    #[cfg(target_arch = "wasm32")] _rt::run_ctors_once();
+    let len0 = arg1;
+    let bytes0 = _rt::Vec::from_raw_parts(arg0.cast(), len0, len0);
+    T::recover_incomplete_operations(_rt::string_lift(bytes0));
+}
+#[doc(hidden)]
+#[allow(non_snake_case)]
+pub unsafe fn _export_sql_list_cabi<T: Guest>(arg0: *mut u8, arg1: usize) {
+    #[cfg(target_arch = "wasm32")] _rt::run_ctors_once();
+    let len0 = arg1;
+    let bytes0 = _rt::Vec::from_raw_parts(arg0.cast(), len0, len0);
+    T::sql_list(_rt::string_lift(bytes0));
+}
+#[doc(hidden)]
+#[allow(non_snake_case)]
+pub unsafe fn _export_db_update_cabi<T: Guest>(arg0: *mut u8, arg1: usize) {
+    #[cfg(target_arch = "wasm32")] _rt::run_ctors_once();
+    let len0 = arg1;
+    let bytes0 = _rt::Vec::from_raw_parts(arg0.cast(), len0, len0);
+    T::db_update(_rt::string_lift(bytes0));
+}
+#[doc(hidden)]
+#[allow(non_snake_case)]
+pub unsafe fn _export_read_encrypted_table_cabi<T: Guest>(arg0: *mut u8, arg1: usize) {
+    #[cfg(target_arch = "wasm32")] _rt::run_ctors_once();
+    let len0 = arg1;
+    let bytes0 = _rt::Vec::from_raw_parts(arg0.cast(), len0, len0);
+    T::read_encrypted_table(_rt::string_lift(bytes0));
+}
+#[doc(hidden)]
+#[allow(non_snake_case)]
+pub unsafe fn _export_manage_audit_columns_cabi<T: Guest>(arg0: *mut u8, arg1: usize) {
+    #[cfg(target_arch = "wasm32")] _rt::run_ctors_once();
+    let len0 = arg1;
+    let bytes0 = _rt::Vec::from_raw_parts(arg0.cast(), len0, len0);
+    T::manage_audit_columns(_rt::string_lift(bytes0));
+}
+#[doc(hidden)]
+#[allow(non_snake_case)]
+pub unsafe fn _export_watch_query_cabi<T: Guest>(arg0: *mut u8, arg1: usize) {
+    #[cfg(target_arch = "wasm32")] _rt::run_ctors_once();
+    let len0 = arg1;
+    let bytes0 = _rt::Vec::from_raw_parts(arg0.cast(), len0, len0);
+    T::watch_query(_rt::string_lift(bytes0));
+}
+#[doc(hidden)]
+#[allow(non_snake_case)]
+pub unsafe fn _export_run_watch_cabi<T: Guest>(arg0: *mut u8, arg1: usize) {
+    #[cfg(target_arch = "wasm32")] _rt::run_ctors_once();
+    let len0 = arg1;
+    let bytes0 = _rt::Vec::from_raw_parts(arg0.cast(), len0, len0);
+    T::run_watch(_rt::string_lift(bytes0));
+}
+#[doc(hidden)]
+#[allow(non_snake_case)]
+pub unsafe fn _export_decrypt_values_cabi<T: Guest>(arg0: *mut u8, arg1: usize) {
+    #[cfg(target_arch = "wasm32")] _rt::run_ctors_once();
+    let len0 = arg1;
+    let bytes0 = _rt::Vec::from_raw_parts(arg0.cast(), len0, len0);
+    T::decrypt_values(_rt::string_lift(bytes0));
+}
+#[doc(hidden)]
+#[allow(non_snake_case)]
+pub unsafe fn _export_storage_usage_cabi<T: Guest>(arg0: *mut u8, arg1: usize) {
+    #[cfg(target_arch = "wasm32")] _rt::run_ctors_once();
+    let len0 = arg1;
+    let bytes0 = _rt::Vec::from_raw_parts(arg0.cast(), len0, len0);
+    T::storage_usage(_rt::string_lift(bytes0));
+}
+#[doc(hidden)]
+#[allow(non_snake_case)]
+pub unsafe fn _export_recalculate_storage_usage_cabi<T: Guest>(arg0: *mut u8, arg1: usize) {
+    #[cfg(target_arch = "wasm32")] _rt::run_ctors_once();
+    let len0 = arg1;
+    let bytes0 = _rt::Vec::from_raw_parts(arg0.cast(), len0, len0);
+    T::recalculate_storage_usage(_rt::string_lift(bytes0));
+}
+#[doc(hidden)]
+#[allow(non_snake_case)]
+pub unsafe fn _export_list_encrypted_columns_cabi<T: Guest>(arg0: *mut u8, arg1: usize) {
+    #[cfg(target_arch = "wasm32")] _rt::run_ctors_once();
+    let len0 = arg1;
+    let bytes0 = _rt::Vec::from_raw_parts(arg0.cast(), len0, len0);
+    T::list_encrypted_columns(_rt::string_lift(bytes0));
+}
+#[doc(hidden)]
+#[allow(non_snake_case)]
+pub unsafe fn _export_execute_table_decryption_cabi<T: Guest>(arg0: *mut u8, arg1: usize) {
+    #[cfg(target_arch = "wasm32")] _rt::run_ctors_once();
+    let len0 = arg1;
+    let bytes0 = _rt::Vec::from_raw_parts(arg0.cast(), len0, len0);
+    T::execute_table_decryption(_rt::string_lift(bytes0));
+}
+#[doc(hidden)]
+#[allow(non_snake_case)]
+pub unsafe fn _export_migrate_deployment_cabi<T: Guest>(arg0: *mut u8, arg1: usize) {
+    #[cfg(target_arch = "wasm32")] _rt::run_ctors_once();
+    let len0 = arg1;
+    let bytes0 = _rt::Vec::from_raw_parts(arg0.cast(), len0, len0);
+    T::migrate_deployment(_rt::string_lift(bytes0));
+}
+#[doc(hidden)]
+#[allow(non_snake_case)]
+pub unsafe fn _export_scan_for_plaintext_leaks_cabi<T: Guest>(arg0: *mut u8, arg1: usize) {
+    #[cfg(target_arch = "wasm32")] _rt::run_ctors_once();
+    let len0 = arg1;
+    let bytes0 = _rt::Vec::from_raw_parts(arg0.cast(), len0, len0);
+    T::scan_for_plaintext_leaks(_rt::string_lift(bytes0));
+}
+#[doc(hidden)]
+#[allow(non_snake_case)]
+pub unsafe fn _export_quarantine_leaked_rows_cabi<T: Guest>(arg0: *mut u8, arg1: usize) {
+    #[cfg(target_arch = "wasm32")] _rt::run_ctors_once();
+    let len0 = arg1;
+    let bytes0 = _rt::Vec::from_raw_parts(arg0.cast(), len0, len0);
+    T::quarantine_leaked_rows(_rt::string_lift(bytes0));
+}
+#[doc(hidden)]
+#[allow(non_snake_case)]
+pub unsafe fn _export_sql_transaction_cabi<T: Guest>(arg0: *mut u8, arg1: usize) {
+    #[cfg(target_arch = "wasm32")] _rt::run_ctors_once();
+    let len0 = arg1;
+    let bytes0 = _rt::Vec::from_raw_parts(arg0.cast(), len0, len0);
+    T::sql_transaction(_rt::string_lift(bytes0));
+}
+#[doc(hidden)]
+#[allow(non_snake_case)]
+pub unsafe fn _export_rotate_master_key_cabi<T: Guest>(arg0: *mut u8, arg1: usize) {
+    #[cfg(target_arch = "wasm32")] _rt::run_ctors_once();
+    let len0 = arg1;
+    let bytes0 = _rt::Vec::from_raw_parts(arg0.cast(), len0, len0);
+    T::rotate_master_key(_rt::string_lift(bytes0));
+}
+#[doc(hidden)]
+#[allow(non_snake_case)]
+pub unsafe fn _export_grant_db_access_cabi<T: Guest>(arg0: *mut u8, arg1: usize) {
+    #[cfg(target_arch = "wasm32")] _rt::run_ctors_once();
+    let len0 = arg1;
+    let bytes0 = _rt::Vec::from_raw_parts(arg0.cast(), len0, len0);
+    T::grant_db_access(_rt::string_lift(bytes0));
+}
+#[doc(hidden)]
+#[allow(non_snake_case)]
+pub unsafe fn _export_revoke_db_access_cabi<T: Guest>(arg0: *mut u8, arg1: usize) {
+    #[cfg(target_arch = "wasm32")] _rt::run_ctors_once();
+    let len0 = arg1;
+    let bytes0 = _rt::Vec::from_raw_parts(arg0.cast(), len0, len0);
+    T::revoke_db_access(_rt::string_lift(bytes0));
+}
+#[doc(hidden)]
+#[allow(non_snake_case)]
+pub unsafe fn _export_sql_query_cursor_cabi<T: Guest>(arg0: *mut u8, arg1: usize) {
+    #[cfg(target_arch = "wasm32")] _rt::run_ctors_once();
+    let len0 = arg1;
+    let bytes0 = _rt::Vec::from_raw_parts(arg0.cast(), len0, len0);
+    T::sql_query_cursor(_rt::string_lift(bytes0));
+}
+#[doc(hidden)]
+#[allow(non_snake_case)]
+pub unsafe fn _export_db_list_tables_cabi<T: Guest>(arg0: *mut u8, arg1: usize) {
+    #[cfg(target_arch = "wasm32")] _rt::run_ctors_once();
+    let len0 = arg1;
+    let bytes0 = _rt::Vec::from_raw_parts(arg0.cast(), len0, len0);
+    T::db_list_tables(_rt::string_lift(bytes0));
+}
+#[doc(hidden)]
+#[allow(non_snake_case)]
+pub unsafe fn _export_db_describe_table_cabi<T: Guest>(arg0: *mut u8, arg1: usize) {
+    #[cfg(target_arch = "wasm32")] _rt::run_ctors_once();
+    let len0 = arg1;
+    let bytes0 = _rt::Vec::from_raw_parts(arg0.cast(), len0, len0);
+    T::db_describe_table(_rt::string_lift(bytes0));
+}
+#[doc(hidden)]
+#[allow(non_snake_case)]
+pub unsafe fn _export_get_settings_cabi<T: Guest>(arg0: *mut u8, arg1: usize) {
+    #[cfg(target_arch = "wasm32")] _rt::run_ctors_once();
+    let len0 = arg1;
+    let bytes0 = _rt::Vec::from_raw_parts(arg0.cast(), len0, len0);
+    T::get_settings(_rt::string_lift(bytes0));
+}
+#[doc(hidden)]
+#[allow(non_snake_case)]
+pub unsafe fn _export_update_settings_cabi<T: Guest>(arg0: *mut u8, arg1: usize) {
+    #[cfg(target_arch = "wasm32")] _rt::run_ctors_once();
+    let len0 = arg1;
+    let bytes0 = _rt::Vec::from_raw_parts(arg0.cast(), len0, len0);
+    T::update_settings(_rt::string_lift(bytes0));
+}
+#[doc(hidden)]
+#[allow(non_snake_case)]
+pub unsafe fn _export_generate_test_data_cabi<T: Guest>(arg0: *mut u8, arg1: usize) {
+    #[cfg(target_arch = "wasm32")] _rt::run_ctors_once();
+    let len0 = arg1;
+    let bytes0 = _rt::Vec::from_raw_parts(arg0.cast(), len0, len0);
+    T::generate_test_data(_rt::string_lift(bytes0));
+}
+#[doc(hidden)]
+#[allow(non_snake_case)]
+pub unsafe fn _export_quick_verify_table_cabi<T: Guest>(arg0: *mut u8, arg1: usize) {
+    #[cfg(target_arch = "wasm32")] _rt::run_ctors_once();
+    let len0 = arg1;
+    let bytes0 = _rt::Vec::from_raw_parts(arg0.cast(), len0, len0);
+    T::quick_verify_table(_rt::string_lift(bytes0));
+}
+#[doc(hidden)]
+#[allow(non_snake_case)]
+pub unsafe fn _export_reconcile_encryption_state_cabi<T: Guest>(arg0: *mut u8, arg1: usize) {
+    #[cfg(target_arch = "wasm32")] _rt::run_ctors_once();
+    let len0 = arg1;
+    let bytes0 = _rt::Vec::from_raw_parts(arg0.cast(), len0, len0);
+    T::reconcile_encryption_state(_rt::string_lift(bytes0));
+}
+#[doc(hidden)]
+#[allow(non_snake_case)]
+pub unsafe fn _export_read_encrypted_data_per_user_cabi<T: Guest>(arg0: *mut u8, arg1: usize) {
     #[cfg(target_arch = "wasm32")] _rt::run_ctors_once();
     let len0 = arg1;
     let bytes0 = _rt::Vec::from_raw_parts(arg0.cast(), len0, len0);
@@ -56,7 +530,67 @@ pub unsafe fn _export_avg_age_for_female_cabi<T: Guest>(arg0: *mut u8, arg1: usi
 pub trait Guest {
     fn register_routes();
     fn db_setup(cmd: _rt::String);
+    fn sql_delete(cmd: _rt::String);
     fn execute_table_encryption(cmd: _rt::String);
+    fn sql_script(cmd: _rt::String);
+    fn decrypt_value(cmd: _rt::String);
+    fn diagnose_keys(cmd: _rt::String);
+    fn consistent_read_session(cmd: _rt::String);
+    fn create_search_index(cmd: _rt::String);
+    fn drop_search_index(cmd: _rt::String);
+    fn search_index_progress(cmd: _rt::String);
+    fn find_duplicate_values(cmd: _rt::String);
+    fn export_state_snapshot(cmd: _rt::String);
+    fn import_state_snapshot(cmd: _rt::String);
+    fn bulk_operation(cmd: _rt::String);
+    fn set_column_rules(cmd: _rt::String);
+    fn freeze_database(cmd: _rt::String);
+    fn unfreeze_database(cmd: _rt::String);
+    fn generate_support_bundle(cmd: _rt::String);
+    fn restore_row_backup(cmd: _rt::String);
+    fn prune_row_backups(cmd: _rt::String);
+    fn set_strict_encrypted_access(cmd: _rt::String);
+    fn issue_strict_bypass_token(cmd: _rt::String);
+    fn prune_history(cmd: _rt::String);
+    fn get_audit_log(cmd: _rt::String);
+    fn bootstrap(cmd: _rt::String);
+    fn create_encrypted_view(cmd: _rt::String);
+    fn refresh_encrypted_view(cmd: _rt::String);
+    fn v2_db_setup(cmd: _rt::String);
+    fn v2_sql_script(cmd: _rt::String);
+    fn route_usage(cmd: _rt::String);
+    fn encryption_progress(cmd: _rt::String);
+    fn migrate_ciphertext_versions(cmd: _rt::String);
+    fn test_credentials(cmd: _rt::String);
+    fn reap_stale_jobs(cmd: _rt::String);
+    fn probe_host_capabilities(cmd: _rt::String);
+    fn recover_incomplete_operations(cmd: _rt::String);
+    fn sql_list(cmd: _rt::String);
+    fn db_update(cmd: _rt::String);
+    fn read_encrypted_table(cmd: _rt::String);
+    fn manage_audit_columns(cmd: _rt::String);
+    fn watch_query(cmd: _rt::String);
+    fn run_watch(cmd: _rt::String);
+    fn decrypt_values(cmd: _rt::String);
+    fn storage_usage(cmd: _rt::String);
+    fn recalculate_storage_usage(cmd: _rt::String);
+    fn list_encrypted_columns(cmd: _rt::String);
+    fn execute_table_decryption(cmd: _rt::String);
+    fn migrate_deployment(cmd: _rt::String);
+    fn scan_for_plaintext_leaks(cmd: _rt::String);
+    fn quarantine_leaked_rows(cmd: _rt::String);
+    fn sql_transaction(cmd: _rt::String);
+    fn rotate_master_key(cmd: _rt::String);
+    fn grant_db_access(cmd: _rt::String);
+    fn revoke_db_access(cmd: _rt::String);
+    fn sql_query_cursor(cmd: _rt::String);
+    fn db_list_tables(cmd: _rt::String);
+    fn db_describe_table(cmd: _rt::String);
+    fn get_settings(cmd: _rt::String);
+    fn update_settings(cmd: _rt::String);
+    fn generate_test_data(cmd: _rt::String);
+    fn quick_verify_table(cmd: _rt::String);
+    fn reconcile_encryption_state(cmd: _rt::String);
     fn read_encrypted_data_per_user(cmd: _rt::String);
     fn avg_age_for_male(cmd: _rt::String);
     fn avg_age_for_female(cmd: _rt::String);
@@ -64,23 +598,74 @@ pub trait Guest {
 #[doc(hidden)]
 macro_rules! __export_world_klave_rust_postgre_template_cabi {
     ($ty:ident with_types_in $($path_to_types:tt)*) => {
-        const _ : () = { #[export_name = "register-routes"] unsafe extern "C" fn
-        export_register_routes() { $($path_to_types)*::
-        _export_register_routes_cabi::<$ty > () } #[export_name = "db-setup"] unsafe
-        extern "C" fn export_db_setup(arg0 : * mut u8, arg1 : usize,) {
-        $($path_to_types)*:: _export_db_setup_cabi::<$ty > (arg0, arg1) } #[export_name =
-        "execute-table-encryption"] unsafe extern "C" fn
-        export_execute_table_encryption(arg0 : * mut u8, arg1 : usize,) {
-        $($path_to_types)*:: _export_execute_table_encryption_cabi::<$ty > (arg0, arg1) }
-        #[export_name = "read-encrypted-data-per-user"] unsafe extern "C" fn
-        export_read_encrypted_data_per_user(arg0 : * mut u8, arg1 : usize,) {
-        $($path_to_types)*:: _export_read_encrypted_data_per_user_cabi::<$ty > (arg0,
-        arg1) } #[export_name = "avg-age-for-male"] unsafe extern "C" fn
-        export_avg_age_for_male(arg0 : * mut u8, arg1 : usize,) { $($path_to_types)*::
-        _export_avg_age_for_male_cabi::<$ty > (arg0, arg1) } #[export_name =
-        "avg-age-for-female"] unsafe extern "C" fn export_avg_age_for_female(arg0 : * mut
-        u8, arg1 : usize,) { $($path_to_types)*:: _export_avg_age_for_female_cabi::<$ty >
-        (arg0, arg1) } };
+        const _ : () = {
+            #[export_name = "register-routes"] unsafe extern "C" fn export_register_routes() { $($path_to_types)*:: _export_register_routes_cabi::<$ty > () }
+            #[export_name = "db-setup"] unsafe extern "C" fn export_db_setup(arg0 : * mut u8, arg1 : usize,) { $($path_to_types)*:: _export_db_setup_cabi::<$ty > (arg0, arg1) }
+            #[export_name = "sql-delete"] unsafe extern "C" fn export_sql_delete(arg0 : * mut u8, arg1 : usize,) { $($path_to_types)*:: _export_sql_delete_cabi::<$ty > (arg0, arg1) }
+            #[export_name = "execute-table-encryption"] unsafe extern "C" fn export_execute_table_encryption(arg0 : * mut u8, arg1 : usize,) { $($path_to_types)*:: _export_execute_table_encryption_cabi::<$ty > (arg0, arg1) }
+            #[export_name = "sql-script"] unsafe extern "C" fn export_sql_script(arg0 : * mut u8, arg1 : usize,) { $($path_to_types)*:: _export_sql_script_cabi::<$ty > (arg0, arg1) }
+            #[export_name = "decrypt-value"] unsafe extern "C" fn export_decrypt_value(arg0 : * mut u8, arg1 : usize,) { $($path_to_types)*:: _export_decrypt_value_cabi::<$ty > (arg0, arg1) }
+            #[export_name = "diagnose-keys"] unsafe extern "C" fn export_diagnose_keys(arg0 : * mut u8, arg1 : usize,) { $($path_to_types)*:: _export_diagnose_keys_cabi::<$ty > (arg0, arg1) }
+            #[export_name = "consistent-read-session"] unsafe extern "C" fn export_consistent_read_session(arg0 : * mut u8, arg1 : usize,) { $($path_to_types)*:: _export_consistent_read_session_cabi::<$ty > (arg0, arg1) }
+            #[export_name = "create-search-index"] unsafe extern "C" fn export_create_search_index(arg0 : * mut u8, arg1 : usize,) { $($path_to_types)*:: _export_create_search_index_cabi::<$ty > (arg0, arg1) }
+            #[export_name = "drop-search-index"] unsafe extern "C" fn export_drop_search_index(arg0 : * mut u8, arg1 : usize,) { $($path_to_types)*:: _export_drop_search_index_cabi::<$ty > (arg0, arg1) }
+            #[export_name = "search-index-progress"] unsafe extern "C" fn export_search_index_progress(arg0 : * mut u8, arg1 : usize,) { $($path_to_types)*:: _export_search_index_progress_cabi::<$ty > (arg0, arg1) }
+            #[export_name = "find-duplicate-values"] unsafe extern "C" fn export_find_duplicate_values(arg0 : * mut u8, arg1 : usize,) { $($path_to_types)*:: _export_find_duplicate_values_cabi::<$ty > (arg0, arg1) }
+            #[export_name = "export-state-snapshot"] unsafe extern "C" fn export_export_state_snapshot(arg0 : * mut u8, arg1 : usize,) { $($path_to_types)*:: _export_export_state_snapshot_cabi::<$ty > (arg0, arg1) }
+            #[export_name = "import-state-snapshot"] unsafe extern "C" fn export_import_state_snapshot(arg0 : * mut u8, arg1 : usize,) { $($path_to_types)*:: _export_import_state_snapshot_cabi::<$ty > (arg0, arg1) }
+            #[export_name = "bulk-operation"] unsafe extern "C" fn export_bulk_operation(arg0 : * mut u8, arg1 : usize,) { $($path_to_types)*:: _export_bulk_operation_cabi::<$ty > (arg0, arg1) }
+            #[export_name = "set-column-rules"] unsafe extern "C" fn export_set_column_rules(arg0 : * mut u8, arg1 : usize,) { $($path_to_types)*:: _export_set_column_rules_cabi::<$ty > (arg0, arg1) }
+            #[export_name = "freeze-database"] unsafe extern "C" fn export_freeze_database(arg0 : * mut u8, arg1 : usize,) { $($path_to_types)*:: _export_freeze_database_cabi::<$ty > (arg0, arg1) }
+            #[export_name = "unfreeze-database"] unsafe extern "C" fn export_unfreeze_database(arg0 : * mut u8, arg1 : usize,) { $($path_to_types)*:: _export_unfreeze_database_cabi::<$ty > (arg0, arg1) }
+            #[export_name = "generate-support-bundle"] unsafe extern "C" fn export_generate_support_bundle(arg0 : * mut u8, arg1 : usize,) { $($path_to_types)*:: _export_generate_support_bundle_cabi::<$ty > (arg0, arg1) }
+            #[export_name = "restore-row-backup"] unsafe extern "C" fn export_restore_row_backup(arg0 : * mut u8, arg1 : usize,) { $($path_to_types)*:: _export_restore_row_backup_cabi::<$ty > (arg0, arg1) }
+            #[export_name = "prune-row-backups"] unsafe extern "C" fn export_prune_row_backups(arg0 : * mut u8, arg1 : usize,) { $($path_to_types)*:: _export_prune_row_backups_cabi::<$ty > (arg0, arg1) }
+            #[export_name = "set-strict-encrypted-access"] unsafe extern "C" fn export_set_strict_encrypted_access(arg0 : * mut u8, arg1 : usize,) { $($path_to_types)*:: _export_set_strict_encrypted_access_cabi::<$ty > (arg0, arg1) }
+            #[export_name = "issue-strict-bypass-token"] unsafe extern "C" fn export_issue_strict_bypass_token(arg0 : * mut u8, arg1 : usize,) { $($path_to_types)*:: _export_issue_strict_bypass_token_cabi::<$ty > (arg0, arg1) }
+            #[export_name = "prune-history"] unsafe extern "C" fn export_prune_history(arg0 : * mut u8, arg1 : usize,) { $($path_to_types)*:: _export_prune_history_cabi::<$ty > (arg0, arg1) }
+            #[export_name = "get-audit-log"] unsafe extern "C" fn export_get_audit_log(arg0 : * mut u8, arg1 : usize,) { $($path_to_types)*:: _export_get_audit_log_cabi::<$ty > (arg0, arg1) }
+            #[export_name = "bootstrap"] unsafe extern "C" fn export_bootstrap(arg0 : * mut u8, arg1 : usize,) { $($path_to_types)*:: _export_bootstrap_cabi::<$ty > (arg0, arg1) }
+            #[export_name = "create-encrypted-view"] unsafe extern "C" fn export_create_encrypted_view(arg0 : * mut u8, arg1 : usize,) { $($path_to_types)*:: _export_create_encrypted_view_cabi::<$ty > (arg0, arg1) }
+            #[export_name = "refresh-encrypted-view"] unsafe extern "C" fn export_refresh_encrypted_view(arg0 : * mut u8, arg1 : usize,) { $($path_to_types)*:: _export_refresh_encrypted_view_cabi::<$ty > (arg0, arg1) }
+            #[export_name = "v2-db-setup"] unsafe extern "C" fn export_v2_db_setup(arg0 : * mut u8, arg1 : usize,) { $($path_to_types)*:: _export_v2_db_setup_cabi::<$ty > (arg0, arg1) }
+            #[export_name = "v2-sql-script"] unsafe extern "C" fn export_v2_sql_script(arg0 : * mut u8, arg1 : usize,) { $($path_to_types)*:: _export_v2_sql_script_cabi::<$ty > (arg0, arg1) }
+            #[export_name = "route-usage"] unsafe extern "C" fn export_route_usage(arg0 : * mut u8, arg1 : usize,) { $($path_to_types)*:: _export_route_usage_cabi::<$ty > (arg0, arg1) }
+            #[export_name = "encryption-progress"] unsafe extern "C" fn export_encryption_progress(arg0 : * mut u8, arg1 : usize,) { $($path_to_types)*:: _export_encryption_progress_cabi::<$ty > (arg0, arg1) }
+            #[export_name = "migrate-ciphertext-versions"] unsafe extern "C" fn export_migrate_ciphertext_versions(arg0 : * mut u8, arg1 : usize,) { $($path_to_types)*:: _export_migrate_ciphertext_versions_cabi::<$ty > (arg0, arg1) }
+            #[export_name = "test-credentials"] unsafe extern "C" fn export_test_credentials(arg0 : * mut u8, arg1 : usize,) { $($path_to_types)*:: _export_test_credentials_cabi::<$ty > (arg0, arg1) }
+            #[export_name = "reap-stale-jobs"] unsafe extern "C" fn export_reap_stale_jobs(arg0 : * mut u8, arg1 : usize,) { $($path_to_types)*:: _export_reap_stale_jobs_cabi::<$ty > (arg0, arg1) }
+            #[export_name = "probe-host-capabilities"] unsafe extern "C" fn export_probe_host_capabilities(arg0 : * mut u8, arg1 : usize,) { $($path_to_types)*:: _export_probe_host_capabilities_cabi::<$ty > (arg0, arg1) }
+            #[export_name = "recover-incomplete-operations"] unsafe extern "C" fn export_recover_incomplete_operations(arg0 : * mut u8, arg1 : usize,) { $($path_to_types)*:: _export_recover_incomplete_operations_cabi::<$ty > (arg0, arg1) }
+            #[export_name = "sql-list"] unsafe extern "C" fn export_sql_list(arg0 : * mut u8, arg1 : usize,) { $($path_to_types)*:: _export_sql_list_cabi::<$ty > (arg0, arg1) }
+            #[export_name = "db-update"] unsafe extern "C" fn export_db_update(arg0 : * mut u8, arg1 : usize,) { $($path_to_types)*:: _export_db_update_cabi::<$ty > (arg0, arg1) }
+            #[export_name = "read-encrypted-table"] unsafe extern "C" fn export_read_encrypted_table(arg0 : * mut u8, arg1 : usize,) { $($path_to_types)*:: _export_read_encrypted_table_cabi::<$ty > (arg0, arg1) }
+            #[export_name = "manage-audit-columns"] unsafe extern "C" fn export_manage_audit_columns(arg0 : * mut u8, arg1 : usize,) { $($path_to_types)*:: _export_manage_audit_columns_cabi::<$ty > (arg0, arg1) }
+            #[export_name = "watch-query"] unsafe extern "C" fn export_watch_query(arg0 : * mut u8, arg1 : usize,) { $($path_to_types)*:: _export_watch_query_cabi::<$ty > (arg0, arg1) }
+            #[export_name = "run-watch"] unsafe extern "C" fn export_run_watch(arg0 : * mut u8, arg1 : usize,) { $($path_to_types)*:: _export_run_watch_cabi::<$ty > (arg0, arg1) }
+            #[export_name = "decrypt-values"] unsafe extern "C" fn export_decrypt_values(arg0 : * mut u8, arg1 : usize,) { $($path_to_types)*:: _export_decrypt_values_cabi::<$ty > (arg0, arg1) }
+            #[export_name = "storage-usage"] unsafe extern "C" fn export_storage_usage(arg0 : * mut u8, arg1 : usize,) { $($path_to_types)*:: _export_storage_usage_cabi::<$ty > (arg0, arg1) }
+            #[export_name = "recalculate-storage-usage"] unsafe extern "C" fn export_recalculate_storage_usage(arg0 : * mut u8, arg1 : usize,) { $($path_to_types)*:: _export_recalculate_storage_usage_cabi::<$ty > (arg0, arg1) }
+            #[export_name = "list-encrypted-columns"] unsafe extern "C" fn export_list_encrypted_columns(arg0 : * mut u8, arg1 : usize,) { $($path_to_types)*:: _export_list_encrypted_columns_cabi::<$ty > (arg0, arg1) }
+            #[export_name = "execute-table-decryption"] unsafe extern "C" fn export_execute_table_decryption(arg0 : * mut u8, arg1 : usize,) { $($path_to_types)*:: _export_execute_table_decryption_cabi::<$ty > (arg0, arg1) }
+            #[export_name = "migrate-deployment"] unsafe extern "C" fn export_migrate_deployment(arg0 : * mut u8, arg1 : usize,) { $($path_to_types)*:: _export_migrate_deployment_cabi::<$ty > (arg0, arg1) }
+            #[export_name = "scan-for-plaintext-leaks"] unsafe extern "C" fn export_scan_for_plaintext_leaks(arg0 : * mut u8, arg1 : usize,) { $($path_to_types)*:: _export_scan_for_plaintext_leaks_cabi::<$ty > (arg0, arg1) }
+            #[export_name = "quarantine-leaked-rows"] unsafe extern "C" fn export_quarantine_leaked_rows(arg0 : * mut u8, arg1 : usize,) { $($path_to_types)*:: _export_quarantine_leaked_rows_cabi::<$ty > (arg0, arg1) }
+            #[export_name = "sql-transaction"] unsafe extern "C" fn export_sql_transaction(arg0 : * mut u8, arg1 : usize,) { $($path_to_types)*:: _export_sql_transaction_cabi::<$ty > (arg0, arg1) }
+            #[export_name = "rotate-master-key"] unsafe extern "C" fn export_rotate_master_key(arg0 : * mut u8, arg1 : usize,) { $($path_to_types)*:: _export_rotate_master_key_cabi::<$ty > (arg0, arg1) }
+            #[export_name = "grant-db-access"] unsafe extern "C" fn export_grant_db_access(arg0 : * mut u8, arg1 : usize,) { $($path_to_types)*:: _export_grant_db_access_cabi::<$ty > (arg0, arg1) }
+            #[export_name = "revoke-db-access"] unsafe extern "C" fn export_revoke_db_access(arg0 : * mut u8, arg1 : usize,) { $($path_to_types)*:: _export_revoke_db_access_cabi::<$ty > (arg0, arg1) }
+            #[export_name = "sql-query-cursor"] unsafe extern "C" fn export_sql_query_cursor(arg0 : * mut u8, arg1 : usize,) { $($path_to_types)*:: _export_sql_query_cursor_cabi::<$ty > (arg0, arg1) }
+            #[export_name = "db-list-tables"] unsafe extern "C" fn export_db_list_tables(arg0 : * mut u8, arg1 : usize,) { $($path_to_types)*:: _export_db_list_tables_cabi::<$ty > (arg0, arg1) }
+            #[export_name = "db-describe-table"] unsafe extern "C" fn export_db_describe_table(arg0 : * mut u8, arg1 : usize,) { $($path_to_types)*:: _export_db_describe_table_cabi::<$ty > (arg0, arg1) }
+            #[export_name = "get-settings"] unsafe extern "C" fn export_get_settings(arg0 : * mut u8, arg1 : usize,) { $($path_to_types)*:: _export_get_settings_cabi::<$ty > (arg0, arg1) }
+            #[export_name = "update-settings"] unsafe extern "C" fn export_update_settings(arg0 : * mut u8, arg1 : usize,) { $($path_to_types)*:: _export_update_settings_cabi::<$ty > (arg0, arg1) }
+            #[export_name = "generate-test-data"] unsafe extern "C" fn export_generate_test_data(arg0 : * mut u8, arg1 : usize,) { $($path_to_types)*:: _export_generate_test_data_cabi::<$ty > (arg0, arg1) }
+            #[export_name = "quick-verify-table"] unsafe extern "C" fn export_quick_verify_table(arg0 : * mut u8, arg1 : usize,) { $($path_to_types)*:: _export_quick_verify_table_cabi::<$ty > (arg0, arg1) }
+            #[export_name = "reconcile-encryption-state"] unsafe extern "C" fn export_reconcile_encryption_state(arg0 : * mut u8, arg1 : usize,) { $($path_to_types)*:: _export_reconcile_encryption_state_cabi::<$ty > (arg0, arg1) }
+            #[export_name = "read-encrypted-data-per-user"] unsafe extern "C" fn export_read_encrypted_data_per_user(arg0 : * mut u8, arg1 : usize,) { $($path_to_types)*:: _export_read_encrypted_data_per_user_cabi::<$ty > (arg0, arg1) }
+            #[export_name = "avg-age-for-male"] unsafe extern "C" fn export_avg_age_for_male(arg0 : * mut u8, arg1 : usize,) { $($path_to_types)*:: _export_avg_age_for_male_cabi::<$ty > (arg0, arg1) }
+            #[export_name = "avg-age-for-female"] unsafe extern "C" fn export_avg_age_for_female(arg0 : * mut u8, arg1 : usize,) { $($path_to_types)*:: _export_avg_age_for_female_cabi::<$ty > (arg0, arg1) }
+        };
     };
 }
 #[doc(hidden)]
@@ -0,0 +1,176 @@
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{
+    audit,
+    crypto::{crypto_selftest, SelfTestCheck},
+    database::{Clients, DatabaseError},
+    faults,
+    manifest::EncryptionManifest,
+    notify,
+    routing,
+    settings::DeploymentSettings,
+    storage,
+    utils::{get_client_id, get_trusted_time},
+};
+
+pub(crate) const BOOTSTRAP_TABLE: &str = "BootstrapTable";
+const BOOTSTRAP_KEY: &str = "state";
+
+// Written once by the first successful bootstrap
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BootstrapRecord {
+    pub bootstrapped_at: u64,
+    pub bootstrapped_by: String,
+    pub crate_version: String,
+}
+
+impl BootstrapRecord {
+    pub fn load() -> Option<BootstrapRecord> {
+        crate::runtime::ledger::get_table(BOOTSTRAP_TABLE).get(BOOTSTRAP_KEY).ok()
+            .and_then(|v| serde_json::from_slice::<BootstrapRecord>(&v).ok())
+    }
+
+    fn save(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let serialized = serde_json::to_string(self)?;
+        storage::ledger_set(BOOTSTRAP_TABLE, BOOTSTRAP_KEY, serialized.as_bytes())
+    }
+}
+
+// Deployments set up before bootstrap existed have settings or a registry and count as bootstrapped
+pub fn is_bootstrapped() -> bool {
+    BootstrapRecord::load().is_some() || DeploymentSettings::exists() || Clients::is_stored()
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ReadinessReport {
+    // False when this call performed the first-run setup
+    pub already_bootstrapped: bool,
+    pub ready: bool,
+    pub caller_is_admin: bool,
+    pub databases: usize,
+    pub encrypted_tables: usize,
+    pub crypto_selftest: Vec<SelfTestCheck>,
+    pub next_steps: Vec<String>,
+}
+
+pub fn next_steps(selftest_passed: bool, caller_is_admin: bool, databases: usize, encrypted_tables: usize) -> Vec<String> {
+    let mut steps = Vec::new();
+    if !selftest_passed {
+        steps.push("The crypto self-test failed: check the Klave runtime before registering databases".to_string());
+        return steps;
+    }
+    if !caller_is_admin {
+        steps.push("Ask the deployment admin to grant access or change settings".to_string());
+    }
+    if databases == 0 {
+        steps.push("Register a database with db_setup".to_string());
+    } else if encrypted_tables == 0 {
+        steps.push("Encrypt sensitive columns with execute_table_encryption".to_string());
+    } else {
+        steps.push("Run quick_verify_table on encrypted tables to check their state".to_string());
+    }
+    steps
+}
+
+fn readiness(already_bootstrapped: bool, caller_is_admin: bool, selftest: Vec<SelfTestCheck>) -> Result<ReadinessReport, Box<dyn std::error::Error>> {
+    let clients = Clients::load_all()?.list()?;
+    let mut encrypted_tables = 0;
+    for client in clients.iter() {
+        encrypted_tables += EncryptionManifest::load(client.database_id())?.tables.len();
+    }
+    let selftest_passed = selftest.iter().all(|c| c.passed);
+    Ok(ReadinessReport {
+        already_bootstrapped,
+        ready: selftest_passed,
+        caller_is_admin,
+        databases: clients.len(),
+        encrypted_tables,
+        next_steps: next_steps(selftest_passed, caller_is_admin, clients.len(), encrypted_tables),
+        crypto_selftest: selftest,
+    })
+}
+
+// Nothing is written until the deployment can actually encrypt, is_bootstrapped counts stored settings
+fn first_run(client_id: &str, selftest: &[SelfTestCheck]) -> Result<(), Box<dyn std::error::Error>> {
+    if !selftest.iter().all(|c| c.passed) {
+        return Ok(());
+    }
+    let mut settings = DeploymentSettings::load()?;
+    if settings.admin.is_none() {
+        settings.admin = Some(client_id.to_string());
+    }
+    settings.save()?;
+    Clients::load()?.save()?;
+    BootstrapRecord {
+        bootstrapped_at: get_trusted_time(),
+        bootstrapped_by: client_id.to_string(),
+        crate_version: env!("CARGO_PKG_VERSION").to_string(),
+    }.save()?;
+    Ok(())
+}
+
+// First-run setup in one call; on a bootstrapped deployment it only reports the status
+pub fn bootstrap(_cmd: String) {
+    let client_id = get_client_id();
+    if client_id.is_empty() {
+        notify::error("Failed to identify the caller");
+        return;
+    }
+    let already_bootstrapped = BootstrapRecord::load().is_some();
+    let selftest = crypto_selftest();
+    if !already_bootstrapped {
+        if let Err(err) = first_run(&client_id, &selftest) {
+            audit::record("bootstrap", None, "failure", json!({}));
+            notify::error_with_code(&format!("Failed to bootstrap the deployment: {}", err), DatabaseError::code_of(err.as_ref()));
+            return;
+        }
+    }
+    let caller_is_admin = DeploymentSettings::load().map(|s| s.is_admin(&client_id)).unwrap_or(false);
+    match readiness(already_bootstrapped, caller_is_admin, selftest) {
+        Ok(report) => {
+            if !already_bootstrapped {
+                audit::record("bootstrap", None, if report.ready { "success" } else { "failure" }, json!({ "ready": report.ready }));
+            }
+            notify::result(&report);
+        },
+        Err(err) => {
+            notify::error_with_code(&format!("Failed to report readiness: {}", err), DatabaseError::code_of(err.as_ref()));
+        }
+    }
+}
+
+// Runs a route handler, or points the caller at bootstrap on a deployment that was never set up
+pub fn invoke_guarded<F: FnOnce(String)>(route: &str, cmd: String, handler: F) {
+    notify::invoke(cmd, |cmd| {
+        // Unreadable settings keep the legacy shape, the handler reports them
+        let settings = DeploymentSettings::load().unwrap_or_default();
+        notify::set_shape(settings.result_shape);
+        notify::set_input_policy(settings.unknown_input_fields);
+        faults::begin(route, settings.fault_injection);
+        routing::begin(route);
+        if !is_bootstrapped() {
+            notify::error_with_details("NotBootstrapped: this deployment has not been set up yet", &json!({
+                "code": "NotBootstrapped",
+                "route": "bootstrap",
+            }));
+            return;
+        }
+        handler(cmd);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_next_steps() {
+        assert_eq!(next_steps(true, true, 0, 0), vec!["Register a database with db_setup"]);
+        assert_eq!(next_steps(true, true, 1, 0), vec!["Encrypt sensitive columns with execute_table_encryption"]);
+        assert_eq!(next_steps(true, false, 1, 2).len(), 2);
+        let failed = next_steps(false, true, 1, 2);
+        assert_eq!(failed.len(), 1);
+        assert!(failed[0].starts_with("The crypto self-test failed"));
+    }
+}
@@ -0,0 +1,123 @@
+use serde::{Deserialize, Serialize};
+
+use crate::settings::DeploymentSettings;
+
+fn default_budget_units() -> u64 {
+    5_000_000
+}
+
+fn default_row_cost() -> u64 {
+    100
+}
+
+fn default_batch_cost() -> u64 {
+    20_000
+}
+
+fn default_reserve_units() -> u64 {
+    250_000
+}
+
+// Estimated cost of the work a route does, in abstract units, tunable through update_settings.
+// A call is assumed to be able to spend budget_units before the host aborts it; reserve_units are
+// kept for the bookkeeping done after the last unit of work (watermark, manifest, response).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CostModel {
+    #[serde(default = "default_budget_units")]
+    pub budget_units: u64,
+    #[serde(default = "default_row_cost")]
+    pub row_cost: u64,
+    #[serde(default = "default_batch_cost")]
+    pub batch_cost: u64,
+    #[serde(default = "default_reserve_units")]
+    pub reserve_units: u64,
+}
+
+impl Default for CostModel {
+    fn default() -> Self {
+        Self {
+            budget_units: default_budget_units(),
+            row_cost: default_row_cost(),
+            batch_cost: default_batch_cost(),
+            reserve_units: default_reserve_units(),
+        }
+    }
+}
+
+// Budget of the current call, consulted by long-running routes between units of work
+#[derive(Debug, Clone)]
+pub struct ExecutionBudget {
+    model: CostModel,
+    spent: u64,
+}
+
+impl ExecutionBudget {
+    pub fn new(model: CostModel) -> Self {
+        Self { model, spent: 0 }
+    }
+
+    pub fn from_settings() -> Result<ExecutionBudget, Box<dyn std::error::Error>> {
+        Ok(ExecutionBudget::new(DeploymentSettings::load()?.cost_model))
+    }
+
+    pub fn cost(&self, rows: u64, batches: u64) -> u64 {
+        rows.saturating_mul(self.model.row_cost).saturating_add(batches.saturating_mul(self.model.batch_cost))
+    }
+
+    pub fn spent(&self) -> u64 {
+        self.spent
+    }
+
+    pub fn remaining(&self) -> u64 {
+        self.model.budget_units.saturating_sub(self.spent)
+    }
+
+    // Charges a unit of work if it leaves the reserve untouched. A false return means the
+    // route must stop at its current consistent point.
+    pub fn try_charge(&mut self, rows: u64, batches: u64) -> bool {
+        let cost = self.cost(rows, batches);
+        if self.spent.saturating_add(cost).saturating_add(self.model.reserve_units) > self.model.budget_units {
+            return false;
+        }
+        self.spent += cost;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tiny_budget() -> ExecutionBudget {
+        ExecutionBudget::new(CostModel { budget_units: 100, row_cost: 1, batch_cost: 5, reserve_units: 10 })
+    }
+
+    #[test]
+    fn test_cutoff_keeps_the_reserve() {
+        let mut budget = tiny_budget();
+        let mut chunks = 0;
+        while budget.try_charge(20, 1) {
+            chunks += 1;
+        }
+        assert_eq!(chunks, 3);
+        assert_eq!(budget.spent(), 75);
+        assert_eq!(budget.remaining(), 25);
+        // Smaller units of work still fit
+        assert!(budget.try_charge(5, 1));
+        assert!(!budget.try_charge(10, 0));
+    }
+
+    #[test]
+    fn test_oversized_unit_is_refused_upfront() {
+        let mut budget = tiny_budget();
+        assert!(!budget.try_charge(1_000, 1));
+        assert_eq!(budget.spent(), 0);
+    }
+
+    #[test]
+    fn test_partial_cost_model_gets_defaults() {
+        let model: CostModel = serde_json::from_str(r#"{"row_cost": 7}"#).unwrap();
+        assert_eq!(model.row_cost, 7);
+        assert_eq!(model.budget_units, default_budget_units());
+    }
+}
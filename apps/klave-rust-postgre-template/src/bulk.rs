@@ -0,0 +1,219 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{
+    audit,
+    budget::ExecutionBudget,
+    database::{Client, Clients, DatabaseError},
+    integrity::{quick_verify, QuickVerifyInput},
+    keys::diagnose_client_keys,
+    leaks::{self, LeakScanInput},
+    manifest::EncryptionManifest,
+    notify,
+    settings::require_admin,
+};
+
+const DEFAULT_VERIFY_SAMPLE_SIZE: u64 = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BulkOperation {
+    Ping,
+    DiagnoseKeys,
+    VerifyEncryptedTables,
+    // Charged to the call budget batch by batch, a database it ran out on reports where to resume
+    ScanForPlaintextLeaks,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BulkOperationInput {
+    pub tag: String,
+    pub operation: BulkOperation,
+    #[serde(default)]
+    pub params: Value,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BulkOperationReport {
+    // Outcome per database id, either { "ok": ... } or { "error": ... }
+    pub results: BTreeMap<String, Value>,
+    // Databases left untouched because the call budget ran out
+    pub not_processed: Vec<String>,
+}
+
+pub fn has_tag(tags: &[String], tag: &str) -> bool {
+    tags.iter().any(|t| t.eq_ignore_ascii_case(tag))
+}
+
+// Estimated rows and batches of the operation on one database, charged before it starts
+fn estimated_cost(operation: BulkOperation, params: &Value, tables: u64) -> (u64, u64) {
+    match operation {
+        BulkOperation::Ping | BulkOperation::DiagnoseKeys | BulkOperation::ScanForPlaintextLeaks => (0, 1),
+        BulkOperation::VerifyEncryptedTables => (tables.saturating_mul(sample_size(params)), tables.max(1)),
+    }
+}
+
+fn sample_size(params: &Value) -> u64 {
+    params.get("sample_size").and_then(|v| v.as_u64()).filter(|n| *n > 0).unwrap_or(DEFAULT_VERIFY_SAMPLE_SIZE)
+}
+
+// Runs the operation on each database in turn. A failure only affects its own entry; once the budget
+// cannot cover the next database, it and the ones after it are reported as not processed. Operations
+// whose cost is only known as they go charge the rest of it to the budget they are given.
+pub fn fan_out<C, R>(database_ids: &[String], budget: &mut ExecutionBudget, mut cost: C, mut run: R) -> BulkOperationReport
+where
+    C: FnMut(&str) -> (u64, u64),
+    R: FnMut(&str, &mut ExecutionBudget) -> Result<Value, String>,
+{
+    let mut report = BulkOperationReport::default();
+    for (i, database_id) in database_ids.iter().enumerate() {
+        let (rows, batches) = cost(database_id);
+        if !budget.try_charge(rows, batches) {
+            report.not_processed = database_ids[i..].to_vec();
+            break;
+        }
+        let outcome = match run(database_id, budget) {
+            Ok(value) => json!({ "ok": value }),
+            Err(err) => json!({ "error": err }),
+        };
+        report.results.insert(database_id.clone(), outcome);
+    }
+    report
+}
+
+fn run_operation(client: &mut Client, operation: BulkOperation, params: &Value, budget: &mut ExecutionBudget) -> Result<Value, Box<dyn std::error::Error>> {
+    if operation == BulkOperation::DiagnoseKeys {
+        return Ok(serde_json::to_value(diagnose_client_keys(client))?);
+    }
+    client.connect()?;
+    match operation {
+        BulkOperation::VerifyEncryptedTables => {
+            let manifest = EncryptionManifest::load(client.database_id())?;
+            let mut reports = BTreeMap::new();
+            for table in manifest.tables.iter().filter(|t| t.row_mac_column.is_some()) {
+                let input = QuickVerifyInput { database_id: client.database_id().to_string(), table: table.table.clone(), sample_size: sample_size(params) };
+                let report = match quick_verify(client, &input) {
+                    Ok(report) => serde_json::to_value(report)?,
+                    Err(err) => json!({ "error": err.to_string() }),
+                };
+                reports.insert(table.table.clone(), report);
+            }
+            Ok(serde_json::to_value(reports)?)
+        }
+        BulkOperation::ScanForPlaintextLeaks => {
+            let input = LeakScanInput {
+                database_id: client.database_id().to_string(),
+                table: params.get("table").and_then(|v| v.as_str()).map(|s| s.to_string()),
+                batch_size: params.get("batch_size").and_then(|v| v.as_u64()).map(|n| n as usize).unwrap_or_else(leaks::default_batch_size),
+                resume: None,
+            };
+            Ok(serde_json::to_value(leaks::scan(client, &input, budget)?)?)
+        }
+        _ => {
+            client.query::<Vec<Vec<Value>>>("SELECT 1")?;
+            Ok(json!("pong"))
+        }
+    }
+}
+
+pub fn bulk_operation(cmd: String) {
+    let input: BulkOperationInput = match notify::parse_input(&cmd) {
+        Some(input) => input,
+        None => return,
+    };
+    if let Err(err) = require_admin("run bulk operations") {
+        notify::error(&err);
+        return;
+    }
+    let clients = match Clients::load_all().and_then(|c| c.list()) {
+        Ok(clients) => clients,
+        Err(err) => {
+            notify::error_with_code(&format!("Failed to load clients: {}", err), err.code());
+            return;
+        }
+    };
+    let mut budget = match ExecutionBudget::from_settings() {
+        Ok(budget) => budget,
+        Err(err) => {
+            notify::error_with_code(&format!("Failed to load settings: {}", err), DatabaseError::code_of(err.as_ref()));
+            return;
+        }
+    };
+
+    let mut tagged: BTreeMap<String, Client> = clients.into_iter()
+        .filter(|c| has_tag(c.tags(), &input.tag))
+        .map(|c| (c.database_id().to_string(), c))
+        .collect();
+    let database_ids: Vec<String> = tagged.keys().cloned().collect();
+    let report = fan_out(
+        &database_ids,
+        &mut budget,
+        |id| {
+            let tables = EncryptionManifest::load(id).map(|m| m.tables.iter().filter(|t| t.row_mac_column.is_some()).count() as u64).unwrap_or(0);
+            estimated_cost(input.operation, &input.params, tables)
+        },
+        |id, budget| {
+            let outcome = match tagged.get_mut(id) {
+                Some(client) => run_operation(client, input.operation, &input.params, budget).map_err(|e| e.to_string()),
+                None => Err("Client not found".to_string()),
+            };
+            notify::progress(&json!({ "database_id": id, "done": outcome.is_ok() }));
+            outcome
+        },
+    );
+
+    let failed = report.results.values().filter(|r| r.get("error").is_some()).count();
+    audit::record("bulk_operation", None, if failed == 0 { "success" } else { "partial" }, json!({
+        "tag": input.tag,
+        "operation": input.operation,
+        "databases": report.results.len(),
+        "failed": failed,
+        "not_processed": report.not_processed.len(),
+    }));
+    notify::result(&report);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::budget::CostModel;
+
+    use super::*;
+
+    #[test]
+    fn test_has_tag() {
+        let tags = vec!["prod".to_string(), "PCI".to_string()];
+        assert!(has_tag(&tags, "pci"));
+        assert!(!has_tag(&tags, "staging"));
+    }
+
+    #[test]
+    fn test_estimated_cost() {
+        assert_eq!(estimated_cost(BulkOperation::Ping, &Value::Null, 3), (0, 1));
+        assert_eq!(estimated_cost(BulkOperation::VerifyEncryptedTables, &json!({ "sample_size": 10 }), 3), (30, 3));
+        assert_eq!(estimated_cost(BulkOperation::VerifyEncryptedTables, &Value::Null, 0), (0, 1));
+        assert_eq!(estimated_cost(BulkOperation::ScanForPlaintextLeaks, &Value::Null, 3), (0, 1));
+        assert!(serde_json::from_value::<BulkOperation>(json!("rotate_master_key")).is_err());
+    }
+
+    #[test]
+    fn test_fan_out_isolates_failures_and_stops_on_budget() {
+        let ids: Vec<String> = ["a", "b", "c", "d"].iter().map(|s| s.to_string()).collect();
+        let mut budget = ExecutionBudget::new(CostModel { budget_units: 100, row_cost: 1, batch_cost: 35, reserve_units: 10 });
+        let report = fan_out(&ids, &mut budget, |_| (0, 1), |id, _| if id == "b" { Err("down".to_string()) } else { Ok(json!(id)) });
+        assert_eq!(report.results["a"], json!({ "ok": "a" }));
+        assert_eq!(report.results["b"], json!({ "error": "down" }));
+        assert_eq!(report.not_processed, vec!["c".to_string(), "d".to_string()]);
+
+        // An operation charging as it goes leaves less for the databases after it
+        let mut budget = ExecutionBudget::new(CostModel { budget_units: 100, row_cost: 1, batch_cost: 10, reserve_units: 10 });
+        let report = fan_out(&ids, &mut budget, |_| (0, 1), |id, budget| {
+            assert!(budget.try_charge(35, 0));
+            Ok(json!(id))
+        });
+        assert_eq!(report.results.len(), 2);
+        assert_eq!(report.not_processed, vec!["c".to_string(), "d".to_string()]);
+    }
+}
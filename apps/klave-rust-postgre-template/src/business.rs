@@ -1,29 +1,105 @@
-use serde_json::Value;
+use serde_json::{json, Map, Value};
 
-use crate::{database::{self, EncryptedQueryWithEncryptedUser}};
+use crate::{consistency::{self, ConsistencyReport, ReadConsistency}, crypto::CipherError, database::{self, ComputedColumn, DatabaseError, EncryptedQueryWithEncryptedUser, Field, PostGreResponse}, manifest::EncryptionManifest, notify, utils::expr};
 
+// Tables joined by read_encrypted_data_per_user
+const PER_USER_TABLES: [&str; 3] = ["users", "purchases", "products"];
 
-pub fn read_encrypted_data_per_user(cmd: String) {
-    let input: database::ReadEncryptedTablePerUserInput = match serde_json::from_str(&cmd) {
-        Ok(input) => input,
-        Err(err) => {
-            klave::notifier::send_string(&format!("Invalid input: {}", err));
-            return;
+// Strong reads are wrapped with the consistency report and reads over an Applying table are flagged
+// as ambiguous; other reads keep the bare result. Wrapped results also point at encrypted predicates
+// that ran without a search index.
+fn send_read_result<T: serde::Serialize>(result: &T, report: &ConsistencyReport, ambiguous: bool, unindexed: &[String]) {
+    if report.requested == ReadConsistency::Strong || ambiguous {
+        let mut envelope = json!({ "result": result });
+        if report.requested == ReadConsistency::Strong {
+            envelope["consistency"] = json!(report);
+        }
+        if ambiguous {
+            envelope["ambiguous"] = Value::Bool(true);
+        }
+        if !unindexed.is_empty() {
+            envelope["hint"] = Value::String(format!("Sequential scan on encrypted predicates {}, see create_search_index", unindexed.join(", ")));
+        }
+        notify::result(&envelope);
+    } else {
+        notify::result(result);
+    }
+}
+
+// Answers a read with whatever can be served without keys when the key store is unavailable.
+// Returns false when the error is not a key failure, so that the caller reports it as usual.
+pub fn send_degraded(err: &(dyn std::error::Error + 'static), raw: Value) -> bool {
+    match CipherError::from_error(err) {
+        Some(cipher_err @ CipherError::KeyUnavailable(_)) => {
+            notify::result(&json!({
+                "degraded": true,
+                "error": { "code": cipher_err.code(), "message": cipher_err.to_string() },
+                "result": raw
+            }));
+            true
+        }
+        _ => false,
+    }
+}
+
+// Evaluates the computed columns over each row and appends them to the resultset. Expressions may only
+// reference returned columns holding plaintext, never ones still carrying ciphertext.
+fn apply_computed_columns(computed: &[ComputedColumn], result: &mut PostGreResponse<Vec<Vec<Value>>>, undecryptable: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let names: Vec<String> = result.fields.iter().map(|f| f.name.clone()).collect();
+    let mut parsed = Vec::new();
+    for (index, column) in computed.iter().enumerate() {
+        if names.contains(&column.name) || computed[..index].iter().any(|c| c.name == column.name) {
+            return Err(format!("Computed column '{}' clashes with another column", column.name).into());
+        }
+        let expression = expr::parse(&column.expr).map_err(|e| format!("Invalid expression for '{}': {}", column.name, e))?;
+        for referenced in expression.columns() {
+            if !names.contains(&referenced) {
+                return Err(format!("Computed column '{}' references unknown column '{}'", column.name, referenced).into());
+            }
+            if undecryptable.contains(&referenced) {
+                return Err(format!("Computed column '{}' references encrypted column '{}' which this route does not decrypt", column.name, referenced).into());
+            }
         }
+        parsed.push((column, expression));
+    }
+
+    for row in result.resultset.iter_mut() {
+        let values: Map<String, Value> = names.iter().cloned().zip(row.iter().cloned()).collect();
+        for (column, expression) in parsed.iter() {
+            let value = expression.evaluate(&values).map_err(|e| format!("Failed to compute '{}': {}", column.name, e))?;
+            row.push(value.into_value());
+        }
+    }
+    result.fields.extend(parsed.iter().map(|(column, _)| Field::named(&column.name)));
+    Ok(())
+}
+
+pub fn read_encrypted_data_per_user(cmd: String) {
+    let input: database::ReadEncryptedTablePerUserInput = match notify::parse_input(&cmd) {
+        Some(input) => input,
+        None => return,
     };
     let mut client: database::Client = match database::Client::load(input.database_id.clone()) {
         Ok(c) => c,
         Err(err) => {
-            klave::notifier::send_string(&format!("Failed to load client: {}", err));
+            notify::error_with_code(&format!("Failed to load client: {}", err), err.code());
             return;
         }
     };
 
     // Connect to the DB and establish a handle
-    let _ = match client.connect() {
+    match client.connect() {
         Ok(_) => (),
         Err(err) => {
-            klave::notifier::send_string(&format!("Failed to connect to client: {}", err));
+            notify::error_with_code(&format!("Failed to connect to client: {}", err), err.code());
+            return;
+        }
+    };
+
+    let report = match consistency::ensure_read_consistency(&client, input.consistency, input.consistency_timeout_ms) {
+        Ok(r) => r,
+        Err(err) => {
+            notify::error_with_code(&format!("Failed to check read consistency: {}", err), DatabaseError::code_of(err.as_ref()));
             return;
         }
     };
@@ -32,7 +108,10 @@ pub fn read_encrypted_data_per_user(cmd: String) {
     let query: EncryptedQueryWithEncryptedUser = match client.build_encrypted_query_per_user(&input) {
         Ok(res) => res,
         Err(err) => {
-            klave::notifier::send_string(&format!("Failed to create query: {}", err));
+            // The name predicates need the key, so there are no rows to return
+            if !send_degraded(err.as_ref(), Value::Null) {
+                notify::error_with_code(&format!("Failed to create query: {}", err), DatabaseError::code_of(err.as_ref()));
+            }
             return;
         }
     };
@@ -40,7 +119,7 @@ pub fn read_encrypted_data_per_user(cmd: String) {
     let mut result = match client.query::<Vec<Vec<Value>>>(&query.query) {
         Ok(res) => res,
         Err(err) => {
-            klave::notifier::send_string(&format!("Failed to query the DB: {}", err));
+            notify::error_with_code(&format!("Failed to query the DB: {}", err), err.code());
             return;
         }
     };
@@ -52,7 +131,7 @@ pub fn read_encrypted_data_per_user(cmd: String) {
         let first_name_value = match elem.get_mut(0) {
             Some(res) => res,
             None => {
-                klave::notifier::send_string(&format!("Missing first name"));
+                notify::error("Missing first name");
                 return;
             }
         };
@@ -65,7 +144,7 @@ pub fn read_encrypted_data_per_user(cmd: String) {
         let last_name_value = match elem.get_mut(1) {
             Some(res) => res,
             None => {
-                klave::notifier::send_string(&format!("Missing last name"));
+                notify::error("Missing last name");
                 return;
             }
         };
@@ -76,31 +155,61 @@ pub fn read_encrypted_data_per_user(cmd: String) {
         };
     }
 
-    let _ = klave::notifier::send_json(&result);
-    return;
-}
+    if !input.computed.is_empty() {
+        // Only first and last name are turned back into cleartext by this route
+        let undecryptable: Vec<String> = match EncryptionManifest::load(client.database_id()) {
+            Ok(manifest) => manifest.tables.iter()
+                .filter(|t| PER_USER_TABLES.contains(&t.table.as_str()))
+                .flat_map(|t| t.columns.iter().map(|c| c.name.clone()))
+                .filter(|c| c != "first_name" && c != "last_name")
+                .collect(),
+            Err(err) => {
+                notify::error_with_code(&format!("Failed to load encryption manifest: {}", err), DatabaseError::code_of(err.as_ref()));
+                return;
+            }
+        };
+        if let Err(err) = apply_computed_columns(&input.computed, &mut result, &undecryptable) {
+            notify::error_with_code(&format!("Failed to compute columns: {}", err), DatabaseError::code_of(err.as_ref()));
+            return;
+        }
+    }
 
-pub fn avg_age_for_male(cmd: String) {
-    let input: database::DatabaseIdInput = match serde_json::from_str(&cmd) {
-        Ok(input) => input,
-        Err(err) => {
-            klave::notifier::send_string(&format!("Invalid input: {}", err));
+    if let Some(select_fields) = input.select_fields.as_ref() {
+        if let Err(err) = database::project_fields(&mut result, select_fields) {
+            notify::error(&format!("Invalid input: {}", err));
             return;
         }
+    }
+
+    send_read_result(&result, &report, query.ambiguous, &query.unindexed);
+}
+
+pub fn avg_age_for_male(cmd: String) {
+    let input: database::DatabaseIdInput = match notify::parse_input(&cmd) {
+        Some(input) => input,
+        None => return,
     };
     let mut client: database::Client = match database::Client::load(input.database_id.clone()) {
         Ok(c) => c,
         Err(err) => {
-            klave::notifier::send_string(&format!("Failed to load client: {}", err));
+            notify::error_with_code(&format!("Failed to load client: {}", err), err.code());
             return;
         }
     };
 
     // Connect to the DB and establish a handle
-    let _ = match client.connect() {
+    match client.connect() {
         Ok(_) => (),
         Err(err) => {
-            klave::notifier::send_string(&format!("Failed to connect to client: {}", err));
+            notify::error_with_code(&format!("Failed to connect to client: {}", err), err.code());
+            return;
+        }
+    };
+
+    let report = match consistency::ensure_read_consistency(&client, input.consistency, input.consistency_timeout_ms) {
+        Ok(r) => r,
+        Err(err) => {
+            notify::error_with_code(&format!("Failed to check read consistency: {}", err), DatabaseError::code_of(err.as_ref()));
             return;
         }
     };
@@ -109,45 +218,50 @@ pub fn avg_age_for_male(cmd: String) {
     let query = match client.build_encrypted_query_per_gender(&"Male".to_string()) {
         Ok(res) => res,
         Err(err) => {
-            klave::notifier::send_string(&format!("Failed to build the query: {}", err));
+            if !send_degraded(err.as_ref(), Value::Null) {
+                notify::error_with_code(&format!("Failed to build the query: {}", err), DatabaseError::code_of(err.as_ref()));
+            }
             return;
         }
     };
 
     // Run query
-    let _ = match client.query::<Vec<Vec<Value>>>(&query) {
+    match client.query::<Vec<Vec<Value>>>(&query.query) {
         Ok(res) => {
-            let _ = klave::notifier::send_json(&res);
-            return;
+            send_read_result(&res, &report, query.ambiguous, &query.unindexed);
         },
         Err(err) => {
-            klave::notifier::send_string(&format!("Failed to run the query: {}", err));
-            return;
+            notify::error_with_code(&format!("Failed to run the query: {}", err), err.code());
         }
-    };
+    }
 }
 
 pub fn avg_age_for_female(cmd: String) {
-    let input: database::DatabaseIdInput = match serde_json::from_str(&cmd) {
-        Ok(input) => input,
-        Err(err) => {
-            klave::notifier::send_string(&format!("Invalid input: {}", err));
-            return;
-        }
+    let input: database::DatabaseIdInput = match notify::parse_input(&cmd) {
+        Some(input) => input,
+        None => return,
     };
     let mut client: database::Client = match database::Client::load(input.database_id.clone()) {
         Ok(c) => c,
         Err(err) => {
-            klave::notifier::send_string(&format!("Failed to load client: {}", err));
+            notify::error_with_code(&format!("Failed to load client: {}", err), err.code());
             return;
         }
     };
 
     // Connect to the DB and establish a handle
-    let _ = match client.connect() {
+    match client.connect() {
         Ok(_) => (),
         Err(err) => {
-            klave::notifier::send_string(&format!("Failed to connect to client: {}", err));
+            notify::error_with_code(&format!("Failed to connect to client: {}", err), err.code());
+            return;
+        }
+    };
+
+    let report = match consistency::ensure_read_consistency(&client, input.consistency, input.consistency_timeout_ms) {
+        Ok(r) => r,
+        Err(err) => {
+            notify::error_with_code(&format!("Failed to check read consistency: {}", err), DatabaseError::code_of(err.as_ref()));
             return;
         }
     };
@@ -156,20 +270,55 @@ pub fn avg_age_for_female(cmd: String) {
     let query = match client.build_encrypted_query_per_gender(&"Female".to_string()) {
         Ok(res) => res,
         Err(err) => {
-            klave::notifier::send_string(&format!("Failed to build the query: {}", err));
+            if !send_degraded(err.as_ref(), Value::Null) {
+                notify::error_with_code(&format!("Failed to build the query: {}", err), DatabaseError::code_of(err.as_ref()));
+            }
             return;
         }
     };
 
     // Run query
-    let _ = match client.query::<Vec<Vec<Value>>>(&query) {
+    match client.query::<Vec<Vec<Value>>>(&query.query) {
         Ok(res) => {
-            let _ = klave::notifier::send_json(&res);
-            return;
+            send_read_result(&res, &report, query.ambiguous, &query.unindexed);
         },
         Err(err) => {
-            klave::notifier::send_string(&format!("Failed to run the query: {}", err));
-            return;
+            notify::error_with_code(&format!("Failed to run the query: {}", err), err.code());
         }
-    };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resultset() -> PostGreResponse<Vec<Vec<Value>>> {
+        PostGreResponse {
+            fields: vec![Field::named("first_name"), Field::named("price"), Field::named("description")],
+            resultset: vec![vec![json!("Jane"), json!("10.00"), json!("00ab")]],
+            lossy_cells: Vec::new(),
+            notices: Vec::new(),
+        }
+    }
+
+    fn computed(name: &str, expr: &str) -> ComputedColumn {
+        ComputedColumn { name: name.to_string(), expr: expr.to_string() }
+    }
+
+    #[test]
+    fn test_apply_computed_columns() {
+        let mut result = resultset();
+        apply_computed_columns(&[computed("with_vat", "price * 1.2"), computed("initial", "substr(first_name, 1, 1)")], &mut result, &[]).unwrap();
+        assert_eq!(result.fields.len(), 5);
+        assert_eq!(result.resultset[0][3..], [json!("12.000"), json!("J")]);
+    }
+
+    #[test]
+    fn test_computed_columns_are_rejected() {
+        let undecryptable = vec!["description".to_string()];
+        assert!(apply_computed_columns(&[computed("d", "upper(description)")], &mut resultset(), &undecryptable).is_err());
+        assert!(apply_computed_columns(&[computed("x", "missing + 1")], &mut resultset(), &[]).is_err());
+        assert!(apply_computed_columns(&[computed("price", "1")], &mut resultset(), &[]).is_err());
+        assert!(apply_computed_columns(&[computed("bad", "price / 0")], &mut resultset(), &[]).is_err());
+    }
 }
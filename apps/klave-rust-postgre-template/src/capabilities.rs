@@ -0,0 +1,210 @@
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{audit, database::{Client, DatabaseError}, notify, storage, utils::get_trusted_time};
+
+pub(crate) const HOST_CAPABILITIES_TABLE: &str = "HostCapabilitiesTable";
+
+// Operations whose support depends on the Klave SQL host or on the server behind it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Capability {
+    HoldCursors,
+    Copy,
+    MultipleResultSets,
+    Listen,
+    // Statements sent outside a transaction block, e.g. CREATE INDEX CONCURRENTLY
+    ConcurrentIndex,
+    SnapshotExport,
+}
+
+impl Capability {
+    pub const ALL: [Capability; 6] = [
+        Capability::HoldCursors,
+        Capability::Copy,
+        Capability::MultipleResultSets,
+        Capability::Listen,
+        Capability::ConcurrentIndex,
+        Capability::SnapshotExport,
+    ];
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            Capability::HoldCursors => "WITH HOLD cursors",
+            Capability::Copy => "COPY",
+            Capability::MultipleResultSets => "multiple result sets",
+            Capability::Listen => "LISTEN",
+            Capability::ConcurrentIndex => "concurrent index builds",
+            Capability::SnapshotExport => "snapshot exports",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProbeMethod {
+    Query,
+    Execute,
+}
+
+// Statement exercising a capability without changing anything. Transactional probes run between
+// BEGIN and ROLLBACK, the others are no-ops on their own.
+#[derive(Debug, Clone, Copy)]
+pub struct Probe {
+    pub capability: Capability,
+    pub begin: Option<&'static str>,
+    pub statement: &'static str,
+    pub method: ProbeMethod,
+}
+
+pub const PROBES: [Probe; 6] = [
+    Probe { capability: Capability::HoldCursors, begin: Some("BEGIN"), statement: "DECLARE kl_capability_probe CURSOR WITH HOLD FOR SELECT 1", method: ProbeMethod::Execute },
+    Probe { capability: Capability::Copy, begin: Some("BEGIN"), statement: "COPY (SELECT 1) TO STDOUT", method: ProbeMethod::Execute },
+    Probe { capability: Capability::MultipleResultSets, begin: Some("BEGIN READ ONLY"), statement: "SELECT 1 AS first; SELECT 2 AS second", method: ProbeMethod::Query },
+    Probe { capability: Capability::Listen, begin: Some("BEGIN"), statement: "LISTEN kl_capability_probe", method: ProbeMethod::Execute },
+    // Drops nothing, fails only when the host wraps statements in a transaction block
+    Probe { capability: Capability::ConcurrentIndex, begin: None, statement: "DROP INDEX CONCURRENTLY IF EXISTS kl_capability_probe", method: ProbeMethod::Execute },
+    Probe { capability: Capability::SnapshotExport, begin: Some("BEGIN ISOLATION LEVEL REPEATABLE READ READ ONLY"), statement: "SELECT pg_export_snapshot()", method: ProbeMethod::Query },
+];
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CapabilityResult {
+    pub capability: Capability,
+    pub supported: bool,
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+// Outcome of the last probe_host_capabilities on a database
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HostCapabilities {
+    pub database_id: String,
+    pub probed_at: u64,
+    pub results: Vec<CapabilityResult>,
+}
+
+impl HostCapabilities {
+    pub fn load(database_id: &str) -> Option<HostCapabilities> {
+        crate::runtime::ledger::get_table(HOST_CAPABILITIES_TABLE).get(database_id).ok()
+            .and_then(|v| serde_json::from_slice::<HostCapabilities>(&v).ok())
+    }
+
+    fn save(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let serialized = serde_json::to_string(self)?;
+        storage::check_quota(serialized.len() as u64)?;
+        storage::ledger_set(HOST_CAPABILITIES_TABLE, &self.database_id, serialized.as_bytes())
+    }
+
+    // None when the capability was not probed
+    pub fn supports(&self, capability: Capability) -> Option<bool> {
+        self.results.iter().find(|r| r.capability == capability).map(|r| r.supported)
+    }
+
+    // Refuses operations the last probe found unsupported; unprobed ones are attempted
+    pub fn check(&self, capability: Capability) -> Result<(), String> {
+        match self.supports(capability) {
+            Some(false) => Err(format!("{} not supported by this host/database (probed at {}), see probe_host_capabilities", capability.label(), self.probed_at)),
+            _ => Ok(()),
+        }
+    }
+}
+
+// Called by the routes relying on a host-dependent capability before they start any work
+pub fn require(database_id: &str, capability: Capability) -> Result<(), String> {
+    match HostCapabilities::load(database_id) {
+        Some(capabilities) => capabilities.check(capability),
+        None => Ok(()),
+    }
+}
+
+fn run_probe(client: &Client, probe: &Probe) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(begin) = probe.begin {
+        client.execute(begin)?;
+    }
+    let outcome = match probe.method {
+        ProbeMethod::Query => client.query::<Vec<Vec<serde_json::Value>>>(probe.statement).map(|_| ()),
+        ProbeMethod::Execute => client.execute(probe.statement).map(|_| ()),
+    };
+    if probe.begin.is_some() {
+        client.execute("ROLLBACK")?;
+    }
+    Ok(outcome?)
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProbeHostCapabilitiesInput {
+    pub database_id: String,
+}
+
+// Runs every probe against the database and replaces its capabilities record; call again to refresh it
+pub fn probe_host_capabilities(cmd: String) {
+    let input: ProbeHostCapabilitiesInput = match notify::parse_input(&cmd) {
+        Some(input) => input,
+        None => return,
+    };
+    let mut client = match Client::load(input.database_id.clone()) {
+        Ok(c) => c,
+        Err(err) => {
+            notify::error_with_code(&format!("Failed to load client: {}", err), err.code());
+            return;
+        }
+    };
+    if let Err(err) = client.connect() {
+        notify::error_with_code(&format!("Failed to connect to client: {}", err), err.code());
+        return;
+    }
+
+    let results = PROBES.iter().map(|probe| {
+        let error = run_probe(&client, probe).err().map(|e| e.to_string());
+        CapabilityResult { capability: probe.capability, supported: error.is_none(), error }
+    }).collect();
+    let capabilities = HostCapabilities { database_id: input.database_id.clone(), probed_at: get_trusted_time(), results };
+    let unsupported: Vec<Capability> = capabilities.results.iter().filter(|r| !r.supported).map(|r| r.capability).collect();
+    match capabilities.save() {
+        Ok(_) => {
+            audit::record("probe_host_capabilities", Some(&input.database_id), "success", json!({ "unsupported": unsupported }));
+            notify::result(&capabilities);
+        },
+        Err(err) => {
+            audit::record("probe_host_capabilities", Some(&input.database_id), "failure", json!({}));
+            notify::error_with_code(&format!("Failed to save host capabilities: {}", err), DatabaseError::code_of(err.as_ref()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::statement::{self, StatementKind};
+
+    use super::*;
+
+    #[test]
+    fn test_probes() {
+        for capability in Capability::ALL {
+            assert_eq!(PROBES.iter().filter(|p| p.capability == capability).count(), 1);
+        }
+        // Anything that could leave a trace runs in a transaction that is rolled back
+        for probe in PROBES.iter().filter(|p| p.begin.is_none()) {
+            assert!(probe.statement.contains("IF EXISTS"));
+        }
+        let reads = PROBES.iter().filter(|p| p.method == ProbeMethod::Query);
+        assert!(reads.into_iter().all(|p| statement::split_statements(p.statement).unwrap().iter().all(|s| statement::classify(&s.text) == StatementKind::Query)));
+    }
+
+    #[test]
+    fn test_check() {
+        let capabilities = HostCapabilities {
+            database_id: "db".to_string(),
+            probed_at: 7,
+            results: vec![
+                CapabilityResult { capability: Capability::Copy, supported: true, error: None },
+                CapabilityResult { capability: Capability::ConcurrentIndex, supported: false, error: Some("cannot run inside a transaction block".to_string()) },
+            ],
+        };
+        assert_eq!(capabilities.check(Capability::Copy), Ok(()));
+        // Unprobed capabilities are attempted
+        assert_eq!(capabilities.check(Capability::Listen), Ok(()));
+        assert_eq!(capabilities.check(Capability::ConcurrentIndex).unwrap_err(),
+            "concurrent index builds not supported by this host/database (probed at 7), see probe_host_capabilities");
+    }
+}
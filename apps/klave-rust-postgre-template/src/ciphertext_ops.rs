@@ -0,0 +1,432 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    crypto::parse_ciphertext,
+    database::DatabaseError,
+    manifest::{EncryptedColumn, EncryptionManifest},
+    notify,
+    statement::{self, Token, TokenKind},
+};
+
+// Hex length of the shortest ciphertext: 12-byte IV and 16-byte tag
+const MIN_CIPHERTEXT_HEX_LENGTH: usize = 2 * (12 + 16);
+
+// Words ending a FROM item, anything else following a table name is its alias
+const CLAUSE_KEYWORDS: &[&str] = &[
+    "WHERE", "JOIN", "INNER", "LEFT", "RIGHT", "FULL", "CROSS", "NATURAL", "ON", "USING", "GROUP", "ORDER", "LIMIT",
+    "OFFSET", "FETCH", "UNION", "INTERSECT", "EXCEPT", "HAVING", "WINDOW", "FOR", "SET", "RETURNING", "TABLESAMPLE",
+];
+// Words ending an ORDER BY, GROUP BY or DISTINCT select list
+const LIST_END_KEYWORDS: &[&str] = &["FROM", "WHERE", "HAVING", "WINDOW", "LIMIT", "OFFSET", "FETCH", "FOR", "UNION", "INTERSECT", "EXCEPT", "ORDER"];
+const ORDER_MODIFIERS: &[&str] = &["ASC", "DESC", "NULLS", "FIRST", "LAST"];
+
+// Operations that give meaningless results when applied to ciphertext
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CiphertextOp {
+    OrderBy,
+    // Equal values only share a ciphertext when the column is deterministic
+    GroupBy,
+    Distinct,
+    RangeComparison,
+    // Ciphertext compared with a plaintext literal
+    LiteralComparison,
+}
+
+impl CiphertextOp {
+    pub fn hint(&self) -> &'static str {
+        match self {
+            CiphertextOp::OrderBy | CiphertextOp::RangeComparison => {
+                "ciphertext order says nothing about the plaintext, read the rows through a decrypting route and sort or filter them there"
+            }
+            CiphertextOp::GroupBy | CiphertextOp::Distinct => {
+                "equal values encrypt differently per row under the column binding or aad_template, group a decrypted read instead"
+            }
+            CiphertextOp::LiteralComparison => {
+                "the column holds ciphertext, search it through a read route that encrypts the searched value"
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CiphertextOperation {
+    // "table.column", or the bare column when the table could not be resolved
+    pub column: String,
+    pub operation: CiphertextOp,
+    // Byte offset of the column reference in the statement
+    pub offset: usize,
+    pub hint: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CiphertextOpsReport {
+    pub operations: Vec<CiphertextOperation>,
+    // The column may or may not be encrypted, the statement references several tables or subqueries
+    pub ambiguous: Vec<CiphertextOperation>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct TableRef {
+    table: String,
+    alias: Option<String>,
+}
+
+enum Resolution {
+    Encrypted(String),
+    Ambiguous,
+    Plaintext,
+}
+
+fn identifier(token: &Token) -> Option<String> {
+    match token.kind {
+        TokenKind::Word => Some(token.text.to_lowercase()),
+        TokenKind::QuotedIdentifier => Some(token.text[1..token.text.len() - 1].replace("\"\"", "\"")),
+        _ => None,
+    }
+}
+
+fn is_symbol(tokens: &[Token], i: usize, symbol: &str) -> bool {
+    tokens.get(i).map(|t| t.kind == TokenKind::Symbol && t.text == symbol).unwrap_or(false)
+}
+
+fn is_keyword(tokens: &[Token], i: usize, keywords: &[&str]) -> bool {
+    tokens.get(i).map(|t| keywords.iter().any(|k| t.is_keyword(k))).unwrap_or(false)
+}
+
+// Tables named after FROM and JOIN; the flag is set when a FROM item is a subquery or function
+fn referenced_tables(tokens: &[Token]) -> (Vec<TableRef>, bool) {
+    let mut tables = Vec::new();
+    let mut opaque = false;
+    for (i, token) in tokens.iter().enumerate() {
+        let list = token.is_keyword("FROM");
+        if !list && !token.is_keyword("JOIN") {
+            continue;
+        }
+        let mut j = i + 1;
+        loop {
+            while is_keyword(tokens, j, &["ONLY", "LATERAL"]) {
+                j += 1;
+            }
+            let mut name = match tokens.get(j).and_then(identifier) {
+                Some(name) => name,
+                None => {
+                    opaque = true;
+                    break;
+                }
+            };
+            j += 1;
+            // Schema-qualified name, the table name is the last part
+            while is_symbol(tokens, j, ".") {
+                match tokens.get(j + 1).and_then(identifier) {
+                    Some(part) => name = part,
+                    None => break,
+                }
+                j += 2;
+            }
+            if is_symbol(tokens, j, "(") {
+                opaque = true;
+                break;
+            }
+            if is_keyword(tokens, j, &["AS"]) {
+                j += 1;
+            }
+            let alias = match tokens.get(j) {
+                Some(t) if (t.kind == TokenKind::Word && !CLAUSE_KEYWORDS.iter().any(|k| t.is_keyword(k))) || t.kind == TokenKind::QuotedIdentifier => {
+                    j += 1;
+                    identifier(t)
+                }
+                _ => None,
+            };
+            tables.push(TableRef { table: name, alias });
+            if !(list && is_symbol(tokens, j, ",")) {
+                break;
+            }
+            j += 1;
+        }
+    }
+    (tables, opaque)
+}
+
+fn encrypted_column<'a>(manifest: &'a EncryptionManifest, table: &str, column: &str) -> Option<&'a EncryptedColumn> {
+    manifest.tables.iter()
+        .find(|t| t.table.eq_ignore_ascii_case(table))
+        .and_then(|t| t.columns.iter().find(|c| c.name.eq_ignore_ascii_case(column)))
+}
+
+// Column reference ending at index i: the column name and its qualifier, with the index of its first token
+fn column_ref(tokens: &[Token], i: usize) -> Option<(Option<String>, String, usize)> {
+    let name = identifier(tokens.get(i)?)?;
+    if is_symbol(tokens, i + 1, "(") || is_symbol(tokens, i + 1, ".") {
+        return None;
+    }
+    if i >= 2 && is_symbol(tokens, i - 1, ".") {
+        return tokens.get(i - 2).and_then(identifier).map(|q| (Some(q), name, i - 2));
+    }
+    Some((None, name, i))
+}
+
+fn is_plain_literal(token: &Token) -> bool {
+    match token.kind {
+        TokenKind::Number => true,
+        TokenKind::StringLiteral => {
+            // A literal already holding ciphertext compares as intended
+            let inner = token.text.trim_start_matches(['E', 'e']).trim_matches('\'');
+            let inner = match parse_ciphertext(inner) {
+                Ok((_, payload)) => payload,
+                Err(_) => return true,
+            };
+            !(inner.len() >= MIN_CIPHERTEXT_HEX_LENGTH && inner.len().is_multiple_of(2) && inner.chars().all(|c| c.is_ascii_hexdigit()))
+        }
+        _ => false,
+    }
+}
+
+// Comparison applied to the reference spanning tokens[start..=end], if any
+fn comparison(tokens: &[Token], start: usize, end: usize) -> Option<CiphertextOp> {
+    let literal_at = |i: usize| tokens.get(i).map(is_plain_literal).unwrap_or(false);
+    // Operator after the reference
+    if is_keyword(tokens, end + 1, &["BETWEEN", "LIKE", "ILIKE"]) || (is_keyword(tokens, end + 1, &["NOT"]) && is_keyword(tokens, end + 2, &["BETWEEN", "LIKE", "ILIKE"])) {
+        return Some(CiphertextOp::RangeComparison);
+    }
+    if (is_symbol(tokens, end + 1, "<") && is_symbol(tokens, end + 2, ">")) || (is_symbol(tokens, end + 1, "!") && is_symbol(tokens, end + 2, "=")) {
+        return if literal_at(end + 3) { Some(CiphertextOp::LiteralComparison) } else { None };
+    }
+    if is_symbol(tokens, end + 1, "<") || is_symbol(tokens, end + 1, ">") {
+        return Some(CiphertextOp::RangeComparison);
+    }
+    if is_symbol(tokens, end + 1, "=") {
+        return if literal_at(end + 2) { Some(CiphertextOp::LiteralComparison) } else { None };
+    }
+    if is_keyword(tokens, end + 1, &["IN"]) && is_symbol(tokens, end + 2, "(") && literal_at(end + 3) {
+        return Some(CiphertextOp::LiteralComparison);
+    }
+    // Operator before the reference
+    if start == 0 {
+        return None;
+    }
+    let before = start - 1;
+    if is_symbol(tokens, before, "=") {
+        if before >= 1 && (is_symbol(tokens, before - 1, "<") || is_symbol(tokens, before - 1, ">")) {
+            return Some(CiphertextOp::RangeComparison);
+        }
+        let operand = if before >= 1 && is_symbol(tokens, before - 1, "!") { before.checked_sub(2) } else { before.checked_sub(1) };
+        return if operand.map(literal_at).unwrap_or(false) { Some(CiphertextOp::LiteralComparison) } else { None };
+    }
+    if is_symbol(tokens, before, ">") && before >= 1 && is_symbol(tokens, before - 1, "<") {
+        return if before >= 2 && literal_at(before - 2) { Some(CiphertextOp::LiteralComparison) } else { None };
+    }
+    if is_symbol(tokens, before, "<") || is_symbol(tokens, before, ">") {
+        return Some(CiphertextOp::RangeComparison);
+    }
+    None
+}
+
+// Items of the list starting at index start, at the depth of its first token: each item is reported as
+// a column reference when it is a bare (possibly qualified) column followed by ORDER BY modifiers only
+fn list_items(tokens: &[Token], start: usize, modifiers: &[&str]) -> Vec<usize> {
+    let depth = match tokens.get(start) {
+        Some(t) => t.depth,
+        None => return Vec::new(),
+    };
+    let mut refs = Vec::new();
+    let mut item: Vec<usize> = Vec::new();
+    let mut i = start;
+    loop {
+        let end_of_list = match tokens.get(i) {
+            None => true,
+            Some(t) => t.depth < depth || (t.depth == depth && (is_symbol(tokens, i, ")") || is_symbol(tokens, i, ";") || is_keyword(tokens, i, LIST_END_KEYWORDS))),
+        };
+        if end_of_list || (tokens[i].depth == depth && is_symbol(tokens, i, ",")) {
+            let significant: Vec<usize> = item.iter().copied()
+                .filter(|k| !is_keyword(tokens, *k, modifiers))
+                .collect();
+            let bare = match significant.as_slice() {
+                [single] => Some(*single),
+                [_, dot, last] if is_symbol(tokens, *dot, ".") => Some(*last),
+                _ => None,
+            };
+            if let Some(last) = bare {
+                refs.push(last);
+            }
+            item.clear();
+            if end_of_list {
+                return refs;
+            }
+        } else {
+            item.push(i);
+        }
+        i += 1;
+    }
+}
+
+// Checks the statements of a call before they run. Operations on ciphertext fail the call unless the caller
+// allowed them; ambiguous ones only produce a warning.
+pub fn check_statements<'a, I: IntoIterator<Item = &'a str>>(statements: I, manifest: &EncryptionManifest) -> Result<Vec<CiphertextOperation>, Vec<CiphertextOperation>> {
+    let mut operations = Vec::new();
+    let mut ambiguous = Vec::new();
+    for sql in statements {
+        // Statements that do not scan fail later with their own error
+        if let Ok(report) = find_ciphertext_operations(sql, manifest) {
+            operations.extend(report.operations);
+            ambiguous.extend(report.ambiguous);
+        }
+    }
+    if operations.is_empty() { Ok(ambiguous) } else { Err(operations) }
+}
+
+// Route-side check: sends the error and returns false when the statements operate on ciphertext,
+// ambiguous operations are sent as warnings
+pub fn allow_statements<'a, I: IntoIterator<Item = &'a str>>(database_id: &str, statements: I) -> bool {
+    let manifest = match EncryptionManifest::load(database_id) {
+        Ok(m) => m,
+        Err(err) => {
+            notify::error_with_code(&format!("Failed to load encryption manifest: {}", err), DatabaseError::code_of(err.as_ref()));
+            return false;
+        }
+    };
+    match check_statements(statements, &manifest) {
+        Ok(ambiguous) => {
+            for op in ambiguous.iter() {
+                notify::warning(&format!("Column {} may be encrypted, {:?} at offset {} would then apply to ciphertext: {}", op.column, op.operation, op.offset, op.hint));
+            }
+            true
+        }
+        Err(operations) => {
+            let columns: Vec<&str> = operations.iter().map(|o| o.column.as_str()).collect();
+            notify::error_with_details(
+                &format!("Statements operate on the ciphertext of {}, pass allow_ciphertext_ops to run them anyway", columns.join(", ")),
+                &operations,
+            );
+            false
+        }
+    }
+}
+
+// Finds ORDER BY, GROUP BY, DISTINCT and comparisons applied to encrypted columns in one statement.
+// Operations on columns that resolve to an encrypted column are reported; when the statement references
+// several tables or subqueries and the column cannot be attributed, it is listed as ambiguous instead.
+pub fn find_ciphertext_operations(sql: &str, manifest: &EncryptionManifest) -> Result<CiphertextOpsReport, statement::ScanError> {
+    let tokens = statement::tokenize(sql)?;
+    let mut report = CiphertextOpsReport::default();
+    if manifest.tables.is_empty() {
+        return Ok(report);
+    }
+    let (tables, opaque) = referenced_tables(&tokens);
+    let single = !opaque && tables.len() == 1;
+
+    let resolve = |qualifier: &Option<String>, name: &str| -> (Resolution, Option<&EncryptedColumn>) {
+        if let Some(q) = qualifier {
+            if let Some(t) = tables.iter().find(|t| t.alias.as_deref() == Some(q.as_str()) || (t.alias.is_none() && t.table == *q)) {
+                return match encrypted_column(manifest, &t.table, name) {
+                    Some(c) => (Resolution::Encrypted(format!("{}.{}", t.table, name)), Some(c)),
+                    None => (Resolution::Plaintext, None),
+                };
+            }
+        }
+        let candidates: Vec<(&TableRef, &EncryptedColumn)> = tables.iter()
+            .filter_map(|t| encrypted_column(manifest, &t.table, name).map(|c| (t, c)))
+            .collect();
+        match candidates.as_slice() {
+            [] if qualifier.is_some() || opaque => match manifest.tables.iter().find_map(|t| encrypted_column(manifest, &t.table, name)) {
+                Some(c) => (Resolution::Ambiguous, Some(c)),
+                None => (Resolution::Plaintext, None),
+            },
+            [] => (Resolution::Plaintext, None),
+            [(t, c)] if single && qualifier.is_none() => (Resolution::Encrypted(format!("{}.{}", t.table, name)), Some(*c)),
+            [(_, c), ..] => (Resolution::Ambiguous, Some(*c)),
+        }
+    };
+
+    let mut record = |end: usize, operation: CiphertextOp| {
+        let (qualifier, name, start) = match column_ref(&tokens, end) {
+            Some(r) => r,
+            None => return,
+        };
+        let (resolution, column) = resolve(&qualifier, &name);
+        // Deterministic ciphertext keeps equality, grouping only breaks under a row binding or an aad_template
+        if matches!(operation, CiphertextOp::GroupBy | CiphertextOp::Distinct) && column.map(|c| c.is_deterministic()).unwrap_or(true) {
+            return;
+        }
+        let found = |column: String| CiphertextOperation { column, operation, offset: tokens[start].offset, hint: operation.hint().to_string() };
+        match resolution {
+            Resolution::Encrypted(column) => report.operations.push(found(column)),
+            Resolution::Ambiguous => report.ambiguous.push(found(name)),
+            Resolution::Plaintext => (),
+        }
+    };
+
+    for i in 0..tokens.len() {
+        if is_keyword(&tokens, i + 1, &["BY"]) && is_keyword(&tokens, i, &["ORDER", "GROUP"]) {
+            let (operation, modifiers) = if tokens[i].is_keyword("ORDER") { (CiphertextOp::OrderBy, ORDER_MODIFIERS) } else { (CiphertextOp::GroupBy, &[][..]) };
+            for end in list_items(&tokens, i + 2, modifiers) {
+                record(end, operation);
+            }
+        }
+        if tokens[i].is_keyword("DISTINCT") && is_keyword(&tokens, i.wrapping_sub(1), &["SELECT"]) && !is_keyword(&tokens, i + 1, &["ON"]) {
+            for end in list_items(&tokens, i + 1, &[]) {
+                record(end, CiphertextOp::Distinct);
+            }
+        }
+        if let Some((_, _, start)) = column_ref(&tokens, i) {
+            if let Some(operation) = comparison(&tokens, start, i) {
+                record(i, operation);
+            }
+        }
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::manifest::EncryptedColumn;
+
+    use super::*;
+
+    fn manifest() -> EncryptionManifest {
+        let mut manifest = EncryptionManifest::new("db");
+        manifest.record_column("users", "id", EncryptedColumn::new("email", None), 0);
+        manifest.record_column("users", "id", EncryptedColumn { aad_template: Some("{tenant_id}".to_string()), ..EncryptedColumn::new("ssn", None) }, 0);
+        manifest.record_column("orders", "id", EncryptedColumn::new("card", None), 0);
+        manifest
+    }
+
+    fn operations(sql: &str) -> Vec<(String, CiphertextOp)> {
+        find_ciphertext_operations(sql, &manifest()).unwrap().operations.into_iter().map(|o| (o.column, o.operation)).collect()
+    }
+
+    #[test]
+    fn test_single_table_operations() {
+        assert_eq!(operations("SELECT * FROM users ORDER BY email DESC, id"), vec![("users.email".to_string(), CiphertextOp::OrderBy)]);
+        assert_eq!(operations("SELECT * FROM users u WHERE u.email >= 'a'"), vec![("users.email".to_string(), CiphertextOp::RangeComparison)]);
+        assert_eq!(operations("SELECT * FROM public.users WHERE 'jane@x.org' = email"), vec![("users.email".to_string(), CiphertextOp::LiteralComparison)]);
+        assert_eq!(operations("SELECT * FROM users WHERE email LIKE '%@x.org'"), vec![("users.email".to_string(), CiphertextOp::RangeComparison)]);
+        assert_eq!(operations("SELECT ssn, count(*) FROM users GROUP BY ssn"), vec![("users.ssn".to_string(), CiphertextOp::GroupBy)]);
+        assert_eq!(operations("SELECT DISTINCT ssn FROM users"), vec![("users.ssn".to_string(), CiphertextOp::Distinct)]);
+    }
+
+    #[test]
+    fn test_no_false_positives() {
+        // Plaintext columns, deterministic equality and ciphertext literals are fine
+        assert!(operations("SELECT * FROM users WHERE id > 3 ORDER BY first_name").is_empty());
+        assert!(operations("SELECT email, count(*) FROM users GROUP BY email").is_empty());
+        assert!(operations("SELECT DISTINCT email FROM users").is_empty());
+        assert!(operations(&format!("SELECT * FROM users WHERE email = '{}'", "ab".repeat(40))).is_empty());
+        assert!(operations(&format!("SELECT * FROM users WHERE email = 'v0:{}'", "ab".repeat(40))).is_empty());
+        assert!(operations("SELECT * FROM users WHERE length(email) > 3").is_empty());
+        assert!(operations("SELECT * FROM orders WHERE email < 'a'").is_empty());
+        assert!(operations("SELECT 'email < 1' FROM users").is_empty());
+    }
+
+    #[test]
+    fn test_ambiguous_tables() {
+        let report = find_ciphertext_operations("SELECT * FROM users JOIN orders o ON o.user_id = users.id ORDER BY card, o.card", &manifest()).unwrap();
+        // The qualified reference resolves, the bare one could come from either table
+        assert_eq!(report.operations.iter().map(|o| o.column.as_str()).collect::<Vec<_>>(), vec!["orders.card"]);
+        assert_eq!(report.ambiguous.iter().map(|o| o.column.as_str()).collect::<Vec<_>>(), vec!["card"]);
+
+        let report = find_ciphertext_operations("SELECT * FROM (SELECT * FROM users) s ORDER BY email", &manifest()).unwrap();
+        assert!(report.operations.is_empty());
+        assert_eq!(report.ambiguous.len(), 1);
+    }
+}
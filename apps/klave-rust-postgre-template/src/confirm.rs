@@ -0,0 +1,312 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{
+    audit,
+    crypto::compute_sha256_hex_string,
+    database::Client,
+    host::cell_as_u64,
+    storage,
+    utils::{get_client_id, get_trusted_time, sql_literal},
+};
+
+pub(crate) const CONFIRMATION_TABLE: &str = "ConfirmationTable";
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+// Long enough for an operator to read the summary and approve it
+const CONFIRMATION_TTL_SECONDS: u64 = 10 * 60;
+const CONFIRMATION_TOKEN_BYTES: i32 = 16;
+
+// What a destructive call is about to do, as shown to the operator approving it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ActionSummary {
+    pub operation: String,
+    pub database_id: String,
+    pub database_name: String,
+    pub database_fingerprint: String,
+    #[serde(default)]
+    pub table: Option<String>,
+    #[serde(default)]
+    pub estimated_rows: Option<u64>,
+    // Further facts the approval covers, e.g. the freeze being lifted
+    #[serde(default)]
+    pub details: BTreeMap<String, String>,
+    #[serde(default)]
+    pub irreversible: Vec<String>,
+}
+
+impl ActionSummary {
+    pub fn new(operation: &str, client: &Client) -> ActionSummary {
+        ActionSummary {
+            operation: operation.to_string(),
+            database_id: client.database_id().to_string(),
+            database_name: client.database_name().to_string(),
+            database_fingerprint: client.fingerprint(),
+            table: None,
+            estimated_rows: None,
+            details: BTreeMap::new(),
+            irreversible: Vec::new(),
+        }
+    }
+
+    // One fact per line in a fixed order, the text the summary hash covers
+    pub fn canonical_text(&self) -> String {
+        let mut lines = vec![
+            format!("operation: {}", self.operation),
+            format!("database: {} ({}, fingerprint {})", self.database_name, self.database_id, self.database_fingerprint),
+        ];
+        if let Some(table) = self.table.as_ref() {
+            lines.push(format!("table: {}", table));
+        }
+        lines.push(format!("estimated affected rows: {}", self.estimated_rows.map(|r| r.to_string()).unwrap_or_else(|| "unknown".to_string())));
+        for (name, value) in self.details.iter() {
+            lines.push(format!("{}: {}", name, value));
+        }
+        for note in self.irreversible.iter() {
+            lines.push(format!("irreversible: {}", note));
+        }
+        lines.join("\n")
+    }
+
+    pub fn hash(&self) -> String {
+        compute_sha256_hex_string(format!("klave-confirmation:{}", self.canonical_text()).as_bytes())
+    }
+}
+
+// Fields a destructive route takes for its confirming call
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ConfirmationInput {
+    #[serde(default)]
+    pub confirmation_token: Option<String>,
+    // Hash of the summary the operator approved, returned with the token
+    #[serde(default)]
+    pub summary_hash: Option<String>,
+}
+
+// Issued token as stored in the ledger, kept once used for the audit trail. Only the hash of the token is
+// stored, and it covers the summary hash: a token is only found again together with the summary it was issued for.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConfirmationRecord {
+    pub token_hash: String,
+    pub operation: String,
+    pub database_id: String,
+    pub summary_hash: String,
+    pub issued_by: String,
+    pub issued_at: u64,
+    pub expires_at: u64,
+    #[serde(default)]
+    pub used_at: Option<u64>,
+}
+
+impl ConfirmationRecord {
+    fn load(token_hash: &str) -> Option<ConfirmationRecord> {
+        crate::runtime::ledger::get_table(CONFIRMATION_TABLE).get(token_hash).ok()
+            .and_then(|v| serde_json::from_slice::<ConfirmationRecord>(&v).ok())
+    }
+
+    fn save(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let serialized = serde_json::to_string(self)?;
+        storage::ledger_set(CONFIRMATION_TABLE, &self.token_hash, serialized.as_bytes())
+    }
+}
+
+pub fn token_hash(token: &str, summary_hash: &str) -> String {
+    compute_sha256_hex_string(format!("confirm:{}:{}", token, summary_hash).as_bytes())
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfirmationError {
+    // No token was issued for this summary
+    Unknown,
+    Used,
+    Expired,
+    // Issued for another operation, database or caller
+    WrongAction,
+    // The action changed since the summary was approved
+    Drift,
+}
+
+impl std::fmt::Display for ConfirmationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfirmationError::Unknown => write!(f, "Unknown confirmation token, or it was issued for another summary"),
+            ConfirmationError::Used => write!(f, "Confirmation token was already used"),
+            ConfirmationError::Expired => write!(f, "Confirmation token expired, request a new one"),
+            ConfirmationError::WrongAction => write!(f, "Confirmation token was issued for another action"),
+            ConfirmationError::Drift => write!(f, "The action changed since it was approved, review the new summary and confirm again"),
+        }
+    }
+}
+
+impl std::error::Error for ConfirmationError {}
+
+// current_hash is the hash of the summary of the action as it stands at the confirming call
+pub fn check(record: &ConfirmationRecord, summary: &ActionSummary, current_hash: &str, caller: &str, now: u64) -> Result<(), ConfirmationError> {
+    if record.used_at.is_some() {
+        return Err(ConfirmationError::Used);
+    }
+    if now >= record.expires_at {
+        return Err(ConfirmationError::Expired);
+    }
+    if record.operation != summary.operation || record.database_id != summary.database_id || record.issued_by != caller {
+        return Err(ConfirmationError::WrongAction);
+    }
+    if record.summary_hash != current_hash {
+        return Err(ConfirmationError::Drift);
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ConfirmationPrompt {
+    pub summary: ActionSummary,
+    pub summary_text: String,
+    pub summary_hash: String,
+    pub confirmation_token: String,
+    pub expires_at: u64,
+}
+
+pub enum ConfirmStep {
+    // Nothing was done yet, the prompt goes back to the caller
+    Issued(Box<ConfirmationPrompt>),
+    Confirmed,
+}
+
+fn issue(summary: &ActionSummary) -> Result<ConfirmationPrompt, Box<dyn std::error::Error>> {
+    let token = hex::encode(crate::runtime::random::get_random_bytes(CONFIRMATION_TOKEN_BYTES)?);
+    let summary_hash = summary.hash();
+    let issued_at = get_trusted_time();
+    let record = ConfirmationRecord {
+        token_hash: token_hash(&token, &summary_hash),
+        operation: summary.operation.clone(),
+        database_id: summary.database_id.clone(),
+        summary_hash: summary_hash.clone(),
+        issued_by: get_client_id(),
+        issued_at,
+        expires_at: issued_at.saturating_add(CONFIRMATION_TTL_SECONDS * NANOS_PER_SECOND),
+        used_at: None,
+    };
+    record.save()?;
+    audit::record("issue_confirmation", Some(&summary.database_id), "success", json!({
+        "operation": summary.operation,
+        "summary_hash": summary_hash,
+        "expires_at": record.expires_at,
+    }));
+    Ok(ConfirmationPrompt {
+        summary: summary.clone(),
+        summary_text: summary.canonical_text(),
+        summary_hash,
+        confirmation_token: token,
+        expires_at: record.expires_at,
+    })
+}
+
+// The token is spent by the call that checks it, in the transaction of the action itself
+fn redeem(summary: &ActionSummary, token: &str, summary_hash: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let mut record = ConfirmationRecord::load(&token_hash(token, summary_hash)).ok_or(ConfirmationError::Unknown)?;
+    let now = get_trusted_time();
+    check(&record, summary, &summary.hash(), &get_client_id(), now)?;
+    record.used_at = Some(now);
+    record.save()
+}
+
+// First call of a destructive route: issues a token bound to the summary. Confirming call: checks the token
+// against the summary of the action as it stands now and spends it.
+pub fn confirm(summary: &ActionSummary, input: &ConfirmationInput) -> Result<ConfirmStep, Box<dyn std::error::Error>> {
+    match (input.confirmation_token.as_deref(), input.summary_hash.as_deref()) {
+        (None, _) => Ok(ConfirmStep::Issued(Box::new(issue(summary)?))),
+        (Some(_), None) => Err("summary_hash is required with the confirmation token".into()),
+        (Some(token), Some(summary_hash)) => {
+            redeem(summary, token, summary_hash)?;
+            Ok(ConfirmStep::Confirmed)
+        }
+    }
+}
+
+// Planner estimate of the rows of the tables, None when the database cannot tell
+pub fn estimate_rows(client: &Client, tables: &[String]) -> Option<u64> {
+    if tables.is_empty() {
+        return Some(0);
+    }
+    let names: Vec<String> = tables.iter().map(|t| sql_literal(&Value::String(t.clone()))).collect();
+    let query = format!("SELECT coalesce(sum(greatest(reltuples, 0)), 0)::bigint FROM pg_class WHERE relkind = 'r' AND relname IN ({})", names.join(", "));
+    let response = client.query::<Vec<Vec<Value>>>(&query).ok()?;
+    response.resultset.first().and_then(|row| row.first()).and_then(cell_as_u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn summary(estimated_rows: u64) -> ActionSummary {
+        let mut summary = ActionSummary {
+            operation: "sql_delete".to_string(),
+            database_id: "db".to_string(),
+            database_name: "shop".to_string(),
+            database_fingerprint: "0f3c".to_string(),
+            table: None,
+            estimated_rows: Some(estimated_rows),
+            details: BTreeMap::new(),
+            irreversible: vec!["encrypted rows can no longer be decrypted".to_string()],
+        };
+        summary.details.insert("encrypted_tables".to_string(), "orders, users".to_string());
+        summary
+    }
+
+    // Hashing needs the enclave, the summary text stands in for its hash here
+    fn record(approved: &ActionSummary) -> ConfirmationRecord {
+        ConfirmationRecord {
+            token_hash: "t".to_string(),
+            operation: approved.operation.clone(),
+            database_id: approved.database_id.clone(),
+            summary_hash: approved.canonical_text(),
+            issued_by: "alice".to_string(),
+            issued_at: 0,
+            expires_at: 100,
+            used_at: None,
+        }
+    }
+
+    #[test]
+    fn test_canonical_text() {
+        assert_eq!(summary(1200).canonical_text(), "operation: sql_delete\n\
+            database: shop (db, fingerprint 0f3c)\n\
+            estimated affected rows: 1200\n\
+            encrypted_tables: orders, users\n\
+            irreversible: encrypted rows can no longer be decrypted");
+        let mut unknown = summary(0);
+        unknown.estimated_rows = None;
+        unknown.table = Some("users".to_string());
+        assert!(unknown.canonical_text().contains("table: users\nestimated affected rows: unknown"));
+    }
+
+    #[test]
+    fn test_check() {
+        let approved = summary(1200);
+        let issued = record(&approved);
+        let current = approved.canonical_text();
+        assert_eq!(check(&issued, &approved, &current, "alice", 5), Ok(()));
+        assert_eq!(check(&issued, &approved, &current, "bob", 5), Err(ConfirmationError::WrongAction));
+        assert_eq!(check(&issued, &approved, &current, "alice", 100), Err(ConfirmationError::Expired));
+        let mut other = approved.clone();
+        other.operation = "unfreeze_database".to_string();
+        assert_eq!(check(&issued, &other, &current, "alice", 5), Err(ConfirmationError::WrongAction));
+        let used = ConfirmationRecord { used_at: Some(6), ..issued.clone() };
+        assert_eq!(check(&used, &approved, &current, "alice", 7), Err(ConfirmationError::Used));
+    }
+
+    // The table grew, or the target changed, between the approval and the confirming call
+    #[test]
+    fn test_drift_invalidates_the_token() {
+        let issued = record(&summary(1200));
+        let grown = summary(1350);
+        assert_eq!(check(&issued, &grown, &grown.canonical_text(), "alice", 5), Err(ConfirmationError::Drift));
+        let mut retargeted = summary(1200);
+        retargeted.details.insert("encrypted_tables".to_string(), "orders, payments, users".to_string());
+        assert_eq!(check(&issued, &retargeted, &retargeted.canonical_text(), "alice", 5), Err(ConfirmationError::Drift));
+        let mut moved = summary(1200);
+        moved.database_fingerprint = "9a1b".to_string();
+        assert_eq!(check(&issued, &moved, &moved.canonical_text(), "alice", 5), Err(ConfirmationError::Drift));
+    }
+}
@@ -0,0 +1,322 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{capabilities::{self, Capability}, ciphertext_ops, database::{self, Client, DatabaseError, PostGreResponse}, notify, settings::DeploymentSettings, statement::{self, StatementKind}, storage, strict, utils::{get_client_id, get_trusted_time, ledger_get, ledger_key, remove_legacy_key}};
+
+pub(crate) const CONSISTENCY_TABLE: &str = "ConsistencyTable";
+
+// Delay between two replay checks on a standby, in milliseconds
+const REPLAY_POLL_INTERVAL_MS: u64 = 50;
+const DEFAULT_CONSISTENCY_TIMEOUT_MS: u64 = 1000;
+// SELECTs run in one consistent_read_session call, larger reports continue with follow-up calls
+const MAX_SESSION_STATEMENTS: usize = 20;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReadConsistency {
+    #[default]
+    Default,
+    Strong,
+}
+
+// Write position of the last encrypted mutation issued by a caller on a database
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LastMutation {
+    pub lsn: String,
+    pub timestamp: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ServedBy {
+    Primary,
+    Replica,
+    // The standby did not replay the awaited LSN within the timeout
+    StaleReplica,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConsistencyReport {
+    pub requested: ReadConsistency,
+    pub served_by: ServedBy,
+    pub awaited_lsn: Option<String>,
+    pub polls: u64,
+}
+
+fn mutation_key(database_id: &str) -> String {
+    ledger_key(&[database_id, &get_client_id()])
+}
+
+fn legacy_mutation_key(database_id: &str) -> String {
+    format!("{}:{}", database_id, get_client_id())
+}
+
+// Only accept LSNs in PostgreSQL's textual form (e.g. "16/B374D848") before splicing them into SQL
+pub fn is_valid_lsn(lsn: &str) -> bool {
+    match lsn.split_once('/') {
+        Some((high, low)) => {
+            !high.is_empty() && !low.is_empty() && high.len() <= 8 && low.len() <= 8
+                && high.chars().chain(low.chars()).all(|c| c.is_ascii_hexdigit())
+        }
+        None => false,
+    }
+}
+
+fn first_cell(client: &Client, query: &str) -> Result<Value, Box<dyn std::error::Error>> {
+    let response = client.query::<Vec<Vec<Value>>>(query)?;
+    Ok(response.resultset.first().and_then(|row| row.first()).cloned().unwrap_or(Value::Null))
+}
+
+// Records the current WAL position after a mutation so that a later strong read can wait for it.
+pub fn record_mutation(client: &Client) -> Result<(), Box<dyn std::error::Error>> {
+    let lsn = match first_cell(client, "SELECT pg_current_wal_lsn()::text")? {
+        Value::String(lsn) => lsn,
+        other => return Err(format!("Unexpected LSN value: {}", other).into()),
+    };
+    let mutation = LastMutation { lsn, timestamp: get_trusted_time() };
+    let serialized = serde_json::to_string(&mutation)?;
+    storage::ledger_set(CONSISTENCY_TABLE, &mutation_key(client.database_id()), serialized.as_bytes())?;
+    remove_legacy_key(CONSISTENCY_TABLE, &mutation_key(client.database_id()), &legacy_mutation_key(client.database_id()));
+    Ok(())
+}
+
+fn load_last_mutation(database_id: &str) -> Option<LastMutation> {
+    ledger_get(CONSISTENCY_TABLE, &mutation_key(database_id), &legacy_mutation_key(database_id))
+        .and_then(|v| serde_json::from_slice::<LastMutation>(&v).ok())
+}
+
+// Prepares a read with the requested consistency. For strong reads on a standby, polls
+// pg_last_wal_replay_lsn() until the caller's last mutation has been replayed or the timeout expires.
+pub fn ensure_read_consistency(client: &Client, requested: ReadConsistency, timeout_ms: Option<u64>) -> Result<ConsistencyReport, Box<dyn std::error::Error>> {
+    let mut report = ConsistencyReport { requested, served_by: ServedBy::Primary, awaited_lsn: None, polls: 0 };
+
+    let in_recovery = first_cell(client, "SELECT pg_is_in_recovery()")?.as_bool().unwrap_or(false);
+    if !in_recovery {
+        return Ok(report);
+    }
+    report.served_by = ServedBy::Replica;
+    if requested == ReadConsistency::Default {
+        return Ok(report);
+    }
+
+    let mutation = match load_last_mutation(client.database_id()) {
+        Some(m) if is_valid_lsn(&m.lsn) => m,
+        _ => return Ok(report),
+    };
+    report.awaited_lsn = Some(mutation.lsn.clone());
+
+    let max_polls = timeout_ms.unwrap_or(DEFAULT_CONSISTENCY_TIMEOUT_MS) / REPLAY_POLL_INTERVAL_MS;
+    let query = format!(
+        "SELECT pg_last_wal_replay_lsn() >= '{}'::pg_lsn FROM pg_sleep({})",
+        mutation.lsn,
+        REPLAY_POLL_INTERVAL_MS as f64 / 1000.0
+    );
+    loop {
+        if first_cell(client, &query)?.as_bool().unwrap_or(false) {
+            return Ok(report);
+        }
+        report.polls += 1;
+        if report.polls >= max_polls {
+            // No primary route is registered for a standby, so the read is served stale and flagged as such
+            report.served_by = ServedBy::StaleReplica;
+            return Ok(report);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConsistentReadInput {
+    pub database_id: String,
+    pub statements: Vec<String>,
+    // Snapshot exported by a previous call, imported instead of exporting a new one
+    #[serde(default)]
+    pub snapshot_id: Option<String>,
+    // Opts out of the deployment default_query_limit
+    #[serde(default)]
+    pub no_limit: bool,
+    // Runs ORDER BY, ranges and comparisons with literals on encrypted columns instead of refusing them
+    #[serde(default)]
+    pub allow_ciphertext_ops: bool,
+    // Fields every resultset is cut down to, in this order; all of them when not given
+    #[serde(default)]
+    pub select_fields: Option<Vec<String>>,
+}
+
+// Resultset cut at the default query limit, next returns the rows that were cut
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TruncatedResult {
+    pub index: usize,
+    pub next: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConsistentReadOutcome {
+    pub snapshot_id: String,
+    // Whether the snapshot was imported from a previous call
+    pub imported: bool,
+    pub resultsets: Vec<PostGreResponse<Vec<Vec<Value>>>>,
+    pub truncated: Vec<TruncatedResult>,
+}
+
+// Snapshot identifiers as returned by pg_export_snapshot(), e.g. "00000003-0000001B-1"
+pub fn is_valid_snapshot_id(snapshot_id: &str) -> bool {
+    let parts: Vec<&str> = snapshot_id.split('-').collect();
+    (parts.len() == 2 || parts.len() == 3)
+        && parts.iter().all(|p| !p.is_empty() && p.len() <= 16 && p.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+// Only single read-only statements may run in a session
+pub(crate) fn check_session_statement(sql: &str) -> Result<(), String> {
+    let statements = statement::split_statements(sql).map_err(|e| e.message)?;
+    if statements.len() != 1 {
+        return Err(format!("expected a single statement, found {}", statements.len()));
+    }
+    match statement::leading_keyword(sql).as_deref() {
+        Some("SELECT") | Some("VALUES") | Some("TABLE") if statement::classify(sql) == StatementKind::Query => (),
+        _ => return Err("only SELECT statements are allowed".to_string()),
+    }
+    // Data-modifying CTEs hide their verb below the top level
+    let tokens = statement::tokenize(sql).map_err(|e| e.message)?;
+    if tokens.iter().any(|t| ["INSERT", "UPDATE", "DELETE", "MERGE"].iter().any(|k| t.is_keyword(k))) {
+        return Err("data-modifying statements are not allowed".to_string());
+    }
+    Ok(())
+}
+
+fn run_session(client: &Client, input: &ConsistentReadInput, query_limit: u64) -> Result<ConsistentReadOutcome, Box<dyn std::error::Error>> {
+    capabilities::require(client.database_id(), Capability::SnapshotExport)?;
+    let (snapshot_id, imported) = match &input.snapshot_id {
+        Some(snapshot_id) => {
+            if !is_valid_snapshot_id(snapshot_id) {
+                return Err(format!("Invalid snapshot id '{}'", snapshot_id).into());
+            }
+            // A snapshot lives only as long as the transaction that exported it
+            if let Err(err) = client.execute(&format!("SET TRANSACTION SNAPSHOT '{}'", snapshot_id)) {
+                return Err(format!("SnapshotExpired: snapshot {} can no longer be imported, start a new session ({})", snapshot_id, err).into());
+            }
+            (snapshot_id.clone(), true)
+        }
+        None => match first_cell(client, "SELECT pg_export_snapshot()")? {
+            Value::String(id) => (id, false),
+            other => return Err(format!("Unexpected snapshot id: {}", other).into()),
+        },
+    };
+
+    let mut resultsets = Vec::new();
+    let mut truncated = Vec::new();
+    for (index, sql) in input.statements.iter().enumerate() {
+        let mut limited = client.query_limited(sql, query_limit)?;
+        if let Some(select_fields) = input.select_fields.as_ref() {
+            database::project_fields(&mut limited.response, select_fields).map_err(|e| format!("Statement {}: {}", index, e))?;
+        }
+        resultsets.push(limited.response);
+        if let Some(next) = limited.next {
+            truncated.push(TruncatedResult { index, next });
+        }
+    }
+    Ok(ConsistentReadOutcome { snapshot_id, imported, resultsets, truncated })
+}
+
+// Runs a list of SELECTs in one REPEATABLE READ transaction and returns the snapshot they all saw.
+pub fn consistent_read_session(cmd: String) {
+    let input: ConsistentReadInput = match notify::parse_input(&cmd) {
+        Some(input) => input,
+        None => return,
+    };
+    if input.statements.is_empty() {
+        notify::error("Invalid input: no statements");
+        return;
+    }
+    if input.statements.len() > MAX_SESSION_STATEMENTS {
+        notify::error(&format!("At most {} statements per call, pass the returned snapshot_id to continue", MAX_SESSION_STATEMENTS));
+        return;
+    }
+    for (index, sql) in input.statements.iter().enumerate() {
+        if let Err(err) = check_session_statement(sql) {
+            notify::error(&format!("Statement {} rejected: {}", index, err));
+            return;
+        }
+    }
+    if !input.allow_ciphertext_ops && !ciphertext_ops::allow_statements(&input.database_id, input.statements.iter().map(|s| s.as_str())) {
+        return;
+    }
+
+    let query_limit = match DeploymentSettings::load() {
+        Ok(settings) if !input.no_limit => settings.default_query_limit,
+        Ok(_) => 0,
+        Err(err) => {
+            notify::error_with_code(&format!("Failed to load settings: {}", err), DatabaseError::code_of(err.as_ref()));
+            return;
+        }
+    };
+
+    let mut client: database::Client = match database::Client::load(input.database_id.clone()) {
+        Ok(c) => c,
+        Err(err) => {
+            notify::error_with_code(&format!("Failed to load client: {}", err), err.code());
+            return;
+        }
+    };
+    // Bypass tokens are consumed by a ledger write, which a query route cannot make
+    if !strict::allow_statements(&mut client, input.statements.iter().map(|s| s.as_str()), None) {
+        return;
+    }
+    match client.connect() {
+        Ok(_) => (),
+        Err(err) => {
+            notify::error_with_code(&format!("Failed to connect to client: {}", err), err.code());
+            return;
+        }
+    };
+
+    if let Err(err) = client.execute("BEGIN ISOLATION LEVEL REPEATABLE READ READ ONLY") {
+        notify::error_with_code(&format!("Failed to open the session: {}", err), err.code());
+        return;
+    }
+    let outcome = run_session(&client, &input, query_limit);
+    let end = if outcome.is_ok() { "COMMIT" } else { "ROLLBACK" };
+    if let Err(err) = client.execute(end) {
+        notify::error_with_code(&format!("Failed to close the session: {}", err), err.code());
+        return;
+    }
+    match outcome {
+        Ok(outcome) => {
+            notify::result(&outcome);
+        },
+        Err(err) => {
+            notify::error_with_code(&format!("Consistent read failed: {}", err), DatabaseError::code_of(err.as_ref()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_valid_lsn() {
+        assert!(is_valid_lsn("16/B374D848"));
+        assert!(is_valid_lsn("0/0"));
+        assert!(!is_valid_lsn("16B374D848"));
+        assert!(!is_valid_lsn("16/'; DROP TABLE x; --"));
+        assert!(!is_valid_lsn("/1"));
+    }
+
+    #[test]
+    fn test_is_valid_snapshot_id() {
+        assert!(is_valid_snapshot_id("00000003-0000001B-1"));
+        assert!(is_valid_snapshot_id("00000003-0000001B"));
+        assert!(!is_valid_snapshot_id("00000003"));
+        assert!(!is_valid_snapshot_id("0003-'; DROP TABLE x; --"));
+    }
+
+    #[test]
+    fn test_check_session_statement() {
+        assert!(check_session_statement("SELECT * FROM users").is_ok());
+        assert!(check_session_statement("WITH t AS (SELECT 1) SELECT * FROM t").is_ok());
+        assert!(check_session_statement("SELECT 1; SELECT 2").is_err());
+        assert!(check_session_statement("DELETE FROM users").is_err());
+        assert!(check_session_statement("WITH d AS (DELETE FROM users RETURNING *) SELECT * FROM d").is_err());
+    }
+}
@@ -0,0 +1,154 @@
+use std::collections::HashSet;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{host::normalize_untyped, utils::{quote_ident, sql_literal}};
+
+// Unique constraints stay meaningful on deterministic ciphertext: equal values encrypt to equal strings.
+// A row-bound column or one with an aad_template encrypts equal values differently per row, so the database no
+// longer sees duplicates.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConstraintKind {
+    Constraint,
+    // Unique index created without a constraint
+    Index,
+}
+
+// Unique constraint or index of a table, with the definition needed to create it again
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UniqueConstraint {
+    pub name: String,
+    pub kind: ConstraintKind,
+    pub columns: Vec<String>,
+    // pg_get_constraintdef for a constraint, pg_get_indexdef for an index
+    pub definition: String,
+}
+
+impl UniqueConstraint {
+    // The name comes from the catalog, any character may be in it
+    fn quoted_name(&self) -> Result<String, Box<dyn std::error::Error>> {
+        quote_ident(&format!("\"{}\"", self.name.replace('"', "\"\"")))
+    }
+
+    pub fn drop_statement(&self, table: &str) -> Result<String, Box<dyn std::error::Error>> {
+        Ok(match self.kind {
+            ConstraintKind::Constraint => format!("ALTER TABLE {} DROP CONSTRAINT IF EXISTS {}", quote_ident(table)?, self.quoted_name()?),
+            ConstraintKind::Index => format!("DROP INDEX IF EXISTS {}", self.quoted_name()?),
+        })
+    }
+
+    pub fn create_statement(&self, table: &str) -> Result<String, Box<dyn std::error::Error>> {
+        Ok(match self.kind {
+            ConstraintKind::Constraint => format!("ALTER TABLE {} ADD CONSTRAINT {} {}", quote_ident(table)?, self.quoted_name()?, self.definition),
+            ConstraintKind::Index => self.definition.clone(),
+        })
+    }
+}
+
+// One row per constraint column: name, kind, definition, column. Unique indexes backing a constraint are left out.
+pub fn unique_constraints_query(table: &str) -> String {
+    let table = sql_literal(&Value::String(table.to_string()));
+    format!("SELECT c.conname, 'constraint', pg_get_constraintdef(c.oid), a.attname \
+        FROM pg_constraint c JOIN pg_class t ON t.oid = c.conrelid \
+        JOIN pg_attribute a ON a.attrelid = t.oid AND a.attnum = ANY(c.conkey) \
+        WHERE c.contype = 'u' AND t.relname = {table} \
+        UNION ALL \
+        SELECT i.relname, 'index', pg_get_indexdef(i.oid), a.attname \
+        FROM pg_index x JOIN pg_class i ON i.oid = x.indexrelid JOIN pg_class t ON t.oid = x.indrelid \
+        JOIN pg_attribute a ON a.attrelid = t.oid AND a.attnum = ANY(x.indkey) \
+        WHERE x.indisunique AND NOT x.indisprimary AND t.relname = {table} \
+        AND NOT EXISTS (SELECT 1 FROM pg_constraint c WHERE c.conindid = x.indexrelid) \
+        ORDER BY 1", table = table)
+}
+
+pub fn parse_unique_constraints(rows: &[Vec<Value>]) -> Vec<UniqueConstraint> {
+    let mut constraints: Vec<UniqueConstraint> = Vec::new();
+    for row in rows {
+        let text = |i: usize| row.get(i).and_then(|v| v.as_str()).unwrap_or_default().to_string();
+        let (name, column) = (text(0), text(3));
+        match constraints.iter_mut().find(|c| c.name == name) {
+            Some(existing) => existing.columns.push(column),
+            None => constraints.push(UniqueConstraint {
+                name,
+                kind: if text(1) == "index" { ConstraintKind::Index } else { ConstraintKind::Constraint },
+                columns: vec![column],
+                definition: text(2),
+            }),
+        }
+    }
+    constraints
+}
+
+// Constraints to drop before the given columns get encrypted per row. Refused unless relax is set.
+pub fn plan_unique_constraints(constraints: &[UniqueConstraint], aad_columns: &[String], relax: bool) -> Result<Vec<UniqueConstraint>, String> {
+    let affected: Vec<UniqueConstraint> = constraints.iter()
+        .filter(|c| c.columns.iter().any(|column| aad_columns.contains(column)))
+        .cloned()
+        .collect();
+    if affected.is_empty() || relax {
+        return Ok(affected);
+    }
+    let listed: Vec<String> = affected.iter().map(|c| format!("{} ({})", c.name, c.columns.join(","))).collect();
+    Err(format!("Unique constraints {} cannot be enforced on columns encrypted per row; \
+        set relax_unique to drop them and check uniqueness when rows are inserted", listed.join(", ")))
+}
+
+fn tuple_key(tuple: &[Value]) -> Option<String> {
+    // Tuples holding a null never collide, as in SQL
+    if tuple.iter().any(|v| v.is_null()) {
+        return None;
+    }
+    let normalized: Vec<Value> = tuple.iter().map(|v| normalize_untyped(v.clone())).collect();
+    Some(Value::Array(normalized).to_string())
+}
+
+// Index of the first incoming tuple equal to an existing one or to an earlier incoming one
+pub fn find_duplicate_tuple(existing: &[Vec<Value>], incoming: &[Vec<Value>]) -> Option<usize> {
+    let mut seen: HashSet<String> = existing.iter().filter_map(|t| tuple_key(t)).collect();
+    incoming.iter().position(|t| match tuple_key(t) {
+        Some(key) => !seen.insert(key),
+        None => false,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn catalog_rows() -> Vec<Vec<Value>> {
+        vec![
+            vec![json!("users_email_key"), json!("constraint"), json!("UNIQUE (email)"), json!("email")],
+            vec![json!("users_name_tenant_idx"), json!("index"), json!("CREATE UNIQUE INDEX users_name_tenant_idx ON public.users USING btree (name, tenant_id)"), json!("name")],
+            vec![json!("users_name_tenant_idx"), json!("index"), json!("CREATE UNIQUE INDEX users_name_tenant_idx ON public.users USING btree (name, tenant_id)"), json!("tenant_id")],
+        ]
+    }
+
+    #[test]
+    fn test_plan_unique_constraints() {
+        let constraints = parse_unique_constraints(&catalog_rows());
+        assert_eq!(constraints.len(), 2);
+        assert_eq!(constraints[1].columns, vec!["name", "tenant_id"]);
+        assert_eq!(constraints[0].drop_statement("users").unwrap(), r#"ALTER TABLE "users" DROP CONSTRAINT IF EXISTS "users_email_key""#);
+        assert_eq!(constraints[0].create_statement("users").unwrap(), r#"ALTER TABLE "users" ADD CONSTRAINT "users_email_key" UNIQUE (email)"#);
+        assert_eq!(constraints[1].drop_statement("users").unwrap(), r#"DROP INDEX IF EXISTS "users_name_tenant_idx""#);
+
+        let aad_columns = vec!["name".to_string()];
+        let err = plan_unique_constraints(&constraints, &aad_columns, false).unwrap_err();
+        assert!(err.starts_with("Unique constraints users_name_tenant_idx (name,tenant_id) cannot be enforced"));
+        let relaxed = plan_unique_constraints(&constraints, &aad_columns, true).unwrap();
+        assert_eq!(relaxed.iter().map(|c| c.name.as_str()).collect::<Vec<_>>(), vec!["users_name_tenant_idx"]);
+        assert!(plan_unique_constraints(&constraints, &["address".to_string()], false).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_find_duplicate_tuple() {
+        let existing = vec![vec![json!("jane"), json!(1)], vec![json!("john"), Value::Null]];
+        assert_eq!(find_duplicate_tuple(&existing, &[vec![json!("jane"), json!(2)], vec![json!("jane"), json!("1")]]), Some(1));
+        assert_eq!(find_duplicate_tuple(&existing, &[vec![json!("john"), Value::Null], vec![json!("john"), Value::Null]]), None);
+        assert_eq!(find_duplicate_tuple(&[], &[vec![json!("ann"), json!(3)], vec![json!("bob"), json!(3)], vec![json!("ann"), json!(3)]]), Some(2));
+    }
+}
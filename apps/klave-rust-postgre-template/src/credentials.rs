@@ -0,0 +1,211 @@
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{database, notify, routing, settings::DeploymentSettings, storage, tls::TlsStatus, utils::get_trusted_time};
+
+pub(crate) const CREDENTIAL_HEALTH_TABLE: &str = "CredentialHealthTable";
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+// Authentication failures in a row after which the stored credentials are flagged as suspect
+const SUSPECT_AFTER_FAILURES: u32 = 3;
+// A successful connection refreshes the record at most this often, failures are always recorded
+const SUCCESS_REFRESH_NANOS: u64 = 60 * NANOS_PER_SECOND;
+// SQLSTATE class 28, invalid authorization specification
+const AUTH_SQLSTATES: [&str; 2] = ["28000", "28p01"];
+const AUTH_MESSAGES: [&str; 3] = ["password authentication failed", "authentication failed", "no pg_hba.conf entry"];
+
+// Authentication outcomes of the connections opened with the stored credentials of a client
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CredentialHealth {
+    #[serde(default)]
+    pub last_authenticated_at: Option<u64>,
+    #[serde(default)]
+    pub consecutive_auth_failures: u32,
+    #[serde(default)]
+    pub last_auth_error: Option<String>,
+    // SSL state of the last connection opened, see tls::TLS_STATUS_QUERY
+    #[serde(default)]
+    pub tls: Option<TlsStatus>,
+}
+
+impl CredentialHealth {
+    pub fn load(database_id: &str) -> CredentialHealth {
+        crate::runtime::ledger::get_table(CREDENTIAL_HEALTH_TABLE).get(database_id).ok()
+            .and_then(|v| serde_json::from_slice::<CredentialHealth>(&v).ok())
+            .unwrap_or_default()
+    }
+
+    fn save(&self, database_id: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let serialized = serde_json::to_string(self)?;
+        storage::ledger_set(CREDENTIAL_HEALTH_TABLE, database_id, serialized.as_bytes())
+    }
+
+    pub fn suspect(&self) -> bool {
+        self.consecutive_auth_failures >= SUSPECT_AFTER_FAILURES
+    }
+
+    // Folds the outcome of a connection in; returns whether the record has to be written. Errors other
+    // than authentication ones, e.g. an unreachable host, say nothing about the credentials.
+    pub fn observe(&mut self, outcome: Result<(), &str>, now: u64) -> bool {
+        match outcome {
+            Ok(()) => {
+                let stale = self.last_authenticated_at.map(|at| now.saturating_sub(at) >= SUCCESS_REFRESH_NANOS).unwrap_or(true);
+                let recovered = self.consecutive_auth_failures > 0;
+                self.last_authenticated_at = Some(now);
+                self.consecutive_auth_failures = 0;
+                self.last_auth_error = None;
+                stale || recovered
+            }
+            Err(message) if is_auth_error(message) => {
+                self.consecutive_auth_failures += 1;
+                self.last_auth_error = Some(message.to_string());
+                true
+            }
+            Err(_) => false,
+        }
+    }
+}
+
+// By SQLSTATE when the host passes it on, by the server message otherwise
+pub fn is_auth_error(message: &str) -> bool {
+    let lower = message.to_lowercase();
+    lower.split(|c: char| !c.is_ascii_alphanumeric()).any(|word| AUTH_SQLSTATES.contains(&word))
+        || AUTH_MESSAGES.iter().any(|m| lower.contains(m))
+}
+
+fn describe_duration(nanos: u64) -> String {
+    let hours = nanos / NANOS_PER_SECOND / 3600;
+    match hours {
+        0 => "less than an hour".to_string(),
+        1..=47 => format!("{} hours", hours),
+        _ => format!("{} days", hours / 24),
+    }
+}
+
+// Warning for credentials that expire within the window or have expired, a window of 0 only reports the latter
+pub fn expiry_warning(database_id: &str, expire_at: Option<u64>, now: u64, window_seconds: u64) -> Option<String> {
+    let expire_at = expire_at?;
+    if now >= expire_at {
+        return Some(format!("Credentials of database {} expired {} ago", database_id, describe_duration(now - expire_at)));
+    }
+    if expire_at - now > window_seconds.saturating_mul(NANOS_PER_SECOND) {
+        return None;
+    }
+    Some(format!("Credentials of database {} expire in {}", database_id, describe_duration(expire_at - now)))
+}
+
+// Called on every connection attempt of a client; the warnings go out with the response of the call
+pub(crate) fn after_connect(database_id: &str, expire_at: Option<u64>, outcome: Result<(), &str>) {
+    let now = get_trusted_time();
+    let settings = DeploymentSettings::load().unwrap_or_default();
+    if let Some(warning) = expiry_warning(database_id, expire_at, now, settings.credential_expiry_warning_seconds) {
+        notify::warning(&warning);
+    }
+    let mut health = CredentialHealth::load(database_id);
+    let was_suspect = health.suspect();
+    // Best effort, a failed write never fails the call; query routes leave the record to the next transaction
+    if health.observe(outcome, now) && routing::can_write_ledger() {
+        if let Err(err) = health.save(database_id) {
+            notify::warning(&format!("Failed to record credential health of {}: {}", database_id, err));
+        }
+    }
+    if health.suspect() {
+        notify::warning(&format!("Credentials of database {} are suspect: {} authentication failures in a row", database_id, health.consecutive_auth_failures));
+    } else if was_suspect {
+        notify::warning(&format!("Credentials of database {} authenticated again", database_id));
+    }
+}
+
+// Called when a new connection was opened; written only from transaction routes, and only when its SSL state
+// differs from the recorded one
+pub(crate) fn record_tls(database_id: &str, status: &TlsStatus) {
+    if !routing::can_write_ledger() {
+        return;
+    }
+    let mut health = CredentialHealth::load(database_id);
+    if health.tls.as_ref().map(|t| t.same_connection(status)).unwrap_or(false) {
+        return;
+    }
+    health.tls = Some(status.clone());
+    if let Err(err) = health.save(database_id) {
+        notify::warning(&format!("Failed to record the TLS status of {}: {}", database_id, err));
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TestCredentialsInput {
+    pub database_id: String,
+}
+
+// Opens a fresh connection with the stored credentials and runs a trivial query; the client record is not written
+pub fn test_credentials(cmd: String) {
+    let input: TestCredentialsInput = match notify::parse_input(&cmd) {
+        Some(input) => input,
+        None => return,
+    };
+    let mut client = match database::Client::load(input.database_id.clone()) {
+        Ok(c) => c,
+        Err(err) => {
+            notify::error_with_code(&format!("Failed to load client: {}", err), err.code());
+            return;
+        }
+    };
+    let error = match client.connect_fresh() {
+        Ok(_) => client.query::<Vec<Vec<serde_json::Value>>>("SELECT 1").err().map(|e| e.to_string()),
+        Err(err) => Some(err.to_string()),
+    };
+    let health = CredentialHealth::load(&input.database_id);
+    let now = get_trusted_time();
+    notify::result(&json!({
+        "database_id": input.database_id,
+        "authenticated": error.is_none(),
+        "error": error,
+        "credentials_expire_at": client.credentials_expire_at(),
+        "expires_in_seconds": client.credentials_expire_at().map(|at| at.saturating_sub(now) / NANOS_PER_SECOND),
+        "credentials_suspect": health.suspect(),
+        "last_authenticated_at": health.last_authenticated_at,
+        "tls": health.tls,
+    }));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_auth_error() {
+        assert!(is_auth_error("db error: FATAL: password authentication failed for user \"app\""));
+        assert!(is_auth_error("SQLSTATE 28P01"));
+        assert!(is_auth_error("error 28000: role \"app\" does not exist"));
+        assert!(!is_auth_error("connection refused"));
+        assert!(!is_auth_error("SQLSTATE 42P01: relation does not exist"));
+    }
+
+    #[test]
+    fn test_observe_flags_suspect_credentials() {
+        let mut health = CredentialHealth::default();
+        assert!(health.observe(Ok(()), 10));
+        // Refreshed at most once a minute
+        assert!(!health.observe(Ok(()), 20));
+        assert!(!health.observe(Err("connection refused"), 30));
+        for _ in 0..SUSPECT_AFTER_FAILURES {
+            assert!(!health.suspect());
+            assert!(health.observe(Err("password authentication failed"), 40));
+        }
+        assert!(health.suspect());
+        assert_eq!(health.last_authenticated_at, Some(20));
+        assert!(health.observe(Ok(()), 50));
+        assert!(!health.suspect());
+        assert_eq!(health.last_auth_error, None);
+    }
+
+    #[test]
+    fn test_expiry_warning() {
+        let day = 24 * 3600 * NANOS_PER_SECOND;
+        assert_eq!(expiry_warning("db", None, 0, 7 * 24 * 3600), None);
+        assert_eq!(expiry_warning("db", Some(10 * day), 0, 7 * 24 * 3600), None);
+        assert_eq!(expiry_warning("db", Some(3 * day), 0, 7 * 24 * 3600).unwrap(), "Credentials of database db expire in 3 days");
+        assert_eq!(expiry_warning("db", Some(day), 0, 7 * 24 * 3600).unwrap(), "Credentials of database db expire in 24 hours");
+        assert!(expiry_warning("db", Some(day), 2 * day, 0).unwrap().contains("expired 24 hours ago"));
+    }
+}
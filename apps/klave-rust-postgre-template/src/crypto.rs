@@ -1,10 +1,11 @@
 use hex::encode;
-use klave::crypto::subtle::{self, CryptoKey, EncryptAlgorithm, KeyDerivationAlgorithm, HkdfDerivParams, AesGcmParams, AesKeyGenParams, DerivedKeyAlgorithm, derive_key, encrypt, export_key};
+use crate::runtime::subtle::{self, CryptoKey, EncryptAlgorithm, KeyDerivationAlgorithm, HkdfDerivParams, AesGcmParams, AesKeyGenParams, DerivedKeyAlgorithm, decrypt, derive_key, encrypt, export_key};
 use serde_json::Value;
-use crate::utils::get_serde_value_into_bytes;
+use crate::{host::is_lossy_text, notify, primary_key::PrimaryKey, utils::get_serde_value_into_bytes};
 
 // AES-GCM constants
 pub const AES_GCM_IV_SIZE: usize = 12;      // 12 bytes (96 bits) - optimal for AES-GCM
+pub const AES_GCM_TAG_SIZE: usize = 16;     // 16 bytes (128 bits)
 
 
 pub fn generate_ecc_crypto_key() -> Result<CryptoKey, Box<dyn std::error::Error>> {
@@ -14,8 +15,8 @@ pub fn generate_ecc_crypto_key() -> Result<CryptoKey, Box<dyn std::error::Error>
     let private_key = match subtle::generate_key(&gen_algorithm, false, &["sign", "derive_key"]) {
         Ok(result) => result,
         Err(err) => {
-            klave::notifier::send_string(&err.to_string());
-            return Err(err.into());
+            notify::warning(&err.to_string());
+            return Err(err);
         }
     };
     Ok(private_key)
@@ -23,22 +24,28 @@ pub fn generate_ecc_crypto_key() -> Result<CryptoKey, Box<dyn std::error::Error>
 
 pub fn compute_sha256_hex_string(data: &[u8]) -> String {
     // Using Klave's crypto utilities
-    match klave::crypto::sha::digest("SHA2-256", data) {
+    match crate::runtime::sha::digest("SHA2-256", data) {
         Ok(hash) => hex::encode(hash),
         Err(e) => {
-            klave::notifier::send_string(&format!("SHA2-256 computation failed: {}", e));
+            notify::warning(&format!("SHA2-256 computation failed: {}", e));
             String::new()
         }
     }
 }
 
+// HKDF salt and info the key of a column is derived with
+pub fn key_derivation_labels(table: &str, column: &str) -> (String, String) {
+    (format!("klave-salt-encryption-'{}'", table), format!("klave-info-encryption-'{}'", column))
+}
+
 pub fn derive_aes_gcm_key(master_key: &CryptoKey, table: String, column_name: String) -> Result<CryptoKey, Box<dyn std::error::Error>> {
     // Use HKDF to derive a key from the master key and the column name
+    let (salt, info) = key_derivation_labels(&table, &column_name);
     let hkdf_derivation_params = HkdfDerivParams {
         hash: "SHA-256".to_string(),
-        salt: format!("klave-salt-encryption-'{}'", table).into_bytes(),
+        salt: salt.into_bytes(),
         // Use the value as info to ensure uniqueness per column and row
-        info: format!("klave-info-encryption-'{}'", column_name).into_bytes(),
+        info: info.into_bytes(),
     };
     let derivation_algorithm = KeyDerivationAlgorithm::Hkdf(hkdf_derivation_params);
     let aes_key_gen_params = AesKeyGenParams {
@@ -47,11 +54,11 @@ pub fn derive_aes_gcm_key(master_key: &CryptoKey, table: String, column_name: St
     let derived_key_algorithm = DerivedKeyAlgorithm::Aes(aes_key_gen_params);
     let extractable = false;
     let usages = ["encrypt", "decrypt"];
-    let aes_gcm_key = match derive_key(&derivation_algorithm, &master_key, &derived_key_algorithm, extractable, &usages) {
+    let aes_gcm_key = match derive_key(&derivation_algorithm, master_key, &derived_key_algorithm, extractable, &usages) {
         Ok(key) => key,
         Err(err) => {
-            klave::notifier::send_string(&format!("Failed to derive key: {}", err));
-            return Err(err.into());
+            notify::warning(&format!("Failed to derive key: {}", err));
+            return Err(err);
         }
     };
     Ok(aes_gcm_key)
@@ -61,22 +68,22 @@ pub fn derive_iv(master_key: &CryptoKey, column_name: String, value: Value) -> R
     let value_in_bytes = match get_serde_value_into_bytes(&value) {
         Ok(bytes) => bytes,
         Err(err) => {
-            klave::notifier::send_string(&format!("Failed to convert value to bytes: {}", err));
-            return Err(err.into());
+            notify::warning(&format!("Failed to convert value to bytes: {}", err));
+            return Err(err);
         }
     };
-    let salt = match klave::crypto::sha::digest("SHA-256", &value_in_bytes)
+    let salt = match crate::runtime::sha::digest("SHA-256", &value_in_bytes)
     {
         Ok(s) => s,
         Err(err) => {
-            klave::notifier::send_string(&format!("Failed to compute salt: {}", err));
-            return Err(err.into());
+            notify::warning(&format!("Failed to compute salt: {}", err));
+            return Err(err);
         }
     };
     let hkdf_deriv_params_iv = HkdfDerivParams {
         hash: "SHA-256".to_string(),
         info: format!("klave-iv-'{}", column_name).into_bytes(),
-        salt: salt,
+        salt,
     };
     let deriv_algo_iv = KeyDerivationAlgorithm::Hkdf(hkdf_deriv_params_iv);
     let aes_key_gen_params = AesKeyGenParams {
@@ -85,19 +92,19 @@ pub fn derive_iv(master_key: &CryptoKey, column_name: String, value: Value) -> R
     let derived_key_algorithm = DerivedKeyAlgorithm::Aes(aes_key_gen_params);
     let usages = ["encrypt", "decrypt"];
     let extractable = true;
-    let iv_key = match derive_key(&deriv_algo_iv, &master_key, &derived_key_algorithm, extractable, &usages) {
+    let iv_key = match derive_key(&deriv_algo_iv, master_key, &derived_key_algorithm, extractable, &usages) {
         Ok(key) => key,
         Err(err) => {
-            klave::notifier::send_string(&format!("Failed to derive key: {}", err));
-            return Err(err.into());
+            notify::warning(&format!("Failed to derive key: {}", err));
+            return Err(err);
         }
     };
     let iv = match export_key("raw", &iv_key)
     {
-        Ok(mut iv) => {iv.truncate(AES_GCM_IV_SIZE as usize); iv},
+        Ok(mut iv) => {iv.truncate(AES_GCM_IV_SIZE); iv},
         Err(err) => {
-            klave::notifier::send_string(&format!("Failed to export key: {}", err));
-            return Err(err.into());
+            notify::warning(&format!("Failed to export key: {}", err));
+            return Err(err);
         }
     };
     Ok(iv)
@@ -108,26 +115,26 @@ pub fn encrypt_value(master_key: &CryptoKey, table_name: String, column_name: St
     let value_in_bytes = match get_serde_value_into_bytes(&value) {
         Ok(bytes) => bytes,
         Err(err) => {
-            klave::notifier::send_string(&format!("Failed to convert value to bytes: {}", err));
+            notify::warning(&format!("Failed to convert value to bytes: {}", err));
             return Err(err);
         }
     };
 
     // Derive AES-GCM key for the column
-    let aes_gcm_key = match derive_aes_gcm_key(&master_key,table_name.clone(), column_name.clone()) {
+    let aes_gcm_key = match derive_aes_gcm_key(master_key,table_name.clone(), column_name.clone()) {
         Ok(key) => key,
         Err(err) => {
-            klave::notifier::send_string(&format!("Failed to derive AES-GCM key: {}", err));
+            notify::warning(&format!("Failed to derive AES-GCM key: {}", err));
             return Err(err);
         }
     };
     // Compute the iv deterministically from the point of view of the value to encrypt.
     // I derive a key from the master key and the value to encrypt, export it as raw bytes, and use the first 12 bytes as the iv.
-    let iv = match derive_iv(&master_key, column_name.clone(), value.clone())
+    let iv = match derive_iv(master_key, column_name.clone(), value.clone())
     {
         Ok(res) => res,
         Err(err) => {
-            klave::notifier::send_string(&format!("Failed to derive AES-GCM key: {}", err));
+            notify::warning(&format!("Failed to derive AES-GCM key: {}", err));
             return Err(err);
         }
     };
@@ -143,7 +150,7 @@ pub fn encrypt_value(master_key: &CryptoKey, table_name: String, column_name: St
     let mut encrypted_value = match encrypt(&encrypt_algo, &aes_gcm_key, &value_in_bytes) {
         Ok(encrypted) => encrypted,
         Err(err) => {
-            klave::notifier::send_string(&format!("Failed to encrypt value: {}", err));
+            notify::warning(&format!("Failed to encrypt value: {}", err));
             return Err(err);
         }
     };
@@ -154,4 +161,713 @@ pub fn encrypt_value(master_key: &CryptoKey, table_name: String, column_name: St
     let encoded_iv_value = encode(&iv_and_encrypted);
 
     Ok(encoded_iv_value)
-}
\ No newline at end of file
+}
+
+const HMAC_SHA256_BLOCK_SIZE: usize = 64;
+// Row MACs are truncated to 128 bits
+pub const ROW_MAC_SIZE: usize = 16;
+
+// HMAC-SHA256 (RFC 2104) built on the Klave digest primitive
+pub fn hmac_sha256(key: &[u8], data: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let mut block_key = if key.len() > HMAC_SHA256_BLOCK_SIZE {
+        crate::runtime::sha::digest("SHA-256", key)?
+    } else {
+        key.to_vec()
+    };
+    block_key.resize(HMAC_SHA256_BLOCK_SIZE, 0);
+
+    let mut inner: Vec<u8> = block_key.iter().map(|b| b ^ 0x36).collect();
+    inner.extend_from_slice(data);
+    let inner_hash = crate::runtime::sha::digest("SHA-256", &inner)?;
+
+    let mut outer: Vec<u8> = block_key.iter().map(|b| b ^ 0x5c).collect();
+    outer.extend_from_slice(&inner_hash);
+    crate::runtime::sha::digest("SHA-256", &outer)
+}
+
+// Derives the raw integrity key used for the row MACs of a table.
+pub fn derive_integrity_key(master_key: &CryptoKey, table: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let hkdf_derivation_params = HkdfDerivParams {
+        hash: "SHA-256".to_string(),
+        salt: format!("klave-salt-integrity-'{}'", table).into_bytes(),
+        info: b"klave-info-row-mac".to_vec(),
+    };
+    let derivation_algorithm = KeyDerivationAlgorithm::Hkdf(hkdf_derivation_params);
+    let derived_key_algorithm = DerivedKeyAlgorithm::Aes(AesKeyGenParams { length: 256 });
+    let integrity_key = match derive_key(&derivation_algorithm, master_key, &derived_key_algorithm, true, &["sign", "verify"]) {
+        Ok(key) => key,
+        Err(err) => {
+            notify::warning(&format!("Failed to derive integrity key: {}", err));
+            return Err(err);
+        }
+    };
+    export_key("raw", &integrity_key)
+}
+
+// Canonical plaintext of the encrypted columns of a row: [table, pk, [[column, value], ...]] with columns sorted by name.
+pub fn canonical_row_plaintext(table: &str, primary_key: &Value, columns: &[(String, Value)]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let mut sorted: Vec<&(String, Value)> = columns.iter().collect();
+    sorted.sort_by(|a, b| a.0.cmp(&b.0));
+    let pairs: Vec<Value> = sorted.iter().map(|(name, value)| Value::Array(vec![Value::String(name.clone()), value.clone()])).collect();
+    let canonical = Value::Array(vec![Value::String(table.to_string()), primary_key.clone(), Value::Array(pairs)]);
+    get_serde_value_into_bytes(&canonical)
+}
+
+// Truncated HMAC of the canonical row plaintext, hex encoded
+pub fn compute_row_mac(integrity_key: &[u8], table: &str, primary_key: &Value, columns: &[(String, Value)]) -> Result<String, Box<dyn std::error::Error>> {
+    let canonical = canonical_row_plaintext(table, primary_key, columns)?;
+    let mut mac = hmac_sha256(integrity_key, &canonical)?;
+    mac.truncate(ROW_MAC_SIZE);
+    Ok(encode(mac))
+}
+
+// Blind indexes are truncated like row MACs, equal plaintexts of a column keep equal indexes
+pub const BLIND_INDEX_SIZE: usize = 16;
+
+// Derives the raw key of the blind index of a column, distinct from the key encrypting it
+pub fn derive_blind_index_key(master_key: &CryptoKey, table: &str, column: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let hkdf_derivation_params = HkdfDerivParams {
+        hash: "SHA-256".to_string(),
+        salt: format!("klave-salt-blind-index-'{}'", table).into_bytes(),
+        info: format!("klave-info-blind-index-'{}'", column).into_bytes(),
+    };
+    let derivation_algorithm = KeyDerivationAlgorithm::Hkdf(hkdf_derivation_params);
+    let derived_key_algorithm = DerivedKeyAlgorithm::Aes(AesKeyGenParams { length: 256 });
+    let index_key = match derive_key(&derivation_algorithm, master_key, &derived_key_algorithm, true, &["sign", "verify"]) {
+        Ok(key) => key,
+        Err(err) => {
+            notify::warning(&format!("Failed to derive blind index key: {}", err));
+            return Err(err);
+        }
+    };
+    export_key("raw", &index_key)
+}
+
+// HMAC of the value serialized as it is encrypted, so that a searched string finds the stored one
+pub fn compute_blind_index(index_key: &[u8], value: &Value) -> Result<String, Box<dyn std::error::Error>> {
+    let mut mac = hmac_sha256(index_key, &get_serde_value_into_bytes(value)?)?;
+    mac.truncate(BLIND_INDEX_SIZE);
+    Ok(encode(mac))
+}
+
+// Version of the additional-data encoding produced by AadTemplate::resolve
+pub const AAD_TEMPLATE_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CipherError {
+    // The additional data supplied at decryption time does not match the one bound at encryption time
+    AadMismatch(String),
+    InvalidTemplate(String),
+    Malformed(String),
+    // A key referenced by the client could not be loaded from the key store
+    KeyUnavailable(String),
+    // The plaintext holds bytes the host could not decode, encrypting it would make the loss permanent
+    LossyPlaintext(String),
+    // The caller holds a reporting-only grant, no key is loaded on its behalf
+    AccessDenied(String),
+    // The database is frozen by freeze_database, carries the reason
+    Frozen(String),
+    // A master key rotation is pending, values cannot be written until it completes
+    RotationPending(String),
+    // The value carries a format version this build cannot read
+    UnsupportedVersion(String),
+}
+
+impl CipherError {
+    // Stable error code reported to callers
+    pub fn code(&self) -> &'static str {
+        match self {
+            CipherError::AadMismatch(_) => "AadMismatch",
+            CipherError::InvalidTemplate(_) => "InvalidTemplate",
+            CipherError::Malformed(_) => "Malformed",
+            CipherError::KeyUnavailable(_) => "KeyUnavailable",
+            CipherError::LossyPlaintext(_) => "LossyPlaintext",
+            CipherError::AccessDenied(_) => "AccessDenied",
+            CipherError::Frozen(_) => "Frozen",
+            CipherError::RotationPending(_) => "RotationPending",
+            CipherError::UnsupportedVersion(_) => "UnsupportedVersion",
+        }
+    }
+
+    // Returns the CipherError behind a boxed error, if any
+    pub fn from_error<'a>(err: &'a (dyn std::error::Error + 'static)) -> Option<&'a CipherError> {
+        err.downcast_ref::<CipherError>()
+    }
+
+    pub fn is_key_unavailable(err: &(dyn std::error::Error + 'static)) -> bool {
+        matches!(CipherError::from_error(err), Some(CipherError::KeyUnavailable(_)))
+    }
+}
+
+impl std::fmt::Display for CipherError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CipherError::AadMismatch(msg) => write!(f, "AadMismatch: {}", msg),
+            CipherError::InvalidTemplate(msg) => write!(f, "InvalidTemplate: {}", msg),
+            CipherError::Malformed(msg) => write!(f, "Malformed ciphertext: {}", msg),
+            CipherError::KeyUnavailable(msg) => write!(f, "KeyUnavailable: {}", msg),
+            CipherError::LossyPlaintext(msg) => write!(f, "LossyPlaintext: {}", msg),
+            CipherError::AccessDenied(msg) => write!(f, "AccessDenied: {}", msg),
+            CipherError::Frozen(msg) => write!(f, "Frozen: {}", msg),
+            CipherError::RotationPending(msg) => write!(f, "RotationPending: {}", msg),
+            CipherError::UnsupportedVersion(msg) => write!(f, "UnsupportedVersion: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for CipherError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AadSegment {
+    Literal(String),
+    Field(String),
+}
+
+// Template binding a ciphertext to application context, e.g. "{tenant_id}|{table}|{column}".
+// `{table}` and `{column}` are resolved from the cipher, any other field from the row or the caller context.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AadTemplate {
+    pub source: String,
+    segments: Vec<AadSegment>,
+}
+
+impl AadTemplate {
+    pub const BUILTIN_FIELDS: [&'static str; 2] = ["table", "column"];
+
+    pub fn parse(source: &str) -> Result<AadTemplate, CipherError> {
+        let mut segments = Vec::new();
+        let mut literal = String::new();
+        let mut chars = source.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' => {
+                    let mut name = String::new();
+                    let mut closed = false;
+                    for n in chars.by_ref() {
+                        if n == '}' {
+                            closed = true;
+                            break;
+                        }
+                        name.push(n);
+                    }
+                    if !closed {
+                        return Err(CipherError::InvalidTemplate(format!("unclosed '{{' in template '{}'", source)));
+                    }
+                    let name = name.trim().to_string();
+                    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+                        return Err(CipherError::InvalidTemplate(format!("invalid field name '{}' in template '{}'", name, source)));
+                    }
+                    if !literal.is_empty() {
+                        segments.push(AadSegment::Literal(std::mem::take(&mut literal)));
+                    }
+                    segments.push(AadSegment::Field(name));
+                }
+                '}' => {
+                    return Err(CipherError::InvalidTemplate(format!("unexpected '}}' in template '{}'", source)));
+                }
+                _ => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            segments.push(AadSegment::Literal(literal));
+        }
+        Ok(AadTemplate { source: source.to_string(), segments })
+    }
+
+    // Fields that must be provided by the row or the caller (builtins excluded)
+    pub fn context_fields(&self) -> Vec<String> {
+        let mut fields: Vec<String> = Vec::new();
+        for segment in self.segments.iter() {
+            if let AadSegment::Field(name) = segment {
+                if !Self::BUILTIN_FIELDS.contains(&name.as_str()) && !fields.contains(name) {
+                    fields.push(name.clone());
+                }
+            }
+        }
+        fields
+    }
+
+    // Checks that every context field references a column of the table
+    pub fn validate(&self, table_columns: &[String]) -> Result<(), CipherError> {
+        for field in self.context_fields() {
+            if !table_columns.iter().any(|c| c == &field) {
+                return Err(CipherError::InvalidTemplate(format!("template '{}' references unknown column '{}'", self.source, field)));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn resolve(&self, table: &str, column: &str, context: &serde_json::Map<String, Value>) -> Result<Vec<u8>, CipherError> {
+        let mut resolved = format!("klave-aad-v{}:", AAD_TEMPLATE_VERSION);
+        for segment in self.segments.iter() {
+            match segment {
+                AadSegment::Literal(s) => resolved.push_str(s),
+                AadSegment::Field(name) if name == "table" => resolved.push_str(table),
+                AadSegment::Field(name) if name == "column" => resolved.push_str(column),
+                AadSegment::Field(name) => match context.get(name) {
+                    // JSON encoding keeps "a|b" + "c" distinct from "a" + "b|c"
+                    Some(value) => resolved.push_str(&value.to_string()),
+                    None => {
+                        return Err(CipherError::AadMismatch(format!("context field '{}' required by the aad_template of {}.{} was not supplied", name, table, column)));
+                    }
+                },
+            }
+        }
+        Ok(resolved.into_bytes())
+    }
+}
+
+// Derives the key sealing the row backups of a table.
+pub fn derive_backup_key(master_key: &CryptoKey, table: &str) -> Result<CryptoKey, Box<dyn std::error::Error>> {
+    let hkdf_derivation_params = HkdfDerivParams {
+        hash: "SHA-256".to_string(),
+        salt: format!("klave-salt-backup-'{}'", table).into_bytes(),
+        info: b"klave-info-row-backup".to_vec(),
+    };
+    let derivation_algorithm = KeyDerivationAlgorithm::Hkdf(hkdf_derivation_params);
+    let derived_key_algorithm = DerivedKeyAlgorithm::Aes(AesKeyGenParams { length: 256 });
+    match derive_key(&derivation_algorithm, master_key, &derived_key_algorithm, false, &["encrypt", "decrypt"]) {
+        Ok(key) => Ok(key),
+        Err(err) => {
+            notify::warning(&format!("Failed to derive backup key: {}", err));
+            Err(err)
+        }
+    }
+}
+
+// Format of a stored ciphertext, carried by each value so that a partially migrated column can be read.
+// Values without a prefix predate versioning.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum CiphertextVersion {
+    // Hex of the 12-byte IV followed by the AES-GCM ciphertext and tag
+    V0,
+    // The V0 payload behind a "v1:" marker, so that a value tells by itself that it is ciphertext
+    V1,
+    // The V1 payload bound to its row: table, column and primary key are authenticated as additional data
+    // and take part in the IV, so a ciphertext moved to another row or column no longer decrypts
+    V2,
+}
+
+// Version written by ColumnCipher::encrypt for lookup columns, which stay deterministic
+pub const CURRENT_CIPHERTEXT_VERSION: CiphertextVersion = CiphertextVersion::V1;
+// Version written for the other columns, see ColumnCipher::with_row_key
+pub const ROW_BOUND_CIPHERTEXT_VERSION: CiphertextVersion = CiphertextVersion::V2;
+
+impl CiphertextVersion {
+    pub const ALL: [CiphertextVersion; 3] = [CiphertextVersion::V0, CiphertextVersion::V1, CiphertextVersion::V2];
+
+    pub fn number(&self) -> u32 {
+        match self {
+            CiphertextVersion::V0 => 0,
+            CiphertextVersion::V1 => 1,
+            CiphertextVersion::V2 => 2,
+        }
+    }
+
+    pub fn from_number(number: u32) -> Option<CiphertextVersion> {
+        CiphertextVersion::ALL.into_iter().find(|v| v.number() == number)
+    }
+
+    // Name used in reports, the prefix of the value without its colon
+    pub fn label(&self) -> String {
+        format!("v{}", self.number())
+    }
+}
+
+// Splits a stored value into its version and hex payload. Hex never holds 'v' or ':', so a value
+// starting with "v<digits>:" is prefixed and anything else is a V0 payload.
+pub fn parse_ciphertext(encoded: &str) -> Result<(CiphertextVersion, &str), CipherError> {
+    let (label, payload) = match encoded.strip_prefix('v').and_then(|rest| rest.split_once(':')) {
+        Some(split) => split,
+        None => return Ok((CiphertextVersion::V0, encoded)),
+    };
+    let number = label.parse::<u32>().map_err(|_| CipherError::Malformed(format!("invalid version prefix 'v{}'", label)))?;
+    match CiphertextVersion::from_number(number) {
+        Some(version) => Ok((version, payload)),
+        None => Err(CipherError::UnsupportedVersion(format!("ciphertext format v{} is newer than this build", number))),
+    }
+}
+
+// V0 stays unprefixed so that values written before versioning and after compare equal
+pub fn frame_ciphertext(version: CiphertextVersion, payload: &str) -> String {
+    match version {
+        CiphertextVersion::V0 => payload.to_string(),
+        version => format!("{}:{}", version.label(), payload),
+    }
+}
+
+// Length of the stored form of a plaintext of plaintext_len bytes: the prefix, then the hex of the IV, the ciphertext and the tag
+pub fn ciphertext_len(version: CiphertextVersion, plaintext_len: usize) -> usize {
+    frame_ciphertext(version, "").len() + 2 * (AES_GCM_IV_SIZE + plaintext_len + AES_GCM_TAG_SIZE)
+}
+
+// Every way the ciphertext of a value may be stored. V0 and V1 share their payload, so a lookup of a column
+// whose legacy values were not migrated yet matches both forms.
+pub fn stored_forms(encoded: &str) -> Vec<String> {
+    match parse_ciphertext(encoded) {
+        Ok((CiphertextVersion::V0 | CiphertextVersion::V1, payload)) => {
+            vec![frame_ciphertext(CiphertextVersion::V1, payload), frame_ciphertext(CiphertextVersion::V0, payload)]
+        },
+        Ok((CiphertextVersion::V2, _)) | Err(_) => vec![encoded.to_string()],
+    }
+}
+
+// Text of a primary key as bound into V2 ciphertexts, so that 7 and "7" bind the same row. A composite key
+// binds the JSON array of the text of its cells, none of which may be null.
+pub fn row_key_text(primary_key: &Value) -> Option<String> {
+    match primary_key {
+        Value::Null => None,
+        Value::String(key) => Some(key.clone()),
+        Value::Array(cells) => cells.iter().map(row_key_text).collect::<Option<Vec<String>>>().map(|texts| Value::from(texts).to_string()),
+        other => Some(other.to_string()),
+    }
+}
+
+// Additional data of a V2 value: the canonical JSON of [table, column, primary key]
+pub fn row_additional_data(table: &str, column: &str, primary_key: &str) -> Result<Vec<u8>, CipherError> {
+    let canonical = serde_json::to_string(&serde_json::json!([table, column, primary_key])).map_err(|e| CipherError::Malformed(e.to_string()))?;
+    Ok(format!("klave-row-v{}:{}", CiphertextVersion::V2.number(), canonical).into_bytes())
+}
+
+// SQL expression giving the version label of a stored value, for counting versions without reading the cells.
+// The column is spliced as given, quoted by the caller
+pub fn ciphertext_version_sql(column: &str) -> String {
+    format!("CASE WHEN {0}::text ~ '^v[0-9]+:' THEN split_part({0}::text, ':', 1) ELSE 'v0' END", column)
+}
+
+// Per-column cipher: derives the column key once and encrypts/decrypts values of that column.
+pub struct ColumnCipher {
+    master_key: CryptoKey,
+    key: CryptoKey,
+    table: String,
+    column: String,
+    aad_template: Option<AadTemplate>,
+    // Primary key of the table when values are bound to their row
+    row_key: Option<PrimaryKey>,
+    // Key of the blind index of the column; values then get a random IV
+    index_key: Option<Vec<u8>>,
+}
+
+impl ColumnCipher {
+    pub fn new(master_key: &CryptoKey, table: &str, column: &str) -> Result<ColumnCipher, Box<dyn std::error::Error>> {
+        let key = derive_aes_gcm_key(master_key, table.to_string(), column.to_string())?;
+        Ok(ColumnCipher {
+            master_key: master_key.clone(),
+            key,
+            table: table.to_string(),
+            column: column.to_string(),
+            aad_template: None,
+            row_key: None,
+            index_key: None,
+        })
+    }
+
+    pub fn with_aad_template(mut self, aad_template: Option<AadTemplate>) -> Self {
+        self.aad_template = aad_template;
+        self
+    }
+
+    pub fn aad_template(&self) -> Option<&AadTemplate> {
+        self.aad_template.as_ref()
+    }
+
+    // Binds the values written by encrypt to their row, whose primary key is read from the context under
+    // the key columns. Without it values are written in the deterministic lookup format.
+    pub fn with_row_key(mut self, primary_key: Option<&PrimaryKey>) -> Self {
+        self.row_key = primary_key.cloned();
+        self
+    }
+
+    pub fn row_key(&self) -> Option<&PrimaryKey> {
+        self.row_key.as_ref()
+    }
+
+    // Encrypts with a random IV per value, equality being searched through blind_index instead
+    pub fn with_index_key(mut self, index_key: Option<Vec<u8>>) -> Self {
+        self.index_key = index_key;
+        self
+    }
+
+    pub fn blind_index(&self, value: &Value) -> Result<Option<String>, Box<dyn std::error::Error>> {
+        match &self.index_key {
+            Some(index_key) => Ok(Some(compute_blind_index(index_key, value)?)),
+            None => Ok(None),
+        }
+    }
+
+    // Version encrypt writes
+    pub fn version(&self) -> CiphertextVersion {
+        match self.row_key {
+            Some(_) => ROW_BOUND_CIPHERTEXT_VERSION,
+            None => CURRENT_CIPHERTEXT_VERSION,
+        }
+    }
+
+    pub fn additional_data(&self, context: &serde_json::Map<String, Value>) -> Result<Vec<u8>, CipherError> {
+        match &self.aad_template {
+            Some(template) => template.resolve(&self.table, &self.column, context),
+            None => Ok(vec![]),
+        }
+    }
+
+    // Primary key of the row in the context, required by V2 values
+    fn bound_row(&self, context: &serde_json::Map<String, Value>) -> Result<String, CipherError> {
+        let row_key = self.row_key.as_ref()
+            .ok_or(CipherError::AadMismatch(format!("{}.{} holds a value bound to its row but is not registered as row-bound", self.table, self.column)))?;
+        row_key_text(&row_key.value(context))
+            .ok_or(CipherError::AadMismatch(format!("primary key '{}' of the row is required to use {}.{}, pass it in the context", row_key, self.table, self.column)))
+    }
+
+    // Row binding followed by the template data, the JSON of the former delimits it
+    fn version_additional_data(&self, version: CiphertextVersion, context: &serde_json::Map<String, Value>) -> Result<Vec<u8>, CipherError> {
+        match version {
+            CiphertextVersion::V0 | CiphertextVersion::V1 => self.additional_data(context),
+            CiphertextVersion::V2 => {
+                let mut additional_data = row_additional_data(&self.table, &self.column, &self.bound_row(context)?)?;
+                additional_data.extend(self.additional_data(context)?);
+                Ok(additional_data)
+            }
+        }
+    }
+
+    pub fn encrypt(&self, value: &Value, context: &serde_json::Map<String, Value>) -> Result<String, Box<dyn std::error::Error>> {
+        if is_lossy_text(value) {
+            return Err(CipherError::LossyPlaintext(format!("column '{}' holds text that is not valid UTF-8", self.column)).into());
+        }
+        let value_in_bytes = get_serde_value_into_bytes(value)?;
+        let version = self.version();
+        let additional_data = self.version_additional_data(version, context)?;
+        // The IV is derived from the additional data along with the value: one IV under different additional
+        // data, another row or another template context, would let the tags give the authentication key away
+        let iv_input = match version {
+            CiphertextVersion::V2 => serde_json::json!([self.bound_row(context)?, encode(&additional_data), value]),
+            _ if !additional_data.is_empty() => serde_json::json!([encode(&additional_data), value]),
+            _ => value.clone(),
+        };
+        let mut iv = match self.index_key {
+            Some(_) => crate::runtime::random::get_random_bytes(AES_GCM_IV_SIZE as i32)?,
+            None => derive_iv(&self.master_key, self.column.clone(), iv_input)?,
+        };
+        let aes_gcm_params = AesGcmParams {
+            iv: iv.clone(),
+            additional_data,
+            tag_length: 128,
+        };
+        let mut encrypted_value = encrypt(&EncryptAlgorithm::AesGcm(aes_gcm_params), &self.key, &value_in_bytes)?;
+        iv.append(&mut encrypted_value);
+        Ok(frame_ciphertext(version, &encode(&iv)))
+    }
+
+    // Dispatches on the version of the value, so that rows written by any release can be read
+    pub fn decrypt(&self, encoded: &str, context: &serde_json::Map<String, Value>) -> Result<Value, Box<dyn std::error::Error>> {
+        let (version, payload) = parse_ciphertext(encoded)?;
+        match self.decrypt_payload(payload, &self.version_additional_data(version, context)?) {
+            // The value was written for another row or column, or the primary key given is not its own
+            Err(err) if version == CiphertextVersion::V2 && CipherError::from_error(err.as_ref()).is_none() => {
+                Err(CipherError::AadMismatch(format!("authentication failed for {}.{} bound to row {}: {}", self.table, self.column, self.bound_row(context)?, err)).into())
+            },
+            decrypted => decrypted,
+        }
+    }
+
+    // Re-encrypts a value of any version in the format of the column; returns None when it already is
+    pub fn upgrade(&self, encoded: &str, context: &serde_json::Map<String, Value>) -> Result<Option<String>, Box<dyn std::error::Error>> {
+        if parse_ciphertext(encoded)?.0 == self.version() {
+            return Ok(None);
+        }
+        let value = self.decrypt(encoded, context)?;
+        Ok(Some(self.encrypt(&value, context)?))
+    }
+
+    // Hex of the IV, the ciphertext and the tag, shared by every version
+    fn decrypt_payload(&self, encoded: &str, additional_data: &[u8]) -> Result<Value, Box<dyn std::error::Error>> {
+        let bytes = hex::decode(encoded).map_err(|e| CipherError::Malformed(e.to_string()))?;
+        if bytes.len() <= AES_GCM_IV_SIZE + AES_GCM_TAG_SIZE {
+            return Err(CipherError::Malformed(format!("expected more than {} bytes, got {}", AES_GCM_IV_SIZE + AES_GCM_TAG_SIZE, bytes.len())).into());
+        }
+        let additional_data = additional_data.to_vec();
+        let aes_gcm_params = AesGcmParams {
+            iv: bytes[..AES_GCM_IV_SIZE].to_vec(),
+            additional_data,
+            tag_length: 128,
+        };
+        let clear = match decrypt(&EncryptAlgorithm::AesGcm(aes_gcm_params), &self.key, &bytes[AES_GCM_IV_SIZE..]) {
+            Ok(clear) => clear,
+            Err(err) => {
+                // With a template, an authentication failure means the supplied context differs
+                if let Some(template) = &self.aad_template {
+                    return Err(CipherError::AadMismatch(format!("authentication failed for {}.{} with aad_template '{}': {}", self.table, self.column, template.source, err)).into());
+                }
+                return Err(err);
+            }
+        };
+        Ok(serde_json::from_slice::<Value>(&clear)?)
+    }
+}
+
+// Outcome of one check of crypto_selftest
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct SelfTestCheck {
+    pub name: String,
+    pub passed: bool,
+    pub detail: Option<String>,
+}
+
+impl SelfTestCheck {
+    fn run<F: FnOnce() -> Result<bool, Box<dyn std::error::Error>>>(name: &str, check: F) -> SelfTestCheck {
+        let (passed, detail) = match check() {
+            Ok(true) => (true, None),
+            Ok(false) => (false, Some("unexpected output".to_string())),
+            Err(err) => (false, Some(err.to_string())),
+        };
+        SelfTestCheck { name: name.to_string(), passed, detail }
+    }
+}
+
+// Known-answer tests of the host primitives, then a column cipher round trip under a throwaway key
+pub fn crypto_selftest() -> Vec<SelfTestCheck> {
+    vec![
+        SelfTestCheck::run("sha256", || {
+            Ok(hex::encode(crate::runtime::sha::digest("SHA2-256", b"abc")?) == "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad")
+        }),
+        // RFC 4231, test case 2
+        SelfTestCheck::run("hmac_sha256", || {
+            Ok(hex::encode(hmac_sha256(b"Jefe", b"what do ya want for nothing?")?) == "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843")
+        }),
+        SelfTestCheck::run("column_cipher", || {
+            let cipher = ColumnCipher::new(&generate_ecc_crypto_key()?, "selftest", "value")?;
+            let context = serde_json::Map::new();
+            let value = serde_json::json!("klave");
+            let encrypted = cipher.encrypt(&value, &context)?;
+            Ok(encrypted == cipher.encrypt(&value, &context)? && cipher.decrypt(&encrypted, &context)? == value)
+        }),
+        // The current format read back with its version prefix spelled out
+        SelfTestCheck::run("ciphertext_version", || {
+            let cipher = ColumnCipher::new(&generate_ecc_crypto_key()?, "selftest", "value")?;
+            let context = serde_json::Map::new();
+            let value = serde_json::json!("klave");
+            let encrypted = cipher.encrypt(&value, &context)?;
+            let (version, payload) = parse_ciphertext(&encrypted)?;
+            let prefixed = format!("{}:{}", version.label(), payload);
+            Ok(version == CURRENT_CIPHERTEXT_VERSION && cipher.decrypt(&prefixed, &context)? == value)
+        }),
+        // A row-bound value decrypts for its own row only
+        SelfTestCheck::run("row_binding", || {
+            let cipher = ColumnCipher::new(&generate_ecc_crypto_key()?, "selftest", "value")?.with_row_key(Some(&PrimaryKey::Single("id".to_string())));
+            let row = |id: u64| serde_json::json!({ "id": id }).as_object().cloned().unwrap_or_default();
+            let value = serde_json::json!("klave");
+            let encrypted = cipher.encrypt(&value, &row(1))?;
+            Ok(parse_ciphertext(&encrypted)?.0 == ROW_BOUND_CIPHERTEXT_VERSION
+                && encrypted != cipher.encrypt(&value, &row(2))?
+                && cipher.decrypt(&encrypted, &row(1))? == value
+                && cipher.decrypt(&encrypted, &row(2)).is_err())
+        }),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Map, Value};
+
+    use super::*;
+
+    fn context(value: Value) -> Map<String, Value> {
+        value.as_object().cloned().unwrap_or_default()
+    }
+
+    #[test]
+    fn test_aad_template_resolve() {
+        let template = AadTemplate::parse("{tenant_id}|{table}|{column}").unwrap();
+        assert_eq!(template.context_fields(), vec!["tenant_id".to_string()]);
+        let aad = template.resolve("users", "email", &context(json!({"tenant_id": "acme"}))).unwrap();
+        assert_eq!(String::from_utf8(aad).unwrap(), "klave-aad-v1:\"acme\"|users|email");
+        let aad = template.resolve("users", "email", &context(json!({"tenant_id": 42}))).unwrap();
+        assert_eq!(String::from_utf8(aad).unwrap(), "klave-aad-v1:42|users|email");
+    }
+
+    #[test]
+    fn test_aad_template_missing_field_is_aad_mismatch() {
+        let template = AadTemplate::parse("{tenant_id}:{version}").unwrap();
+        let err = template.resolve("users", "email", &context(json!({"tenant_id": "acme"}))).unwrap_err();
+        assert!(matches!(err, CipherError::AadMismatch(ref msg) if msg.contains("'version'")));
+    }
+
+    #[test]
+    fn test_aad_template_values_are_not_ambiguous() {
+        let template = AadTemplate::parse("{a}|{b}").unwrap();
+        let first = template.resolve("t", "c", &context(json!({"a": "x|y", "b": "z"}))).unwrap();
+        let second = template.resolve("t", "c", &context(json!({"a": "x", "b": "y|z"}))).unwrap();
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn test_key_unavailable_is_detected_through_box() {
+        let err: Box<dyn std::error::Error> = CipherError::KeyUnavailable("master".to_string()).into();
+        assert!(CipherError::is_key_unavailable(err.as_ref()));
+        assert_eq!(CipherError::from_error(err.as_ref()).map(|e| e.code()), Some("KeyUnavailable"));
+        let other: Box<dyn std::error::Error> = "connection refused".into();
+        assert!(!CipherError::is_key_unavailable(other.as_ref()));
+    }
+
+    // Stored values as each format was written, pinned so that older rows stay readable
+    #[test]
+    fn test_ciphertext_version_vectors() {
+        let v0 = "000102030405060708090a0b5a3c6e1f8d2b4a6c8e0f1a2b3c4d5e6f708192a3b4";
+        assert_eq!(parse_ciphertext(v0).unwrap(), (CiphertextVersion::V0, v0));
+        assert_eq!(parse_ciphertext(&format!("v0:{}", v0)).unwrap(), (CiphertextVersion::V0, v0));
+        assert_eq!(frame_ciphertext(CiphertextVersion::V0, v0), v0);
+        assert_eq!(hex::decode(v0).unwrap().len(), AES_GCM_IV_SIZE + AES_GCM_TAG_SIZE + 5);
+        for version in CiphertextVersion::ALL {
+            assert_eq!(parse_ciphertext(&frame_ciphertext(version, v0)).unwrap(), (version, v0));
+            assert_eq!(CiphertextVersion::from_number(version.number()), Some(version));
+        }
+        assert!(CiphertextVersion::ALL.contains(&CURRENT_CIPHERTEXT_VERSION));
+
+        let v1 = format!("v1:{}", v0);
+        assert_eq!(parse_ciphertext(&v1).unwrap(), (CiphertextVersion::V1, v0));
+        assert_eq!(frame_ciphertext(CiphertextVersion::V1, v0), v1);
+        assert_eq!(stored_forms(v0), vec![v1.clone(), v0.to_string()]);
+        assert_eq!(stored_forms(&v1), vec![v1.clone(), v0.to_string()]);
+        let v2 = format!("v2:{}", v0);
+        assert_eq!(parse_ciphertext(&v2).unwrap(), (CiphertextVersion::V2, v0));
+        assert_eq!(stored_forms(&v2), vec![v2.clone()]);
+    }
+
+    #[test]
+    fn test_row_additional_data() {
+        assert_eq!(row_key_text(&json!(7)), Some("7".to_string()));
+        assert_eq!(row_key_text(&json!("7")), Some("7".to_string()));
+        assert_eq!(row_key_text(&Value::Null), None);
+        assert_eq!(row_key_text(&json!([7, "2"])), Some(r#"["7","2"]"#.to_string()));
+        assert_eq!(row_key_text(&json!([7, null])), None);
+        assert_eq!(String::from_utf8(row_additional_data("users", "email", "7").unwrap()).unwrap(), r#"klave-row-v2:["users","email","7"]"#);
+        // Quotes in names cannot make two rows collide
+        assert_ne!(row_additional_data("a\",\"b", "c", "1").unwrap(), row_additional_data("a", "b\",\"c", "1").unwrap());
+    }
+
+    #[test]
+    fn test_unknown_ciphertext_versions() {
+        assert!(matches!(parse_ciphertext("v9:00ff"), Err(CipherError::UnsupportedVersion(_))));
+        assert!(matches!(parse_ciphertext("vx:00ff"), Err(CipherError::Malformed(_))));
+        assert_eq!(CipherError::UnsupportedVersion(String::new()).code(), "UnsupportedVersion");
+        assert_eq!(ciphertext_version_sql("ssn"), "CASE WHEN ssn::text ~ '^v[0-9]+:' THEN split_part(ssn::text, ':', 1) ELSE 'v0' END");
+    }
+
+    #[test]
+    fn test_canonical_row_plaintext_sorts_columns() {
+        let a = canonical_row_plaintext("users", &json!(7), &[("b".to_string(), json!("x")), ("a".to_string(), json!(1))]).unwrap();
+        let b = canonical_row_plaintext("users", &json!(7), &[("a".to_string(), json!(1)), ("b".to_string(), json!("x"))]).unwrap();
+        assert_eq!(a, b);
+        assert_eq!(String::from_utf8(a).unwrap(), r#"["users",7,[["a",1],["b","x"]]]"#);
+    }
+
+    #[test]
+    fn test_aad_template_validation() {
+        let columns = vec!["id".to_string(), "tenant_id".to_string()];
+        assert!(AadTemplate::parse("{tenant_id}|{table}").unwrap().validate(&columns).is_ok());
+        assert!(matches!(AadTemplate::parse("{tenant}").unwrap().validate(&columns), Err(CipherError::InvalidTemplate(_))));
+        assert!(AadTemplate::parse("{tenant_id").is_err());
+        assert!(AadTemplate::parse("tenant}").is_err());
+        assert!(AadTemplate::parse("{}").is_err());
+    }
+}
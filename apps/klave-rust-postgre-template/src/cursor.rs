@@ -0,0 +1,150 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    ciphertext_ops,
+    database::{self, Field, MAX_PAGE_SIZE},
+    notify,
+    partitions::KeyRange,
+    strict,
+    utils::{quote_ident, quote_idents},
+};
+
+// Keyset page of a table, ordered by a single column that should be unique (e.g. the primary key):
+// rows sharing the cursor value are not returned again, nor are rows where it is NULL
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CursorQueryInput {
+    pub database_id: String,
+    pub table: String,
+    // Columns to return, all of them when empty; order_by is added when missing
+    #[serde(default)]
+    pub columns: Vec<String>,
+    pub order_by: String,
+    // next_cursor of the previous page, the first page when not given
+    #[serde(default)]
+    pub after: Option<Value>,
+    pub limit: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CursorPage {
+    pub fields: Vec<Field>,
+    pub rows: Vec<Vec<Value>>,
+    // after of the next call, null once the table is exhausted
+    pub next_cursor: Option<Value>,
+}
+
+// Columns of the page, checked against those of the table
+pub fn cursor_columns(input: &CursorQueryInput, table_columns: &[String]) -> Result<Vec<String>, String> {
+    if table_columns.is_empty() {
+        return Err(format!("Invalid input: table {} does not exist", input.table));
+    }
+    let mut columns = if input.columns.is_empty() { table_columns.to_vec() } else { input.columns.clone() };
+    if !columns.contains(&input.order_by) {
+        columns.push(input.order_by.clone());
+    }
+    match columns.iter().find(|c| !table_columns.contains(c)) {
+        Some(missing) => Err(format!("Invalid input: table {} has no column {}", input.table, missing)),
+        None => Ok(columns),
+    }
+}
+
+// Reads one row more than limit, telling whether another page follows
+pub fn cursor_query(table: &str, columns: &[String], order_by: &str, after: Option<&Value>, limit: u64) -> Result<String, Box<dyn std::error::Error>> {
+    if matches!(after, Some(Value::Array(_)) | Some(Value::Object(_))) {
+        return Err("Invalid input: after must be a single value of the order_by column".into());
+    }
+    let order_by = quote_ident(order_by)?;
+    let mut filter = vec![format!("{} IS NOT NULL", order_by)];
+    filter.extend(KeyRange { after: after.cloned(), up_to: None }.predicate(&order_by));
+    Ok(format!("SELECT {} FROM {} WHERE {} ORDER BY {} LIMIT {}", quote_idents(columns)?.join(","), quote_ident(table)?, filter.join(" AND "), order_by, limit.saturating_add(1)))
+}
+
+// Cuts the extra row; the cursor is the order_by value of the last row returned
+pub fn cursor_page(response: database::PostGreResponse<Vec<Vec<Value>>>, order_by: &str, limit: u64) -> CursorPage {
+    let mut rows = response.resultset;
+    let has_more = rows.len() as u64 > limit;
+    rows.truncate(limit as usize);
+    let position = response.fields.iter().position(|f| f.name == order_by);
+    let next_cursor = match (has_more, position) {
+        (true, Some(position)) => rows.last().and_then(|row| row.get(position)).cloned(),
+        _ => None,
+    };
+    CursorPage { fields: response.fields, rows, next_cursor }
+}
+
+pub fn sql_query_cursor(cmd: String) {
+    let input: CursorQueryInput = match notify::parse_input(&cmd) {
+        Some(input) => input,
+        None => return,
+    };
+    if input.limit == 0 {
+        notify::error("Invalid input: limit must be greater than 0");
+        return;
+    }
+    let limit = input.limit.min(MAX_PAGE_SIZE);
+    let mut client = match database::Client::load(input.database_id.clone()) {
+        Ok(c) => c,
+        Err(err) => {
+            notify::error_with_code(&format!("Failed to load client: {}", err), err.code());
+            return;
+        }
+    };
+    if let Err(err) = client.connect() {
+        notify::error_with_code(&format!("Failed to connect to client: {}", err), err.code());
+        return;
+    }
+    let columns = match client.get_table_columns(&input.table).map_err(|e| e.to_string()).and_then(|c| cursor_columns(&input, &c)) {
+        Ok(columns) => columns,
+        Err(err) => {
+            notify::error(&err);
+            return;
+        }
+    };
+    let query = match cursor_query(&input.table, &columns, &input.order_by, input.after.as_ref(), limit) {
+        Ok(query) => query,
+        Err(err) => {
+            notify::error(&err.to_string());
+            return;
+        }
+    };
+    // Ordering by an encrypted column would page through ciphertext order
+    if !ciphertext_ops::allow_statements(&input.database_id, [query.as_str()]) {
+        return;
+    }
+    if !strict::allow_statements(&mut client, std::iter::once(query.as_str()), None) {
+        return;
+    }
+    match client.query::<Vec<Vec<Value>>>(&query) {
+        Ok(response) => notify::result(&cursor_page(response, &input.order_by, limit)),
+        Err(err) => notify::error_with_code(&format!("Failed to query the DB: {}", err), err.code()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn input(columns: &[&str], order_by: &str) -> CursorQueryInput {
+        serde_json::from_value(json!({ "database_id": "db", "table": "users", "columns": columns, "order_by": order_by, "limit": 2 })).unwrap()
+    }
+
+    #[test]
+    fn test_cursor_query() {
+        let table_columns = vec!["id".to_string(), "email".to_string()];
+        assert_eq!(cursor_columns(&input(&[], "id"), &table_columns).unwrap(), table_columns);
+        assert_eq!(cursor_columns(&input(&["email"], "id"), &table_columns).unwrap(), vec!["email", "id"]);
+        assert_eq!(cursor_columns(&input(&["name"], "id"), &table_columns).unwrap_err(), "Invalid input: table users has no column name");
+        assert!(cursor_columns(&input(&[], "created_at"), &table_columns).is_err());
+        assert_eq!(cursor_columns(&input(&[], "id"), &[]).unwrap_err(), "Invalid input: table users does not exist");
+
+        assert_eq!(cursor_query("users", &table_columns, "id", None, 2).unwrap(),
+            "SELECT \"id\",\"email\" FROM \"users\" WHERE \"id\" IS NOT NULL ORDER BY \"id\" LIMIT 3");
+        assert_eq!(cursor_query("users", &table_columns, "id", Some(&json!("it's")), 2).unwrap(),
+            "SELECT \"id\",\"email\" FROM \"users\" WHERE \"id\" IS NOT NULL AND \"id\" > 'it''s' ORDER BY \"id\" LIMIT 3");
+        assert!(cursor_query("users", &table_columns, "id; DROP TABLE x", None, 2).is_err());
+        assert!(cursor_query("users", &table_columns, "id", Some(&json!([1, 2])), 2).is_err());
+    }
+}
@@ -0,0 +1,159 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::{database::{self, decrypt_or_plaintext, Client, DatabaseError}, host::cell_as_u64, manifest::EncryptionManifest, notify, primary_key::PrimaryKey, utils::is_plain_identifier};
+
+// Groups returned by one call, the report says when more exist
+const MAX_DUPLICATE_GROUPS: u64 = 100;
+// Primary keys listed per group
+const MAX_SAMPLE_KEYS: u64 = 5;
+
+fn default_min_count() -> u64 {
+    2
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FindDuplicatesInput {
+    pub database_id: String,
+    pub table: String,
+    pub column: String,
+    #[serde(default = "default_min_count")]
+    pub min_count: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DuplicateGroup {
+    pub value: Value,
+    pub count: u64,
+    pub sample_primary_keys: Vec<Value>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DuplicatesReport {
+    pub table: String,
+    pub column: String,
+    pub groups: Vec<DuplicateGroup>,
+    // More groups matched than MAX_DUPLICATE_GROUPS
+    pub truncated: bool,
+    // The table is Applying, groups may split one value between its plaintext and its ciphertext
+    pub ambiguous: bool,
+}
+
+// Ciphertexts only repeat for equal plaintexts when the column is encrypted without per-row additional data
+fn check_deterministic(manifest: &EncryptionManifest, table: &str, column: &str) -> Result<(), String> {
+    match manifest.column(table, column) {
+        None => Err(format!("Column {}.{} is not encrypted", table, column)),
+        Some(entry) if !entry.is_deterministic() => Err(format!(
+            "Column {}.{} binds its ciphertexts to per-row context through its row or an aad_template, equal values do not share a ciphertext; \
+            a blind index on the column is needed to find duplicates", table, column)),
+        Some(_) => Ok(()),
+    }
+}
+
+// The sample keys of a composite primary key are arrays of its cells
+pub fn duplicates_query(table: &str, column: &str, primary_key: &PrimaryKey, min_count: u64) -> String {
+    let order = primary_key.columns().join(", ");
+    let sample = match primary_key {
+        PrimaryKey::Single(key) => key.clone(),
+        PrimaryKey::Composite(_) => format!("json_build_array({})", order),
+    };
+    format!("SELECT {column}, count(*), to_json((array_agg({sample} ORDER BY {order}))[1:{samples}])::text \
+        FROM {table} WHERE {column} IS NOT NULL GROUP BY {column} HAVING count(*) >= {min_count} \
+        ORDER BY count(*) DESC, {column} LIMIT {limit}",
+        samples = MAX_SAMPLE_KEYS, limit = MAX_DUPLICATE_GROUPS + 1)
+}
+
+fn find_duplicates(client: &Client, input: &FindDuplicatesInput) -> Result<DuplicatesReport, Box<dyn std::error::Error>> {
+    let manifest = EncryptionManifest::load(client.database_id())?;
+    check_deterministic(&manifest, &input.table, &input.column)?;
+    let entry = manifest.table(&input.table).ok_or(format!("Table {} has no encrypted columns", input.table))?;
+    let applying = manifest.is_applying(&input.table);
+
+    let response = client.query::<Vec<Vec<Value>>>(&duplicates_query(&input.table, &input.column, &entry.primary_key, input.min_count))?;
+    let truncated = response.resultset.len() as u64 > MAX_DUPLICATE_GROUPS;
+
+    // Only the group labels are decrypted, never the rest of the column
+    let master_key = client.load_master_key()?;
+    let cipher = client.column_cipher(&master_key, &manifest, &input.table, &input.column)?;
+    let mut groups = Vec::new();
+    for row in response.resultset.iter().take(MAX_DUPLICATE_GROUPS as usize) {
+        let value = match row.first() {
+            Some(Value::String(encoded)) => decrypt_or_plaintext(&cipher, encoded, &Map::new(), applying)?.0,
+            Some(other) => other.clone(),
+            None => continue,
+        };
+        let count = row.get(1).and_then(cell_as_u64).unwrap_or(0);
+        let sample_primary_keys = row.get(2).and_then(|v| v.as_str())
+            .and_then(|keys| serde_json::from_str::<Vec<Value>>(keys).ok())
+            .unwrap_or_default();
+        groups.push(DuplicateGroup { value, count, sample_primary_keys });
+    }
+    Ok(DuplicatesReport { table: input.table.clone(), column: input.column.clone(), groups, truncated, ambiguous: applying })
+}
+
+pub fn find_duplicate_values(cmd: String) {
+    let input: FindDuplicatesInput = match notify::parse_input(&cmd) {
+        Some(input) => input,
+        None => return,
+    };
+    if !is_plain_identifier(&input.table) || !is_plain_identifier(&input.column) {
+        notify::error("Invalid input: table and column must be plain identifiers");
+        return;
+    }
+    if input.min_count < 2 {
+        notify::error("min_count must be at least 2");
+        return;
+    }
+    let mut client: database::Client = match database::Client::load(input.database_id.clone()) {
+        Ok(c) => c,
+        Err(err) => {
+            notify::error_with_code(&format!("Failed to load client: {}", err), err.code());
+            return;
+        }
+    };
+    match client.connect() {
+        Ok(_) => (),
+        Err(err) => {
+            notify::error_with_code(&format!("Failed to connect to client: {}", err), err.code());
+            return;
+        }
+    };
+
+    match find_duplicates(&client, &input) {
+        Ok(report) => {
+            notify::result(&report);
+        },
+        Err(err) => {
+            notify::error_with_code(&format!("Failed to find duplicate values: {}", err), DatabaseError::code_of(err.as_ref()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::manifest::EncryptedColumn;
+
+    use super::*;
+
+    #[test]
+    fn test_check_deterministic() {
+        let mut manifest = EncryptionManifest::new("db");
+        manifest.record_column("users", "id", EncryptedColumn::new("email", None), 0);
+        manifest.record_column("users", "id", EncryptedColumn { aad_template: Some("{table}:{id}".to_string()), ..EncryptedColumn::new("phone", None) }, 0);
+        assert!(check_deterministic(&manifest, "users", "email").is_ok());
+        assert!(check_deterministic(&manifest, "users", "phone").unwrap_err().contains("blind index"));
+        assert!(check_deterministic(&manifest, "users", "age").is_err());
+    }
+
+    #[test]
+    fn test_duplicates_query() {
+        let query = duplicates_query("users", "email", &"id".into(), 3);
+        assert!(query.contains("to_json((array_agg(id ORDER BY id))[1:5])"));
+        assert!(query.contains("HAVING count(*) >= 3"));
+        assert!(query.contains("[1:5]"));
+        assert!(query.ends_with(&format!("LIMIT {}", MAX_DUPLICATE_GROUPS + 1)));
+        let composite = PrimaryKey::Composite(vec!["order_id".to_string(), "line_no".to_string()]);
+        assert!(duplicates_query("order_lines", "sku", &composite, 2).contains("array_agg(json_build_array(order_id, line_no) ORDER BY order_id, line_no)"));
+    }
+}
@@ -0,0 +1,357 @@
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{json, Value};
+
+// Stable codes callers can branch on; the message stays free-form and may change between versions
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ErrorCode {
+    InvalidInput,
+    ClientNotFound,
+    ConnectionFailed,
+    QueryFailed,
+    AccessDenied,
+    NotBootstrapped,
+    KeygenFailed,
+    NoClientContext,
+    ColumnTypeIncompatible,
+    OperationFailed,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ErrorResponse {
+    pub code: ErrorCode,
+    pub message: String,
+    pub details: Option<Value>,
+}
+
+impl ErrorResponse {
+    pub fn new(message: &str, details: Option<Value>) -> Self {
+        Self::with_code(None, message, details)
+    }
+
+    // A typed error brings its own code, the message is classified otherwise
+    pub fn with_code(code: Option<ErrorCode>, message: &str, details: Option<Value>) -> Self {
+        Self { code: code.unwrap_or_else(|| classify(message)), message: message.to_string(), details }
+    }
+}
+
+// Fallback for failures without a typed error: handlers word them after a few fixed prefixes, the code is read from them
+pub fn classify(message: &str) -> ErrorCode {
+    let starts = |prefixes: &[&str]| prefixes.iter().any(|p| message.starts_with(p));
+    if starts(&["Invalid input", "Invalid settings", "Invalid generator"]) {
+        ErrorCode::InvalidInput
+    } else if message.contains("NotFound: no database") {
+        ErrorCode::ClientNotFound
+    } else if starts(&["NotBootstrapped"]) {
+        ErrorCode::NotBootstrapped
+    } else if message.contains("KeygenFailed") {
+        ErrorCode::KeygenFailed
+    } else if message.contains("NoClientContext") {
+        ErrorCode::NoClientContext
+    } else if message.contains("ColumnTypeIncompatible") {
+        ErrorCode::ColumnTypeIncompatible
+    } else if starts(&["Failed to connect"]) {
+        ErrorCode::ConnectionFailed
+    } else if starts(&["Only the deployment admin"]) || message.contains("AccessDenied") {
+        ErrorCode::AccessDenied
+    } else if starts(&["Failed to query", "Failed to run the query", "Query failed", "Consistent read failed", "Statement "]) {
+        ErrorCode::QueryFailed
+    } else {
+        ErrorCode::OperationFailed
+    }
+}
+
+enum Container {
+    // Key of the member being read, None before the first one
+    Object { key: Option<String>, in_value: bool },
+    Array { index: usize },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PathSegment {
+    Key(String),
+    Index(usize),
+}
+
+// Path of the value serde_json stopped at, e.g. "$.statements[2]"
+pub fn json_path_at(input: &str, line: usize, column: usize) -> String {
+    let mut path = "$".to_string();
+    for segment in path_segments_at(input, line, column) {
+        match segment {
+            PathSegment::Key(key) => path.push_str(&format!(".{}", key)),
+            PathSegment::Index(index) => path.push_str(&format!("[{}]", index)),
+        }
+    }
+    path
+}
+
+// serde_json reports the line and column of the last byte it read, the input is replayed up to there
+pub fn path_segments_at(input: &str, line: usize, column: usize) -> Vec<PathSegment> {
+    let mut stack: Vec<Container> = Vec::new();
+    let mut in_string = false;
+    let mut escaped = false;
+    let mut key: Option<Vec<u8>> = None;
+    for (index, text) in input.split('\n').enumerate().take(line.max(1)) {
+        let bytes = if index + 1 == line { &text.as_bytes()[..column.min(text.len())] } else { text.as_bytes() };
+        for &byte in bytes {
+            if in_string {
+                match byte {
+                    _ if escaped => escaped = false,
+                    b'\\' => escaped = true,
+                    b'"' => {
+                        in_string = false;
+                        if let (Some(Container::Object { key: current, in_value: false }), Some(read)) = (stack.last_mut(), key.take()) {
+                            *current = Some(String::from_utf8_lossy(&read).to_string());
+                        }
+                    }
+                    _ => {
+                        if let Some(read) = key.as_mut() {
+                            read.push(byte);
+                        }
+                    }
+                }
+                continue;
+            }
+            match byte {
+                b'"' => {
+                    in_string = true;
+                    if matches!(stack.last(), Some(Container::Object { in_value: false, .. })) {
+                        key = Some(Vec::new());
+                    }
+                }
+                b'{' => stack.push(Container::Object { key: None, in_value: false }),
+                b'[' => stack.push(Container::Array { index: 0 }),
+                b'}' | b']' => {
+                    stack.pop();
+                }
+                b':' => {
+                    if let Some(Container::Object { in_value, .. }) = stack.last_mut() {
+                        *in_value = true;
+                    }
+                }
+                b',' => match stack.last_mut() {
+                    Some(Container::Object { in_value, .. }) => *in_value = false,
+                    Some(Container::Array { index }) => *index += 1,
+                    None => (),
+                },
+                _ => (),
+            }
+        }
+    }
+
+    stack.into_iter().filter_map(|container| match container {
+        Container::Object { key: Some(key), in_value: true } => Some(PathSegment::Key(key)),
+        Container::Array { index } => Some(PathSegment::Index(index)),
+        _ => None,
+    }).collect()
+}
+
+// Field named by a deny_unknown_fields error, with the fields the struct expects
+fn unknown_field(err: &serde_json::Error) -> Option<(String, Vec<String>)> {
+    let message = err.to_string();
+    let (field, rest) = message.strip_prefix("unknown field `")?.split_once('`')?;
+    let expected = rest.split('`').skip(1).step_by(2).map(|f| f.to_string()).collect();
+    Some((field.to_string(), expected))
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substituted = previous[j] + usize::from(ca != *cb);
+            current.push(substituted.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+// Expected field the unknown one is most likely a typo of
+pub fn closest_field(field: &str, expected: &[String]) -> Option<String> {
+    expected.iter()
+        .map(|candidate| (edit_distance(field, candidate), candidate))
+        .filter(|(distance, candidate)| *distance <= (candidate.chars().count() / 3).max(1))
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, candidate)| candidate.clone())
+}
+
+// Message and details of a payload that failed to deserialize
+pub fn input_error(input: &str, err: &serde_json::Error) -> (String, Value) {
+    let path = json_path_at(input, err.line(), err.column());
+    let mut details = json!({ "path": path, "line": err.line(), "column": err.column() });
+    let message = match unknown_field(err) {
+        Some((field, expected)) => {
+            let suggestion = closest_field(&field, &expected);
+            let hint = suggestion.as_ref().map(|s| format!(", did you mean `{}`?", s)).unwrap_or_default();
+            details["unknown_field"] = json!(field);
+            details["suggestion"] = json!(suggestion);
+            details["expected"] = json!(expected);
+            format!("Invalid input at {}: unknown field `{}`{}", path, field, hint)
+        },
+        None => format!("Invalid input at {}: {}", path, err),
+    };
+    (message, details)
+}
+
+// What a route does with fields its input does not define
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UnknownFieldPolicy {
+    // Dropped with a warning, so that callers of the original route names keep working
+    #[default]
+    Warn,
+    Reject,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct UnknownField {
+    pub path: String,
+    pub field: String,
+    pub suggestion: Option<String>,
+}
+
+impl UnknownField {
+    pub fn describe(&self) -> String {
+        match &self.suggestion {
+            Some(suggestion) => format!("Ignored unknown field `{}` at {}, did you mean `{}`?", self.field, self.path, suggestion),
+            None => format!("Ignored unknown field `{}` at {}", self.field, self.path),
+        }
+    }
+}
+
+// Removes the unknown field serde_json stopped at, None when it cannot be found
+fn drop_field(input: &str, err: &serde_json::Error, field: &str) -> Option<String> {
+    let mut value: Value = serde_json::from_str(input).ok()?;
+    let mut target = &mut value;
+    for segment in path_segments_at(input, err.line(), err.column()) {
+        target = match segment {
+            PathSegment::Key(key) => target.get_mut(key.as_str())?,
+            PathSegment::Index(index) => target.get_mut(index)?,
+        };
+    }
+    target.as_object_mut()?.remove(field)?;
+    serde_json::to_string(&value).ok()
+}
+
+// Parses the payload of a route whose input denies unknown fields. Under Warn each unknown field is
+// dropped and reported back, every other error fails the parse with the message and details of input_error.
+pub fn parse_input<T: DeserializeOwned>(input: &str, policy: UnknownFieldPolicy) -> Result<(T, Vec<UnknownField>), (String, Value)> {
+    let mut text = input.to_string();
+    let mut ignored = Vec::new();
+    loop {
+        let err = match serde_json::from_str::<T>(&text) {
+            Ok(parsed) => return Ok((parsed, ignored)),
+            Err(err) => err,
+        };
+        let unknown = unknown_field(&err).filter(|_| policy == UnknownFieldPolicy::Warn);
+        let dropped = unknown.and_then(|(field, expected)| drop_field(&text, &err, &field).map(|next| (next, field, expected)));
+        match dropped {
+            Some((next, field, expected)) => {
+                let suggestion = closest_field(&field, &expected);
+                ignored.push(UnknownField { path: json_path_at(&text, err.line(), err.column()), field, suggestion });
+                text = next;
+            },
+            None => return Err(input_error(&text, &err)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+
+    use super::*;
+
+    #[allow(dead_code)]
+    #[derive(Debug, Deserialize)]
+    struct Input {
+        database_id: String,
+        #[serde(default)]
+        statements: Vec<String>,
+        #[serde(default)]
+        nested: Option<Nested>,
+    }
+
+    #[allow(dead_code)]
+    #[derive(Debug, Deserialize)]
+    #[serde(deny_unknown_fields)]
+    struct Nested {
+        limit: u64,
+    }
+
+    #[allow(dead_code)]
+    #[derive(Debug, Deserialize)]
+    #[serde(deny_unknown_fields)]
+    struct StrictInput {
+        database_id: String,
+        #[serde(default)]
+        columns: Vec<String>,
+        #[serde(default)]
+        nested: Option<Nested>,
+    }
+
+    fn path_of(input: &str) -> String {
+        let err = serde_json::from_str::<Input>(input).unwrap_err();
+        input_error(input, &err).1["path"].as_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_input_error_path() {
+        assert_eq!(path_of(r#"{"statements": []}"#), "$");
+        assert_eq!(path_of(r#"{"database_id": 7}"#), "$.database_id");
+        assert_eq!(path_of(r#"{"database_id": "db", "statements": ["a", 1]}"#), "$.statements[1]");
+        assert_eq!(path_of("{\"database_id\": \"d,b\",\n \"nested\": {\"limit\": \"x\"}}"), "$.nested.limit");
+        assert_eq!(path_of(r#"{"database_id": "db", "nested": {}}"#), "$.nested");
+        let err = serde_json::from_str::<Input>(r#"{"database_id": 7}"#).unwrap_err();
+        assert!(input_error(r#"{"database_id": 7}"#, &err).0.starts_with("Invalid input at $.database_id: invalid type"));
+    }
+
+    #[test]
+    fn test_classify() {
+        assert_eq!(classify("Invalid input: no statements"), ErrorCode::InvalidInput);
+        assert_eq!(classify("Failed to load client: NotFound: no database 'db'"), ErrorCode::ClientNotFound);
+        assert_eq!(classify("Failed to connect to client: refused"), ErrorCode::ConnectionFailed);
+        assert_eq!(classify("Failed to query the DB: relation does not exist"), ErrorCode::QueryFailed);
+        assert_eq!(classify("Only the deployment admin can read route usage"), ErrorCode::AccessDenied);
+        assert_eq!(classify("Failed to add database client: KeygenFailed: the database id could not be generated"), ErrorCode::KeygenFailed);
+        assert_eq!(classify("Failed to add database client: NoClientContext: the identity of the caller is not available"), ErrorCode::NoClientContext);
+        assert_eq!(classify("Failed to encrypt columns: ColumnTypeIncompatible: columns age (integer) of table users cannot hold the hex ciphertext"), ErrorCode::ColumnTypeIncompatible);
+        assert_eq!(classify("Failed to encrypt columns: disk full"), ErrorCode::OperationFailed);
+    }
+
+    #[test]
+    fn test_unknown_field_suggestions() {
+        let input = r#"{"databse_id": "db"}"#;
+        let err = serde_json::from_str::<StrictInput>(input).unwrap_err();
+        let (message, details) = input_error(input, &err);
+        assert_eq!(message, "Invalid input at $: unknown field `databse_id`, did you mean `database_id`?");
+        assert_eq!(details["expected"], json!(["database_id", "columns", "nested"]));
+        let expected: Vec<String> = ["database_id", "columns", "nested"].iter().map(|f| f.to_string()).collect();
+        assert_eq!(closest_field("column", &expected), Some("columns".to_string()));
+        assert_eq!(closest_field("nestde", &expected), Some("nested".to_string()));
+        assert_eq!(closest_field("limit", &expected), None);
+        assert_eq!(closest_field("db", &expected), None);
+    }
+
+    #[test]
+    fn test_parse_input_policies() {
+        let input = "{\"database_id\": \"db\", \"column\": [\"email\"],\n \"nested\": {\"limit\": 5, \"limt\": 6}}";
+        let (parsed, ignored) = parse_input::<StrictInput>(input, UnknownFieldPolicy::Warn).unwrap();
+        assert_eq!((parsed.database_id.as_str(), parsed.columns.len(), parsed.nested.unwrap().limit), ("db", 0, 5));
+        assert_eq!(ignored, vec![
+            UnknownField { path: "$".to_string(), field: "column".to_string(), suggestion: Some("columns".to_string()) },
+            UnknownField { path: "$.nested".to_string(), field: "limt".to_string(), suggestion: Some("limit".to_string()) },
+        ]);
+        assert_eq!(ignored[0].describe(), "Ignored unknown field `column` at $, did you mean `columns`?");
+
+        let (message, details) = parse_input::<StrictInput>(input, UnknownFieldPolicy::Reject).unwrap_err();
+        assert_eq!(message, "Invalid input at $: unknown field `column`, did you mean `columns`?");
+        assert_eq!(details["suggestion"], json!("columns"));
+        // Other errors fail under either policy
+        let (message, _) = parse_input::<StrictInput>(r#"{"databse_id": "db"}"#, UnknownFieldPolicy::Warn).unwrap_err();
+        assert!(message.starts_with("Invalid input at $: missing field `database_id`"));
+        assert_eq!(classify(&message), ErrorCode::InvalidInput);
+    }
+}
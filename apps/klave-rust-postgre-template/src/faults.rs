@@ -0,0 +1,287 @@
+use std::cell::RefCell;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{crypto::CipherError, utils::get_trusted_time};
+
+const NANOS_PER_MILLI: u64 = 1_000_000;
+// Longest delay a rule may inject, a route cannot be held longer than this
+pub const MAX_DELAY_MS: u64 = 30_000;
+
+// Failures injected into the calls matching a route and/or database, for testing how callers handle them.
+// Clients tagged production are never affected.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FaultRule {
+    // None matches every route
+    #[serde(default)]
+    pub route: Option<String>,
+    // None matches every database not tagged production
+    #[serde(default)]
+    pub database_id: Option<String>,
+    // Added before every call to the SQL host
+    #[serde(default)]
+    pub delay_ms: u64,
+    // The Nth call to the SQL host within an invocation fails as a dropped connection, counted from 1
+    #[serde(default)]
+    pub fail_host_call: Option<u64>,
+    // Share of the ciphertexts read from encrypted columns that come back altered
+    #[serde(default)]
+    pub corrupt_ciphertext_percent: u8,
+    #[serde(default)]
+    pub fail_key_load: bool,
+}
+
+impl FaultRule {
+    fn matches(&self, route: &str, database_id: &str) -> bool {
+        self.route.as_deref().map(|r| r == route).unwrap_or(true)
+            && self.database_id.as_deref().map(|d| d == database_id).unwrap_or(true)
+    }
+
+    fn injects_anything(&self) -> bool {
+        self.delay_ms > 0 || self.fail_host_call.is_some() || self.corrupt_ciphertext_percent > 0 || self.fail_key_load
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FaultInjection {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default)]
+    pub rules: Vec<FaultRule>,
+}
+
+impl FaultInjection {
+    // Checked by update_settings before the rules are stored
+    pub fn validate(&self, production_ids: &[String]) -> Result<(), String> {
+        for (index, rule) in self.rules.iter().enumerate() {
+            if !rule.injects_anything() {
+                return Err(format!("fault rule {} injects nothing", index));
+            }
+            if rule.delay_ms > MAX_DELAY_MS {
+                return Err(format!("fault rule {} delays by more than {} ms", index, MAX_DELAY_MS));
+            }
+            if rule.corrupt_ciphertext_percent > 100 {
+                return Err(format!("fault rule {} corrupts more than 100% of the ciphertexts", index));
+            }
+            if rule.fail_host_call == Some(0) {
+                return Err(format!("fault rule {} counts host calls from 1", index));
+            }
+            if let Some(database_id) = rule.database_id.as_ref().filter(|d| production_ids.contains(d)) {
+                return Err(format!("fault rule {} targets database {} which is tagged production", index, database_id));
+            }
+        }
+        Ok(())
+    }
+}
+
+// A fault that was injected into this invocation, stamped on its result
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct InjectedFault {
+    pub kind: &'static str,
+    pub database_id: String,
+    pub detail: String,
+}
+
+// Injection state of one invocation
+#[derive(Debug, Clone, Default)]
+struct FaultState {
+    route: String,
+    injection: FaultInjection,
+    host_calls: u64,
+    injected: Vec<InjectedFault>,
+}
+
+impl FaultState {
+    fn rules(&self, database_id: &str, production: bool) -> Vec<FaultRule> {
+        if !self.injection.enabled || production {
+            return vec![];
+        }
+        self.injection.rules.iter().filter(|r| r.matches(&self.route, database_id)).cloned().collect()
+    }
+
+    fn record(&mut self, kind: &'static str, database_id: &str, detail: String) {
+        self.injected.push(InjectedFault { kind, database_id: database_id.to_string(), detail });
+    }
+}
+
+thread_local! {
+    static STATE: RefCell<FaultState> = RefCell::new(FaultState::default());
+}
+
+// Arms the rules for the invocation of a route, called before its handler runs
+pub fn begin(route: &str, injection: FaultInjection) {
+    STATE.with(|s| *s.borrow_mut() = FaultState { route: route.to_string(), injection, ..Default::default() });
+}
+
+pub fn injected() -> Vec<InjectedFault> {
+    STATE.with(|s| s.borrow().injected.clone())
+}
+
+// Adds the injected faults to a result so that nobody takes them for real failures; None when the
+// payload cannot carry them and they have to be sent separately
+pub fn stamp(payload: &mut Value) -> Option<Value> {
+    let injected = injected();
+    if injected.is_empty() {
+        return None;
+    }
+    match payload.as_object_mut() {
+        Some(object) => {
+            object.insert("injected_faults".to_string(), json!(injected));
+            None
+        },
+        None => Some(json!({ "injected_faults": injected })),
+    }
+}
+
+// The trusted time is the only clock, so a delay is spent waiting for it to move on
+fn wait(delay_ms: u64) {
+    let until = get_trusted_time().saturating_add(delay_ms * NANOS_PER_MILLI);
+    while get_trusted_time() < until {}
+}
+
+// Runs before every call to the SQL host: applies the delays, then fails the call if a rule says so
+pub fn before_host_call(database_id: &str, production: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let (rules, call) = STATE.with(|s| {
+        let mut state = s.borrow_mut();
+        state.host_calls += 1;
+        (state.rules(database_id, production), state.host_calls)
+    });
+    let delay_ms: u64 = rules.iter().map(|r| r.delay_ms).sum::<u64>().min(MAX_DELAY_MS);
+    if delay_ms > 0 {
+        wait(delay_ms);
+        STATE.with(|s| s.borrow_mut().record("delay", database_id, format!("{} ms before host call {}", delay_ms, call)));
+    }
+    if rules.iter().any(|r| r.fail_host_call == Some(call)) {
+        STATE.with(|s| s.borrow_mut().record("connection_failure", database_id, format!("host call {}", call)));
+        return Err(format!("Injected fault: connection to database {} lost on host call {}", database_id, call).into());
+    }
+    Ok(())
+}
+
+pub fn before_key_load(database_id: &str, production: bool) -> Result<(), CipherError> {
+    let fail = STATE.with(|s| s.borrow().rules(database_id, production).iter().any(|r| r.fail_key_load));
+    if !fail {
+        return Ok(());
+    }
+    STATE.with(|s| s.borrow_mut().record("key_load_failure", database_id, "master key".to_string()));
+    Err(CipherError::KeyUnavailable(format!("injected fault: key store unavailable for database {}", database_id)))
+}
+
+// Highest corruption rate of the rules matching a database, if any
+pub fn corruption_percent(database_id: &str, production: bool) -> Option<u8> {
+    STATE.with(|s| s.borrow().rules(database_id, production).iter().map(|r| r.corrupt_ciphertext_percent).max()).filter(|p| *p > 0)
+}
+
+// Stable across calls, so that the same cells come back corrupted every time
+fn cell_hash(text: &str) -> u64 {
+    text.bytes().fold(0xcbf29ce484222325, |hash, byte| (hash ^ byte as u64).wrapping_mul(0x100000001b3))
+}
+
+// Flips the last hex digit of the selected cells of the encrypted columns; returns how many were altered
+pub fn corrupt_ciphertexts(raw: &mut Value, encrypted_columns: &[String], percent: u8) -> usize {
+    let selected: Vec<usize> = match raw.get("fields").and_then(|f| f.as_array()) {
+        Some(fields) => fields.iter().enumerate()
+            .filter(|(_, f)| f.get("name").and_then(|n| n.as_str()).map(|n| encrypted_columns.iter().any(|c| c.eq_ignore_ascii_case(n))).unwrap_or(false))
+            .map(|(i, _)| i)
+            .collect(),
+        None => return 0,
+    };
+    let mut corrupted = 0;
+    if let Some(rows) = raw.get_mut("resultset").and_then(|r| r.as_array_mut()) {
+        for row in rows.iter_mut().filter_map(|r| r.as_array_mut()) {
+            for i in selected.iter() {
+                let text = match row.get(*i).and_then(|c| c.as_str()) {
+                    Some(text) if cell_hash(text) % 100 < percent as u64 => text,
+                    _ => continue,
+                };
+                let mut altered = text.to_string();
+                let flipped = match altered.pop() {
+                    Some('0') => '1',
+                    Some(_) => '0',
+                    None => continue,
+                };
+                altered.push(flipped);
+                row[*i] = Value::String(altered);
+                corrupted += 1;
+            }
+        }
+    }
+    corrupted
+}
+
+pub fn record_corruption(database_id: &str, corrupted: usize) {
+    if corrupted > 0 {
+        STATE.with(|s| s.borrow_mut().record("corrupt_ciphertext", database_id, format!("{} cells", corrupted)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(value: Value) -> FaultRule {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn test_validate() {
+        let injection = |rules: Vec<FaultRule>| FaultInjection { enabled: true, rules };
+        let production = vec!["prod".to_string()];
+        assert!(injection(vec![rule(json!({ "route": "sql_list", "delay_ms": 100 }))]).validate(&production).is_ok());
+        assert_eq!(injection(vec![rule(json!({ "route": "sql_list" }))]).validate(&production).unwrap_err(), "fault rule 0 injects nothing");
+        assert!(injection(vec![rule(json!({ "fail_host_call": 0 }))]).validate(&production).is_err());
+        assert!(injection(vec![rule(json!({ "corrupt_ciphertext_percent": 101 }))]).validate(&production).is_err());
+        assert_eq!(injection(vec![rule(json!({ "database_id": "prod", "fail_key_load": true }))]).validate(&production).unwrap_err(),
+            "fault rule 0 targets database prod which is tagged production");
+        assert!(serde_json::from_value::<FaultRule>(json!({ "delay": 5 })).is_err());
+    }
+
+    #[test]
+    fn test_rules_skip_production_and_other_routes() {
+        begin("sql_query", FaultInjection { enabled: true, rules: vec![
+            rule(json!({ "route": "sql_query", "fail_host_call": 2 })),
+            rule(json!({ "route": "sql_list", "fail_key_load": true })),
+        ] });
+        assert!(before_host_call("db", false).is_ok());
+        assert!(before_host_call("db", false).unwrap_err().to_string().contains("host call 2"));
+        assert!(before_key_load("db", false).is_ok());
+        assert_eq!(injected().len(), 1);
+
+        begin("sql_query", FaultInjection { enabled: true, rules: vec![rule(json!({ "fail_host_call": 1, "corrupt_ciphertext_percent": 50 }))] });
+        assert!(before_host_call("db", true).is_ok());
+        assert_eq!(corruption_percent("db", true), None);
+        assert_eq!(corruption_percent("db", false), Some(50));
+
+        begin("sql_query", FaultInjection { enabled: false, rules: vec![rule(json!({ "fail_key_load": true }))] });
+        assert!(before_key_load("db", false).is_ok());
+        assert!(injected().is_empty());
+    }
+
+    #[test]
+    fn test_corrupt_ciphertexts() {
+        let mut raw = json!({
+            "fields": [{ "name": "id" }, { "name": "email" }],
+            "resultset": [[1, "00ab"], [2, "00ac"], [3, null]],
+        });
+        assert_eq!(corrupt_ciphertexts(&mut raw.clone(), &["email".to_string()], 0), 0);
+        assert_eq!(corrupt_ciphertexts(&mut raw, &["email".to_string()], 100), 2);
+        assert_eq!(raw["resultset"], json!([[1, "00a0"], [2, "00a0"], [3, null]]));
+    }
+
+    #[test]
+    fn test_stamp() {
+        begin("sql_query", FaultInjection::default());
+        let mut payload = json!({ "rows": 1 });
+        assert_eq!(stamp(&mut payload), None);
+        assert_eq!(payload, json!({ "rows": 1 }));
+
+        STATE.with(|s| s.borrow_mut().record("delay", "db", "10 ms before host call 1".to_string()));
+        assert_eq!(stamp(&mut payload), None);
+        assert_eq!(payload["injected_faults"][0]["kind"], "delay");
+        let mut list = json!([1]);
+        assert!(stamp(&mut list).is_some());
+    }
+}
@@ -0,0 +1,140 @@
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{audit, confirm::{self, ActionSummary, ConfirmStep, ConfirmationInput}, database, notify, settings::require_admin, utils::get_trusted_time};
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FreezeInput {
+    pub database_id: String,
+    pub reason: String,
+    pub duration_seconds: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UnfreezeInput {
+    pub database_id: String,
+    // Returned by a first call without it, ties the unfreeze to the freeze the caller has seen
+    #[serde(flatten)]
+    pub confirmation: ConfirmationInput,
+}
+
+pub fn freeze_until(now: u64, duration_seconds: u64) -> Result<u64, String> {
+    if duration_seconds == 0 {
+        return Err("duration_seconds must be greater than 0".to_string());
+    }
+    duration_seconds.checked_mul(NANOS_PER_SECOND).and_then(|d| now.checked_add(d)).ok_or("duration_seconds is too large".to_string())
+}
+
+fn load_client(database_id: &str) -> Option<database::Client> {
+    match database::Client::load(database_id.to_string()) {
+        Ok(c) => Some(c),
+        Err(err) => {
+            notify::error_with_code(&format!("Failed to load client: {}", err), err.code());
+            None
+        }
+    }
+}
+
+// Stops every use of the keys of a database until the freeze lapses or is lifted; rows are left untouched
+pub fn freeze_database(cmd: String) {
+    let input: FreezeInput = match notify::parse_input(&cmd) {
+        Some(input) => input,
+        None => return,
+    };
+    if input.reason.trim().is_empty() {
+        notify::error("A freeze needs a reason");
+        return;
+    }
+    if let Err(err) = require_admin("freeze a database") {
+        notify::error(&err);
+        return;
+    }
+    let mut client = match load_client(&input.database_id) {
+        Some(c) => c,
+        None => return,
+    };
+    let until = match freeze_until(get_trusted_time(), input.duration_seconds) {
+        Ok(until) => until,
+        Err(err) => {
+            notify::error(&format!("Invalid input: {}", err));
+            return;
+        }
+    };
+
+    client.set_freeze(Some(until), Some(input.reason.clone()));
+    match client.save_policies() {
+        Ok(_) => {
+            audit::record("freeze_database", Some(&input.database_id), "success", json!({ "reason": input.reason, "frozen_until": until }));
+            notify::result(&json!({ "database_id": input.database_id, "frozen_until": until, "reason": input.reason }));
+        },
+        Err(err) => {
+            audit::record("freeze_database", Some(&input.database_id), "failure", json!({ "reason": input.reason }));
+            notify::error_with_code(&format!("Failed to freeze database: {}", err), err.code());
+        }
+    }
+}
+
+pub fn unfreeze_database(cmd: String) {
+    let input: UnfreezeInput = match notify::parse_input(&cmd) {
+        Some(input) => input,
+        None => return,
+    };
+    if let Err(err) = require_admin("unfreeze a database") {
+        notify::error(&err);
+        return;
+    }
+    let mut client = match load_client(&input.database_id) {
+        Some(c) => c,
+        None => return,
+    };
+    let (until, reason) = match client.frozen_until() {
+        Some(until) => (until, client.frozen(get_trusted_time()).map(|r| r.to_string())),
+        None => {
+            notify::error(&format!("Database {} is not frozen", input.database_id));
+            return;
+        }
+    };
+
+    let mut summary = ActionSummary::new("unfreeze_database", &client);
+    summary.details.insert("frozen_until".to_string(), until.to_string());
+    summary.details.insert("reason".to_string(), reason.clone().unwrap_or_default());
+    match confirm::confirm(&summary, &input.confirmation) {
+        Ok(ConfirmStep::Issued(prompt)) => {
+            notify::result(&prompt);
+            return;
+        }
+        Ok(ConfirmStep::Confirmed) => (),
+        Err(err) => {
+            audit::record("unfreeze_database", Some(&input.database_id), "refused", json!({ "frozen_until": until }));
+            notify::error_with_code(&format!("Refusing to unfreeze: {}", err), database::DatabaseError::code_of(err.as_ref()));
+            return;
+        }
+    }
+
+    client.set_freeze(None, None);
+    match client.save_policies() {
+        Ok(_) => {
+            audit::record("unfreeze_database", Some(&input.database_id), "success", json!({ "frozen_until": until, "reason": reason }));
+            notify::result(&json!({ "database_id": input.database_id, "unfrozen": true }));
+        },
+        Err(err) => {
+            audit::record("unfreeze_database", Some(&input.database_id), "failure", json!({ "frozen_until": until }));
+            notify::error_with_code(&format!("Failed to unfreeze database: {}", err), err.code());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_freeze_until() {
+        assert_eq!(freeze_until(5, 2), Ok(5 + 2 * NANOS_PER_SECOND));
+        assert!(freeze_until(5, 0).is_err());
+        assert!(freeze_until(5, u64::MAX).is_err());
+    }
+}
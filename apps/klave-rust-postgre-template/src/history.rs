@@ -0,0 +1,383 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{
+    audit::{self, AuditEntry, AUDIT_LOG_TABLE},
+    database::{Clients, DatabaseError},
+    locks::{self, JobLock, LockDecision, JOB_LOCK_TABLE},
+    manifest::{EncryptionManifest, TableState},
+    notify,
+    settings::{require_admin, DeploymentSettings},
+    storage,
+    utils::{get_trusted_time, ledger_get, ledger_key, remove_legacy_key},
+};
+
+pub(crate) const AUDIT_AGGREGATE_TABLE: &str = "AuditAggregateTable";
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * NANOS_PER_SECOND;
+// Detailed entries compacted by one prune_history call, later calls pick up the rest
+const DEFAULT_PRUNE_BATCH: usize = 500;
+
+fn default_prune_batch() -> usize {
+    DEFAULT_PRUNE_BATCH
+}
+
+// Audit entries of one day and database, counted by route then outcome
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditAggregate {
+    pub day: u64,
+    pub database_id: Option<String>,
+    pub counts: BTreeMap<String, BTreeMap<String, u64>>,
+}
+
+impl AuditAggregate {
+    // "-" stands for entries of no database, a database id of "-" is encoded
+    pub fn key(day: u64, database_id: Option<&str>) -> String {
+        ledger_key(&[&format!("{:08}", day), database_id.unwrap_or("-")])
+    }
+
+    fn legacy_key(day: u64, database_id: Option<&str>) -> String {
+        format!("{:08}/{}", day, database_id.unwrap_or("-"))
+    }
+
+    pub fn fold(&mut self, entry: &AuditEntry) {
+        *self.counts.entry(entry.route.clone()).or_default().entry(entry.outcome.clone()).or_default() += 1;
+    }
+
+    pub fn total(&self) -> u64 {
+        self.counts.values().flat_map(|outcomes| outcomes.values()).sum()
+    }
+
+    fn load(day: u64, database_id: Option<&str>) -> Option<AuditAggregate> {
+        ledger_get(AUDIT_AGGREGATE_TABLE, &Self::key(day, database_id), &Self::legacy_key(day, database_id))
+            .and_then(|v| serde_json::from_slice::<AuditAggregate>(&v).ok())
+    }
+
+    fn save(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let serialized = serde_json::to_string(self)?;
+        let key = Self::key(self.day, self.database_id.as_deref());
+        storage::ledger_set(AUDIT_AGGREGATE_TABLE, &key, serialized.as_bytes())?;
+        remove_legacy_key(AUDIT_AGGREGATE_TABLE, &key, &Self::legacy_key(self.day, self.database_id.as_deref()));
+        Ok(())
+    }
+}
+
+pub fn day_of(timestamp: u64) -> u64 {
+    timestamp / NANOS_PER_DAY
+}
+
+// Start of the retention window, None when the retention is 0 and everything is kept
+pub fn retention_cutoff(now: u64, retention_seconds: u64) -> Option<u64> {
+    if retention_seconds == 0 {
+        return None;
+    }
+    Some(now.saturating_sub(retention_seconds.saturating_mul(NANOS_PER_SECOND)))
+}
+
+// Records still needed to follow up on work that has not finished
+#[derive(Debug, Clone, Default)]
+pub struct OpenRecords {
+    // Database and start of each job holding a live lock
+    pub jobs: Vec<(String, u64)>,
+    // Databases with a table left in the applying state by a failed encryption run
+    pub failed: Vec<String>,
+}
+
+impl OpenRecords {
+    pub fn references(&self, entry: &AuditEntry) -> bool {
+        let database_id = match entry.database_id.as_deref() {
+            Some(db) => db,
+            None => return false,
+        };
+        self.jobs.iter().any(|(db, since)| db == database_id && entry.timestamp >= *since)
+            || (entry.outcome != "success" && self.failed.iter().any(|db| db == database_id))
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CompactionPlan {
+    pub compact: Vec<AuditEntry>,
+    // Entries past retention kept because an open record references them
+    pub protected: usize,
+    // Entries past retention left for the next call
+    pub more: bool,
+}
+
+// Oldest entries first, so that an interrupted run resumes where it stopped
+pub fn plan_compaction(mut entries: Vec<AuditEntry>, cutoff: u64, open: &OpenRecords, limit: usize) -> CompactionPlan {
+    entries.sort_by(|a, b| a.id.cmp(&b.id));
+    let mut plan = CompactionPlan::default();
+    for entry in entries.into_iter().filter(|e| e.timestamp < cutoff) {
+        if open.references(&entry) {
+            plan.protected += 1;
+        } else if plan.compact.len() < limit {
+            plan.compact.push(entry);
+        } else {
+            plan.more = true;
+        }
+    }
+    plan
+}
+
+// Folds the entries into the aggregates of their day, starting from the stored aggregates
+pub fn aggregate_entries<F>(entries: &[AuditEntry], mut stored: F) -> Vec<AuditAggregate>
+where
+    F: FnMut(u64, Option<&str>) -> Option<AuditAggregate>,
+{
+    let mut aggregates: BTreeMap<String, AuditAggregate> = BTreeMap::new();
+    for entry in entries {
+        let day = day_of(entry.timestamp);
+        let key = AuditAggregate::key(day, entry.database_id.as_deref());
+        aggregates.entry(key)
+            .or_insert_with(|| stored(day, entry.database_id.as_deref()).unwrap_or(AuditAggregate { day, database_id: entry.database_id.clone(), counts: BTreeMap::new() }))
+            .fold(entry);
+    }
+    aggregates.into_values().collect()
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PruneHistoryInput {
+    #[serde(default = "default_prune_batch")]
+    pub limit: usize,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct PruneReport {
+    pub compacted_entries: usize,
+    pub aggregates_written: usize,
+    pub protected_entries: usize,
+    pub aggregates_deleted: usize,
+    pub job_records_deleted: usize,
+    // Call prune_history again to compact the rest
+    pub more: bool,
+}
+
+fn open_records(jobs: &[(String, JobLock)], now: u64, stale_after: u64) -> Result<OpenRecords, Box<dyn std::error::Error>> {
+    let mut open = OpenRecords::default();
+    for (_, job) in jobs {
+        if let LockDecision::Held(_) = locks::decide(Some(job), now, stale_after) {
+            open.jobs.push((job.database_id.clone(), job.acquired_at));
+        }
+    }
+    for client in Clients::load_all()?.list()? {
+        let manifest = EncryptionManifest::load(client.database_id())?;
+        if manifest.tables.iter().any(|t| t.state == TableState::Applying) {
+            open.failed.push(client.database_id().to_string());
+        }
+    }
+    Ok(open)
+}
+
+fn prune(limit: usize) -> Result<PruneReport, Box<dyn std::error::Error>> {
+    let settings = DeploymentSettings::load()?;
+    let now = get_trusted_time();
+    let jobs = locks::load_all()?;
+    let stale_after = settings.job_stale_after_seconds.saturating_mul(NANOS_PER_SECOND);
+    let mut report = PruneReport::default();
+
+    if let Some(cutoff) = retention_cutoff(now, settings.audit_detail_retention_seconds) {
+        let plan = plan_compaction(audit::load_entries()?, cutoff, &open_records(&jobs, now, stale_after)?, limit);
+        // Aggregates are written before the detailed rows go, both in this transaction
+        let aggregates = aggregate_entries(&plan.compact, AuditAggregate::load);
+        for aggregate in aggregates.iter() {
+            aggregate.save()?;
+        }
+        for entry in plan.compact.iter() {
+            storage::ledger_remove(AUDIT_LOG_TABLE, &entry.id)?;
+        }
+        report.compacted_entries = plan.compact.len();
+        report.aggregates_written = aggregates.len();
+        report.protected_entries = plan.protected;
+        report.more = plan.more;
+    }
+
+    if let Some(cutoff) = retention_cutoff(now, settings.audit_aggregate_retention_seconds) {
+        let ledger = crate::runtime::ledger::get_table(AUDIT_AGGREGATE_TABLE);
+        for key in ledger.list_keys()? {
+            let expired = key.split('/').next().and_then(|day| day.parse::<u64>().ok())
+                .map(|day| (day + 1) * NANOS_PER_DAY <= cutoff)
+                .unwrap_or(false);
+            if expired {
+                storage::ledger_remove(AUDIT_AGGREGATE_TABLE, &key)?;
+                report.aggregates_deleted += 1;
+            }
+        }
+    }
+
+    // Only abandoned locks, a live lock belongs to a running job
+    if let Some(cutoff) = retention_cutoff(now, settings.job_record_retention_seconds) {
+        for (key, _) in jobs.iter().filter(|(_, j)| j.heartbeat_at < cutoff && !matches!(locks::decide(Some(j), now, stale_after), LockDecision::Held(_))) {
+            storage::ledger_remove(JOB_LOCK_TABLE, key)?;
+            report.job_records_deleted += 1;
+        }
+    }
+    Ok(report)
+}
+
+// Maintenance: compacts old audit entries into daily aggregates and drops records past their retention
+pub fn prune_history(cmd: String) {
+    let input: PruneHistoryInput = match notify::parse_input(&cmd) {
+        Some(input) => input,
+        None => return,
+    };
+    if let Err(err) = require_admin("prune the history") {
+        audit::record("prune_history", None, "refused", json!({}));
+        notify::error(&err);
+        return;
+    }
+    match prune(input.limit) {
+        Ok(report) => {
+            audit::record("prune_history", None, "success", json!({ "compacted_entries": report.compacted_entries, "more": report.more }));
+            notify::result(&report);
+        },
+        Err(err) => {
+            audit::record("prune_history", None, "failure", json!({}));
+            notify::error_with_code(&format!("Failed to prune history: {}", err), DatabaseError::code_of(err.as_ref()));
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AuditLogInput {
+    // Trusted time range, in nanoseconds, both ends included
+    #[serde(default)]
+    pub from: Option<u64>,
+    #[serde(default)]
+    pub to: Option<u64>,
+    #[serde(default)]
+    pub database_id: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AuditLogView {
+    pub entries: Vec<AuditEntry>,
+    // Days compacted by prune_history that overlap the range
+    pub aggregates: Vec<AuditAggregate>,
+}
+
+// Detail where it is still kept, daily aggregates for older days
+pub fn select_history(mut entries: Vec<AuditEntry>, mut aggregates: Vec<AuditAggregate>, input: &AuditLogInput) -> AuditLogView {
+    let from = input.from.unwrap_or(0);
+    let to = input.to.unwrap_or(u64::MAX);
+    let database = |db: &Option<String>| input.database_id.is_none() || db == &input.database_id;
+    entries.retain(|e| e.timestamp >= from && e.timestamp <= to && database(&e.database_id));
+    entries.sort_by(|a, b| a.id.cmp(&b.id));
+    aggregates.retain(|a| {
+        let start = a.day.saturating_mul(NANOS_PER_DAY);
+        let end = start.saturating_add(NANOS_PER_DAY - 1);
+        start <= to && end >= from && database(&a.database_id)
+    });
+    aggregates.sort_by(|a, b| (a.day, &a.database_id).cmp(&(b.day, &b.database_id)));
+    AuditLogView { entries, aggregates }
+}
+
+fn load_aggregates() -> Result<Vec<AuditAggregate>, Box<dyn std::error::Error>> {
+    let ledger = crate::runtime::ledger::get_table(AUDIT_AGGREGATE_TABLE);
+    let mut aggregates = Vec::new();
+    for key in ledger.list_keys()? {
+        if let Ok(aggregate) = serde_json::from_slice::<AuditAggregate>(&ledger.get(&key)?) {
+            aggregates.push(aggregate);
+        }
+    }
+    Ok(aggregates)
+}
+
+pub fn get_audit_log(cmd: String) {
+    let input: AuditLogInput = match notify::parse_input(&cmd) {
+        Some(input) => input,
+        None => return,
+    };
+    if let Err(err) = require_admin("read the audit log") {
+        notify::error(&err);
+        return;
+    }
+    let sources = audit::load_entries().and_then(|entries| Ok((entries, load_aggregates()?)));
+    match sources {
+        Ok((entries, aggregates)) => notify::result(&select_history(entries, aggregates, &input)),
+        Err(err) => notify::error_with_code(&format!("Failed to read the audit log: {}", err), DatabaseError::code_of(err.as_ref())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use super::*;
+
+    fn entry(timestamp: u64, route: &str, database_id: Option<&str>, outcome: &str) -> AuditEntry {
+        AuditEntry {
+            id: format!("{:020}-00", timestamp),
+            timestamp,
+            sender: "alice".to_string(),
+            route: route.to_string(),
+            database_id: database_id.map(|db| db.to_string()),
+            outcome: outcome.to_string(),
+            details: Value::Null,
+        }
+    }
+
+    #[test]
+    fn test_plan_compaction() {
+        let day = NANOS_PER_DAY;
+        let entries = vec![
+            entry(3 * day, "sql_script", Some("db1"), "success"),
+            entry(day, "sql_script", Some("db1"), "success"),
+            entry(day + 1, "sql_script", Some("db2"), "failure"),
+            entry(2 * day, "execute_table_encryption", Some("db3"), "success"),
+            entry(2 * day + 1, "db_setup", None, "success"),
+            entry(10 * day, "sql_script", Some("db1"), "success"),
+        ];
+        let open = OpenRecords { jobs: vec![("db3".to_string(), 2 * day)], failed: vec!["db2".to_string()] };
+        let plan = plan_compaction(entries.clone(), 5 * day, &open, 2);
+        assert_eq!(plan.compact.iter().map(|e| e.timestamp).collect::<Vec<_>>(), vec![day, 2 * day + 1]);
+        assert_eq!(plan.protected, 2);
+        assert!(plan.more);
+
+        let plan = plan_compaction(entries, 5 * day, &OpenRecords::default(), 10);
+        assert_eq!(plan.compact.len(), 5);
+        assert!(!plan.more);
+    }
+
+    #[test]
+    fn test_aggregate_entries() {
+        let day = NANOS_PER_DAY;
+        let mut stored = AuditAggregate { day: 1, database_id: Some("db1".to_string()), counts: BTreeMap::new() };
+        stored.fold(&entry(day, "sql_script", Some("db1"), "success"));
+        let entries = vec![
+            entry(day + 5, "sql_script", Some("db1"), "success"),
+            entry(day + 6, "sql_script", Some("db1"), "failure"),
+            entry(2 * day, "sql_script", Some("db1"), "success"),
+        ];
+        let aggregates = aggregate_entries(&entries, |day, database_id| (day == 1 && database_id == Some("db1")).then(|| stored.clone()));
+        assert_eq!(aggregates.len(), 2);
+        assert_eq!(aggregates[0].counts["sql_script"]["success"], 2);
+        assert_eq!(aggregates[0].counts["sql_script"]["failure"], 1);
+        assert_eq!(aggregates[0].total(), 3);
+        assert_eq!(aggregates[1].total(), 1);
+    }
+
+    #[test]
+    fn test_select_history() {
+        let day = NANOS_PER_DAY;
+        let entries = vec![entry(5 * day, "sql_script", Some("db1"), "success"), entry(5 * day + 1, "sql_script", Some("db2"), "success")];
+        let aggregates = vec![
+            AuditAggregate { day: 1, database_id: Some("db1".to_string()), counts: BTreeMap::new() },
+            AuditAggregate { day: 3, database_id: Some("db1".to_string()), counts: BTreeMap::new() },
+        ];
+        let input = AuditLogInput { from: Some(2 * day - 1), to: None, database_id: Some("db1".to_string()) };
+        let view = select_history(entries, aggregates, &input);
+        assert_eq!(view.entries.len(), 1);
+        assert_eq!(view.aggregates.iter().map(|a| a.day).collect::<Vec<_>>(), vec![1, 3]);
+    }
+
+    #[test]
+    fn test_retention_cutoff() {
+        assert_eq!(retention_cutoff(10 * NANOS_PER_SECOND, 0), None);
+        assert_eq!(retention_cutoff(10 * NANOS_PER_SECOND, 4), Some(6 * NANOS_PER_SECOND));
+        assert_eq!(retention_cutoff(NANOS_PER_SECOND, 4), Some(0));
+    }
+}
@@ -0,0 +1,651 @@
+use std::borrow::Cow;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::{database::Field, testdata::format_timestamp, utils::pg_type_name};
+
+// Normalization of the resultsets returned by the Klave SQL host.
+// Host versions disagree on how cells are encoded (numbers as JSON numbers or strings, timestamps
+// in several formats, booleans as "t"/"f"), which breaks canonicalization and deterministic search.
+// Every quirk is handled here so that the rest of the crate sees a single shape:
+// numbers as strings, booleans as bools, timestamps as "YYYY-MM-DD HH:MM:SS[.ffffff][+HH:MM]".
+
+// Field type codes observed in host responses
+const HOST_TYPE_INTEGER: u32 = 3;
+const HOST_TYPE_TEXT: u32 = 12;
+const HOST_TYPE_NUMERIC: u32 = 15;
+
+// Offset PostgreSQL adds to the numeric type modifier
+const NUMERIC_TYPMOD_OFFSET: u64 = 4;
+
+// Hosts substitute bytes they cannot decode as UTF-8 with this character
+pub const REPLACEMENT_CHARACTER: char = '\u{FFFD}';
+
+// What a read does with text cells the host could not decode as UTF-8
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TextDecodePolicy {
+    // The read fails, naming the first offending row and column
+    Error,
+    // Cells are kept as decoded and listed in the lossy_cells of the response
+    #[default]
+    Lossy,
+}
+
+// What scripts and DDL helpers do with the notices the server raises while running a statement
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NoticePolicy {
+    // Notices are passed on, only those matching notice_error_patterns fail the statement
+    #[default]
+    Warn,
+    // Any WARNING fails the statement as well
+    Fail,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LossyCell {
+    pub row: usize,
+    pub column: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CellKind {
+    Integer,
+    Numeric,
+    Boolean,
+    Timestamp,
+    Text,
+    Unknown,
+}
+
+// Type name carried by newer hosts, either as its own key or in the description
+fn type_name(field: &Field) -> Option<String> {
+    field.type_name.clone()
+        .or_else(|| field.description.clone())
+        .map(|s| s.to_ascii_lowercase())
+}
+
+pub fn cell_kind(field: &Field) -> CellKind {
+    if let Some(name) = type_name(field) {
+        let kind = match name.as_str() {
+            "int2" | "int4" | "int8" | "smallint" | "integer" | "bigint" => CellKind::Integer,
+            "numeric" | "decimal" | "float4" | "float8" | "real" | "double precision" => CellKind::Numeric,
+            "bool" | "boolean" => CellKind::Boolean,
+            "timestamp" | "timestamptz" | "timestamp without time zone" | "timestamp with time zone" => CellKind::Timestamp,
+            "text" | "varchar" | "bpchar" | "character varying" | "character" => CellKind::Text,
+            _ => CellKind::Unknown,
+        };
+        if kind != CellKind::Unknown {
+            return kind;
+        }
+    }
+    match field.field_type {
+        HOST_TYPE_INTEGER => CellKind::Integer,
+        HOST_TYPE_NUMERIC => CellKind::Numeric,
+        HOST_TYPE_TEXT => CellKind::Text,
+        _ => CellKind::Unknown,
+    }
+}
+
+// SQL type a literal of the field is cast to so that PostgreSQL does not have to guess it, e.g. in a
+// VALUES list. The exact type when the host names it, the widest of its kind otherwise.
+pub fn sql_cast(field: &Field) -> Option<&'static str> {
+    let named = type_name(field).and_then(|name| match name.as_str() {
+        "int2" | "smallint" => Some("smallint"),
+        "int4" | "integer" => Some("integer"),
+        "int8" | "bigint" => Some("bigint"),
+        "numeric" | "decimal" => Some("numeric"),
+        "float4" | "real" => Some("real"),
+        "float8" | "double precision" => Some("double precision"),
+        "bool" | "boolean" => Some("boolean"),
+        "timestamp" | "timestamp without time zone" => Some("timestamp"),
+        "timestamptz" | "timestamp with time zone" => Some("timestamptz"),
+        "varchar" | "character varying" => Some("varchar"),
+        "text" | "bpchar" | "character" => Some("text"),
+        _ => None,
+    });
+    named.or(match field.field_type {
+        HOST_TYPE_INTEGER => Some("bigint"),
+        HOST_TYPE_NUMERIC => Some("numeric"),
+        HOST_TYPE_TEXT => Some("text"),
+        _ => None,
+    })
+}
+
+// Hosts report scale 0 for numeric columns and encode precision and scale in the size field as the
+// PostgreSQL type modifier ((precision << 16) | scale) + 4.
+pub fn numeric_scale(field: &Field) -> u32 {
+    if field.scale > 0 {
+        return field.scale;
+    }
+    match field.size {
+        Some(typmod) if typmod >= NUMERIC_TYPMOD_OFFSET && typmod <= u32::MAX as u64 => ((typmod - NUMERIC_TYPMOD_OFFSET) & 0xffff) as u32,
+        _ => 0,
+    }
+}
+
+// Pads the fractional part of a decimal string to the given scale. Digits are never dropped.
+pub fn normalize_decimal(text: &str, scale: u32) -> Option<String> {
+    let trimmed = text.trim();
+    let unsigned = trimmed.strip_prefix('-').unwrap_or(trimmed);
+    let (int_part, frac_part) = unsigned.split_once('.').unwrap_or((unsigned, ""));
+    if int_part.is_empty() || !int_part.chars().all(|c| c.is_ascii_digit()) || !frac_part.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let mut frac = frac_part.to_string();
+    while frac.len() < scale as usize {
+        frac.push('0');
+    }
+    let sign = if trimmed.starts_with('-') { "-" } else { "" };
+    if frac.is_empty() {
+        Some(format!("{}{}", sign, int_part))
+    } else {
+        Some(format!("{}{}.{}", sign, int_part, frac))
+    }
+}
+
+pub fn normalize_boolean(value: &Value) -> Option<bool> {
+    match value {
+        Value::Bool(b) => Some(*b),
+        Value::Number(n) => n.as_u64().filter(|v| *v <= 1).map(|v| v == 1),
+        Value::String(s) => match s.trim().to_ascii_lowercase().as_str() {
+            "t" | "true" | "1" => Some(true),
+            "f" | "false" | "0" => Some(false),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+// Accepts "YYYY-MM-DD HH:MM:SS", an ISO 8601 "T" separator, trailing zeros in the fraction,
+// "Z" or abbreviated offsets ("+00", "+0530"), and unix seconds.
+pub fn normalize_timestamp(value: &Value) -> Option<String> {
+    let text = match value {
+        Value::Number(n) => return n.as_i64().map(format_timestamp),
+        Value::String(s) => s.trim(),
+        _ => return None,
+    };
+    if text.len() < 19 || !text.is_char_boundary(19) {
+        return None;
+    }
+    let (datetime, rest) = text.split_at(19);
+    let bytes = datetime.as_bytes();
+    if bytes[4] != b'-' || bytes[7] != b'-' || !(bytes[10] == b' ' || bytes[10] == b'T') || bytes[13] != b':' || bytes[16] != b':' {
+        return None;
+    }
+    let mut canonical = format!("{} {}", &datetime[..10], &datetime[11..]);
+
+    let (fraction, offset) = match rest.strip_prefix('.') {
+        Some(after_dot) => {
+            let end = after_dot.find(|c: char| !c.is_ascii_digit()).unwrap_or(after_dot.len());
+            (&after_dot[..end], &after_dot[end..])
+        }
+        None => ("", rest),
+    };
+    let fraction = fraction.trim_end_matches('0');
+    if !fraction.is_empty() {
+        canonical.push('.');
+        canonical.push_str(fraction);
+    }
+
+    let offset = offset.trim();
+    if offset.is_empty() {
+        return Some(canonical);
+    }
+    if offset == "Z" {
+        canonical.push_str("+00:00");
+        return Some(canonical);
+    }
+    let (sign, digits) = offset.split_at(1);
+    if sign != "+" && sign != "-" {
+        return None;
+    }
+    let digits: String = digits.chars().filter(|c| *c != ':').collect();
+    if !digits.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    match digits.len() {
+        2 => canonical.push_str(&format!("{}{}:00", sign, digits)),
+        4 => canonical.push_str(&format!("{}{}:{}", sign, &digits[..2], &digits[2..])),
+        _ => return None,
+    }
+    Some(canonical)
+}
+
+// Reads back a canonical integer cell, which is a string since normalization
+pub fn cell_as_u64(value: &Value) -> Option<u64> {
+    match value {
+        Value::String(s) => s.trim().parse::<u64>().ok(),
+        other => other.as_u64(),
+    }
+}
+
+// Canonical form of a cell without type information: numbers become strings, everything else is kept.
+pub fn normalize_untyped(value: Value) -> Value {
+    match value {
+        Value::Number(n) => Value::String(n.to_string()),
+        other => other,
+    }
+}
+
+// Canonical form of a cell; values that do not match their declared type are left untouched.
+pub fn normalize_cell(field: &Field, value: Value) -> Value {
+    match cell_kind(field) {
+        CellKind::Numeric => {
+            let text = match &value {
+                Value::Number(n) => n.to_string(),
+                Value::String(s) => s.clone(),
+                _ => return value,
+            };
+            match normalize_decimal(&text, numeric_scale(field)) {
+                Some(normalized) => Value::String(normalized),
+                None => value,
+            }
+        }
+        CellKind::Boolean => normalize_boolean(&value).map(Value::Bool).unwrap_or(value),
+        CellKind::Timestamp => normalize_timestamp(&value).map(Value::String).unwrap_or(value),
+        CellKind::Integer | CellKind::Text | CellKind::Unknown => normalize_untyped(value),
+    }
+}
+
+// Normalizes every cell of a resultset and records the scale of numeric fields.
+pub fn normalize_resultset(fields: &mut [Field], resultset: &mut [Vec<Value>]) {
+    for field in fields.iter_mut() {
+        if cell_kind(field) == CellKind::Numeric {
+            field.scale = numeric_scale(field);
+        }
+        // Named after the type code when the host does not name it
+        if field.type_name.is_none() {
+            field.type_name = field.description.clone().or_else(|| pg_type_name(field.field_type).map(|n| n.to_string()));
+        }
+    }
+    for row in resultset.iter_mut() {
+        for (i, cell) in row.iter_mut().enumerate() {
+            let value = std::mem::take(cell);
+            *cell = match fields.get(i) {
+                Some(field) => normalize_cell(field, value),
+                None => normalize_untyped(value),
+            };
+        }
+    }
+}
+
+// True when a text cell carries bytes that did not survive decoding
+pub fn is_lossy_text(value: &Value) -> bool {
+    value.as_str().map(|s| s.contains(REPLACEMENT_CHARACTER)).unwrap_or(false)
+}
+
+pub fn lossy_cells(fields: &[Field], resultset: &[Vec<Value>]) -> Vec<LossyCell> {
+    let mut cells = Vec::new();
+    for (row, values) in resultset.iter().enumerate() {
+        for (i, value) in values.iter().enumerate() {
+            if is_lossy_text(value) {
+                let column = fields.get(i).map(|f| f.name.clone()).unwrap_or_else(|| i.to_string());
+                cells.push(LossyCell { row, column });
+            }
+        }
+    }
+    cells
+}
+
+// Reads a "\uXXXX" escape starting at the given byte offset
+fn hex_escape(raw: &str, at: usize) -> Option<u16> {
+    raw.get(at..at + 6)
+        .filter(|s| s.starts_with("\\u"))
+        .and_then(|s| u16::from_str_radix(&s[2..], 16).ok())
+}
+
+// Some hosts escape undecodable bytes as unpaired UTF-16 surrogates, which serde_json refuses.
+// They are rewritten to the replacement character so that the policy applies to them as well.
+pub fn repair_lone_surrogates(raw: &str) -> Cow<'_, str> {
+    let bytes = raw.as_bytes();
+    let mut repaired = String::new();
+    let mut copied = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] != b'\\' {
+            i += 1;
+            continue;
+        }
+        let code = match hex_escape(raw, i) {
+            Some(code) => code,
+            None => {
+                i += 2;
+                continue;
+            }
+        };
+        let high = (0xD800..0xDC00).contains(&code);
+        let low = (0xDC00..0xE000).contains(&code);
+        if high && hex_escape(raw, i + 6).map(|next| (0xDC00..0xE000).contains(&next)).unwrap_or(false) {
+            i += 12;
+            continue;
+        }
+        if high || low {
+            repaired.push_str(&raw[copied..i]);
+            repaired.push_str("\\ufffd");
+            copied = i + 6;
+        }
+        i += 6;
+    }
+    if copied == 0 {
+        return Cow::Borrowed(raw);
+    }
+    repaired.push_str(&raw[copied..]);
+    Cow::Owned(repaired)
+}
+
+// NOTICE or WARNING raised by the server while running a statement, when the host passes it on
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Notice {
+    pub severity: String,
+    pub message: String,
+}
+
+const NOTICE_SEVERITIES: [&str; 5] = ["DEBUG", "LOG", "INFO", "NOTICE", "WARNING"];
+const NOTICE_KEYS: [&str; 3] = ["notices", "warnings", "messages"];
+
+// "NOTICE:  relation \"t\" already exists, skipping" as printed by the server
+fn parse_notice_line(line: &str) -> Option<Notice> {
+    let (severity, message) = line.trim().split_once(':')?;
+    let severity = severity.trim().to_uppercase();
+    if !NOTICE_SEVERITIES.contains(&severity.as_str()) {
+        return None;
+    }
+    Some(Notice { severity, message: message.trim().to_string() })
+}
+
+fn notice_from_value(value: &Value) -> Option<Notice> {
+    match value {
+        Value::String(line) => parse_notice_line(line)
+            .or_else(|| Some(Notice { severity: "NOTICE".to_string(), message: line.trim().to_string() })),
+        Value::Object(obj) => {
+            let message = ["message", "text", "msg"].iter().find_map(|key| obj.get(*key).and_then(|v| v.as_str()))?;
+            let severity = ["severity", "level"].iter().find_map(|key| obj.get(*key).and_then(|v| v.as_str())).unwrap_or("NOTICE");
+            Some(Notice { severity: severity.trim().to_uppercase(), message: message.trim().to_string() })
+        }
+        _ => None,
+    }
+}
+
+// Removes the notices from a host result object, whichever key the host version uses for them
+pub fn take_notices(obj: &mut Map<String, Value>) -> Vec<Notice> {
+    let mut notices = Vec::new();
+    for key in NOTICE_KEYS {
+        if let Some(Value::Array(entries)) = obj.remove(key) {
+            notices.extend(entries.iter().filter_map(notice_from_value));
+        }
+    }
+    notices
+}
+
+// First notice that fails the statement under the policy, patterns match case-insensitively at any severity
+pub fn fatal_notice<'a>(notices: &'a [Notice], policy: NoticePolicy, error_patterns: &[String]) -> Option<&'a Notice> {
+    notices.iter().find(|notice| {
+        let message = notice.message.to_lowercase();
+        (policy == NoticePolicy::Fail && notice.severity == "WARNING")
+            || error_patterns.iter().any(|pattern| !pattern.is_empty() && message.contains(&pattern.to_lowercase()))
+    })
+}
+
+// Result of an execute call. Host versions return either a bare command tag such as "UPDATE 3"
+// or a JSON object with row counts, possibly encoded once more as a JSON string.
+// Notices come as a list in the object or as server lines printed before the command tag.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExecuteResult {
+    pub command_tag: Option<String>,
+    pub rows_affected: Option<u64>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub notices: Vec<Notice>,
+}
+
+fn command_tag_rows(tag: &str) -> Option<u64> {
+    tag.split_whitespace().last().and_then(|last| last.parse::<u64>().ok())
+}
+
+pub fn normalize_execute_result(raw: &str) -> ExecuteResult {
+    match serde_json::from_str::<Value>(raw) {
+        Ok(Value::String(inner)) => normalize_execute_result(&inner),
+        Ok(Value::Number(n)) => ExecuteResult { command_tag: None, rows_affected: n.as_u64(), notices: Vec::new() },
+        Ok(Value::Object(mut obj)) => {
+            let notices = take_notices(&mut obj);
+            let command_tag = ["command_tag", "commandTag", "command", "status"].iter()
+                .find_map(|key| obj.get(*key).and_then(|v| v.as_str()))
+                .map(|s| s.to_string());
+            let rows_affected = ["rows_affected", "rowsAffected", "row_count", "rowCount"].iter()
+                .find_map(|key| obj.get(*key).and_then(|v| v.as_u64()))
+                .or_else(|| command_tag.as_deref().and_then(command_tag_rows));
+            ExecuteResult { command_tag, rows_affected, notices }
+        }
+        _ => {
+            let mut notices = Vec::new();
+            let mut tag_lines = Vec::new();
+            for line in raw.lines().map(str::trim).filter(|l| !l.is_empty()) {
+                match parse_notice_line(line) {
+                    Some(notice) => notices.push(notice),
+                    None => tag_lines.push(line),
+                }
+            }
+            let tag = tag_lines.join(" ");
+            if tag.is_empty() {
+                return ExecuteResult { notices, ..ExecuteResult::default() };
+            }
+            ExecuteResult { rows_affected: command_tag_rows(&tag), command_tag: Some(tag), notices }
+        }
+    }
+}
+
+// Normalizes a raw host response in place when its resultset is made of rows.
+// Undecodable text fails the read or is listed under "lossy_cells", depending on the policy.
+// Notices are moved under "notices" whatever the shape of the resultset.
+pub fn normalize_response(response: &mut Value, policy: TextDecodePolicy) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(obj) = response.as_object_mut() {
+        let notices = take_notices(obj);
+        if !notices.is_empty() {
+            obj.insert("notices".to_string(), serde_json::to_value(notices)?);
+        }
+    }
+    let rows_shaped = response.get("resultset").and_then(|r| r.as_array()).map(|rows| rows.iter().all(|r| r.is_array())).unwrap_or(false);
+    if !rows_shaped {
+        return Ok(());
+    }
+    let mut fields: Vec<Field> = serde_json::from_value(response.get("fields").cloned().unwrap_or(Value::Array(Vec::new())))?;
+    let mut resultset: Vec<Vec<Value>> = serde_json::from_value(response["resultset"].take())?;
+    normalize_resultset(&mut fields, &mut resultset);
+    let lossy = lossy_cells(&fields, &resultset);
+    if let Some(first) = lossy.first() {
+        if policy == TextDecodePolicy::Error {
+            return Err(format!("Row {}, column '{}' holds text that is not valid UTF-8", first.row, first.column).into());
+        }
+        response["lossy_cells"] = serde_json::to_value(&lossy)?;
+    }
+    response["fields"] = serde_json::to_value(fields)?;
+    response["resultset"] = serde_json::to_value(resultset)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn field(field_type: u32, size: Option<u64>) -> Field {
+        let mut f = Field::named("c");
+        f.field_type = field_type;
+        f.size = size;
+        f
+    }
+
+    #[test]
+    fn test_numbers_as_json_numbers_or_strings() {
+        let integer = field(HOST_TYPE_INTEGER, None);
+        assert_eq!(normalize_cell(&integer, json!(42)), json!("42"));
+        assert_eq!(normalize_cell(&integer, json!("42")), json!("42"));
+    }
+
+    #[test]
+    fn test_numeric_scale_from_type_modifier() {
+        let price = field(HOST_TYPE_NUMERIC, Some(655366));
+        assert_eq!(numeric_scale(&price), 2);
+        assert_eq!(normalize_cell(&price, json!("25.5")), json!("25.50"));
+        assert_eq!(normalize_cell(&price, json!(1200)), json!("1200.00"));
+        assert_eq!(normalize_cell(&price, json!("1.2345")), json!("1.2345"));
+        assert_eq!(normalize_cell(&price, json!("NaN")), json!("NaN"));
+        assert_eq!(numeric_scale(&field(HOST_TYPE_NUMERIC, None)), 0);
+    }
+
+    #[test]
+    fn test_booleans() {
+        let mut flag = field(0, None);
+        flag.type_name = Some("bool".to_string());
+        assert_eq!(normalize_cell(&flag, json!("t")), json!(true));
+        assert_eq!(normalize_cell(&flag, json!("false")), json!(false));
+        assert_eq!(normalize_cell(&flag, json!(1)), json!(true));
+        assert_eq!(normalize_cell(&flag, json!("maybe")), json!("maybe"));
+    }
+
+    #[test]
+    fn test_timestamps() {
+        assert_eq!(normalize_timestamp(&json!("2024-03-01T10:20:30Z")).unwrap(), "2024-03-01 10:20:30+00:00");
+        assert_eq!(normalize_timestamp(&json!("2024-03-01 10:20:30.120000+00")).unwrap(), "2024-03-01 10:20:30.12+00:00");
+        assert_eq!(normalize_timestamp(&json!("2024-03-01 10:20:30+0530")).unwrap(), "2024-03-01 10:20:30+05:30");
+        assert_eq!(normalize_timestamp(&json!("2024-03-01 10:20:30.000")).unwrap(), "2024-03-01 10:20:30");
+        assert_eq!(normalize_timestamp(&json!(0)).unwrap(), "1970-01-01 00:00:00");
+        assert_eq!(normalize_timestamp(&json!("yesterday")), None);
+    }
+
+    #[test]
+    fn test_cell_as_u64() {
+        assert_eq!(cell_as_u64(&json!("42")), Some(42));
+        assert_eq!(cell_as_u64(&json!(42)), Some(42));
+        assert_eq!(cell_as_u64(&json!("4.2")), None);
+    }
+
+    #[test]
+    fn test_text_is_untouched() {
+        let text = field(HOST_TYPE_TEXT, Some(104));
+        assert_eq!(normalize_cell(&text, json!("2024-03-01T10:20:30Z")), json!("2024-03-01T10:20:30Z"));
+        assert_eq!(normalize_cell(&text, Value::Null), Value::Null);
+    }
+
+    #[test]
+    fn test_normalize_response() {
+        let mut response = json!({
+            "fields": [
+                { "name": "id", "type": 3, "size": 18446744073709551615u64, "scale": 0, "nullable": true, "description": null },
+                { "name": "price", "type": 15, "size": 655366, "scale": 0, "nullable": true, "description": null }
+            ],
+            "resultset": [[1, "25.5"], [2, 3]]
+        });
+        normalize_response(&mut response, TextDecodePolicy::Error).unwrap();
+        assert_eq!(response["resultset"], json!([["1", "25.50"], ["2", "3.00"]]));
+        assert_eq!(response["fields"][1]["scale"], json!(2));
+    }
+
+    #[test]
+    fn test_large_numbers_keep_their_digits() {
+        // Without arbitrary_precision these would round through f64
+        let raw = r#"{
+            "fields": [
+                { "name": "id", "type": 3, "size": 8, "scale": 0, "nullable": false, "description": null },
+                { "name": "amount", "type": 15, "size": 2490382, "scale": 0, "nullable": true, "description": null }
+            ],
+            "resultset": [[9007199254740993, 12345678901234567890123456.0123456789], [9223372036854775807, "-0.1"]]
+        }"#;
+        let mut response: Value = serde_json::from_str(raw).unwrap();
+        normalize_response(&mut response, TextDecodePolicy::Error).unwrap();
+        assert_eq!(response["resultset"], json!([
+            ["9007199254740993", "12345678901234567890123456.0123456789"],
+            ["9223372036854775807", "-0.1000000000"]
+        ]));
+        assert_eq!(response["fields"][1]["scale"], json!(10));
+        assert_eq!(cell_as_u64(&response["resultset"][0][0]), Some(9007199254740993));
+    }
+
+    #[test]
+    fn test_lossy_text() {
+        let raw = r#"{"fields":[{"name":"id","type":3},{"name":"note","type":12}],"resultset":[[1,"caf\u00e9"],[2,"caf\ud800"],[3,"\ud83d\ude00 \\ud800"]]}"#;
+        let repaired = repair_lone_surrogates(raw);
+        assert!(repaired.contains("caf\\ufffd"));
+        // Valid pairs and escaped backslashes are left alone
+        assert!(repaired.contains(r#""\ud83d\ude00 \\ud800""#));
+
+        let response: Value = serde_json::from_str(&repaired).unwrap();
+        let err = normalize_response(&mut response.clone(), TextDecodePolicy::Error).unwrap_err();
+        assert_eq!(err.to_string(), "Row 1, column 'note' holds text that is not valid UTF-8");
+
+        let mut lossy = response;
+        normalize_response(&mut lossy, TextDecodePolicy::Lossy).unwrap();
+        assert_eq!(lossy["lossy_cells"], json!([{ "row": 1, "column": "note" }]));
+        assert!(matches!(repair_lone_surrogates(r#"{"a":"b"}"#), Cow::Borrowed(_)));
+    }
+
+    // Execute results as returned by the various host versions, with the result each must normalize to
+    const EXECUTE_RESULT_CORPUS: &[(&str, Option<&str>, Option<u64>)] = &[
+        ("UPDATE 3", Some("UPDATE 3"), Some(3)),
+        ("INSERT 0 2", Some("INSERT 0 2"), Some(2)),
+        ("CREATE TABLE", Some("CREATE TABLE"), None),
+        (r#""DELETE 4""#, Some("DELETE 4"), Some(4)),
+        (r#"{"command_tag":"UPDATE 5","rows_affected":5}"#, Some("UPDATE 5"), Some(5)),
+        (r#"{"rowsAffected":7}"#, None, Some(7)),
+        (r#"{"command":"DELETE 1"}"#, Some("DELETE 1"), Some(1)),
+        (r#""{\"rows_affected\":8}""#, None, Some(8)),
+        ("9", None, Some(9)),
+        ("", None, None),
+    ];
+
+    #[test]
+    fn test_execute_result_corpus() {
+        for (raw, command_tag, rows_affected) in EXECUTE_RESULT_CORPUS {
+            let result = normalize_execute_result(raw);
+            assert_eq!((result.command_tag.as_deref(), result.rows_affected), (*command_tag, *rows_affected), "normalizing {}", raw);
+        }
+        assert_eq!(serde_json::to_value(normalize_execute_result("UPDATE 3")).unwrap(), json!({ "command_tag": "UPDATE 3", "rows_affected": 3 }));
+    }
+
+    fn notice(severity: &str, message: &str) -> Notice {
+        Notice { severity: severity.to_string(), message: message.to_string() }
+    }
+
+    // Execute results carrying server notices, in the forms the host versions use
+    #[test]
+    fn test_execute_result_notices() {
+        let skipped = normalize_execute_result("NOTICE:  table \"t\" does not exist, skipping\nDROP TABLE");
+        assert_eq!(skipped.command_tag.as_deref(), Some("DROP TABLE"));
+        assert_eq!(skipped.notices, vec![notice("NOTICE", "table \"t\" does not exist, skipping")]);
+
+        let listed = normalize_execute_result(r#"{"command_tag":"CREATE TABLE","notices":[{"severity":"notice","message":"identifier \"a_very_long_name\" will be truncated to \"a_very\""}]}"#);
+        assert_eq!((listed.command_tag.as_deref(), listed.notices.len()), (Some("CREATE TABLE"), 1));
+        assert_eq!(listed.notices[0].severity, "NOTICE");
+
+        let warnings = normalize_execute_result(r#""{\"rowsAffected\":0,\"warnings\":[\"WARNING:  there is no transaction in progress\"],\"messages\":[{\"level\":\"INFO\",\"text\":\"done\"}]}""#);
+        assert_eq!(warnings.rows_affected, Some(0));
+        assert_eq!(warnings.notices, vec![notice("WARNING", "there is no transaction in progress"), notice("INFO", "done")]);
+
+        assert_eq!(normalize_execute_result("WARNING: nothing to do").notices.len(), 1);
+        assert_eq!(normalize_execute_result("WARNING: nothing to do").command_tag, None);
+    }
+
+    #[test]
+    fn test_response_notices() {
+        let mut response = json!({ "fields": [{ "name": "id" }], "resultset": [[1]], "warnings": ["WARNING:  there is already a transaction in progress"] });
+        normalize_response(&mut response, TextDecodePolicy::Lossy).unwrap();
+        assert_eq!(response.get("warnings"), None);
+        assert_eq!(response["notices"], json!([{ "severity": "WARNING", "message": "there is already a transaction in progress" }]));
+    }
+
+    #[test]
+    fn test_fatal_notice() {
+        let patterns = vec!["will be truncated".to_string()];
+        let truncated = [notice("NOTICE", "identifier \"x\" Will Be Truncated to \"y\"")];
+        let warning = [notice("WARNING", "there is no transaction in progress")];
+        let skipping = [notice("NOTICE", "relation \"t\" already exists, skipping")];
+        assert!(fatal_notice(&truncated, NoticePolicy::Warn, &patterns).is_some());
+        assert!(fatal_notice(&warning, NoticePolicy::Warn, &patterns).is_none());
+        assert!(fatal_notice(&warning, NoticePolicy::Fail, &patterns).is_some());
+        assert!(fatal_notice(&skipping, NoticePolicy::Fail, &patterns).is_none());
+        assert!(fatal_notice(&truncated, NoticePolicy::Warn, &[]).is_none());
+    }
+}
@@ -0,0 +1,480 @@
+use std::collections::BTreeMap;
+
+use crate::runtime::subtle::CryptoKey;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{audit, budget::ExecutionBudget, crypto::{ciphertext_version_sql, derive_integrity_key, CURRENT_CIPHERTEXT_VERSION}, database::{self, CiphertextMigration, Client, DatabaseError}, host::cell_as_u64, locks::JobLock, manifest::{EncryptedTable, EncryptionManifest, TableState}, notify, utils::{get_trusted_time, quote_ident}, views};
+
+// Primary keys of mismatching rows listed in a report, the counters keep the full picture
+const MAX_REPORTED_MISMATCHES: usize = 20;
+// Two-sided 95% normal quantile
+const Z_95: f64 = 1.96;
+
+fn default_sample_size() -> u64 {
+    100
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct QuickVerifyInput {
+    pub database_id: String,
+    pub table: String,
+    #[serde(default = "default_sample_size")]
+    pub sample_size: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QuickVerifyReport {
+    pub table: String,
+    pub total_rows: u64,
+    pub sampled: u64,
+    pub matched: u64,
+    pub mismatched: u64,
+    // Sampled rows written before the MAC column existed or outside the encrypted write paths
+    pub missing_mac: u64,
+    pub mismatched_primary_keys: Vec<Value>,
+    pub estimated_mismatch_rate: f64,
+    // Upper bound of the mismatch rate at 95% confidence
+    pub mismatch_rate_upper_bound: f64,
+    pub estimated_mismatched_rows: u64,
+    // Non-null cells of each encrypted column by ciphertext format version, over the whole table
+    pub ciphertext_versions: BTreeMap<String, BTreeMap<String, u64>>,
+}
+
+// Upper bound of a proportion at 95% confidence: rule of three when nothing was observed, Wilson score otherwise
+pub fn mismatch_rate_upper_bound(failures: u64, sampled: u64) -> f64 {
+    if sampled == 0 {
+        return 1.0;
+    }
+    let n = sampled as f64;
+    if failures == 0 {
+        return (3.0 / n).min(1.0);
+    }
+    let p = failures as f64 / n;
+    let z2 = Z_95 * Z_95;
+    let centre = p + z2 / (2.0 * n);
+    let margin = Z_95 * (p * (1.0 - p) / n + z2 / (4.0 * n * n)).sqrt();
+    ((centre + margin) / (1.0 + z2 / n)).min(1.0)
+}
+
+impl QuickVerifyReport {
+    pub fn new(table: &str, total_rows: u64) -> Self {
+        Self {
+            table: table.to_string(),
+            total_rows,
+            sampled: 0,
+            matched: 0,
+            mismatched: 0,
+            missing_mac: 0,
+            mismatched_primary_keys: Vec::new(),
+            estimated_mismatch_rate: 0.0,
+            mismatch_rate_upper_bound: 1.0,
+            estimated_mismatched_rows: 0,
+            ciphertext_versions: BTreeMap::new(),
+        }
+    }
+
+    pub fn add_mismatch(&mut self, primary_key: Value) {
+        self.mismatched += 1;
+        if self.mismatched_primary_keys.len() < MAX_REPORTED_MISMATCHES {
+            self.mismatched_primary_keys.push(primary_key);
+        }
+    }
+
+    // Extrapolates the sample to the whole table; rows without a MAC are not counted as evidence either way
+    pub fn finish(&mut self) {
+        let checked = self.matched + self.mismatched;
+        if checked > 0 {
+            self.estimated_mismatch_rate = self.mismatched as f64 / checked as f64;
+        }
+        self.mismatch_rate_upper_bound = mismatch_rate_upper_bound(self.mismatched, checked);
+        self.estimated_mismatched_rows = (self.estimated_mismatch_rate * self.total_rows as f64).round() as u64;
+    }
+}
+
+pub fn version_counts_query(table: &str, column: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let column = quote_ident(column)?;
+    Ok(format!("SELECT {} AS version, count(*) FROM {} WHERE {} IS NOT NULL GROUP BY 1", ciphertext_version_sql(&column), quote_ident(table)?, column))
+}
+
+pub fn parse_version_counts(rows: &[Vec<Value>]) -> BTreeMap<String, u64> {
+    rows.iter()
+        .filter_map(|row| Some((row.first()?.as_str()?.to_string(), row.get(1).and_then(cell_as_u64)?)))
+        .collect()
+}
+
+// Counted in SQL, no cell is decrypted
+pub(crate) fn ciphertext_version_counts(client: &Client, entry: &EncryptedTable) -> Result<BTreeMap<String, BTreeMap<String, u64>>, Box<dyn std::error::Error>> {
+    let mut counts = BTreeMap::new();
+    for column in entry.columns.iter() {
+        let response = client.query::<Vec<Vec<Value>>>(&version_counts_query(&entry.table, &column.name)?)?;
+        counts.insert(column.name.clone(), parse_version_counts(&response.resultset));
+    }
+    Ok(counts)
+}
+
+pub(crate) fn quick_verify(client: &Client, input: &QuickVerifyInput) -> Result<QuickVerifyReport, Box<dyn std::error::Error>> {
+    let manifest = EncryptionManifest::load(client.database_id())?;
+    let entry = manifest.table(&input.table).ok_or(format!("Table {} has no encrypted columns", input.table))?;
+    let mac_column = entry.row_mac_column.clone().ok_or(format!("Table {} has no row MAC column", input.table))?;
+
+    let count = client.query::<Vec<Vec<Value>>>(&format!("SELECT count(*) FROM {}", quote_ident(&input.table)?))?;
+    let total_rows = count.resultset.first().and_then(|r| r.first()).and_then(cell_as_u64).unwrap_or(0);
+    let mut report = QuickVerifyReport::new(&input.table, total_rows);
+    report.ciphertext_versions = ciphertext_version_counts(client, entry)?;
+
+    // Only the sampled rows are decrypted
+    let master_key = client.load_master_key()?;
+    let integrity_key = derive_integrity_key(&master_key, &input.table)?;
+    let suffix = format!("ORDER BY random() LIMIT {}", input.sample_size);
+    let rows = client.read_decrypted_rows(&master_key, &manifest, &input.table, &[mac_column.as_str()], &suffix)?;
+
+    for row in rows.iter().map(|r| &r.values) {
+        report.sampled += 1;
+        let stored = match row.get(&mac_column).and_then(|v| v.as_str()) {
+            Some(mac) => mac.to_string(),
+            None => {
+                report.missing_mac += 1;
+                continue;
+            }
+        };
+        if client.row_mac(&integrity_key, entry, row)? == stored {
+            report.matched += 1;
+        } else {
+            report.add_mismatch(entry.primary_key.value(row));
+        }
+    }
+    report.finish();
+    Ok(report)
+}
+
+pub fn quick_verify_table(cmd: String) {
+    let input: QuickVerifyInput = match notify::parse_input(&cmd) {
+        Some(input) => input,
+        None => return,
+    };
+    if input.sample_size == 0 {
+        notify::error("sample_size must be greater than 0");
+        return;
+    }
+    let mut client: database::Client = match database::Client::load(input.database_id.clone()) {
+        Ok(c) => c,
+        Err(err) => {
+            notify::error_with_code(&format!("Failed to load client: {}", err), err.code());
+            return;
+        }
+    };
+    match client.connect() {
+        Ok(_) => (),
+        Err(err) => {
+            notify::error_with_code(&format!("Failed to connect to client: {}", err), err.code());
+            return;
+        }
+    };
+
+    match quick_verify(&client, &input) {
+        Ok(report) => {
+            notify::result(&report);
+        },
+        Err(err) => {
+            notify::error_with_code(&format!("Failed to verify table {}: {}", input.table, err), DatabaseError::code_of(err.as_ref()));
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ReconcileInput {
+    pub database_id: String,
+    // Only reconcile this table, all Applying tables otherwise
+    #[serde(default)]
+    pub table: Option<String>,
+    #[serde(default = "default_sample_size")]
+    pub sample_size: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ColumnResolution {
+    // Every sampled value decrypted, the column is kept
+    Encrypted,
+    // No sampled value decrypted, the column was never rewritten and is dropped from the manifest
+    Plaintext,
+    // Both kinds were found, the table stays Applying until encryption is resumed
+    Mixed,
+}
+
+pub fn resolve_column(ciphertext: u64, plaintext: u64) -> ColumnResolution {
+    match (ciphertext, plaintext) {
+        (_, 0) => ColumnResolution::Encrypted,
+        (0, _) => ColumnResolution::Plaintext,
+        _ => ColumnResolution::Mixed,
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ColumnReconciliation {
+    pub column: String,
+    pub ciphertext: u64,
+    pub plaintext: u64,
+    pub resolution: ColumnResolution,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TableReconciliation {
+    pub table: String,
+    pub sampled: u64,
+    pub columns: Vec<ColumnReconciliation>,
+    // State left in the manifest, None when no encrypted column remained
+    pub state: Option<TableState>,
+}
+
+// Resolves a table stuck in Applying by sampling its rows and checking which columns hold ciphertext.
+fn reconcile_table(client: &Client, manifest: &mut EncryptionManifest, table: &str, sample_size: u64) -> Result<TableReconciliation, Box<dyn std::error::Error>> {
+    let master_key = client.load_master_key()?;
+    let suffix = format!("ORDER BY random() LIMIT {}", sample_size);
+    let rows = client.read_decrypted_rows(&master_key, manifest, table, &[], &suffix)?;
+    let entry = manifest.table(table).ok_or(format!("Table {} has no encrypted columns", table))?.clone();
+
+    let mut columns = Vec::new();
+    for column in entry.columns.iter() {
+        let mut ciphertext = 0;
+        let mut plaintext = 0;
+        for row in rows.iter() {
+            if row.plaintext_columns.contains(&column.name) {
+                plaintext += 1;
+            } else if row.values.get(&column.name).map(|v| !v.is_null()).unwrap_or(false) {
+                ciphertext += 1;
+            }
+        }
+        columns.push(ColumnReconciliation { column: column.name.clone(), ciphertext, plaintext, resolution: resolve_column(ciphertext, plaintext) });
+    }
+
+    for column in columns.iter().filter(|c| c.resolution == ColumnResolution::Plaintext) {
+        manifest.remove_column(table, &column.column);
+    }
+    let state = if manifest.table(table).is_none() {
+        None
+    } else if columns.iter().any(|c| c.resolution == ColumnResolution::Mixed) {
+        Some(TableState::Applying)
+    } else {
+        manifest.set_table_state(table, TableState::Applied, get_trusted_time())?;
+        Some(TableState::Applied)
+    };
+    Ok(TableReconciliation { table: table.to_string(), sampled: rows.len() as u64, columns, state })
+}
+
+pub fn reconcile_encryption_state(cmd: String) {
+    let input: ReconcileInput = match notify::parse_input(&cmd) {
+        Some(input) => input,
+        None => return,
+    };
+    if input.sample_size == 0 {
+        notify::error("sample_size must be greater than 0");
+        return;
+    }
+    let mut client: database::Client = match database::Client::load(input.database_id.clone()) {
+        Ok(c) => c,
+        Err(err) => {
+            notify::error_with_code(&format!("Failed to load client: {}", err), err.code());
+            return;
+        }
+    };
+    match client.connect() {
+        Ok(_) => (),
+        Err(err) => {
+            notify::error_with_code(&format!("Failed to connect to client: {}", err), err.code());
+            return;
+        }
+    };
+    let mut manifest = match EncryptionManifest::load(&input.database_id) {
+        Ok(m) => m,
+        Err(err) => {
+            notify::error_with_code(&format!("Failed to load encryption manifest: {}", err), DatabaseError::code_of(err.as_ref()));
+            return;
+        }
+    };
+
+    let tables: Vec<String> = manifest.tables.iter()
+        .filter(|t| t.state == TableState::Applying && input.table.as_ref().map(|name| name == &t.table).unwrap_or(true))
+        .map(|t| t.table.clone())
+        .collect();
+    let mut reconciled = Vec::new();
+    for table in tables.iter() {
+        match reconcile_table(&client, &mut manifest, table, input.sample_size) {
+            Ok(result) => reconciled.push(result),
+            Err(err) => {
+                notify::error_with_code(&format!("Failed to reconcile table {}: {}", table, err), DatabaseError::code_of(err.as_ref()));
+                return;
+            }
+        }
+    }
+    // Views built on a table that was dropped since
+    match views::detect_broken_views(&client, &mut manifest) {
+        Ok(broken) => {
+            for view in broken {
+                notify::warning(&format!("View {} is broken, its source table no longer exists", view));
+            }
+        },
+        Err(err) => {
+            notify::error_with_code(&format!("Failed to check views: {}", err), DatabaseError::code_of(err.as_ref()));
+            return;
+        }
+    }
+    if let Err(err) = manifest.save() {
+        notify::error_with_code(&format!("Failed to save encryption manifest: {}", err), DatabaseError::code_of(err.as_ref()));
+        return;
+    }
+    audit::record("reconcile_encryption_state", Some(&input.database_id), "ok", serde_json::to_value(&reconciled).unwrap_or(Value::Null));
+    notify::result(&reconciled);
+}
+
+fn default_migration_chunk_size() -> usize {
+    100
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MigrateVersionsInput {
+    pub database_id: String,
+    pub table: String,
+    #[serde(default = "default_migration_chunk_size")]
+    pub chunk_size: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct VersionMigrationReport {
+    pub table: String,
+    pub target_version: String,
+    // Columns worked on by this call, the last one stopped early when the budget ran low
+    pub columns: Vec<CiphertextMigration>,
+    pub complete: bool,
+    pub ciphertext_versions: BTreeMap<String, BTreeMap<String, u64>>,
+}
+
+fn migrate_columns(client: &Client, master_key: &CryptoKey, manifest: &EncryptionManifest, entry: &EncryptedTable, chunk_size: usize, budget: &mut ExecutionBudget, lock: &mut JobLock) -> Result<Vec<CiphertextMigration>, Box<dyn std::error::Error>> {
+    let mut columns = Vec::new();
+    for column in entry.columns.iter() {
+        let cipher = client.column_cipher(master_key, manifest, &entry.table, &column.name)?;
+        let migration = client.migrate_ciphertext_column(&cipher, entry, &column.name, chunk_size, budget, lock)?;
+        let complete = migration.complete;
+        columns.push(migration);
+        if !complete {
+            break;
+        }
+    }
+    Ok(columns)
+}
+
+pub(crate) fn migrate_versions(client: &Client, input: &MigrateVersionsInput, budget: &mut ExecutionBudget) -> Result<VersionMigrationReport, Box<dyn std::error::Error>> {
+    let manifest = EncryptionManifest::load(client.database_id())?;
+    let entry = manifest.table(&input.table).ok_or(format!("Table {} has no encrypted columns", input.table))?;
+    // Plaintext cells of an unfinished run could pass for a version prefix
+    if entry.state != TableState::Applied {
+        return Err(format!("Table {} is still being encrypted, finish that run first", input.table).into());
+    }
+    let master_key = client.load_write_key()?;
+    let mut lock = JobLock::acquire(client.database_id(), &input.table)?;
+    let columns = migrate_columns(client, &master_key, &manifest, entry, input.chunk_size, budget, &mut lock);
+    lock.release();
+    let columns = columns?;
+    let complete = columns.len() == entry.columns.len() && columns.iter().all(|c| c.complete);
+    Ok(VersionMigrationReport {
+        table: input.table.clone(),
+        target_version: CURRENT_CIPHERTEXT_VERSION.label(),
+        columns,
+        complete,
+        ciphertext_versions: ciphertext_version_counts(client, entry)?,
+    })
+}
+
+// Rewrites cells of older ciphertext formats in the current one. Stops when the execution budget runs low,
+// calling again resumes with the cells left.
+pub fn migrate_ciphertext_versions(cmd: String) {
+    let input: MigrateVersionsInput = match notify::parse_input(&cmd) {
+        Some(input) => input,
+        None => return,
+    };
+    let mut client: database::Client = match database::Client::load(input.database_id.clone()) {
+        Ok(c) => c,
+        Err(err) => {
+            notify::error_with_code(&format!("Failed to load client: {}", err), err.code());
+            return;
+        }
+    };
+    match client.connect() {
+        Ok(_) => (),
+        Err(err) => {
+            notify::error_with_code(&format!("Failed to connect to client: {}", err), err.code());
+            return;
+        }
+    };
+    let mut budget = match ExecutionBudget::from_settings() {
+        Ok(b) => b,
+        Err(err) => {
+            notify::error_with_code(&format!("Failed to load settings: {}", err), DatabaseError::code_of(err.as_ref()));
+            return;
+        }
+    };
+
+    match migrate_versions(&client, &input, &mut budget) {
+        Ok(report) => {
+            let migrated: u64 = report.columns.iter().map(|c| c.migrated).sum();
+            audit::record("migrate_ciphertext_versions", Some(&input.database_id), if report.complete { "complete" } else { "partial" },
+                serde_json::json!({ "table": input.table, "migrated": migrated, "target_version": report.target_version }));
+            notify::result(&report);
+        },
+        Err(err) => {
+            audit::record("migrate_ciphertext_versions", Some(&input.database_id), "failure", serde_json::json!({ "table": input.table }));
+            notify::error_with_code(&format!("Failed to migrate ciphertext versions of table {}: {}", input.table, err), DatabaseError::code_of(err.as_ref()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_mismatch_rate_upper_bound() {
+        assert_eq!(mismatch_rate_upper_bound(0, 0), 1.0);
+        assert!((mismatch_rate_upper_bound(0, 100) - 0.03).abs() < 1e-9);
+        let bound = mismatch_rate_upper_bound(5, 100);
+        assert!(bound > 0.05 && bound < 0.12);
+        assert_eq!(mismatch_rate_upper_bound(10, 10), 1.0);
+    }
+
+    #[test]
+    fn test_version_counts() {
+        assert_eq!(version_counts_query("users", "email").unwrap(),
+            r#"SELECT CASE WHEN "email"::text ~ '^v[0-9]+:' THEN split_part("email"::text, ':', 1) ELSE 'v0' END AS version, count(*) FROM "users" WHERE "email" IS NOT NULL GROUP BY 1"#);
+        let counts = parse_version_counts(&[vec![json!("v0"), json!("40")], vec![json!("v1"), json!(2)], vec![Value::Null, json!(1)]]);
+        assert_eq!(counts, BTreeMap::from([("v0".to_string(), 40), ("v1".to_string(), 2)]));
+    }
+
+    #[test]
+    fn test_resolve_column() {
+        assert_eq!(resolve_column(10, 0), ColumnResolution::Encrypted);
+        assert_eq!(resolve_column(0, 0), ColumnResolution::Encrypted);
+        assert_eq!(resolve_column(0, 10), ColumnResolution::Plaintext);
+        assert_eq!(resolve_column(4, 6), ColumnResolution::Mixed);
+    }
+
+    #[test]
+    fn test_report_extrapolation() {
+        let mut report = QuickVerifyReport::new("users", 10_000);
+        for i in 0..98 {
+            report.sampled += 1;
+            if i < 96 { report.matched += 1; } else { report.add_mismatch(json!(i)); }
+        }
+        report.sampled += 2;
+        report.missing_mac += 2;
+        report.finish();
+        assert_eq!(report.mismatched_primary_keys, vec![json!(96), json!(97)]);
+        assert_eq!(report.estimated_mismatched_rows, 204);
+        assert!(report.mismatch_rate_upper_bound > report.estimated_mismatch_rate);
+    }
+}
@@ -0,0 +1,396 @@
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{
+    audit,
+    constraints::UniqueConstraint,
+    database::{Client, DatabaseError},
+    locks,
+    manifest::{EncryptionManifest, TableState},
+    notify,
+    settings::require_admin,
+    storage,
+    utils::{get_client_id, get_trusted_time, ledger_key},
+};
+
+pub(crate) const INTENT_LOG_TABLE: &str = "IntentLogTable";
+
+// Operation made of several statements and ledger writes that cannot share a transaction. Each step
+// is recorded once done, an operation interrupted in between is left as a dangling intent.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Operation {
+    // Drops unique constraints already recorded as relaxed in the manifest
+    RelaxConstraints { table: String, constraints: Vec<UniqueConstraint> },
+    // Computes the row MACs of a table whose columns are all encrypted, then marks it Applied
+    FinishEncryption { table: String, chunk_size: usize },
+}
+
+impl Operation {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Operation::RelaxConstraints { .. } => "relax_constraints",
+            Operation::FinishEncryption { .. } => "finish_encryption",
+        }
+    }
+
+    pub fn table(&self) -> &str {
+        match self {
+            Operation::RelaxConstraints { table, .. } => table,
+            Operation::FinishEncryption { table, .. } => table,
+        }
+    }
+
+    pub fn steps(&self) -> Vec<String> {
+        match self {
+            Operation::RelaxConstraints { constraints, .. } => constraints.iter().map(|c| drop_step(&c.name)).collect(),
+            Operation::FinishEncryption { .. } => vec![ROW_MACS_STEP.to_string(), MANIFEST_STEP.to_string()],
+        }
+    }
+}
+
+const ROW_MACS_STEP: &str = "row_macs";
+const MANIFEST_STEP: &str = "manifest";
+
+fn drop_step(constraint: &str) -> String {
+    format!("drop:{}", constraint)
+}
+
+fn restore_step(constraint: &str) -> String {
+    format!("restore:{}", constraint)
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Intent {
+    pub database_id: String,
+    pub operation: Operation,
+    #[serde(default)]
+    pub completed: Vec<String>,
+    pub owner: String,
+    pub started_at: u64,
+    pub updated_at: u64,
+}
+
+impl Intent {
+    pub fn new(database_id: &str, operation: Operation, owner: &str, now: u64) -> Intent {
+        Intent { database_id: database_id.to_string(), operation, completed: Vec::new(), owner: owner.to_string(), started_at: now, updated_at: now }
+    }
+
+    // One intent per operation kind and table, so that retrying an operation resumes its dangling intent
+    pub fn key(&self) -> String {
+        ledger_key(&[&self.database_id, self.operation.kind(), self.operation.table()])
+    }
+
+    pub fn is_completed(&self, step: &str) -> bool {
+        self.completed.iter().any(|s| s == step)
+    }
+
+    pub fn pending_steps(&self) -> Vec<String> {
+        self.operation.steps().into_iter().filter(|s| !self.is_completed(s)).collect()
+    }
+
+    pub fn mark_completed(&mut self, step: &str, now: u64) {
+        if !self.is_completed(step) {
+            self.completed.push(step.to_string());
+        }
+        self.updated_at = now;
+    }
+
+    fn save(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let serialized = serde_json::to_string(self)?;
+        storage::ledger_set(INTENT_LOG_TABLE, &self.key(), serialized.as_bytes())
+    }
+
+    // Records the intent before its first step runs. A dangling intent of the same operation is taken
+    // over with the steps it completed; one of a different operation on the table is replaced.
+    pub fn begin(database_id: &str, operation: Operation) -> Result<Intent, Box<dyn std::error::Error>> {
+        let mut intent = Intent::new(database_id, operation, &get_client_id(), get_trusted_time());
+        let existing = crate::runtime::ledger::get_table(INTENT_LOG_TABLE).get(&intent.key()).ok()
+            .and_then(|v| serde_json::from_slice::<Intent>(&v).ok());
+        if let Some(existing) = existing.filter(|e| e.operation == intent.operation) {
+            intent.completed = existing.completed;
+            intent.started_at = existing.started_at;
+        }
+        storage::check_quota(serde_json::to_string(&intent)?.len() as u64)?;
+        intent.save()?;
+        Ok(intent)
+    }
+
+    // Runs the step unless an earlier attempt completed it, then records it
+    pub fn step<F>(&mut self, step: &str, run: F) -> Result<(), Box<dyn std::error::Error>>
+    where
+        F: FnOnce() -> Result<(), Box<dyn std::error::Error>>,
+    {
+        if self.is_completed(step) {
+            return Ok(());
+        }
+        run()?;
+        self.mark_completed(step, get_trusted_time());
+        self.save()
+    }
+
+    pub fn finish(self) -> Result<(), Box<dyn std::error::Error>> {
+        storage::ledger_remove(INTENT_LOG_TABLE, &self.key())
+    }
+}
+
+pub(crate) fn load_all() -> Result<Vec<Intent>, Box<dyn std::error::Error>> {
+    let ledger = crate::runtime::ledger::get_table(INTENT_LOG_TABLE);
+    let mut intents = Vec::new();
+    for key in ledger.list_keys()? {
+        if let Ok(intent) = serde_json::from_slice::<Intent>(&ledger.get(&key)?) {
+            intents.push(intent);
+        }
+    }
+    Ok(intents)
+}
+
+// Runs the steps left. A crash between a step and its record runs the step again, so every step can be repeated.
+pub(crate) fn run(client: &Client, intent: &mut Intent) -> Result<(), Box<dyn std::error::Error>> {
+    match intent.operation.clone() {
+        Operation::RelaxConstraints { table, constraints } => {
+            for constraint in constraints.iter() {
+                intent.step(&drop_step(&constraint.name), || client.execute_ddl(&constraint.drop_statement(&table)?).map(|_| ()))?;
+            }
+        },
+        Operation::FinishEncryption { table, chunk_size } => {
+            // The row MAC covers every encrypted column of the table, so it is recomputed once all columns are done
+            intent.step(ROW_MACS_STEP, || {
+                client.refresh_row_macs(&table, chunk_size).map_err(|err| {
+                    notify::warning(&format!("Failed to compute row MACs of table {}: {}", table, err));
+                    err
+                })
+            })?;
+            intent.step(MANIFEST_STEP, || {
+                EncryptionManifest::update(client.database_id(), |manifest| {
+                    manifest.set_watermark(&table, None)?;
+                    manifest.set_partitions(&table, Vec::new())?;
+                    manifest.set_table_state(&table, TableState::Applied, get_trusted_time())
+                }).map(|_| ())
+            })?;
+        },
+    }
+    Ok(())
+}
+
+// Begins the operation, runs all its steps and clears the intent. A failure leaves the intent for the
+// next attempt or recover_incomplete_operations.
+pub(crate) fn perform(client: &Client, operation: Operation) -> Result<(), Box<dyn std::error::Error>> {
+    let mut intent = Intent::begin(client.database_id(), operation)?;
+    run(client, &mut intent)?;
+    intent.finish()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum Recovery {
+    // Run the steps left
+    Complete,
+    // Create again the constraints dropped before the operation was abandoned
+    RollBack { restore: Vec<UniqueConstraint> },
+    // Nothing left to do or undo
+    Discard,
+}
+
+impl Recovery {
+    pub fn outcome(&self) -> &'static str {
+        match self {
+            Recovery::Complete => "completed",
+            Recovery::RollBack { .. } => "rolled_back",
+            Recovery::Discard => "discarded",
+        }
+    }
+}
+
+// Decides from the manifest whether a dangling intent is still wanted
+pub fn plan_recovery(intent: &Intent, manifest: &EncryptionManifest) -> Recovery {
+    match &intent.operation {
+        Operation::RelaxConstraints { table, constraints } => {
+            // The manifest keeps the definitions while the table is encrypted, writes are checked against them
+            let recorded = manifest.table(table)
+                .map(|t| constraints.iter().all(|c| t.relaxed_constraints.iter().any(|r| r.name == c.name)))
+                .unwrap_or(false);
+            if recorded {
+                return Recovery::Complete;
+            }
+            let restore: Vec<UniqueConstraint> = constraints.iter().filter(|c| intent.is_completed(&drop_step(&c.name))).cloned().collect();
+            if restore.is_empty() { Recovery::Discard } else { Recovery::RollBack { restore } }
+        },
+        Operation::FinishEncryption { table, .. } => match manifest.table(table) {
+            Some(entry) if entry.state == TableState::Applying => Recovery::Complete,
+            _ => Recovery::Discard,
+        },
+    }
+}
+
+fn recover(mut intent: Intent) -> Result<Recovery, Box<dyn std::error::Error>> {
+    let manifest = EncryptionManifest::load(&intent.database_id)?;
+    let recovery = plan_recovery(&intent, &manifest);
+    if recovery != Recovery::Discard {
+        let mut client = Client::load(intent.database_id.clone())?;
+        client.connect()?;
+        match &recovery {
+            Recovery::Complete => run(&client, &mut intent)?,
+            Recovery::RollBack { restore } => {
+                let table = intent.operation.table().to_string();
+                for constraint in restore.iter() {
+                    intent.step(&restore_step(&constraint.name), || client.execute_ddl(&constraint.create_statement(&table)?).map(|_| ()))?;
+                }
+            },
+            Recovery::Discard => (),
+        }
+    }
+    intent.finish()?;
+    Ok(recovery)
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RecoverIncompleteOperationsInput {
+    // All databases when not given
+    #[serde(default)]
+    pub database_id: Option<String>,
+    // Also recovers intents updated within job_stale_after_seconds, whose operation may still be running
+    #[serde(default)]
+    pub force: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RecoveredIntent {
+    pub database_id: String,
+    pub kind: String,
+    pub table: String,
+    pub completed: Vec<String>,
+    pub recovery: Option<Recovery>,
+    pub error: Option<String>,
+}
+
+// Maintenance: completes or rolls back the operations left dangling by an interrupted call
+pub fn recover_incomplete_operations(cmd: String) {
+    let input: RecoverIncompleteOperationsInput = match notify::parse_input(&cmd) {
+        Some(input) => input,
+        None => return,
+    };
+    if let Err(err) = require_admin("recover incomplete operations") {
+        notify::error(&err);
+        return;
+    }
+    let intents = match load_all() {
+        Ok(intents) => intents,
+        Err(err) => {
+            notify::error_with_code(&format!("Failed to load the intent log: {}", err), DatabaseError::code_of(err.as_ref()));
+            return;
+        }
+    };
+
+    let now = get_trusted_time();
+    let stale_after = locks::stale_after();
+    let mut recovered = Vec::new();
+    let mut skipped = 0;
+    for intent in intents.into_iter().filter(|i| input.database_id.as_ref().map(|d| *d == i.database_id).unwrap_or(true)) {
+        if !input.force && now.saturating_sub(intent.updated_at) <= stale_after {
+            skipped += 1;
+            continue;
+        }
+        let mut entry = RecoveredIntent {
+            database_id: intent.database_id.clone(),
+            kind: intent.operation.kind().to_string(),
+            table: intent.operation.table().to_string(),
+            completed: intent.completed.clone(),
+            recovery: None,
+            error: None,
+        };
+        let outcome = match recover(intent) {
+            Ok(recovery) => {
+                let outcome = recovery.outcome();
+                entry.recovery = Some(recovery);
+                outcome
+            },
+            Err(err) => {
+                entry.error = Some(err.to_string());
+                "failure"
+            }
+        };
+        audit::record("recover_incomplete_operations", Some(&entry.database_id), outcome, json!({ "kind": entry.kind, "table": entry.table }));
+        recovered.push(entry);
+    }
+    notify::result(&json!({ "recovered": recovered, "skipped_recent": skipped }));
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{constraints::ConstraintKind, manifest::EncryptedColumn};
+
+    use super::*;
+
+    fn constraint(name: &str) -> UniqueConstraint {
+        UniqueConstraint { name: name.to_string(), kind: ConstraintKind::Constraint, columns: vec!["email".to_string()], definition: "UNIQUE (email)".to_string() }
+    }
+
+    fn relax(constraints: &[&str]) -> Intent {
+        let operation = Operation::RelaxConstraints { table: "users".to_string(), constraints: constraints.iter().map(|c| constraint(c)).collect() };
+        Intent::new("db", operation, "alice", 10)
+    }
+
+    fn applying(relaxed: &[&str]) -> EncryptionManifest {
+        let mut manifest = EncryptionManifest::new("db");
+        manifest.record_column("users", "id", EncryptedColumn::new("email", None), 0);
+        let constraints: Vec<UniqueConstraint> = relaxed.iter().map(|c| constraint(c)).collect();
+        manifest.record_relaxed_constraints("users", &constraints).unwrap();
+        manifest.set_table_state("users", TableState::Applying, 0).unwrap();
+        manifest
+    }
+
+    #[test]
+    fn test_steps() {
+        let mut intent = relax(&["users_email_key", "users_login_key"]);
+        assert_eq!(intent.pending_steps(), vec!["drop:users_email_key", "drop:users_login_key"]);
+        intent.mark_completed("drop:users_email_key", 20);
+        intent.mark_completed("drop:users_email_key", 30);
+        assert_eq!(intent.pending_steps(), vec!["drop:users_login_key"]);
+        assert_eq!((intent.completed.len(), intent.updated_at), (1, 30));
+
+        let serialized = serde_json::to_value(&intent).unwrap();
+        assert_eq!(serialized["operation"]["kind"], "relax_constraints");
+        assert_eq!(serde_json::from_value::<Intent>(serialized).unwrap(), intent);
+        // Retries of an operation share the record of its intent
+        assert_eq!(intent.key(), relax(&["users_email_key"]).key());
+        let finish = Intent::new("db", Operation::FinishEncryption { table: "users".to_string(), chunk_size: 100 }, "alice", 10);
+        assert_ne!(intent.key(), finish.key());
+    }
+
+    #[test]
+    fn test_recovery_of_relaxed_constraints() {
+        let manifest = applying(&["users_email_key", "users_login_key"]);
+        // Crash before the first drop, between the drops and before the intent was cleared
+        let mut intent = relax(&["users_email_key", "users_login_key"]);
+        assert_eq!(plan_recovery(&intent, &manifest), Recovery::Complete);
+        intent.mark_completed("drop:users_email_key", 20);
+        assert_eq!(plan_recovery(&intent, &manifest), Recovery::Complete);
+        intent.mark_completed("drop:users_login_key", 30);
+        assert_eq!(plan_recovery(&intent, &manifest), Recovery::Complete);
+        assert!(intent.pending_steps().is_empty());
+
+        // Encryption reverted since: the dropped constraints are created again
+        let mut intent = relax(&["users_email_key", "users_login_key"]);
+        intent.mark_completed("drop:users_email_key", 20);
+        let reverted = EncryptionManifest::new("db");
+        assert_eq!(plan_recovery(&intent, &reverted), Recovery::RollBack { restore: vec![constraint("users_email_key")] });
+        assert_eq!(plan_recovery(&relax(&["users_email_key"]), &reverted), Recovery::Discard);
+    }
+
+    #[test]
+    fn test_recovery_of_finished_encryption() {
+        let mut manifest = applying(&[]);
+        let mut intent = Intent::new("db", Operation::FinishEncryption { table: "users".to_string(), chunk_size: 100 }, "alice", 10);
+        assert_eq!(plan_recovery(&intent, &manifest), Recovery::Complete);
+        // Crash after the row MACs: only the manifest step is left
+        intent.mark_completed(ROW_MACS_STEP, 20);
+        assert_eq!(plan_recovery(&intent, &manifest), Recovery::Complete);
+        assert_eq!(intent.pending_steps(), vec![MANIFEST_STEP]);
+        // Crash after the manifest step, before the intent was cleared
+        manifest.set_table_state("users", TableState::Applied, 30).unwrap();
+        assert_eq!(plan_recovery(&intent, &manifest), Recovery::Discard);
+        assert_eq!(plan_recovery(&intent, &EncryptionManifest::new("db")), Recovery::Discard);
+    }
+}
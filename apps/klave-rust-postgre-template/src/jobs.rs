@@ -0,0 +1,275 @@
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{
+    audit,
+    database::{Clients, DatabaseError},
+    locks::{self, JobLock, LockDecision, JOB_LOCK_TABLE},
+    manifest::{EncryptedTable, EncryptionManifest, EncryptionWatermark, TableState},
+    notify,
+    partitions::PartitionStatus,
+    settings::DeploymentSettings,
+    storage,
+    utils::{get_client_id, get_trusted_time},
+};
+
+// Calls made to the encryption run of a table. Every call replaces it, so a stalled run resumed by
+// another caller belongs to that caller from then on.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct JobActivity {
+    pub owner: String,
+    pub heartbeat_at: u64,
+    #[serde(default)]
+    pub failure: Option<String>,
+    #[serde(default)]
+    pub stalled: Option<StalledRun>,
+}
+
+// How far a run got when it stopped receiving calls; execute_table_encryption resumes from there
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StalledRun {
+    pub stalled_at: u64,
+    pub last_heartbeat_at: u64,
+    pub watermark: Option<EncryptionWatermark>,
+    pub partitions_done: usize,
+    pub partitions_total: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    Running,
+    // Abandoned by its caller and reaped, resumable
+    Stalled,
+    // Stopped on an error, resumable once the cause is fixed
+    Failed,
+    Complete,
+}
+
+pub fn job_status(table: &EncryptedTable) -> JobStatus {
+    if table.state == TableState::Applied {
+        return JobStatus::Complete;
+    }
+    if let Some(job) = table.job.as_ref() {
+        if job.stalled.is_some() {
+            return JobStatus::Stalled;
+        }
+        if job.failure.is_some() {
+            return JobStatus::Failed;
+        }
+    }
+    // A partitioned run has failed once only failed partitions are left
+    let unfinished = table.partitions.iter().filter(|p| p.status != PartitionStatus::Done);
+    let mut failed = false;
+    for partition in unfinished {
+        if partition.status != PartitionStatus::Failed {
+            return JobStatus::Running;
+        }
+        failed = true;
+    }
+    if failed { JobStatus::Failed } else { JobStatus::Running }
+}
+
+// Runs recorded before heartbeats existed count from their last manifest change
+pub fn last_heartbeat(table: &EncryptedTable) -> u64 {
+    table.job.as_ref().map(|j| j.heartbeat_at).unwrap_or(table.updated_at)
+}
+
+// Marks the run Stalled when no call came for longer than stale_after and no job holds a live lock on it
+pub fn stall_if_stale(table: &mut EncryptedTable, now: u64, stale_after: u64, live_lock: bool) -> bool {
+    if live_lock || job_status(table) != JobStatus::Running || now.saturating_sub(last_heartbeat(table)) <= stale_after {
+        return false;
+    }
+    let stalled = StalledRun {
+        stalled_at: now,
+        last_heartbeat_at: last_heartbeat(table),
+        watermark: table.watermark.clone(),
+        partitions_done: table.partitions.iter().filter(|p| p.status == PartitionStatus::Done).count(),
+        partitions_total: table.partitions.len(),
+    };
+    let owner = table.job.as_ref().map(|j| j.owner.clone()).unwrap_or_default();
+    table.job = Some(JobActivity { owner, heartbeat_at: stalled.last_heartbeat_at, failure: None, stalled: Some(stalled) });
+    true
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ReapedJob {
+    pub database_id: String,
+    pub table: String,
+    pub owner: String,
+    pub stalled: StalledRun,
+    // Jobs whose abandoned locks on the table were released
+    pub released_locks: Vec<String>,
+}
+
+// Stalls the abandoned runs of a database, only those of `owner` when given, and releases their locks
+pub(crate) fn reap_database(database_id: &str, owner: Option<&str>, now: u64, stale_after: u64) -> Result<Vec<ReapedJob>, Box<dyn std::error::Error>> {
+    let table_locks: Vec<(String, JobLock)> = locks::load_all()?.into_iter().filter(|(_, l)| l.database_id == database_id).collect();
+    let live = |table: &str| table_locks.iter().any(|(_, l)| l.table == table && matches!(locks::decide(Some(l), now, stale_after), LockDecision::Held(_)));
+    let mut reaped = Vec::new();
+    EncryptionManifest::update(database_id, |manifest| {
+        // Run again on a manifest conflict
+        reaped.clear();
+        for table in manifest.tables.iter_mut() {
+            let owned = owner.map(|o| table.job.as_ref().map(|j| j.owner == o).unwrap_or(false)).unwrap_or(true);
+            if owned && stall_if_stale(table, now, stale_after, live(&table.table)) {
+                let job = table.job.clone().ok_or("stalled run without activity")?;
+                reaped.push(ReapedJob {
+                    database_id: database_id.to_string(),
+                    table: table.table.clone(),
+                    owner: job.owner,
+                    stalled: job.stalled.ok_or("stalled run without record")?,
+                    released_locks: Vec::new(),
+                });
+            }
+        }
+        Ok(())
+    })?;
+    for job in reaped.iter_mut() {
+        for (key, lock) in table_locks.iter().filter(|(_, l)| l.table == job.table) {
+            storage::ledger_remove(JOB_LOCK_TABLE, key)?;
+            job.released_locks.push(lock.job_id.clone());
+        }
+    }
+    Ok(reaped)
+}
+
+// Piggybacked on the submission of a new encryption run; a failure only warns
+#[cfg(feature = "component")]
+pub(crate) fn reap_on_submission(database_id: &str, table: &str) {
+    let manifest = match EncryptionManifest::load(database_id) {
+        Ok(m) => m,
+        Err(_) => return,
+    };
+    // Resuming calls heartbeat the run, only a new submission pays for the sweep
+    if manifest.is_applying(table) || !manifest.tables.iter().any(|t| t.state == TableState::Applying) {
+        return;
+    }
+    match reap_database(database_id, None, get_trusted_time(), locks::stale_after()) {
+        Ok(reaped) => {
+            for job in reaped {
+                notify::warning(&format!("Encryption job on table {} stalled, resume it with execute_table_encryption", job.table));
+                audit::record("reap_stale_jobs", Some(database_id), "stalled", json!({ "table": job.table, "owner": job.owner }));
+            }
+        },
+        Err(err) => {
+            notify::warning(&format!("Failed to reap stale jobs: {}", err));
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ReapStaleJobsInput {
+    // All databases when not given
+    #[serde(default)]
+    pub database_id: Option<String>,
+}
+
+// Maintenance: abandoned encryption runs become Stalled and their locks are released. The admin reaps
+// every run, other callers only the runs they made the last call to.
+pub fn reap_stale_jobs(cmd: String) {
+    let input: ReapStaleJobsInput = match notify::parse_input(&cmd) {
+        Some(input) => input,
+        None => return,
+    };
+    let caller = get_client_id();
+    let settings = match DeploymentSettings::load() {
+        Ok(s) => s,
+        Err(err) => {
+            notify::error_with_code(&format!("Failed to load settings: {}", err), DatabaseError::code_of(err.as_ref()));
+            return;
+        }
+    };
+    let owner = if settings.is_admin(&caller) { None } else { Some(caller.as_str()) };
+    let database_ids = match input.database_id.clone() {
+        Some(database_id) => vec![database_id],
+        None => match if owner.is_none() { Clients::load_all() } else { Clients::load() }.and_then(|c| c.list()) {
+            Ok(clients) => clients.iter().map(|c| c.database_id().to_string()).collect(),
+            Err(err) => {
+                notify::error_with_code(&format!("Failed to load clients: {}", err), err.code());
+                return;
+            }
+        },
+    };
+
+    let now = get_trusted_time();
+    let stale_after = locks::stale_after();
+    let mut reaped = Vec::new();
+    for database_id in database_ids.iter() {
+        match reap_database(database_id, owner, now, stale_after) {
+            Ok(jobs) => reaped.extend(jobs),
+            Err(err) => {
+                audit::record("reap_stale_jobs", Some(database_id), "failure", json!({}));
+                notify::error_with_code(&format!("Failed to reap stale jobs of database {}: {}", database_id, err), DatabaseError::code_of(err.as_ref()));
+                return;
+            }
+        }
+    }
+    for job in reaped.iter() {
+        audit::record("reap_stale_jobs", Some(&job.database_id), "stalled", json!({ "table": job.table, "owner": job.owner, "released_locks": job.released_locks }));
+    }
+    notify::result(&json!({ "reaped": reaped, "all_owners": owner.is_none() }));
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::{manifest::EncryptedColumn, partitions::plan_partitions};
+
+    use super::*;
+
+    const STALE_AFTER: u64 = 100;
+
+    fn applying(manifest: &mut EncryptionManifest, owner: &str, at: u64) {
+        manifest.record_column("users", "id", EncryptedColumn::new("email", None), 0);
+        manifest.set_table_state("users", TableState::Applying, at).unwrap();
+        manifest.record_heartbeat("users", owner, at).unwrap();
+    }
+
+    #[test]
+    fn test_job_stalls_and_is_resumed_by_another_caller() {
+        let mut manifest = EncryptionManifest::new("db");
+        applying(&mut manifest, "alice", 10);
+        let watermark = EncryptionWatermark { completed_columns: vec![], column: "email".to_string(), after_primary_key: Some(json!(42)) };
+        manifest.set_watermark("users", Some(watermark.clone())).unwrap();
+
+        let table = manifest.tables.iter_mut().find(|t| t.table == "users").unwrap();
+        assert!(!stall_if_stale(table, 10 + STALE_AFTER, STALE_AFTER, false));
+        assert!(!stall_if_stale(table, 20 + STALE_AFTER, STALE_AFTER, true));
+        assert!(stall_if_stale(table, 20 + STALE_AFTER, STALE_AFTER, false));
+        assert_eq!(job_status(table), JobStatus::Stalled);
+        let stalled = table.job.as_ref().unwrap().stalled.clone().unwrap();
+        assert_eq!((stalled.last_heartbeat_at, stalled.watermark), (10, Some(watermark.clone())));
+        // Reaping twice changes nothing
+        assert!(!stall_if_stale(table, 500, STALE_AFTER, false));
+
+        manifest.record_heartbeat("users", "bob", 600).unwrap();
+        let table = manifest.table("users").unwrap();
+        assert_eq!(job_status(table), JobStatus::Running);
+        assert_eq!(table.job.as_ref().unwrap().owner, "bob");
+        assert_eq!(manifest.watermark("users"), Some(&watermark));
+    }
+
+    #[test]
+    fn test_job_status() {
+        let mut manifest = EncryptionManifest::new("db");
+        applying(&mut manifest, "alice", 10);
+        manifest.record_failure("users", "connection reset").unwrap();
+        assert_eq!(job_status(manifest.table("users").unwrap()), JobStatus::Failed);
+        // A failed run is not reaped as stalled
+        let table = manifest.tables.iter_mut().find(|t| t.table == "users").unwrap();
+        assert!(!stall_if_stale(table, 1_000, STALE_AFTER, false));
+
+        manifest.record_heartbeat("users", "alice", 20).unwrap();
+        let mut partitions = plan_partitions(&[json!(1), json!(9), json!(5)], 0);
+        partitions[0].status = PartitionStatus::Done;
+        partitions[1].status = PartitionStatus::Failed;
+        manifest.set_partitions("users", partitions).unwrap();
+        assert_eq!(job_status(manifest.table("users").unwrap()), JobStatus::Failed);
+
+        manifest.set_table_state("users", TableState::Applied, 30).unwrap();
+        assert_eq!(job_status(manifest.table("users").unwrap()), JobStatus::Complete);
+    }
+}
@@ -0,0 +1,121 @@
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{audit, budget::ExecutionBudget, database, notify};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DiagnoseKeysInput {
+    pub database_id: String,
+}
+
+fn default_rotation_chunk_size() -> usize {
+    100
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RotateMasterKeyInput {
+    pub database_id: String,
+    #[serde(default = "default_rotation_chunk_size")]
+    pub chunk_size: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyRole {
+    Master,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KeyStatus {
+    pub role: KeyRole,
+    pub key_name: Option<String>,
+    pub loadable: bool,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KeyDiagnosis {
+    pub database_id: String,
+    pub healthy: bool,
+    pub keys: Vec<KeyStatus>,
+}
+
+impl KeyDiagnosis {
+    pub fn new(database_id: &str, keys: Vec<KeyStatus>) -> Self {
+        Self {
+            database_id: database_id.to_string(),
+            healthy: keys.iter().all(|k| k.loadable),
+            keys,
+        }
+    }
+}
+
+// Checks every key referenced by a client against the key store. The master key is the only key a client holds.
+pub fn diagnose_client_keys(client: &database::Client) -> KeyDiagnosis {
+    let master = match client.load_master_key() {
+        Ok(_) => KeyStatus { role: KeyRole::Master, key_name: client.master_key_name().map(|n| n.to_string()), loadable: true, error: None },
+        Err(err) => KeyStatus { role: KeyRole::Master, key_name: client.master_key_name().map(|n| n.to_string()), loadable: false, error: Some(err.to_string()) },
+    };
+    KeyDiagnosis::new(client.database_id(), vec![master])
+}
+
+pub fn diagnose_keys(cmd: String) {
+    let input: DiagnoseKeysInput = match notify::parse_input(&cmd) {
+        Some(input) => input,
+        None => return,
+    };
+    let client = match database::Client::load(input.database_id.clone()) {
+        Ok(c) => c,
+        Err(err) => {
+            notify::error_with_code(&format!("Failed to load client: {}", err), err.code());
+            return;
+        }
+    };
+    notify::result(&diagnose_client_keys(&client));
+}
+
+// Re-encrypts the registered columns of a client under a new master key. Stops when the execution budget runs
+// low, calling again resumes the rotation; the old key stays in use until every row was rewritten.
+pub fn rotate_master_key(cmd: String) {
+    let input: RotateMasterKeyInput = match notify::parse_input(&cmd) {
+        Some(input) => input,
+        None => return,
+    };
+    let mut client = match database::Client::load(input.database_id.clone()) {
+        Ok(c) => c,
+        Err(err) => {
+            notify::error_with_code(&format!("Failed to load client: {}", err), err.code());
+            return;
+        }
+    };
+    if let Err(err) = client.require_owner("rotate its master key") {
+        notify::error_with_code(&format!("Failed to rotate master key: {}", err), err.code());
+        return;
+    }
+    if let Err(err) = client.connect() {
+        notify::error_with_code(&format!("Failed to connect to client: {}", err), err.code());
+        return;
+    }
+    let mut budget = match ExecutionBudget::from_settings() {
+        Ok(b) => b,
+        Err(err) => {
+            notify::error_with_code(&format!("Failed to load settings: {}", err), database::DatabaseError::code_of(err.as_ref()));
+            return;
+        }
+    };
+
+    match client.rotate_master_key(input.chunk_size, &mut budget) {
+        Ok(rotation) => {
+            let rewritten: u64 = rotation.tables.iter().map(|t| t.rewritten).sum();
+            audit::record("rotate_master_key", Some(&input.database_id), if rotation.complete { "complete" } else { "partial" },
+                json!({ "rewritten": rewritten, "backups_resealed": rotation.backups_resealed, "old_key_deleted": rotation.old_key_deleted }));
+            notify::result(&rotation);
+        },
+        Err(err) => {
+            audit::record("rotate_master_key", Some(&input.database_id), "failure", json!({}));
+            notify::error_with_code(&format!("Failed to rotate master key: {}", err), database::DatabaseError::code_of(err.as_ref()));
+        }
+    }
+}
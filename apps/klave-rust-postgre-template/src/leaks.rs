@@ -0,0 +1,469 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+
+use crate::{
+    audit,
+    budget::ExecutionBudget,
+    crypto::{parse_ciphertext, CipherError, ColumnCipher, AES_GCM_IV_SIZE, AES_GCM_TAG_SIZE},
+    database::{scan_context, Client, DatabaseError, Field},
+    locks::JobLock,
+    manifest::{EncryptedColumn, EncryptedTable, EncryptionManifest, TableState},
+    notify,
+    partitions::{KeyRange, KeysetPager},
+    settings::AccessLevel,
+};
+
+// Primary keys listed per column and class, the counters keep the full picture
+const MAX_SAMPLED_KEYS: usize = 20;
+// Shortest run of hex digits taken for an attempt at ciphertext: the IV alone
+const MIN_HEX_LIKE_LEN: usize = AES_GCM_IV_SIZE * 2;
+
+pub(crate) fn default_batch_size() -> usize {
+    500
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CellClass {
+    // Parses with its version prefix and decrypts
+    Valid,
+    // Looks like ciphertext but has the wrong length, an unknown version or does not authenticate
+    Suspicious,
+    Plaintext,
+}
+
+// What a stored value looks like before any decryption; Valid only means it is worth decrypting
+pub fn cell_shape(encoded: &str) -> CellClass {
+    let payload = match parse_ciphertext(encoded) {
+        Ok((_, payload)) => payload,
+        // A version prefix this build cannot read
+        Err(CipherError::UnsupportedVersion(_)) => return CellClass::Suspicious,
+        Err(_) => encoded,
+    };
+    if payload.len() < MIN_HEX_LIKE_LEN || !payload.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return CellClass::Plaintext;
+    }
+    if payload.len() % 2 != 0 || payload.len() / 2 <= AES_GCM_IV_SIZE + AES_GCM_TAG_SIZE {
+        return CellClass::Suspicious;
+    }
+    CellClass::Valid
+}
+
+// None for NULL cells; `decrypts` is only called on values shaped like ciphertext
+pub fn classify<F: FnOnce(&str) -> bool>(value: &Value, decrypts: F) -> Option<CellClass> {
+    match value {
+        Value::Null => None,
+        Value::String(encoded) => match cell_shape(encoded) {
+            CellClass::Valid if !decrypts(encoded) => Some(CellClass::Suspicious),
+            class => Some(class),
+        },
+        _ => Some(CellClass::Plaintext),
+    }
+}
+
+// Column where a scan or a quarantine stopped, and the last primary key it read
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LeakScanCursor {
+    pub table: String,
+    pub column: String,
+    #[serde(default)]
+    pub after: Option<Value>,
+}
+
+// Registered columns left to walk, in manifest order, starting at the cursor. Tables being encrypted legitimately
+// hold plaintext and are left out.
+pub fn scan_plan(manifest: &EncryptionManifest, table: Option<&str>, resume: Option<&LeakScanCursor>) -> Result<Vec<LeakScanCursor>, String> {
+    let mut plan: Vec<LeakScanCursor> = manifest.tables.iter()
+        .filter(|t| t.state == TableState::Applied && table.map(|name| name == t.table).unwrap_or(true))
+        .flat_map(|t| t.columns.iter().map(|c| LeakScanCursor { table: t.table.clone(), column: c.name.clone(), after: None }))
+        .collect();
+    if let Some(resume) = resume {
+        let start = plan.iter().position(|c| c.table == resume.table && c.column == resume.column)
+            .ok_or(format!("Column {} of table {} is no longer registered, scan again without resume", resume.column, resume.table))?;
+        plan.drain(..start);
+        plan[0].after = resume.after.clone();
+    }
+    Ok(plan)
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct ColumnLeakReport {
+    pub table: String,
+    pub column: String,
+    // Non-null cells read
+    pub scanned: u64,
+    pub valid: u64,
+    pub suspicious: u64,
+    pub plaintext: u64,
+    pub suspicious_keys: Vec<Value>,
+    pub plaintext_keys: Vec<Value>,
+}
+
+impl ColumnLeakReport {
+    fn new(table: &str, column: &str) -> Self {
+        Self { table: table.to_string(), column: column.to_string(), ..Default::default() }
+    }
+
+    pub fn add(&mut self, class: CellClass, primary_key: &Value) {
+        self.scanned += 1;
+        let keys = match class {
+            CellClass::Valid => {
+                self.valid += 1;
+                return;
+            }
+            CellClass::Suspicious => {
+                self.suspicious += 1;
+                &mut self.suspicious_keys
+            }
+            CellClass::Plaintext => {
+                self.plaintext += 1;
+                &mut self.plaintext_keys
+            }
+        };
+        if keys.len() < MAX_SAMPLED_KEYS {
+            keys.push(primary_key.clone());
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct LeakScanReport {
+    pub columns: Vec<ColumnLeakReport>,
+    // Tables being encrypted, not scanned
+    pub skipped_tables: Vec<String>,
+    // Set when the execution budget ran low, scanning again with it continues from there
+    pub resume: Option<LeakScanCursor>,
+}
+
+struct ClassifiedCell {
+    primary_key: Value,
+    value: Value,
+    context: Map<String, Value>,
+    class: CellClass,
+}
+
+enum ColumnWalk {
+    Complete,
+    // Stopped after the given primary key
+    Stopped(Option<Value>),
+}
+
+// Reads the column a batch at a time by primary key and hands the classified non-null cells of each batch to
+// `handle`. A batch is charged to the budget before it is read.
+fn walk_column<H>(client: &Client, entry: &EncryptedTable, column: &EncryptedColumn, cipher: &ColumnCipher, pager: &mut KeysetPager, budget: &mut ExecutionBudget, mut handle: H) -> Result<ColumnWalk, Box<dyn std::error::Error>>
+where
+    H: FnMut(&[ClassifiedCell]) -> Result<(), Box<dyn std::error::Error>>,
+{
+    let context_columns = column.parsed_aad_template()?.map(|t| t.context_fields()).unwrap_or_default();
+    while !pager.is_done() {
+        if !budget.try_charge(pager.batch_size() as u64, 1) {
+            return Ok(ColumnWalk::Stopped(pager.last_key()));
+        }
+        let answer = client.get_column_to_encrypt(&entry.table, &entry.primary_key, &column.name, &context_columns, pager)?;
+        pager.advance(&answer.resultset);
+        let mut cells = Vec::new();
+        for row in answer.resultset.iter() {
+            let value = row.get(entry.primary_key.column_count()).cloned().unwrap_or(Value::Null);
+            let context = scan_context(&entry.primary_key, &answer.fields, row);
+            let class = match classify(&value, |encoded| cipher.decrypt(encoded, &context).is_ok()) {
+                Some(class) => class,
+                None => continue,
+            };
+            cells.push(ClassifiedCell { primary_key: entry.primary_key.leading_value(row), value, context, class });
+        }
+        handle(&cells)?;
+    }
+    Ok(ColumnWalk::Complete)
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LeakScanInput {
+    pub database_id: String,
+    // Only scans this table, every registered table otherwise
+    #[serde(default)]
+    pub table: Option<String>,
+    #[serde(default = "default_batch_size")]
+    pub batch_size: usize,
+    #[serde(default)]
+    pub resume: Option<LeakScanCursor>,
+}
+
+pub(crate) fn scan(client: &Client, input: &LeakScanInput, budget: &mut ExecutionBudget) -> Result<LeakScanReport, Box<dyn std::error::Error>> {
+    let manifest = EncryptionManifest::load(client.database_id())?;
+    if let Some(table) = input.table.as_ref() {
+        manifest.table(table).ok_or(format!("Table {} has no encrypted columns", table))?;
+    }
+    let master_key = client.load_master_key()?;
+    let mut report = LeakScanReport {
+        skipped_tables: manifest.tables.iter()
+            .filter(|t| t.state == TableState::Applying && input.table.as_ref().map(|name| name == &t.table).unwrap_or(true))
+            .map(|t| t.table.clone())
+            .collect(),
+        ..Default::default()
+    };
+    for target in scan_plan(&manifest, input.table.as_deref(), input.resume.as_ref())? {
+        let (entry, column) = match (manifest.table(&target.table), manifest.column(&target.table, &target.column)) {
+            (Some(entry), Some(column)) => (entry, column),
+            _ => continue,
+        };
+        let cipher = client.column_cipher(&master_key, &manifest, &target.table, &target.column)?;
+        let mut column_report = ColumnLeakReport::new(&target.table, &target.column);
+        let mut pager = KeysetPager::new(KeyRange { after: target.after.clone(), up_to: None }, input.batch_size, &entry.primary_key);
+        let walk = walk_column(client, entry, column, &cipher, &mut pager, budget, |cells| {
+            for cell in cells {
+                column_report.add(cell.class, &cell.primary_key);
+            }
+            Ok(())
+        })?;
+        if column_report.plaintext > 0 || column_report.suspicious > 0 {
+            notify::warning(&format!("Column {} of table {} holds {} plaintext and {} suspicious values", target.column, target.table, column_report.plaintext, column_report.suspicious));
+        }
+        report.columns.push(column_report);
+        if let ColumnWalk::Stopped(after) = walk {
+            report.resume = Some(LeakScanCursor { after, ..target });
+            break;
+        }
+    }
+    Ok(report)
+}
+
+// Walks the registered encrypted columns and reports the cells that do not hold valid ciphertext
+pub fn scan_for_plaintext_leaks(cmd: String) {
+    let input: LeakScanInput = match notify::parse_input(&cmd) {
+        Some(input) => input,
+        None => return,
+    };
+    let mut client = match Client::load(input.database_id.clone()) {
+        Ok(c) => c,
+        Err(err) => {
+            notify::error_with_code(&format!("Failed to load client: {}", err), err.code());
+            return;
+        }
+    };
+    if let Err(err) = client.connect() {
+        notify::error_with_code(&format!("Failed to connect to client: {}", err), err.code());
+        return;
+    }
+    let mut budget = match ExecutionBudget::from_settings() {
+        Ok(budget) => budget,
+        Err(err) => {
+            notify::error_with_code(&format!("Failed to load settings: {}", err), DatabaseError::code_of(err.as_ref()));
+            return;
+        }
+    };
+    match scan(&client, &input, &mut budget) {
+        Ok(report) => notify::result(&report),
+        Err(err) => notify::error_with_code(&format!("Failed to scan for plaintext leaks: {}", err), DatabaseError::code_of(err.as_ref())),
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct QuarantineInput {
+    pub database_id: String,
+    pub table: String,
+    // Registered columns to remediate, all of them when empty
+    #[serde(default)]
+    pub columns: Vec<String>,
+    #[serde(default = "default_batch_size")]
+    pub batch_size: usize,
+    #[serde(default)]
+    pub resume: Option<LeakScanCursor>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct ColumnQuarantine {
+    pub column: String,
+    pub encrypted: u64,
+    // Left alone: encrypting a value that already looks like ciphertext would hide it for good
+    pub suspicious: u64,
+    pub encrypted_keys: Vec<Value>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct QuarantineReport {
+    pub table: String,
+    pub columns: Vec<ColumnQuarantine>,
+    pub resume: Option<LeakScanCursor>,
+}
+
+fn quarantine(client: &Client, input: &QuarantineInput, budget: &mut ExecutionBudget, lock: &mut JobLock) -> Result<QuarantineReport, Box<dyn std::error::Error>> {
+    let manifest = EncryptionManifest::load(client.database_id())?;
+    let entry = manifest.table(&input.table).ok_or(format!("Table {} has no encrypted columns", input.table))?;
+    if entry.state == TableState::Applying {
+        return Err(format!("Table {} is being encrypted, resume execute_table_encryption instead", input.table).into());
+    }
+    for column in input.columns.iter() {
+        manifest.column(&input.table, column).ok_or(format!("Column {} of table {} is not encrypted", column, input.table))?;
+    }
+    let master_key = client.load_write_key()?;
+    let mut report = QuarantineReport { table: input.table.clone(), ..Default::default() };
+    let plan = scan_plan(&manifest, Some(&input.table), input.resume.as_ref())?;
+    for target in plan.into_iter().filter(|t| input.columns.is_empty() || input.columns.contains(&t.column)) {
+        let column = match manifest.column(&target.table, &target.column) {
+            Some(column) => column,
+            None => continue,
+        };
+        let cipher = client.column_cipher(&master_key, &manifest, &target.table, &target.column)?;
+        let mut fields = entry.primary_key.fields();
+        fields.push(Field::named(&target.column));
+        if let Some(index_column) = column.blind_index.as_ref() {
+            fields.push(Field::named(index_column));
+        }
+        let mut outcome = ColumnQuarantine { column: target.column.clone(), ..Default::default() };
+        let mut pager = KeysetPager::new(KeyRange { after: target.after.clone(), up_to: None }, input.batch_size, &entry.primary_key);
+        let walk = walk_column(client, entry, column, &cipher, &mut pager, budget, |cells| {
+            let mut rows = Vec::new();
+            for cell in cells {
+                match cell.class {
+                    CellClass::Plaintext => {
+                        let mut row = entry.primary_key.cells(&cell.primary_key);
+                        row.push(Value::String(cipher.encrypt(&cell.value, &cell.context)?));
+                        row.extend(cipher.blind_index(&cell.value)?.map(Value::String));
+                        rows.push(row);
+                    },
+                    CellClass::Suspicious => outcome.suspicious += 1,
+                    CellClass::Valid => (),
+                }
+            }
+            if rows.is_empty() {
+                return Ok(());
+            }
+            for row in rows.iter().take(MAX_SAMPLED_KEYS.saturating_sub(outcome.encrypted_keys.len())) {
+                outcome.encrypted_keys.push(entry.primary_key.leading_value(row));
+            }
+            outcome.encrypted += rows.len() as u64;
+            client.execute(&client.build_update_query(rows, fields.clone(), entry.primary_key.column_count(), target.table.clone())?)?;
+            lock.heartbeat()?;
+            Ok(())
+        })?;
+        report.columns.push(outcome);
+        if let ColumnWalk::Stopped(after) = walk {
+            report.resume = Some(LeakScanCursor { after, ..target });
+            break;
+        }
+    }
+    Ok(report)
+}
+
+// Encrypts in place the plaintext cells found by scan_for_plaintext_leaks. Suspicious cells are only counted.
+pub fn quarantine_leaked_rows(cmd: String) {
+    let input: QuarantineInput = match notify::parse_input(&cmd) {
+        Some(input) => input,
+        None => return,
+    };
+    let mut client = match Client::load(input.database_id.clone()) {
+        Ok(c) => c,
+        Err(err) => {
+            notify::error_with_code(&format!("Failed to load client: {}", err), err.code());
+            return;
+        }
+    };
+    if client.access() == AccessLevel::ReportingOnly {
+        notify::error("Reporting-only callers cannot quarantine rows");
+        return;
+    }
+    if let Err(err) = client.connect() {
+        notify::error_with_code(&format!("Failed to connect to client: {}", err), err.code());
+        return;
+    }
+    let mut budget = match ExecutionBudget::from_settings() {
+        Ok(budget) => budget,
+        Err(err) => {
+            notify::error_with_code(&format!("Failed to load settings: {}", err), DatabaseError::code_of(err.as_ref()));
+            return;
+        }
+    };
+    let mut lock = match JobLock::acquire(&input.database_id, &input.table) {
+        Ok(lock) => lock,
+        Err(err) => {
+            notify::error_with_code(&format!("Failed to lock table {}: {}", input.table, err), DatabaseError::code_of(err.as_ref()));
+            return;
+        }
+    };
+    let result = quarantine(&client, &input, &mut budget, &mut lock);
+    lock.release();
+    match result {
+        Ok(report) => {
+            let encrypted: u64 = report.columns.iter().map(|c| c.encrypted).sum();
+            audit::record("quarantine_leaked_rows", Some(&input.database_id), "success", json!({
+                "table": input.table,
+                "columns": report.columns.iter().map(|c| json!({ "column": c.column, "encrypted": c.encrypted, "suspicious": c.suspicious, "encrypted_keys": c.encrypted_keys })).collect::<Vec<Value>>(),
+                "encrypted": encrypted,
+                "complete": report.resume.is_none(),
+            }));
+            notify::result(&report);
+        }
+        Err(err) => {
+            audit::record("quarantine_leaked_rows", Some(&input.database_id), "failure", json!({ "table": input.table, "error": err.to_string() }));
+            notify::error_with_code(&format!("Failed to quarantine leaked rows: {}", err), DatabaseError::code_of(err.as_ref()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CIPHERTEXT: &str = "000102030405060708090a0b5a3c6e1f8d2b4a6c8e0f1a2b3c4d5e6f708192a3b4";
+
+    #[test]
+    fn test_cell_shape() {
+        assert_eq!(cell_shape(CIPHERTEXT), CellClass::Valid);
+        assert_eq!(cell_shape(&format!("v0:{}", CIPHERTEXT)), CellClass::Valid);
+        // Hex of the wrong length: odd, or no room for a tag
+        assert_eq!(cell_shape(&CIPHERTEXT[1..]), CellClass::Suspicious);
+        assert_eq!(cell_shape(&CIPHERTEXT[..56]), CellClass::Suspicious);
+        assert_eq!(cell_shape(&format!("v9:{}", CIPHERTEXT)), CellClass::Suspicious);
+        // Short digit strings and text are plaintext
+        assert_eq!(cell_shape("4111111111111111"), CellClass::Plaintext);
+        assert_eq!(cell_shape("alice@example.com"), CellClass::Plaintext);
+        assert_eq!(cell_shape("visit: tuesday"), CellClass::Plaintext);
+    }
+
+    #[test]
+    fn test_classify() {
+        assert_eq!(classify(&json!(CIPHERTEXT), |_| true), Some(CellClass::Valid));
+        // Right shape but the tag does not authenticate
+        assert_eq!(classify(&json!(CIPHERTEXT), |_| false), Some(CellClass::Suspicious));
+        assert_eq!(classify(&json!("alice"), |_| panic!("plaintext is not decrypted")), Some(CellClass::Plaintext));
+        assert_eq!(classify(&json!(42), |_| true), Some(CellClass::Plaintext));
+        assert_eq!(classify(&Value::Null, |_| true), None);
+    }
+
+    #[test]
+    fn test_column_report_bounds_the_sample() {
+        let mut report = ColumnLeakReport::new("users", "ssn");
+        for i in 0..30 {
+            report.add(CellClass::Plaintext, &json!(i));
+        }
+        report.add(CellClass::Valid, &json!(30));
+        report.add(CellClass::Suspicious, &json!(31));
+        assert_eq!((report.scanned, report.valid, report.suspicious, report.plaintext), (32, 1, 1, 30));
+        assert_eq!(report.plaintext_keys.len(), MAX_SAMPLED_KEYS);
+        assert_eq!(report.suspicious_keys, vec![json!(31)]);
+    }
+
+    #[test]
+    fn test_scan_plan() {
+        let mut manifest = EncryptionManifest::new("db");
+        manifest.record_column("users", "id", EncryptedColumn::new("ssn", None), 0);
+        manifest.record_column("users", "id", EncryptedColumn::new("email", None), 0);
+        manifest.record_column("orders", "id", EncryptedColumn::new("card", None), 0);
+        manifest.record_column("drafts", "id", EncryptedColumn::new("note", None), 0);
+        manifest.set_table_state("drafts", TableState::Applying, 0).unwrap();
+
+        let columns = |plan: Vec<LeakScanCursor>| plan.into_iter().map(|c| format!("{}.{}", c.table, c.column)).collect::<Vec<String>>();
+        assert_eq!(columns(scan_plan(&manifest, None, None).unwrap()), vec!["users.ssn", "users.email", "orders.card"]);
+        assert_eq!(columns(scan_plan(&manifest, Some("orders"), None).unwrap()), vec!["orders.card"]);
+
+        let resume = LeakScanCursor { table: "users".to_string(), column: "email".to_string(), after: Some(json!(7)) };
+        let plan = scan_plan(&manifest, None, Some(&resume)).unwrap();
+        assert_eq!(plan[0], resume);
+        assert_eq!(plan[1].after, None);
+        let gone = LeakScanCursor { column: "phone".to_string(), ..resume };
+        assert!(scan_plan(&manifest, None, Some(&gone)).is_err());
+    }
+}
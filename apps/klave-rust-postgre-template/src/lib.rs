@@ -1,102 +1,597 @@
+#[cfg(feature = "component")]
 #[allow(warnings)]
 mod bindings;
 
+#[cfg(feature = "component")]
 use bindings::Guest;
-use klave;
 
 pub mod database;
 pub mod crypto;
 pub mod utils;
 pub mod business;
+pub mod statement;
+pub mod audit;
+pub mod script;
+pub mod manifest;
+pub mod consistency;
+pub mod settings;
+pub mod testdata;
+pub mod integrity;
+pub mod keys;
+pub mod host;
+pub mod budget;
+pub mod search_index;
+pub mod snapshot;
+pub mod notify;
+pub mod locks;
+pub mod duplicates;
+pub mod bulk;
+pub mod rules;
+pub mod freeze;
+pub mod ciphertext_ops;
+pub mod constraints;
+pub mod support;
+pub mod backup;
+pub mod strict;
+pub mod history;
+pub mod bootstrap;
+pub mod views;
+pub mod routing;
+pub mod partitions;
+pub mod confirm;
+pub mod credentials;
+pub mod jobs;
+pub mod capabilities;
+pub mod errors;
+pub mod intents;
+pub mod posture;
+pub mod faults;
+pub mod audit_columns;
+pub mod watch;
+pub mod tls;
+pub mod storage;
+pub mod migrate;
+pub mod leaks;
+pub mod primary_key;
+pub mod access;
+pub mod cursor;
+pub mod schema;
+pub mod runtime;
+#[cfg(feature = "simulator")]
+pub mod simulator;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RouteKind {
+    Query,
+    Transaction,
+}
+
+// Every route of the component, registered in this order
+pub const ROUTES: &[(&str, RouteKind)] = &[
+    ("db_setup", RouteKind::Transaction),
+    ("sql_delete", RouteKind::Transaction),
+    ("execute_table_encryption", RouteKind::Transaction),
+    ("sql_script", RouteKind::Transaction),
+    ("decrypt_value", RouteKind::Query),
+    ("consistent_read_session", RouteKind::Query),
+    ("get_settings", RouteKind::Query),
+    ("update_settings", RouteKind::Transaction),
+    ("generate_test_data", RouteKind::Transaction),
+    ("quick_verify_table", RouteKind::Query),
+    ("reconcile_encryption_state", RouteKind::Transaction),
+    ("diagnose_keys", RouteKind::Query),
+    ("create_search_index", RouteKind::Transaction),
+    ("drop_search_index", RouteKind::Transaction),
+    ("search_index_progress", RouteKind::Query),
+    ("find_duplicate_values", RouteKind::Query),
+    ("export_state_snapshot", RouteKind::Transaction),
+    ("import_state_snapshot", RouteKind::Transaction),
+    ("bulk_operation", RouteKind::Transaction),
+    ("set_column_rules", RouteKind::Transaction),
+    ("freeze_database", RouteKind::Transaction),
+    ("unfreeze_database", RouteKind::Transaction),
+    ("generate_support_bundle", RouteKind::Query),
+    ("restore_row_backup", RouteKind::Transaction),
+    ("prune_row_backups", RouteKind::Transaction),
+    ("set_strict_encrypted_access", RouteKind::Transaction),
+    ("issue_strict_bypass_token", RouteKind::Transaction),
+    ("prune_history", RouteKind::Transaction),
+    ("get_audit_log", RouteKind::Query),
+    ("bootstrap", RouteKind::Transaction),
+    ("create_encrypted_view", RouteKind::Transaction),
+    ("refresh_encrypted_view", RouteKind::Transaction),
+    ("route_usage", RouteKind::Query),
+    ("encryption_progress", RouteKind::Query),
+    ("migrate_ciphertext_versions", RouteKind::Transaction),
+    ("test_credentials", RouteKind::Transaction),
+    ("reap_stale_jobs", RouteKind::Transaction),
+    ("probe_host_capabilities", RouteKind::Transaction),
+    ("recover_incomplete_operations", RouteKind::Transaction),
+    ("sql_list", RouteKind::Query),
+    ("db_update", RouteKind::Transaction),
+    ("read_encrypted_table", RouteKind::Query),
+    ("manage_audit_columns", RouteKind::Transaction),
+    ("watch_query", RouteKind::Transaction),
+    ("run_watch", RouteKind::Transaction),
+    ("decrypt_values", RouteKind::Query),
+    ("storage_usage", RouteKind::Query),
+    ("recalculate_storage_usage", RouteKind::Transaction),
+    ("list_encrypted_columns", RouteKind::Query),
+    ("execute_table_decryption", RouteKind::Transaction),
+    ("migrate_deployment", RouteKind::Transaction),
+    ("scan_for_plaintext_leaks", RouteKind::Query),
+    ("quarantine_leaked_rows", RouteKind::Transaction),
+    ("sql_transaction", RouteKind::Transaction),
+    ("rotate_master_key", RouteKind::Transaction),
+    ("grant_db_access", RouteKind::Transaction),
+    ("revoke_db_access", RouteKind::Transaction),
+    ("sql_query_cursor", RouteKind::Query),
+    ("db_list_tables", RouteKind::Query),
+    ("db_describe_table", RouteKind::Query),
+    //routes defined in business part
+    ("read_encrypted_data_per_user", RouteKind::Query),
+    ("avg_age_for_male", RouteKind::Query),
+    ("avg_age_for_female", RouteKind::Query),
+];
+
+// Versioned names of routes: same handler and kind, results serialized with the given shape.
+// The original name keeps its legacy output and is deprecated in favor of the alias.
+pub const ROUTE_ALIASES: &[(&str, &str, notify::ResultShape)] = &[
+    ("v2_db_setup", "db_setup", notify::ResultShape::Envelope),
+    ("v2_sql_script", "sql_script", notify::ResultShape::Envelope),
+];
+
+// Thin adapters from the exported functions to the route handlers
+#[cfg(feature = "component")]
 struct Component;
+#[cfg(feature = "component")]
 impl Guest for Component {
 
     fn register_routes(){
-        klave::router::add_user_transaction(&String::from("db_setup"));
-        klave::router::add_user_query(&String::from("execute_table_encryption"));
-
-        //routes defined in business part
-        klave::router::add_user_query(&String::from("read_encrypted_data_per_user"));
-        klave::router::add_user_query(&String::from("avg_age_for_male"));
-        klave::router::add_user_query(&String::from("avg_age_for_female"));
+        for (route, kind) in ROUTES {
+            match kind {
+                RouteKind::Query => klave::router::add_user_query(route),
+                RouteKind::Transaction => klave::router::add_user_transaction(route),
+            }
+        }
+        for (alias, route, _) in ROUTE_ALIASES {
+            match routing::route_kind(route) {
+                Some(RouteKind::Query) => klave::router::add_user_query(alias),
+                Some(RouteKind::Transaction) => klave::router::add_user_transaction(alias),
+                None => (),
+            }
+        }
     }
 
     //endpoints to test Postgres client management
     fn db_setup(cmd: String) {
-        let input: database::DBInputDetails = match serde_json::from_str(&cmd) {
-            Ok(input) => input,
-            Err(err) => {
-                klave::notifier::send_string(&format!("Invalid input: {}", err));
+        routing::invoke_route("db_setup", cmd, database::db_setup);
+    }
+
+    fn v2_db_setup(cmd: String) {
+        routing::invoke_route("v2_db_setup", cmd, database::db_setup);
+    }
+
+    fn sql_delete(cmd: String) {
+        bootstrap::invoke_guarded("sql_delete", cmd, |cmd| {
+            let input: database::DeleteInput = match notify::parse_input(&cmd) {
+                Some(input) => input,
+                None => return,
+            };
+
+            // The manifest names the encrypted tables, only owners get to read it. A listing left behind by
+            // a record already gone has nothing to consult and is cleaned up below.
+            let mut client = match database::Client::load(input.database_id.clone()) {
+                Ok(c) => Some(c),
+                Err(database::DatabaseError::NotFound(_)) if !database::Client::is_stored(&input.database_id) => None,
+                Err(err) => {
+                    notify::error_with_code(&format!("Failed to load client: {}", err), err.code());
+                    return;
+                }
+            };
+            if let Some(Err(err)) = client.as_ref().map(|c| c.require_owner("delete it")) {
+                notify::error_with_code(&format!("Failed to delete client: {}", err), err.code());
                 return;
             }
-        };
+            let manifest = match client {
+                Some(_) => match manifest::EncryptionManifest::load(&input.database_id) {
+                    Ok(m) => m,
+                    Err(err) => {
+                        notify::error_with_code(&format!("Failed to load encryption manifest: {}", err), database::DatabaseError::code_of(err.as_ref()));
+                        return;
+                    }
+                },
+                None => manifest::EncryptionManifest::new(&input.database_id),
+            };
+            let encrypted_tables: Vec<String> = manifest.tables.iter().map(|t| t.table.clone()).collect();
+            let path = match database::deletion_path(&manifest, input.abandon_encrypted_data) {
+                Ok(path) => path,
+                Err(err) => {
+                    audit::record("sql_delete", Some(&input.database_id), "refused", serde_json::json!({ "encrypted_tables": encrypted_tables }));
+                    notify::error(&format!("Refusing to delete client: {}", err));
+                    return;
+                }
+            };
 
-        let mut clients = match database::Clients::load() {
-            Ok(c) => c,
-            Err(err) => {
-                klave::notifier::send_string(&format!("Failed to load clients: {}", err));
-                return;
+            // Encrypted tables come from the manifest of a loaded client
+            if let (database::DeletionPath::AbandonedEncryptedData, Some(client)) = (path, client.as_mut()) {
+                let mut summary = confirm::ActionSummary::new("sql_delete", client);
+                // Without a connection the rows are reported as unknown
+                if client.connect().is_ok() {
+                    summary.estimated_rows = confirm::estimate_rows(client, &encrypted_tables);
+                }
+                summary.details.insert("encrypted_tables".to_string(), encrypted_tables.join(", "));
+                summary.irreversible.push("The client registration and its encryption manifest are deleted".to_string());
+                summary.irreversible.push("Rows of the encrypted tables can no longer be decrypted through this deployment".to_string());
+                match confirm::confirm(&summary, &input.confirmation) {
+                    Ok(confirm::ConfirmStep::Issued(prompt)) => {
+                        notify::result(&prompt);
+                        return;
+                    },
+                    Ok(confirm::ConfirmStep::Confirmed) => (),
+                    Err(err) => {
+                        audit::record("sql_delete", Some(&input.database_id), "refused", serde_json::json!({ "encrypted_tables": encrypted_tables }));
+                        notify::error_with_code(&format!("Refusing to delete client: {}", err), database::DatabaseError::code_of(err.as_ref()));
+                        return;
+                    }
+                }
             }
-        };
-
-        match clients.add(
-            input.clone(),
-        ) {
-            Ok(database_id) => {
-                klave::notifier::send_string(&database_id);
-            },
-            Err(err) => {
-                klave::notifier::send_string(&format!("Failed to add database client: {}", err));
+
+            let mut clients = match database::Clients::load() {
+                Ok(c) => c,
+                Err(err) => {
+                    notify::error_with_code(&format!("Failed to load clients: {}", err), err.code());
+                    return;
+                }
+            };
+            if let Err(err) = clients.delete(&input.database_id) {
+                notify::error_with_code(&format!("Failed to delete client: {}", err), err.code());
                 return;
             }
-        };
+            if !encrypted_tables.is_empty() {
+                if let Err(err) = manifest::EncryptionManifest::remove(&input.database_id) {
+                    notify::warning(&format!("Failed to remove encryption manifest: {}", err));
+                }
+            }
+            // The path taken lets post-mortems tell whether encrypted data was knowingly abandoned
+            audit::record("sql_delete", Some(&input.database_id), "deleted", serde_json::json!({ "path": path, "encrypted_tables": encrypted_tables }));
+            // The legacy shape keeps the sentence existing callers match on, the envelope carries the outcome
+            match notify::result_shape() {
+                notify::ResultShape::Legacy => notify::result(&format!("Client {} deleted", input.database_id)),
+                notify::ResultShape::Envelope => notify::result(&serde_json::json!({
+                    "database_id": input.database_id,
+                    "deleted": true,
+                    "encrypted_data_unreadable": path == database::DeletionPath::AbandonedEncryptedData,
+                    "encrypted_tables": encrypted_tables,
+                })),
+            }
+        });
     }
 
     fn execute_table_encryption(cmd: String) {
-        let db_table: database::DBTable = match serde_json::from_str(&cmd) {
-            Ok(input) => input,
-            Err(err) => {
-                klave::notifier::send_string(&format!("Invalid input: {}", err));
-                return;
-            }
-        };
+        bootstrap::invoke_guarded("execute_table_encryption", cmd, |cmd| {
+            let db_table: database::DBTable = match notify::parse_input(&cmd) {
+                Some(input) => input,
+                None => return,
+            };
 
-        let mut client: database::Client = match database::Client::load(db_table.database_id.clone()) {
-            Ok(c) => c,
-            Err(err) => {
-                klave::notifier::send_string(&format!("Failed to load client: {}", err));
-                return;
+            let mut client: database::Client = match database::Client::load(db_table.database_id.clone()) {
+                Ok(c) => c,
+                Err(err) => {
+                    notify::error_with_code(&format!("Failed to load client: {}", err), err.code());
+                    return;
+                }
+            };
+            match client.connect() {
+                Ok(_) => (),
+                Err(err) => {
+                    notify::error_with_code(&format!("Failed to connect to client: {}", err), err.code());
+                    return;
+                }
+            };
+            let mut budget = match budget::ExecutionBudget::from_settings() {
+                Ok(b) => b,
+                Err(err) => {
+                    notify::error_with_code(&format!("Failed to load settings: {}", err), database::DatabaseError::code_of(err.as_ref()));
+                    return;
+                }
+            };
+            let table = db_table.table.clone();
+            jobs::reap_on_submission(&db_table.database_id, &table);
+            match client.encrypt_columns(db_table, &mut budget) {
+                Ok(database::EncryptionProgress::Complete(totals)) => {
+                    notify::result(&serde_json::json!({ "complete": true, "table": table, "rows": totals.rows, "batches": totals.batches, "encrypted": totals.encrypted, "skipped": totals.skipped }));
+                },
+                Ok(database::EncryptionProgress::Partial(watermark)) => {
+                    // Calling again with the same input resumes from the watermark
+                    notify::result(&serde_json::json!({ "partial": true, "table": table, "resume": watermark }));
+                },
+                Ok(database::EncryptionProgress::Partition(report)) => {
+                    // Each call works on the next unfinished partition, see encryption_progress
+                    notify::result(&serde_json::json!({ "partial": true, "table": table, "partition": report }));
+                },
+                Err(err) => {
+                    notify::error_with_code(&format!("Failed to encrypt columns: {}", err), database::DatabaseError::code_of(err.as_ref()));
+                    return;
+                }
             }
-        };
-        let _ = match client.connect() {
-            Ok(_) => (),
-            Err(err) => {
-                klave::notifier::send_string(&format!("Failed to connect to client: {}", err));
-                return;
+            // Remember the write position so that strong reads can wait for it
+            if let Err(err) = consistency::record_mutation(&client) {
+                notify::warning(&format!("Failed to record mutation position: {}", err));
             }
-        };
-        let _ = match client.encrypt_columns(db_table) {
-            Ok(_) => (),
-            Err(err) => {
-                klave::notifier::send_string(&format!("Failed to encrypt columns: {}", err));
-                return;
+        });
+    }
+
+    fn decrypt_value(cmd: String) {
+        bootstrap::invoke_guarded("decrypt_value", cmd, |cmd| {
+            let input: database::DecryptValueInput = match notify::parse_input(&cmd) {
+                Some(input) => input,
+                None => return,
+            };
+
+            let client: database::Client = match database::Client::load(input.database_id.clone()) {
+                Ok(c) => c,
+                Err(err) => {
+                    notify::error_with_code(&format!("Failed to load client: {}", err), err.code());
+                    return;
+                }
+            };
+            match client.decrypt_value(&input) {
+                Ok(value) => {
+                    notify::result(&value);
+                },
+                Err(err) => {
+                    // Without the key the stored ciphertext is handed back as is
+                    if !business::send_degraded(err.as_ref(), serde_json::Value::String(input.value.clone())) {
+                        notify::error_with_code(&format!("Failed to decrypt value: {}", err), database::DatabaseError::code_of(err.as_ref()));
+                    }
+                }
             }
-        };
+        });
+    }
+
+    fn consistent_read_session(cmd: String) {
+        bootstrap::invoke_guarded("consistent_read_session", cmd, consistency::consistent_read_session);
+    }
+
+    fn diagnose_keys(cmd: String) {
+        bootstrap::invoke_guarded("diagnose_keys", cmd, keys::diagnose_keys);
+    }
+
+    fn create_search_index(cmd: String) {
+        bootstrap::invoke_guarded("create_search_index", cmd, search_index::create_search_index);
+    }
+
+    fn drop_search_index(cmd: String) {
+        bootstrap::invoke_guarded("drop_search_index", cmd, search_index::drop_search_index);
+    }
+
+    fn search_index_progress(cmd: String) {
+        bootstrap::invoke_guarded("search_index_progress", cmd, search_index::search_index_progress);
+    }
+
+    fn find_duplicate_values(cmd: String) {
+        bootstrap::invoke_guarded("find_duplicate_values", cmd, duplicates::find_duplicate_values);
+    }
+
+    fn export_state_snapshot(cmd: String) {
+        bootstrap::invoke_guarded("export_state_snapshot", cmd, snapshot::export_state_snapshot);
+    }
+
+    fn import_state_snapshot(cmd: String) {
+        bootstrap::invoke_guarded("import_state_snapshot", cmd, snapshot::import_state_snapshot);
+    }
+
+    fn bulk_operation(cmd: String) {
+        bootstrap::invoke_guarded("bulk_operation", cmd, bulk::bulk_operation);
+    }
+
+    fn set_column_rules(cmd: String) {
+        bootstrap::invoke_guarded("set_column_rules", cmd, rules::set_column_rules);
+    }
+
+    fn freeze_database(cmd: String) {
+        bootstrap::invoke_guarded("freeze_database", cmd, freeze::freeze_database);
+    }
+
+    fn unfreeze_database(cmd: String) {
+        bootstrap::invoke_guarded("unfreeze_database", cmd, freeze::unfreeze_database);
+    }
+
+    fn generate_support_bundle(cmd: String) {
+        bootstrap::invoke_guarded("generate_support_bundle", cmd, support::generate_support_bundle);
+    }
+
+    fn restore_row_backup(cmd: String) {
+        bootstrap::invoke_guarded("restore_row_backup", cmd, backup::restore_row_backup);
+    }
+
+    fn prune_row_backups(cmd: String) {
+        bootstrap::invoke_guarded("prune_row_backups", cmd, backup::prune_row_backups);
+    }
+
+    fn set_strict_encrypted_access(cmd: String) {
+        bootstrap::invoke_guarded("set_strict_encrypted_access", cmd, strict::set_strict_encrypted_access);
+    }
+
+    fn issue_strict_bypass_token(cmd: String) {
+        bootstrap::invoke_guarded("issue_strict_bypass_token", cmd, strict::issue_strict_bypass_token);
+    }
+
+    fn get_settings(cmd: String) {
+        bootstrap::invoke_guarded("get_settings", cmd, settings::get_settings);
+    }
+
+    fn update_settings(cmd: String) {
+        bootstrap::invoke_guarded("update_settings", cmd, settings::update_settings);
+    }
+
+    fn generate_test_data(cmd: String) {
+        bootstrap::invoke_guarded("generate_test_data", cmd, testdata::generate_test_data);
+    }
+
+    fn quick_verify_table(cmd: String) {
+        bootstrap::invoke_guarded("quick_verify_table", cmd, integrity::quick_verify_table);
+    }
+
+    fn reconcile_encryption_state(cmd: String) {
+        bootstrap::invoke_guarded("reconcile_encryption_state", cmd, integrity::reconcile_encryption_state);
+    }
+
+    fn bootstrap(cmd: String) {
+        notify::invoke(cmd, bootstrap::bootstrap);
+    }
+
+    fn create_encrypted_view(cmd: String) {
+        bootstrap::invoke_guarded("create_encrypted_view", cmd, views::create_encrypted_view);
+    }
+
+    fn refresh_encrypted_view(cmd: String) {
+        bootstrap::invoke_guarded("refresh_encrypted_view", cmd, views::refresh_encrypted_view);
+    }
+
+    fn prune_history(cmd: String) {
+        bootstrap::invoke_guarded("prune_history", cmd, history::prune_history);
+    }
+
+    fn get_audit_log(cmd: String) {
+        bootstrap::invoke_guarded("get_audit_log", cmd, history::get_audit_log);
+    }
+
+    fn sql_script(cmd: String) {
+        routing::invoke_route("sql_script", cmd, script::sql_script);
+    }
+
+    fn v2_sql_script(cmd: String) {
+        routing::invoke_route("v2_sql_script", cmd, script::sql_script);
+    }
+
+    fn route_usage(cmd: String) {
+        bootstrap::invoke_guarded("route_usage", cmd, routing::route_usage);
+    }
+
+    fn encryption_progress(cmd: String) {
+        bootstrap::invoke_guarded("encryption_progress", cmd, partitions::encryption_progress);
+    }
+
+    fn migrate_ciphertext_versions(cmd: String) {
+        bootstrap::invoke_guarded("migrate_ciphertext_versions", cmd, integrity::migrate_ciphertext_versions);
+    }
+
+    fn test_credentials(cmd: String) {
+        bootstrap::invoke_guarded("test_credentials", cmd, credentials::test_credentials);
+    }
+
+    fn reap_stale_jobs(cmd: String) {
+        bootstrap::invoke_guarded("reap_stale_jobs", cmd, jobs::reap_stale_jobs);
+    }
+
+    fn probe_host_capabilities(cmd: String) {
+        bootstrap::invoke_guarded("probe_host_capabilities", cmd, capabilities::probe_host_capabilities);
+    }
+
+    fn recover_incomplete_operations(cmd: String) {
+        bootstrap::invoke_guarded("recover_incomplete_operations", cmd, intents::recover_incomplete_operations);
+    }
+
+    fn sql_list(cmd: String) {
+        bootstrap::invoke_guarded("sql_list", cmd, posture::sql_list);
+    }
+
+    fn db_update(cmd: String) {
+        bootstrap::invoke_guarded("db_update", cmd, database::db_update);
+    }
+
+    fn read_encrypted_table(cmd: String) {
+        bootstrap::invoke_guarded("read_encrypted_table", cmd, database::read_encrypted_table);
+    }
+
+    fn manage_audit_columns(cmd: String) {
+        bootstrap::invoke_guarded("manage_audit_columns", cmd, audit_columns::manage_audit_columns);
+    }
+
+    fn watch_query(cmd: String) {
+        bootstrap::invoke_guarded("watch_query", cmd, watch::watch_query);
+    }
+
+    fn run_watch(cmd: String) {
+        bootstrap::invoke_guarded("run_watch", cmd, watch::run_watch);
+    }
+
+    fn decrypt_values(cmd: String) {
+        bootstrap::invoke_guarded("decrypt_values", cmd, database::decrypt_values);
+    }
+
+    fn storage_usage(cmd: String) {
+        bootstrap::invoke_guarded("storage_usage", cmd, storage::storage_usage);
+    }
+
+    fn recalculate_storage_usage(cmd: String) {
+        bootstrap::invoke_guarded("recalculate_storage_usage", cmd, storage::recalculate_storage_usage);
+    }
+
+    fn list_encrypted_columns(cmd: String) {
+        bootstrap::invoke_guarded("list_encrypted_columns", cmd, manifest::list_encrypted_columns);
+    }
+
+    fn execute_table_decryption(cmd: String) {
+        bootstrap::invoke_guarded("execute_table_decryption", cmd, database::execute_table_decryption);
+    }
+
+    fn migrate_deployment(cmd: String) {
+        bootstrap::invoke_guarded("migrate_deployment", cmd, migrate::migrate_deployment);
+    }
+
+    fn scan_for_plaintext_leaks(cmd: String) {
+        bootstrap::invoke_guarded("scan_for_plaintext_leaks", cmd, leaks::scan_for_plaintext_leaks);
+    }
+
+    fn quarantine_leaked_rows(cmd: String) {
+        bootstrap::invoke_guarded("quarantine_leaked_rows", cmd, leaks::quarantine_leaked_rows);
+    }
+
+    fn sql_transaction(cmd: String) {
+        bootstrap::invoke_guarded("sql_transaction", cmd, script::sql_transaction);
+    }
+
+    fn rotate_master_key(cmd: String) {
+        bootstrap::invoke_guarded("rotate_master_key", cmd, keys::rotate_master_key);
+    }
+
+    fn grant_db_access(cmd: String) {
+        bootstrap::invoke_guarded("grant_db_access", cmd, access::grant_db_access);
+    }
+
+    fn revoke_db_access(cmd: String) {
+        bootstrap::invoke_guarded("revoke_db_access", cmd, access::revoke_db_access);
+    }
+
+    fn sql_query_cursor(cmd: String) {
+        bootstrap::invoke_guarded("sql_query_cursor", cmd, cursor::sql_query_cursor);
+    }
+
+    fn db_list_tables(cmd: String) {
+        bootstrap::invoke_guarded("db_list_tables", cmd, schema::db_list_tables);
+    }
+
+    fn db_describe_table(cmd: String) {
+        bootstrap::invoke_guarded("db_describe_table", cmd, schema::db_describe_table);
     }
 
     fn read_encrypted_data_per_user(cmd: String) {
-        business::read_encrypted_data_per_user(cmd);
+        bootstrap::invoke_guarded("read_encrypted_data_per_user", cmd, business::read_encrypted_data_per_user);
     }
 
     fn avg_age_for_male(cmd: String) {
-        business::avg_age_for_male(cmd);
+        bootstrap::invoke_guarded("avg_age_for_male", cmd, business::avg_age_for_male);
     }
 
     fn avg_age_for_female(cmd: String) {
-        business::avg_age_for_female(cmd);
+        bootstrap::invoke_guarded("avg_age_for_female", cmd, business::avg_age_for_female);
     }
 
 }
 
+#[cfg(feature = "component")]
 bindings::export!(Component with_types_in bindings);
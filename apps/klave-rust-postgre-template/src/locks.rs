@@ -0,0 +1,167 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{notify, settings::DeploymentSettings, storage, utils::{get_trusted_time, ledger_get, ledger_key, remove_legacy_key}};
+
+pub(crate) const JOB_LOCK_TABLE: &str = "JobLockTable";
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+
+// Lock held by the job working on a table, one per database and table so that jobs on different tables
+// of the same database run side by side. Calls on different partitions of a partitioned job hold one each.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct JobLock {
+    pub job_id: String,
+    pub database_id: String,
+    pub table: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub partition: Option<usize>,
+    pub acquired_at: u64,
+    pub heartbeat_at: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LockDecision {
+    Acquire,
+    // The holder stopped sending heartbeats, its lock is force-released
+    TakeOver(String),
+    Held(String),
+}
+
+pub fn lock_key(database_id: &str, table: &str, partition: Option<usize>) -> String {
+    match partition {
+        Some(partition) => ledger_key(&[database_id, table, &partition.to_string()]),
+        None => ledger_key(&[database_id, table]),
+    }
+}
+
+// Key older versions stored locks under
+fn legacy_lock_key(database_id: &str, table: &str, partition: Option<usize>) -> String {
+    match partition {
+        Some(partition) => format!("{}:{}#{}", database_id, table, partition),
+        None => format!("{}:{}", database_id, table),
+    }
+}
+
+// Nanoseconds without a heartbeat after which a job is abandoned, from the deployment settings
+pub fn stale_after() -> u64 {
+    DeploymentSettings::load().unwrap_or_default().job_stale_after_seconds.saturating_mul(NANOS_PER_SECOND)
+}
+
+pub fn decide(existing: Option<&JobLock>, now: u64, stale_after: u64) -> LockDecision {
+    match existing {
+        None => LockDecision::Acquire,
+        Some(lock) if now.saturating_sub(lock.heartbeat_at) > stale_after => LockDecision::TakeOver(lock.job_id.clone()),
+        Some(lock) => LockDecision::Held(lock.job_id.clone()),
+    }
+}
+
+fn load(database_id: &str, table: &str, partition: Option<usize>) -> Option<JobLock> {
+    ledger_get(JOB_LOCK_TABLE, &lock_key(database_id, table, partition), &legacy_lock_key(database_id, table, partition))
+        .and_then(|v| serde_json::from_slice::<JobLock>(&v).ok())
+}
+
+// Locks with the key they are stored under, which is a legacy one for locks older versions took
+pub(crate) fn load_all() -> Result<Vec<(String, JobLock)>, Box<dyn std::error::Error>> {
+    let ledger = crate::runtime::ledger::get_table(JOB_LOCK_TABLE);
+    let mut jobs = Vec::new();
+    for key in ledger.list_keys()? {
+        if let Ok(lock) = serde_json::from_slice::<JobLock>(&ledger.get(&key)?) {
+            jobs.push((key, lock));
+        }
+    }
+    Ok(jobs)
+}
+
+// Job holding a live lock on the table, if any
+pub fn held_by(database_id: &str, table: &str) -> Option<String> {
+    match decide(load(database_id, table, None).as_ref(), get_trusted_time(), stale_after()) {
+        LockDecision::Held(job_id) => Some(job_id),
+        _ => None,
+    }
+}
+
+impl JobLock {
+    // Takes the lock of a table for a new job, refused while another job holds it and keeps it alive
+    pub fn acquire(database_id: &str, table: &str) -> Result<JobLock, Box<dyn std::error::Error>> {
+        match JobLock::try_acquire(database_id, table, None)? {
+            Some(lock) => Ok(lock),
+            None => Err(format!("Table {} is locked by job {}", table, held_by(database_id, table).unwrap_or_default()).into()),
+        }
+    }
+
+    // None while another job holds the lock
+    pub fn try_acquire(database_id: &str, table: &str, partition: Option<usize>) -> Result<Option<JobLock>, Box<dyn std::error::Error>> {
+        let now = get_trusted_time();
+        match decide(load(database_id, table, partition).as_ref(), now, stale_after()) {
+            LockDecision::Held(_) => {
+                return Ok(None);
+            }
+            LockDecision::TakeOver(job_id) => {
+                notify::warning(&format!("Releasing stale lock of job {} on table {}", job_id, table));
+            }
+            LockDecision::Acquire => (),
+        }
+        let lock = JobLock {
+            job_id: hex::encode(crate::runtime::random::get_random_bytes(16)?),
+            database_id: database_id.to_string(),
+            table: table.to_string(),
+            partition,
+            acquired_at: now,
+            heartbeat_at: now,
+        };
+        lock.save()?;
+        remove_legacy_key(JOB_LOCK_TABLE, &lock.key(), &legacy_lock_key(database_id, table, partition));
+        Ok(Some(lock))
+    }
+
+    pub fn key(&self) -> String {
+        lock_key(&self.database_id, &self.table, self.partition)
+    }
+
+    fn save(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let serialized = serde_json::to_string(self)?;
+        storage::ledger_set(JOB_LOCK_TABLE, &self.key(), serialized.as_bytes())
+    }
+
+    // Renewed after each batch. Fails when another job took the lock over in the meantime.
+    pub fn heartbeat(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        match load(&self.database_id, &self.table, self.partition) {
+            Some(current) if current.job_id != self.job_id => {
+                Err(format!("Lock of table {} was taken over by job {}", self.table, current.job_id).into())
+            }
+            _ => {
+                self.heartbeat_at = get_trusted_time();
+                self.save()
+            }
+        }
+    }
+
+    pub fn release(&self) {
+        if load(&self.database_id, &self.table, self.partition).map(|current| current.job_id == self.job_id).unwrap_or(false) {
+            if let Err(err) = storage::ledger_remove(JOB_LOCK_TABLE, &self.key()) {
+                notify::warning(&format!("Failed to release lock of table {}: {}", self.table, err));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lock(heartbeat_at: u64) -> JobLock {
+        JobLock { job_id: "a".to_string(), database_id: "db".to_string(), table: "users".to_string(), partition: None, acquired_at: 0, heartbeat_at }
+    }
+
+    #[test]
+    fn test_decide() {
+        let stale_after = 300 * NANOS_PER_SECOND;
+        assert_eq!(decide(None, 10, stale_after), LockDecision::Acquire);
+        assert_eq!(decide(Some(&lock(10)), 10 + stale_after, stale_after), LockDecision::Held("a".to_string()));
+        assert_eq!(decide(Some(&lock(10)), 11 + stale_after, stale_after), LockDecision::TakeOver("a".to_string()));
+        // Locks are per table and partition, whatever the table is called
+        assert_ne!(lock_key("db", "users", None), lock_key("db", "orders", None));
+        assert_ne!(lock_key("db", "users#1", None), lock_key("db", "users", Some(1)));
+        assert_ne!(lock_key("db:users", "x", None), lock_key("db", "users:x", None));
+        assert_eq!(lock(0).key(), "db/users");
+    }
+}
@@ -0,0 +1,629 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{constraints::UniqueConstraint, crypto::{key_derivation_labels, AadTemplate, AAD_TEMPLATE_VERSION}, database::{Client, DatabaseError}, jobs::JobActivity, notify, partitions::EncryptionPartition, primary_key::PrimaryKey, rules::ColumnRule, storage, views::{EncryptedView, ViewState}};
+
+pub(crate) const ENCRYPTION_MANIFEST_TABLE: &str = "EncryptionManifestTable";
+// Attempts of EncryptionManifest::update before a conflict is reported to the caller
+const MANIFEST_UPDATE_ATTEMPTS: usize = 5;
+// Companion column holding the truncated HMAC of the encrypted columns of a row
+pub const ROW_MAC_COLUMN: &str = "_row_mac";
+// Appended to the name of a column encrypted with a blind index to name its companion column
+pub const BLIND_INDEX_SUFFIX: &str = "__idx";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EncryptedColumn {
+    pub name: String,
+    #[serde(default)]
+    pub aad_template: Option<String>,
+    #[serde(default)]
+    pub aad_template_version: Option<u32>,
+    // Index created on the ciphertext by create_search_index
+    #[serde(default)]
+    pub search_index: Option<String>,
+    // Checked against the plaintext before it is encrypted, the database cannot constrain ciphertext
+    #[serde(default)]
+    pub rules: Vec<ColumnRule>,
+    // Values are bound to their row by its primary key; columns recorded before row binding are lookup columns
+    #[serde(default)]
+    pub row_bound: bool,
+    // Companion column holding the blind index of the values, which are then encrypted with random IVs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub blind_index: Option<String>,
+    // SQL type the column had before it was altered to text to hold the ciphertext, restored by decrypt_columns
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub original_type: Option<String>,
+}
+
+impl EncryptedColumn {
+    pub fn new(name: &str, aad_template: Option<&AadTemplate>) -> Self {
+        Self {
+            name: name.to_string(),
+            aad_template: aad_template.map(|t| t.source.clone()),
+            aad_template_version: aad_template.map(|_| AAD_TEMPLATE_VERSION),
+            search_index: None,
+            rules: Vec::new(),
+            row_bound: false,
+            blind_index: None,
+            original_type: None,
+        }
+    }
+
+    // Equal values share a ciphertext, so the column can be looked up, grouped and constrained by value
+    pub fn is_deterministic(&self) -> bool {
+        self.aad_template.is_none() && !self.row_bound && self.blind_index.is_none()
+    }
+
+    pub fn parsed_aad_template(&self) -> Result<Option<AadTemplate>, Box<dyn std::error::Error>> {
+        match &self.aad_template {
+            Some(source) => Ok(Some(AadTemplate::parse(source)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+// Two-phase state of a table: Applying is written before the database work and flipped to Applied once it
+// has completed, so a crash in between leaves a record readers know to treat conservatively.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TableState {
+    #[default]
+    Applied,
+    Applying,
+}
+
+// Point where an encryption run stopped early: columns fully rewritten, and the column in progress
+// with the last primary key written (None when none of its rows was written yet)
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EncryptionWatermark {
+    pub completed_columns: Vec<String>,
+    pub column: String,
+    pub after_primary_key: Option<Value>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EncryptedTable {
+    pub table: String,
+    pub primary_key: PrimaryKey,
+    pub columns: Vec<EncryptedColumn>,
+    pub updated_at: u64,
+    #[serde(default)]
+    pub row_mac_column: Option<String>,
+    #[serde(default)]
+    pub state: TableState,
+    #[serde(default)]
+    pub watermark: Option<EncryptionWatermark>,
+    // Unique constraints dropped because the database could no longer enforce them, checked by bulk_insert instead
+    #[serde(default)]
+    pub relaxed_constraints: Vec<UniqueConstraint>,
+    // Primary-key ranges of a partitioned encryption run, each with its own watermark
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub partitions: Vec<EncryptionPartition>,
+    // Calls made to the encryption run, see jobs::reap_stale_jobs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub job: Option<JobActivity>,
+}
+
+// Record of the tables and columns encrypted for a database, keyed by database_id.
+// version is bumped by every save; a save based on an older version is refused.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EncryptionManifest {
+    pub database_id: String,
+    pub tables: Vec<EncryptedTable>,
+    #[serde(default)]
+    pub version: u64,
+    // Views materialized from the tables above, see views::create_encrypted_view
+    #[serde(default)]
+    pub views: Vec<EncryptedView>,
+    // Tables whose audit columns the template maintains, see audit_columns::manage_audit_columns
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub audit_column_tables: Vec<String>,
+}
+
+// Another writer saved the manifest after it was loaded
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ManifestConflict {
+    pub expected: u64,
+    pub found: u64,
+}
+
+impl std::fmt::Display for ManifestConflict {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "encryption manifest changed concurrently (loaded version {}, stored version {})", self.expected, self.found)
+    }
+}
+
+impl std::error::Error for ManifestConflict {}
+
+// Where manifest records live: the ledger, or an in-memory map in tests
+pub trait ManifestStore {
+    fn read(&self, database_id: &str) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error>>;
+    fn write(&self, database_id: &str, record: &[u8]) -> Result<(), Box<dyn std::error::Error>>;
+}
+
+pub struct LedgerManifestStore;
+
+impl ManifestStore for LedgerManifestStore {
+    fn read(&self, database_id: &str) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error>> {
+        Ok(crate::runtime::ledger::get_table(ENCRYPTION_MANIFEST_TABLE).get(database_id).ok())
+    }
+
+    fn write(&self, database_id: &str, record: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+        storage::ledger_set(ENCRYPTION_MANIFEST_TABLE, database_id, record)
+    }
+}
+
+impl EncryptionManifest {
+    pub fn new(database_id: &str) -> Self {
+        Self {
+            database_id: database_id.to_string(),
+            tables: Vec::new(),
+            version: 0,
+            views: Vec::new(),
+            audit_column_tables: Vec::new(),
+        }
+    }
+
+    // Loads the manifest of a database, an empty manifest is returned when none was recorded yet
+    pub fn load(database_id: &str) -> Result<EncryptionManifest, Box<dyn std::error::Error>> {
+        Self::load_from(&LedgerManifestStore, database_id)
+    }
+
+    pub fn load_from<S: ManifestStore>(store: &S, database_id: &str) -> Result<EncryptionManifest, Box<dyn std::error::Error>> {
+        match store.read(database_id)? {
+            Some(v) => {
+                let manifest: EncryptionManifest = match serde_json::from_slice(&v) {
+                    Ok(m) => m,
+                    Err(e) => {
+                        notify::warning(&format!("ERROR: failed to parse encryption manifest: {}", e));
+                        return Err(e.into());
+                    }
+                };
+                Ok(manifest)
+            },
+            None => Ok(EncryptionManifest::new(database_id))
+        }
+    }
+
+    pub fn save(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.save_to(&LedgerManifestStore)
+    }
+
+    // Writes the manifest if nobody saved it since it was loaded, and bumps its version
+    pub fn save_to<S: ManifestStore>(&mut self, store: &S) -> Result<(), Box<dyn std::error::Error>> {
+        let stored = Self::load_from(store, &self.database_id)?.version;
+        if stored != self.version {
+            return Err(ManifestConflict { expected: self.version, found: stored }.into());
+        }
+        self.version += 1;
+        let serialized = serde_json::to_string(self)?;
+        if let Err(err) = store.write(&self.database_id, serialized.as_bytes()) {
+            self.version -= 1;
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    // Applies a change on the latest manifest and saves it, reloading and reapplying the change when
+    // another writer got there first. Changes must only touch what their caller owns (e.g. one table).
+    pub fn update<F>(database_id: &str, mutate: F) -> Result<EncryptionManifest, Box<dyn std::error::Error>>
+    where
+        F: FnMut(&mut EncryptionManifest) -> Result<(), Box<dyn std::error::Error>>,
+    {
+        Self::update_in(&LedgerManifestStore, database_id, mutate)
+    }
+
+    pub fn update_in<S, F>(store: &S, database_id: &str, mut mutate: F) -> Result<EncryptionManifest, Box<dyn std::error::Error>>
+    where
+        S: ManifestStore,
+        F: FnMut(&mut EncryptionManifest) -> Result<(), Box<dyn std::error::Error>>,
+    {
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            let mut manifest = Self::load_from(store, database_id)?;
+            mutate(&mut manifest)?;
+            match manifest.save_to(store) {
+                Ok(()) => return Ok(manifest),
+                Err(err) if attempt < MANIFEST_UPDATE_ATTEMPTS && err.downcast_ref::<ManifestConflict>().is_some() => continue,
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    pub fn remove(database_id: &str) -> Result<(), Box<dyn std::error::Error>> {
+        storage::ledger_remove(ENCRYPTION_MANIFEST_TABLE, database_id)
+    }
+
+    pub fn table(&self, table: &str) -> Option<&EncryptedTable> {
+        self.tables.iter().find(|t| t.table == table)
+    }
+
+    pub fn column(&self, table: &str, column: &str) -> Option<&EncryptedColumn> {
+        self.table(table).and_then(|t| t.columns.iter().find(|c| c.name == column))
+    }
+
+    // Adds or replaces the entry of an encrypted column
+    pub fn record_column(&mut self, table: &str, primary_key: impl Into<PrimaryKey>, column: EncryptedColumn, updated_at: u64) {
+        let primary_key = primary_key.into();
+        match self.tables.iter_mut().find(|t| t.table == table) {
+            Some(entry) => {
+                entry.primary_key = primary_key;
+                entry.updated_at = updated_at;
+                match entry.columns.iter_mut().find(|c| c.name == column.name) {
+                    // Re-registering a column keeps the index built on its ciphertext, its rules unless new ones are given,
+                    // and the type it had before a resumed run found it altered to text
+                    Some(existing) => {
+                        let rules = if column.rules.is_empty() { std::mem::take(&mut existing.rules) } else { column.rules };
+                        let original_type = column.original_type.or(existing.original_type.take());
+                        *existing = EncryptedColumn { search_index: existing.search_index.take(), rules, original_type, ..column }
+                    }
+                    None => entry.columns.push(column),
+                }
+            }
+            None => self.tables.push(EncryptedTable {
+                table: table.to_string(),
+                primary_key,
+                columns: vec![column],
+                updated_at,
+                row_mac_column: None,
+                state: TableState::default(),
+                watermark: None,
+                relaxed_constraints: Vec::new(),
+                partitions: Vec::new(),
+                job: None,
+            }),
+        }
+    }
+
+    pub fn view(&self, name: &str) -> Option<&EncryptedView> {
+        self.views.iter().find(|v| v.name == name)
+    }
+
+    pub fn record_view(&mut self, view: EncryptedView) {
+        self.views.retain(|v| v.name != view.name);
+        self.views.push(view);
+    }
+
+    pub fn set_view_state(&mut self, name: &str, state: ViewState, reason: Option<String>) -> Result<(), Box<dyn std::error::Error>> {
+        let view = self.views.iter_mut().find(|v| v.name == name).ok_or(format!("No view {}", name))?;
+        view.state = state;
+        view.broken_reason = reason;
+        Ok(())
+    }
+
+    pub fn set_view_refreshed(&mut self, name: &str, refreshed_at: u64) -> Result<(), Box<dyn std::error::Error>> {
+        let view = self.views.iter_mut().find(|v| v.name == name).ok_or(format!("No view {}", name))?;
+        view.refreshed_at = Some(refreshed_at);
+        Ok(())
+    }
+
+    // Whether the columns of a table may hold a mix of ciphertext and plaintext
+    pub fn is_applying(&self, table: &str) -> bool {
+        self.table(table).map(|t| t.state == TableState::Applying).unwrap_or(false)
+    }
+
+    pub fn set_table_state(&mut self, table: &str, state: TableState, updated_at: u64) -> Result<(), Box<dyn std::error::Error>> {
+        let entry = self.tables.iter_mut().find(|t| t.table == table).ok_or(format!("Table {} has no encrypted columns", table))?;
+        entry.state = state;
+        entry.updated_at = updated_at;
+        Ok(())
+    }
+
+    // Records constraints about to be dropped; one already recorded keeps its original definition
+    pub fn record_relaxed_constraints(&mut self, table: &str, constraints: &[UniqueConstraint]) -> Result<(), Box<dyn std::error::Error>> {
+        let entry = self.tables.iter_mut().find(|t| t.table == table).ok_or(format!("Table {} has no encrypted columns", table))?;
+        for constraint in constraints {
+            if !entry.relaxed_constraints.iter().any(|c| c.name == constraint.name) {
+                entry.relaxed_constraints.push(constraint.clone());
+            }
+        }
+        Ok(())
+    }
+
+    // Watermark of an interrupted encryption run, only meaningful while the table is Applying
+    pub fn watermark(&self, table: &str) -> Option<&EncryptionWatermark> {
+        self.table(table).filter(|t| t.state == TableState::Applying).and_then(|t| t.watermark.as_ref())
+    }
+
+    pub fn set_watermark(&mut self, table: &str, watermark: Option<EncryptionWatermark>) -> Result<(), Box<dyn std::error::Error>> {
+        let entry = self.tables.iter_mut().find(|t| t.table == table).ok_or(format!("Table {} has no encrypted columns", table))?;
+        entry.watermark = watermark;
+        Ok(())
+    }
+
+    // A call to the encryption run of the table: it is running again, whoever makes it
+    pub fn record_heartbeat(&mut self, table: &str, owner: &str, now: u64) -> Result<(), Box<dyn std::error::Error>> {
+        let entry = self.tables.iter_mut().find(|t| t.table == table).ok_or(format!("Table {} has no encrypted columns", table))?;
+        entry.job = Some(JobActivity { owner: owner.to_string(), heartbeat_at: now, failure: None, stalled: None });
+        Ok(())
+    }
+
+    // Error that stopped the last call of an unpartitioned run
+    pub fn record_failure(&mut self, table: &str, failure: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let entry = self.tables.iter_mut().find(|t| t.table == table).ok_or(format!("Table {} has no encrypted columns", table))?;
+        if let Some(job) = entry.job.as_mut() {
+            job.failure = Some(failure.to_string());
+        }
+        Ok(())
+    }
+
+    // Partitions of an interrupted partitioned run, only meaningful while the table is Applying
+    pub fn partitions(&self, table: &str) -> &[EncryptionPartition] {
+        self.table(table).filter(|t| t.state == TableState::Applying).map(|t| t.partitions.as_slice()).unwrap_or(&[])
+    }
+
+    pub fn set_partitions(&mut self, table: &str, partitions: Vec<EncryptionPartition>) -> Result<(), Box<dyn std::error::Error>> {
+        let entry = self.tables.iter_mut().find(|t| t.table == table).ok_or(format!("Table {} has no encrypted columns", table))?;
+        entry.partitions = partitions;
+        Ok(())
+    }
+
+    pub fn set_partition(&mut self, table: &str, partition: EncryptionPartition) -> Result<(), Box<dyn std::error::Error>> {
+        let entry = self.tables.iter_mut().find(|t| t.table == table).ok_or(format!("Table {} has no encrypted columns", table))?;
+        let slot = entry.partitions.iter_mut().find(|p| p.index == partition.index).ok_or(format!("Table {} has no partition {}", table, partition.index))?;
+        *slot = partition;
+        Ok(())
+    }
+
+    // Drops a column that turned out not to be encrypted, and the table once it has no column left
+    pub fn remove_column(&mut self, table: &str, column: &str) {
+        if let Some(entry) = self.tables.iter_mut().find(|t| t.table == table) {
+            entry.columns.retain(|c| c.name != column);
+        }
+        self.tables.retain(|t| !t.columns.is_empty());
+    }
+
+    pub fn set_search_index(&mut self, table: &str, column: &str, index: Option<String>, updated_at: u64) -> Result<(), Box<dyn std::error::Error>> {
+        let entry = self.tables.iter_mut().find(|t| t.table == table).ok_or(format!("Table {} has no encrypted columns", table))?;
+        let encrypted = entry.columns.iter_mut().find(|c| c.name == column).ok_or(format!("Column {}.{} is not encrypted", table, column))?;
+        encrypted.search_index = index;
+        entry.updated_at = updated_at;
+        Ok(())
+    }
+
+    pub fn set_column_rules(&mut self, table: &str, column: &str, rules: Vec<ColumnRule>, updated_at: u64) -> Result<(), Box<dyn std::error::Error>> {
+        let entry = self.tables.iter_mut().find(|t| t.table == table).ok_or(format!("Table {} has no encrypted columns", table))?;
+        let encrypted = entry.columns.iter_mut().find(|c| c.name == column).ok_or(format!("Column {}.{} is not encrypted", table, column))?;
+        encrypted.rules = rules;
+        entry.updated_at = updated_at;
+        Ok(())
+    }
+
+    // Encrypted columns among the given ones that predicates would have to scan sequentially
+    pub fn unindexed_columns(&self, table: &str, columns: &[&str]) -> Vec<String> {
+        columns.iter()
+            .filter(|c| self.column(table, c).map(|e| e.search_index.is_none()).unwrap_or(false))
+            .map(|c| format!("{}.{}", table, c))
+            .collect()
+    }
+
+    // Columns among the given ones that a finished run already encrypted; a run still applying is resumed instead
+    pub fn already_encrypted(&self, table: &str, columns: &[String]) -> Vec<String> {
+        if self.is_applying(table) {
+            return Vec::new();
+        }
+        columns.iter().filter(|c| self.column(table, c).is_some()).cloned().collect()
+    }
+
+    // Encrypted columns of every table with the labels their keys are derived with, for list_encrypted_columns
+    pub fn listing(&self) -> Value {
+        let tables: Vec<Value> = self.tables.iter().map(|t| json!({
+            "table": t.table,
+            "primary_key": t.primary_key,
+            "state": t.state,
+            "updated_at": t.updated_at,
+            "columns": t.columns.iter().map(|c| {
+                let (salt, info) = key_derivation_labels(&t.table, &c.name);
+                json!({ "name": c.name, "aad_template": c.aad_template, "row_bound": c.row_bound, "blind_index": c.blind_index, "search_index": c.search_index, "key_salt": salt, "key_info": info })
+            }).collect::<Vec<Value>>(),
+        })).collect();
+        json!({ "database_id": self.database_id, "tables": tables })
+    }
+
+    pub fn manages_audit_columns(&self, table: &str) -> bool {
+        self.audit_column_tables.iter().any(|t| t == table)
+    }
+
+    pub fn set_audit_columns(&mut self, table: &str, managed: bool) {
+        self.audit_column_tables.retain(|t| t != table);
+        if managed {
+            self.audit_column_tables.push(table.to_string());
+        }
+    }
+
+    // Marks the row MAC companion column of an already recorded table
+    pub fn record_row_mac_column(&mut self, table: &str, row_mac_column: &str, updated_at: u64) -> Result<(), Box<dyn std::error::Error>> {
+        let entry = self.tables.iter_mut().find(|t| t.table == table).ok_or(format!("Table {} has no encrypted columns", table))?;
+        entry.row_mac_column = Some(row_mac_column.to_string());
+        entry.updated_at = updated_at;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ListEncryptedColumnsInput {
+    pub database_id: String,
+}
+
+// Tables and columns encrypted for a database, so that callers of read_encrypted_table need not keep track
+pub fn list_encrypted_columns(cmd: String) {
+    let input: ListEncryptedColumnsInput = match notify::parse_input(&cmd) {
+        Some(input) => input,
+        None => return,
+    };
+    if let Err(err) = Client::load(input.database_id.clone()) {
+        notify::error_with_code(&format!("Failed to load client: {}", err), err.code());
+        return;
+    }
+    match EncryptionManifest::load(&input.database_id) {
+        Ok(manifest) => notify::result(&manifest.listing()),
+        Err(err) => notify::error_with_code(&format!("Failed to load encryption manifest: {}", err), DatabaseError::code_of(err.as_ref())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, collections::HashMap};
+
+    use crate::partitions::{plan_partitions, PartitionStatus};
+
+    use super::*;
+
+    // In-memory stand-in for the ledger
+    #[derive(Default)]
+    struct MemoryStore {
+        records: RefCell<HashMap<String, Vec<u8>>>,
+    }
+
+    impl ManifestStore for MemoryStore {
+        fn read(&self, database_id: &str) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error>> {
+            Ok(self.records.borrow().get(database_id).cloned())
+        }
+
+        fn write(&self, database_id: &str, record: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+            self.records.borrow_mut().insert(database_id.to_string(), record.to_vec());
+            Ok(())
+        }
+    }
+
+    fn watermark(column: &str) -> EncryptionWatermark {
+        EncryptionWatermark { completed_columns: vec![], column: column.to_string(), after_primary_key: Some(Value::from(42)) }
+    }
+
+    // Progress of one encryption job on its own table
+    fn record_progress(store: &MemoryStore, table: &str, column: &str) -> Result<EncryptionManifest, Box<dyn std::error::Error>> {
+        EncryptionManifest::update_in(store, "db", |manifest| {
+            manifest.record_column(table, "id", EncryptedColumn::new(column, None), 0);
+            manifest.set_table_state(table, TableState::Applying, 0)?;
+            manifest.set_watermark(table, Some(watermark(column)))
+        })
+    }
+
+    #[test]
+    fn test_interleaved_jobs_keep_their_progress() {
+        let store = MemoryStore::default();
+        let mut interleaved = false;
+        let manifest = EncryptionManifest::update_in(&store, "db", |manifest| {
+            // Job B saves its progress while job A is between its load and its save
+            if !interleaved {
+                interleaved = true;
+                record_progress(&store, "orders", "card_number")?;
+            }
+            manifest.record_column("users", "id", EncryptedColumn::new("email", None), 0);
+            manifest.set_table_state("users", TableState::Applying, 0)?;
+            manifest.set_watermark("users", Some(watermark("email")))
+        }).unwrap();
+
+        assert_eq!(manifest.version, 2);
+        let stored = EncryptionManifest::load_from(&store, "db").unwrap();
+        assert_eq!(stored.watermark("users"), Some(&watermark("email")));
+        assert_eq!(stored.watermark("orders"), Some(&watermark("card_number")));
+    }
+
+    // Calls working on different partitions of a table keep each other's progress
+    #[test]
+    fn test_interleaved_partitions() {
+        let store = MemoryStore::default();
+        EncryptionManifest::update_in(&store, "db", |manifest| {
+            manifest.record_column("users", "id", EncryptedColumn::new("email", None), 0);
+            manifest.set_table_state("users", TableState::Applying, 0)?;
+            manifest.set_partitions("users", plan_partitions(&[Value::from(1), Value::from(9), Value::from(5)], 0))
+        }).unwrap();
+        let mut interleaved = false;
+        EncryptionManifest::update_in(&store, "db", |manifest| {
+            if !interleaved {
+                interleaved = true;
+                EncryptionManifest::update_in(&store, "db", |manifest| {
+                    let mut second = manifest.partitions("users")[1].clone();
+                    second.status = PartitionStatus::Done;
+                    manifest.set_partition("users", second)
+                })?;
+            }
+            let mut first = manifest.partitions("users")[0].clone();
+            first.status = PartitionStatus::InProgress;
+            first.watermark = Some(watermark("email"));
+            manifest.set_partition("users", first)
+        }).unwrap();
+
+        let stored = EncryptionManifest::load_from(&store, "db").unwrap();
+        let statuses: Vec<PartitionStatus> = stored.partitions("users").iter().map(|p| p.status).collect();
+        assert_eq!(statuses, vec![PartitionStatus::InProgress, PartitionStatus::Done]);
+        assert_eq!(stored.partitions("users")[0].watermark, Some(watermark("email")));
+        assert!(stored.partitions("orders").is_empty());
+    }
+
+    #[test]
+    fn test_stale_save_is_refused() {
+        let store = MemoryStore::default();
+        let mut stale = EncryptionManifest::load_from(&store, "db").unwrap();
+        record_progress(&store, "orders", "card_number").unwrap();
+        stale.record_column("users", "id", EncryptedColumn::new("email", None), 0);
+        let err = stale.save_to(&store).unwrap_err();
+        assert_eq!(err.downcast_ref::<ManifestConflict>(), Some(&ManifestConflict { expected: 0, found: 1 }));
+    }
+
+    #[test]
+    fn test_table_state() {
+        let mut manifest = EncryptionManifest::new("db");
+        manifest.record_column("users", "id", EncryptedColumn::new("email", None), 0);
+        manifest.record_column("users", "id", EncryptedColumn::new("phone", None), 0);
+        assert!(!manifest.is_applying("users"));
+        manifest.set_table_state("users", TableState::Applying, 1).unwrap();
+        assert!(manifest.is_applying("users"));
+        assert!(manifest.set_table_state("orders", TableState::Applied, 1).is_err());
+
+        manifest.remove_column("users", "phone");
+        assert!(manifest.column("users", "phone").is_none());
+        manifest.remove_column("users", "email");
+        assert!(manifest.table("users").is_none());
+    }
+
+    #[test]
+    fn test_original_type_survives_resume() {
+        let mut manifest = EncryptionManifest::new("db");
+        let altered = EncryptedColumn { original_type: Some("integer".to_string()), ..EncryptedColumn::new("age", None) };
+        manifest.record_column("users", "id", altered, 0);
+        // The resumed run finds the column text already
+        manifest.record_column("users", "id", EncryptedColumn::new("age", None), 1);
+        assert_eq!(manifest.column("users", "age").unwrap().original_type.as_deref(), Some("integer"));
+        assert!(!serde_json::to_string(&EncryptedColumn::new("email", None)).unwrap().contains("original_type"));
+    }
+
+    #[test]
+    fn test_already_encrypted_and_listing() {
+        let mut manifest = EncryptionManifest::new("db");
+        manifest.record_column("users", "id", EncryptedColumn::new("email", None), 3);
+        let columns = vec!["email".to_string(), "phone".to_string()];
+        assert_eq!(manifest.already_encrypted("users", &columns), vec!["email".to_string()]);
+        assert!(manifest.already_encrypted("orders", &columns).is_empty());
+        manifest.set_table_state("users", TableState::Applying, 4).unwrap();
+        assert!(manifest.already_encrypted("users", &columns).is_empty());
+
+        let listing = manifest.listing();
+        assert_eq!(listing["tables"][0]["primary_key"], "id");
+        assert_eq!(listing["tables"][0]["state"], "applying");
+        assert_eq!(listing["tables"][0]["columns"][0]["key_salt"], "klave-salt-encryption-'users'");
+        assert_eq!(listing["tables"][0]["columns"][0]["key_info"], "klave-info-encryption-'email'");
+    }
+
+    #[test]
+    fn test_search_index_survives_re_registration() {
+        let mut manifest = EncryptionManifest::new("db");
+        manifest.record_column("users", "id", EncryptedColumn::new("email", None), 0);
+        manifest.record_column("users", "id", EncryptedColumn::new("phone", None), 0);
+        manifest.set_search_index("users", "email", Some("kl_search_users_email".to_string()), 1).unwrap();
+        assert!(manifest.set_search_index("users", "age", None, 1).is_err());
+        assert_eq!(manifest.unindexed_columns("users", &["email", "phone", "age"]), vec!["users.phone".to_string()]);
+
+        manifest.record_column("users", "id", EncryptedColumn::new("email", None), 2);
+        assert_eq!(manifest.column("users", "email").unwrap().search_index.as_deref(), Some("kl_search_users_email"));
+    }
+
+    #[test]
+    fn test_records_without_state_are_applied() {
+        let table: EncryptedTable = serde_json::from_str(r#"{"table":"users","primary_key":"id","columns":[],"updated_at":0}"#).unwrap();
+        assert_eq!(table.state, TableState::Applied);
+    }
+}
@@ -0,0 +1,195 @@
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{audit, database::{Client, Clients}, notify, settings::require_admin};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MigrationStep {
+    // Clients registered by the plaintext template have no master key; creating one leaves their data alone
+    CreateMasterKeys,
+    // Policies kept in the client record by earlier versions move to CLIENT_POLICY_TABLE
+    SplitClientPolicies,
+}
+
+// In the order they are applied
+const MIGRATION_STEPS: [MigrationStep; 2] = [MigrationStep::CreateMasterKeys, MigrationStep::SplitClientPolicies];
+
+// Migrations older deployments may expect that this version has nothing to do for
+const NOT_APPLICABLE: [(&str, &str); 3] = [
+    ("split_client_list", "clients are listed in the single \"ALL\" record, which is still the current layout"),
+    ("add_record_mac", "client records carry no integrity MAC in this version"),
+    ("seal_passwords", "client passwords are not sealed in this version"),
+];
+
+fn default_dry_run() -> bool {
+    true
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MigrateDeploymentInput {
+    // Only reports the plan
+    #[serde(default = "default_dry_run")]
+    pub dry_run: bool,
+}
+
+// What the inspection found about one registered client
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct ClientFinding {
+    pub database_id: String,
+    pub missing_master_key: bool,
+    pub legacy_policies: bool,
+    // Restored from a state snapshot: it has a key name but its keys must be re-imported, not created
+    pub needs_key_attach: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unreadable: Option<String>,
+}
+
+impl ClientFinding {
+    fn inspect(database_id: &str) -> ClientFinding {
+        match Client::load(database_id.to_string()) {
+            Ok(client) => ClientFinding {
+                database_id: database_id.to_string(),
+                missing_master_key: client.master_key_name().is_none(),
+                legacy_policies: client.has_legacy_policies(),
+                needs_key_attach: client.needs_key_attach(),
+                unreadable: None,
+            },
+            Err(err) => ClientFinding { database_id: database_id.to_string(), unreadable: Some(err.to_string()), ..Default::default() },
+        }
+    }
+
+    fn needs(&self, step: MigrationStep) -> bool {
+        match step {
+            MigrationStep::CreateMasterKeys => self.missing_master_key && !self.needs_key_attach,
+            MigrationStep::SplitClientPolicies => self.legacy_policies,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StepStatus {
+    // Nothing left to migrate, an earlier run may have done it
+    Done,
+    Planned,
+    Applied,
+    Failed,
+    // An earlier step failed; the next run resumes there
+    NotRun,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct StepOutcome {
+    pub step: MigrationStep,
+    pub status: StepStatus,
+    // Clients the step has to migrate
+    pub clients: Vec<String>,
+    pub migrated: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+// Steps are planned from the state of the records, so a run after a partial failure only finds what is left
+pub fn plan(findings: &[ClientFinding]) -> Vec<StepOutcome> {
+    MIGRATION_STEPS.iter().map(|step| {
+        let clients: Vec<String> = findings.iter().filter(|f| f.needs(*step)).map(|f| f.database_id.clone()).collect();
+        let status = if clients.is_empty() { StepStatus::Done } else { StepStatus::Planned };
+        StepOutcome { step: *step, status, clients, migrated: Vec::new(), error: None }
+    }).collect()
+}
+
+fn apply_step(step: MigrationStep, database_id: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let mut client = Client::load(database_id.to_string())?;
+    match step {
+        MigrationStep::CreateMasterKeys => client.ensure_master_key(),
+        MigrationStep::SplitClientPolicies => Ok(client.save_policies()?),
+    }
+}
+
+// Applies the planned steps in order and stops at the first failure
+fn apply(steps: &mut [StepOutcome]) {
+    let mut failed = false;
+    for outcome in steps.iter_mut().filter(|o| o.status == StepStatus::Planned) {
+        if failed {
+            outcome.status = StepStatus::NotRun;
+            continue;
+        }
+        for database_id in outcome.clients.iter() {
+            match apply_step(outcome.step, database_id) {
+                Ok(()) => outcome.migrated.push(database_id.clone()),
+                Err(err) => {
+                    outcome.error = Some(format!("client {}: {}", database_id, err));
+                    failed = true;
+                    break;
+                }
+            }
+        }
+        outcome.status = if failed { StepStatus::Failed } else { StepStatus::Applied };
+    }
+}
+
+// Upgrades a deployment of the plaintext template in place, see MIGRATION_STEPS
+pub fn migrate_deployment(cmd: String) {
+    let input: MigrateDeploymentInput = match notify::parse_input(&cmd) {
+        Some(input) => input,
+        None => return,
+    };
+    if let Err(err) = require_admin("migrate the deployment") {
+        audit::record("migrate_deployment", None, "refused", json!({}));
+        notify::error(&err);
+        return;
+    }
+    let clients = match Clients::load_all() {
+        Ok(clients) => clients,
+        Err(err) => {
+            notify::error_with_code(&format!("Failed to load clients: {}", err), err.code());
+            return;
+        }
+    };
+    let findings: Vec<ClientFinding> = clients.clients.iter().map(|id| ClientFinding::inspect(id)).collect();
+    let mut steps = plan(&findings);
+    if !input.dry_run {
+        apply(&mut steps);
+    }
+    let not_applicable: Vec<_> = NOT_APPLICABLE.iter().map(|(step, reason)| json!({ "step": step, "reason": reason })).collect();
+    let failed = steps.iter().any(|s| s.status == StepStatus::Failed);
+    if !input.dry_run {
+        let migrated: usize = steps.iter().map(|s| s.migrated.len()).sum();
+        audit::record("migrate_deployment", None, if failed { "failure" } else { "success" }, json!({ "migrated": migrated }));
+    }
+    notify::result(&json!({ "dry_run": input.dry_run, "clients": findings, "steps": steps, "not_applicable": not_applicable }));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn finding(database_id: &str, missing_master_key: bool, legacy_policies: bool) -> ClientFinding {
+        ClientFinding { database_id: database_id.to_string(), missing_master_key, legacy_policies, ..Default::default() }
+    }
+
+    #[test]
+    fn test_plan() {
+        let mut restored = finding("restored", true, false);
+        restored.needs_key_attach = true;
+        let findings = vec![finding("a", true, false), finding("b", false, true), finding("c", false, false), restored];
+        let steps = plan(&findings);
+        assert_eq!(steps[0].step, MigrationStep::CreateMasterKeys);
+        assert_eq!(steps[0].clients, vec!["a".to_string()]);
+        assert_eq!(steps[1].clients, vec!["b".to_string()]);
+        assert!(steps.iter().all(|s| s.status == StepStatus::Planned));
+
+        // Once migrated, a run finds nothing left to do
+        let steps = plan(&[finding("a", false, false), finding("b", false, false)]);
+        assert!(steps.iter().all(|s| s.status == StepStatus::Done));
+        assert_eq!(serde_json::to_value(&steps[0]).unwrap(), json!({ "step": "create_master_keys", "status": "done", "clients": [], "migrated": [] }));
+    }
+
+    #[test]
+    fn test_input() {
+        assert!(serde_json::from_value::<MigrateDeploymentInput>(json!({})).unwrap().dry_run);
+        assert!(serde_json::from_value::<MigrateDeploymentInput>(json!({ "dryRun": false })).is_err());
+    }
+}
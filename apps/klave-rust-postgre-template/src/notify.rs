@@ -0,0 +1,229 @@
+use std::cell::RefCell;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{errors::{self, ErrorCode, ErrorResponse, UnknownFieldPolicy}, faults};
+
+// Kind of a notification. Clients read the single result and may ignore the other channels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Channel {
+    Result,
+    Progress,
+    Warning,
+    Debug,
+    // The called route name is deprecated, the payload names its successor
+    Deprecation,
+}
+
+// How results and errors of a route are serialized. Versioned route aliases opt into the envelope,
+// the original route names keep the legacy shape.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ResultShape {
+    #[default]
+    Legacy,
+    Envelope,
+}
+
+impl ResultShape {
+    pub fn result(self, payload: Value) -> Value {
+        match self {
+            ResultShape::Legacy => payload,
+            ResultShape::Envelope => json!({ "success": true, "data": payload }),
+        }
+    }
+
+    // The legacy shape gained the code next to its message, the envelope carries the whole ErrorResponse
+    pub fn error(self, message: &str, details: Option<Value>) -> Value {
+        self.error_response(ErrorResponse::new(message, details))
+    }
+
+    pub fn error_response(self, response: ErrorResponse) -> Value {
+        match (self, response.details) {
+            (ResultShape::Legacy, None) => json!({ "error": response.message, "code": response.code }),
+            (ResultShape::Legacy, Some(details)) => json!({ "error": response.message, "code": response.code, "details": details }),
+            (ResultShape::Envelope, details) => json!({ "success": false, "error": { "code": response.code, "message": response.message, "details": details } }),
+        }
+    }
+}
+
+// Every notification is sent as a frame; seq orders the frames of one invocation, trace_id tells invocations apart
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Frame {
+    pub channel: Channel,
+    pub seq: u64,
+    pub trace_id: String,
+    pub payload: Value,
+}
+
+// Framing state of one invocation. Only the first result goes out on the result channel,
+// later ones are demoted to warnings so that a client never sees two answers.
+#[derive(Debug, Clone, Default)]
+pub struct Framer {
+    trace_id: String,
+    seq: u64,
+    result_sent: bool,
+    shape: ResultShape,
+    input_policy: UnknownFieldPolicy,
+}
+
+impl Framer {
+    pub fn new(trace_id: &str) -> Self {
+        Self { trace_id: trace_id.to_string(), seq: 0, result_sent: false, shape: ResultShape::default(), input_policy: UnknownFieldPolicy::default() }
+    }
+
+    pub fn frame(&mut self, channel: Channel, payload: Value) -> Frame {
+        let (channel, payload) = match channel {
+            Channel::Result if self.result_sent => (Channel::Warning, json!({ "superseded_result": payload })),
+            Channel::Result => {
+                self.result_sent = true;
+                (Channel::Result, payload)
+            }
+            other => (other, payload),
+        };
+        let frame = Frame { channel, seq: self.seq, trace_id: self.trace_id.clone(), payload };
+        self.seq += 1;
+        frame
+    }
+
+    // Closing frame: a null result when the handler never produced one
+    pub fn finish(&mut self) -> Option<Frame> {
+        if self.result_sent {
+            None
+        } else {
+            let payload = self.shape.result(Value::Null);
+            Some(self.frame(Channel::Result, payload))
+        }
+    }
+
+    pub fn shape(&self) -> ResultShape {
+        self.shape
+    }
+
+    pub fn set_shape(&mut self, shape: ResultShape) {
+        self.shape = shape;
+    }
+
+    pub fn input_policy(&self) -> UnknownFieldPolicy {
+        self.input_policy
+    }
+
+    pub fn set_input_policy(&mut self, policy: UnknownFieldPolicy) {
+        self.input_policy = policy;
+    }
+}
+
+thread_local! {
+    static FRAMER: RefCell<Framer> = RefCell::new(Framer::default());
+}
+
+fn send(channel: Channel, mut payload: Value) {
+    // Results of an invocation that had faults injected say so
+    if channel == Channel::Result {
+        if let Some(injected) = faults::stamp(&mut payload) {
+            send(Channel::Warning, injected);
+        }
+    }
+    let frame = FRAMER.with(|f| f.borrow_mut().frame(channel, payload));
+    let _ = crate::runtime::notifier::send_json(&frame);
+}
+
+// Runs a route handler with a fresh trace id and makes sure exactly one result frame is sent
+pub fn invoke<F: FnOnce(String)>(cmd: String, handler: F) {
+    let trace_id = crate::runtime::random::get_random_bytes(8).map(hex::encode).unwrap_or_default();
+    FRAMER.with(|f| *f.borrow_mut() = Framer::new(&trace_id));
+    handler(cmd);
+    if let Some(frame) = FRAMER.with(|f| f.borrow_mut().finish()) {
+        let _ = crate::runtime::notifier::send_json(&frame);
+    }
+}
+
+fn shape() -> ResultShape {
+    FRAMER.with(|f| f.borrow().shape())
+}
+
+// For a handler whose legacy answer is not the structured one it gives under the envelope
+pub fn result_shape() -> ResultShape {
+    shape()
+}
+
+// Serialization of the results of this invocation, set before the handler runs
+pub fn set_shape(shape: ResultShape) {
+    FRAMER.with(|f| f.borrow_mut().set_shape(shape));
+}
+
+pub fn result<T: Serialize + ?Sized>(payload: &T) {
+    send(Channel::Result, shape().result(serde_json::to_value(payload).unwrap_or(Value::Null)));
+}
+
+// Failure of the route, sent as its result
+pub fn error(message: &str) {
+    send(Channel::Result, shape().error(message, None));
+}
+
+// Failure of a typed error, e.g. DatabaseError::code; None leaves the code to the wording of the message
+pub fn error_with_code(message: &str, code: Option<ErrorCode>) {
+    send(Channel::Result, shape().error_response(ErrorResponse::with_code(code, message, None)));
+}
+
+// Failure with structured details the caller can act on
+pub fn error_with_details<T: Serialize + ?Sized>(message: &str, details: &T) {
+    send(Channel::Result, shape().error(message, Some(serde_json::to_value(details).unwrap_or(Value::Null))));
+}
+
+// Handling of unknown input fields for this invocation, set before the handler runs
+pub fn set_input_policy(policy: UnknownFieldPolicy) {
+    FRAMER.with(|f| f.borrow_mut().set_input_policy(policy));
+}
+
+// Parses the payload of a route, see errors::parse_input. A payload that cannot be used is answered
+// with the path of the offending value and None is returned; dropped unknown fields go out as warnings.
+pub fn parse_input<T: serde::de::DeserializeOwned>(cmd: &str) -> Option<T> {
+    let policy = FRAMER.with(|f| f.borrow().input_policy());
+    match errors::parse_input::<T>(cmd, policy) {
+        Ok((input, ignored)) => {
+            for field in ignored.iter() {
+                warning(&field.describe());
+            }
+            Some(input)
+        },
+        Err((message, details)) => {
+            send(Channel::Result, shape().error(&message, Some(details)));
+            None
+        }
+    }
+}
+
+pub fn deprecation<T: Serialize + ?Sized>(payload: &T) {
+    send(Channel::Deprecation, serde_json::to_value(payload).unwrap_or(Value::Null));
+}
+
+pub fn progress<T: Serialize + ?Sized>(payload: &T) {
+    send(Channel::Progress, serde_json::to_value(payload).unwrap_or(Value::Null));
+}
+
+pub fn warning(message: &str) {
+    send(Channel::Warning, Value::String(message.to_string()));
+}
+
+pub fn debug(message: &str) {
+    send(Channel::Debug, Value::String(message.to_string()));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exactly_one_result() {
+        let mut framer = Framer::new("t");
+        assert_eq!(framer.frame(Channel::Debug, Value::Null).seq, 0);
+        assert_eq!(framer.finish().map(|f| (f.channel, f.seq)), Some((Channel::Result, 1)));
+        assert!(framer.finish().is_none());
+
+        let frame = Framer::new("t").frame(Channel::Result, json!(1));
+        assert_eq!(serde_json::to_value(&frame).unwrap(), json!({ "channel": "result", "seq": 0, "trace_id": "t", "payload": 1 }));
+    }
+}
@@ -0,0 +1,316 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    database::{Client, DatabaseError},
+    jobs::job_status,
+    manifest::{EncryptionManifest, EncryptionWatermark, TableState},
+    notify,
+    primary_key::{key_literal, PrimaryKey},
+    utils::{quote_ident, sql_literal},
+};
+
+// Upper bound on the partitions of an encryption job, each one is a manifest record
+pub const MAX_PARTITIONS: usize = 64;
+
+// Primary keys in (after, up_to], an open end when None
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct KeyRange {
+    pub after: Option<Value>,
+    pub up_to: Option<Value>,
+}
+
+impl KeyRange {
+    // The primary key is spliced as given, quoted by the caller; a composite key is compared as a row
+    pub fn predicate(&self, primary_key: &str) -> Option<String> {
+        let mut bounds = Vec::new();
+        if let Some(after) = self.after.as_ref() {
+            bounds.push(format!("{} > {}", primary_key, key_literal(after)));
+        }
+        if let Some(up_to) = self.up_to.as_ref() {
+            bounds.push(format!("{} <= {}", primary_key, sql_literal(up_to)));
+        }
+        if bounds.is_empty() {
+            return None;
+        }
+        Some(bounds.join(" AND "))
+    }
+
+    // Same upper bound, resuming after the given key
+    pub fn resumed_after(&self, after: Option<Value>) -> KeyRange {
+        KeyRange { after: after.or_else(|| self.after.clone()), up_to: self.up_to.clone() }
+    }
+}
+
+// Keyset pagination over a range: each page is read with `ORDER BY pk LIMIT batch_size` after the last key seen
+#[derive(Debug, Clone)]
+pub struct KeysetPager {
+    range: KeyRange,
+    batch_size: usize,
+    key: PrimaryKey,
+    done: bool,
+}
+
+impl KeysetPager {
+    pub fn new(range: KeyRange, batch_size: usize, key: &PrimaryKey) -> KeysetPager {
+        KeysetPager { range, batch_size: batch_size.max(1), key: key.clone(), done: false }
+    }
+
+    // Bounds of the next page
+    pub fn page(&self) -> &KeyRange {
+        &self.range
+    }
+
+    pub fn batch_size(&self) -> usize {
+        self.batch_size
+    }
+
+    // Last primary key of the pages seen so far
+    pub fn last_key(&self) -> Option<Value> {
+        self.range.after.clone()
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    // Moves past a page whose rows start with the key columns; a short page is the last one
+    pub fn advance(&mut self, page: &[Vec<Value>]) {
+        if page.len() < self.batch_size {
+            self.done = true;
+        }
+        if let Some(row) = page.last() {
+            self.range.after = Some(self.key.leading_value(row));
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PartitionStatus {
+    #[default]
+    Pending,
+    // Stopped at its watermark when the execution budget ran low
+    InProgress,
+    Done,
+    // Stopped on an error, claimed again only once no other partition is left
+    Failed,
+}
+
+// Primary-key range of a partitioned encryption job with its own progress
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EncryptionPartition {
+    pub index: usize,
+    pub range: KeyRange,
+    #[serde(default)]
+    pub status: PartitionStatus,
+    #[serde(default)]
+    pub watermark: Option<EncryptionWatermark>,
+    #[serde(default)]
+    pub error: Option<String>,
+    #[serde(default)]
+    pub updated_at: u64,
+}
+
+// Min, max and the quantiles splitting the primary keys into `partitions` ranges, in a single row
+pub fn distribution_query(table: &str, primary_key: &str, partitions: usize) -> Result<String, Box<dyn std::error::Error>> {
+    let primary_key = quote_ident(primary_key)?;
+    let mut selected = vec![format!("min({})", primary_key), format!("max({})", primary_key)];
+    for i in 1..partitions {
+        selected.push(format!("percentile_disc({}) WITHIN GROUP (ORDER BY {})", i as f64 / partitions as f64, primary_key));
+    }
+    Ok(format!("SELECT {} FROM {}", selected.join(", "), quote_ident(table)?))
+}
+
+// Ranges between the sampled quantiles. Repeated quantiles of a skewed distribution give fewer partitions,
+// an empty table a single one. The outer ranges are open so that keys outside min/max are covered too.
+pub fn plan_partitions(distribution: &[Value], updated_at: u64) -> Vec<EncryptionPartition> {
+    let mut bounds: Vec<Value> = Vec::new();
+    if distribution.first().map(|min| !min.is_null()).unwrap_or(false) {
+        for quantile in distribution.iter().skip(2).filter(|q| !q.is_null()) {
+            if bounds.last() != Some(quantile) {
+                bounds.push(quantile.clone());
+            }
+        }
+    }
+    let mut partitions = Vec::new();
+    let mut after: Option<Value> = None;
+    for up_to in bounds.into_iter().map(Some).chain(std::iter::once(None)) {
+        partitions.push(EncryptionPartition {
+            index: partitions.len(),
+            range: KeyRange { after: after.clone(), up_to: up_to.clone() },
+            status: PartitionStatus::Pending,
+            watermark: None,
+            error: None,
+            updated_at,
+        });
+        after = up_to;
+    }
+    partitions
+}
+
+// Unfinished partitions in the order calls claim them, failed ones last so that a poisoned range
+// does not hold up the others
+pub fn claim_order(partitions: &[EncryptionPartition]) -> Vec<usize> {
+    let mut order: Vec<usize> = partitions.iter().filter(|p| matches!(p.status, PartitionStatus::Pending | PartitionStatus::InProgress)).map(|p| p.index).collect();
+    order.extend(partitions.iter().filter(|p| p.status == PartitionStatus::Failed).map(|p| p.index));
+    order
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PartitionReport {
+    pub partition: usize,
+    pub status: PartitionStatus,
+    pub done: usize,
+    pub total: usize,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EncryptionProgressInput {
+    pub database_id: String,
+    pub table: String,
+}
+
+// State of the encryption job of a table, per partition when it was started with partitions
+pub fn encryption_progress(cmd: String) {
+    let input: EncryptionProgressInput = match notify::parse_input(&cmd) {
+        Some(input) => input,
+        None => return,
+    };
+    if let Err(err) = Client::load(input.database_id.clone()) {
+        notify::error_with_code(&format!("Failed to load client: {}", err), err.code());
+        return;
+    }
+    let manifest = match EncryptionManifest::load(&input.database_id) {
+        Ok(m) => m,
+        Err(err) => {
+            notify::error_with_code(&format!("Failed to load encryption manifest: {}", err), DatabaseError::code_of(err.as_ref()));
+            return;
+        }
+    };
+    match manifest.table(&input.table) {
+        Some(table) => {
+            let done = table.partitions.iter().filter(|p| p.status == PartitionStatus::Done).count();
+            notify::result(&serde_json::json!({
+                "table": table.table,
+                "state": table.state,
+                "complete": table.state == TableState::Applied,
+                "status": job_status(table),
+                "job": table.job,
+                "watermark": table.watermark,
+                "partitions": table.partitions,
+                "partitions_done": done,
+            }));
+        },
+        None => {
+            notify::error(&format!("Table {} has no encrypted columns", input.table));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_distribution_query() {
+        assert_eq!(distribution_query("users", "id", 3).unwrap(),
+            r#"SELECT min("id"), max("id"), percentile_disc(0.3333333333333333) WITHIN GROUP (ORDER BY "id"), percentile_disc(0.6666666666666666) WITHIN GROUP (ORDER BY "id") FROM "users""#);
+        assert_eq!(distribution_query("public.\"Order Items\"", "id", 1).unwrap(), r#"SELECT min("id"), max("id") FROM "public"."Order Items""#);
+        assert!(distribution_query("users; DROP TABLE x", "id", 1).is_err());
+    }
+
+    #[test]
+    fn test_plan_partitions() {
+        let partitions = plan_partitions(&[json!("1"), json!("100"), json!("25"), json!("50"), json!("75")], 7);
+        assert_eq!(partitions.len(), 4);
+        assert_eq!(partitions[0].range, KeyRange { after: None, up_to: Some(json!("25")) });
+        assert_eq!(partitions[1].range, KeyRange { after: Some(json!("25")), up_to: Some(json!("50")) });
+        assert_eq!(partitions[3].range, KeyRange { after: Some(json!("75")), up_to: None });
+        assert!(partitions.iter().enumerate().all(|(i, p)| p.index == i && p.updated_at == 7));
+
+        // Skewed keys and empty tables
+        assert_eq!(plan_partitions(&[json!(1), json!(9), json!(1), json!(1), json!(9)], 0).len(), 3);
+        let empty = plan_partitions(&[Value::Null, Value::Null, Value::Null], 0);
+        assert_eq!(empty.len(), 1);
+        assert_eq!(empty[0].range.predicate("id"), None);
+    }
+
+    #[test]
+    fn test_key_range() {
+        let range = KeyRange { after: Some(json!(25)), up_to: Some(json!("o'50")) };
+        assert_eq!(range.predicate("id").unwrap(), "id > 25 AND id <= 'o''50'");
+        assert_eq!(range.resumed_after(Some(json!(30))).predicate("id").unwrap(), "id > 30 AND id <= 'o''50'");
+        assert_eq!(range.resumed_after(None), range);
+        let composite = KeyRange { after: Some(json!([7, "b"])), up_to: None };
+        assert_eq!(composite.predicate(r#"("order_id","line_no")"#).unwrap(), r#"("order_id","line_no") > (7,'b')"#);
+    }
+
+    // Stands for `SELECT pk, col FROM t WHERE <page> ORDER BY pk LIMIT batch_size` on the rows
+    fn read_page(rows: &[Vec<Value>], pager: &KeysetPager) -> Vec<Vec<Value>> {
+        let key = |row: &Vec<Value>| row[0].as_u64().unwrap();
+        let page = pager.page();
+        rows.iter()
+            .filter(|row| page.after.as_ref().is_none_or(|after| key(row) > after.as_u64().unwrap()))
+            .filter(|row| page.up_to.as_ref().is_none_or(|up_to| key(row) <= up_to.as_u64().unwrap()))
+            .take(pager.batch_size())
+            .cloned()
+            .collect()
+    }
+
+    fn batches(rows: &[Vec<Value>], range: KeyRange, batch_size: usize) -> Vec<usize> {
+        let mut pager = KeysetPager::new(range, batch_size, &PrimaryKey::Single("id".to_string()));
+        let mut batches = Vec::new();
+        let mut seen = 0;
+        while !pager.is_done() {
+            let page = read_page(rows, &pager);
+            if let (Some(first), Some(after)) = (page.first(), pager.last_key()) {
+                assert!(first[0].as_u64() > after.as_u64());
+            }
+            seen += page.len();
+            pager.advance(&page);
+            if !page.is_empty() {
+                batches.push(page.len());
+            }
+        }
+        assert_eq!(batches.iter().sum::<usize>(), seen);
+        batches
+    }
+
+    #[test]
+    fn test_keyset_pager() {
+        let rows: Vec<Vec<Value>> = (1..=10_000u64).map(|id| vec![json!(id), json!(format!("value {}", id))]).collect();
+        let full = batches(&rows, KeyRange::default(), 500);
+        assert_eq!(full.len(), 20);
+        assert!(full.iter().all(|size| *size == 500));
+
+        // Uneven split, the last batch is short
+        let uneven = batches(&rows, KeyRange::default(), 3_000);
+        assert_eq!(uneven, vec![3_000, 3_000, 3_000, 1_000]);
+
+        // A resumed run and a partition only page through their range
+        assert_eq!(batches(&rows, KeyRange { after: Some(json!(9_250)), up_to: None }, 500), vec![500, 250]);
+        assert_eq!(batches(&rows, KeyRange { after: Some(json!(100)), up_to: Some(json!(1_100)) }, 400), vec![400, 400, 200]);
+        assert_eq!(batches(&rows, KeyRange::default(), 0).len(), 10_000);
+        assert!(batches(&[], KeyRange::default(), 500).is_empty());
+
+        // A composite key resumes after every key column of the last row
+        let key = PrimaryKey::Composite(vec!["order_id".to_string(), "line_no".to_string()]);
+        let mut pager = KeysetPager::new(KeyRange::default(), 2, &key);
+        pager.advance(&[vec![json!(1), json!(1), json!("a")], vec![json!(1), json!(2), json!("b")]]);
+        assert_eq!(pager.last_key(), Some(json!([1, 2])));
+        assert!(!pager.is_done());
+    }
+
+    #[test]
+    fn test_claim_order() {
+        let mut partitions = plan_partitions(&[json!(1), json!(9), json!(3), json!(5), json!(7)], 0);
+        partitions[0].status = PartitionStatus::Failed;
+        partitions[1].status = PartitionStatus::Done;
+        partitions[3].status = PartitionStatus::InProgress;
+        assert_eq!(claim_order(&partitions), vec![2, 3, 0]);
+    }
+}
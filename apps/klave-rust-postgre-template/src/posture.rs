@@ -0,0 +1,298 @@
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{
+    audit::{self, AuditEntry},
+    credentials::{expiry_warning, CredentialHealth},
+    database::{Client, Clients, DatabaseError},
+    intents::{self, Intent},
+    jobs::{job_status, JobStatus},
+    manifest::EncryptionManifest,
+    notify,
+    settings::DeploymentSettings,
+    utils::get_trusted_time,
+};
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+// Failed operations older than this no longer count against a client
+const FAILED_OPERATIONS_WINDOW_SECONDS: u64 = 24 * 3600;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ListDetail {
+    #[default]
+    Summary,
+    // Adds health and encryption posture, read from the ledger only
+    Posture,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SqlListInput {
+    #[serde(default)]
+    pub detail: ListDetail,
+    // Adds the user each client connects as
+    #[serde(default)]
+    pub include_user: bool,
+}
+
+// What sql_list tells about a client; credentials never leave the enclave
+#[derive(Debug, Clone, Serialize)]
+pub struct ClientListing {
+    pub database_id: String,
+    pub database_name: String,
+    pub host: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    pub has_master_key: bool,
+    pub tags: Vec<String>,
+    pub production: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub posture: Option<ClientPosture>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PostureStatus {
+    Ok,
+    Warning,
+    Critical,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct JobCounts {
+    pub running: usize,
+    pub stalled: usize,
+    pub failed: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ClientPosture {
+    pub status: PostureStatus,
+    // Why the status is not ok, most severe first
+    pub issues: Vec<String>,
+    // Outcome of the last connections, as recorded by every connect
+    pub last_authenticated_at: Option<u64>,
+    pub consecutive_auth_failures: u32,
+    pub last_auth_error: Option<String>,
+    pub encrypted_tables: usize,
+    pub encrypted_columns: usize,
+    pub has_master_key: bool,
+    pub needs_key_attach: bool,
+    pub frozen_until: Option<u64>,
+    pub credentials_expire_at: Option<u64>,
+    pub jobs: JobCounts,
+    // Intents left by interrupted operations, see recover_incomplete_operations
+    pub incomplete_operations: usize,
+    // Audited failures within the last FAILED_OPERATIONS_WINDOW_SECONDS
+    pub failed_operations: usize,
+}
+
+// Ledger records a posture is assembled from
+pub struct PostureSources<'a> {
+    pub health: &'a CredentialHealth,
+    pub manifest: &'a EncryptionManifest,
+    pub intents: &'a [Intent],
+    pub audit: &'a [AuditEntry],
+}
+
+pub fn client_posture(client: &Client, sources: &PostureSources, now: u64, expiry_window_seconds: u64) -> ClientPosture {
+    let database_id = client.database_id();
+    let mut jobs = JobCounts::default();
+    for table in sources.manifest.tables.iter() {
+        match job_status(table) {
+            JobStatus::Running => jobs.running += 1,
+            JobStatus::Stalled => jobs.stalled += 1,
+            JobStatus::Failed => jobs.failed += 1,
+            JobStatus::Complete => (),
+        }
+    }
+    let window_start = now.saturating_sub(FAILED_OPERATIONS_WINDOW_SECONDS * NANOS_PER_SECOND);
+    let failed_operations = sources.audit.iter()
+        .filter(|e| e.database_id.as_deref() == Some(database_id) && e.outcome == "failure" && e.timestamp >= window_start)
+        .count();
+
+    let mut posture = ClientPosture {
+        status: PostureStatus::Ok,
+        issues: Vec::new(),
+        last_authenticated_at: sources.health.last_authenticated_at,
+        consecutive_auth_failures: sources.health.consecutive_auth_failures,
+        last_auth_error: sources.health.last_auth_error.clone(),
+        encrypted_tables: sources.manifest.tables.len(),
+        encrypted_columns: sources.manifest.tables.iter().map(|t| t.columns.len()).sum(),
+        has_master_key: client.master_key_name().is_some(),
+        needs_key_attach: client.needs_key_attach(),
+        frozen_until: client.frozen(now).and(client.frozen_until()),
+        credentials_expire_at: client.credentials_expire_at(),
+        jobs,
+        incomplete_operations: sources.intents.iter().filter(|i| i.database_id == database_id).count(),
+        failed_operations,
+    };
+    assess(&mut posture, sources.health, database_id, now, expiry_window_seconds);
+    posture
+}
+
+// Critical when the client cannot be used as is, warning when something needs a look
+fn assess(posture: &mut ClientPosture, health: &CredentialHealth, database_id: &str, now: u64, expiry_window_seconds: u64) {
+    let mut critical = Vec::new();
+    let mut warnings = Vec::new();
+    if health.suspect() {
+        critical.push(format!("{} authentication failures in a row", health.consecutive_auth_failures));
+    }
+    if posture.needs_key_attach {
+        critical.push("keys have to be attached again".to_string());
+    }
+    if posture.encrypted_tables > 0 && !posture.has_master_key {
+        critical.push("encrypted tables without a master key".to_string());
+    }
+    if posture.jobs.failed > 0 {
+        critical.push(format!("{} failed encryption jobs", posture.jobs.failed));
+    }
+    match posture.credentials_expire_at {
+        Some(at) if now >= at => critical.push("credentials expired".to_string()),
+        expire_at => {
+            if let Some(warning) = expiry_warning(database_id, expire_at, now, expiry_window_seconds) {
+                warnings.push(warning);
+            }
+        }
+    }
+    if posture.frozen_until.is_some() {
+        warnings.push("frozen".to_string());
+    }
+    if posture.jobs.stalled > 0 {
+        warnings.push(format!("{} stalled encryption jobs", posture.jobs.stalled));
+    }
+    if posture.incomplete_operations > 0 {
+        warnings.push(format!("{} incomplete operations", posture.incomplete_operations));
+    }
+    if posture.failed_operations > 0 {
+        warnings.push(format!("{} failed operations in the last day", posture.failed_operations));
+    }
+    posture.status = if !critical.is_empty() {
+        PostureStatus::Critical
+    } else if !warnings.is_empty() {
+        PostureStatus::Warning
+    } else {
+        PostureStatus::Ok
+    };
+    posture.issues = critical.into_iter().chain(warnings).collect();
+}
+
+impl ClientListing {
+    pub fn new(client: &Client, include_user: bool) -> Self {
+        ClientListing {
+            database_id: client.database_id().to_string(),
+            database_name: client.database_name().to_string(),
+            host: client.host().to_string(),
+            user: if include_user { Some(client.user().to_string()) } else { None },
+            has_master_key: client.master_key_name().is_some(),
+            tags: client.tags().to_vec(),
+            production: client.is_production(),
+            posture: None,
+        }
+    }
+}
+
+fn list_clients(input: &SqlListInput) -> Result<Vec<ClientListing>, Box<dyn std::error::Error>> {
+    let clients = Clients::load()?.list()?;
+    let mut listings: Vec<ClientListing> = clients.iter().map(|c| ClientListing::new(c, input.include_user)).collect();
+    if input.detail == ListDetail::Posture {
+        let now = get_trusted_time();
+        let expiry_window_seconds = DeploymentSettings::load()?.credential_expiry_warning_seconds;
+        let intents = intents::load_all()?;
+        let audit = audit::load_entries()?;
+        for (client, listing) in clients.iter().zip(listings.iter_mut()) {
+            let health = CredentialHealth::load(client.database_id());
+            let manifest = EncryptionManifest::load(client.database_id())?;
+            let sources = PostureSources { health: &health, manifest: &manifest, intents: &intents, audit: &audit };
+            listing.posture = Some(client_posture(client, &sources, now, expiry_window_seconds));
+        }
+    }
+    Ok(listings)
+}
+
+// Lists the registered databases; posture detail never connects to them
+pub fn sql_list(cmd: String) {
+    let input: SqlListInput = match notify::parse_input(&cmd) {
+        Some(input) => input,
+        None => return,
+    };
+    match list_clients(&input) {
+        Ok(clients) => notify::result(&json!({ "clients": clients })),
+        Err(err) => notify::error_with_code(&format!("Failed to list clients: {}", err), DatabaseError::code_of(err.as_ref())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::{database::ClientPolicies, manifest::{EncryptedColumn, TableState}};
+
+    use super::*;
+
+    const NOW: u64 = 10 * 24 * 3600 * NANOS_PER_SECOND;
+
+    fn client(master_key_name: Option<&str>, credentials_expire_at: Option<u64>) -> Client {
+        let client: Client = serde_json::from_value(json!({
+            "database_id": "db",
+            "db_input_details": { "host": "localhost", "dbname": "shop", "user": "app", "password": "pw", "credentials_expire_at": credentials_expire_at },
+            "opaque_handle": "handle",
+            "master_key_name": master_key_name,
+        })).unwrap();
+        client.with_policies(ClientPolicies::default())
+    }
+
+    fn failure(database_id: &str, timestamp: u64) -> AuditEntry {
+        serde_json::from_value(json!({
+            "id": "1", "timestamp": timestamp, "sender": "alice", "route": "sql_script", "database_id": database_id, "outcome": "failure", "details": {},
+        })).unwrap()
+    }
+
+    #[test]
+    fn test_healthy_client_is_ok() {
+        let health = CredentialHealth { last_authenticated_at: Some(NOW - 5), ..Default::default() };
+        let mut manifest = EncryptionManifest::new("db");
+        manifest.record_column("users", "id", EncryptedColumn::new("email", None), 0);
+        manifest.record_column("users", "id", EncryptedColumn::new("phone", None), 0);
+        manifest.set_table_state("users", TableState::Applied, 0).unwrap();
+        // Failures of another database or older than a day do not count
+        let audit = [failure("other", NOW), failure("db", 0)];
+        let sources = PostureSources { health: &health, manifest: &manifest, intents: &[], audit: &audit };
+
+        let posture = client_posture(&client(Some("key"), None), &sources, NOW, 3600);
+        assert_eq!((posture.status, posture.issues.len()), (PostureStatus::Ok, 0));
+        assert_eq!((posture.encrypted_tables, posture.encrypted_columns), (1, 2));
+        assert_eq!((posture.jobs, posture.failed_operations), (JobCounts::default(), 0));
+    }
+
+    #[test]
+    fn test_listing_leaves_credentials_out() {
+        let listing = serde_json::to_value(ClientListing::new(&client(Some("key"), None), false)).unwrap();
+        assert_eq!(listing, json!({ "database_id": "db", "database_name": "shop", "host": "localhost", "has_master_key": true, "tags": [], "production": false }));
+        let listing = serde_json::to_string(&ClientListing::new(&client(None, None), true)).unwrap();
+        assert!(listing.contains(r#""user":"app""#));
+        assert!(!listing.contains("password") && !listing.contains("pw") && !listing.contains("handle"));
+    }
+
+    #[test]
+    fn test_status_rules() {
+        let health = CredentialHealth::default();
+        let manifest = EncryptionManifest::new("db");
+        let audit = [failure("db", NOW - 10)];
+        let sources = PostureSources { health: &health, manifest: &manifest, intents: &[], audit: &audit };
+        let posture = client_posture(&client(None, Some(NOW + 60 * NANOS_PER_SECOND)), &sources, NOW, 3600);
+        assert_eq!(posture.status, PostureStatus::Warning);
+        assert_eq!(posture.issues, vec!["Credentials of database db expire in less than an hour", "1 failed operations in the last day"]);
+
+        let suspect = CredentialHealth { consecutive_auth_failures: 3, ..Default::default() };
+        let mut applying = EncryptionManifest::new("db");
+        applying.record_column("users", "id", EncryptedColumn::new("email", None), 0);
+        applying.set_table_state("users", TableState::Applying, 0).unwrap();
+        let sources = PostureSources { health: &suspect, manifest: &applying, intents: &[], audit: &[] };
+        let posture = client_posture(&client(None, Some(NOW)), &sources, NOW, 3600);
+        assert_eq!(posture.status, PostureStatus::Critical);
+        assert_eq!(posture.issues, vec!["3 authentication failures in a row", "encrypted tables without a master key", "credentials expired"]);
+        assert_eq!(posture.jobs.running, 1);
+    }
+}
@@ -0,0 +1,195 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::{database::Field, utils::{quote_ident, quote_idents, sql_literal}};
+
+// Primary key of a table: a column name, or the columns of a composite key in key order. Rows are matched on
+// every key column; the value of a composite key is the array of its cells, e.g. [order_id, line_no].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum PrimaryKey {
+    Single(String),
+    Composite(Vec<String>),
+}
+
+impl PrimaryKey {
+    pub fn columns(&self) -> &[String] {
+        match self {
+            PrimaryKey::Single(column) => std::slice::from_ref(column),
+            PrimaryKey::Composite(columns) => columns,
+        }
+    }
+
+    pub fn column_count(&self) -> usize {
+        self.columns().len()
+    }
+
+    pub fn contains(&self, column: &str) -> bool {
+        self.columns().iter().any(|c| c == column)
+    }
+
+    pub fn is_composite(&self) -> bool {
+        matches!(self, PrimaryKey::Composite(_))
+    }
+
+    // Names that cannot be quoted, or a key naming no column or one twice, are refused
+    pub fn validate(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let columns = self.columns();
+        if columns.is_empty() {
+            return Err("Invalid input: primary_key lists no column".into());
+        }
+        if let Some(column) = columns.iter().enumerate().find(|(i, c)| columns[..*i].contains(c)).map(|(_, c)| c) {
+            return Err(format!("Invalid input: primary key column {} is listed twice", column).into());
+        }
+        quote_idents(columns)?;
+        Ok(())
+    }
+
+    // Key columns quoted and joined, for select lists and ORDER BY
+    pub fn quoted(&self) -> Result<String, Box<dyn std::error::Error>> {
+        Ok(quote_idents(self.columns())?.join(","))
+    }
+
+    // Left-hand side of a comparison with a key value: the column, or the row constructor of the columns
+    pub fn operand(&self) -> Result<String, Box<dyn std::error::Error>> {
+        match self {
+            PrimaryKey::Single(column) => quote_ident(column),
+            PrimaryKey::Composite(_) => Ok(format!("({})", self.quoted()?)),
+        }
+    }
+
+    // Value of the key of a row, a missing column counting as null
+    pub fn value(&self, row: &Map<String, Value>) -> Value {
+        let cell = |column: &String| row.get(column).cloned().unwrap_or(Value::Null);
+        match self {
+            PrimaryKey::Single(column) => cell(column),
+            PrimaryKey::Composite(columns) => Value::Array(columns.iter().map(cell).collect()),
+        }
+    }
+
+    // Value of the key of a row read with the key columns first
+    pub fn leading_value(&self, row: &[Value]) -> Value {
+        let cell = |i: usize| row.get(i).cloned().unwrap_or(Value::Null);
+        match self {
+            PrimaryKey::Single(_) => cell(0),
+            PrimaryKey::Composite(columns) => Value::Array((0..columns.len()).map(cell).collect()),
+        }
+    }
+
+    // Cells of a key value in key order, the inverse of value
+    pub fn cells(&self, key: &Value) -> Vec<Value> {
+        match (self, key) {
+            (PrimaryKey::Composite(_), Value::Array(cells)) => cells.clone(),
+            _ => vec![key.clone()],
+        }
+    }
+
+    pub fn fields(&self) -> Vec<Field> {
+        self.columns().iter().map(|c| Field::named(c)).collect()
+    }
+}
+
+impl From<&str> for PrimaryKey {
+    fn from(column: &str) -> Self {
+        PrimaryKey::Single(column.to_string())
+    }
+}
+
+impl std::fmt::Display for PrimaryKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.columns().join(","))
+    }
+}
+
+// Literal a key value is compared with: the value, or a row of the cells of a composite key
+pub fn key_literal(key: &Value) -> String {
+    match key {
+        Value::Array(cells) => format!("({})", cells.iter().map(sql_literal).collect::<Vec<String>>().join(",")),
+        other => sql_literal(other),
+    }
+}
+
+// Columns of the primary key of a table, in key order
+pub fn primary_key_query(table: &str) -> String {
+    format!("SELECT k.column_name FROM information_schema.table_constraints c \
+        JOIN information_schema.key_column_usage k ON k.constraint_name = c.constraint_name \
+        AND k.table_schema = c.table_schema AND k.table_name = c.table_name \
+        WHERE c.constraint_type = 'PRIMARY KEY' AND c.table_name = {} ORDER BY k.ordinal_position", sql_literal(&Value::String(table.to_string())))
+}
+
+// Key read from the rows of primary_key_query, None for a table without one
+pub fn parse_primary_key(rows: &[Vec<Value>]) -> Option<PrimaryKey> {
+    let columns: Vec<String> = rows.iter().filter_map(|r| r.first().and_then(|v| v.as_str()).map(|s| s.to_string())).collect();
+    match columns.len() {
+        0 => None,
+        1 => columns.into_iter().next().map(PrimaryKey::Single),
+        _ => Some(PrimaryKey::Composite(columns)),
+    }
+}
+
+// Key to match rows on: the one information_schema lists, which a key given by the caller must match. Trusting a
+// given column that is not unique would rewrite the other rows sharing its values.
+pub fn check_primary_key(table: &str, given: Option<&PrimaryKey>, detected: Option<PrimaryKey>, table_columns: &[String]) -> Result<PrimaryKey, Box<dyn std::error::Error>> {
+    let detected = detected.ok_or(format!("Invalid input: table {} has no primary key, rows cannot be matched", table))?;
+    if let Some(column) = detected.columns().iter().find(|c| !table_columns.contains(c)) {
+        return Err(format!("Primary key column {} is not a column of table {}", column, table).into());
+    }
+    match given {
+        Some(given) if given.columns() != detected.columns() => Err(format!(
+            "Invalid input: primary_key {} does not match the primary key ({}) of table {}", given, detected, table).into()),
+        _ => Ok(detected),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_primary_key() {
+        let single: PrimaryKey = serde_json::from_value(json!("id")).unwrap();
+        let composite: PrimaryKey = serde_json::from_value(json!(["order_id", "line_no"])).unwrap();
+        assert_eq!(serde_json::to_value(&single).unwrap(), json!("id"));
+        assert_eq!(composite.columns(), ["order_id", "line_no"]);
+        assert_eq!(composite.operand().unwrap(), r#"("order_id","line_no")"#);
+        assert_eq!(single.operand().unwrap(), r#""id""#);
+        assert_eq!(composite.to_string(), "order_id,line_no");
+
+        let row: Map<String, Value> = serde_json::from_value(json!({ "order_id": 7, "line_no": 2, "sku": "a" })).unwrap();
+        assert_eq!(composite.value(&row), json!([7, 2]));
+        assert_eq!(composite.leading_value(&[json!(7), json!(2), json!("a")]), json!([7, 2]));
+        assert_eq!(composite.cells(&json!([7, 2])), vec![json!(7), json!(2)]);
+        assert_eq!(single.cells(&json!(7)), vec![json!(7)]);
+        assert_eq!(key_literal(&json!([7, "o'2"])), "(7,'o''2')");
+
+        assert!(PrimaryKey::Composite(vec![]).validate().is_err());
+        assert!(PrimaryKey::Composite(vec!["a".to_string(), "a".to_string()]).validate().is_err());
+        assert!(composite.validate().is_ok());
+    }
+
+    #[test]
+    fn test_parse_primary_key() {
+        assert!(primary_key_query("order_lines").contains("c.table_name = 'order_lines' ORDER BY k.ordinal_position"));
+        assert_eq!(parse_primary_key(&[]), None);
+        assert_eq!(parse_primary_key(&[vec![json!("id")]]), Some(PrimaryKey::Single("id".to_string())));
+        assert_eq!(parse_primary_key(&[vec![json!("order_id")], vec![json!("line_no")]]),
+            Some(PrimaryKey::Composite(vec!["order_id".to_string(), "line_no".to_string()])));
+    }
+
+    #[test]
+    fn test_check_primary_key() {
+        let columns: Vec<String> = ["order_id", "line_no", "sku"].iter().map(|c| c.to_string()).collect();
+        let detected = PrimaryKey::Composite(vec!["order_id".to_string(), "line_no".to_string()]);
+        assert_eq!(check_primary_key("order_lines", None, Some(detected.clone()), &columns).unwrap(), detected);
+        assert_eq!(check_primary_key("order_lines", Some(&detected), Some(detected.clone()), &columns).unwrap(), detected);
+        // A single-column key given as a list is the same key
+        assert!(check_primary_key("users", Some(&PrimaryKey::Composite(vec!["id".to_string()])), Some("id".into()), &["id".to_string()]).is_ok());
+
+        let err = check_primary_key("order_lines", Some(&"sku".into()), Some(detected.clone()), &columns).unwrap_err().to_string();
+        assert_eq!(err, "Invalid input: primary_key sku does not match the primary key (order_id,line_no) of table order_lines");
+        let err = check_primary_key("logs", None, None, &columns).unwrap_err().to_string();
+        assert!(err.starts_with("Invalid input: table logs has no primary key"));
+        assert!(check_primary_key("order_lines", None, Some(detected), &columns[1..]).is_err());
+    }
+}
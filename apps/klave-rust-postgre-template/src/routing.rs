@@ -0,0 +1,178 @@
+use std::{cell::Cell, collections::BTreeMap};
+
+use serde_json::{json, Value};
+
+use crate::{
+    bootstrap,
+    errors::UnknownFieldPolicy,
+    notify::{self, ResultShape},
+    settings::{require_admin, DeploymentSettings},
+    storage,
+    RouteKind, ROUTES, ROUTE_ALIASES,
+};
+
+pub(crate) const ROUTE_USAGE_TABLE: &str = "RouteUsageTable";
+
+// What a called route name stands for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ResolvedRoute {
+    pub route: &'static str,
+    pub shape: ResultShape,
+    // Versioned aliases reject unknown input fields whatever the deployment settings say
+    pub strict_input: bool,
+    // Versioned alias to call instead, for a route name kept for existing callers
+    pub deprecated_by: Option<&'static str>,
+}
+
+thread_local! {
+    // Kind of the route being invoked, None outside an invocation
+    static CURRENT_KIND: Cell<Option<RouteKind>> = const { Cell::new(None) };
+}
+
+// Called before the handler of a route runs
+pub fn begin(name: &str) {
+    CURRENT_KIND.with(|k| k.set(resolve(name).and_then(|resolved| route_kind(resolved.route))));
+}
+
+// Klave only lets transactions write the ledger; records kept on the side are skipped on query routes
+pub fn can_write_ledger() -> bool {
+    CURRENT_KIND.with(|k| k.get()) != Some(RouteKind::Query)
+}
+
+pub fn route_kind(route: &str) -> Option<RouteKind> {
+    ROUTES.iter().find(|(name, _)| *name == route).map(|(_, kind)| *kind)
+}
+
+pub fn resolve(name: &str) -> Option<ResolvedRoute> {
+    if let Some((_, route, shape)) = ROUTE_ALIASES.iter().find(|(alias, _, _)| *alias == name) {
+        return Some(ResolvedRoute { route, shape: *shape, strict_input: true, deprecated_by: None });
+    }
+    let (route, _) = ROUTES.iter().find(|(route, _)| *route == name)?;
+    Some(ResolvedRoute {
+        route,
+        shape: ResultShape::Legacy,
+        strict_input: false,
+        deprecated_by: ROUTE_ALIASES.iter().find(|(_, target, _)| target == route).map(|(alias, _, _)| *alias),
+    })
+}
+
+fn load_usage(name: &str) -> u64 {
+    crate::runtime::ledger::get_table(ROUTE_USAGE_TABLE).get(name).ok()
+        .and_then(|v| serde_json::from_slice::<u64>(&v).ok())
+        .unwrap_or(0)
+}
+
+// Only transactions may write the ledger, calls of a name aliasing a query route are not counted
+fn counts_usage(route: &str) -> bool {
+    route_kind(route) == Some(RouteKind::Transaction)
+}
+
+// Best effort, a failed count never fails the call
+fn record_usage(name: &str) {
+    let count = load_usage(name) + 1;
+    if let Err(err) = storage::ledger_set(ROUTE_USAGE_TABLE, name, count.to_string().as_bytes()) {
+        notify::warning(&format!("Failed to count call of {}: {}", name, err));
+    }
+}
+
+// Runs the handler of an aliased route name with the serialization of that name. Calls are counted per name
+// so that operators can see who still calls a deprecated one before turning legacy routes off.
+pub fn invoke_route<F: FnOnce(String)>(name: &str, cmd: String, handler: F) {
+    let resolved = resolve(name);
+    bootstrap::invoke_guarded(name, cmd, |cmd| {
+        let resolved = match resolved {
+            Some(r) => r,
+            None => {
+                notify::error(&format!("Unknown route {}", name));
+                return;
+            }
+        };
+        // Original names keep the shape set from the deployment settings
+        if resolved.shape == ResultShape::Envelope {
+            notify::set_shape(resolved.shape);
+        }
+        if resolved.strict_input {
+            notify::set_input_policy(UnknownFieldPolicy::Reject);
+        }
+        if counts_usage(resolved.route) {
+            record_usage(name);
+        }
+        if let Some(successor) = resolved.deprecated_by {
+            let enabled = DeploymentSettings::load().map(|s| s.legacy_routes_enabled).unwrap_or(true);
+            if !enabled {
+                notify::error(&format!("Route {} was retired, call {} instead", name, successor));
+                return;
+            }
+            notify::deprecation(&json!({ "route": name, "successor": successor }));
+        }
+        handler(cmd);
+    });
+}
+
+pub fn route_usage(_cmd: String) {
+    if let Err(err) = require_admin("read route usage") {
+        notify::error(&err);
+        return;
+    }
+    let mut usage: BTreeMap<&str, Value> = BTreeMap::new();
+    // Calls of an uncounted name are null rather than 0
+    let calls = |name: &str, route: &str| if counts_usage(route) { json!(load_usage(name)) } else { Value::Null };
+    for (alias, route, _) in ROUTE_ALIASES {
+        usage.insert(alias, json!({ "calls": calls(alias, route), "deprecated": false }));
+        usage.insert(route, json!({ "calls": calls(route, route), "deprecated": true, "successor": alias }));
+    }
+    notify::result(&usage);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::notify::{Channel, Framer};
+
+    use super::*;
+
+    #[test]
+    fn test_resolve() {
+        assert_eq!(resolve("db_setup"), Some(ResolvedRoute { route: "db_setup", shape: ResultShape::Legacy, strict_input: false, deprecated_by: Some("v2_db_setup") }));
+        assert_eq!(resolve("v2_db_setup"), Some(ResolvedRoute { route: "db_setup", shape: ResultShape::Envelope, strict_input: true, deprecated_by: None }));
+        assert_eq!(resolve("v2_sql_script").map(|r| r.route), Some("sql_script"));
+        assert_eq!(resolve("get_settings").and_then(|r| r.deprecated_by), None);
+        assert_eq!(resolve("v3_db_setup"), None);
+        for (alias, route, _) in ROUTE_ALIASES {
+            assert!(route_kind(route).is_some(), "{} aliases an unknown route", alias);
+        }
+    }
+
+    #[test]
+    fn test_counts_usage() {
+        assert!(counts_usage("db_setup"));
+        assert!(counts_usage("sql_script"));
+        assert!(!counts_usage("route_usage"));
+        assert!(!counts_usage("unknown"));
+    }
+
+    // Results as db_setup and sql_script send them, under each name
+    #[test]
+    fn test_both_serializations() {
+        let db_setup = json!("0f3c");
+        let sql_script = json!({ "statements": [{ "index": 0, "command_tag": "INSERT 0 1", "rows_affected": 1 }] });
+        for payload in [db_setup, sql_script] {
+            let mut legacy = Framer::new("t");
+            assert_eq!(legacy.frame(Channel::Result, legacy.shape().result(payload.clone())).payload, payload);
+            let mut envelope = Framer::new("t");
+            envelope.set_shape(ResultShape::Envelope);
+            assert_eq!(envelope.frame(Channel::Result, envelope.shape().result(payload.clone())).payload, json!({ "success": true, "data": payload }));
+        }
+
+        let message = "Failed to add database client: connection refused";
+        assert_eq!(ResultShape::Legacy.error(message, None), json!({ "error": message, "code": "OPERATION_FAILED" }));
+        assert_eq!(ResultShape::Envelope.error(message, None), json!({ "success": false, "error": { "code": "OPERATION_FAILED", "message": message, "details": null } }));
+        assert_eq!(ResultShape::Legacy.error("Refused", Some(json!({ "code": "x" }))), json!({ "error": "Refused", "code": "OPERATION_FAILED", "details": { "code": "x" } }));
+        let not_found = ResultShape::Envelope.error("Failed to load client: NotFound: no database 'db'", None);
+        assert_eq!(not_found["error"]["code"], json!("CLIENT_NOT_FOUND"));
+
+        // A handler that sends nothing still answers in its shape
+        let mut silent = Framer::new("t");
+        silent.set_shape(ResultShape::Envelope);
+        assert_eq!(silent.finish().unwrap().payload, json!({ "success": true, "data": null }));
+    }
+}
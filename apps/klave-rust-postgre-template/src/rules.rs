@@ -0,0 +1,231 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{
+    audit,
+    database,
+    host::normalize_untyped,
+    manifest::{EncryptedColumn, EncryptionManifest},
+    notify,
+    settings::AccessLevel,
+    utils::{expr::Decimal, get_trusted_time, pattern::Pattern},
+};
+
+// Violations listed in a report, the count keeps the full picture
+const MAX_REPORTED_VIOLATIONS: usize = 20;
+
+// Validation rule of an encrypted column. Null values only break not_empty.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "rule", rename_all = "snake_case")]
+pub enum ColumnRule {
+    Regex { pattern: String },
+    Min { value: Value },
+    Max { value: Value },
+    MaxLength { value: usize },
+    NotEmpty,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SetColumnRulesInput {
+    pub database_id: String,
+    pub table: String,
+    pub column: String,
+    // An empty list removes the rules of the column
+    pub rules: Vec<ColumnRule>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RuleViolation {
+    // Index of the row in the input, or its primary key when existing rows are scanned
+    pub row: Value,
+    pub column: String,
+    pub rule: String,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ValidationReport {
+    pub violations: u64,
+    pub samples: Vec<RuleViolation>,
+}
+
+fn value_text(value: &Value) -> String {
+    match normalize_untyped(value.clone()) {
+        Value::String(s) => s,
+        other => other.to_string(),
+    }
+}
+
+fn bound(value: &Value) -> Option<Decimal> {
+    Decimal::parse(&value_text(value))
+}
+
+impl ColumnRule {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ColumnRule::Regex { .. } => "regex",
+            ColumnRule::Min { .. } => "min",
+            ColumnRule::Max { .. } => "max",
+            ColumnRule::MaxLength { .. } => "max_length",
+            ColumnRule::NotEmpty => "not_empty",
+        }
+    }
+
+    // Refuses rules that could never be checked
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            ColumnRule::Regex { pattern } => Pattern::parse(pattern).map(|_| ()).map_err(|e| format!("invalid pattern '{}': {}", pattern, e)),
+            ColumnRule::Min { value } | ColumnRule::Max { value } => match bound(value) {
+                Some(_) => Ok(()),
+                None => Err(format!("{} bound {} is not a number", self.name(), value)),
+            },
+            ColumnRule::MaxLength { .. } | ColumnRule::NotEmpty => Ok(()),
+        }
+    }
+
+    pub fn check(&self, value: &Value) -> Result<(), String> {
+        if value.is_null() {
+            return match self {
+                ColumnRule::NotEmpty => Err("is null".to_string()),
+                _ => Ok(()),
+            };
+        }
+        let text = value_text(value);
+        match self {
+            ColumnRule::Regex { pattern } => {
+                let compiled = Pattern::parse(pattern).map_err(|e| format!("invalid pattern '{}': {}", pattern, e))?;
+                if compiled.is_match(&text) { Ok(()) } else { Err(format!("does not match {}", pattern)) }
+            }
+            ColumnRule::Min { value: limit } | ColumnRule::Max { value: limit } => {
+                let number = Decimal::parse(&text).ok_or("is not a number".to_string())?;
+                let limit_number = bound(limit).ok_or(format!("{} bound {} is not a number", self.name(), limit))?;
+                let ordering = number.cmp(&limit_number).ok_or("is out of the supported numeric range".to_string())?;
+                match (self, ordering) {
+                    (ColumnRule::Min { .. }, std::cmp::Ordering::Less) => Err(format!("is below {}", value_text(limit))),
+                    (ColumnRule::Max { .. }, std::cmp::Ordering::Greater) => Err(format!("is above {}", value_text(limit))),
+                    _ => Ok(()),
+                }
+            }
+            ColumnRule::MaxLength { value: limit } => {
+                let length = text.chars().count();
+                if length > *limit { Err(format!("is {} characters long, more than {}", length, limit)) } else { Ok(()) }
+            }
+            ColumnRule::NotEmpty => if text.trim().is_empty() { Err("is empty".to_string()) } else { Ok(()) },
+        }
+    }
+}
+
+impl ValidationReport {
+    pub fn check(&mut self, row: Value, column: &EncryptedColumn, value: &Value) {
+        for rule in column.rules.iter() {
+            if let Err(message) = rule.check(value) {
+                self.violations += 1;
+                if self.samples.len() < MAX_REPORTED_VIOLATIONS {
+                    self.samples.push(RuleViolation { row: row.clone(), column: column.name.clone(), rule: rule.name().to_string(), message });
+                }
+            }
+        }
+    }
+
+    // Checks the values of rows about to be written; checked pairs the index of a value in a row with its column
+    pub fn check_rows(&mut self, checked: &[(usize, &EncryptedColumn)], rows: &[Vec<Value>]) {
+        for (i, row) in rows.iter().enumerate() {
+            for (index, column) in checked.iter() {
+                self.check(json!(i), column, row.get(*index).unwrap_or(&Value::Null));
+            }
+        }
+    }
+
+    pub fn is_clean(&self) -> bool {
+        self.violations == 0
+    }
+
+    pub fn summary(&self) -> String {
+        let listed: Vec<String> = self.samples.iter()
+            .map(|v| format!("row {}, column '{}': {} ({})", v.row, v.column, v.message, v.rule))
+            .collect();
+        format!("{} values break the column rules: {}", self.violations, listed.join("; "))
+    }
+}
+
+fn set_rules(client: &database::Client, input: &SetColumnRulesInput) -> Result<(), Box<dyn std::error::Error>> {
+    for rule in input.rules.iter() {
+        rule.validate()?;
+    }
+    EncryptionManifest::update(client.database_id(), |manifest| {
+        manifest.set_column_rules(&input.table, &input.column, input.rules.clone(), get_trusted_time())
+    })?;
+    Ok(())
+}
+
+pub fn set_column_rules(cmd: String) {
+    let input: SetColumnRulesInput = match notify::parse_input(&cmd) {
+        Some(input) => input,
+        None => return,
+    };
+    let client = match database::Client::load(input.database_id.clone()) {
+        Ok(c) => c,
+        Err(err) => {
+            notify::error_with_code(&format!("Failed to load client: {}", err), err.code());
+            return;
+        }
+    };
+    if client.access() == AccessLevel::ReportingOnly {
+        notify::error("Reporting-only callers cannot change column rules");
+        return;
+    }
+
+    let rule_names: Vec<&str> = input.rules.iter().map(|r| r.name()).collect();
+    match set_rules(&client, &input) {
+        Ok(_) => {
+            audit::record("set_column_rules", Some(&input.database_id), "success", json!({ "table": input.table, "column": input.column, "rules": rule_names }));
+            notify::result(&json!({ "table": input.table, "column": input.column, "rules": input.rules }));
+        },
+        Err(err) => {
+            audit::record("set_column_rules", Some(&input.database_id), "failure", json!({ "table": input.table, "column": input.column }));
+            notify::error_with_code(&format!("Failed to set column rules: {}", err), database::DatabaseError::code_of(err.as_ref()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn column(rules: Value) -> EncryptedColumn {
+        let mut column = EncryptedColumn::new("email", None);
+        column.rules = serde_json::from_value(rules).unwrap();
+        column
+    }
+
+    #[test]
+    fn test_rules() {
+        let rule = |r: Value| serde_json::from_value::<ColumnRule>(r).unwrap();
+        let min = rule(json!({ "rule": "min", "value": 0 }));
+        assert!(min.check(&json!("0.00")).is_ok());
+        assert_eq!(min.check(&json!("-1.5")).unwrap_err(), "is below 0");
+        assert_eq!(min.check(&json!("abc")).unwrap_err(), "is not a number");
+        assert!(rule(json!({ "rule": "max", "value": "10.5" })).check(&json!(11)).is_err());
+        assert!(rule(json!({ "rule": "max_length", "value": 3 })).check(&json!("héé")).is_ok());
+        assert!(rule(json!({ "rule": "not_empty" })).check(&json!("  ")).is_err());
+        assert!(rule(json!({ "rule": "not_empty" })).check(&Value::Null).is_err());
+        assert!(rule(json!({ "rule": "regex", "pattern": "^a" })).check(&Value::Null).is_ok());
+
+        assert!(rule(json!({ "rule": "regex", "pattern": "(" })).validate().is_err());
+        assert!(rule(json!({ "rule": "min", "value": "many" })).validate().is_err());
+        assert!(serde_json::from_value::<ColumnRule>(json!({ "rule": "unique" })).is_err());
+    }
+
+    #[test]
+    fn test_check_rows() {
+        let email = column(json!([{ "rule": "regex", "pattern": "^[^@]+@[^@]+$" }, { "rule": "not_empty" }]));
+        let rows = vec![vec![json!("1"), json!("jane@example.com")], vec![json!("2"), json!("")], vec![json!("3"), Value::Null]];
+        let mut report = ValidationReport::default();
+        report.check_rows(&[(1, &email)], &rows);
+        assert_eq!(report.violations, 3);
+        assert_eq!(report.samples[0], RuleViolation { row: json!(1), column: "email".to_string(), rule: "regex".to_string(), message: "does not match ^[^@]+@[^@]+$".to_string() });
+        assert_eq!(report.samples[2].row, json!(2));
+        assert!(report.summary().starts_with("3 values break the column rules: row 1, column 'email'"));
+    }
+}
@@ -0,0 +1,161 @@
+// Calls into the Klave SDK. The crate reaches the host through here only, so that with the simulator
+// feature an in-memory host can stand in for it, see simulator.rs. Without it every call goes to klave.
+
+// Returns the simulated outcome when a simulated host is installed on this thread
+#[cfg(feature = "simulator")]
+macro_rules! simulated {
+    ($host:ident => $call:expr) => {
+        if let Some(outcome) = crate::simulator::with_host(|$host| $call) {
+            return outcome;
+        }
+    };
+}
+
+#[cfg(not(feature = "simulator"))]
+macro_rules! simulated {
+    ($host:ident => $call:expr) => {};
+}
+
+pub mod ledger {
+    use std::error::Error;
+
+    pub struct Table {
+        name: String,
+    }
+
+    pub fn get_table(name: &str) -> Table {
+        Table { name: name.to_string() }
+    }
+
+    impl Table {
+        pub fn get(&self, key: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+            simulated!(host => host.ledger_get(&self.name, key));
+            klave::ledger::get_table(&self.name).get(key)
+        }
+
+        pub fn set(&self, key: &str, value: &[u8]) -> Result<(), Box<dyn Error>> {
+            simulated!(host => host.ledger_set(&self.name, key, value));
+            klave::ledger::get_table(&self.name).set(key, value)
+        }
+
+        pub fn remove(&self, key: &str) -> Result<(), Box<dyn Error>> {
+            simulated!(host => host.ledger_remove(&self.name, key));
+            klave::ledger::get_table(&self.name).remove(key)
+        }
+
+        pub fn list_keys(&self) -> Result<Vec<String>, Box<dyn Error>> {
+            simulated!(host => Ok(host.ledger_keys(&self.name)));
+            klave::ledger::get_table(&self.name).list_keys()
+        }
+    }
+}
+
+pub mod context {
+    pub fn get(param: &str) -> Result<String, Box<dyn std::error::Error>> {
+        simulated!(host => host.context(param));
+        klave::context::get(param)
+    }
+}
+
+pub mod notifier {
+    pub fn send_json<T: serde::Serialize>(value: &T) -> Result<(), Box<dyn std::error::Error>> {
+        simulated!(host => serde_json::to_value(value).map(|v| host.notify(v)).map_err(|e| e.into()));
+        klave::notifier::send_json(value)
+    }
+}
+
+pub mod random {
+    pub fn get_random_bytes(size: i32) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        simulated!(host => Ok(host.random_bytes(size.max(0) as usize)));
+        klave::crypto::random::get_random_bytes(size)
+    }
+}
+
+pub mod sha {
+    pub fn digest(algorithm: &str, data: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        simulated!(host => host.digest(algorithm, data));
+        klave::crypto::sha::digest(algorithm, data)
+    }
+}
+
+pub mod sql {
+    pub fn connection_open(uri: &str) -> Result<String, Box<dyn std::error::Error>> {
+        simulated!(host => host.connection_open(uri));
+        klave::sql::connection_open(uri)
+    }
+
+    pub fn query(connection: &str, query: &str) -> Result<String, Box<dyn std::error::Error>> {
+        simulated!(host => host.sql_query(connection, query));
+        klave::sql::query(connection, query)
+    }
+
+    pub fn execute(connection: &str, query: &str) -> Result<String, Box<dyn std::error::Error>> {
+        simulated!(host => host.sql_execute(connection, query));
+        klave::sql::execute(connection, query)
+    }
+}
+
+pub mod subtle {
+    use std::error::Error;
+
+    pub use klave::crypto::subtle::{
+        AesGcmParams, AesKeyGenParams, CryptoKey, DerivedKeyAlgorithm, EcKeyGenParams, EncryptAlgorithm, HkdfDerivParams,
+        KeyDerivationAlgorithm, KeyGenAlgorithm, KeyWrapAlgorithm, RsaHashedKeyGenParams, RsaOaepParams,
+    };
+
+    pub fn generate_key(algorithm: &KeyGenAlgorithm, extractable: bool, usages: &[&str]) -> Result<CryptoKey, Box<dyn Error>> {
+        simulated!(host => host.generate_key(algorithm, extractable, usages));
+        klave::crypto::subtle::generate_key(algorithm, extractable, usages)
+    }
+
+    pub fn derive_key(algorithm: &KeyDerivationAlgorithm, base_key: &CryptoKey, derived: &DerivedKeyAlgorithm, extractable: bool, usages: &[&str]) -> Result<CryptoKey, Box<dyn Error>> {
+        simulated!(host => host.derive_key(algorithm, base_key, derived, extractable, usages));
+        klave::crypto::subtle::derive_key(algorithm, base_key, derived, extractable, usages)
+    }
+
+    pub fn encrypt(algorithm: &EncryptAlgorithm, key: &CryptoKey, clear_text: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        simulated!(host => host.encrypt(algorithm, key, clear_text));
+        klave::crypto::subtle::encrypt(algorithm, key, clear_text)
+    }
+
+    pub fn decrypt(algorithm: &EncryptAlgorithm, key: &CryptoKey, cipher_text: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        simulated!(host => host.decrypt(algorithm, key, cipher_text));
+        klave::crypto::subtle::decrypt(algorithm, key, cipher_text)
+    }
+
+    pub fn export_key(format: &str, key: &CryptoKey) -> Result<Vec<u8>, Box<dyn Error>> {
+        simulated!(host => host.export_key(format, key));
+        klave::crypto::subtle::export_key(format, key)
+    }
+
+    pub fn import_key(format: &str, key_data: &[u8], algorithm: &KeyGenAlgorithm, extractable: bool, usages: &[&str]) -> Result<CryptoKey, Box<dyn Error>> {
+        simulated!(host => host.unsupported("import_key"));
+        klave::crypto::subtle::import_key(format, key_data, algorithm, extractable, usages)
+    }
+
+    pub fn wrap_key(format: &str, key: &CryptoKey, wrapping_key: &CryptoKey, algorithm: &KeyWrapAlgorithm) -> Result<Vec<u8>, Box<dyn Error>> {
+        simulated!(host => host.unsupported("wrap_key"));
+        klave::crypto::subtle::wrap_key(format, key, wrapping_key, algorithm)
+    }
+
+    pub fn unwrap_key(format: &str, wrapped_key: &[u8], unwrapping_key: &CryptoKey, algorithm: &KeyWrapAlgorithm, unwrapped: &KeyGenAlgorithm, extractable: bool, usages: &[&str]) -> Result<CryptoKey, Box<dyn Error>> {
+        simulated!(host => host.unsupported("unwrap_key"));
+        klave::crypto::subtle::unwrap_key(format, wrapped_key, unwrapping_key, algorithm, unwrapped, extractable, usages)
+    }
+
+    pub fn save_key(key: &CryptoKey, name: &str) -> Result<(), Box<dyn Error>> {
+        simulated!(host => host.save_key(key, name));
+        klave::crypto::subtle::save_key(key, name)
+    }
+
+    pub fn load_key(name: &str) -> Result<CryptoKey, Box<dyn Error>> {
+        simulated!(host => host.load_key(name));
+        klave::crypto::subtle::load_key(name)
+    }
+
+    // The key has to come from load_key, the host deletes it by the name it was saved under
+    pub fn delete_key(key: &CryptoKey) -> Result<(), Box<dyn Error>> {
+        simulated!(host => host.delete_key(key));
+        klave::crypto::subtle::delete_key(key)
+    }
+}
@@ -0,0 +1,203 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{
+    database::{self, Field},
+    host::{cell_as_u64, normalize_boolean},
+    notify,
+    utils::sql_literal,
+};
+
+const SYSTEM_SCHEMAS: &str = "'pg_catalog', 'information_schema', 'pg_toast'";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ListTablesInput {
+    pub database_id: String,
+    // Every schema but the system ones when not given
+    #[serde(default)]
+    pub schema: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DescribeTableInput {
+    pub database_id: String,
+    pub table: String,
+    // The current schema of the session when not given, as for an unqualified table name
+    #[serde(default)]
+    pub schema: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TableSummary {
+    pub schema: String,
+    pub name: String,
+    // Planner estimate, None for a table never vacuumed or analyzed
+    pub row_estimate: Option<u64>,
+}
+
+// Column of a table, a Field as the query path returns it plus what information_schema adds
+#[derive(Debug, Clone, Serialize)]
+pub struct ColumnDescription {
+    #[serde(flatten)]
+    pub field: Field,
+    pub default: Option<String>,
+    pub primary_key: bool,
+    pub foreign_key: bool,
+}
+
+fn schema_filter(column: &str, schema: Option<&str>) -> String {
+    match schema {
+        Some(schema) => format!("{} = {}", column, sql_literal(&Value::String(schema.to_string()))),
+        None => format!("{} NOT IN ({})", column, SYSTEM_SCHEMAS),
+    }
+}
+
+pub fn list_tables_query(schema: Option<&str>) -> String {
+    format!("SELECT t.table_schema, t.table_name, c.reltuples::bigint FROM information_schema.tables t \
+        LEFT JOIN pg_namespace n ON n.nspname = t.table_schema \
+        LEFT JOIN pg_class c ON c.relnamespace = n.oid AND c.relname = t.table_name \
+        WHERE t.table_type = 'BASE TABLE' AND {} ORDER BY t.table_schema, t.table_name", schema_filter("t.table_schema", schema))
+}
+
+// One row per column, in ordinal order; the key flags come from the constraints the column takes part in
+pub fn describe_table_query(table: &str, schema: Option<&str>) -> String {
+    let schema = match schema {
+        Some(schema) => sql_literal(&Value::String(schema.to_string())),
+        None => "current_schema()".to_string(),
+    };
+    let constraint = |kind: &str| format!("EXISTS (SELECT 1 FROM information_schema.table_constraints tc \
+        JOIN information_schema.key_column_usage k ON k.constraint_name = tc.constraint_name \
+        AND k.table_schema = tc.table_schema AND k.table_name = tc.table_name \
+        WHERE tc.constraint_type = '{}' AND k.table_schema = c.table_schema AND k.table_name = c.table_name \
+        AND k.column_name = c.column_name)", kind);
+    format!("SELECT c.column_name, \
+        CASE WHEN c.data_type IN ('USER-DEFINED', 'ARRAY') THEN c.udt_name ELSE c.data_type END, \
+        (SELECT t.oid FROM pg_type t JOIN pg_namespace n ON n.oid = t.typnamespace WHERE t.typname = c.udt_name AND n.nspname = c.udt_schema), \
+        c.is_nullable, c.column_default, c.character_maximum_length, c.numeric_scale, {}, {} \
+        FROM information_schema.columns c WHERE c.table_name = {} AND c.table_schema = {} ORDER BY c.ordinal_position",
+        constraint("PRIMARY KEY"), constraint("FOREIGN KEY"), sql_literal(&Value::String(table.to_string())), schema)
+}
+
+fn text(cell: Option<&Value>) -> Option<String> {
+    match cell {
+        Some(Value::String(s)) => Some(s.clone()),
+        Some(Value::Null) | None => None,
+        Some(other) => Some(other.to_string()),
+    }
+}
+
+fn flag(cell: Option<&Value>) -> bool {
+    cell.and_then(normalize_boolean).unwrap_or(false)
+}
+
+pub fn parse_tables(rows: &[Vec<Value>]) -> Vec<TableSummary> {
+    rows.iter().filter_map(|row| Some(TableSummary {
+        schema: text(row.first())?,
+        name: text(row.get(1))?,
+        // -1 since PostgreSQL 14, 0 before, for a table whose size was never estimated
+        row_estimate: row.get(2).and_then(cell_as_u64),
+    })).collect()
+}
+
+pub fn parse_columns(rows: &[Vec<Value>]) -> Vec<ColumnDescription> {
+    rows.iter().filter_map(|row| {
+        let name = text(row.first())?;
+        Some(ColumnDescription {
+            field: Field {
+                field_type: row.get(2).and_then(cell_as_u64).and_then(|oid| u32::try_from(oid).ok()).unwrap_or(0),
+                size: row.get(5).and_then(cell_as_u64),
+                scale: row.get(6).and_then(cell_as_u64).and_then(|scale| u32::try_from(scale).ok()).unwrap_or(0),
+                nullable: text(row.get(3)).map(|n| n == "YES").unwrap_or(true),
+                description: None,
+                type_name: text(row.get(1)),
+                extra: HashMap::new(),
+                name,
+            },
+            default: text(row.get(4)),
+            primary_key: flag(row.get(7)),
+            foreign_key: flag(row.get(8)),
+        })
+    }).collect()
+}
+
+fn connected_client(database_id: &str) -> Option<database::Client> {
+    let mut client = match database::Client::load(database_id.to_string()) {
+        Ok(c) => c,
+        Err(err) => {
+            notify::error_with_code(&format!("Failed to load client: {}", err), err.code());
+            return None;
+        }
+    };
+    if let Err(err) = client.connect() {
+        notify::error_with_code(&format!("Failed to connect to client: {}", err), err.code());
+        return None;
+    }
+    Some(client)
+}
+
+pub fn db_list_tables(cmd: String) {
+    let input: ListTablesInput = match notify::parse_input(&cmd) {
+        Some(input) => input,
+        None => return,
+    };
+    let client = match connected_client(&input.database_id) {
+        Some(c) => c,
+        None => return,
+    };
+    match client.list_tables(input.schema.as_deref()) {
+        Ok(tables) => notify::result(&json!({ "tables": tables })),
+        Err(err) => notify::error_with_code(&format!("Failed to list tables: {}", err), err.code()),
+    }
+}
+
+pub fn db_describe_table(cmd: String) {
+    let input: DescribeTableInput = match notify::parse_input(&cmd) {
+        Some(input) => input,
+        None => return,
+    };
+    let client = match connected_client(&input.database_id) {
+        Some(c) => c,
+        None => return,
+    };
+    match client.describe_table(&input.table, input.schema.as_deref()) {
+        Ok(columns) => notify::result(&json!({ "table": input.table, "columns": columns })),
+        Err(err) => notify::error_with_code(&format!("Failed to describe table: {}", err), err.code()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_schema_queries() {
+        assert!(list_tables_query(None).contains("t.table_schema NOT IN ('pg_catalog', 'information_schema', 'pg_toast')"));
+        assert!(list_tables_query(Some("it's")).contains("t.table_schema = 'it''s'"));
+        let describe = describe_table_query("users", None);
+        assert!(describe.contains("c.table_name = 'users' AND c.table_schema = current_schema()"));
+        assert!(describe.contains("constraint_type = 'PRIMARY KEY'") && describe.contains("constraint_type = 'FOREIGN KEY'"));
+        assert!(describe_table_query("users", Some("audit")).ends_with("c.table_schema = 'audit' ORDER BY c.ordinal_position"));
+    }
+
+    #[test]
+    fn test_parse_schema_rows() {
+        let tables = parse_tables(&[vec![json!("public"), json!("users"), json!("1200")], vec![json!("public"), json!("empty"), json!("-1")]]);
+        assert_eq!(tables[0], TableSummary { schema: "public".to_string(), name: "users".to_string(), row_estimate: Some(1200) });
+        assert_eq!(tables[1].row_estimate, None);
+
+        let columns = parse_columns(&[
+            vec![json!("id"), json!("integer"), json!("23"), json!("NO"), json!("nextval('users_id_seq'::regclass)"), Value::Null, json!("0"), json!(true), json!(false)],
+            vec![json!("org_id"), json!("uuid"), json!("2950"), json!("YES"), Value::Null, Value::Null, Value::Null, json!("f"), json!("t")],
+        ]);
+        let id = &columns[0];
+        assert_eq!((id.field.name.as_str(), id.field.field_type, id.field.nullable, id.primary_key, id.foreign_key), ("id", 23, false, true, false));
+        assert_eq!(id.default.as_deref(), Some("nextval('users_id_seq'::regclass)"));
+        assert_eq!((columns[1].field.nullable, columns[1].primary_key, columns[1].foreign_key), (true, false, true));
+        let described = serde_json::to_value(id).unwrap();
+        assert_eq!((described["name"].clone(), described["type"].clone(), described["type_name"].clone()), (json!("id"), json!(23), json!("integer")));
+    }
+}
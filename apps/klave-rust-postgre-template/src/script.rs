@@ -0,0 +1,435 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+
+use crate::{audit, backup, ciphertext_ops, consistency::check_session_statement, crypto::compute_sha256_hex_string, database::{self, rows_as_objects, DatabaseError, PostGreResponse, RowFormat}, host::{fatal_notice, Notice}, notify, settings::{AccessLevel, DeploymentSettings}, statement::{self, StatementKind}, strict, utils::get_trusted_time};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SqlScriptInput {
+    pub database_id: String,
+    pub script: String,
+    #[serde(default)]
+    pub stop_on_error: bool,
+    #[serde(default)]
+    pub transactional: bool,
+    // Opts out of the deployment default_query_limit
+    #[serde(default)]
+    pub no_limit: bool,
+    // Runs ORDER BY, ranges and comparisons with literals on encrypted columns instead of refusing them
+    #[serde(default)]
+    pub allow_ciphertext_ops: bool,
+    // Seals the rows changed by each UPDATE or DELETE before it runs, see restore_row_backup
+    #[serde(default)]
+    pub backup_before_write: bool,
+    // Single-use exception issued by the admin when the client is in strict encrypted access mode
+    #[serde(default)]
+    pub strict_bypass_token: Option<String>,
+    // Runs the script in a transaction that is always rolled back, to preview what it would change
+    #[serde(default)]
+    pub dry_run: bool,
+    // Objects moves the rows of each resultset to rows, keyed by field name
+    #[serde(default)]
+    pub format: RowFormat,
+}
+
+// Statements executed in order in a single transaction, see sql_transaction
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TransactionInput {
+    pub database_id: String,
+    pub statements: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TransactionStatementResult {
+    pub index: usize,
+    pub status: StatementStatus,
+    pub rows_affected: Option<u64>,
+    pub command_tag: Option<String>,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TransactionOutcome {
+    pub committed: bool,
+    pub statements: Vec<TransactionStatementResult>,
+    // Index of the statement whose failure rolled the transaction back
+    pub failed_index: Option<usize>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StatementStatus {
+    Ok,
+    Error,
+    Skipped,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StatementOutcome {
+    pub index: usize,
+    pub kind: StatementKind,
+    pub line: usize,
+    pub column: usize,
+    pub offset: usize,
+    pub statement_hash: String,
+    pub status: StatementStatus,
+    pub resultset: Option<PostGreResponse<Vec<Vec<Value>>>>,
+    // Rows of the resultset as objects when the objects format was asked for
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rows: Option<Vec<Map<String, Value>>>,
+    pub rows_affected: Option<u64>,
+    #[serde(default)]
+    pub command_tag: Option<String>,
+    pub error: Option<String>,
+    // The resultset was cut at the default query limit
+    #[serde(default)]
+    pub truncated: bool,
+    // Query returning the rows that were cut
+    #[serde(default)]
+    pub next: Option<String>,
+    // Backups of the rows the statement changed
+    #[serde(default)]
+    pub backup_ids: Vec<String>,
+    // Notices the server raised while running the statement
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub notices: Vec<Notice>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScriptOutcome {
+    pub statements: Vec<StatementOutcome>,
+    pub transactional: bool,
+    pub committed: Option<bool>,
+    // Nothing was committed; sequences advanced by the script are not rolled back
+    #[serde(default)]
+    pub dry_run: bool,
+    pub started_at: u64,
+    pub elapsed: u64,
+    // Notices raised over the whole script
+    #[serde(default)]
+    pub notice_count: usize,
+}
+
+// Statement of a dry run that would fail or escape the rolled-back transaction, with the reason
+pub fn dry_run_refusal(statements: &[statement::Statement]) -> Option<(&statement::Statement, String)> {
+    statements.iter().find_map(|stmt| {
+        if statement::classify(&stmt.text) == StatementKind::TransactionControl {
+            return Some((stmt, "transaction control would end the rolled-back transaction".to_string()));
+        }
+        statement::transaction_block_restriction(&stmt.text).map(|reason| (stmt, reason))
+    })
+}
+
+// Runs every statement of the script in order and collects one outcome per statement.
+// In transactional mode the script is wrapped in BEGIN/COMMIT and any failure rolls everything back.
+// A dry run is wrapped in BEGIN/ROLLBACK, rows handed back by RETURNING are read as a resultset.
+// Schema changes raising a notice that the deployment notice policy makes fatal count as failed.
+pub fn run_script(client: &database::Client, input: &SqlScriptInput, statements: Vec<statement::Statement>, query_limit: u64, settings: &DeploymentSettings) -> ScriptOutcome {
+    let started_at = get_trusted_time();
+    let mut outcomes: Vec<StatementOutcome> = Vec::new();
+    let mut aborted = false;
+    let mut committed = None;
+    let transactional = input.transactional || input.dry_run;
+    // Nothing is changed by a dry run, so there is nothing to back up
+    let backup_before_write = !input.dry_run && (input.backup_before_write || client.backup_before_write());
+
+    if transactional {
+        if let Err(err) = client.begin_transaction() {
+            aborted = true;
+            committed = Some(false);
+            notify::warning(&format!("Failed to open transaction: {}", err));
+        }
+    }
+
+    for stmt in statements.iter() {
+        let kind = statement::classify(&stmt.text);
+        let mut outcome = StatementOutcome {
+            index: stmt.index,
+            kind,
+            line: stmt.line,
+            column: stmt.column,
+            offset: stmt.offset,
+            statement_hash: compute_sha256_hex_string(stmt.text.as_bytes()),
+            status: StatementStatus::Skipped,
+            resultset: None,
+            rows: None,
+            rows_affected: None,
+            command_tag: None,
+            error: None,
+            truncated: false,
+            next: None,
+            backup_ids: Vec::new(),
+            notices: Vec::new(),
+        };
+        if aborted {
+            outcomes.push(outcome);
+            continue;
+        }
+
+        let result: Result<(), Box<dyn std::error::Error>> = match kind {
+            StatementKind::Query => client.query_limited(&stmt.text, query_limit).map(|mut limited| {
+                outcome.notices = std::mem::take(&mut limited.response.notices);
+                outcome.resultset = Some(limited.response);
+                outcome.truncated = limited.next.is_some();
+                outcome.next = limited.next;
+            }),
+            StatementKind::Execute if input.dry_run && statement::has_returning(&stmt.text) => client.query_returning::<Vec<Vec<Value>>>(&stmt.text).map(|mut response| {
+                outcome.rows_affected = Some(response.resultset.len() as u64);
+                outcome.notices = std::mem::take(&mut response.notices);
+                outcome.resultset = Some(response);
+            }).map_err(|e| e.into()),
+            StatementKind::Execute | StatementKind::TransactionControl => {
+                // A statement whose rows could not be backed up is not run
+                let backed_up: Result<(), Box<dyn std::error::Error>> = match kind {
+                    StatementKind::Execute if backup_before_write => backup::backup_statement_rows(client, &stmt.text, stmt.index)
+                        .map(|ids| outcome.backup_ids = ids)
+                        .map_err(|e| format!("Row backup failed: {}", e).into()),
+                    _ => Ok(()),
+                };
+                backed_up.and_then(|_| Ok(client.execute(&stmt.text)?)).and_then(|res| {
+                    outcome.rows_affected = res.rows_affected;
+                    outcome.command_tag = res.command_tag;
+                    outcome.notices = res.notices;
+                    if !statement::is_schema_change(&stmt.text) {
+                        return Ok(());
+                    }
+                    match fatal_notice(&outcome.notices, settings.notice_policy, &settings.notice_error_patterns) {
+                        Some(notice) => Err(format!("{} treated as an error: {}", notice.severity, notice.message).into()),
+                        None => Ok(()),
+                    }
+                })
+            }
+        };
+        if input.format == RowFormat::Objects {
+            if let Some(response) = outcome.resultset.as_mut() {
+                outcome.rows = Some(rows_as_objects(&response.fields, std::mem::take(&mut response.resultset)));
+            }
+        }
+        match result {
+            Ok(_) => outcome.status = StatementStatus::Ok,
+            Err(err) => {
+                outcome.status = StatementStatus::Error;
+                outcome.error = Some(format!("Statement {} failed at line {}, column {}: {}", stmt.index, stmt.line, stmt.column, err));
+                // A failed statement aborts the surrounding transaction in PostgreSQL
+                if input.stop_on_error || transactional {
+                    aborted = true;
+                }
+            }
+        }
+        outcomes.push(outcome);
+    }
+
+    if transactional && committed.is_none() {
+        let failed = outcomes.iter().any(|o| matches!(o.status, StatementStatus::Error));
+        let (end, closed) = if failed || input.dry_run { ("ROLLBACK", client.rollback()) } else { ("COMMIT", client.commit()) };
+        committed = match closed {
+            Ok(_) => Some(!failed && !input.dry_run),
+            Err(err) => {
+                notify::warning(&format!("Failed to {} transaction: {}", end, err));
+                Some(false)
+            }
+        };
+    }
+
+    let notice_count = outcomes.iter().map(|o| o.notices.len()).sum();
+    ScriptOutcome {
+        statements: outcomes,
+        transactional,
+        committed,
+        dry_run: input.dry_run,
+        started_at,
+        elapsed: get_trusted_time().saturating_sub(started_at),
+        notice_count,
+    }
+}
+
+pub fn sql_script(cmd: String) {
+    let input: SqlScriptInput = match notify::parse_input(&cmd) {
+        Some(input) => input,
+        None => return,
+    };
+
+    let statements = match statement::split_statements(&input.script) {
+        Ok(s) => s,
+        Err(err) => {
+            let (line, column) = statement::line_col(&input.script, err.offset);
+            notify::error(&format!("Failed to parse script at line {}, column {}: {}", line, column, err.message));
+            return;
+        }
+    };
+    if statements.is_empty() {
+        notify::error("Script contains no statements");
+        return;
+    }
+    if input.dry_run {
+        if let Some((stmt, reason)) = dry_run_refusal(&statements) {
+            notify::error(&format!("Statement {} at line {}, column {} cannot be dry run: {}", stmt.index, stmt.line, stmt.column, reason));
+            return;
+        }
+    }
+    // The script is already wrapped in a transaction, nested transaction control would break it
+    if input.transactional {
+        if let Some(stmt) = statements.iter().find(|s| statement::classify(&s.text) == StatementKind::TransactionControl) {
+            notify::error(&format!("Transaction control statement {} at line {}, column {} is not allowed in a transactional script", stmt.index, stmt.line, stmt.column));
+            return;
+        }
+    }
+
+    if !input.allow_ciphertext_ops && !ciphertext_ops::allow_statements(&input.database_id, statements.iter().map(|s| s.text.as_str())) {
+        return;
+    }
+
+    let mut client: database::Client = match database::Client::load(input.database_id.clone()) {
+        Ok(c) => c,
+        Err(err) => {
+            notify::error_with_code(&format!("Failed to load client: {}", err), err.code());
+            return;
+        }
+    };
+    if !strict::allow_statements(&mut client, statements.iter().map(|s| s.text.as_str()), input.strict_bypass_token.as_deref()) {
+        return;
+    }
+    if client.access() == AccessLevel::ReportingOnly {
+        if let Some((stmt, err)) = statements.iter().find_map(|s| check_session_statement(&s.text).err().map(|e| (s, e))) {
+            notify::error(&format!("Statement {} at line {}, column {} is refused for a reporting-only caller: {}", stmt.index, stmt.line, stmt.column, err));
+            return;
+        }
+    }
+    match client.connect() {
+        Ok(_) => (),
+        Err(err) => {
+            notify::error_with_code(&format!("Failed to connect to client: {}", err), err.code());
+            return;
+        }
+    };
+
+    let settings = match DeploymentSettings::load() {
+        Ok(settings) => settings,
+        Err(err) => {
+            notify::error_with_code(&format!("Failed to load settings: {}", err), DatabaseError::code_of(err.as_ref()));
+            return;
+        }
+    };
+    let query_limit = if input.no_limit { 0 } else { settings.default_query_limit };
+
+    let outcome = run_script(&client, &input, statements, query_limit, &settings);
+
+    let failed = outcome.statements.iter().filter(|o| matches!(o.status, StatementStatus::Error)).count();
+    audit::record(
+        "sql_script",
+        Some(&input.database_id),
+        if failed == 0 { "success" } else { "failure" },
+        json!({
+            "transactional": input.transactional,
+            "stop_on_error": input.stop_on_error,
+            "committed": outcome.committed,
+            "dry_run": input.dry_run,
+            "failed_statements": failed,
+            "notices": outcome.notice_count,
+            "statement_hashes": outcome.statements.iter().map(|o| o.statement_hash.clone()).collect::<Vec<String>>(),
+        }),
+    );
+
+    notify::result(&outcome);
+}
+
+// Refuses a transaction before it reaches the database: nothing to run, or statements that would end it early
+pub fn check_transaction_statements(statements: &[String]) -> Result<(), String> {
+    if statements.is_empty() {
+        return Err("Invalid input: statements must not be empty".to_string());
+    }
+    if let Some(index) = statements.iter().position(|s| s.trim().is_empty()) {
+        return Err(format!("Invalid input: statement {} is empty", index));
+    }
+    if let Some(index) = statements.iter().position(|s| statement::classify(s) == StatementKind::TransactionControl) {
+        return Err(format!("Invalid input: statement {} is transaction control, the statements already run in a transaction", index));
+    }
+    Ok(())
+}
+
+// Executes the statements in order, stopping at the first failure; the statements after it are skipped
+pub fn run_transaction(client: &database::Client, statements: &[String]) -> TransactionOutcome {
+    let mut results = Vec::new();
+    let mut failed_index = None;
+    for (index, text) in statements.iter().enumerate() {
+        if failed_index.is_some() {
+            results.push(TransactionStatementResult { index, status: StatementStatus::Skipped, rows_affected: None, command_tag: None, error: None });
+            continue;
+        }
+        match client.execute(text) {
+            Ok(result) => results.push(TransactionStatementResult { index, status: StatementStatus::Ok, rows_affected: result.rows_affected, command_tag: result.command_tag, error: None }),
+            Err(err) => {
+                failed_index = Some(index);
+                results.push(TransactionStatementResult { index, status: StatementStatus::Error, rows_affected: None, command_tag: None, error: Some(err.to_string()) });
+            }
+        }
+    }
+    TransactionOutcome { committed: false, statements: results, failed_index }
+}
+
+// Runs a list of statements atomically: every statement is committed, or none is when one fails
+pub fn sql_transaction(cmd: String) {
+    let input: TransactionInput = match notify::parse_input(&cmd) {
+        Some(input) => input,
+        None => return,
+    };
+    if let Err(err) = check_transaction_statements(&input.statements) {
+        notify::error(&err);
+        return;
+    }
+    if !ciphertext_ops::allow_statements(&input.database_id, input.statements.iter().map(|s| s.as_str())) {
+        return;
+    }
+
+    let mut client: database::Client = match database::Client::load(input.database_id.clone()) {
+        Ok(c) => c,
+        Err(err) => {
+            notify::error_with_code(&format!("Failed to load client: {}", err), err.code());
+            return;
+        }
+    };
+    if !strict::allow_statements(&mut client, input.statements.iter().map(|s| s.as_str()), None) {
+        return;
+    }
+    if client.access() == AccessLevel::ReportingOnly {
+        if let Some((index, err)) = input.statements.iter().enumerate().find_map(|(i, s)| check_session_statement(s).err().map(|e| (i, e))) {
+            notify::error(&format!("Statement {} is refused for a reporting-only caller: {}", index, err));
+            return;
+        }
+    }
+    if let Err(err) = client.connect() {
+        notify::error_with_code(&format!("Failed to connect to client: {}", err), err.code());
+        return;
+    }
+    if let Err(err) = client.begin_transaction() {
+        notify::error_with_code(&format!("Failed to open a transaction: {}", err), err.code());
+        return;
+    }
+
+    let mut outcome = run_transaction(&client, &input.statements);
+    let statement_hashes: Vec<String> = input.statements.iter().map(|s| compute_sha256_hex_string(s.as_bytes())).collect();
+    let failed = match (outcome.failed_index, outcome.statements.iter().find_map(|s| s.error.clone())) {
+        (Some(index), Some(err)) => {
+            let rollback_err = client.rollback().err();
+            Some(format!("Statement {} failed: {}", index, database::rollback_error(&err, rollback_err.as_ref())))
+        },
+        _ => match client.commit() {
+            Ok(_) => {
+                outcome.committed = true;
+                None
+            },
+            Err(err) => Some(format!("Failed to commit the transaction: {}", err)),
+        },
+    };
+    audit::record(
+        "sql_transaction",
+        Some(&input.database_id),
+        if outcome.committed { "success" } else { "failure" },
+        json!({ "committed": outcome.committed, "failed_index": outcome.failed_index, "statement_hashes": statement_hashes }),
+    );
+    match failed {
+        Some(message) => notify::error_with_details(&message, &outcome),
+        None => notify::result(&outcome),
+    }
+}
@@ -0,0 +1,199 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{audit, capabilities::{self, Capability}, database::{self, Client, DatabaseError}, host::normalize_boolean, manifest::EncryptionManifest, notify, utils::{escape_like, get_trusted_time, is_plain_identifier, LikeInput}};
+
+// Prefix of the indexes created on ciphertext columns
+const SEARCH_INDEX_PREFIX: &str = "kl_search_";
+// PostgreSQL truncates identifiers beyond this length
+const MAX_IDENTIFIER_LENGTH: usize = 63;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SearchIndexInput {
+    pub database_id: String,
+    pub table: String,
+    pub column: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SearchIndexProgressInput {
+    pub database_id: String,
+}
+
+// FNV-1a, only used to keep truncated index names apart
+fn short_hash(value: &str) -> String {
+    let hash = value.bytes().fold(0xcbf29ce484222325u64, |h, b| (h ^ b as u64).wrapping_mul(0x100000001b3));
+    format!("{:08x}", hash as u32)
+}
+
+// Generated name of the search index of a column, shortened with a hash suffix when the table and column
+// names do not fit in a PostgreSQL identifier
+pub fn search_index_name(table: &str, column: &str) -> String {
+    let name = format!("{}{}_{}", SEARCH_INDEX_PREFIX, table, column).to_lowercase();
+    if name.len() <= MAX_IDENTIFIER_LENGTH {
+        return name;
+    }
+    let hash = short_hash(&name);
+    format!("{}_{}", &name[..MAX_IDENTIFIER_LENGTH - hash.len() - 1], hash)
+}
+
+fn check_input(input: &SearchIndexInput) -> Result<(), String> {
+    for name in [&input.table, &input.column] {
+        if !is_plain_identifier(name) {
+            return Err(format!("'{}' is not a plain identifier", name));
+        }
+    }
+    Ok(())
+}
+
+// CREATE INDEX CONCURRENTLY cannot run inside a transaction block, each statement below is sent on its own.
+// A failed concurrent build leaves an invalid index behind, which is dropped before reporting the failure.
+fn create_search_index_for(client: &Client, input: &SearchIndexInput) -> Result<String, Box<dyn std::error::Error>> {
+    let manifest = EncryptionManifest::load(client.database_id())?;
+    let column = manifest.column(&input.table, &input.column).ok_or(format!("Column {}.{} is not encrypted", input.table, input.column))?;
+    if let Some(existing) = &column.search_index {
+        return Ok(existing.clone());
+    }
+
+    capabilities::require(client.database_id(), Capability::ConcurrentIndex)?;
+    let name = search_index_name(&input.table, &input.column);
+    let created = client.execute_ddl(&format!("CREATE INDEX CONCURRENTLY IF NOT EXISTS {} ON {} ({})", name, input.table, input.column));
+    let valid = created.is_ok() && client.query::<Vec<Vec<Value>>>(&format!("SELECT indisvalid FROM pg_index WHERE indexrelid = '{}'::regclass", name))?
+        .resultset.first().and_then(|r| r.first()).and_then(normalize_boolean).unwrap_or(false);
+    if !valid {
+        let _ = client.execute(&format!("DROP INDEX CONCURRENTLY IF EXISTS {}", name));
+        return Err(match created {
+            Err(err) => format!("Failed to build index {}: {}", name, err),
+            Ok(_) => format!("Index {} was left invalid by a concurrent build and has been dropped", name),
+        }.into());
+    }
+
+    EncryptionManifest::update(client.database_id(), |manifest| manifest.set_search_index(&input.table, &input.column, Some(name.clone()), get_trusted_time()))?;
+    Ok(name)
+}
+
+fn drop_search_index_for(client: &Client, input: &SearchIndexInput) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    let manifest = EncryptionManifest::load(client.database_id())?;
+    let column = manifest.column(&input.table, &input.column).ok_or(format!("Column {}.{} is not encrypted", input.table, input.column))?;
+    let name = match &column.search_index {
+        Some(name) => name.clone(),
+        None => return Ok(None),
+    };
+    capabilities::require(client.database_id(), Capability::ConcurrentIndex)?;
+    client.execute_ddl(&format!("DROP INDEX CONCURRENTLY IF EXISTS {}", name))?;
+    EncryptionManifest::update(client.database_id(), |manifest| manifest.set_search_index(&input.table, &input.column, None, get_trusted_time()))?;
+    Ok(Some(name))
+}
+
+fn load_and_connect(database_id: &str) -> Option<Client> {
+    let mut client: database::Client = match database::Client::load(database_id.to_string()) {
+        Ok(c) => c,
+        Err(err) => {
+            notify::error_with_code(&format!("Failed to load client: {}", err), err.code());
+            return None;
+        }
+    };
+    match client.connect() {
+        Ok(_) => Some(client),
+        Err(err) => {
+            notify::error_with_code(&format!("Failed to connect to client: {}", err), err.code());
+            None
+        }
+    }
+}
+
+fn parse_input(cmd: &str) -> Option<SearchIndexInput> {
+    let input: SearchIndexInput = notify::parse_input(cmd)?;
+    if let Err(err) = check_input(&input) {
+        notify::error(&format!("Invalid input: {}", err));
+        return None;
+    }
+    Some(input)
+}
+
+pub fn create_search_index(cmd: String) {
+    let (input, client) = match parse_input(&cmd).and_then(|i| load_and_connect(&i.database_id).map(|c| (i, c))) {
+        Some(loaded) => loaded,
+        None => return,
+    };
+
+    match create_search_index_for(&client, &input) {
+        Ok(index) => {
+            audit::record("create_search_index", Some(&input.database_id), "success", json!({ "table": input.table, "column": input.column, "index": index }));
+            notify::result(&json!({ "table": input.table, "column": input.column, "index": index }));
+        },
+        Err(err) => {
+            audit::record("create_search_index", Some(&input.database_id), "failure", json!({ "table": input.table, "column": input.column }));
+            notify::error_with_code(&format!("Failed to create search index: {}", err), DatabaseError::code_of(err.as_ref()));
+        }
+    }
+}
+
+pub fn drop_search_index(cmd: String) {
+    let (input, client) = match parse_input(&cmd).and_then(|i| load_and_connect(&i.database_id).map(|c| (i, c))) {
+        Some(loaded) => loaded,
+        None => return,
+    };
+
+    match drop_search_index_for(&client, &input) {
+        Ok(index) => {
+            audit::record("drop_search_index", Some(&input.database_id), "success", json!({ "table": input.table, "column": input.column, "index": index }));
+            notify::result(&json!({ "table": input.table, "column": input.column, "dropped": index }));
+        },
+        Err(err) => {
+            audit::record("drop_search_index", Some(&input.database_id), "failure", json!({ "table": input.table, "column": input.column }));
+            notify::error_with_code(&format!("Failed to drop search index: {}", err), DatabaseError::code_of(err.as_ref()));
+        }
+    }
+}
+
+// Progress of the search index builds currently running, polled from another call while create_search_index
+// is waiting on its CREATE INDEX CONCURRENTLY
+pub fn search_index_progress(cmd: String) {
+    let input: SearchIndexProgressInput = match notify::parse_input(&cmd) {
+        Some(input) => input,
+        None => return,
+    };
+    let client = match load_and_connect(&input.database_id) {
+        Some(c) => c,
+        None => return,
+    };
+
+    let mut pattern = escape_like(SEARCH_INDEX_PREFIX, '\\', LikeInput::Literal);
+    pattern.pattern.push('%');
+    let query = format!("SELECT i.relname AS index, p.phase, p.blocks_done, p.blocks_total, p.tuples_done, p.tuples_total \
+        FROM pg_stat_progress_create_index AS p \
+        INNER JOIN pg_class AS i ON i.oid = p.index_relid \
+        WHERE i.relname LIKE {}", pattern.to_sql());
+    match client.query::<Vec<Vec<Value>>>(&query) {
+        Ok(res) => {
+            notify::result(&res);
+        },
+        Err(err) => {
+            notify::error_with_code(&format!("Failed to read index build progress: {}", err), err.code());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_search_index_name() {
+        assert_eq!(search_index_name("Users", "email"), "kl_search_users_email");
+        let long = search_index_name(&"t".repeat(40), &"c".repeat(40));
+        assert_eq!(long.len(), MAX_IDENTIFIER_LENGTH);
+        assert_ne!(long, search_index_name(&"t".repeat(40), &"c".repeat(41)));
+    }
+
+    #[test]
+    fn test_check_input() {
+        let input = |table: &str, column: &str| SearchIndexInput { database_id: "db".to_string(), table: table.to_string(), column: column.to_string() };
+        assert!(check_input(&input("users", "first_name")).is_ok());
+        assert!(check_input(&input("users; DROP TABLE users", "email")).is_err());
+        assert!(check_input(&input("users", "1email")).is_err());
+    }
+}
@@ -0,0 +1,336 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{budget::CostModel, database::{Clients, DatabaseError}, errors::UnknownFieldPolicy, faults::FaultInjection, host::{NoticePolicy, TextDecodePolicy}, notify::{self, ResultShape}, storage, utils::get_client_id};
+
+pub(crate) const DEPLOYMENT_SETTINGS_TABLE: &str = "DeploymentSettingsTable";
+const DEPLOYMENT_SETTINGS_KEY: &str = "settings";
+
+fn default_max_generated_rows() -> u64 {
+    10_000
+}
+
+fn default_query_limit() -> u64 {
+    1_000
+}
+
+fn default_row_backup_retention_seconds() -> u64 {
+    7 * 24 * 60 * 60
+}
+
+fn default_row_backup_quota_bytes() -> u64 {
+    10 * 1024 * 1024
+}
+
+fn default_audit_detail_retention_seconds() -> u64 {
+    30 * 24 * 60 * 60
+}
+
+fn default_job_record_retention_seconds() -> u64 {
+    7 * 24 * 60 * 60
+}
+
+fn default_credential_expiry_warning_seconds() -> u64 {
+    7 * 24 * 60 * 60
+}
+
+fn default_watch_max_rows() -> u64 {
+    5_000
+}
+
+fn default_job_stale_after_seconds() -> u64 {
+    5 * 60
+}
+
+fn default_legacy_routes_enabled() -> bool {
+    true
+}
+
+fn default_notice_error_patterns() -> Vec<String> {
+    vec!["will be truncated".to_string()]
+}
+
+// What a caller may do with the registered databases
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AccessLevel {
+    #[default]
+    Full,
+    // Read-only statements, encrypted columns masked, no key is ever loaded
+    ReportingOnly,
+}
+
+// Deployment-wide settings, stored as a single ledger record.
+// Every field has a default so that records written by older versions keep loading.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeploymentSettings {
+    // Identity allowed to change the settings, claimed by the first caller of update_settings
+    #[serde(default)]
+    pub admin: Option<String>,
+    #[serde(default = "default_max_generated_rows")]
+    pub max_generated_rows: u64,
+    #[serde(default)]
+    pub cost_model: CostModel,
+    // Rows returned by a raw SELECT without LIMIT unless the caller opts out, 0 disables the limit
+    #[serde(default = "default_query_limit")]
+    pub default_query_limit: u64,
+    // Reads of text the host could not decode as UTF-8 either fail or flag the cells
+    #[serde(default)]
+    pub text_decode_policy: TextDecodePolicy,
+    // Callers granted reporting access only
+    #[serde(default)]
+    pub reporting_only: Vec<String>,
+    // How long row backups taken before UPDATE/DELETE statements can be restored
+    #[serde(default = "default_row_backup_retention_seconds")]
+    pub row_backup_retention_seconds: u64,
+    // Sealed row backups a client may keep, in bytes
+    #[serde(default = "default_row_backup_quota_bytes")]
+    pub row_backup_quota_bytes: u64,
+    // Detailed audit entries older than this are compacted into daily aggregates by prune_history, 0 keeps them
+    #[serde(default = "default_audit_detail_retention_seconds")]
+    pub audit_detail_retention_seconds: u64,
+    // Daily audit aggregates older than this are deleted by prune_history, 0 keeps them
+    #[serde(default)]
+    pub audit_aggregate_retention_seconds: u64,
+    // Abandoned job locks older than this are deleted by prune_history
+    #[serde(default = "default_job_record_retention_seconds")]
+    pub job_record_retention_seconds: u64,
+    // An encryption job without a call or heartbeat for this long is abandoned: its locks can be taken over
+    // and reap_stale_jobs marks it Stalled
+    #[serde(default = "default_job_stale_after_seconds")]
+    pub job_stale_after_seconds: u64,
+    // Route names superseded by a versioned alias keep answering, with a deprecation notice, until this is turned off
+    #[serde(default = "default_legacy_routes_enabled")]
+    pub legacy_routes_enabled: bool,
+    // Serialization of the routes called by their original name; the envelope answers every route
+    // with { success, data } or { success, error }. Versioned aliases always use the envelope.
+    #[serde(default)]
+    pub result_shape: ResultShape,
+    // Fields a payload sent to an original route name holds but its input does not define are dropped
+    // with a warning, or fail the call. Versioned aliases always reject them.
+    #[serde(default)]
+    pub unknown_input_fields: UnknownFieldPolicy,
+    // Whether a WARNING raised by a schema change fails it
+    #[serde(default)]
+    pub notice_policy: NoticePolicy,
+    // Notices that fail a schema change whatever their severity, matched case-insensitively in the message
+    #[serde(default = "default_notice_error_patterns")]
+    pub notice_error_patterns: Vec<String>,
+    // Calls against a client whose credentials_expire_at falls within this window carry a warning
+    #[serde(default = "default_credential_expiry_warning_seconds")]
+    pub credential_expiry_warning_seconds: u64,
+    // Delays and failures injected into matching calls for integration testing, never into production clients
+    #[serde(default)]
+    pub fault_injection: FaultInjection,
+    // Rows a watched query may return, checked when it is registered and on every run
+    #[serde(default = "default_watch_max_rows")]
+    pub watch_max_rows: u64,
+    // Ledger bytes the crate may use, checked before row backups, cached host state and job records, 0 disables it
+    #[serde(default)]
+    pub storage_quota_bytes: u64,
+}
+
+impl Default for DeploymentSettings {
+    fn default() -> Self {
+        Self {
+            admin: None,
+            max_generated_rows: default_max_generated_rows(),
+            cost_model: CostModel::default(),
+            default_query_limit: default_query_limit(),
+            text_decode_policy: TextDecodePolicy::default(),
+            reporting_only: Vec::new(),
+            row_backup_retention_seconds: default_row_backup_retention_seconds(),
+            row_backup_quota_bytes: default_row_backup_quota_bytes(),
+            audit_detail_retention_seconds: default_audit_detail_retention_seconds(),
+            audit_aggregate_retention_seconds: 0,
+            job_record_retention_seconds: default_job_record_retention_seconds(),
+            job_stale_after_seconds: default_job_stale_after_seconds(),
+            legacy_routes_enabled: default_legacy_routes_enabled(),
+            result_shape: ResultShape::default(),
+            unknown_input_fields: UnknownFieldPolicy::default(),
+            notice_policy: NoticePolicy::default(),
+            notice_error_patterns: default_notice_error_patterns(),
+            credential_expiry_warning_seconds: default_credential_expiry_warning_seconds(),
+            fault_injection: FaultInjection::default(),
+            watch_max_rows: default_watch_max_rows(),
+            storage_quota_bytes: 0,
+        }
+    }
+}
+
+impl DeploymentSettings {
+    pub fn load() -> Result<DeploymentSettings, Box<dyn std::error::Error>> {
+        match crate::runtime::ledger::get_table(DEPLOYMENT_SETTINGS_TABLE).get(DEPLOYMENT_SETTINGS_KEY) {
+            Ok(v) => {
+                let settings: DeploymentSettings = match serde_json::from_slice(&v) {
+                    Ok(s) => s,
+                    Err(e) => {
+                        notify::warning(&format!("ERROR: failed to parse deployment settings: {}", e));
+                        return Err(e.into());
+                    }
+                };
+                Ok(settings)
+            },
+            Err(_e) => Ok(DeploymentSettings::default())
+        }
+    }
+
+    // True once settings were written, load returns the defaults until then
+    pub fn exists() -> bool {
+        crate::runtime::ledger::get_table(DEPLOYMENT_SETTINGS_TABLE).get(DEPLOYMENT_SETTINGS_KEY).is_ok()
+    }
+
+    pub fn save(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let serialized = serde_json::to_string(self)?;
+        storage::ledger_set(DEPLOYMENT_SETTINGS_TABLE, DEPLOYMENT_SETTINGS_KEY, serialized.as_bytes())
+    }
+
+    pub fn is_admin(&self, client_id: &str) -> bool {
+        self.admin.as_deref() == Some(client_id)
+    }
+
+    // The admin always keeps full access, so that a grant cannot lock the deployment out of its keys
+    pub fn access_level(&self, client_id: &str) -> AccessLevel {
+        if !self.is_admin(client_id) && self.reporting_only.iter().any(|c| c == client_id) {
+            AccessLevel::ReportingOnly
+        } else {
+            AccessLevel::Full
+        }
+    }
+
+    // Overlays the given JSON object on the current settings; unknown keys are rejected.
+    pub fn merge(&self, patch: &Value) -> Result<DeploymentSettings, Box<dyn std::error::Error>> {
+        let patch = patch.as_object().ok_or("Settings patch must be a JSON object")?;
+        if patch.contains_key("admin") {
+            return Err("The admin identity cannot be changed through update_settings".into());
+        }
+        let mut current = serde_json::to_value(self)?;
+        let known: Vec<String> = current.as_object().map(|o| o.keys().cloned().collect()).unwrap_or_default();
+        for (key, value) in patch.iter() {
+            if !known.contains(key) {
+                return Err(format!("Unknown setting '{}'", key).into());
+            }
+            current[key] = value.clone();
+        }
+        Ok(serde_json::from_value(current)?)
+    }
+}
+
+// Refuses callers other than the admin recorded in the settings, for routes acting on the whole deployment
+pub fn require_admin(action: &str) -> Result<(), String> {
+    let settings = DeploymentSettings::load().map_err(|e| format!("Failed to load settings: {}", e))?;
+    let client_id = get_client_id();
+    if client_id.is_empty() || !settings.is_admin(&client_id) {
+        return Err(format!("Only the deployment admin can {}", action));
+    }
+    Ok(())
+}
+
+pub fn get_settings(_cmd: String) {
+    match DeploymentSettings::load() {
+        Ok(settings) => {
+            notify::result(&settings);
+        },
+        Err(err) => {
+            notify::error_with_code(&format!("Failed to load settings: {}", err), DatabaseError::code_of(err.as_ref()));
+        }
+    }
+}
+
+pub fn update_settings(cmd: String) {
+    let patch: Value = match notify::parse_input(&cmd) {
+        Some(input) => input,
+        None => return,
+    };
+    let settings = match DeploymentSettings::load() {
+        Ok(s) => s,
+        Err(err) => {
+            notify::error_with_code(&format!("Failed to load settings: {}", err), DatabaseError::code_of(err.as_ref()));
+            return;
+        }
+    };
+
+    let client_id = get_client_id();
+    if client_id.is_empty() {
+        notify::error("Failed to identify the caller");
+        return;
+    }
+    if settings.admin.is_some() && !settings.is_admin(&client_id) {
+        notify::error("Only the deployment admin can update settings");
+        return;
+    }
+
+    let mut updated = match settings.merge(&patch) {
+        Ok(s) => s,
+        Err(err) => {
+            notify::error_with_code(&format!("Invalid settings: {}", err), DatabaseError::code_of(err.as_ref()));
+            return;
+        }
+    };
+    if updated.fault_injection.enabled {
+        let production_ids = match Clients::load_all().and_then(|c| c.list()) {
+            Ok(clients) => clients.iter().filter(|c| c.is_production()).map(|c| c.database_id().to_string()).collect::<Vec<String>>(),
+            Err(err) => {
+                notify::error_with_code(&format!("Failed to load clients: {}", err), err.code());
+                return;
+            }
+        };
+        if let Err(err) = updated.fault_injection.validate(&production_ids) {
+            notify::error(&format!("Invalid settings: {}", err));
+            return;
+        }
+    }
+    // First writer claims the admin role
+    updated.admin = Some(settings.admin.clone().unwrap_or(client_id));
+
+    match updated.save() {
+        Ok(_) => {
+            notify::result(&updated);
+        },
+        Err(err) => {
+            notify::error_with_code(&format!("Failed to save settings: {}", err), DatabaseError::code_of(err.as_ref()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_merge_settings() {
+        let settings = DeploymentSettings::default();
+        let merged = settings.merge(&json!({ "max_generated_rows": 50 })).unwrap();
+        assert_eq!(merged.max_generated_rows, 50);
+        assert!(settings.merge(&json!({ "unknown": 1 })).is_err());
+        assert!(settings.merge(&json!({ "admin": "me" })).is_err());
+        assert!(settings.merge(&json!({ "max_generated_rows": "many" })).is_err());
+        let tuned = settings.merge(&json!({ "cost_model": { "row_cost": 1 } })).unwrap();
+        assert_eq!(tuned.cost_model.row_cost, 1);
+        assert_eq!(tuned.cost_model.budget_units, CostModel::default().budget_units);
+    }
+
+    #[test]
+    fn test_old_records_get_defaults() {
+        let settings: DeploymentSettings = serde_json::from_str("{}").unwrap();
+        assert_eq!(settings.max_generated_rows, 10_000);
+        assert_eq!(settings.default_query_limit, 1_000);
+        assert_eq!(settings.text_decode_policy, TextDecodePolicy::Lossy);
+        assert!(settings.reporting_only.is_empty());
+        assert!(settings.admin.is_none());
+    }
+
+    #[test]
+    fn test_access_level() {
+        let settings = DeploymentSettings {
+            admin: Some("admin".to_string()),
+            reporting_only: vec!["bi".to_string(), "admin".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(settings.access_level("bi"), AccessLevel::ReportingOnly);
+        assert_eq!(settings.access_level("admin"), AccessLevel::Full);
+        assert_eq!(settings.access_level("other"), AccessLevel::Full);
+    }
+}
@@ -0,0 +1,1602 @@
+// In-memory stand-in for the Klave host, built with the simulator feature. Routes run natively end to end
+// against a ledger, a key store and a SQL host scripted from fixture files, and every frame they send is
+// captured, see simulate_route. The crypto only mimics the SDK: AES-GCM is replaced by a keyed stream and
+// tag built on SHA-256, so simulated ciphertexts are not interchangeable with the ones of a deployment.
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, HashMap},
+    error::Error,
+    path::Path,
+};
+
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::{
+    bindings::Guest,
+    notify::{self, Frame},
+    routing,
+    runtime::subtle::{CryptoKey, DerivedKeyAlgorithm, EncryptAlgorithm, KeyDerivationAlgorithm, KeyGenAlgorithm},
+    Component, RouteKind,
+};
+
+const TAG_SIZE: usize = 16;
+
+// Caller and trusted time of the calls until the test changes them
+pub const DEFAULT_SENDER: &str = "simulator-admin";
+pub const DEFAULT_TRUSTED_TIME: u64 = 1_700_000_000;
+
+// One scripted answer: the first rule, most recently added first, whose fragments all appear in the statement
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SqlRule {
+    #[serde(rename = "match")]
+    pub fragments: Vec<String>,
+    // Column names of a query answer
+    #[serde(default)]
+    pub fields: Vec<String>,
+    #[serde(default)]
+    pub rows: Vec<Vec<Value>>,
+    // Raw answer of an execute, a command tag by default
+    #[serde(default)]
+    pub result: Option<Value>,
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+impl SqlRule {
+    pub fn query(fragments: &[&str], fields: &[&str], rows: Vec<Vec<Value>>) -> SqlRule {
+        SqlRule {
+            fragments: fragments.iter().map(|f| f.to_string()).collect(),
+            fields: fields.iter().map(|f| f.to_string()).collect(),
+            rows,
+            ..Default::default()
+        }
+    }
+
+    pub fn failing(fragments: &[&str], error: &str) -> SqlRule {
+        SqlRule { fragments: fragments.iter().map(|f| f.to_string()).collect(), error: Some(error.to_string()), ..Default::default() }
+    }
+
+    fn matches(&self, sql: &str) -> bool {
+        self.fragments.iter().all(|f| sql.contains(f.as_str()))
+    }
+
+    fn query_answer(&self) -> Result<String, Box<dyn Error>> {
+        if let Some(error) = self.error.as_ref() {
+            return Err(error.clone().into());
+        }
+        let fields: Vec<Value> = self.fields.iter().map(|name| json!({ "name": name })).collect();
+        Ok(json!({ "fields": fields, "resultset": self.rows }).to_string())
+    }
+
+    fn execute_answer(&self, sql: &str) -> Result<String, Box<dyn Error>> {
+        if let Some(error) = self.error.as_ref() {
+            return Err(error.clone().into());
+        }
+        Ok(self.result.clone().unwrap_or_else(|| default_execute_answer(sql)).to_string())
+    }
+}
+
+fn default_execute_answer(sql: &str) -> Value {
+    let verb = sql.split_whitespace().next().unwrap_or_default().to_uppercase();
+    json!({ "command_tag": verb })
+}
+
+// Answers of the scripted SQL host, as loaded from a fixture file
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SqlScript {
+    #[serde(default)]
+    pub queries: Vec<SqlRule>,
+    #[serde(default)]
+    pub executes: Vec<SqlRule>,
+}
+
+impl SqlScript {
+    pub fn load(path: &Path) -> Result<SqlScript, Box<dyn Error>> {
+        let source = std::fs::read_to_string(path).map_err(|e| format!("Failed to read fixture {}: {}", path.display(), e))?;
+        Ok(serde_json::from_str(&source).map_err(|e| format!("Failed to parse fixture {}: {}", path.display(), e))?)
+    }
+
+    // Rules of the other script take precedence
+    pub fn extend(&mut self, other: SqlScript) {
+        self.queries.extend(other.queries);
+        self.executes.extend(other.executes);
+    }
+}
+
+// Statement received by the SQL host, in order
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SqlStatement {
+    Query(String),
+    Execute(String),
+}
+
+impl SqlStatement {
+    pub fn sql(&self) -> &str {
+        match self {
+            SqlStatement::Query(sql) | SqlStatement::Execute(sql) => sql,
+        }
+    }
+}
+
+struct SimulatedKey {
+    material: Vec<u8>,
+    extractable: bool,
+}
+
+#[derive(Default)]
+pub struct SimulatedHost {
+    ledger: BTreeMap<String, BTreeMap<String, Vec<u8>>>,
+    keys: HashMap<String, SimulatedKey>,
+    saved_keys: HashMap<String, CryptoKey>,
+    context: HashMap<String, String>,
+    script: SqlScript,
+    statements: Vec<SqlStatement>,
+    frames: Vec<Value>,
+    // Counter the deterministic random bytes are drawn from
+    draws: u64,
+    connections: u64,
+    // Error connection_open answers with, followed by the connection string as some hosts do
+    refused_connections: Option<String>,
+    // Set while a query route runs, Klave only lets transactions write the ledger
+    read_only: bool,
+    // Answers digests with an error, as a runtime whose crypto is broken would
+    broken_digest: bool,
+    // Refuses to delete saved keys, as a key store that keeps them would
+    locked_keys: bool,
+}
+
+impl SimulatedHost {
+    pub fn new() -> SimulatedHost {
+        let mut host = SimulatedHost::default();
+        host.set_sender(DEFAULT_SENDER);
+        host.set_trusted_time(DEFAULT_TRUSTED_TIME);
+        host
+    }
+
+    pub fn with_script(mut self, script: SqlScript) -> SimulatedHost {
+        self.script.extend(script);
+        self
+    }
+
+    pub fn set_sender(&mut self, sender: &str) {
+        self.context.insert("sender".to_string(), sender.to_string());
+    }
+
+    pub fn set_trusted_time(&mut self, time: u64) {
+        self.context.insert("trusted_time".to_string(), time.to_string());
+    }
+
+    pub fn push_query(&mut self, rule: SqlRule) {
+        self.script.queries.push(rule);
+    }
+
+    pub fn push_execute(&mut self, rule: SqlRule) {
+        self.script.executes.push(rule);
+    }
+
+    pub fn statements(&self) -> &[SqlStatement] {
+        &self.statements
+    }
+
+    pub fn ledger_keys(&self, table: &str) -> Vec<String> {
+        self.ledger.get(table).map(|t| t.keys().cloned().collect()).unwrap_or_default()
+    }
+
+    pub(crate) fn ledger_get(&self, table: &str, key: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+        self.ledger.get(table).and_then(|t| t.get(key)).cloned().ok_or_else(|| format!("Key {} not found in table {}", key, table).into())
+    }
+
+    fn ledger_writable(&self, table: &str) -> Result<(), Box<dyn Error>> {
+        match self.read_only {
+            true => Err(format!("Cannot write table {} from a query route", table).into()),
+            false => Ok(()),
+        }
+    }
+
+    pub(crate) fn ledger_set(&mut self, table: &str, key: &str, value: &[u8]) -> Result<(), Box<dyn Error>> {
+        self.ledger_writable(table)?;
+        self.ledger.entry(table.to_string()).or_default().insert(key.to_string(), value.to_vec());
+        Ok(())
+    }
+
+    pub(crate) fn ledger_remove(&mut self, table: &str, key: &str) -> Result<(), Box<dyn Error>> {
+        self.ledger_writable(table)?;
+        if let Some(records) = self.ledger.get_mut(table) {
+            records.remove(key);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn digest(&self, algorithm: &str, data: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        match self.broken_digest {
+            true => Err(format!("Digest {} failed", algorithm).into()),
+            false => digest(algorithm, data),
+        }
+    }
+
+    pub(crate) fn context(&self, param: &str) -> Result<String, Box<dyn Error>> {
+        self.context.get(param).cloned().ok_or_else(|| format!("No context value {}", param).into())
+    }
+
+    pub(crate) fn notify(&mut self, frame: Value) {
+        self.frames.push(frame);
+    }
+
+    pub(crate) fn random_bytes(&mut self, size: usize) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(size);
+        while bytes.len() < size {
+            self.draws += 1;
+            bytes.extend(sha256(&self.draws.to_be_bytes()));
+        }
+        bytes.truncate(size);
+        bytes
+    }
+
+    pub fn refuse_connections(&mut self, error: &str) {
+        self.refused_connections = Some(error.to_string());
+    }
+
+    pub(crate) fn connection_open(&mut self, uri: &str) -> Result<String, Box<dyn Error>> {
+        if let Some(error) = &self.refused_connections {
+            return Err(format!("{} ({})", error, uri).into());
+        }
+        self.connections += 1;
+        Ok(format!("simulated-connection-{}", self.connections))
+    }
+
+    pub(crate) fn sql_query(&mut self, _connection: &str, sql: &str) -> Result<String, Box<dyn Error>> {
+        self.statements.push(SqlStatement::Query(sql.to_string()));
+        match self.script.queries.iter().rev().find(|rule| rule.matches(sql)) {
+            Some(rule) => rule.query_answer(),
+            None => Err(format!("No scripted answer for query: {}", sql).into()),
+        }
+    }
+
+    pub(crate) fn sql_execute(&mut self, _connection: &str, sql: &str) -> Result<String, Box<dyn Error>> {
+        self.statements.push(SqlStatement::Execute(sql.to_string()));
+        match self.script.executes.iter().rev().find(|rule| rule.matches(sql)) {
+            Some(rule) => rule.execute_answer(sql),
+            None => Ok(default_execute_answer(sql).to_string()),
+        }
+    }
+
+    fn add_key(&mut self, material: Vec<u8>, extractable: bool, usages: &[&str], algorithm: &str) -> CryptoKey {
+        let id = format!("simulated-key-{}", self.keys.len() + 1);
+        self.keys.insert(id.clone(), SimulatedKey { material, extractable });
+        CryptoKey::new(&id, "secret", extractable, usages.iter().map(|u| u.to_string()).collect(), algorithm)
+    }
+
+    fn key(&self, key: &CryptoKey) -> Result<&SimulatedKey, Box<dyn Error>> {
+        let id = key.clone().name();
+        self.keys.get(&id).ok_or_else(|| format!("Unknown key {}", id).into())
+    }
+
+    pub(crate) fn generate_key(&mut self, algorithm: &KeyGenAlgorithm, extractable: bool, usages: &[&str]) -> Result<CryptoKey, Box<dyn Error>> {
+        let name = match algorithm {
+            KeyGenAlgorithm::Ecc(_) => "ECDSA",
+            KeyGenAlgorithm::Aes(_) => "AES-GCM",
+            _ => return self.unsupported("generate_key for RSA and HMAC"),
+        };
+        let material = self.random_bytes(32);
+        Ok(self.add_key(material, extractable, usages, name))
+    }
+
+    pub(crate) fn derive_key(&mut self, algorithm: &KeyDerivationAlgorithm, base_key: &CryptoKey, derived: &DerivedKeyAlgorithm, extractable: bool, usages: &[&str]) -> Result<CryptoKey, Box<dyn Error>> {
+        let params = match algorithm {
+            KeyDerivationAlgorithm::Hkdf(params) => params,
+            _ => return self.unsupported("derive_key with ECDH"),
+        };
+        let DerivedKeyAlgorithm::Aes(aes) = derived;
+        let mut material = hkdf_sha256(&self.key(base_key)?.material, &params.salt, &params.info);
+        material.truncate((aes.length as usize / 8).clamp(1, material.len()));
+        Ok(self.add_key(material, extractable, usages, "AES-GCM"))
+    }
+
+    pub(crate) fn export_key(&self, _format: &str, key: &CryptoKey) -> Result<Vec<u8>, Box<dyn Error>> {
+        let key = self.key(key)?;
+        if !key.extractable {
+            return Err("The key is not extractable".into());
+        }
+        Ok(key.material.clone())
+    }
+
+    pub(crate) fn encrypt(&self, algorithm: &EncryptAlgorithm, key: &CryptoKey, clear_text: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        let params = match algorithm {
+            EncryptAlgorithm::AesGcm(params) => params,
+            _ => return self.unsupported("encrypt with RSA-OAEP"),
+        };
+        let material = &self.key(key)?.material;
+        let mut sealed = keystream_xor(material, &params.iv, clear_text);
+        let tag = stream_tag(material, &params.iv, &params.additional_data, &sealed);
+        sealed.extend(tag);
+        Ok(sealed)
+    }
+
+    pub(crate) fn decrypt(&self, algorithm: &EncryptAlgorithm, key: &CryptoKey, cipher_text: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        let params = match algorithm {
+            EncryptAlgorithm::AesGcm(params) => params,
+            _ => return self.unsupported("decrypt with RSA-OAEP"),
+        };
+        if cipher_text.len() < TAG_SIZE {
+            return Err("Ciphertext shorter than its tag".into());
+        }
+        let material = &self.key(key)?.material;
+        let (sealed, tag) = cipher_text.split_at(cipher_text.len() - TAG_SIZE);
+        if stream_tag(material, &params.iv, &params.additional_data, sealed) != tag {
+            return Err("Authentication failed".into());
+        }
+        Ok(keystream_xor(material, &params.iv, sealed))
+    }
+
+    pub(crate) fn save_key(&mut self, key: &CryptoKey, name: &str) -> Result<(), Box<dyn Error>> {
+        self.saved_keys.insert(name.to_string(), key.clone());
+        Ok(())
+    }
+
+    pub(crate) fn load_key(&self, name: &str) -> Result<CryptoKey, Box<dyn Error>> {
+        self.saved_keys.get(name).cloned().ok_or_else(|| format!("Key {} not found", name).into())
+    }
+
+    pub(crate) fn delete_key(&mut self, key: &CryptoKey) -> Result<(), Box<dyn Error>> {
+        let id = key.clone().name();
+        if self.locked_keys {
+            return Err(format!("Key {} is locked", id).into());
+        }
+        let before = self.saved_keys.len();
+        self.saved_keys.retain(|_, saved| saved.clone().name() != id);
+        if self.saved_keys.len() == before {
+            return Err(format!("Key {} not found", id).into());
+        }
+        Ok(())
+    }
+
+    pub fn saved_key_names(&self) -> Vec<String> {
+        self.saved_keys.keys().cloned().collect()
+    }
+
+    pub(crate) fn unsupported<T>(&self, operation: &str) -> Result<T, Box<dyn Error>> {
+        Err(format!("{} is not available in the simulator", operation).into())
+    }
+}
+
+thread_local! {
+    static HOST: RefCell<Option<SimulatedHost>> = const { RefCell::new(None) };
+}
+
+// Routes the SDK calls of this thread to the host, replacing the one installed before
+pub fn install(host: SimulatedHost) {
+    HOST.with(|h| *h.borrow_mut() = Some(host));
+}
+
+pub fn uninstall() -> Option<SimulatedHost> {
+    HOST.with(|h| h.borrow_mut().take())
+}
+
+// None when no host is installed on this thread, the call then goes to the Klave SDK
+pub fn with_host<R, F: FnOnce(&mut SimulatedHost) -> R>(f: F) -> Option<R> {
+    HOST.with(|h| h.borrow_mut().as_mut().map(f))
+}
+
+// Runs a route handler end to end on the installed host, a fresh one when there is none, and returns
+// the frames it sent
+pub fn simulate_route(name: &str, input: &Value) -> Vec<Frame> {
+    if with_host(|_| ()).is_none() {
+        install(SimulatedHost::new());
+    }
+    let kind = routing::resolve(name).and_then(|resolved| routing::route_kind(resolved.route));
+    with_host(|host| {
+        host.frames.clear();
+        host.read_only = kind == Some(RouteKind::Query);
+    });
+    let cmd = input.to_string();
+    if !dispatch(name, cmd) {
+        notify::invoke(String::new(), |_| notify::error(&format!("Unknown route {}", name)));
+    }
+    with_host(|host| host.read_only = false);
+    let frames = with_host(|host| std::mem::take(&mut host.frames)).unwrap_or_default();
+    frames.into_iter().filter_map(|frame| serde_json::from_value(frame).ok()).collect()
+}
+
+// Calls the exported function of the route, false for an unknown name
+fn dispatch(name: &str, cmd: String) -> bool {
+    match name {
+        "db_setup" => Component::db_setup(cmd),
+        "sql_delete" => Component::sql_delete(cmd),
+        "execute_table_encryption" => Component::execute_table_encryption(cmd),
+        "sql_script" => Component::sql_script(cmd),
+        "decrypt_value" => Component::decrypt_value(cmd),
+        "consistent_read_session" => Component::consistent_read_session(cmd),
+        "get_settings" => Component::get_settings(cmd),
+        "update_settings" => Component::update_settings(cmd),
+        "generate_test_data" => Component::generate_test_data(cmd),
+        "quick_verify_table" => Component::quick_verify_table(cmd),
+        "reconcile_encryption_state" => Component::reconcile_encryption_state(cmd),
+        "diagnose_keys" => Component::diagnose_keys(cmd),
+        "create_search_index" => Component::create_search_index(cmd),
+        "drop_search_index" => Component::drop_search_index(cmd),
+        "search_index_progress" => Component::search_index_progress(cmd),
+        "find_duplicate_values" => Component::find_duplicate_values(cmd),
+        "export_state_snapshot" => Component::export_state_snapshot(cmd),
+        "import_state_snapshot" => Component::import_state_snapshot(cmd),
+        "bulk_operation" => Component::bulk_operation(cmd),
+        "set_column_rules" => Component::set_column_rules(cmd),
+        "freeze_database" => Component::freeze_database(cmd),
+        "unfreeze_database" => Component::unfreeze_database(cmd),
+        "generate_support_bundle" => Component::generate_support_bundle(cmd),
+        "restore_row_backup" => Component::restore_row_backup(cmd),
+        "prune_row_backups" => Component::prune_row_backups(cmd),
+        "set_strict_encrypted_access" => Component::set_strict_encrypted_access(cmd),
+        "issue_strict_bypass_token" => Component::issue_strict_bypass_token(cmd),
+        "prune_history" => Component::prune_history(cmd),
+        "get_audit_log" => Component::get_audit_log(cmd),
+        "bootstrap" => Component::bootstrap(cmd),
+        "create_encrypted_view" => Component::create_encrypted_view(cmd),
+        "refresh_encrypted_view" => Component::refresh_encrypted_view(cmd),
+        "route_usage" => Component::route_usage(cmd),
+        "encryption_progress" => Component::encryption_progress(cmd),
+        "migrate_ciphertext_versions" => Component::migrate_ciphertext_versions(cmd),
+        "test_credentials" => Component::test_credentials(cmd),
+        "reap_stale_jobs" => Component::reap_stale_jobs(cmd),
+        "probe_host_capabilities" => Component::probe_host_capabilities(cmd),
+        "recover_incomplete_operations" => Component::recover_incomplete_operations(cmd),
+        "sql_list" => Component::sql_list(cmd),
+        "db_update" => Component::db_update(cmd),
+        "read_encrypted_table" => Component::read_encrypted_table(cmd),
+        "manage_audit_columns" => Component::manage_audit_columns(cmd),
+        "watch_query" => Component::watch_query(cmd),
+        "run_watch" => Component::run_watch(cmd),
+        "decrypt_values" => Component::decrypt_values(cmd),
+        "storage_usage" => Component::storage_usage(cmd),
+        "recalculate_storage_usage" => Component::recalculate_storage_usage(cmd),
+        "list_encrypted_columns" => Component::list_encrypted_columns(cmd),
+        "execute_table_decryption" => Component::execute_table_decryption(cmd),
+        "migrate_deployment" => Component::migrate_deployment(cmd),
+        "scan_for_plaintext_leaks" => Component::scan_for_plaintext_leaks(cmd),
+        "quarantine_leaked_rows" => Component::quarantine_leaked_rows(cmd),
+        "sql_transaction" => Component::sql_transaction(cmd),
+        "rotate_master_key" => Component::rotate_master_key(cmd),
+        "grant_db_access" => Component::grant_db_access(cmd),
+        "revoke_db_access" => Component::revoke_db_access(cmd),
+        "sql_query_cursor" => Component::sql_query_cursor(cmd),
+        "db_list_tables" => Component::db_list_tables(cmd),
+        "db_describe_table" => Component::db_describe_table(cmd),
+        "read_encrypted_data_per_user" => Component::read_encrypted_data_per_user(cmd),
+        "avg_age_for_male" => Component::avg_age_for_male(cmd),
+        "avg_age_for_female" => Component::avg_age_for_female(cmd),
+        "v2_db_setup" => Component::v2_db_setup(cmd),
+        "v2_sql_script" => Component::v2_sql_script(cmd),
+        _ => return false,
+    }
+    true
+}
+
+pub(crate) fn digest(algorithm: &str, data: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    match algorithm {
+        "SHA-256" | "SHA2-256" => Ok(sha256(data).to_vec()),
+        _ => Err(format!("Digest {} is not available in the simulator", algorithm).into()),
+    }
+}
+
+fn keystream_xor(key: &[u8], iv: &[u8], data: &[u8]) -> Vec<u8> {
+    data.chunks(32).enumerate().flat_map(|(block, chunk)| {
+        let mut seed = key.to_vec();
+        seed.extend_from_slice(iv);
+        seed.extend_from_slice(&(block as u32).to_be_bytes());
+        let stream = sha256(&seed);
+        chunk.iter().zip(stream).map(|(b, s)| b ^ s).collect::<Vec<u8>>()
+    }).collect()
+}
+
+fn stream_tag(key: &[u8], iv: &[u8], additional_data: &[u8], sealed: &[u8]) -> Vec<u8> {
+    let mut message = (iv.len() as u64).to_be_bytes().to_vec();
+    message.extend_from_slice(iv);
+    message.extend_from_slice(&(additional_data.len() as u64).to_be_bytes());
+    message.extend_from_slice(additional_data);
+    message.extend_from_slice(sealed);
+    hmac_sha256(key, &message)[..TAG_SIZE].to_vec()
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> [u8; 32] {
+    let mut block_key = if key.len() > 64 { sha256(key).to_vec() } else { key.to_vec() };
+    block_key.resize(64, 0);
+    let mut inner: Vec<u8> = block_key.iter().map(|b| b ^ 0x36).collect();
+    inner.extend_from_slice(data);
+    let mut outer: Vec<u8> = block_key.iter().map(|b| b ^ 0x5c).collect();
+    outer.extend_from_slice(&sha256(&inner));
+    sha256(&outer)
+}
+
+// RFC 5869 with SHA-256, one block of output
+fn hkdf_sha256(key: &[u8], salt: &[u8], info: &[u8]) -> Vec<u8> {
+    let prk = hmac_sha256(salt, key);
+    let mut block = info.to_vec();
+    block.push(1);
+    hmac_sha256(&prk, &block).to_vec()
+}
+
+const SHA256_K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+fn sha256(data: &[u8]) -> [u8; 32] {
+    let mut state: [u32; 8] = [0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19];
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((data.len() as u64) * 8).to_be_bytes());
+    for block in message.chunks(64) {
+        let mut w = [0u32; 64];
+        for (i, word) in block.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16].wrapping_add(s0).wrapping_add(w[i - 7]).wrapping_add(s1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = state;
+        for i in 0..64 {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let t1 = h.wrapping_add(s1).wrapping_add(ch).wrapping_add(SHA256_K[i]).wrapping_add(w[i]);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(maj);
+            h = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(t1);
+            d = c;
+            c = b;
+            b = a;
+            a = t1.wrapping_add(t2);
+        }
+        for (s, v) in state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+            *s = s.wrapping_add(v);
+        }
+    }
+    let mut out = [0u8; 32];
+    for (i, s) in state.iter().enumerate() {
+        out[i * 4..i * 4 + 4].copy_from_slice(&s.to_be_bytes());
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{database::Client, notify::Channel, settings::DeploymentSettings, ROUTES};
+
+    fn fixture(name: &str) -> SqlScript {
+        SqlScript::load(&Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures/simulator").join(name)).unwrap()
+    }
+
+    fn result(frames: &[Frame]) -> Value {
+        let results: Vec<&Frame> = frames.iter().filter(|f| f.channel == Channel::Result).collect();
+        assert_eq!(results.len(), 1, "{:?}", frames);
+        results[0].payload.clone()
+    }
+
+    // Registers the database of the fixtures, returns its id
+    fn register_database() -> Value {
+        let registered = result(&simulate_route("db_setup", &json!({ "host": "db", "dbname": "app", "user": "app", "password": "secret" })));
+        registered["database_id"].clone()
+    }
+
+    // Values the last UPDATE of the column wrote, by primary key
+    fn written(column: &str) -> Vec<(String, String)> {
+        let statements = with_host(|host| host.statements().to_vec()).unwrap();
+        let update = statements.iter().rev().find(|s| s.sql().contains(&format!("UPDATE \"users\" SET \"{}\" =", column))).unwrap();
+        let values = update.sql().split("VALUES (").nth(1).unwrap().split(") UPDATE").next().unwrap();
+        values.split("),(").map(|pair| {
+            let cells: Vec<&str> = pair.trim_matches(|c| c == '(' || c == ')').split(',').map(|c| c.trim_matches('\'')).collect();
+            (cells[0].to_string(), cells[1].to_string())
+        }).collect()
+    }
+
+    #[test]
+    fn test_encrypt_and_read_back() {
+        install(SimulatedHost::new().with_script(fixture("users.json")));
+        let bootstrap = result(&simulate_route("bootstrap", &json!({})));
+        assert_eq!(bootstrap["ready"], json!(true));
+
+        let database_id = register_database();
+        assert!(database_id.is_string());
+
+        let encryption = result(&simulate_route("execute_table_encryption", &json!({
+            "database_id": database_id,
+            "table": "users",
+            "columns": ["email"],
+            "primary_key": "id",
+            "chunk_size": 10,
+            "lookup_columns": ["email"],
+        })));
+        assert_eq!(encryption, json!({ "complete": true, "table": "users", "rows": 3, "batches": 1, "encrypted": 3, "skipped": 0 }));
+
+        let ciphertexts = written("email");
+        assert_eq!(ciphertexts.len(), 3);
+        assert!(ciphertexts.iter().all(|(_, c)| !c.contains("example.com") && c.starts_with("v1:")));
+
+        let decrypted = result(&simulate_route("decrypt_value", &json!({
+            "database_id": database_id,
+            "table": "users",
+            "column": "email",
+            "value": ciphertexts[1].1,
+        })));
+        assert_eq!(decrypted, json!("grace@example.com"));
+
+        // The database answers the encrypted predicate with the stored ciphertext
+        with_host(|host| host.push_query(SqlRule::query(&["FROM \"users\"", "WHERE"], &["id", "email"], vec![vec![json!(1), json!(ciphertexts[0].1)]])));
+        let rows = result(&simulate_route("read_encrypted_table", &json!({
+            "database_id": database_id,
+            "table": "users",
+            "encrypted_column": "email",
+            "values": ["ada@example.com"],
+        })));
+        assert_eq!(rows["resultset"], json!([["1", "ada@example.com"]]));
+        let empty = result(&simulate_route("read_encrypted_table", &json!({
+            "database_id": database_id,
+            "table": "users",
+            "encrypted_column": "email",
+            "values": [],
+        })));
+        assert_eq!(empty["code"], json!("INVALID_INPUT"));
+
+        // Encrypting again only rewrites the values that are not ciphertext yet
+        with_host(|host| {
+            host.push_query(SqlRule::query(&["SELECT \"id\",\"email\" FROM \"users\"", "ORDER BY \"id\""], &["id", "email"], vec![
+                vec![json!(1), json!(ciphertexts[0].1)],
+                vec![json!(2), json!(ciphertexts[1].1)],
+                vec![json!(3), json!("linus@example.com")],
+            ]));
+            host.push_query(SqlRule::query(&["WHERE \"id\" > 3"], &["id", "email"], vec![]));
+        });
+        let again = result(&simulate_route("execute_table_encryption", &json!({
+            "database_id": database_id,
+            "table": "users",
+            "columns": ["email"],
+            "primary_key": "id",
+            "chunk_size": 10,
+            "force": true,
+            "lookup_columns": ["email"],
+        })));
+        assert_eq!(again, json!({ "complete": true, "table": "users", "rows": 3, "batches": 1, "encrypted": 1, "skipped": 2 }));
+        let rewritten = written("email");
+        assert_eq!(rewritten.len(), 1);
+        assert_eq!(rewritten[0].0, "3");
+        assert!(rewritten[0].1.starts_with("v1:"));
+        uninstall();
+    }
+
+    #[test]
+    fn test_row_bound_encryption() {
+        install(SimulatedHost::new().with_script(fixture("users.json")));
+        result(&simulate_route("bootstrap", &json!({})));
+        let database_id = register_database();
+        let encryption = result(&simulate_route("execute_table_encryption", &json!({
+            "database_id": database_id,
+            "table": "users",
+            "columns": ["email"],
+            "primary_key": "id",
+            "chunk_size": 10,
+        })));
+        assert_eq!(encryption["encrypted"], json!(3));
+        let ciphertexts = written("email");
+        assert!(ciphertexts.iter().all(|(_, c)| c.starts_with("v2:")));
+
+        let decrypt = |id: Value| result(&simulate_route("decrypt_value", &json!({
+            "database_id": database_id,
+            "table": "users",
+            "column": "email",
+            "value": ciphertexts[1].1,
+            "context": { "id": id },
+        })));
+        assert_eq!(decrypt(json!(2)), json!("grace@example.com"));
+        // Moved to another row, the value no longer authenticates
+        assert!(decrypt(json!(1))["error"].as_str().unwrap().contains("bound to row 1"));
+
+        let lookup = result(&simulate_route("read_encrypted_table", &json!({
+            "database_id": database_id,
+            "table": "users",
+            "encrypted_column": "email",
+            "values": ["grace@example.com"],
+        })));
+        assert!(lookup["error"].as_str().unwrap().contains("lookup_columns"), "{}", lookup);
+        uninstall();
+    }
+
+    #[test]
+    fn test_randomized_with_blind_index() {
+        install(SimulatedHost::new().with_script(fixture("users.json")));
+        result(&simulate_route("bootstrap", &json!({})));
+        let database_id = register_database();
+        let encryption = result(&simulate_route("execute_table_encryption", &json!({
+            "database_id": database_id,
+            "table": "users",
+            "columns": ["email"],
+            "primary_key": "id",
+            "chunk_size": 10,
+            "mode": "randomized_with_index",
+        })));
+        assert_eq!(encryption["encrypted"], json!(3));
+        let statements = with_host(|host| host.statements().iter().map(|s| s.sql().to_string()).collect::<Vec<String>>()).unwrap();
+        assert!(statements.contains(&"ALTER TABLE \"users\" ADD COLUMN IF NOT EXISTS \"email__idx\" text".to_string()));
+        let update = statements.iter().rev().find(|s| s.starts_with("WITH new_values (\"id\",\"email\",\"email__idx\")")).unwrap();
+        let index_of_grace = update.split("),(").nth(1).unwrap().split(',').nth(2).unwrap().trim_matches(|c| c == '\'' || c == ')').to_string();
+        assert_eq!(index_of_grace.len(), 32);
+
+        // The searched value is hashed with the same key and looked up in the companion column
+        with_host(|host| host.push_query(SqlRule::query(&["FROM \"users\"", "\"email__idx\" IN"], &["id", "email"], vec![])));
+        result(&simulate_route("read_encrypted_table", &json!({
+            "database_id": database_id,
+            "table": "users",
+            "encrypted_column": "email",
+            "values": ["grace@example.com"],
+        })));
+        let lookup = with_host(|host| host.statements().last().map(|s| s.sql().to_string())).unwrap().unwrap();
+        assert_eq!(lookup, format!("SELECT * FROM \"users\" WHERE \"email__idx\" IN ('{}')", index_of_grace));
+
+        with_host(|host| {
+            host.push_query(SqlRule::query(&["information_schema.key_column_usage", "'orders'"], &["column_name"], vec![vec![json!("id")]]));
+            host.push_query(SqlRule::query(&["information_schema.columns", "'orders'"], &["column_name"], vec![vec![json!("id")], vec![json!("card")]]));
+        });
+        let mixed = result(&simulate_route("execute_table_encryption", &json!({
+            "database_id": database_id,
+            "table": "orders",
+            "columns": ["card"],
+            "primary_key": "id",
+            "chunk_size": 10,
+            "mode": "randomized_with_index",
+            "lookup_columns": ["card"],
+        })));
+        assert!(mixed["error"].as_str().unwrap().contains("lookup_columns only apply to the deterministic mode"), "{}", mixed);
+        uninstall();
+    }
+
+    #[test]
+    fn test_rotate_master_key() {
+        install(SimulatedHost::new().with_script(fixture("users.json")));
+        result(&simulate_route("bootstrap", &json!({})));
+        let database_id = register_database();
+        result(&simulate_route("execute_table_encryption", &json!({
+            "database_id": database_id,
+            "table": "users",
+            "columns": ["email"],
+            "primary_key": "id",
+            "chunk_size": 10,
+        })));
+        let ciphertexts = written("email");
+        let decrypt = |value: &str| result(&simulate_route("decrypt_value", &json!({
+            "database_id": database_id,
+            "table": "users",
+            "column": "email",
+            "value": value,
+            "context": { "id": 2 },
+        })));
+        with_host(|host| host.push_query(SqlRule::query(&["SELECT \"id\",\"email\" FROM \"users\"", "ORDER BY \"id\""], &["id", "email"],
+            ciphertexts.iter().map(|(id, c)| vec![json!(id), json!(c)]).collect())));
+
+        // A failing batch is rolled back and the old key stays the master key
+        with_host(|host| host.push_execute(SqlRule::failing(&["UPDATE \"users\" SET \"email\""], "canceling statement due to statement timeout")));
+        let before = with_host(|host| host.statements().len()).unwrap();
+        let error = result(&simulate_route("rotate_master_key", &json!({ "database_id": database_id })));
+        assert!(error["error"].as_str().unwrap().contains("the transaction was rolled back"), "{}", error);
+        let executed: Vec<String> = with_host(|host| host.statements()[before..].iter().filter_map(|s| match s {
+            SqlStatement::Execute(sql) => Some(sql.split_whitespace().next().unwrap_or_default().to_string()),
+            SqlStatement::Query(_) => None,
+        }).collect()).unwrap();
+        assert_eq!(executed, vec!["BEGIN", "WITH", "ROLLBACK"]);
+        assert_eq!(decrypt(&ciphertexts[1].1), json!("grace@example.com"));
+        assert_eq!(with_host(|host| host.saved_key_names().len()).unwrap(), 2);
+
+        // Called again, the rotation reuses the key it generated and replaces the old one
+        with_host(|host| host.push_execute(SqlRule { fragments: vec!["UPDATE \"users\" SET \"email\"".to_string()], result: Some(json!("UPDATE 3")), ..Default::default() }));
+        let rotation = result(&simulate_route("rotate_master_key", &json!({ "database_id": database_id })));
+        assert_eq!(rotation["complete"], json!(true), "{}", rotation);
+        assert_eq!(rotation["tables"], json!([{ "table": "users", "rewritten": 3, "complete": true }]));
+        assert_eq!(rotation["old_key_deleted"], json!(true));
+        assert_eq!(with_host(|host| host.saved_key_names().len()).unwrap(), 1);
+
+        let rotated = written("email");
+        assert_eq!(rotated.len(), 3);
+        assert_ne!(rotated[1].1, ciphertexts[1].1);
+        assert_eq!(decrypt(&rotated[1].1), json!("grace@example.com"));
+        assert!(decrypt(&ciphertexts[1].1)["error"].is_string());
+        uninstall();
+    }
+
+    #[test]
+    fn test_writes_wait_for_a_pending_rotation() {
+        install(SimulatedHost::new().with_script(fixture("users.json")));
+        result(&simulate_route("bootstrap", &json!({})));
+        let database_id = register_database();
+        result(&simulate_route("execute_table_encryption", &json!({
+            "database_id": database_id, "table": "users", "columns": ["email"], "primary_key": "id", "chunk_size": 10,
+        })));
+        let ciphertexts = written("email");
+        let rows = |range: std::ops::Range<usize>| ciphertexts[range].iter().map(|(id, c)| vec![json!(id), json!(c)]).collect::<Vec<_>>();
+        with_host(|host| {
+            host.push_query(SqlRule::query(&["SELECT \"id\",\"email\" FROM \"users\"", "ORDER BY \"id\""], &["id", "email"], rows(0..2)));
+            host.push_query(SqlRule::query(&["SELECT \"id\",\"email\" FROM \"users\" WHERE \"id\" > '2'"], &["id", "email"], rows(2..3)));
+        });
+
+        // Only readers were granted to this caller, the rotation is for owners
+        result(&simulate_route("grant_db_access", &json!({ "database_id": database_id, "grantee_client_id": "reader-1", "role": "reader" })));
+        with_host(|host| host.set_sender("reader-1"));
+        let denied = result(&simulate_route("rotate_master_key", &json!({ "database_id": database_id, "chunk_size": 2 })));
+        assert_eq!(denied["code"], json!("ACCESS_DENIED"), "{}", denied);
+        with_host(|host| host.set_sender(DEFAULT_SENDER));
+
+        // Room for a single batch: the first two rows are rewritten under the new key
+        result(&simulate_route("update_settings", &json!({ "cost_model": { "budget_units": 280_000 } })));
+        let partial = result(&simulate_route("rotate_master_key", &json!({ "database_id": database_id, "chunk_size": 2 })));
+        assert_eq!(partial["complete"], json!(false), "{}", partial);
+        assert_eq!(partial["tables"], json!([{ "table": "users", "rewritten": 2, "complete": false }]));
+
+        // A value written now under the old key would not survive the end of the rotation
+        let refused = result(&simulate_route("generate_test_data", &json!({
+            "database_id": database_id, "table": "users", "rows": 1, "columns": { "id": { "type": "integer", "min": 4, "max": 4 }, "email": { "type": "email" } },
+        })));
+        assert!(refused["error"].as_str().unwrap().contains("RotationPending"), "{}", refused);
+        assert!(!with_host(|host| host.statements().iter().any(|s| s.sql().starts_with("INSERT INTO \"users\""))).unwrap());
+
+        result(&simulate_route("update_settings", &json!({ "cost_model": { "budget_units": 5_000_000 } })));
+        let rotation = result(&simulate_route("rotate_master_key", &json!({ "database_id": database_id, "chunk_size": 2 })));
+        assert_eq!(rotation["complete"], json!(true), "{}", rotation);
+        assert_eq!(rotation["tables"], json!([{ "table": "users", "rewritten": 1, "complete": true }]));
+        let rotated = written("email");
+        assert_eq!(rotated[0].0, "3");
+        let decrypted = result(&simulate_route("decrypt_value", &json!({
+            "database_id": database_id, "table": "users", "column": "email", "value": rotated[0].1, "context": { "id": 3 },
+        })));
+        assert_eq!(decrypted, json!("edsger@example.com"));
+        uninstall();
+    }
+
+    #[test]
+    fn test_unscripted_and_failing_sql() {
+        install(SimulatedHost::new());
+        result(&simulate_route("bootstrap", &json!({})));
+        // An unreachable database is not registered, its master key is deleted with it
+        let keys = with_host(|host| host.saved_key_names().len()).unwrap();
+        let details = json!({ "host": "db", "dbname": "app", "user": "app", "password": "secret" });
+        let error = result(&simulate_route("db_setup", &details));
+        assert_eq!(error["code"], json!("CONNECTION_FAILED"), "{}", error);
+        assert!(error["error"].as_str().unwrap().contains("No scripted answer for query: SELECT 1"), "{}", error);
+        assert_eq!(with_host(|host| host.saved_key_names().len()).unwrap(), keys);
+        assert_eq!(result(&simulate_route("sql_list", &json!({}))), json!({ "clients": [] }));
+
+        let mut unchecked = details.clone();
+        unchecked["validate"] = json!(false);
+        let database_id = result(&simulate_route("db_setup", &unchecked));
+        assert!(database_id.is_string(), "{}", database_id);
+        with_host(|host| {
+            host.push_query(SqlRule::query(&["pg_constraint"], &["conname"], vec![]));
+            host.push_query(SqlRule::query(&["information_schema.key_column_usage"], &["column_name"], vec![vec![json!("id")]]));
+            host.push_query(SqlRule::query(&["information_schema.columns"], &["column_name"], vec![vec![json!("id")], vec![json!("email")]]));
+            host.push_query(SqlRule::failing(&["FROM \"users\""], "relation \"users\" does not exist"));
+        });
+        let error = result(&simulate_route("execute_table_encryption", &json!({
+            "database_id": database_id,
+            "table": "users",
+            "columns": ["email"],
+            "primary_key": "id",
+            "chunk_size": 10,
+        })));
+        assert!(error["error"].as_str().unwrap().contains("relation \"users\" does not exist"));
+
+        // A name that cannot be quoted never reaches the database
+        let before = with_host(|host| host.statements().len()).unwrap();
+        let error = result(&simulate_route("execute_table_encryption", &json!({
+            "database_id": database_id,
+            "table": "users; DROP TABLE x",
+            "columns": ["email"],
+            "primary_key": "id",
+            "chunk_size": 10,
+        })));
+        assert!(error["error"].as_str().unwrap().contains("Invalid identifier"), "{}", error);
+        assert!(with_host(|host| host.statements()[before..].iter().all(|s| !s.sql().contains("DROP"))).unwrap());
+        uninstall();
+    }
+
+    #[test]
+    fn test_primary_key_detection() {
+        install(SimulatedHost::new().with_script(fixture("users.json")));
+        result(&simulate_route("bootstrap", &json!({})));
+        let database_id = register_database();
+        let encrypt = |extra: Value| {
+            let mut input = json!({ "database_id": database_id, "table": "users", "columns": ["email"], "chunk_size": 10 });
+            input.as_object_mut().unwrap().extend(extra.as_object().cloned().unwrap());
+            result(&simulate_route("execute_table_encryption", &input))
+        };
+
+        // A key that is not the primary key of the table is refused before any row is read
+        let error = encrypt(json!({ "primary_key": "email" }));
+        assert!(error["error"].as_str().unwrap().contains("primary_key email does not match the primary key (id) of table users"), "{}", error);
+        assert!(with_host(|host| host.statements().iter().all(|s| !s.sql().contains("UPDATE"))).unwrap());
+
+        with_host(|host| host.push_query(SqlRule::query(&["information_schema.key_column_usage", "'users'"], &["column_name"], vec![])));
+        let error = encrypt(json!({}));
+        assert!(error["error"].as_str().unwrap().contains("table users has no primary key"), "{}", error);
+
+        // Without primary_key the one of the table is used
+        with_host(|host| host.push_query(SqlRule::query(&["information_schema.key_column_usage", "'users'"], &["column_name"], vec![vec![json!("id")]])));
+        let encryption = encrypt(json!({}));
+        assert_eq!(encryption["complete"], json!(true), "{}", encryption);
+        assert_eq!(written("email").len(), 3);
+        uninstall();
+    }
+
+    #[test]
+    fn test_encryption_rolls_back() {
+        install(SimulatedHost::new().with_script(fixture("users.json")));
+        result(&simulate_route("bootstrap", &json!({})));
+        let database_id = register_database();
+        with_host(|host| host.push_execute(SqlRule::failing(&["UPDATE \"users\" SET \"email\""], "canceling statement due to statement timeout")));
+        let error = result(&simulate_route("execute_table_encryption", &json!({
+            "database_id": database_id,
+            "table": "users",
+            "columns": ["email"],
+            "primary_key": "id",
+            "chunk_size": 10,
+        })));
+        assert!(error["error"].as_str().unwrap().ends_with("statement timeout; the transaction was rolled back, no row was changed"), "{}", error);
+        let executed: Vec<String> = with_host(|host| host.statements().iter().filter_map(|s| match s {
+            SqlStatement::Execute(sql) => Some(sql.split_whitespace().next().unwrap_or_default().to_string()),
+            SqlStatement::Query(_) => None,
+        }).collect()).unwrap();
+        assert_eq!(executed, vec!["BEGIN", "WITH", "ROLLBACK"]);
+
+        // A rollback that fails too is reported as such
+        with_host(|host| host.push_execute(SqlRule::failing(&["ROLLBACK"], "connection reset")));
+        let error = result(&simulate_route("execute_table_encryption", &json!({
+            "database_id": database_id,
+            "table": "users",
+            "columns": ["email"],
+            "primary_key": "id",
+            "chunk_size": 10,
+        })));
+        assert!(error["error"].as_str().unwrap().ends_with("the rollback failed too (connection reset), rows may be partially rewritten"), "{}", error);
+        uninstall();
+    }
+
+    #[test]
+    fn test_encrypt_incompatible_column_type() {
+        install(SimulatedHost::new().with_script(fixture("users.json")));
+        result(&simulate_route("bootstrap", &json!({})));
+        let database_id = register_database();
+        // The longest email does not fit in a varchar(32) once encrypted
+        with_host(|host| {
+            host.push_query(SqlRule::query(&["FROM information_schema.columns c", "c.table_name = 'users'"],
+                &["column_name", "data_type", "oid", "is_nullable", "column_default", "character_maximum_length", "numeric_scale", "primary_key", "foreign_key"], vec![
+                    vec![json!("id"), json!("integer"), json!(23), json!("NO"), Value::Null, Value::Null, json!(0), json!(true), json!(false)],
+                    vec![json!("email"), json!("character varying"), json!(1043), json!("YES"), Value::Null, json!(32), Value::Null, json!(false), json!(false)],
+                ]));
+            host.push_query(SqlRule::query(&["max(octet_length(\"email\"::text))"], &["max"], vec![vec![json!(18)]]));
+        });
+        let encrypt = |alter_columns: bool| result(&simulate_route("execute_table_encryption", &json!({
+            "database_id": database_id,
+            "table": "users",
+            "columns": ["email"],
+            "primary_key": "id",
+            "chunk_size": 10,
+            "alter_columns": alter_columns,
+        })));
+        let executed = || with_host(|host| host.statements().iter().filter_map(|s| match s {
+            SqlStatement::Execute(sql) => Some(sql.clone()),
+            SqlStatement::Query(_) => None,
+        }).collect::<Vec<String>>()).unwrap();
+
+        let error = encrypt(false);
+        assert_eq!(error["code"], json!("COLUMN_TYPE_INCOMPATIBLE"), "{}", error);
+        assert!(error["error"].as_str().unwrap().contains("columns email (character varying(32)) of table users"), "{}", error);
+        assert!(executed().is_empty());
+
+        let encryption = encrypt(true);
+        assert_eq!(encryption["complete"], json!(true), "{}", encryption);
+        let statements = executed();
+        let alter = statements.iter().position(|s| s == "ALTER TABLE \"users\" ALTER COLUMN \"email\" TYPE text USING \"email\"::text").unwrap();
+        assert_eq!(statements[alter - 1], "BEGIN");
+        assert!(statements[alter + 1].contains("UPDATE \"users\" SET \"email\""));
+
+        // Decrypting the ciphertext gives the column its type back
+        let ciphertexts = written("email");
+        with_host(|host| host.push_query(SqlRule::query(&["SELECT \"id\",\"email\" FROM \"users\"", "ORDER BY \"id\""], &["id", "email"],
+            ciphertexts.iter().map(|(id, c)| vec![json!(id), json!(c)]).collect())));
+        let decryption = result(&simulate_route("execute_table_decryption", &json!({
+            "database_id": database_id,
+            "table": "users",
+            "columns": ["email"],
+            "primary_key": "id",
+            "chunk_size": 10,
+        })));
+        assert!(decryption.to_string().contains("\"decrypted\":3"), "{}", decryption);
+        assert!(executed().contains(&"ALTER TABLE \"users\" ALTER COLUMN \"email\" TYPE character varying(32) USING \"email\"::character varying(32)".to_string()));
+        uninstall();
+    }
+
+    #[test]
+    fn test_sql_transaction() {
+        install(SimulatedHost::new().with_script(fixture("users.json")));
+        result(&simulate_route("bootstrap", &json!({})));
+        let database_id = register_database();
+        let statements = json!([
+            "CREATE TABLE plans (id int PRIMARY KEY, name text)",
+            "INSERT INTO plans VALUES (1, 'free'), (2, 'pro')",
+            "CREATE INDEX plans_name ON plans (name)",
+        ]);
+        with_host(|host| host.push_execute(SqlRule { fragments: vec!["INSERT INTO plans".to_string()], result: Some(json!("INSERT 0 2")), ..Default::default() }));
+        let outcome = result(&simulate_route("sql_transaction", &json!({ "database_id": database_id, "statements": statements })));
+        assert_eq!(outcome["committed"], json!(true));
+        assert_eq!(outcome["statements"][1]["rows_affected"], json!(2));
+        assert_eq!(outcome["failed_index"], Value::Null);
+
+        // The failing statement is reported by index, the ones after it are not run
+        with_host(|host| host.push_execute(SqlRule::failing(&["INSERT INTO plans"], "duplicate key value violates unique constraint")));
+        let error = result(&simulate_route("sql_transaction", &json!({ "database_id": database_id, "statements": statements })));
+        assert_eq!(error["code"], json!("QUERY_FAILED"));
+        assert!(error["error"].as_str().unwrap().starts_with("Statement 1 failed: duplicate key"), "{}", error);
+        assert_eq!(error["details"]["failed_index"], json!(1));
+        assert_eq!(error["details"]["statements"][2]["status"], json!("skipped"));
+        let last = with_host(|host| host.statements().last().cloned()).unwrap();
+        assert_eq!(last, Some(SqlStatement::Execute("ROLLBACK".to_string())));
+
+        let empty = result(&simulate_route("sql_transaction", &json!({ "database_id": database_id, "statements": [] })));
+        assert_eq!(empty["code"], json!("INVALID_INPUT"));
+        let nested = result(&simulate_route("sql_transaction", &json!({ "database_id": database_id, "statements": ["COMMIT"] })));
+        assert_eq!(nested["code"], json!("INVALID_INPUT"));
+        uninstall();
+    }
+
+    #[test]
+    fn test_bootstrap_waits_for_a_passing_selftest() {
+        install(SimulatedHost::new().with_script(fixture("users.json")));
+        with_host(|host| host.broken_digest = true);
+        let report = result(&simulate_route("bootstrap", &json!({})));
+        assert_eq!((report["ready"].clone(), report["already_bootstrapped"].clone()), (json!(false), json!(false)), "{}", report);
+        assert!(!DeploymentSettings::exists());
+        let error = result(&simulate_route("db_setup", &json!({ "host": "db", "dbname": "app", "user": "app", "password": "secret" })));
+        assert_eq!(error["code"], json!("NOT_BOOTSTRAPPED"), "{}", error);
+
+        with_host(|host| host.broken_digest = false);
+        let report = result(&simulate_route("bootstrap", &json!({})));
+        assert_eq!((report["ready"].clone(), report["already_bootstrapped"].clone()), (json!(true), json!(false)), "{}", report);
+        assert!(register_database().is_string());
+        uninstall();
+    }
+
+    #[test]
+    fn test_credential_health_is_written_by_transactions() {
+        install(SimulatedHost::new().with_script(fixture("users.json")));
+        result(&simulate_route("bootstrap", &json!({})));
+        let database_id = register_database();
+        // A stale success seen on a query route is not recorded, and not warned about
+        with_host(|host| host.set_trusted_time(DEFAULT_TRUSTED_TIME + 120_000_000_000));
+        let frames = simulate_route("db_list_tables", &json!({ "database_id": database_id, "schema": "public" }));
+        let warnings: Vec<&Frame> = frames.iter().filter(|f| f.channel == Channel::Warning).collect();
+        assert!(!warnings.iter().any(|f| f.payload.as_str().unwrap().contains("credential health")), "{:?}", warnings);
+
+        with_host(|host| host.refuse_connections("FATAL: password authentication failed for user \"app\""));
+        for _ in 0..3 {
+            let tested = result(&simulate_route("test_credentials", &json!({ "database_id": database_id })));
+            assert_eq!(tested["authenticated"], json!(false), "{}", tested);
+        }
+        let tested = result(&simulate_route("test_credentials", &json!({ "database_id": database_id })));
+        assert_eq!(tested["credentials_suspect"], json!(true), "{}", tested);
+        uninstall();
+    }
+
+    #[test]
+    fn test_tls_status_is_written_by_transactions() {
+        install(SimulatedHost::new().with_script(fixture("users.json")));
+        result(&simulate_route("bootstrap", &json!({})));
+        let database_id = register_database();
+        let key = database_id.as_str().unwrap().to_string();
+        let forget = || with_host(|host| {
+            host.ledger_remove("CredentialHealthTable", &key).unwrap();
+            host.ledger_remove("ConnectionHandleTable", &key).unwrap();
+        }).unwrap();
+        // A query route opening a connection leaves the status to the next transaction
+        forget();
+        let frames = simulate_route("db_list_tables", &json!({ "database_id": database_id, "schema": "public" }));
+        assert!(!frames.iter().any(|f| f.channel == Channel::Warning && f.payload.as_str().unwrap().contains("TLS status")), "{:?}", frames);
+        assert!(with_host(|host| host.ledger_get("CredentialHealthTable", &key).is_err()).unwrap());
+
+        result(&simulate_route("test_credentials", &json!({ "database_id": database_id })));
+        let health: Value = serde_json::from_slice(&with_host(|host| host.ledger_get("CredentialHealthTable", &key)).unwrap().unwrap()).unwrap();
+        assert!(health.get("tls").is_some(), "{}", health);
+        uninstall();
+    }
+
+    #[test]
+    fn test_connection_handles_are_cached_by_transactions() {
+        install(SimulatedHost::new().with_script(fixture("users.json")));
+        result(&simulate_route("bootstrap", &json!({})));
+        let database_id = register_database();
+        let key = database_id.as_str().unwrap().to_string();
+        let cached = || with_host(|host| host.ledger_get("ConnectionHandleTable", &key).is_ok()).unwrap();
+        assert!(cached());
+        with_host(|host| host.ledger_remove("ConnectionHandleTable", &key).unwrap());
+        simulate_route("db_list_tables", &json!({ "database_id": database_id, "schema": "public" }));
+        assert!(!cached());
+        result(&simulate_route("test_credentials", &json!({ "database_id": database_id })));
+        assert!(cached());
+        uninstall();
+    }
+
+    #[test]
+    fn test_failed_lookups_are_audited_by_transactions() {
+        install(SimulatedHost::new().with_script(fixture("users.json")));
+        result(&simulate_route("bootstrap", &json!({})));
+        let lookups = || with_host(|host| host.ledger_keys("AuditLogTable").iter()
+            .filter(|k| String::from_utf8(host.ledger_get("AuditLogTable", k).unwrap()).unwrap().contains("client_lookup"))
+            .count()).unwrap();
+        // A query route answers the unknown id without trying to write the audit log
+        let frames = simulate_route("db_list_tables", &json!({ "database_id": "unknown", "schema": "public" }));
+        assert!(!frames.iter().any(|f| f.channel == Channel::Warning), "{:?}", frames);
+        assert_eq!(lookups(), 0);
+
+        let error = result(&simulate_route("sql_delete", &json!({ "database_id": "unknown" })));
+        assert_eq!(error["code"], json!("CLIENT_NOT_FOUND"), "{}", error);
+        assert_eq!(lookups(), 1);
+        uninstall();
+    }
+
+    #[test]
+    fn test_db_setup_connection_parameters() {
+        install(SimulatedHost::new().with_script(fixture("users.json")));
+        result(&simulate_route("bootstrap", &json!({})));
+        let details = json!({ "host": "db", "dbname": "app", "user": "app", "password": "secret" });
+        let default_port = result(&simulate_route("db_setup", &details));
+        let mut pooled = details.clone();
+        pooled.as_object_mut().unwrap().extend(json!({ "port": 6432, "sslmode": "require", "connect_timeout": 5 }).as_object().cloned().unwrap());
+        let pooler = result(&simulate_route("db_setup", &pooled));
+        // The port tells the two registrations apart, the same details give back the same client
+        assert_ne!(pooler, default_port);
+        assert_eq!(pooler["server_version"], json!("16.4"));
+        assert_eq!(result(&simulate_route("db_setup", &pooled)), pooler);
+        assert_eq!(result(&simulate_route("db_setup", &details)), default_port);
+        assert_eq!(result(&simulate_route("db_setup", &json!({ "uri": "postgres://app:secret@db/app" }))), default_port);
+        assert_eq!(result(&simulate_route("db_setup", &json!({ "uri": "postgresql://app:secret@db:6432/app?sslmode=require&connect_timeout=5" }))), pooler);
+        let error = result(&simulate_route("db_setup", &json!({ "uri": "postgres://app:secret@db:port/app" })));
+        assert_eq!(error["code"], json!("INVALID_INPUT"), "{}", error);
+
+        // Either form drops a misspelled field with a suggestion, as other route inputs do
+        let warnings = |frames: &[Frame]| frames.iter().filter(|f| f.channel == Channel::Warning).map(|f| f.payload.clone()).collect::<Vec<Value>>();
+        let frames = simulate_route("db_setup", &json!({ "uri": "postgres://app:secret@db/app", "tagz": ["production"] }));
+        assert_eq!(result(&frames), default_port);
+        assert_eq!(warnings(&frames), vec![json!("Ignored unknown field `tagz` at $, did you mean `tags`?")]);
+        let mut misspelled = details.clone();
+        misspelled["prt"] = json!(6432);
+        let frames = simulate_route("db_setup", &misspelled);
+        assert_eq!(result(&frames), default_port);
+        assert_eq!(warnings(&frames), vec![json!("Ignored unknown field `prt` at $, did you mean `port`?")]);
+
+        pooled["sslmode"] = json!("allow");
+        let error = result(&simulate_route("db_setup", &pooled));
+        assert!(error["error"].as_str().unwrap().contains("sslmode allow is not one of"), "{}", error);
+        assert_eq!(error["code"], json!("INVALID_INPUT"));
+        uninstall();
+    }
+
+    #[test]
+    fn test_save_keeps_master_key() {
+        install(SimulatedHost::new().with_script(fixture("users.json")));
+        result(&simulate_route("bootstrap", &json!({})));
+        let database_id = register_database().as_str().unwrap().to_string();
+        let mut client = Client::load(database_id.clone()).unwrap();
+        let master_key_name = client.master_key_name().map(|n| n.to_string());
+        assert!(master_key_name.is_some());
+        client.save().unwrap();
+        client.save().unwrap();
+        let reloaded = Client::load(database_id).unwrap();
+        assert_eq!(reloaded.master_key_name().map(|n| n.to_string()), master_key_name);
+        assert_eq!(with_host(|host| host.saved_key_names().len()).unwrap(), 1);
+        uninstall();
+    }
+
+    #[test]
+    fn test_query_page() {
+        install(SimulatedHost::new().with_script(fixture("users.json")));
+        result(&simulate_route("bootstrap", &json!({})));
+        let mut client = Client::load(register_database().as_str().unwrap().to_string()).unwrap();
+        client.connect().unwrap();
+        with_host(|host| host.push_query(SqlRule::query(&["_sub LIMIT 3 OFFSET 2"], &["id"], vec![vec![json!(3)], vec![json!(4)], vec![json!(5)]])));
+        let page = client.query_page("SELECT id FROM users ORDER BY id LIMIT 100", 1, 2).unwrap();
+        assert_eq!((page.rows, page.page, page.page_size, page.has_more), (vec![vec![json!("3")], vec![json!("4")]], 1, 2, true));
+        assert!(with_host(|host| host.statements().last().unwrap().sql().contains("ORDER BY id LIMIT 100\n) _sub LIMIT 3 OFFSET 2")).unwrap());
+
+        with_host(|host| host.push_query(SqlRule::query(&["_sub LIMIT 3 OFFSET 4"], &["id"], vec![vec![json!(5)]])));
+        assert!(!client.query_page("SELECT id FROM users ORDER BY id", 2, 2).unwrap().has_more);
+        let before = with_host(|host| host.statements().len()).unwrap();
+        let err = client.query_page("DELETE FROM users", 0, 2).unwrap_err();
+        assert_eq!(err.code(), Some(crate::errors::ErrorCode::InvalidInput));
+        assert_eq!(with_host(|host| host.statements().len()).unwrap(), before);
+        uninstall();
+    }
+
+    #[test]
+    fn test_sql_query_cursor() {
+        install(SimulatedHost::new().with_script(fixture("users.json")));
+        result(&simulate_route("bootstrap", &json!({})));
+        let database_id = register_database();
+        with_host(|host| {
+            host.push_query(SqlRule::query(&["information_schema.columns", "'users'"], &["column_name"], vec![vec![json!("id")], vec![json!("email")]]));
+            host.push_query(SqlRule::query(&["SELECT \"email\",\"id\" FROM \"users\" WHERE \"id\" IS NOT NULL ORDER BY \"id\" LIMIT 3"], &["email", "id"],
+                vec![vec![json!("a@x"), json!(1)], vec![json!("b@x"), json!(2)], vec![json!("c@x"), json!(3)]]));
+            host.push_query(SqlRule::query(&["\"id\" > '2' ORDER BY"], &["email", "id"], vec![vec![json!("c@x"), json!(3)]]));
+        });
+        let page = |after: Value| result(&simulate_route("sql_query_cursor", &json!({ "database_id": database_id, "table": "users", "columns": ["email"], "order_by": "id", "after": after, "limit": 2 })));
+        let first = page(Value::Null);
+        assert_eq!(first["rows"], json!([["a@x", "1"], ["b@x", "2"]]), "{}", first);
+        assert_eq!(first["next_cursor"], json!("2"));
+        let last = page(first["next_cursor"].clone());
+        assert_eq!((last["rows"].clone(), last["next_cursor"].clone()), (json!([["c@x", "3"]]), Value::Null));
+
+        let error = result(&simulate_route("sql_query_cursor", &json!({ "database_id": database_id, "table": "users", "order_by": "created_at", "limit": 2 })));
+        assert_eq!(error["code"], json!("INVALID_INPUT"), "{}", error);
+        uninstall();
+    }
+
+    #[test]
+    fn test_schema_introspection() {
+        install(SimulatedHost::new().with_script(fixture("users.json")));
+        result(&simulate_route("bootstrap", &json!({})));
+        let database_id = register_database();
+        with_host(|host| {
+            host.push_query(SqlRule::query(&["FROM information_schema.tables t", "t.table_schema = 'public'"], &["table_schema", "table_name", "reltuples"],
+                vec![vec![json!("public"), json!("users"), json!(3)]]));
+            host.push_query(SqlRule::query(&["FROM information_schema.columns c", "c.table_name = 'users'"],
+                &["column_name", "data_type", "oid", "is_nullable", "column_default", "character_maximum_length", "numeric_scale", "primary_key", "foreign_key"],
+                vec![vec![json!("id"), json!("integer"), json!(23), json!("NO"), Value::Null, Value::Null, json!(0), json!(true), json!(false)]]));
+            host.push_query(SqlRule::query(&["FROM information_schema.columns c", "c.table_name = 'ghosts'"], &["column_name"], vec![]));
+        });
+        let listed = result(&simulate_route("db_list_tables", &json!({ "database_id": database_id, "schema": "public" })));
+        assert_eq!(listed, json!({ "tables": [{ "schema": "public", "name": "users", "row_estimate": 3 }] }));
+        let described = result(&simulate_route("db_describe_table", &json!({ "database_id": database_id, "table": "users" })));
+        let id = &described["columns"][0];
+        assert_eq!((id["name"].clone(), id["type_name"].clone(), id["nullable"].clone(), id["primary_key"].clone()), (json!("id"), json!("integer"), json!(false), json!(true)), "{}", described);
+        let error = result(&simulate_route("db_describe_table", &json!({ "database_id": database_id, "table": "ghosts" })));
+        assert_eq!(error["code"], json!("INVALID_INPUT"), "{}", error);
+        uninstall();
+    }
+
+    #[test]
+    fn test_db_setup_without_caller_identity() {
+        install(SimulatedHost::new().with_script(fixture("users.json")));
+        result(&simulate_route("bootstrap", &json!({})));
+        let stored = || with_host(|host| (host.ledger_keys("DatabaseClientTable").len(), host.saved_key_names().len())).unwrap();
+        let before = stored();
+        with_host(|host| host.context.remove("sender"));
+        let error = result(&simulate_route("db_setup", &json!({ "host": "db", "dbname": "app", "user": "app", "password": "secret" })));
+        assert_eq!(error["code"], json!("NO_CLIENT_CONTEXT"), "{}", error);
+        // Neither a client record nor a master key is left behind
+        assert_eq!(stored(), before);
+        uninstall();
+    }
+
+    #[test]
+    fn test_statement_echo() {
+        install(SimulatedHost::new().with_script(fixture("users.json")));
+        result(&simulate_route("bootstrap", &json!({})));
+        let details = json!({ "host": "db", "dbname": "app", "user": "app", "password": "s3cret pass" });
+        let database_id = result(&simulate_route("db_setup", &details))["database_id"].clone();
+        with_host(|host| host.push_query(SqlRule::query(&["WHERE email ="], &["id"], vec![vec![json!(2)]])));
+        let script = "SELECT id FROM users WHERE email = 'grace@example.com'";
+        let debug = |frames: &[Frame]| frames.iter().filter(|f| f.channel == Channel::Debug).map(|f| f.payload.clone()).collect::<Vec<Value>>();
+        assert!(debug(&simulate_route("sql_script", &json!({ "database_id": database_id, "script": script }))).is_empty());
+
+        let mut echoed = details.clone();
+        echoed["host"] = json!("db2");
+        echoed["echo_statements"] = json!(true);
+        let database_id = result(&simulate_route("db_setup", &echoed))["database_id"].clone();
+        let frames = debug(&simulate_route("sql_script", &json!({ "database_id": database_id, "script": script })));
+        assert!(frames.iter().any(|f| f.as_str().unwrap().contains("SELECT id FROM users WHERE email = '<redacted 19 chars>'")), "{:?}", frames);
+        assert!(frames.iter().all(|f| !f.as_str().unwrap().contains("grace")));
+
+        // A refused connection is reported without the password the host echoed
+        with_host(|host| host.refuse_connections("could not connect"));
+        echoed["host"] = json!("db3");
+        let error = result(&simulate_route("db_setup", &echoed));
+        let message = error["error"].as_str().unwrap();
+        assert!(message.contains("could not connect (host=db3 dbname=app user=app password=<redacted>"), "{}", message);
+        assert!(!message.contains("s3cret"));
+        uninstall();
+    }
+
+    #[test]
+    fn test_delete_destroys_keys() {
+        install(SimulatedHost::new().with_script(fixture("users.json")));
+        result(&simulate_route("bootstrap", &json!({})));
+        let database_id = register_database();
+        result(&simulate_route("execute_table_encryption", &json!({
+            "database_id": database_id,
+            "table": "users",
+            "columns": ["email"],
+            "primary_key": "id",
+            "chunk_size": 10,
+        })));
+        let keys = with_host(|host| host.saved_key_names().len()).unwrap();
+        // Other callers learn neither that the database exists nor what it encrypts
+        with_host(|host| host.set_sender("intruder"));
+        let error = result(&simulate_route("sql_delete", &json!({ "database_id": database_id })));
+        assert_eq!(error["code"], json!("CLIENT_NOT_FOUND"), "{}", error);
+        assert!(!error.to_string().contains("users"), "{}", error);
+        with_host(|host| host.set_sender(DEFAULT_SENDER));
+        let error = result(&simulate_route("sql_delete", &json!({ "database_id": database_id })));
+        assert!(error["error"].as_str().unwrap().contains("Client still owns encrypted tables: users"), "{}", error);
+
+        let prompt = result(&simulate_route("sql_delete", &json!({ "database_id": database_id, "force": true })));
+        // The envelope reports what the deletion did, the legacy shape keeps its sentence
+        result(&simulate_route("update_settings", &json!({ "result_shape": "envelope" })));
+        let deleted = result(&simulate_route("sql_delete", &json!({
+            "database_id": database_id,
+            "force": true,
+            "confirmation_token": prompt["confirmation_token"],
+            "summary_hash": prompt["summary_hash"],
+        })));
+        assert_eq!(deleted["data"], json!({ "database_id": database_id, "deleted": true, "encrypted_data_unreadable": true, "encrypted_tables": ["users"] }));
+        assert_eq!(with_host(|host| host.saved_key_names().len()).unwrap(), keys - 1);
+        assert!(with_host(|host| host.ledger_get("ConnectionHandleTable", database_id.as_str().unwrap()).is_err()).unwrap());
+        result(&simulate_route("update_settings", &json!({ "result_shape": "legacy" })));
+        assert!(listed_ids().is_empty());
+
+        // Without encrypted tables nothing becomes unreadable
+        let database_id = register_database();
+        result(&simulate_route("update_settings", &json!({ "result_shape": "envelope" })));
+        let deleted = result(&simulate_route("sql_delete", &json!({ "database_id": database_id })));
+        assert_eq!(deleted["data"]["encrypted_data_unreadable"], json!(false));
+        assert_eq!(with_host(|host| host.saved_key_names().len()).unwrap(), keys - 1);
+        result(&simulate_route("update_settings", &json!({ "result_shape": "legacy" })));
+        let database_id = register_database();
+        let deleted = result(&simulate_route("sql_delete", &json!({ "database_id": database_id })));
+        assert_eq!(deleted, json!(format!("Client {} deleted", database_id.as_str().unwrap())));
+        uninstall();
+    }
+
+    #[test]
+    fn test_delete_needs_a_loadable_record() {
+        install(SimulatedHost::new().with_script(fixture("users.json")));
+        result(&simulate_route("bootstrap", &json!({})));
+        let database_id = register_database();
+        let id = database_id.as_str().unwrap().to_string();
+        let keys = with_host(|host| host.saved_key_names().len()).unwrap();
+
+        // Listing a database grants nothing to a caller its record does not name
+        with_host(|host| {
+            host.ledger_set("DatabaseClientTable", "LIST_intruder", json!({ "clients": [id] }).to_string().as_bytes()).unwrap();
+            host.set_sender("intruder");
+        });
+        let denied = result(&simulate_route("sql_delete", &json!({ "database_id": database_id })));
+        assert_eq!(denied["code"], json!("CLIENT_NOT_FOUND"), "{}", denied);
+        with_host(|host| host.set_sender(DEFAULT_SENDER));
+        assert!(with_host(|host| host.ledger_get("DatabaseClientTable", &id).is_ok()).unwrap());
+
+        // Nor does an unreadable record let its keys be orphaned
+        with_host(|host| host.ledger_set("DatabaseClientTable", &id, b"{not json").unwrap());
+        let error = result(&simulate_route("sql_delete", &json!({ "database_id": database_id })));
+        assert!(error["error"].as_str().unwrap().starts_with("Failed to load client"), "{}", error);
+        assert!(with_host(|host| host.ledger_get("DatabaseClientTable", &id).is_ok()).unwrap());
+        assert_eq!(with_host(|host| host.saved_key_names().len()).unwrap(), keys);
+
+        // A listing left behind by a record already gone is cleaned up
+        with_host(|host| host.ledger_remove("DatabaseClientTable", &id).unwrap());
+        result(&simulate_route("sql_delete", &json!({ "database_id": database_id })));
+        assert!(listed_ids().is_empty());
+        uninstall();
+    }
+
+    fn listed_ids() -> Vec<Value> {
+        let listed = result(&simulate_route("sql_list", &json!({})));
+        listed["clients"].as_array().unwrap().iter().map(|c| c["database_id"].clone()).collect()
+    }
+
+    #[test]
+    fn test_delete_removes_the_record_before_the_keys() {
+        install(SimulatedHost::new().with_script(fixture("users.json")));
+        result(&simulate_route("bootstrap", &json!({})));
+        let database_id = register_database();
+        let keys = with_host(|host| host.saved_key_names().len()).unwrap();
+        assert!(keys > 0);
+        with_host(|host| host.locked_keys = true);
+        let error = result(&simulate_route("sql_delete", &json!({ "database_id": database_id })));
+        assert!(error["error"].as_str().unwrap().contains("is locked"), "{}", error);
+        // The keys are left behind, nothing refers to them any more
+        assert!(with_host(|host| host.ledger_get("DatabaseClientTable", database_id.as_str().unwrap()).is_err()).unwrap());
+        assert!(listed_ids().is_empty());
+        assert_eq!(with_host(|host| host.saved_key_names().len()).unwrap(), keys);
+        uninstall();
+    }
+
+    #[test]
+    fn test_client_list_per_sender() {
+        install(SimulatedHost::new().with_script(fixture("users.json")));
+        result(&simulate_route("bootstrap", &json!({})));
+        let legacy = register_database();
+        // Lay the list out the way earlier versions did, under ALL
+        with_host(|host| {
+            let key = format!("LIST_{}", DEFAULT_SENDER);
+            let list = host.ledger_get("DatabaseClientTable", &key).unwrap();
+            host.ledger_remove("DatabaseClientTable", &key).unwrap();
+            host.ledger_set("DatabaseClientTable", "ALL", &list).unwrap();
+        });
+        assert_eq!(listed_ids(), vec![legacy.clone()]);
+        assert_eq!(register_database(), legacy);
+
+        // The first save copies the legacy entries to the list of the caller, the legacy list stays
+        let pooled = result(&simulate_route("db_setup", &json!({ "host": "db", "port": 6432, "dbname": "app", "user": "app", "password": "secret" })));
+        assert_eq!(listed_ids(), vec![legacy.clone(), pooled["database_id"].clone()]);
+        assert!(with_host(|host| host.ledger_get("DatabaseClientTable", "ALL").is_ok()).unwrap());
+
+        // Another identity still falls back to it, and saves a list of its own without taking it over
+        with_host(|host| {
+            let key = legacy.as_str().unwrap();
+            let mut record: Value = serde_json::from_slice(&host.ledger_get("DatabaseClientTable", key).unwrap()).unwrap();
+            record.as_object_mut().unwrap().remove("owners");
+            host.ledger_set("DatabaseClientTable", key, record.to_string().as_bytes()).unwrap();
+        });
+        with_host(|host| host.set_sender("tenant-b"));
+        assert_eq!(listed_ids(), vec![legacy.clone()]);
+        let own = result(&simulate_route("db_setup", &json!({ "host": "db", "port": 7000, "dbname": "app", "user": "app", "password": "secret" })));
+        assert_eq!(listed_ids(), vec![legacy.clone(), own["database_id"].clone()]);
+        assert!(with_host(|host| host.ledger_get("DatabaseClientTable", "ALL").is_ok()).unwrap());
+
+        // An ownerless record reached through the legacy list can be read, not owned
+        let error = result(&simulate_route("sql_delete", &json!({ "database_id": legacy })));
+        assert_eq!(error["code"], json!("ACCESS_DENIED"), "{}", error);
+        with_host(|host| host.set_sender(DEFAULT_SENDER));
+        assert_eq!(listed_ids().len(), 2);
+        uninstall();
+    }
+
+    #[test]
+    fn test_shared_database_access() {
+        install(SimulatedHost::new().with_script(fixture("users.json")));
+        result(&simulate_route("bootstrap", &json!({})));
+        with_host(|host| host.set_sender("owner-a"));
+        let database_id = register_database();
+        let grant = |grantee: &str, role: &str| result(&simulate_route("grant_db_access", &json!({ "database_id": database_id, "grantee_client_id": grantee, "role": role })));
+        let revoke = |grantee: &str, role: &str| result(&simulate_route("revoke_db_access", &json!({ "database_id": database_id, "grantee_client_id": grantee, "role": role })));
+        assert_eq!(grant("reader-b", "reader"), json!({ "database_id": database_id, "owners": ["owner-a"], "readers": ["reader-b"] }));
+
+        // A reader sees the database and queries it, nothing more
+        with_host(|host| host.set_sender("reader-b"));
+        assert_eq!(listed_ids(), vec![database_id.clone()]);
+        with_host(|host| host.push_query(SqlRule::query(&["FROM users"], &["id"], vec![vec![json!(1)]])));
+        let outcome = result(&simulate_route("sql_script", &json!({ "database_id": database_id, "script": "SELECT id FROM users" })));
+        assert!(outcome["statements"][0]["error"].is_null(), "{}", outcome);
+        let outcome = result(&simulate_route("sql_script", &json!({ "database_id": database_id, "script": "UPDATE users SET email = 'x'" })));
+        assert!(outcome.to_string().contains("only owners of database"), "{}", outcome);
+        let error = result(&simulate_route("execute_table_encryption", &json!({ "database_id": database_id, "table": "users", "columns": ["email"], "primary_key": "id", "chunk_size": 10 })));
+        assert_eq!(error["code"], json!("ACCESS_DENIED"), "{}", error);
+        assert_eq!(grant("tenant-c", "reader")["code"], json!("ACCESS_DENIED"));
+        assert_eq!(result(&simulate_route("sql_delete", &json!({ "database_id": database_id })))["code"], json!("ACCESS_DENIED"));
+
+        // Anyone else is answered as for an unknown id
+        with_host(|host| host.set_sender("tenant-c"));
+        let error = result(&simulate_route("execute_table_encryption", &json!({ "database_id": database_id, "table": "users", "columns": ["email"], "primary_key": "id", "chunk_size": 10 })));
+        assert_eq!(error["code"], json!("CLIENT_NOT_FOUND"), "{}", error);
+        let unknown = result(&simulate_route("execute_table_encryption", &json!({ "database_id": "unknown", "table": "users", "columns": ["email"], "primary_key": "id", "chunk_size": 10 })));
+        assert_eq!(unknown["error"].as_str().unwrap().replace("unknown", database_id.as_str().unwrap()), error["error"].as_str().unwrap());
+
+        // The last owner stays until another one is granted
+        with_host(|host| host.set_sender("owner-a"));
+        assert_eq!(revoke("owner-a", "owner")["code"], json!("INVALID_INPUT"));
+        assert_eq!(grant("reader-b", "owner")["owners"], json!(["owner-a", "reader-b"]));
+        assert_eq!(revoke("owner-a", "owner")["owners"], json!(["reader-b"]));
+        assert!(listed_ids().is_empty());
+        with_host(|host| host.set_sender("reader-b"));
+        assert_eq!(revoke("reader-b", "reader")["code"], json!("INVALID_INPUT"));
+        assert_eq!(result(&simulate_route("sql_delete", &json!({ "database_id": database_id }))), json!(format!("Client {} deleted", database_id.as_str().unwrap())));
+
+        // A record written before owners existed belongs to the identities listing it
+        with_host(|host| host.set_sender("tenant-c"));
+        let legacy = register_database();
+        with_host(|host| {
+            let key = legacy.as_str().unwrap();
+            let mut record: Value = serde_json::from_slice(&host.ledger_get("DatabaseClientTable", key).unwrap()).unwrap();
+            record.as_object_mut().unwrap().remove("owners");
+            record.as_object_mut().unwrap().remove("readers");
+            host.ledger_set("DatabaseClientTable", key, record.to_string().as_bytes()).unwrap();
+        });
+        let granted = result(&simulate_route("grant_db_access", &json!({ "database_id": legacy, "grantee_client_id": "reader-b", "role": "reader" })));
+        assert_eq!(granted["owners"], json!(["tenant-c"]), "{}", granted);
+        uninstall();
+    }
+
+    #[test]
+    fn test_script_rows_as_objects() {
+        install(SimulatedHost::new().with_script(fixture("users.json")));
+        result(&simulate_route("bootstrap", &json!({})));
+        let database_id = register_database();
+        with_host(|host| host.push_query(SqlRule::query(&["JOIN orders"], &["id", "email", "id"], vec![vec![json!(1), json!("ada@example.com"), json!(40)]])));
+        let script = "SELECT u.id, u.email, o.id FROM users u JOIN orders o ON o.user_id = u.id";
+        let outcome = result(&simulate_route("sql_script", &json!({ "database_id": database_id, "script": script, "format": "objects" })));
+        let statement = &outcome["statements"][0];
+        assert_eq!(statement["rows"], json!([{ "id": "1", "email": "ada@example.com", "id_2": "40" }]));
+        assert_eq!(statement["resultset"]["resultset"], json!([]));
+
+        let outcome = result(&simulate_route("sql_script", &json!({ "database_id": database_id, "script": script })));
+        assert_eq!(outcome["statements"][0]["resultset"]["resultset"], json!([["1", "ada@example.com", "40"]]));
+        assert!(outcome["statements"][0].get("rows").is_none());
+        uninstall();
+    }
+
+    #[test]
+    fn test_every_route_dispatches() {
+        install(SimulatedHost::new());
+        for (route, _) in ROUTES.iter() {
+            let frames = simulate_route(route, &json!({}));
+            assert!(frames.iter().any(|f| f.channel == Channel::Result), "{}", route);
+            assert_ne!(result(&frames)["error"], json!(format!("Unknown route {}", route)));
+        }
+        let unknown = result(&simulate_route("no_such_route", &json!({})));
+        assert_eq!(unknown["error"], json!("Unknown route no_such_route"));
+        uninstall();
+    }
+
+    #[test]
+    fn test_query_routes_cannot_write_the_ledger() {
+        install(SimulatedHost::new().with_script(fixture("users.json")));
+        result(&simulate_route("bootstrap", &json!({})));
+        let database_id = register_database();
+        with_host(|host| {
+            host.read_only = true;
+            assert!(host.ledger_set("EncryptionManifestTable", "db", b"{}").is_err());
+            assert!(host.ledger_remove("EncryptionManifestTable", "db").is_err());
+            host.read_only = false;
+        });
+        // The run records its manifest, which a query route could not
+        let encryption = result(&simulate_route("execute_table_encryption", &json!({
+            "database_id": database_id, "table": "users", "columns": ["email"], "primary_key": "id", "chunk_size": 10,
+        })));
+        assert_eq!(encryption["complete"], json!(true), "{}", encryption);
+        assert!(with_host(|host| host.ledger_get("EncryptionManifestTable", database_id.as_str().unwrap()).is_ok()).unwrap());
+        uninstall();
+    }
+
+    #[test]
+    fn test_aad_contexts_get_their_own_iv() {
+        install(SimulatedHost::new());
+        let master_key = crate::crypto::generate_ecc_crypto_key().unwrap();
+        let template = crate::crypto::AadTemplate::parse("{tenant_id}|{table}|{column}").unwrap();
+        let cipher = crate::crypto::ColumnCipher::new(&master_key, "users", "email").unwrap().with_aad_template(Some(template));
+        let iv = |tenant: &str| {
+            let context = json!({ "tenant_id": tenant }).as_object().unwrap().clone();
+            let encoded = cipher.encrypt(&json!("ada@example.com"), &context).unwrap();
+            let (_, payload) = crate::crypto::parse_ciphertext(&encoded).unwrap();
+            payload[..2 * crate::crypto::AES_GCM_IV_SIZE].to_string()
+        };
+        assert_ne!(iv("acme"), iv("globex"));
+        // Still deterministic within a context
+        assert_eq!(iv("acme"), iv("acme"));
+        uninstall();
+    }
+
+    #[test]
+    fn test_simulated_crypto() {
+        // FIPS 180-2 test vectors
+        assert_eq!(hex::encode(sha256(b"abc")), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        assert_eq!(hex::encode(sha256(b"")), "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+        assert_eq!(hex::encode(sha256(&[b'a'; 1000])), "41edece42d63e8d9bf515a9ba6932e1c20cbc9f5a5d134645adb5db1b9737ea3");
+        // RFC 4231 case 2
+        assert_eq!(hex::encode(hmac_sha256(b"Jefe", b"what do ya want for nothing?")), "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843");
+        assert!(digest("SHA-512", b"abc").is_err());
+
+        let host = SimulatedHost::new();
+        assert_eq!(host.context("sender").unwrap(), DEFAULT_SENDER);
+        assert!(host.ledger_get("DatabaseClientTable", "ALL").is_err());
+    }
+}
@@ -0,0 +1,342 @@
+use std::collections::BTreeMap;
+
+use crate::runtime::subtle::{self, AesGcmParams, AesKeyGenParams, EncryptAlgorithm, KeyGenAlgorithm, KeyWrapAlgorithm, RsaHashedKeyGenParams, RsaOaepParams};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{
+    audit::{self, AUDIT_LOG_TABLE},
+    consistency::CONSISTENCY_TABLE,
+    database::{is_client_list_key, DatabaseError, CLIENT_POLICY_TABLE, DATABASE_CLIENT_TABLE},
+    history::AUDIT_AGGREGATE_TABLE,
+    manifest::ENCRYPTION_MANIFEST_TABLE,
+    notify,
+    settings::{require_admin, DEPLOYMENT_SETTINGS_TABLE},
+    storage,
+    utils::get_trusted_time,
+};
+
+pub const SNAPSHOT_VERSION: u32 = 1;
+// Hex characters of ciphertext per progress frame
+const SNAPSHOT_CHUNK_SIZE: usize = 64 * 1024;
+// Ledger tables owned by the crate. Key material lives in the key store and never enters a snapshot.
+const SNAPSHOT_TABLES: [&str; 7] = [DATABASE_CLIENT_TABLE, CLIENT_POLICY_TABLE, ENCRYPTION_MANIFEST_TABLE, CONSISTENCY_TABLE, DEPLOYMENT_SETTINGS_TABLE, AUDIT_LOG_TABLE, AUDIT_AGGREGATE_TABLE];
+
+// Ledger records of the crate, by table then key. BTreeMaps and serde_json's sorted maps make the
+// serialized bundle canonical.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StateBundle {
+    pub version: u32,
+    pub exported_at: u64,
+    pub tables: BTreeMap<String, BTreeMap<String, Value>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SnapshotHeader {
+    pub version: u32,
+    pub exported_at: u64,
+    // AES-256-GCM bundle key wrapped with RSA-OAEP under the operator public key, hex encoded
+    pub wrapped_key: String,
+    pub iv: String,
+    pub chunk_count: usize,
+    pub records: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SnapshotChunk {
+    pub index: usize,
+    pub data: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ExportSnapshotInput {
+    // Operator RSA public key (SPKI DER, hex encoded)
+    pub public_key: String,
+}
+
+fn default_dry_run() -> bool {
+    true
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ImportSnapshotInput {
+    pub header: SnapshotHeader,
+    pub chunks: Vec<SnapshotChunk>,
+    // Operator RSA private key (PKCS#8 DER, hex encoded), only held for the duration of the call
+    pub private_key: String,
+    // Records are restored into "<prefix>_<table>" ledger tables, the live tables when empty
+    #[serde(default)]
+    pub prefix: String,
+    #[serde(default = "default_dry_run")]
+    pub dry_run: bool,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct TableDiff {
+    pub added: Vec<String>,
+    pub changed: Vec<String>,
+    pub unchanged: u64,
+}
+
+fn bundle_aad(version: u32, exported_at: u64) -> Vec<u8> {
+    format!("klave-state-snapshot/v{}/{}", version, exported_at).into_bytes()
+}
+
+// Restored clients lose their key reference and connection handle until their keys are re-imported
+pub fn sanitize_client_record(key: &str, record: &mut Value) {
+    if is_client_list_key(key) {
+        return;
+    }
+    if let Some(client) = record.as_object_mut() {
+        client.insert("master_key_name".to_string(), Value::Null);
+        client.remove("key_rotation");
+        client.insert("opaque_handle".to_string(), Value::String(String::new()));
+        client.insert("needs_key_attach".to_string(), Value::Bool(true));
+    }
+}
+
+pub fn canonical_bytes(bundle: &StateBundle) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    Ok(serde_json::to_vec(&serde_json::to_value(bundle)?)?)
+}
+
+pub fn split_chunks(data: &str, chunk_size: usize) -> Vec<SnapshotChunk> {
+    data.as_bytes().chunks(chunk_size.max(1)).enumerate()
+        .map(|(index, c)| SnapshotChunk { index, data: String::from_utf8_lossy(c).to_string() })
+        .collect()
+}
+
+// Puts the chunks back in order, every index of the header must be present exactly once
+pub fn reassemble_chunks(header: &SnapshotHeader, chunks: &[SnapshotChunk]) -> Result<String, String> {
+    if chunks.len() != header.chunk_count {
+        return Err(format!("expected {} chunks, got {}", header.chunk_count, chunks.len()));
+    }
+    let mut ordered: Vec<&SnapshotChunk> = chunks.iter().collect();
+    ordered.sort_by_key(|c| c.index);
+    if ordered.iter().enumerate().any(|(i, c)| c.index != i) {
+        return Err("chunk indexes are not contiguous".to_string());
+    }
+    Ok(ordered.iter().map(|c| c.data.as_str()).collect())
+}
+
+pub fn target_table(prefix: &str, table: &str) -> String {
+    if prefix.is_empty() {
+        table.to_string()
+    } else {
+        format!("{}_{}", prefix, table)
+    }
+}
+
+fn is_valid_prefix(prefix: &str) -> bool {
+    prefix.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+pub fn diff_table(records: &BTreeMap<String, Value>, existing: impl Fn(&str) -> Option<Value>) -> TableDiff {
+    let mut diff = TableDiff::default();
+    for (key, record) in records.iter() {
+        match existing(key) {
+            None => diff.added.push(key.clone()),
+            Some(current) if &current != record => diff.changed.push(key.clone()),
+            Some(_) => diff.unchanged += 1,
+        }
+    }
+    diff
+}
+
+fn collect_bundle() -> Result<StateBundle, Box<dyn std::error::Error>> {
+    let mut tables = BTreeMap::new();
+    for table in SNAPSHOT_TABLES {
+        let ledger = crate::runtime::ledger::get_table(table);
+        let mut records = BTreeMap::new();
+        for key in ledger.list_keys()? {
+            let mut record: Value = serde_json::from_slice(&ledger.get(&key)?)?;
+            if table == DATABASE_CLIENT_TABLE {
+                sanitize_client_record(&key, &mut record);
+            }
+            records.insert(key, record);
+        }
+        tables.insert(table.to_string(), records);
+    }
+    Ok(StateBundle { version: SNAPSHOT_VERSION, exported_at: get_trusted_time(), tables })
+}
+
+fn rsa_algorithm() -> KeyGenAlgorithm {
+    KeyGenAlgorithm::Rsa(RsaHashedKeyGenParams::default())
+}
+
+fn seal_bundle(bundle: &StateBundle, public_key: &str) -> Result<(SnapshotHeader, Vec<SnapshotChunk>), Box<dyn std::error::Error>> {
+    let operator_key = subtle::import_key("spki", &hex::decode(public_key)?, &rsa_algorithm(), false, &["encrypt", "wrap_key"])?;
+    let bundle_key = subtle::generate_key(&KeyGenAlgorithm::Aes(AesKeyGenParams::default()), true, &["encrypt", "decrypt"])?;
+    let iv = crate::runtime::random::get_random_bytes(12)?;
+
+    let params = AesGcmParams { iv: iv.clone(), additional_data: bundle_aad(bundle.version, bundle.exported_at), tag_length: 128 };
+    let ciphertext = subtle::encrypt(&EncryptAlgorithm::AesGcm(params), &bundle_key, &canonical_bytes(bundle)?)?;
+    let wrapped_key = subtle::wrap_key("raw", &bundle_key, &operator_key, &KeyWrapAlgorithm::RsaOaep(RsaOaepParams::default()))?;
+
+    let chunks = split_chunks(&hex::encode(ciphertext), SNAPSHOT_CHUNK_SIZE);
+    let header = SnapshotHeader {
+        version: bundle.version,
+        exported_at: bundle.exported_at,
+        wrapped_key: hex::encode(wrapped_key),
+        iv: hex::encode(iv),
+        chunk_count: chunks.len(),
+        records: bundle.tables.values().map(|t| t.len()).sum(),
+    };
+    Ok((header, chunks))
+}
+
+fn open_bundle(input: &ImportSnapshotInput) -> Result<StateBundle, Box<dyn std::error::Error>> {
+    if input.header.version != SNAPSHOT_VERSION {
+        return Err(format!("Unsupported snapshot version {}, expected {}", input.header.version, SNAPSHOT_VERSION).into());
+    }
+    let ciphertext = hex::decode(reassemble_chunks(&input.header, &input.chunks)?)?;
+    let operator_key = subtle::import_key("pkcs8", &hex::decode(&input.private_key)?, &rsa_algorithm(), false, &["decrypt", "unwrap_key"])?;
+    let bundle_key = subtle::unwrap_key("raw", &hex::decode(&input.header.wrapped_key)?, &operator_key,
+        &KeyWrapAlgorithm::RsaOaep(RsaOaepParams::default()), &KeyGenAlgorithm::Aes(AesKeyGenParams::default()), false, &["decrypt"])?;
+
+    let params = AesGcmParams { iv: hex::decode(&input.header.iv)?, additional_data: bundle_aad(input.header.version, input.header.exported_at), tag_length: 128 };
+    let plaintext = subtle::decrypt(&EncryptAlgorithm::AesGcm(params), &bundle_key, &ciphertext)?;
+    let bundle: StateBundle = serde_json::from_slice(&plaintext)?;
+    if bundle.version != input.header.version || bundle.exported_at != input.header.exported_at {
+        return Err("Snapshot header does not match its bundle".into());
+    }
+    Ok(bundle)
+}
+
+// Both routes change or expose the whole deployment, only the admin recorded in the settings may call them
+fn check_admin() -> Result<(), String> {
+    require_admin("export or import state snapshots")
+}
+
+pub fn export_state_snapshot(cmd: String) {
+    let input: ExportSnapshotInput = match notify::parse_input(&cmd) {
+        Some(input) => input,
+        None => return,
+    };
+    if let Err(err) = check_admin() {
+        notify::error(&err);
+        return;
+    }
+
+    let sealed = collect_bundle().and_then(|bundle| seal_bundle(&bundle, &input.public_key));
+    let (header, chunks) = match sealed {
+        Ok(sealed) => sealed,
+        Err(err) => {
+            audit::record("export_state_snapshot", None, "failure", json!({ "error": err.to_string() }));
+            notify::error_with_code(&format!("Failed to export state snapshot: {}", err), DatabaseError::code_of(err.as_ref()));
+            return;
+        }
+    };
+    audit::record("export_state_snapshot", None, "success", json!({ "records": header.records, "chunks": header.chunk_count }));
+
+    // Chunks go out in order on the progress channel, the header closing the stream as the result
+    for chunk in chunks.iter() {
+        notify::progress(chunk);
+    }
+    notify::result(&header);
+}
+
+pub fn import_state_snapshot(cmd: String) {
+    let input: ImportSnapshotInput = match notify::parse_input(&cmd) {
+        Some(input) => input,
+        None => return,
+    };
+    if !is_valid_prefix(&input.prefix) {
+        notify::error("Invalid input: prefix may only hold letters, digits, '_' and '-'");
+        return;
+    }
+    if let Err(err) = check_admin() {
+        notify::error(&err);
+        return;
+    }
+
+    let bundle = match open_bundle(&input) {
+        Ok(bundle) => bundle,
+        Err(err) => {
+            notify::error_with_code(&format!("Failed to open state snapshot: {}", err), DatabaseError::code_of(err.as_ref()));
+            return;
+        }
+    };
+
+    let mut diffs = BTreeMap::new();
+    for (table, records) in bundle.tables.iter() {
+        let target = crate::runtime::ledger::get_table(&target_table(&input.prefix, table));
+        let diff = diff_table(records, |key| target.get(key).ok().and_then(|v| serde_json::from_slice(&v).ok()));
+        if !input.dry_run {
+            for key in diff.added.iter().chain(diff.changed.iter()) {
+                if let Err(err) = storage::ledger_set(&target_table(&input.prefix, table), key, serde_json::to_string(&records[key]).unwrap_or_default().as_bytes()) {
+                    notify::error_with_code(&format!("Failed to restore {}/{}: {}", table, key, err), DatabaseError::code_of(err.as_ref()));
+                    return;
+                }
+            }
+        }
+        diffs.insert(target_table(&input.prefix, table), diff);
+    }
+
+    if !input.dry_run {
+        audit::record("import_state_snapshot", None, "success", json!({ "prefix": input.prefix, "exported_at": bundle.exported_at }));
+    }
+    notify::result(&json!({
+        "dry_run": input.dry_run,
+        "exported_at": bundle.exported_at,
+        "tables": diffs
+    }));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLIENT_LIST_KEY: &str = "LIST_alice";
+
+    fn bundle() -> StateBundle {
+        let mut clients = BTreeMap::new();
+        clients.insert(CLIENT_LIST_KEY.to_string(), json!({ "clients": ["db1"] }));
+        clients.insert("db1".to_string(), json!({ "opaque_handle": "h", "master_key_name": "k", "key_rotation": { "key_name": "k2" }, "database_id": "db1" }));
+        let mut tables = BTreeMap::new();
+        tables.insert(DATABASE_CLIENT_TABLE.to_string(), clients);
+        StateBundle { version: SNAPSHOT_VERSION, exported_at: 1, tables }
+    }
+
+    #[test]
+    fn test_client_records_are_sanitized() {
+        let mut bundle = bundle();
+        for (key, record) in bundle.tables.get_mut(DATABASE_CLIENT_TABLE).unwrap().iter_mut() {
+            sanitize_client_record(key, record);
+        }
+        let clients = &bundle.tables[DATABASE_CLIENT_TABLE];
+        assert_eq!(clients["db1"]["master_key_name"], Value::Null);
+        assert!(clients["db1"].get("key_rotation").is_none());
+        assert_eq!(clients["db1"]["needs_key_attach"], Value::Bool(true));
+        assert_eq!(clients[CLIENT_LIST_KEY], json!({ "clients": ["db1"] }));
+    }
+
+    #[test]
+    fn test_canonical_bytes_sort_keys() {
+        let bytes = String::from_utf8(canonical_bytes(&bundle()).unwrap()).unwrap();
+        assert!(bytes.find("\"database_id\"").unwrap() < bytes.find("\"master_key_name\"").unwrap());
+        assert_eq!(serde_json::from_str::<StateBundle>(&bytes).unwrap(), bundle());
+    }
+
+    #[test]
+    fn test_chunks_round_trip_out_of_order() {
+        let data = "0123456789abcdef0123";
+        let mut chunks = split_chunks(data, 6);
+        let header = SnapshotHeader { version: 1, exported_at: 1, wrapped_key: String::new(), iv: String::new(), chunk_count: chunks.len(), records: 0 };
+        chunks.reverse();
+        assert_eq!(reassemble_chunks(&header, &chunks).unwrap(), data);
+        chunks.pop();
+        assert!(reassemble_chunks(&header, &chunks).is_err());
+    }
+
+    #[test]
+    fn test_diff_table_and_target() {
+        let records = &bundle().tables[DATABASE_CLIENT_TABLE];
+        let diff = diff_table(records, |key| (key == CLIENT_LIST_KEY).then(|| json!({ "clients": ["db1"] })));
+        assert_eq!(diff, TableDiff { added: vec!["db1".to_string()], changed: vec![], unchanged: 1 });
+        assert_eq!(target_table("", "T"), "T");
+        assert_eq!(target_table("drill", "T"), "drill_T");
+        assert!(!is_valid_prefix("drill/../x"));
+    }
+}
@@ -0,0 +1,738 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::utils::sql_literal;
+
+// Lexical kinds produced by the statement scanner
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TokenKind {
+    Word,
+    QuotedIdentifier,
+    StringLiteral,
+    Number,
+    Parameter,
+    Symbol,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub text: String,
+    // Byte offset of the token in the scanned text
+    pub offset: usize,
+    // Parenthesis nesting depth at which the token appears
+    pub depth: usize,
+}
+
+impl Token {
+    // Case-insensitive keyword comparison, only meaningful for bare words
+    pub fn is_keyword(&self, keyword: &str) -> bool {
+        self.kind == TokenKind::Word && self.text.eq_ignore_ascii_case(keyword)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StatementKind {
+    Query,
+    Execute,
+    TransactionControl,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Statement {
+    pub index: usize,
+    pub text: String,
+    pub offset: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScanError {
+    pub message: String,
+    pub offset: usize,
+}
+
+impl std::fmt::Display for ScanError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} at offset {}", self.message, self.offset)
+    }
+}
+
+impl std::error::Error for ScanError {}
+
+fn is_ident_start(b: u8) -> bool {
+    b.is_ascii_alphabetic() || b == b'_' || b >= 0x80
+}
+
+fn is_ident_char(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b == b'_' || b == b'$' || b >= 0x80
+}
+
+// Returns the dollar-quote tag (e.g. "$body$") starting at `start`, if any.
+fn dollar_tag(bytes: &[u8], start: usize) -> Option<usize> {
+    let mut i = start + 1;
+    if i < bytes.len() && bytes[i] == b'$' {
+        return Some(i + 1);
+    }
+    if i >= bytes.len() || !(bytes[i].is_ascii_alphabetic() || bytes[i] == b'_') {
+        return None;
+    }
+    while i < bytes.len() && (bytes[i].is_ascii_alphanumeric() || bytes[i] == b'_') {
+        i += 1;
+    }
+    if i < bytes.len() && bytes[i] == b'$' {
+        Some(i + 1)
+    } else {
+        None
+    }
+}
+
+// Splits SQL text into tokens, skipping whitespace and comments.
+// Quoted strings, quoted identifiers and dollar-quoted bodies are kept as single tokens.
+pub fn tokenize(sql: &str) -> Result<Vec<Token>, ScanError> {
+    let bytes = sql.as_bytes();
+    let mut tokens = Vec::new();
+    let mut depth: usize = 0;
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let b = bytes[i];
+        let start = i;
+
+        if b.is_ascii_whitespace() {
+            i += 1;
+            continue;
+        }
+
+        // Line comment
+        if b == b'-' && bytes.get(i + 1) == Some(&b'-') {
+            while i < bytes.len() && bytes[i] != b'\n' {
+                i += 1;
+            }
+            continue;
+        }
+
+        // Block comment, which nests in PostgreSQL
+        if b == b'/' && bytes.get(i + 1) == Some(&b'*') {
+            let mut nesting = 1;
+            i += 2;
+            while i < bytes.len() && nesting > 0 {
+                if bytes[i] == b'/' && bytes.get(i + 1) == Some(&b'*') {
+                    nesting += 1;
+                    i += 2;
+                } else if bytes[i] == b'*' && bytes.get(i + 1) == Some(&b'/') {
+                    nesting -= 1;
+                    i += 2;
+                } else {
+                    i += 1;
+                }
+            }
+            if nesting > 0 {
+                return Err(ScanError { message: "Unterminated block comment".to_string(), offset: start });
+            }
+            continue;
+        }
+
+        // String literal, including E'' strings with backslash escapes
+        let escape_string = (b == b'E' || b == b'e') && bytes.get(i + 1) == Some(&b'\'');
+        if b == b'\'' || escape_string {
+            i += if escape_string { 2 } else { 1 };
+            let mut closed = false;
+            while i < bytes.len() {
+                if escape_string && bytes[i] == b'\\' {
+                    i += 2;
+                    continue;
+                }
+                if bytes[i] == b'\'' {
+                    if bytes.get(i + 1) == Some(&b'\'') {
+                        i += 2;
+                        continue;
+                    }
+                    i += 1;
+                    closed = true;
+                    break;
+                }
+                i += 1;
+            }
+            if !closed {
+                return Err(ScanError { message: "Unterminated string literal".to_string(), offset: start });
+            }
+            tokens.push(Token { kind: TokenKind::StringLiteral, text: sql[start..i].to_string(), offset: start, depth });
+            continue;
+        }
+
+        // Quoted identifier
+        if b == b'"' {
+            i += 1;
+            let mut closed = false;
+            while i < bytes.len() {
+                if bytes[i] == b'"' {
+                    if bytes.get(i + 1) == Some(&b'"') {
+                        i += 2;
+                        continue;
+                    }
+                    i += 1;
+                    closed = true;
+                    break;
+                }
+                i += 1;
+            }
+            if !closed {
+                return Err(ScanError { message: "Unterminated quoted identifier".to_string(), offset: start });
+            }
+            tokens.push(Token { kind: TokenKind::QuotedIdentifier, text: sql[start..i].to_string(), offset: start, depth });
+            continue;
+        }
+
+        if b == b'$' {
+            // Positional parameter
+            if bytes.get(i + 1).is_some_and(|c| c.is_ascii_digit()) {
+                i += 1;
+                while i < bytes.len() && bytes[i].is_ascii_digit() {
+                    i += 1;
+                }
+                tokens.push(Token { kind: TokenKind::Parameter, text: sql[start..i].to_string(), offset: start, depth });
+                continue;
+            }
+            // Dollar-quoted body
+            if let Some(tag_end) = dollar_tag(bytes, i) {
+                let tag = &sql[start..tag_end];
+                match sql[tag_end..].find(tag) {
+                    Some(pos) => {
+                        i = tag_end + pos + tag.len();
+                        tokens.push(Token { kind: TokenKind::StringLiteral, text: sql[start..i].to_string(), offset: start, depth });
+                        continue;
+                    }
+                    None => {
+                        return Err(ScanError { message: "Unterminated dollar-quoted string".to_string(), offset: start });
+                    }
+                }
+            }
+        }
+
+        if is_ident_start(b) {
+            while i < bytes.len() && is_ident_char(bytes[i]) {
+                i += 1;
+            }
+            tokens.push(Token { kind: TokenKind::Word, text: sql[start..i].to_string(), offset: start, depth });
+            continue;
+        }
+
+        if b.is_ascii_digit() || (b == b'.' && bytes.get(i + 1).is_some_and(|c| c.is_ascii_digit())) {
+            while i < bytes.len() && (bytes[i].is_ascii_alphanumeric() || bytes[i] == b'.' || bytes[i] == b'_') {
+                i += 1;
+            }
+            tokens.push(Token { kind: TokenKind::Number, text: sql[start..i].to_string(), offset: start, depth });
+            continue;
+        }
+
+        // Any other character is a single-character symbol
+        let len = sql[i..].chars().next().map(|c| c.len_utf8()).unwrap_or(1);
+        i += len;
+        if b == b')' {
+            depth = depth.saturating_sub(1);
+        }
+        tokens.push(Token { kind: TokenKind::Symbol, text: sql[start..i].to_string(), offset: start, depth });
+        if b == b'(' {
+            depth += 1;
+        }
+    }
+
+    Ok(tokens)
+}
+
+// Returns the 1-based line and column of a byte offset.
+pub fn line_col(sql: &str, offset: usize) -> (usize, usize) {
+    let prefix = &sql[..offset.min(sql.len())];
+    let line = prefix.matches('\n').count() + 1;
+    let column = match prefix.rfind('\n') {
+        Some(pos) => prefix[pos + 1..].chars().count() + 1,
+        None => prefix.chars().count() + 1,
+    };
+    (line, column)
+}
+
+// Splits a script into its statements on top-level semicolons.
+// Empty statements (only whitespace or comments) are dropped.
+pub fn split_statements(script: &str) -> Result<Vec<Statement>, ScanError> {
+    let tokens = tokenize(script)?;
+    let mut statements = Vec::new();
+    let mut first: Option<usize> = None;
+    let mut last_end: usize = 0;
+
+    let push = |first: Option<usize>, end: usize, statements: &mut Vec<Statement>| {
+        if let Some(start) = first {
+            let (line, column) = line_col(script, start);
+            statements.push(Statement {
+                index: statements.len(),
+                text: script[start..end].trim_end().to_string(),
+                offset: start,
+                line,
+                column,
+            });
+        }
+    };
+
+    for token in tokens.iter() {
+        if token.kind == TokenKind::Symbol && token.text == ";" {
+            push(first, last_end, &mut statements);
+            first = None;
+            continue;
+        }
+        if first.is_none() {
+            first = Some(token.offset);
+        }
+        last_end = token.offset + token.text.len();
+    }
+    push(first, last_end, &mut statements);
+
+    Ok(statements)
+}
+
+// Main verb of a statement; for WITH statements this is the verb following the CTE list.
+pub fn leading_keyword(sql: &str) -> Option<String> {
+    let tokens = tokenize(sql).ok()?;
+    let first = tokens.iter().find(|t| !(t.kind == TokenKind::Symbol && t.text == "("))?;
+    if first.kind != TokenKind::Word {
+        return None;
+    }
+    if first.is_keyword("WITH") {
+        let verb = tokens.iter().skip(1).find(|t| {
+            t.depth == 0 && ["SELECT", "INSERT", "UPDATE", "DELETE", "MERGE", "VALUES", "TABLE"].iter().any(|k| t.is_keyword(k))
+        });
+        return Some(verb.map(|t| t.text.to_uppercase()).unwrap_or_else(|| "WITH".to_string()));
+    }
+    Some(first.text.to_uppercase())
+}
+
+// Trailing semicolons are dropped and the statement goes on its own lines so that a trailing comment
+// cannot swallow the closing parenthesis
+fn wrap_rows(sql: &str, limit: u64, offset: u64) -> String {
+    let inner = sql.trim().trim_end_matches(|c: char| c == ';' || c.is_whitespace());
+    let mut wrapped = format!("SELECT * FROM (\n{}\n) _sub LIMIT {}", inner, limit);
+    if offset > 0 {
+        wrapped.push_str(&format!(" OFFSET {}", offset));
+    }
+    wrapped
+}
+
+// Wraps a SELECT whose outermost query has no LIMIT/FETCH so that at most limit + 1 rows come back, the
+// extra row telling the caller that rows were cut. Subqueries and CTE bodies sit below depth 0 and do not
+// count. None when the statement is kept as is.
+pub fn with_default_limit(sql: &str, limit: u64) -> Option<String> {
+    if !matches!(leading_keyword(sql).as_deref(), Some("SELECT") | Some("VALUES") | Some("TABLE")) {
+        return None;
+    }
+    let tokens = tokenize(sql).ok()?;
+    let outermost = |keyword: &str| tokens.iter().any(|t| t.depth == 0 && t.is_keyword(keyword));
+    // SELECT ... INTO creates a table, and data-modifying CTEs must not be re-run for every page
+    if outermost("LIMIT") || outermost("FETCH") || outermost("INTO")
+        || tokens.iter().any(|t| ["INSERT", "UPDATE", "DELETE", "MERGE"].iter().any(|k| t.is_keyword(k))) {
+        return None;
+    }
+    Some(wrap_rows(sql, limit.saturating_add(1), 0))
+}
+
+// Query returning the rows following the first limit ones, suggested to the caller of a cut query
+pub fn next_page(sql: &str, limit: u64) -> String {
+    wrap_rows(sql, limit, limit)
+}
+
+// Page of a SELECT, pages counted from 0. One row more than page_size is asked for so that the caller can
+// tell whether another page follows. A LIMIT of the statement itself stays inside the subquery.
+pub fn paginate(sql: &str, page: u64, page_size: u64) -> Result<String, String> {
+    let verb = leading_keyword(sql).unwrap_or_default();
+    if !matches!(verb.as_str(), "SELECT" | "VALUES" | "TABLE") {
+        return Err(format!("Invalid input: only SELECT statements can be paginated, not {}", if verb.is_empty() { "this statement" } else { &verb }));
+    }
+    if let Some(verb) = write_verb(sql) {
+        return Err(format!("Invalid input: a statement writing data ({}) cannot be paginated, each page would run it again", verb));
+    }
+    if page_size == 0 {
+        return Err("Invalid input: page_size must be greater than 0".to_string());
+    }
+    let offset = page.checked_mul(page_size).ok_or("Invalid input: page is too large for the page_size")?;
+    Ok(wrap_rows(sql, page_size + 1, offset))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WriteKind {
+    Update,
+    Delete,
+}
+
+// Table changed by a single-table UPDATE or DELETE, and the alias and WHERE clause selecting its rows:
+// "SELECT ... FROM <table> <filter>" reads the rows the statement is about to change.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WriteTarget {
+    pub kind: WriteKind,
+    pub table: String,
+    pub filter: String,
+}
+
+// None for anything else, including WITH statements, UPDATE ... FROM, DELETE ... USING, WHERE CURRENT OF
+// and schema-qualified targets, whose rows cannot be selected from the statement text alone
+pub fn write_target(sql: &str) -> Option<WriteTarget> {
+    let sql = sql.trim().trim_end_matches(|c: char| c == ';' || c.is_whitespace());
+    let tokens = tokenize(sql).ok()?;
+    let (kind, mut i) = match tokens.first() {
+        Some(t) if t.is_keyword("UPDATE") => (WriteKind::Update, 1),
+        Some(t) if t.is_keyword("DELETE") && tokens.get(1).map(|f| f.is_keyword("FROM")).unwrap_or(false) => (WriteKind::Delete, 2),
+        _ => return None,
+    };
+    if tokens.get(i)?.is_keyword("ONLY") {
+        i += 1;
+    }
+    let table = match tokens.get(i) {
+        Some(t) if t.kind == TokenKind::Word => t.text.clone(),
+        Some(t) if t.kind == TokenKind::QuotedIdentifier => t.text[1..t.text.len() - 1].replace("\"\"", "\""),
+        _ => return None,
+    };
+    if tokens.get(i + 1).map(|t| t.kind == TokenKind::Symbol && t.text == ".").unwrap_or(false) {
+        return None;
+    }
+
+    let at = |keyword: &str| tokens.iter().skip(i + 1).position(|t| t.depth == 0 && t.is_keyword(keyword)).map(|p| p + i + 1);
+    let offset = |index: Option<usize>| index.map(|j| tokens[j].offset).unwrap_or(sql.len());
+    let (set, where_clause, returning) = (at("SET"), at("WHERE"), at("RETURNING"));
+    if at("USING").is_some() || (kind == WriteKind::Update && (set.is_none() || at("FROM").is_some())) {
+        return None;
+    }
+    if where_clause.map(|w| tokens.get(w + 1).map(|t| t.is_keyword("CURRENT")).unwrap_or(false)).unwrap_or(false) {
+        return None;
+    }
+    let alias_end = match kind {
+        WriteKind::Update => set,
+        WriteKind::Delete => where_clause.or(returning),
+    };
+    let alias = tokens.get(i + 1).map(|t| &sql[t.offset.min(offset(alias_end))..offset(alias_end)]).unwrap_or("");
+    let condition = where_clause.map(|w| &sql[tokens[w].offset..offset(returning)]).unwrap_or("");
+    let filter = format!("{} {}", alias.trim(), condition.trim()).trim().to_string();
+    Some(WriteTarget { kind, table, filter })
+}
+
+pub fn classify(sql: &str) -> StatementKind {
+    match leading_keyword(sql).as_deref() {
+        Some("SELECT") | Some("SHOW") | Some("EXPLAIN") | Some("VALUES") | Some("TABLE") => StatementKind::Query,
+        Some("BEGIN") | Some("START") | Some("COMMIT") | Some("END") | Some("ROLLBACK") | Some("ABORT")
+        | Some("SAVEPOINT") | Some("RELEASE") => StatementKind::TransactionControl,
+        _ => StatementKind::Execute,
+    }
+}
+
+// Checked before anything goes through the query path: every statement must read only. Data-modifying
+// CTEs, SELECT ... INTO and EXPLAIN ANALYZE of a write all change the database despite their verb;
+// FOR UPDATE row locks do not. Functions with side effects cannot be told apart from the text.
+pub fn check_read_only(sql: &str) -> Result<(), String> {
+    let statements = split_statements(sql).map_err(|e| e.to_string())?;
+    if statements.is_empty() {
+        return Err("The query holds no statement".to_string());
+    }
+    for stmt in statements.iter() {
+        if let Some(verb) = write_verb(&stmt.text) {
+            return Err(format!("Statement {} is not read-only ({}), writes go through sql_script", stmt.index + 1, verb));
+        }
+    }
+    Ok(())
+}
+
+// Verb that makes a statement write, None for a read-only one
+fn write_verb(sql: &str) -> Option<String> {
+    let verb = leading_keyword(sql).unwrap_or_default();
+    if !matches!(verb.as_str(), "SELECT" | "SHOW" | "EXPLAIN" | "VALUES" | "TABLE") {
+        return Some(if verb.is_empty() { "unknown".to_string() } else { verb });
+    }
+    let tokens = match tokenize(sql) {
+        Ok(tokens) => tokens,
+        Err(_) => return Some("unknown".to_string()),
+    };
+    if verb == "EXPLAIN" {
+        // Only EXPLAIN ANALYZE runs the explained statement
+        let explained = tokens.iter().skip(1).find(|t| t.depth == 0 && t.kind == TokenKind::Word
+            && !["ANALYZE", "ANALYSE", "VERBOSE"].iter().any(|k| t.is_keyword(k)))?;
+        let analyze = tokens.iter().take_while(|t| t.offset < explained.offset).any(|t| t.is_keyword("ANALYZE") || t.is_keyword("ANALYSE"));
+        return if analyze { write_verb(&sql[explained.offset..]) } else { None };
+    }
+    if tokens.iter().any(|t| t.depth == 0 && t.is_keyword("INTO")) {
+        return Some("SELECT INTO".to_string());
+    }
+    tokens.iter().enumerate().find(|(i, t)| {
+        ["INSERT", "DELETE", "MERGE", "TRUNCATE"].iter().any(|k| t.is_keyword(k))
+            || (t.is_keyword("UPDATE") && !(*i > 0 && (tokens[i - 1].is_keyword("FOR") || tokens[i - 1].is_keyword("KEY"))))
+    }).map(|(_, t)| t.text.to_uppercase())
+}
+
+// Statements changing the schema, to which the deployment notice policy applies
+pub fn is_schema_change(sql: &str) -> bool {
+    matches!(leading_keyword(sql).as_deref(), Some("CREATE") | Some("ALTER") | Some("DROP") | Some("COMMENT"))
+}
+
+// Data-modifying statement handing rows back, which then has to be read through the query path
+pub fn has_returning(sql: &str) -> bool {
+    classify(sql) == StatementKind::Execute
+        && tokenize(sql).map(|tokens| tokens.iter().any(|t| t.depth == 0 && t.is_keyword("RETURNING"))).unwrap_or(false)
+}
+
+// Transaction control and session settings, which change no data
+pub fn is_session_control(sql: &str) -> bool {
+    matches!(leading_keyword(sql).as_deref(), Some("BEGIN" | "START" | "COMMIT" | "END" | "ROLLBACK" | "ABORT" | "SAVEPOINT" | "RELEASE" | "SET" | "RESET"))
+}
+
+// Why PostgreSQL refuses to run a statement inside a transaction block, for the statements it does
+pub fn transaction_block_restriction(sql: &str) -> Option<String> {
+    let tokens = tokenize(sql).ok()?;
+    let words: Vec<String> = tokens.iter().filter(|t| t.kind == TokenKind::Word).take(3).map(|t| t.text.to_uppercase()).collect();
+    let second = words.get(1).map(String::as_str).unwrap_or("");
+    let has = |keyword: &str| tokens.iter().any(|t| t.depth == 0 && t.is_keyword(keyword));
+    let verb = words.first()?.as_str();
+    let reason = match verb {
+        "VACUUM" => "VACUUM",
+        "CREATE" | "DROP" | "REINDEX" if has("CONCURRENTLY") => return Some(format!("{} ... CONCURRENTLY cannot run inside a transaction block", verb)),
+        "CREATE" | "DROP" if matches!(second, "DATABASE" | "TABLESPACE") => return Some(format!("{} {} cannot run inside a transaction block", verb, second)),
+        "ALTER" if second == "SYSTEM" => "ALTER SYSTEM",
+        "ALTER" if second == "DATABASE" && has("TABLESPACE") => "ALTER DATABASE ... SET TABLESPACE",
+        "ALTER" if second == "TYPE" && has("ADD") && has("VALUE") => {
+            return Some("ALTER TYPE ... ADD VALUE cannot run inside a transaction block before PostgreSQL 12, and the new value cannot be used before the transaction commits".to_string());
+        }
+        "CLUSTER" if words.len() == 1 => "CLUSTER without a table",
+        "DISCARD" if second == "ALL" => "DISCARD ALL",
+        _ => return None,
+    };
+    Some(format!("{} cannot run inside a transaction block", reason))
+}
+
+// Literal a parameter is bound as. A string holding a backslash goes in an E'' string so that it reads
+// the same whatever standard_conforming_strings is set to; arrays and objects are bound as jsonb.
+pub fn parameter_literal(value: &Value) -> Result<String, String> {
+    match value {
+        Value::String(s) if s.contains('\0') => Err("holds a NUL character, which PostgreSQL text cannot store".to_string()),
+        Value::String(s) if s.contains('\\') => Ok(format!("E'{}'", s.replace('\\', "\\\\").replace('\'', "''"))),
+        // Parenthesized so that the sign never runs into an operator before it, e.g. "a-$1" as "a--1"
+        Value::Number(n) if n.to_string().starts_with('-') => Ok(format!("({})", n)),
+        Value::Array(_) | Value::Object(_) => Ok(format!("{}::jsonb", parameter_literal(&Value::String(value.to_string()))?)),
+        other => Ok(sql_literal(other)),
+    }
+}
+
+// Substitutes the $1, $2, ... placeholders of a statement with the literals of the given values.
+// Placeholders inside string literals, quoted identifiers and comments are left alone.
+pub fn bind_parameters(sql: &str, params: &[Value]) -> Result<String, String> {
+    let tokens = tokenize(sql).map_err(|e| format!("Invalid input: {}", e))?;
+    let mut bound = String::with_capacity(sql.len());
+    let mut copied = 0;
+    let mut referenced = vec![false; params.len()];
+    for token in tokens.iter().filter(|t| t.kind == TokenKind::Parameter) {
+        let index = token.text[1..].parse::<usize>().ok().filter(|i| *i >= 1 && *i <= params.len())
+            .ok_or(format!("Invalid input: parameter {} has no value, {} given", token.text, params.len()))?;
+        let literal = parameter_literal(&params[index - 1]).map_err(|e| format!("Invalid input: parameter {} {}", token.text, e))?;
+        bound.push_str(&sql[copied..token.offset]);
+        bound.push_str(&literal);
+        copied = token.offset + token.text.len();
+        referenced[index - 1] = true;
+    }
+    bound.push_str(&sql[copied..]);
+    match referenced.iter().position(|r| !r) {
+        Some(unused) => Err(format!("Invalid input: parameter ${} is given but not referenced", unused + 1)),
+        None => Ok(bound),
+    }
+}
+
+// Statement fit for a transcript: string literals longer than max characters are replaced, the rest is kept.
+// A statement that does not scan is not shown at all.
+pub fn redact_literals(sql: &str, max: usize) -> String {
+    let tokens = match tokenize(sql) {
+        Ok(tokens) => tokens,
+        Err(e) => return format!("<statement not shown: {}>", e),
+    };
+    let mut redacted = String::with_capacity(sql.len());
+    let mut copied = 0;
+    for token in tokens.iter().filter(|t| t.kind == TokenKind::StringLiteral && t.text.chars().count() > max) {
+        redacted.push_str(&sql[copied..token.offset]);
+        redacted.push_str(&format!("'<redacted {} chars>'", token.text.chars().count()));
+        copied = token.offset + token.text.len();
+    }
+    redacted.push_str(&sql[copied..]);
+    redacted
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_redact_literals() {
+        let sql = "SELECT * FROM users WHERE email = 'ada@example.com' AND token = $$0123456789abcdef0123$$ AND id = 'it''s'";
+        assert_eq!(redact_literals(sql, 16),
+            "SELECT * FROM users WHERE email = '<redacted 17 chars>' AND token = '<redacted 24 chars>' AND id = 'it''s'");
+        assert_eq!(redact_literals("UPDATE t SET a = E'\\x41' -- 'not a literal'", 4), "UPDATE t SET a = '<redacted 7 chars>' -- 'not a literal'");
+        assert_eq!(redact_literals("SELECT 'unterminated", 16), "<statement not shown: Unterminated string literal at offset 7>");
+    }
+
+    #[test]
+    fn test_split_respects_quotes_and_comments() {
+        let script = "SELECT 'a;b' AS x; -- trailing; comment\nINSERT INTO t VALUES ($$semi;colon$$);\n/* block; */ UPDATE \"odd;name\" SET a = 1";
+        let statements = split_statements(script).unwrap();
+        assert_eq!(statements.len(), 3);
+        assert_eq!(statements[0].text, "SELECT 'a;b' AS x");
+        assert_eq!(statements[1].text, "INSERT INTO t VALUES ($$semi;colon$$)");
+        assert_eq!(statements[1].line, 2);
+        assert_eq!(statements[2].text, "UPDATE \"odd;name\" SET a = 1");
+        assert_eq!(statements[2].line, 3);
+        assert_eq!(statements[2].column, 14);
+    }
+
+    #[test]
+    fn test_split_skips_empty_statements() {
+        let statements = split_statements(";; -- nothing\n;SELECT 1;").unwrap();
+        assert_eq!(statements.len(), 1);
+        assert_eq!(statements[0].index, 0);
+    }
+
+    #[test]
+    fn test_unterminated_literal_is_reported() {
+        let err = split_statements("SELECT 1; SELECT 'oops").unwrap_err();
+        assert_eq!(err.offset, 17);
+    }
+
+    #[test]
+    fn test_check_read_only() {
+        assert!(check_read_only("  -- comment\nselect 1; SHOW server_version").is_ok());
+        assert!(check_read_only("WITH x AS (SELECT 1) SELECT * FROM x").is_ok());
+        assert!(check_read_only("EXPLAIN UPDATE t SET a = 1").is_ok());
+        assert!(check_read_only("SELECT * FROM t FOR NO KEY UPDATE").is_ok());
+        assert!(check_read_only("SELECT 'DELETE' AS \"update\"").is_ok());
+        assert_eq!(check_read_only("  -- comment\nUPDATE t SET a = 1").unwrap_err(), "Statement 1 is not read-only (UPDATE), writes go through sql_script");
+        assert_eq!(check_read_only("SELECT 1; /* x */ DROP TABLE t").unwrap_err(), "Statement 2 is not read-only (DROP), writes go through sql_script");
+        assert!(check_read_only("WITH d AS (DELETE FROM t RETURNING *) SELECT * FROM d").unwrap_err().contains("(DELETE)"));
+        assert!(check_read_only("SELECT * INTO backup FROM t").unwrap_err().contains("(SELECT INTO)"));
+        assert!(check_read_only("EXPLAIN (ANALYZE, BUFFERS) INSERT INTO t VALUES (1)").unwrap_err().contains("(INSERT)"));
+        assert!(check_read_only(" ; -- nothing").is_err());
+    }
+
+    #[test]
+    fn test_classify() {
+        assert_eq!(classify("  -- comment\nselect 1"), StatementKind::Query);
+        assert_eq!(classify("WITH x AS (SELECT 1) SELECT * FROM x"), StatementKind::Query);
+        assert_eq!(classify("WITH x AS (SELECT 1) DELETE FROM t USING x"), StatementKind::Execute);
+        assert_eq!(classify("(SELECT 1) UNION (SELECT 2)"), StatementKind::Query);
+        assert_eq!(classify("begin"), StatementKind::TransactionControl);
+        assert_eq!(classify("START TRANSACTION"), StatementKind::TransactionControl);
+        assert_eq!(classify("UPDATE t SET a = 1"), StatementKind::Execute);
+        assert!(is_schema_change("create index if not exists users_email_idx ON users (email)"));
+        assert!(is_schema_change("DROP TABLE IF EXISTS t"));
+        assert!(!is_schema_change("INSERT INTO t VALUES (1)"));
+    }
+
+    #[test]
+    fn test_transaction_block_restriction() {
+        assert_eq!(transaction_block_restriction("vacuum analyze users").unwrap(), "VACUUM cannot run inside a transaction block");
+        assert!(transaction_block_restriction("CREATE UNIQUE INDEX CONCURRENTLY i ON t (a)").unwrap().starts_with("CREATE ... CONCURRENTLY"));
+        assert!(transaction_block_restriction("DROP DATABASE reports").is_some());
+        assert!(transaction_block_restriction("ALTER TYPE mood ADD VALUE 'meh'").unwrap().contains("PostgreSQL 12"));
+        assert!(transaction_block_restriction("CLUSTER").is_some());
+        assert!(transaction_block_restriction("CLUSTER users USING users_pkey").is_none());
+        assert!(transaction_block_restriction("CREATE INDEX i ON t (a)").is_none());
+        assert!(transaction_block_restriction("REFRESH MATERIALIZED VIEW CONCURRENTLY v").is_none());
+        assert!(transaction_block_restriction("UPDATE t SET note = 'vacuum concurrently'").is_none());
+        assert!(has_returning("DELETE FROM t WHERE id = 1 RETURNING id"));
+        assert!(!has_returning("WITH d AS (DELETE FROM t RETURNING *) SELECT * FROM d"));
+        assert!(!has_returning("UPDATE t SET a = 'returning'"));
+    }
+
+    #[test]
+    fn test_tokenize_depth_and_escape_strings() {
+        let tokens = tokenize("SELECT (a) FROM t WHERE b = E'it\\'s'").unwrap();
+        let a = tokens.iter().find(|t| t.text == "a").unwrap();
+        assert_eq!(a.depth, 1);
+        assert_eq!(tokens.last().unwrap().kind, TokenKind::StringLiteral);
+    }
+
+    #[test]
+    fn test_default_limit_wraps_unbounded_selects() {
+        assert_eq!(with_default_limit("SELECT id, name FROM users ORDER BY id;", 1000).unwrap(),
+            "SELECT * FROM (\nSELECT id, name FROM users ORDER BY id\n) _sub LIMIT 1001");
+        // Only the outermost query counts
+        assert!(with_default_limit("SELECT * FROM (SELECT * FROM users LIMIT 5) u", 10).is_some());
+        assert!(with_default_limit("WITH recent AS (SELECT * FROM orders LIMIT 10) SELECT * FROM recent", 10).is_some());
+        assert!(with_default_limit("SELECT a FROM t UNION SELECT a FROM u", 10).is_some());
+        // A trailing comment stays on its own line
+        assert!(with_default_limit("SELECT 1 -- one", 10).unwrap().contains("-- one\n)"));
+    }
+
+    #[test]
+    fn test_paginate() {
+        assert_eq!(paginate("SELECT id FROM users ORDER BY id;", 0, 10).unwrap(), "SELECT * FROM (\nSELECT id FROM users ORDER BY id\n) _sub LIMIT 11");
+        // The limit of the statement applies before the page is taken
+        assert_eq!(paginate("SELECT id FROM users ORDER BY id LIMIT 25", 2, 10).unwrap(),
+            "SELECT * FROM (\nSELECT id FROM users ORDER BY id LIMIT 25\n) _sub LIMIT 11 OFFSET 20");
+        assert!(paginate("WITH recent AS (SELECT * FROM orders) SELECT * FROM recent", 1, 5).unwrap().ends_with("LIMIT 6 OFFSET 5"));
+
+        assert_eq!(paginate("UPDATE users SET a = 1", 0, 10).unwrap_err(), "Invalid input: only SELECT statements can be paginated, not UPDATE");
+        assert!(paginate("SELECT * INTO backup FROM users", 0, 10).unwrap_err().contains("SELECT INTO"));
+        assert!(paginate("WITH d AS (DELETE FROM t RETURNING *) SELECT * FROM d", 0, 10).is_err());
+        assert!(paginate("SELECT 1", 0, 0).unwrap_err().starts_with("Invalid input"));
+        assert!(paginate("SELECT 1", u64::MAX, 2).unwrap_err().starts_with("Invalid input"));
+    }
+
+    #[test]
+    fn test_default_limit_keeps_bounded_and_other_statements() {
+        assert!(with_default_limit("SELECT * FROM users LIMIT 5", 10).is_none());
+        assert!(with_default_limit("SELECT a FROM t UNION SELECT a FROM u LIMIT 3", 10).is_none());
+        assert!(with_default_limit("WITH x AS (SELECT 1) SELECT * FROM x FETCH FIRST 2 ROWS ONLY", 10).is_none());
+        assert!(with_default_limit("SELECT * INTO backup FROM users", 10).is_none());
+        assert!(with_default_limit("WITH d AS (DELETE FROM t RETURNING *) SELECT * FROM d", 10).is_none());
+        assert!(with_default_limit("EXPLAIN SELECT * FROM users", 10).is_none());
+        assert!(with_default_limit("SELECT 'LIMIT' FROM users", 10).is_some());
+        assert_eq!(next_page("SELECT 1", 10), "SELECT * FROM (\nSELECT 1\n) _sub LIMIT 10 OFFSET 10");
+    }
+
+
+    #[test]
+    fn test_write_target() {
+        let target = write_target("UPDATE users AS u SET name = 'x;y' WHERE u.id IN (SELECT id FROM banned) RETURNING id;").unwrap();
+        assert_eq!((target.kind, target.table.as_str()), (WriteKind::Update, "users"));
+        assert_eq!(target.filter, "AS u WHERE u.id IN (SELECT id FROM banned)");
+        let target = write_target("delete from only \"Users\" where id = 3").unwrap();
+        assert_eq!((target.kind, target.table.as_str(), target.filter.as_str()), (WriteKind::Delete, "Users", "where id = 3"));
+        assert_eq!(write_target("DELETE FROM logs").unwrap().filter, "");
+        assert!(write_target("UPDATE a SET x = b.x FROM b WHERE a.id = b.id").is_none());
+        assert!(write_target("DELETE FROM a USING b WHERE a.id = b.id").is_none());
+        assert!(write_target("DELETE FROM a WHERE CURRENT OF c").is_none());
+        assert!(write_target("UPDATE public.a SET x = 1").is_none());
+        assert!(write_target("WITH d AS (SELECT 1) DELETE FROM a").is_none());
+        assert!(write_target("INSERT INTO a VALUES (1)").is_none());
+    }
+
+    #[test]
+    fn test_bind_parameters_escapes_values() {
+        let params = vec![json!("O'Brien"), json!(42), json!(-1.5), json!(true), Value::Null];
+        assert_eq!(bind_parameters("SELECT * FROM t WHERE name = $1 AND age > $2 AND score-$3 > 0 AND active = $4 AND note IS NOT DISTINCT FROM $5", &params).unwrap(),
+            "SELECT * FROM t WHERE name = 'O''Brien' AND age > 42 AND score-(-1.5) > 0 AND active = true AND note IS NOT DISTINCT FROM NULL");
+        assert_eq!(bind_parameters("SELECT $1", &[json!(r"C:\temp\'x'")]).unwrap(), r"SELECT E'C:\\temp\\''x'''");
+        assert_eq!(bind_parameters("SELECT $1", &[json!("naïve 日本 🎉")]).unwrap(), "SELECT 'naïve 日本 🎉'");
+        assert_eq!(bind_parameters("SELECT $1", &[json!({"a": "it's"})]).unwrap(), r#"SELECT '{"a":"it''s"}'::jsonb"#);
+        // The bound statement scans as a single literal, the value cannot end it early
+        for value in ["'; DROP TABLE t; --", r"\'; DROP TABLE t; --"] {
+            let bound = bind_parameters("SELECT $1", &[json!(value)]).unwrap();
+            assert_eq!(split_statements(&bound).unwrap().len(), 1);
+            assert_eq!(tokenize(&bound).unwrap()[1].kind, TokenKind::StringLiteral);
+        }
+    }
+
+    #[test]
+    fn test_bind_parameters_placeholders() {
+        // Placeholders in literals and comments are not parameters
+        assert_eq!(bind_parameters("SELECT '$1', $1 -- $2", &[json!(1)]).unwrap(), "SELECT '$1', 1 -- $2");
+        assert_eq!(bind_parameters("SELECT $1, $1", &[json!("a")]).unwrap(), "SELECT 'a', 'a'");
+        assert_eq!(bind_parameters("SELECT 1", &[]).unwrap(), "SELECT 1");
+        assert_eq!(bind_parameters("SELECT $2", &[json!(1)]).unwrap_err(), "Invalid input: parameter $2 has no value, 1 given");
+        assert_eq!(bind_parameters("SELECT $0", &[json!(1)]).unwrap_err(), "Invalid input: parameter $0 has no value, 1 given");
+        assert_eq!(bind_parameters("SELECT $1", &[json!(1), json!(2)]).unwrap_err(), "Invalid input: parameter $2 is given but not referenced");
+        assert!(bind_parameters("SELECT $1", &[json!("a\0b")]).unwrap_err().contains("NUL"));
+    }
+}
@@ -0,0 +1,326 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{
+    audit::{self, AUDIT_LOG_TABLE},
+    backup::ROW_BACKUP_TABLE,
+    bootstrap::BOOTSTRAP_TABLE,
+    capabilities::HOST_CAPABILITIES_TABLE,
+    confirm::CONFIRMATION_TABLE,
+    consistency::CONSISTENCY_TABLE,
+    credentials::CREDENTIAL_HEALTH_TABLE,
+    database::{is_client_list_key, DatabaseError, CLIENT_POLICY_TABLE, CONNECTION_HANDLE_TABLE, DATABASE_CLIENT_TABLE},
+    history::AUDIT_AGGREGATE_TABLE,
+    intents::INTENT_LOG_TABLE,
+    locks::JOB_LOCK_TABLE,
+    manifest::ENCRYPTION_MANIFEST_TABLE,
+    notify,
+    routing::ROUTE_USAGE_TABLE,
+    settings::{require_admin, DeploymentSettings, DEPLOYMENT_SETTINGS_TABLE},
+    utils::{get_trusted_time, ledger_key_components},
+    watch::QUERY_WATCH_TABLE,
+};
+
+pub(crate) const STORAGE_USAGE_TABLE: &str = "StorageUsageTable";
+const STORAGE_USAGE_KEY: &str = "usage";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StorageCategory {
+    Clients,
+    Metadata,
+    Audit,
+    Cache,
+    Backups,
+    Jobs,
+}
+
+// Ledger tables written by the crate and what their records count as. Tables restored under a snapshot
+// prefix are not counted.
+const TRACKED_TABLES: [(&str, StorageCategory); 17] = [
+    (DATABASE_CLIENT_TABLE, StorageCategory::Clients),
+    (CLIENT_POLICY_TABLE, StorageCategory::Clients),
+    (CREDENTIAL_HEALTH_TABLE, StorageCategory::Clients),
+    (ENCRYPTION_MANIFEST_TABLE, StorageCategory::Metadata),
+    (CONSISTENCY_TABLE, StorageCategory::Metadata),
+    (DEPLOYMENT_SETTINGS_TABLE, StorageCategory::Metadata),
+    (BOOTSTRAP_TABLE, StorageCategory::Metadata),
+    (ROUTE_USAGE_TABLE, StorageCategory::Metadata),
+    (QUERY_WATCH_TABLE, StorageCategory::Metadata),
+    (CONFIRMATION_TABLE, StorageCategory::Metadata),
+    (AUDIT_LOG_TABLE, StorageCategory::Audit),
+    (AUDIT_AGGREGATE_TABLE, StorageCategory::Audit),
+    (CONNECTION_HANDLE_TABLE, StorageCategory::Cache),
+    (HOST_CAPABILITIES_TABLE, StorageCategory::Cache),
+    (ROW_BACKUP_TABLE, StorageCategory::Backups),
+    (JOB_LOCK_TABLE, StorageCategory::Jobs),
+    (INTENT_LOG_TABLE, StorageCategory::Jobs),
+];
+
+impl StorageCategory {
+    pub fn of(table: &str) -> Option<StorageCategory> {
+        TRACKED_TABLES.iter().find(|(name, _)| *name == table).map(|(_, category)| *category)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UsageCounter {
+    pub bytes: u64,
+    pub records: u64,
+}
+
+impl UsageCounter {
+    // Sizes of the record before and after a write, None when it did not or no longer exists
+    fn apply(&mut self, old: Option<usize>, new: Option<usize>) {
+        self.bytes = self.bytes.saturating_sub(old.unwrap_or(0) as u64).saturating_add(new.unwrap_or(0) as u64);
+        match (old, new) {
+            (None, Some(_)) => self.records += 1,
+            (Some(_), None) => self.records = self.records.saturating_sub(1),
+            _ => {}
+        }
+    }
+}
+
+// Serialized size of the records the crate keeps in the ledger. Counters move with every write, so records
+// older than the accounting or written around it are only counted once recalculate_storage_usage ran.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StorageUsage {
+    #[serde(default)]
+    pub categories: BTreeMap<StorageCategory, UsageCounter>,
+    // Records attributed to a database; deployment-wide ones only count in the categories
+    #[serde(default)]
+    pub databases: BTreeMap<String, BTreeMap<StorageCategory, UsageCounter>>,
+    #[serde(default)]
+    pub recalculated_at: Option<u64>,
+}
+
+impl StorageUsage {
+    pub fn load() -> StorageUsage {
+        crate::runtime::ledger::get_table(STORAGE_USAGE_TABLE).get(STORAGE_USAGE_KEY).ok()
+            .and_then(|v| serde_json::from_slice::<StorageUsage>(&v).ok())
+            .unwrap_or_default()
+    }
+
+    // Not counted itself, it is rewritten with every other write
+    fn save(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let serialized = serde_json::to_string(self)?;
+        crate::runtime::ledger::get_table(STORAGE_USAGE_TABLE).set(STORAGE_USAGE_KEY, serialized.as_bytes())
+    }
+
+    pub fn total_bytes(&self) -> u64 {
+        self.categories.values().map(|c| c.bytes).sum()
+    }
+
+    fn apply(&mut self, category: StorageCategory, owner: Option<&str>, old: Option<usize>, new: Option<usize>) {
+        self.categories.entry(category).or_default().apply(old, new);
+        if let Some(owner) = owner {
+            let counters = self.databases.entry(owner.to_string()).or_default();
+            counters.entry(category).or_default().apply(old, new);
+            counters.retain(|_, c| c.records > 0 || c.bytes > 0);
+            if counters.is_empty() {
+                self.databases.remove(owner);
+            }
+        }
+    }
+
+    // Usage of a single database, for callers that only asked about it
+    pub fn for_database(&self, database_id: &str) -> StorageUsage {
+        StorageUsage {
+            categories: self.databases.get(database_id).cloned().unwrap_or_default(),
+            databases: BTreeMap::new(),
+            recalculated_at: self.recalculated_at,
+        }
+    }
+}
+
+// Database a record belongs to: the database_id it holds, else the one its key is made of
+fn owner(table: &str, key: &str, record: &[u8]) -> Option<String> {
+    let field = serde_json::from_slice::<Value>(record).ok()
+        .and_then(|v| v.get("database_id").and_then(|d| d.as_str()).map(|d| d.to_string()));
+    if field.is_some() {
+        return field;
+    }
+    let components = ledger_key_components(key);
+    match table {
+        DATABASE_CLIENT_TABLE if is_client_list_key(key) => None,
+        DATABASE_CLIENT_TABLE | CLIENT_POLICY_TABLE | CREDENTIAL_HEALTH_TABLE | CONNECTION_HANDLE_TABLE
+        | HOST_CAPABILITIES_TABLE | ENCRYPTION_MANIFEST_TABLE => Some(key.to_string()),
+        CONSISTENCY_TABLE | QUERY_WATCH_TABLE | INTENT_LOG_TABLE | JOB_LOCK_TABLE if components.len() > 1 => components.into_iter().next(),
+        ROW_BACKUP_TABLE if components.len() == 2 && components[0] == "index" => components.into_iter().nth(1),
+        _ => None,
+    }
+}
+
+// Best effort, a write that went through is never failed by its accounting
+fn account(table: &str, key: &str, old: Option<&[u8]>, new: Option<&[u8]>) {
+    let category = match StorageCategory::of(table) {
+        Some(category) => category,
+        None => return,
+    };
+    let owner = new.or(old).and_then(|record| owner(table, key, record));
+    let mut usage = StorageUsage::load();
+    usage.apply(category, owner.as_deref(), old.map(|v| v.len()), new.map(|v| v.len()));
+    if let Err(err) = usage.save() {
+        notify::warning(&format!("Failed to account for ledger storage: {}", err));
+    }
+}
+
+// Every write of the crate to the ledger goes through here so that the usage counters follow it
+pub fn ledger_set(table: &str, key: &str, value: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+    let ledger = crate::runtime::ledger::get_table(table);
+    let old = ledger.get(key).ok();
+    ledger.set(key, value)?;
+    account(table, key, old.as_deref(), Some(value));
+    Ok(())
+}
+
+pub fn ledger_remove(table: &str, key: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let ledger = crate::runtime::ledger::get_table(table);
+    let old = ledger.get(key).ok();
+    ledger.remove(key)?;
+    account(table, key, old.as_deref(), None);
+    Ok(())
+}
+
+fn exceeds_quota(usage: &StorageUsage, additional: u64, quota: u64) -> Result<(), String> {
+    let total = usage.total_bytes().saturating_add(additional);
+    if quota > 0 && total > quota {
+        return Err(format!("Ledger storage would reach {} of the {} bytes allowed, prune history or backups first (see storage_usage)", total, quota));
+    }
+    Ok(())
+}
+
+// Checked before large writes: row backups, cached host state and job records
+pub fn check_quota(additional: u64) -> Result<(), Box<dyn std::error::Error>> {
+    let settings = DeploymentSettings::load()?;
+    exceeds_quota(&StorageUsage::load(), additional, settings.storage_quota_bytes)?;
+    Ok(())
+}
+
+// Usage rebuilt from the records themselves
+fn tally<'a>(records: impl Iterator<Item = (&'a str, String, Vec<u8>)>) -> StorageUsage {
+    let mut usage = StorageUsage::default();
+    for (table, key, record) in records {
+        if let Some(category) = StorageCategory::of(table) {
+            usage.apply(category, owner(table, &key, &record).as_deref(), None, Some(record.len()));
+        }
+    }
+    usage
+}
+
+fn recalculate() -> Result<StorageUsage, Box<dyn std::error::Error>> {
+    let mut records = Vec::new();
+    for (table, _) in TRACKED_TABLES.iter() {
+        let ledger = crate::runtime::ledger::get_table(table);
+        for key in ledger.list_keys()? {
+            if let Ok(record) = ledger.get(&key) {
+                records.push((*table, key, record));
+            }
+        }
+    }
+    let mut usage = tally(records.into_iter());
+    usage.recalculated_at = Some(get_trusted_time());
+    usage.save()?;
+    Ok(usage)
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StorageUsageInput {
+    // Usage of a single database; the whole breakdown when absent
+    #[serde(default)]
+    pub database_id: Option<String>,
+}
+
+pub fn storage_usage(cmd: String) {
+    let input: StorageUsageInput = match notify::parse_input(&cmd) {
+        Some(input) => input,
+        None => return,
+    };
+    if let Err(err) = require_admin("read the storage usage") {
+        notify::error(&err);
+        return;
+    }
+    let usage = StorageUsage::load();
+    let quota = DeploymentSettings::load().map(|s| s.storage_quota_bytes).unwrap_or(0);
+    match input.database_id {
+        Some(database_id) => {
+            let usage = usage.for_database(&database_id);
+            notify::result(&json!({ "database_id": database_id, "total_bytes": usage.total_bytes(), "usage": usage }));
+        },
+        None => notify::result(&json!({ "total_bytes": usage.total_bytes(), "quota_bytes": quota, "usage": usage })),
+    }
+}
+
+// Maintenance: rebuilds the counters by scanning the tables, for when incremental accounting drifted
+pub fn recalculate_storage_usage(_cmd: String) {
+    if let Err(err) = require_admin("recalculate the storage usage") {
+        audit::record("recalculate_storage_usage", None, "refused", json!({}));
+        notify::error(&err);
+        return;
+    }
+    let before = StorageUsage::load().total_bytes();
+    match recalculate() {
+        Ok(usage) => {
+            audit::record("recalculate_storage_usage", None, "success", json!({ "before_bytes": before, "total_bytes": usage.total_bytes() }));
+            notify::result(&json!({ "before_bytes": before, "total_bytes": usage.total_bytes(), "usage": usage }));
+        },
+        Err(err) => {
+            audit::record("recalculate_storage_usage", None, "failure", json!({}));
+            notify::error_with_code(&format!("Failed to recalculate storage usage: {}", err), DatabaseError::code_of(err.as_ref()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::utils::ledger_key;
+
+    use super::*;
+
+    #[test]
+    fn test_owner() {
+        assert_eq!(owner(AUDIT_LOG_TABLE, "x", br#"{"database_id":"db"}"#).as_deref(), Some("db"));
+        assert_eq!(owner(AUDIT_AGGREGATE_TABLE, "00019000/%2D", br#"{"database_id":null}"#), None);
+        assert_eq!(owner(DATABASE_CLIENT_TABLE, "ALL", b"[]"), None);
+        assert_eq!(owner(DATABASE_CLIENT_TABLE, "LIST_alice", br#"{"clients":["db1"]}"#), None);
+        assert_eq!(owner(CLIENT_POLICY_TABLE, "db", b"{}").as_deref(), Some("db"));
+        assert_eq!(owner(ROW_BACKUP_TABLE, &ledger_key(&["index", "db/1"]), b"{}").as_deref(), Some("db/1"));
+        assert_eq!(owner(CONSISTENCY_TABLE, &ledger_key(&["db", "client"]), b"{}").as_deref(), Some("db"));
+        assert_eq!(owner(ROUTE_USAGE_TABLE, "sql_list", b"3"), None);
+    }
+
+    #[test]
+    fn test_counters_follow_writes() {
+        let mut usage = StorageUsage::default();
+        usage.apply(StorageCategory::Backups, Some("db"), None, Some(100));
+        usage.apply(StorageCategory::Backups, Some("db"), Some(100), Some(40));
+        usage.apply(StorageCategory::Audit, None, None, Some(10));
+        assert_eq!(usage.categories[&StorageCategory::Backups], UsageCounter { bytes: 40, records: 1 });
+        assert_eq!(usage.total_bytes(), 50);
+        assert_eq!(usage.for_database("db").total_bytes(), 40);
+
+        usage.apply(StorageCategory::Backups, Some("db"), Some(40), None);
+        assert!(usage.databases.is_empty());
+        // A record the counters never saw cannot take them below zero
+        usage.apply(StorageCategory::Audit, None, Some(500), None);
+        assert_eq!(usage.total_bytes(), 0);
+    }
+
+    #[test]
+    fn test_tally_and_quota() {
+        let usage = tally(vec![
+            (ROW_BACKUP_TABLE, "b1".to_string(), br#"{"database_id":"db","sealed":"00"}"#.to_vec()),
+            (JOB_LOCK_TABLE, ledger_key(&["db", "users", "0"]), b"{}".to_vec()),
+            (STORAGE_USAGE_TABLE, STORAGE_USAGE_KEY.to_string(), b"{}".to_vec()),
+        ].into_iter());
+        assert_eq!(usage.databases["db"].len(), 2);
+        assert_eq!(usage.total_bytes(), 36);
+        assert!(exceeds_quota(&usage, 64, 100).is_ok());
+        assert!(exceeds_quota(&usage, 65, 100).unwrap_err().contains("101 of the 100 bytes"));
+        assert!(exceeds_quota(&usage, 1 << 40, 0).is_ok());
+        assert_eq!(serde_json::to_value(&usage).unwrap()["categories"]["backups"]["records"], 1);
+    }
+}
@@ -0,0 +1,345 @@
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{
+    audit,
+    crypto::compute_sha256_hex_string,
+    database::{Client, DatabaseError},
+    manifest::EncryptionManifest,
+    notify,
+    settings::require_admin,
+    statement::{self, Token, TokenKind},
+    utils::get_trusted_time,
+};
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+// Bypass tokens are meant for a migration run right after they are issued
+const BYPASS_TOKEN_TTL_SECONDS: u64 = 15 * 60;
+const BYPASS_TOKEN_BYTES: i32 = 16;
+
+// Single-use exception to strict encrypted access, only the hash of the token is stored
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StrictBypass {
+    pub token_hash: String,
+    pub reason: String,
+    pub expires_at: u64,
+}
+
+impl StrictBypass {
+    pub fn accepts(&self, token_hash: &str, now: u64) -> bool {
+        self.token_hash == token_hash && now < self.expires_at
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SetStrictAccessInput {
+    pub database_id: String,
+    pub enabled: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct IssueBypassInput {
+    pub database_id: String,
+    pub reason: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EncryptedTableReference {
+    pub statement: usize,
+    pub table: String,
+    // Byte offset of the reference in the statement
+    pub offset: usize,
+    // Found in a dollar-quoted body, e.g. a DO block or a function definition, which is not parsed
+    pub opaque: bool,
+}
+
+// Token range where a CTE name stands for the CTE rather than a table
+struct CteScope {
+    name: String,
+    definition: usize,
+    from: usize,
+    to: usize,
+}
+
+fn identifier(token: &Token) -> Option<String> {
+    match token.kind {
+        TokenKind::Word => Some(token.text.to_lowercase()),
+        TokenKind::QuotedIdentifier => Some(token.text[1..token.text.len() - 1].replace("\"\"", "\"")),
+        _ => None,
+    }
+}
+
+fn is_symbol(tokens: &[Token], i: usize, symbol: &str) -> bool {
+    tokens.get(i).map(|t| t.kind == TokenKind::Symbol && t.text == symbol).unwrap_or(false)
+}
+
+fn is_keyword(tokens: &[Token], i: usize, keyword: &str) -> bool {
+    tokens.get(i).map(|t| t.is_keyword(keyword)).unwrap_or(false)
+}
+
+// Index of the parenthesis closing the one at open
+fn closing(tokens: &[Token], open: usize) -> Option<usize> {
+    let depth = tokens[open].depth;
+    tokens.iter().enumerate().skip(open + 1).find(|(_, t)| t.depth == depth && t.kind == TokenKind::Symbol && t.text == ")").map(|(i, _)| i)
+}
+
+// Names defined by every WITH clause, nested ones included. A non-recursive CTE only shadows a table after
+// its own body, which still reads the real table; the scope ends with the query holding the WITH.
+fn cte_scopes(tokens: &[Token]) -> Vec<CteScope> {
+    let mut scopes = Vec::new();
+    for (w, token) in tokens.iter().enumerate() {
+        if !token.is_keyword("WITH") {
+            continue;
+        }
+        let end = tokens.iter().enumerate().skip(w + 1).find(|(_, t)| t.depth < token.depth).map(|(i, _)| i).unwrap_or(tokens.len());
+        let recursive = is_keyword(tokens, w + 1, "RECURSIVE");
+        let mut j = if recursive { w + 2 } else { w + 1 };
+        while let Some(name) = tokens.get(j).and_then(identifier) {
+            let definition = j;
+            j += 1;
+            if is_symbol(tokens, j, "(") {
+                j = match closing(tokens, j) {
+                    Some(close) => close + 1,
+                    None => break,
+                };
+            }
+            if !is_keyword(tokens, j, "AS") {
+                break;
+            }
+            j += 1;
+            if is_keyword(tokens, j, "NOT") {
+                j += 1;
+            }
+            if is_keyword(tokens, j, "MATERIALIZED") {
+                j += 1;
+            }
+            if !is_symbol(tokens, j, "(") {
+                break;
+            }
+            let body_end = match closing(tokens, j) {
+                Some(close) => close,
+                None => break,
+            };
+            scopes.push(CteScope { name, definition, from: if recursive { j } else { body_end + 1 }, to: end });
+            j = body_end + 1;
+            if !is_symbol(tokens, j, ",") {
+                break;
+            }
+            j += 1;
+        }
+    }
+    scopes
+}
+
+fn mentions(text: &str, table: &str) -> bool {
+    let lowered = text.to_lowercase();
+    let table = table.to_lowercase();
+    let is_word = |c: char| c.is_ascii_alphanumeric() || c == '_';
+    lowered.match_indices(&table).any(|(i, _)| {
+        !lowered[..i].chars().next_back().map(is_word).unwrap_or(false) && !lowered[i + table.len()..].chars().next().map(is_word).unwrap_or(false)
+    })
+}
+
+// Every place a statement may read or write one of the given tables. Matching is deliberately broad:
+// aliases, schema-qualified names and qualified columns all count, and only CTE names in scope are excluded.
+pub fn encrypted_table_references(statement: usize, sql: &str, tables: &[String]) -> Result<Vec<EncryptedTableReference>, statement::ScanError> {
+    let tokens = statement::tokenize(sql)?;
+    let scopes = cte_scopes(&tokens);
+    let mut references = Vec::new();
+    for (k, token) in tokens.iter().enumerate() {
+        if token.kind == TokenKind::StringLiteral && token.text.starts_with('$') {
+            for table in tables.iter().filter(|t| mentions(&token.text, t)) {
+                references.push(EncryptedTableReference { statement, table: table.clone(), offset: token.offset, opaque: true });
+            }
+            continue;
+        }
+        let name = match identifier(token) {
+            Some(name) => name,
+            None => continue,
+        };
+        // Table names are used unquoted by the encryption routes, so case is never significant
+        let table = tables.iter().find(|t| t.eq_ignore_ascii_case(&name));
+        let table = match table {
+            Some(table) => table,
+            None => continue,
+        };
+        let shadowed = scopes.iter().any(|s| s.name.eq_ignore_ascii_case(&name) && (s.definition == k || (s.from..s.to).contains(&k)));
+        if !shadowed {
+            references.push(EncryptedTableReference { statement, table: table.clone(), offset: token.offset, opaque: false });
+        }
+    }
+    Ok(references)
+}
+
+fn bypass_hash(token: &str) -> String {
+    compute_sha256_hex_string(format!("strict-bypass:{}", token).as_bytes())
+}
+
+// Refuses statements touching an encrypted table of a client in strict mode, unless a valid bypass token is
+// given; the token is consumed and the client policies saved. Errors are sent to the caller.
+pub fn allow_statements<'a>(client: &mut Client, statements: impl Iterator<Item = &'a str>, bypass_token: Option<&str>) -> bool {
+    if !client.strict_encrypted_access() {
+        return true;
+    }
+    let manifest = match EncryptionManifest::load(client.database_id()) {
+        Ok(manifest) => manifest,
+        Err(err) => {
+            notify::error_with_code(&format!("Failed to load encryption manifest: {}", err), DatabaseError::code_of(err.as_ref()));
+            return false;
+        }
+    };
+    let tables: Vec<String> = manifest.tables.iter().map(|t| t.table.clone()).collect();
+    let mut references = Vec::new();
+    for (index, sql) in statements.enumerate() {
+        match encrypted_table_references(index, sql, &tables) {
+            Ok(found) => references.extend(found),
+            Err(err) => {
+                notify::error(&format!("Statement {} cannot be checked for strict encrypted access: {}", index, err));
+                return false;
+            }
+        }
+    }
+    let first = match references.first() {
+        Some(first) => first.clone(),
+        None => return true,
+    };
+
+    let touched: Vec<String> = references.iter().map(|r| r.table.clone()).collect();
+    match bypass_token {
+        Some(token) => {
+            let bypass = match client.take_strict_bypass(&bypass_hash(token), get_trusted_time()) {
+                Some(bypass) => bypass,
+                None => {
+                    audit::record("strict_bypass", Some(client.database_id()), "refused", json!({ "tables": touched }));
+                    notify::error("Invalid, expired or already used strict_bypass_token");
+                    return false;
+                }
+            };
+            if let Err(err) = client.save_policies() {
+                notify::error_with_code(&format!("Failed to consume strict_bypass_token: {}", err), err.code());
+                return false;
+            }
+            audit::record("strict_bypass", Some(client.database_id()), "success", json!({ "reason": bypass.reason, "tables": touched }));
+            notify::warning(&format!("Strict encrypted access bypassed: {}", bypass.reason));
+            true
+        }
+        None => {
+            notify::error_with_details(&format!(
+                "Statement {} references encrypted table '{}' and the database is in strict encrypted access mode; \
+                use the encrypted read routes, or an admin-issued strict_bypass_token for a migration",
+                first.statement, first.table), &references);
+            false
+        }
+    }
+}
+
+pub fn set_strict_encrypted_access(cmd: String) {
+    let input: SetStrictAccessInput = match notify::parse_input(&cmd) {
+        Some(input) => input,
+        None => return,
+    };
+    if let Err(err) = require_admin("change strict encrypted access") {
+        notify::error(&err);
+        return;
+    }
+    let mut client = match Client::load(input.database_id.clone()) {
+        Ok(c) => c,
+        Err(err) => {
+            notify::error_with_code(&format!("Failed to load client: {}", err), err.code());
+            return;
+        }
+    };
+    client.set_strict_encrypted_access(input.enabled);
+    match client.save_policies() {
+        Ok(_) => {
+            audit::record("set_strict_encrypted_access", Some(&input.database_id), "success", json!({ "enabled": input.enabled }));
+            notify::result(&json!({ "database_id": input.database_id, "strict_encrypted_access": input.enabled }));
+        },
+        Err(err) => {
+            audit::record("set_strict_encrypted_access", Some(&input.database_id), "failure", json!({ "enabled": input.enabled }));
+            notify::error_with_code(&format!("Failed to save client: {}", err), err.code());
+        }
+    }
+}
+
+// Issues the single bypass token of a client, replacing any unused one. The token is only returned here.
+pub fn issue_strict_bypass_token(cmd: String) {
+    let input: IssueBypassInput = match notify::parse_input(&cmd) {
+        Some(input) => input,
+        None => return,
+    };
+    if input.reason.trim().is_empty() {
+        notify::error("A bypass token needs a reason");
+        return;
+    }
+    if let Err(err) = require_admin("issue a strict bypass token") {
+        notify::error(&err);
+        return;
+    }
+    let mut client = match Client::load(input.database_id.clone()) {
+        Ok(c) => c,
+        Err(err) => {
+            notify::error_with_code(&format!("Failed to load client: {}", err), err.code());
+            return;
+        }
+    };
+    let token = match crate::runtime::random::get_random_bytes(BYPASS_TOKEN_BYTES) {
+        Ok(bytes) => hex::encode(bytes),
+        Err(err) => {
+            notify::error_with_code(&format!("Failed to generate token: {}", err), DatabaseError::code_of(err.as_ref()));
+            return;
+        }
+    };
+    let expires_at = get_trusted_time().saturating_add(BYPASS_TOKEN_TTL_SECONDS * NANOS_PER_SECOND);
+    client.set_strict_bypass(Some(StrictBypass { token_hash: bypass_hash(&token), reason: input.reason.clone(), expires_at }));
+    match client.save_policies() {
+        Ok(_) => {
+            audit::record("issue_strict_bypass_token", Some(&input.database_id), "success", json!({ "reason": input.reason, "expires_at": expires_at }));
+            notify::result(&json!({ "database_id": input.database_id, "strict_bypass_token": token, "expires_at": expires_at }));
+        },
+        Err(err) => {
+            audit::record("issue_strict_bypass_token", Some(&input.database_id), "failure", json!({ "reason": input.reason }));
+            notify::error_with_code(&format!("Failed to save client: {}", err), err.code());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tables(sql: &str) -> Vec<(String, bool)> {
+        let registered = vec!["users".to_string(), "Payments".to_string()];
+        encrypted_table_references(0, sql, &registered).unwrap().into_iter().map(|r| (r.table, r.opaque)).collect()
+    }
+
+    #[test]
+    fn test_encrypted_table_references() {
+        assert_eq!(tables("SELECT u.email FROM public.USERS AS u"), vec![("users".to_string(), false)]);
+        assert!(tables("SELECT * FROM orders o JOIN products p ON p.id = o.product_id").is_empty());
+        assert_eq!(tables("SELECT * FROM payments"), vec![("Payments".to_string(), false)]);
+        assert_eq!(tables("UPDATE \"Payments\" SET total = 0").len(), 1);
+        assert_eq!(tables("INSERT INTO users (id) VALUES (1)").len(), 1);
+
+        // A CTE named after an encrypted table shadows it once defined, but its own body reads the table
+        assert!(tables("WITH users AS (SELECT 1 AS id) SELECT * FROM users").is_empty());
+        assert_eq!(tables("WITH users AS (SELECT id FROM users) SELECT * FROM users").len(), 1);
+        assert!(tables("WITH RECURSIVE users(n) AS (SELECT 1 UNION SELECT n + 1 FROM users) SELECT n FROM users").is_empty());
+        // The shadowing ends with the subquery holding the WITH
+        assert_eq!(tables("SELECT * FROM (WITH users AS (SELECT 1) SELECT * FROM users) s, users").len(), 1);
+
+        assert_eq!(tables("DO $$ BEGIN DELETE FROM users; END $$"), vec![("users".to_string(), true)]);
+        assert!(tables("DO $$ BEGIN DELETE FROM superusers; END $$").is_empty());
+        assert!(tables("SELECT 'users' AS label").is_empty());
+    }
+
+    #[test]
+    fn test_bypass_accepts() {
+        let bypass = StrictBypass { token_hash: "h".to_string(), reason: "migration".to_string(), expires_at: 10 };
+        assert!(bypass.accepts("h", 9));
+        assert!(!bypass.accepts("h", 10));
+        assert!(!bypass.accepts("x", 9));
+    }
+}
@@ -0,0 +1,335 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{
+    audit::{self, AuditEntry},
+    capabilities::HostCapabilities,
+    credentials::CredentialHealth,
+    crypto::compute_sha256_hex_string,
+    database::{Client, Clients, DatabaseError},
+    keys::{diagnose_client_keys, KeyDiagnosis},
+    manifest::EncryptionManifest,
+    notify,
+    settings::{require_admin, DeploymentSettings},
+    utils::get_trusted_time,
+    RouteKind, ROUTES,
+};
+
+// Upper bound on the serialized bundle, the oldest records are dropped first to fit
+const MAX_BUNDLE_BYTES: usize = 256 * 1024;
+const MAX_FAILED_OPERATIONS: usize = 50;
+// Hex characters kept from the hash of a name
+const NAME_HASH_LENGTH: usize = 12;
+const REDACTED: &str = "<redacted>";
+// Fields whose values are replaced wherever they appear in the bundle
+const SENSITIVE_FIELDS: &[&str] = &["password", "user", "host", "dbname", "opaque_handle", "master_key_name", "key_name", "private_key", "public_key", "sender"];
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SupportBundleInput {
+    // Table and column names are hashed unless the caller asks for them
+    #[serde(default)]
+    pub include_names: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClientSummary {
+    pub database_id: String,
+    pub tags: Vec<String>,
+    pub production: bool,
+    pub has_master_key: bool,
+    pub needs_key_attach: bool,
+    pub frozen_until: Option<u64>,
+    pub credentials_expire_at: Option<u64>,
+    // A string of authentication failures since the last successful connection
+    pub credentials_suspect: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ColumnShape {
+    pub name: String,
+    pub has_aad_template: bool,
+    pub has_search_index: bool,
+    pub rules: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TableShape {
+    pub table: String,
+    pub state: Value,
+    pub columns: Vec<ColumnShape>,
+    pub has_row_mac: bool,
+    pub interrupted: bool,
+    pub relaxed_constraints: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ManifestShape {
+    pub database_id: String,
+    pub version: u64,
+    pub tables: Vec<TableShape>,
+}
+
+// Audit entry of a failed operation; only the names of its detail fields are kept
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FailedOperation {
+    pub timestamp: u64,
+    pub route: String,
+    pub database_id: Option<String>,
+    pub outcome: String,
+    pub detail_fields: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SupportBundle {
+    pub crate_version: String,
+    pub generated_at: u64,
+    pub routes: Vec<(String, RouteKind)>,
+    pub clients: Vec<ClientSummary>,
+    pub manifests: Vec<ManifestShape>,
+    pub failed_operations: Vec<FailedOperation>,
+    pub key_diagnostics: Vec<KeyDiagnosis>,
+    // Probed databases only
+    pub host_capabilities: Vec<HostCapabilities>,
+    pub settings: Value,
+    // Set when records were dropped to keep the bundle under its size bound
+    pub truncated: bool,
+}
+
+// What the bundle is built from, read from the ledger and the key store by the route
+pub struct SupportSources {
+    pub clients: Vec<Client>,
+    // Same order as clients
+    pub credential_health: Vec<CredentialHealth>,
+    pub manifests: Vec<EncryptionManifest>,
+    pub audit: Vec<AuditEntry>,
+    pub key_diagnostics: Vec<KeyDiagnosis>,
+    pub host_capabilities: Vec<HostCapabilities>,
+    pub settings: DeploymentSettings,
+}
+
+// Stable across bundles so that reports about the same table can be correlated
+pub fn hash_name(name: &str) -> String {
+    compute_sha256_hex_string(format!("support:{}", name).as_bytes()).chars().take(NAME_HASH_LENGTH).collect()
+}
+
+// Hashes names, identities and table names alike; hash_name outside of tests
+type NameHasher<'a> = &'a dyn Fn(&str) -> String;
+
+fn manifest_shape(manifest: &EncryptionManifest, include_names: bool, hash: NameHasher) -> ManifestShape {
+    let shown_name = |name: &str| if include_names { name.to_string() } else { hash(name) };
+    let tables = manifest.tables.iter().map(|t| TableShape {
+        table: shown_name(&t.table),
+        state: serde_json::to_value(t.state).unwrap_or(Value::Null),
+        columns: t.columns.iter().map(|c| ColumnShape {
+            name: shown_name(&c.name),
+            has_aad_template: c.aad_template.is_some(),
+            has_search_index: c.search_index.is_some(),
+            rules: c.rules.iter().map(|r| r.name().to_string()).collect(),
+        }).collect(),
+        has_row_mac: t.row_mac_column.is_some(),
+        interrupted: t.watermark.is_some(),
+        relaxed_constraints: t.relaxed_constraints.len(),
+    }).collect();
+    ManifestShape { database_id: manifest.database_id.clone(), version: manifest.version, tables }
+}
+
+fn settings_shape(settings: &DeploymentSettings, hash: NameHasher) -> Value {
+    let mut value = serde_json::to_value(settings).unwrap_or(Value::Null);
+    if let Some(fields) = value.as_object_mut() {
+        fields.insert("admin".to_string(), json!(settings.admin.as_deref().map(hash)));
+        fields.insert("reporting_only".to_string(), json!(settings.reporting_only.iter().map(|id| hash(id)).collect::<Vec<_>>()));
+    }
+    value
+}
+
+// Redaction layer: sensitive fields are replaced and every known secret is cut out of the remaining strings
+pub fn redact(value: &mut Value, secrets: &[&str]) {
+    match value {
+        Value::String(text) => {
+            for secret in secrets.iter().filter(|s| !s.is_empty()) {
+                if text.contains(secret) {
+                    *text = text.replace(secret, REDACTED);
+                }
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(|item| redact(item, secrets)),
+        Value::Object(fields) => {
+            for (key, field) in fields.iter_mut() {
+                if SENSITIVE_FIELDS.contains(&key.as_str()) && !field.is_null() {
+                    *field = Value::String(REDACTED.to_string());
+                } else {
+                    redact(field, secrets);
+                }
+            }
+        }
+        _ => (),
+    }
+}
+
+fn bundle_size(bundle: &SupportBundle) -> usize {
+    serde_json::to_vec(bundle).map(|b| b.len()).unwrap_or(usize::MAX)
+}
+
+// Drops the oldest failed operations, then manifests, until the bundle fits
+pub fn bound_size(bundle: &mut SupportBundle, max_bytes: usize) {
+    while bundle_size(bundle) > max_bytes {
+        if !bundle.failed_operations.is_empty() {
+            bundle.failed_operations.remove(0);
+        } else if bundle.manifests.pop().is_none() && bundle.key_diagnostics.pop().is_none() && bundle.clients.pop().is_none() {
+            break;
+        }
+        bundle.truncated = true;
+    }
+}
+
+pub fn assemble(sources: &SupportSources, include_names: bool, generated_at: u64, hash: NameHasher) -> Result<Value, Box<dyn std::error::Error>> {
+    let mut failed: Vec<&AuditEntry> = sources.audit.iter().filter(|e| e.outcome != "success").collect();
+    failed.sort_by_key(|e| e.timestamp);
+    let skipped = failed.len().saturating_sub(MAX_FAILED_OPERATIONS);
+    let failed_operations = failed.iter().skip(skipped).map(|e| FailedOperation {
+        timestamp: e.timestamp,
+        route: e.route.clone(),
+        database_id: e.database_id.clone(),
+        outcome: e.outcome.clone(),
+        detail_fields: e.details.as_object().map(|d| d.keys().cloned().collect()).unwrap_or_default(),
+    }).collect();
+
+    let mut bundle = SupportBundle {
+        crate_version: env!("CARGO_PKG_VERSION").to_string(),
+        generated_at,
+        routes: ROUTES.iter().map(|(route, kind)| (route.to_string(), *kind)).collect(),
+        clients: sources.clients.iter().enumerate().map(|(i, c)| ClientSummary {
+            database_id: c.database_id().to_string(),
+            tags: c.tags().to_vec(),
+            production: c.is_production(),
+            has_master_key: c.master_key_name().is_some(),
+            needs_key_attach: c.needs_key_attach(),
+            frozen_until: c.frozen_until(),
+            credentials_expire_at: c.credentials_expire_at(),
+            credentials_suspect: sources.credential_health.get(i).map(|h| h.suspect()).unwrap_or(false),
+        }).collect(),
+        manifests: sources.manifests.iter().map(|m| manifest_shape(m, include_names, hash)).collect(),
+        failed_operations,
+        key_diagnostics: sources.key_diagnostics.clone(),
+        host_capabilities: sources.host_capabilities.clone(),
+        settings: settings_shape(&sources.settings, hash),
+        truncated: skipped > 0,
+    };
+    bound_size(&mut bundle, MAX_BUNDLE_BYTES);
+
+    let secrets: Vec<&str> = sources.clients.iter().flat_map(|c| c.connection_secrets()).collect();
+    let mut value = serde_json::to_value(&bundle)?;
+    redact(&mut value, &secrets);
+    Ok(value)
+}
+
+fn collect_sources() -> Result<SupportSources, Box<dyn std::error::Error>> {
+    let clients = Clients::load_all()?.list()?;
+    let mut manifests = Vec::new();
+    for client in clients.iter() {
+        manifests.push(EncryptionManifest::load(client.database_id())?);
+    }
+    let key_diagnostics = clients.iter().map(diagnose_client_keys).collect();
+    let credential_health = clients.iter().map(|c| CredentialHealth::load(c.database_id())).collect();
+    let host_capabilities = clients.iter().filter_map(|c| HostCapabilities::load(c.database_id())).collect();
+    Ok(SupportSources { clients, credential_health, manifests, audit: audit::load_entries()?, key_diagnostics, host_capabilities, settings: DeploymentSettings::load()? })
+}
+
+// Diagnostic context safe to attach to an issue: shapes, counts and statuses, never credentials, keys or row data
+pub fn generate_support_bundle(cmd: String) {
+    let input: SupportBundleInput = match notify::parse_input(&cmd) {
+        Some(input) => input,
+        None => return,
+    };
+    if let Err(err) = require_admin("generate a support bundle") {
+        notify::error(&err);
+        return;
+    }
+    match collect_sources().and_then(|sources| assemble(&sources, input.include_names, get_trusted_time(), &hash_name)) {
+        Ok(bundle) => notify::result(&bundle),
+        Err(err) => notify::error_with_code(&format!("Failed to generate support bundle: {}", err), DatabaseError::code_of(err.as_ref())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{capabilities::{Capability, CapabilityResult}, database::ClientPolicies, keys::{KeyRole, KeyStatus}, manifest::EncryptedColumn};
+
+    // The key store hash is not available natively
+    fn test_hash(name: &str) -> String {
+        format!("h{}", name.len())
+    }
+
+    const SECRETS: &[&str] = &["db.internal.example", "s3cr3t-pa55word", "payroll_admin", "salary_ssn", "admin-identity-42", "SELECT ssn FROM salary_ssn", "key-name-7f3a"];
+
+    fn sources() -> SupportSources {
+        let mut client: Client = serde_json::from_value(json!({
+            "database_id": "db",
+            "db_input_details": { "host": "db.internal.example", "dbname": "payroll", "user": "payroll_admin", "password": "s3cr3t-pa55word", "tags": ["production"], "credentials_expire_at": 9 },
+            "opaque_handle": "handle-1",
+            "master_key_name": "key-name-7f3a",
+        })).unwrap();
+        client = client.with_policies(ClientPolicies::default());
+        client.set_freeze(Some(5), Some("incident".to_string()));
+
+        let mut manifest = EncryptionManifest::new("db");
+        manifest.record_column("salary_ssn", "id", EncryptedColumn::new("ssn", None), 1);
+
+        let failure: AuditEntry = serde_json::from_value(json!({
+            "id": "1", "timestamp": 1, "sender": "admin-identity-42", "route": "sql_script", "database_id": "db",
+            "outcome": "failure", "details": { "error": "SELECT ssn FROM salary_ssn failed on db.internal.example" },
+        })).unwrap();
+        let success: AuditEntry = serde_json::from_value(json!({
+            "id": "2", "timestamp": 2, "sender": "admin-identity-42", "route": "db_setup", "database_id": "db", "outcome": "success", "details": {},
+        })).unwrap();
+
+        let diagnosis = KeyDiagnosis::new("db", vec![KeyStatus {
+            role: KeyRole::Master,
+            key_name: Some("key-name-7f3a".to_string()),
+            loadable: false,
+            error: Some("connection to db.internal.example as payroll_admin:s3cr3t-pa55word refused".to_string()),
+        }]);
+        let settings = DeploymentSettings { admin: Some("admin-identity-42".to_string()), ..Default::default() };
+
+        let health = CredentialHealth { consecutive_auth_failures: 3, last_auth_error: Some("password authentication failed for user payroll_admin".to_string()), ..Default::default() };
+
+        let capabilities = HostCapabilities {
+            database_id: "db".to_string(),
+            probed_at: 3,
+            results: vec![CapabilityResult { capability: Capability::Copy, supported: false, error: Some("COPY refused on db.internal.example".to_string()) }],
+        };
+
+        SupportSources { clients: vec![client], credential_health: vec![health], manifests: vec![manifest], audit: vec![failure, success], key_diagnostics: vec![diagnosis], host_capabilities: vec![capabilities], settings }
+    }
+
+    #[test]
+    fn test_bundle_never_holds_planted_secrets() {
+        let bundle = assemble(&sources(), false, 10, &test_hash).unwrap();
+        let serialized = bundle.to_string();
+        for secret in SECRETS {
+            assert!(!serialized.contains(secret), "bundle leaks {}", secret);
+        }
+        assert_eq!(bundle["clients"][0]["frozen_until"], json!(5));
+        assert_eq!(bundle["clients"][0]["credentials_expire_at"], json!(9));
+        assert_eq!(bundle["clients"][0]["credentials_suspect"], json!(true));
+        assert_eq!(bundle["host_capabilities"][0]["results"][0]["supported"], json!(false));
+        assert_eq!(bundle["failed_operations"].as_array().unwrap().len(), 1);
+        assert_eq!(bundle["failed_operations"][0]["detail_fields"], json!(["error"]));
+        assert_eq!(bundle["manifests"][0]["tables"][0]["table"], json!("h10"));
+        assert_eq!(bundle["settings"]["admin"], json!("h17"));
+        assert!(bundle["routes"].as_array().unwrap().contains(&json!(["generate_support_bundle", "query"])));
+
+        let named = assemble(&sources(), true, 10, &test_hash).unwrap();
+        assert_eq!(named["manifests"][0]["tables"][0]["columns"][0]["name"], json!("ssn"));
+    }
+
+    #[test]
+    fn test_bound_size() {
+        let mut bundle: SupportBundle = serde_json::from_value(assemble(&sources(), false, 10, &test_hash).unwrap()).unwrap();
+        bound_size(&mut bundle, 0);
+        assert!(bundle.truncated);
+        assert!(bundle.failed_operations.is_empty() && bundle.manifests.is_empty() && bundle.clients.is_empty());
+    }
+}
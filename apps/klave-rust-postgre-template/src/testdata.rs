@@ -0,0 +1,382 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{budget::ExecutionBudget, database, notify, settings::DeploymentSettings};
+
+const INSERT_CHUNK_SIZE: usize = 500;
+
+const FIRST_NAMES: [&str; 16] = [
+    "Alice", "Bruno", "Chloe", "David", "Emma", "Farid", "Grace", "Hugo",
+    "Ines", "Jonas", "Keiko", "Liam", "Maya", "Noah", "Olga", "Pablo",
+];
+const LAST_NAMES: [&str; 16] = [
+    "Martin", "Bernard", "Dubois", "Smith", "Garcia", "Muller", "Rossi", "Kowalski",
+    "Nguyen", "Tanaka", "Silva", "Novak", "Jensen", "Moreau", "Ivanova", "Lopez",
+];
+const EMAIL_DOMAINS: [&str; 4] = ["example.com", "example.org", "example.net", "test.local"];
+
+// Value generator of a column
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Generator {
+    Uuid,
+    FirstName,
+    LastName,
+    FullName,
+    Email {
+        #[serde(default)]
+        domain: Option<String>,
+    },
+    Integer { min: i64, max: i64 },
+    // Produced as a string to keep the exact scale
+    Decimal { min: f64, max: f64, scale: u32 },
+    // Window given in unix seconds, produced as "YYYY-MM-DD HH:MM:SS"
+    Timestamp { from: i64, to: i64 },
+    Choice {
+        values: Vec<Value>,
+        #[serde(default)]
+        weights: Option<Vec<u32>>,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GenerateTestDataInput {
+    pub database_id: String,
+    pub table: String,
+    pub rows: u64,
+    // Generator per column name
+    pub columns: BTreeMap<String, Generator>,
+}
+
+// Buffered entropy source, refilled from runtime::random
+pub struct Entropy {
+    buffer: Vec<u8>,
+    position: usize,
+    refill: fn(usize) -> Vec<u8>,
+}
+
+fn klave_random(len: usize) -> Vec<u8> {
+    crate::runtime::random::get_random_bytes(len as i32).unwrap_or_default()
+}
+
+impl Entropy {
+    pub fn new() -> Self {
+        Self::with_source(klave_random)
+    }
+
+    pub fn with_source(refill: fn(usize) -> Vec<u8>) -> Self {
+        Self { buffer: Vec::new(), position: 0, refill }
+    }
+
+    pub fn bytes(&mut self, len: usize) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        if self.position + len > self.buffer.len() {
+            let fresh = (self.refill)(len.max(1024));
+            if fresh.len() < len {
+                return Err("Entropy source exhausted".into());
+            }
+            self.buffer = fresh;
+            self.position = 0;
+        }
+        let out = self.buffer[self.position..self.position + len].to_vec();
+        self.position += len;
+        Ok(out)
+    }
+
+    pub fn next_u64(&mut self) -> Result<u64, Box<dyn std::error::Error>> {
+        let bytes = self.bytes(8)?;
+        let mut array = [0u8; 8];
+        array.copy_from_slice(&bytes);
+        Ok(u64::from_le_bytes(array))
+    }
+
+    // Uniform integer in [0, bound) using rejection sampling
+    pub fn below(&mut self, bound: u64) -> Result<u64, Box<dyn std::error::Error>> {
+        if bound == 0 {
+            return Ok(0);
+        }
+        let zone = u64::MAX - (u64::MAX % bound);
+        loop {
+            let v = self.next_u64()?;
+            if v < zone {
+                return Ok(v % bound);
+            }
+        }
+    }
+
+    pub fn range_i64(&mut self, min: i64, max: i64) -> Result<i64, Box<dyn std::error::Error>> {
+        let span = (max as i128 - min as i128 + 1) as u128;
+        if span > u64::MAX as u128 {
+            return Ok(self.next_u64()? as i64);
+        }
+        Ok((min as i128 + self.below(span as u64)? as i128) as i64)
+    }
+}
+
+impl Default for Entropy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Converts days since 1970-01-01 to a (year, month, day) civil date
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = if z >= 0 { z } else { z - 146_096 } / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+pub fn format_timestamp(unix_seconds: i64) -> String {
+    let days = unix_seconds.div_euclid(86_400);
+    let secs = unix_seconds.rem_euclid(86_400);
+    let (year, month, day) = civil_from_days(days);
+    format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02}", year, month, day, secs / 3600, (secs % 3600) / 60, secs % 60)
+}
+
+impl Generator {
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            Generator::Integer { min, max } if min > max => Err(format!("integer range {}..{} is empty", min, max)),
+            Generator::Decimal { min, max, scale } => {
+                if min > max || !min.is_finite() || !max.is_finite() {
+                    return Err(format!("decimal range {}..{} is invalid", min, max));
+                }
+                if *scale > 12 {
+                    return Err(format!("decimal scale {} is too large", scale));
+                }
+                Ok(())
+            }
+            Generator::Timestamp { from, to } if from > to => Err(format!("timestamp window {}..{} is empty", from, to)),
+            Generator::Choice { values, weights } => {
+                if values.is_empty() {
+                    return Err("choice needs at least one value".to_string());
+                }
+                if let Some(w) = weights {
+                    if w.len() != values.len() {
+                        return Err(format!("choice has {} values but {} weights", values.len(), w.len()));
+                    }
+                    if w.iter().all(|x| *x == 0) {
+                        return Err("choice weights are all zero".to_string());
+                    }
+                }
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn generate(&self, entropy: &mut Entropy) -> Result<Value, Box<dyn std::error::Error>> {
+        let value = match self {
+            Generator::Uuid => {
+                let mut b = entropy.bytes(16)?;
+                // RFC 4122 version 4, variant 1
+                b[6] = (b[6] & 0x0f) | 0x40;
+                b[8] = (b[8] & 0x3f) | 0x80;
+                let h = hex::encode(b);
+                Value::String(format!("{}-{}-{}-{}-{}", &h[0..8], &h[8..12], &h[12..16], &h[16..20], &h[20..32]))
+            }
+            Generator::FirstName => Value::String(FIRST_NAMES[entropy.below(FIRST_NAMES.len() as u64)? as usize].to_string()),
+            Generator::LastName => Value::String(LAST_NAMES[entropy.below(LAST_NAMES.len() as u64)? as usize].to_string()),
+            Generator::FullName => {
+                let first = FIRST_NAMES[entropy.below(FIRST_NAMES.len() as u64)? as usize];
+                let last = LAST_NAMES[entropy.below(LAST_NAMES.len() as u64)? as usize];
+                Value::String(format!("{} {}", first, last))
+            }
+            Generator::Email { domain } => {
+                let first = FIRST_NAMES[entropy.below(FIRST_NAMES.len() as u64)? as usize].to_lowercase();
+                let last = LAST_NAMES[entropy.below(LAST_NAMES.len() as u64)? as usize].to_lowercase();
+                let suffix = entropy.below(10_000)?;
+                let domain = match domain {
+                    Some(d) => d.clone(),
+                    None => EMAIL_DOMAINS[entropy.below(EMAIL_DOMAINS.len() as u64)? as usize].to_string(),
+                };
+                Value::String(format!("{}.{}{}@{}", first, last, suffix, domain))
+            }
+            Generator::Integer { min, max } => json!(entropy.range_i64(*min, *max)?),
+            Generator::Decimal { min, max, scale } => {
+                let factor = 10i64.pow(*scale);
+                let low = (min * factor as f64).ceil() as i64;
+                let high = (max * factor as f64).floor() as i64;
+                let units = entropy.range_i64(low, high.max(low))?;
+                let sign = if units < 0 { "-" } else { "" };
+                let abs = units.unsigned_abs();
+                let text = if *scale == 0 {
+                    format!("{}{}", sign, abs)
+                } else {
+                    format!("{}{}.{:0width$}", sign, abs / factor as u64, abs % factor as u64, width = *scale as usize)
+                };
+                Value::String(text)
+            }
+            Generator::Timestamp { from, to } => Value::String(format_timestamp(entropy.range_i64(*from, *to)?)),
+            Generator::Choice { values, weights } => {
+                let weights: Vec<u64> = match weights {
+                    Some(w) => w.iter().map(|x| *x as u64).collect(),
+                    None => vec![1; values.len()],
+                };
+                let mut pick = entropy.below(weights.iter().sum())?;
+                let mut chosen = values.len() - 1;
+                for (i, w) in weights.iter().enumerate() {
+                    if pick < *w {
+                        chosen = i;
+                        break;
+                    }
+                    pick -= w;
+                }
+                values[chosen].clone()
+            }
+        };
+        Ok(value)
+    }
+}
+
+pub fn generate_rows(columns: &[(String, Generator)], rows: u64, entropy: &mut Entropy) -> Result<Vec<Vec<Value>>, Box<dyn std::error::Error>> {
+    let mut out = Vec::with_capacity(rows as usize);
+    for _ in 0..rows {
+        let mut row = Vec::with_capacity(columns.len());
+        for (_, generator) in columns.iter() {
+            row.push(generator.generate(entropy)?);
+        }
+        out.push(row);
+    }
+    Ok(out)
+}
+
+pub fn generate_test_data(cmd: String) {
+    let input: GenerateTestDataInput = match notify::parse_input(&cmd) {
+        Some(input) => input,
+        None => return,
+    };
+    if input.columns.is_empty() || input.rows == 0 {
+        notify::error("Invalid input: at least one column and one row are required");
+        return;
+    }
+    for (name, generator) in input.columns.iter() {
+        if let Err(err) = generator.validate() {
+            notify::error(&format!("Invalid generator for column {}: {}", name, err));
+            return;
+        }
+    }
+
+    let settings = match DeploymentSettings::load() {
+        Ok(s) => s,
+        Err(err) => {
+            notify::error_with_code(&format!("Failed to load settings: {}", err), database::DatabaseError::code_of(err.as_ref()));
+            return;
+        }
+    };
+    if input.rows > settings.max_generated_rows {
+        notify::error(&format!("Requested {} rows but the deployment allows at most {}", input.rows, settings.max_generated_rows));
+        return;
+    }
+
+    let mut client: database::Client = match database::Client::load(input.database_id.clone()) {
+        Ok(c) => c,
+        Err(err) => {
+            notify::error_with_code(&format!("Failed to load client: {}", err), err.code());
+            return;
+        }
+    };
+    // Safe mode: never seed a database flagged as production
+    if client.is_production() {
+        notify::error("Refusing to generate test data on a database tagged production");
+        return;
+    }
+    match client.connect() {
+        Ok(_) => (),
+        Err(err) => {
+            notify::error_with_code(&format!("Failed to connect to client: {}", err), err.code());
+            return;
+        }
+    };
+
+    let mut entropy = Entropy::new();
+    let columns: Vec<(String, Generator)> = input.columns.into_iter().collect();
+    let rows = match generate_rows(&columns, input.rows, &mut entropy) {
+        Ok(r) => r,
+        Err(err) => {
+            notify::error_with_code(&format!("Failed to generate rows: {}", err), database::DatabaseError::code_of(err.as_ref()));
+            return;
+        }
+    };
+    let mut budget = match ExecutionBudget::from_settings() {
+        Ok(b) => b,
+        Err(err) => {
+            notify::error_with_code(&format!("Failed to load settings: {}", err), database::DatabaseError::code_of(err.as_ref()));
+            return;
+        }
+    };
+    let column_names: Vec<String> = columns.iter().map(|(name, _)| name.clone()).collect();
+    match client.bulk_insert(&input.table, &column_names, rows, INSERT_CHUNK_SIZE, &mut budget) {
+        Ok(inserted) if inserted < input.rows => {
+            // Generated rows are random, so resuming is a matter of asking for the remaining count
+            notify::result(&json!({ "table": input.table, "inserted": inserted, "partial": true, "resume": { "rows": input.rows - inserted } }));
+        },
+        Ok(inserted) => {
+            notify::result(&json!({ "table": input.table, "inserted": inserted }));
+        },
+        Err(err) => {
+            notify::error_with_code(&format!("Failed to insert generated rows: {}", err), database::DatabaseError::code_of(err.as_ref()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn counter_source(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 37 + 11) as u8).collect()
+    }
+
+    #[test]
+    fn test_format_timestamp() {
+        assert_eq!(format_timestamp(0), "1970-01-01 00:00:00");
+        assert_eq!(format_timestamp(951_782_400), "2000-02-29 00:00:00");
+        assert_eq!(format_timestamp(1_700_000_000), "2023-11-14 22:13:20");
+        assert_eq!(format_timestamp(-1), "1969-12-31 23:59:59");
+    }
+
+    #[test]
+    fn test_generators_stay_in_bounds() {
+        let mut entropy = Entropy::with_source(counter_source);
+        for _ in 0..200 {
+            let v = Generator::Integer { min: -5, max: 5 }.generate(&mut entropy).unwrap();
+            assert!((-5..=5).contains(&v.as_i64().unwrap()));
+
+            let d = Generator::Decimal { min: 1.5, max: 2.25, scale: 2 }.generate(&mut entropy).unwrap();
+            let parsed: f64 = d.as_str().unwrap().parse().unwrap();
+            assert!((1.5..=2.25).contains(&parsed));
+            assert_eq!(d.as_str().unwrap().split('.').nth(1).unwrap().len(), 2);
+        }
+    }
+
+    #[test]
+    fn test_uuid_and_email_shape() {
+        let mut entropy = Entropy::with_source(counter_source);
+        let uuid = Generator::Uuid.generate(&mut entropy).unwrap();
+        let uuid = uuid.as_str().unwrap();
+        assert_eq!(uuid.len(), 36);
+        assert_eq!(&uuid[14..15], "4");
+        let email = Generator::Email { domain: Some("corp.test".to_string()) }.generate(&mut entropy).unwrap();
+        assert!(email.as_str().unwrap().ends_with("@corp.test"));
+    }
+
+    #[test]
+    fn test_weighted_choice_skips_zero_weights() {
+        let mut entropy = Entropy::with_source(counter_source);
+        let generator = Generator::Choice { values: vec![json!("a"), json!("b")], weights: Some(vec![0, 3]) };
+        for _ in 0..50 {
+            assert_eq!(generator.generate(&mut entropy).unwrap(), json!("b"));
+        }
+        assert!(Generator::Choice { values: vec![json!("a")], weights: Some(vec![0]) }.validate().is_err());
+    }
+}
@@ -0,0 +1,101 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{database::PostGreResponse, host::normalize_boolean};
+
+// SSL state of the session the handle belongs to
+pub const TLS_STATUS_QUERY: &str = "SELECT ssl, version, cipher FROM pg_stat_ssl WHERE pid = pg_backend_pid()";
+
+// Whether a client may be used over a connection that is not known to be encrypted
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RequireTls {
+    #[default]
+    Off,
+    // Every call on an unencrypted connection carries a warning
+    On,
+    // Connections not known to be encrypted are refused
+    Strict,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TlsStatus {
+    // None when the host does not expose pg_stat_ssl
+    pub encrypted: Option<bool>,
+    #[serde(default)]
+    pub version: Option<String>,
+    #[serde(default)]
+    pub cipher: Option<String>,
+    pub checked_at: u64,
+}
+
+impl TlsStatus {
+    // Result of TLS_STATUS_QUERY; a failed query or an unexpected row leaves the status unknown
+    pub fn parse(response: Option<&PostGreResponse<Vec<Vec<Value>>>>, checked_at: u64) -> TlsStatus {
+        let mut status = TlsStatus { checked_at, ..Default::default() };
+        let response = match response {
+            Some(response) => response,
+            None => return status,
+        };
+        let cell = |row: &[Value], name: &str| response.fields.iter().position(|f| f.name == name).and_then(|i| row.get(i).cloned());
+        if let Some(row) = response.resultset.first() {
+            status.encrypted = cell(row, "ssl").and_then(|v| normalize_boolean(&v));
+            status.version = cell(row, "version").and_then(|v| v.as_str().map(|s| s.to_string()));
+            status.cipher = cell(row, "cipher").and_then(|v| v.as_str().map(|s| s.to_string()));
+        }
+        status
+    }
+
+    // Recorded again only when the connection properties differ, not on every check
+    pub fn same_connection(&self, other: &TlsStatus) -> bool {
+        self.encrypted == other.encrypted && self.version == other.version && self.cipher == other.cipher
+    }
+}
+
+// Ok with a warning when the connection is not known to be encrypted and TLS is required, Err in strict mode
+pub fn enforce(require: RequireTls, status: Option<&TlsStatus>, database_id: &str) -> Result<Option<String>, String> {
+    let state = match status.and_then(|s| s.encrypted) {
+        Some(true) => return Ok(None),
+        Some(false) => "is not encrypted",
+        None => "could not be verified as encrypted",
+    };
+    match require {
+        RequireTls::Off => Ok(None),
+        RequireTls::On => Ok(Some(format!("The connection to database {} {} (require_tls)", database_id, state))),
+        RequireTls::Strict => Err(format!("Refusing the connection to database {}: it {} and require_tls is strict", database_id, state)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn response(rows: Value) -> PostGreResponse<Vec<Vec<Value>>> {
+        serde_json::from_value(json!({
+            "fields": [{ "name": "ssl" }, { "name": "version" }, { "name": "cipher" }],
+            "resultset": rows,
+        })).unwrap()
+    }
+
+    #[test]
+    fn test_parse() {
+        let status = TlsStatus::parse(Some(&response(json!([["t", "TLSv1.3", "TLS_AES_256_GCM_SHA384"]]))), 7);
+        assert_eq!(status, TlsStatus { encrypted: Some(true), version: Some("TLSv1.3".to_string()), cipher: Some("TLS_AES_256_GCM_SHA384".to_string()), checked_at: 7 });
+        assert_eq!(TlsStatus::parse(Some(&response(json!([[false, null, null]]))), 7).encrypted, Some(false));
+        assert_eq!(TlsStatus::parse(Some(&response(json!([]))), 7).encrypted, None);
+        assert_eq!(TlsStatus::parse(None, 7).encrypted, None);
+    }
+
+    #[test]
+    fn test_enforce() {
+        let plain = TlsStatus { encrypted: Some(false), ..Default::default() };
+        let encrypted = TlsStatus { encrypted: Some(true), ..Default::default() };
+        assert_eq!(enforce(RequireTls::Strict, Some(&encrypted), "db"), Ok(None));
+        assert_eq!(enforce(RequireTls::Off, Some(&plain), "db"), Ok(None));
+        assert_eq!(enforce(RequireTls::On, Some(&plain), "db"), Ok(Some("The connection to database db is not encrypted (require_tls)".to_string())));
+        assert_eq!(enforce(RequireTls::Strict, None, "db").unwrap_err(), "Refusing the connection to database db: it could not be verified as encrypted and require_tls is strict");
+        assert_eq!(serde_json::from_value::<RequireTls>(json!("strict")).unwrap(), RequireTls::Strict);
+    }
+}
@@ -1,61 +1,475 @@
 use serde_json::Value;
+use crate::{crypto::{parse_ciphertext, AES_GCM_IV_SIZE, AES_GCM_TAG_SIZE}, database::Field, host::sql_cast, notify::{self, Channel, Frame}, storage};
 
-// pub fn get_client_id() -> String {
-//     let client_id = match klave::context::get("sender") {
-//             Ok(id) => id,
-//             Err(e) => {
-//                 klave::notifier::send_string(&format!("Failed to get client ID: {}", e));
-//                 return String::new();
-//             }
-//         };
-//     return client_id;
-// }
+pub mod expr;
+pub mod pattern;
+
+pub fn get_client_id() -> String {
+    let client_id = match crate::runtime::context::get("sender") {
+            Ok(id) => id,
+            Err(e) => {
+                notify::warning(&format!("Failed to get client ID: {}", e));
+                return String::new();
+            }
+        };
+    client_id
+}
+
+// Trusted time of the current call as provided by the Klave host, 0 when unavailable
+pub fn get_trusted_time() -> u64 {
+    match crate::runtime::context::get("trusted_time") {
+        Ok(time) => time.trim().parse::<u64>().unwrap_or(0),
+        Err(_) => 0,
+    }
+}
+
+// How user input embedded in a LIKE/ILIKE pattern is interpreted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LikeInput {
+    // The input is matched literally, its wildcards and escape characters are escaped
+    Literal,
+    // The input is already a pattern written by the caller and is passed through
+    Pattern,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LikePattern {
+    pub pattern: String,
+    pub escape_char: char,
+}
+
+impl LikePattern {
+    // SQL fragment to place after LIKE/ILIKE: the quoted pattern followed by its ESCAPE clause
+    pub fn to_sql(&self) -> String {
+        format!("'{}' ESCAPE '{}'", self.pattern.replace('\'', "''"), self.escape_char.to_string().replace('\'', "''"))
+    }
+}
+
+// Escapes user input for use in a LIKE/ILIKE pattern. In literal mode `%`, `_` and the escape
+// character itself are prefixed with the escape character; in pattern mode the input is kept as is.
+pub fn escape_like(input: &str, escape_char: char, mode: LikeInput) -> LikePattern {
+    let pattern = match mode {
+        LikeInput::Pattern => input.to_string(),
+        LikeInput::Literal => {
+            let mut escaped = String::with_capacity(input.len());
+            for c in input.chars() {
+                if c == '%' || c == '_' || c == escape_char {
+                    escaped.push(escape_char);
+                }
+                escaped.push(c);
+            }
+            escaped
+        }
+    };
+    LikePattern { pattern, escape_char }
+}
+
+// Unquoted SQL identifier, safe to splice into generated statements
+pub fn is_plain_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+// Longest identifier PostgreSQL keeps as is, longer ones are silently truncated (NAMEDATALEN - 1)
+pub const MAX_IDENTIFIER_BYTES: usize = 63;
+
+// Double-quoted form of a table or column name to splice into generated SQL, e.g. public."Order Items"
+// gives "public"."Order Items". Each of the one or two dot-separated parts is either plain (letters, digits,
+// underscore) or already double-quoted. Names are the ones stored in the catalog: case is kept, not folded.
+pub fn quote_ident(name: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let invalid = |reason: &str| -> Box<dyn std::error::Error> { format!("Invalid identifier {:?}: {}", name, reason).into() };
+    let mut parts: Vec<String> = Vec::new();
+    let mut rest = name;
+    loop {
+        let (part, after) = match rest.strip_prefix('"') {
+            Some(quoted) => {
+                // The closing quote is the first one not doubled
+                let mut part = String::new();
+                let mut chars = quoted.char_indices().peekable();
+                let mut end = None;
+                while let Some((i, c)) = chars.next() {
+                    if c != '"' {
+                        part.push(c);
+                    } else if chars.peek().map(|(_, next)| *next) == Some('"') {
+                        part.push('"');
+                        chars.next();
+                    } else {
+                        end = Some(i + 1);
+                        break;
+                    }
+                }
+                match end {
+                    Some(end) => (part, &quoted[end..]),
+                    None => return Err(invalid("unterminated quoted identifier")),
+                }
+            },
+            None => {
+                let end = rest.find('.').unwrap_or(rest.len());
+                let plain = &rest[..end];
+                if !is_plain_identifier(plain) {
+                    return Err(invalid("only letters, digits and underscores are allowed outside double quotes"));
+                }
+                (plain.to_string(), &rest[end..])
+            }
+        };
+        if part.is_empty() || part.contains('\0') {
+            return Err(invalid("empty identifier"));
+        }
+        if part.len() > MAX_IDENTIFIER_BYTES {
+            return Err(invalid(&format!("longer than {} bytes", MAX_IDENTIFIER_BYTES)));
+        }
+        parts.push(format!("\"{}\"", part.replace('"', "\"\"")));
+        match after.strip_prefix('.') {
+            Some(next) if parts.len() < 2 => rest = next,
+            Some(_) => return Err(invalid("only a schema prefix may qualify a name")),
+            None if after.is_empty() => break,
+            None => return Err(invalid("unexpected characters after a quoted identifier")),
+        }
+    }
+    Ok(parts.join("."))
+}
+
+pub fn quote_idents(names: &[String]) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    names.iter().map(|name| quote_ident(name)).collect()
+}
+
+const LEDGER_KEY_SEPARATOR: char = '/';
+
+// Ledger key made of the given names. Each one is percent-encoded, separator, '%' and '-' included,
+// so that two distinct lists of names never give the same key.
+pub fn ledger_key(components: &[&str]) -> String {
+    let encoded: Vec<String> = components.iter().map(|component| {
+        let mut out = String::with_capacity(component.len());
+        for byte in component.bytes() {
+            if byte.is_ascii_alphanumeric() || byte == b'_' || byte == b'.' {
+                out.push(byte as char);
+            } else {
+                out.push_str(&format!("%{:02X}", byte));
+            }
+        }
+        out
+    }).collect();
+    encoded.join(&LEDGER_KEY_SEPARATOR.to_string())
+}
+
+// Names a ledger_key was made of; keys older versions composed by concatenation come back whole
+pub fn ledger_key_components(key: &str) -> Vec<String> {
+    key.split(LEDGER_KEY_SEPARATOR).map(|component| {
+        let bytes = component.as_bytes();
+        let mut out = Vec::with_capacity(bytes.len());
+        let mut i = 0;
+        while i < bytes.len() {
+            let decoded = if bytes[i] == b'%' { component.get(i + 1..i + 3).and_then(|h| u8::from_str_radix(h, 16).ok()) } else { None };
+            match decoded {
+                Some(byte) => {
+                    out.push(byte);
+                    i += 3;
+                },
+                None => {
+                    out.push(bytes[i]);
+                    i += 1;
+                }
+            }
+        }
+        String::from_utf8_lossy(&out).into_owned()
+    }).collect()
+}
+
+// Record under its key, or under the key an older version composed for it by concatenating the names
+pub fn ledger_get(table: &str, key: &str, legacy_key: &str) -> Option<Vec<u8>> {
+    let ledger = crate::runtime::ledger::get_table(table);
+    ledger.get(key).ok().or_else(|| if legacy_key != key { ledger.get(legacy_key).ok() } else { None })
+}
+
+// Drops the record an older version stored under the legacy key, once it was written under its key
+pub fn remove_legacy_key(table: &str, key: &str, legacy_key: &str) {
+    let ledger = crate::runtime::ledger::get_table(table);
+    if legacy_key != key && ledger.get(legacy_key).is_ok() {
+        if let Err(err) = storage::ledger_remove(table, legacy_key) {
+            notify::warning(&format!("Failed to remove legacy ledger key {}: {}", legacy_key, err));
+        }
+    }
+}
+
+// Name of a type code found in Field::field_type: the codes of the host driver (3, 12 and 15, named after the
+// widest type of their kind) and the PostgreSQL OIDs of the common types. None for any other code.
+pub fn pg_type_name(code: u32) -> Option<&'static str> {
+    let name = match code {
+        3 => "bigint",
+        12 => "text",
+        15 => "numeric",
+        16 => "boolean",
+        17 => "bytea",
+        20 => "bigint",
+        21 => "smallint",
+        23 => "integer",
+        25 => "text",
+        114 => "json",
+        700 => "real",
+        701 => "double precision",
+        1042 => "character",
+        1043 => "character varying",
+        1082 => "date",
+        1083 => "time",
+        1114 => "timestamp",
+        1184 => "timestamptz",
+        1186 => "interval",
+        1266 => "timetz",
+        1700 => "numeric",
+        2950 => "uuid",
+        3802 => "jsonb",
+        _ => return None,
+    };
+    Some(name)
+}
+
+// Renders a single value as a SQL literal, quotes in strings are doubled
+pub fn sql_literal(value: &Value) -> String {
+    match value {
+        Value::String(s) => format!("'{}'", s.replace('\'', "''")),
+        Value::Number(n) => n.to_string(),
+        Value::Bool(b) => b.to_string(),
+        Value::Null => "NULL".to_string(),
+        other => format!("'{}'", other.to_string().replace('\'', "''")),
+    }
+}
+
+// True for a value carrying the version marker of a ciphertext format this build reads, in front of
+// the hex of an IV, a ciphertext and a tag. Unmarked legacy ciphertext only has its shape, see leaks::cell_shape.
+pub fn is_encrypted_value(value: &Value) -> bool {
+    let encoded = match value {
+        Value::String(encoded) => encoded,
+        _ => return false,
+    };
+    match parse_ciphertext(encoded) {
+        Ok((_, payload)) => payload.len() < encoded.len()
+            && payload.len() % 2 == 0
+            && payload.len() / 2 > AES_GCM_IV_SIZE + AES_GCM_TAG_SIZE
+            && payload.bytes().all(|b| b.is_ascii_hexdigit()),
+        Err(_) => false,
+    }
+}
 
 pub fn get_serde_value_into_bytes(value: &serde_json::Value) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
     let bytes = match serde_json::to_vec(value) {
         Ok(b) => b,
         Err(e) => {
-            klave::notifier::send_string(&format!("Failed to serialize serde_json::Value to bytes: {}", e));
+            notify::warning(&format!("Failed to serialize serde_json::Value to bytes: {}", e));
             return Err(e.into());
         }
     };
     Ok(bytes)
 }
 
-pub fn flatten_vec_of_vec_values_to_single_string(data: Vec<Vec<Value>>) -> String {
-    let inner_strings: Vec<String> = data
-        .into_iter() // Take ownership of the outer Vec
-        .map(|inner_vec| { // For each inner Vec<Value>
-            let values_as_strings: Vec<String> = inner_vec
-                .into_iter() // Take ownership of inner Vec's elements
-                .map(|value| { // For each Value
-                    // Convert the Value enum variant into its string representation
-                    match value {
-                        Value::String(s) => format!("'{}'",s),
-                        Value::Number(n) => n.to_string(),
-                        Value::Bool(b) => b.to_string(),
-                        Value::Null => "null".to_string(),
-                        Value::Array(arr) => {
-                            arr.into_iter()
-                                .map(|v| v.to_string())
-                                .collect::<Vec<String>>()
-                                .join(";")
-                        },
-                        Value::Object(obj) => {
-                            obj.into_iter()
-                                .map(|(k, v)| format!("{}:{}", k, v.to_string()))
-                                .collect::<Vec<String>>()
-                                .join(";")
-                        },
-                    }
-                })
-                .collect(); // Collect all processed Value strings into a Vec<String>
+// Rows of a VALUES list: (v1,v2),(v3,v4). Cells are rendered by sql_literal; when fields are given, the cells
+// of the first row carry the cast of their field so that the whole column gets the type of the original one.
+pub fn flatten_vec_of_vec_values_to_single_string(data: Vec<Vec<Value>>, fields: &[Field]) -> String {
+    data.iter().enumerate().map(|(i, row)| {
+        let cells: Vec<String> = row.iter().enumerate().map(|(j, value)| {
+            match fields.get(j).and_then(sql_cast).filter(|_| i == 0) {
+                Some(cast) => format!("{}::{}", sql_literal(value), cast),
+                None => sql_literal(value),
+            }
+        }).collect();
+        format!("({})", cells.join(","))
+    }).collect::<Vec<String>>().join(",")
+}
+
+// What a client does with a notification stream: keeps the frames of one invocation on the requested
+// channels in seq order. Messages that are not frames are skipped; a gap in the sequence means frames
+// were lost and is reported as an error.
+pub fn reassemble_frames(messages: &[Value], trace_id: &str, channels: &[Channel]) -> Result<Vec<Frame>, String> {
+    let mut frames: Vec<Frame> = messages.iter()
+        .filter_map(|m| serde_json::from_value::<Frame>(m.clone()).ok())
+        .filter(|f| f.trace_id == trace_id)
+        .collect();
+    frames.sort_by_key(|f| f.seq);
+    frames.dedup_by_key(|f| f.seq);
+    if let Some((expected, _)) = frames.iter().enumerate().find(|(i, f)| f.seq != *i as u64) {
+        return Err(format!("frame {} of trace {} is missing", expected, trace_id));
+    }
+    Ok(frames.into_iter().filter(|f| channels.contains(&f.channel)).collect())
+}
 
-            // Join the values for this inner_vec with a comma, then wrap in parentheses
-            format!("({})", values_as_strings.join(","))
-        })
-        .collect(); // Collect all the resulting parenthesized strings into a Vec<String>
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
 
-    // Finally, join all these parenthesized strings into one single String
-    inner_strings.join(",")
-}
\ No newline at end of file
+    use crate::notify::Framer;
+
+    use super::*;
+
+    // Frames of a two-batch encryption run that hit a warning, as sent by the notifier
+    fn encryption_run(trace_id: &str) -> Vec<Value> {
+        let mut framer = Framer::new(trace_id);
+        let mut frames = vec![
+            framer.frame(Channel::Progress, json!({ "table": "users", "column": "email", "rows": 500 })),
+            framer.frame(Channel::Warning, json!("Failed to encrypt: connection reset")),
+            framer.frame(Channel::Progress, json!({ "table": "users", "column": "email", "rows": 120 })),
+            framer.frame(Channel::Progress, json!({ "table": "users", "column": "email", "complete": true })),
+            framer.frame(Channel::Result, json!({ "partial": false })),
+            // A late result never reaches the result channel
+            framer.frame(Channel::Result, json!({ "error": "late" })),
+        ];
+        frames.extend(framer.finish());
+        frames.iter().map(|f| serde_json::to_value(f).unwrap()).collect()
+    }
+
+    #[test]
+    fn test_ledger_keys_never_collide() {
+        let max = "a".repeat(63);
+        let names: Vec<Vec<&str>> = vec![
+            vec!["db", "orders:prod"],
+            vec!["db:orders", "prod"],
+            vec!["db", "orders", "prod"],
+            vec!["db", "orders/prod"],
+            vec!["db/orders", "prod"],
+            vec!["db", "orders%2Fprod"],
+            vec!["db", "orders#1"],
+            vec!["db", "orders", "1"],
+            vec!["db", "-"],
+            vec!["db", ""],
+            vec!["db"],
+            vec!["db", "commandes_été"],
+            vec!["db", "commandes_e\u{301}te"],
+            vec!["db", "表"],
+            vec!["db", &max],
+            vec!["db", &max, ""],
+        ];
+        let keys: std::collections::HashSet<String> = names.iter().map(|n| ledger_key(n)).collect();
+        assert_eq!(keys.len(), names.len());
+        assert_eq!(ledger_key(&["db", "orders:prod"]), "db/orders%3Aprod");
+        assert_eq!(ledger_key(&["00019000", "-"]), "00019000/%2D");
+        // Plain names keep the key older versions composed
+        assert_eq!(ledger_key(&["db", "users", "42", "00000000000000000007.0"]), "db/users/42/00000000000000000007.0");
+        assert!(ledger_key(&["db", &max]).len() < 100);
+        for name in names.iter() {
+            assert_eq!(&ledger_key_components(&ledger_key(name)), name);
+        }
+    }
+
+    #[test]
+    fn test_reassemble_frames_of_an_encryption_run() {
+        let mut stream = encryption_run("a");
+        stream.extend(encryption_run("b"));
+        stream.push(json!("not a frame"));
+        stream.reverse();
+
+        let results = reassemble_frames(&stream, "a", &[Channel::Result]).unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].payload, json!({ "partial": false }));
+
+        let progress = reassemble_frames(&stream, "a", &[Channel::Progress]).unwrap();
+        assert_eq!(progress.iter().map(|f| f.seq).collect::<Vec<_>>(), vec![0, 2, 3]);
+
+        let warnings = reassemble_frames(&stream, "a", &[Channel::Warning]).unwrap();
+        assert_eq!(warnings.len(), 2);
+        assert_eq!(warnings[1].payload, json!({ "superseded_result": { "error": "late" } }));
+    }
+
+    #[test]
+    fn test_large_numbers_in_sql_and_plaintext() {
+        let row: Value = serde_json::from_str("[9007199254740993, 12345678901234567890123456.0123456789]").unwrap();
+        let cells = row.as_array().unwrap().clone();
+        assert_eq!(sql_literal(&cells[0]), "9007199254740993");
+        assert_eq!(flatten_vec_of_vec_values_to_single_string(vec![cells.clone()], &[]), "(9007199254740993,12345678901234567890123456.0123456789)");
+        // Plaintext handed to the cipher is byte-for-byte what was read
+        let bytes = get_serde_value_into_bytes(&cells[1]).unwrap();
+        assert_eq!(bytes, b"12345678901234567890123456.0123456789");
+        let back: Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(back, cells[1]);
+    }
+
+    #[test]
+    fn test_reassemble_frames_reports_gaps() {
+        let mut stream = encryption_run("a");
+        stream.remove(2);
+        assert!(reassemble_frames(&stream, "a", &[Channel::Result]).is_err());
+    }
+
+    #[test]
+    fn test_quote_ident() {
+        assert_eq!(quote_ident("users").unwrap(), "\"users\"");
+        assert_eq!(quote_ident("Users").unwrap(), "\"Users\"");
+        assert_eq!(quote_ident("public.\"Order Items\"").unwrap(), "\"public\".\"Order Items\"");
+        assert_eq!(quote_ident("\"Sales\".orders").unwrap(), "\"Sales\".\"orders\"");
+        assert_eq!(quote_ident("\"say \"\"hi\"\"\"").unwrap(), "\"say \"\"hi\"\"\"");
+        assert_eq!(quote_ident("\"a.b\"").unwrap(), "\"a.b\"");
+        assert_eq!(quote_ident(&"a".repeat(MAX_IDENTIFIER_BYTES)).unwrap().len(), MAX_IDENTIFIER_BYTES + 2);
+
+        for invalid in ["users; DROP TABLE x", "", "1users", "a.b.c", "public.", ".users", "\"open", "\"\"", "\"a\"b", "\"a\"\"", "user-name", "é"] {
+            assert!(quote_ident(invalid).is_err(), "{}", invalid);
+        }
+        assert!(quote_ident(&"a".repeat(MAX_IDENTIFIER_BYTES + 1)).is_err());
+        assert!(quote_ident(&format!("\"{}\"", "é".repeat(32))).is_err());
+        assert_eq!(quote_idents(&["id".to_string(), "Email".to_string()]).unwrap(), vec!["\"id\"", "\"Email\""]);
+    }
+
+    #[test]
+    fn test_is_encrypted_value() {
+        let payload = "000102030405060708090a0b5a3c6e1f8d2b4a6c8e0f1a2b3c4d5e6f708192a3b4";
+        assert!(is_encrypted_value(&json!(format!("v1:{}", payload))));
+        assert!(is_encrypted_value(&json!(format!("v0:{}", payload))));
+        // Legacy values carry no marker, plaintext may look like anything
+        assert!(!is_encrypted_value(&json!(payload)));
+        assert!(!is_encrypted_value(&json!("v1:not hex")));
+        assert!(!is_encrypted_value(&json!("v1:00ff")));
+        assert!(!is_encrypted_value(&json!(format!("v9:{}", payload))));
+        assert!(!is_encrypted_value(&json!("alice@example.com")));
+        assert!(!is_encrypted_value(&json!(42)));
+        assert!(!is_encrypted_value(&Value::Null));
+    }
+
+    #[test]
+    fn test_values_list() {
+        let rows = vec![
+            vec![json!("1"), json!("O'Brien"), json!(null), json!(true)],
+            vec![json!("2"), json!("line one\nline 'two'"), json!("12.50"), json!(false)],
+            vec![json!(3), json!(""), json!(7), json!(null)],
+        ];
+        assert_eq!(flatten_vec_of_vec_values_to_single_string(rows.clone(), &[]),
+            "('1','O''Brien',NULL,true),('2','line one\nline ''two''','12.50',false),(3,'',7,NULL)");
+
+        // Only the first row is cast, the other rows take the type of the column
+        let mut id = Field::named("id");
+        id.field_type = 3;
+        let mut price = Field::named("price");
+        price.description = Some("numeric".to_string());
+        let mut active = Field::named("active");
+        active.type_name = Some("bool".to_string());
+        let fields = vec![id, Field::named("name"), price, active];
+        assert_eq!(flatten_vec_of_vec_values_to_single_string(rows, &fields),
+            "('1'::bigint,'O''Brien',NULL::numeric,true::boolean),('2','line one\nline ''two''','12.50',false),(3,'',7,NULL)");
+        assert_eq!(flatten_vec_of_vec_values_to_single_string(vec![], &fields), "");
+    }
+
+    #[test]
+    fn test_sql_literal() {
+        assert_eq!(sql_literal(&Value::from("O'Brien")), "'O''Brien'");
+        assert_eq!(sql_literal(&Value::from(42)), "42");
+        assert_eq!(sql_literal(&Value::Null), "NULL");
+    }
+
+    #[test]
+    fn test_escape_like_wildcards() {
+        assert_eq!(escape_like("100%", '\\', LikeInput::Literal).pattern, r"100\%");
+        assert_eq!(escape_like("first_name", '\\', LikeInput::Literal).pattern, r"first\_name");
+        assert_eq!(escape_like("a%b_c", '!', LikeInput::Literal).pattern, "a!%b!_c");
+    }
+
+    #[test]
+    fn test_escape_like_escape_char_and_backslashes() {
+        assert_eq!(escape_like("a!b", '!', LikeInput::Literal).pattern, "a!!b");
+        assert_eq!(escape_like(r"C:\dir\%", '\\', LikeInput::Literal).pattern, r"C:\\dir\\\%");
+        assert_eq!(escape_like(r"\\_", '\\', LikeInput::Literal).pattern, r"\\\\\_");
+        // With another escape character, backslashes are ordinary characters
+        assert_eq!(escape_like(r"C:\dir\", '!', LikeInput::Literal).pattern, r"C:\dir\");
+    }
+
+    #[test]
+    fn test_escape_like_pattern_passthrough_and_sql() {
+        assert_eq!(escape_like("jo%n_", '\\', LikeInput::Pattern).pattern, "jo%n_");
+        assert_eq!(escape_like("O'Brien%", '\\', LikeInput::Literal).to_sql(), r"'O''Brien\%' ESCAPE '\'");
+    }
+}
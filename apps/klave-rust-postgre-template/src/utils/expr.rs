@@ -0,0 +1,794 @@
+use std::collections::BTreeSet;
+
+use serde_json::{Map, Value};
+
+// Small expression language evaluated in the enclave over decrypted rows, e.g.
+// `price * quantity`, `'***@' || split_part(email, '@', 2)` or `length(last_name) > 5`.
+//
+//   expr           := and ("or" and)*
+//   and            := not ("and" not)*
+//   not            := "not" not | comparison
+//   comparison     := concat (("=" | "!=" | "<>" | "<" | "<=" | ">" | ">=") concat)?
+//   concat         := additive ("||" additive)*
+//   additive       := multiplicative (("+" | "-") multiplicative)*
+//   multiplicative := unary (("*" | "/" | "%") unary)*
+//   unary          := "-" unary | primary
+//   primary        := number | 'string' | true | false | null | column | function "(" args ")" | "(" expr ")"
+//
+// Numbers are exact decimals; any operation on null yields null.
+
+// Digits kept after the decimal point of a division, on top of the operands' own scale
+const DIVISION_EXTRA_SCALE: u32 = 6;
+const MAX_SCALE: u32 = 18;
+const MAX_DEPTH: usize = 64;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExprError {
+    pub message: String,
+    pub offset: usize,
+}
+
+impl std::fmt::Display for ExprError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} at offset {}", self.message, self.offset)
+    }
+}
+
+impl std::error::Error for ExprError {}
+
+fn error<T>(message: impl Into<String>, offset: usize) -> Result<T, ExprError> {
+    Err(ExprError { message: message.into(), offset })
+}
+
+// Exact decimal: mantissa * 10^-scale
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Decimal {
+    mantissa: i128,
+    scale: u32,
+}
+
+impl Decimal {
+    pub fn parse(text: &str) -> Option<Decimal> {
+        let text = text.trim();
+        let (negative, unsigned) = match text.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, text),
+        };
+        let (int_part, frac_part) = unsigned.split_once('.').unwrap_or((unsigned, ""));
+        if int_part.is_empty() || frac_part.len() > MAX_SCALE as usize
+            || !int_part.chars().all(|c| c.is_ascii_digit()) || !frac_part.chars().all(|c| c.is_ascii_digit()) {
+            return None;
+        }
+        let mantissa: i128 = format!("{}{}", int_part, frac_part).parse().ok()?;
+        Some(Decimal { mantissa: if negative { -mantissa } else { mantissa }, scale: frac_part.len() as u32 })
+    }
+
+    fn rescale(&self, scale: u32) -> Option<Decimal> {
+        let factor = 10i128.checked_pow(scale.checked_sub(self.scale)?)?;
+        Some(Decimal { mantissa: self.mantissa.checked_mul(factor)?, scale })
+    }
+
+    fn aligned(a: Decimal, b: Decimal) -> Option<(i128, i128, u32)> {
+        let scale = a.scale.max(b.scale);
+        Some((a.rescale(scale)?.mantissa, b.rescale(scale)?.mantissa, scale))
+    }
+
+    pub(crate) fn add(self, other: Decimal) -> Option<Decimal> {
+        let (a, b, scale) = Decimal::aligned(self, other)?;
+        Some(Decimal { mantissa: a.checked_add(b)?, scale })
+    }
+
+    fn sub(self, other: Decimal) -> Option<Decimal> {
+        let (a, b, scale) = Decimal::aligned(self, other)?;
+        Some(Decimal { mantissa: a.checked_sub(b)?, scale })
+    }
+
+    fn mul(self, other: Decimal) -> Option<Decimal> {
+        let scale = self.scale + other.scale;
+        if scale > MAX_SCALE {
+            return None;
+        }
+        Some(Decimal { mantissa: self.mantissa.checked_mul(other.mantissa)?, scale })
+    }
+
+    // Rounds half away from zero to the result scale. The divisor must not be zero.
+    pub(crate) fn div(self, other: Decimal) -> Option<Decimal> {
+        let scale = (self.scale.max(other.scale) + DIVISION_EXTRA_SCALE).min(MAX_SCALE);
+        // self / other = (a * 10^(scale + other.scale - self.scale)) / b, with one extra digit for rounding
+        let shift = (scale + other.scale + 1).checked_sub(self.scale)?;
+        let numerator = self.mantissa.checked_mul(10i128.checked_pow(shift)?)?;
+        let quotient = numerator / other.mantissa;
+        let rounded = if quotient >= 0 { (quotient + 5) / 10 } else { (quotient - 5) / 10 };
+        Some(Decimal { mantissa: rounded, scale })
+    }
+
+    fn rem(self, other: Decimal) -> Option<Decimal> {
+        let (a, b, scale) = Decimal::aligned(self, other)?;
+        Some(Decimal { mantissa: a.checked_rem(b)?, scale })
+    }
+
+    fn is_zero(&self) -> bool {
+        self.mantissa == 0
+    }
+
+    pub(crate) fn cmp(&self, other: &Decimal) -> Option<std::cmp::Ordering> {
+        let (a, b, _) = Decimal::aligned(*self, *other)?;
+        Some(a.cmp(&b))
+    }
+
+    fn to_i64(self) -> Option<i64> {
+        let factor = 10i128.checked_pow(self.scale)?;
+        if self.mantissa % factor != 0 {
+            return None;
+        }
+        i64::try_from(self.mantissa / factor).ok()
+    }
+}
+
+impl std::fmt::Display for Decimal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let digits = self.mantissa.unsigned_abs().to_string();
+        let sign = if self.mantissa < 0 { "-" } else { "" };
+        if self.scale == 0 {
+            return write!(f, "{}{}", sign, digits);
+        }
+        let padded = format!("{:0>width$}", digits, width = self.scale as usize + 1);
+        let (int_part, frac_part) = padded.split_at(padded.len() - self.scale as usize);
+        write!(f, "{}{}.{}", sign, int_part, frac_part)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Scalar {
+    Null,
+    Bool(bool),
+    Number(Decimal),
+    Text(String),
+}
+
+impl Scalar {
+    // Row values are canonical cells: numbers arrive as strings and stay text until used as numbers
+    pub fn from_value(value: &Value) -> Scalar {
+        match value {
+            Value::Null => Scalar::Null,
+            Value::Bool(b) => Scalar::Bool(*b),
+            Value::Number(n) => Decimal::parse(&n.to_string()).map(Scalar::Number).unwrap_or_else(|| Scalar::Text(n.to_string())),
+            Value::String(s) => Scalar::Text(s.clone()),
+            other => Scalar::Text(other.to_string()),
+        }
+    }
+
+    // Numbers are returned as strings, in line with the normalized resultsets
+    pub fn into_value(self) -> Value {
+        match self {
+            Scalar::Null => Value::Null,
+            Scalar::Bool(b) => Value::Bool(b),
+            Scalar::Number(d) => Value::String(d.to_string()),
+            Scalar::Text(s) => Value::String(s),
+        }
+    }
+
+    fn type_name(&self) -> &'static str {
+        match self {
+            Scalar::Null => "null",
+            Scalar::Bool(_) => "boolean",
+            Scalar::Number(_) => "number",
+            Scalar::Text(_) => "text",
+        }
+    }
+
+    fn as_number(&self) -> Option<Decimal> {
+        match self {
+            Scalar::Number(d) => Some(*d),
+            Scalar::Text(s) => Decimal::parse(s),
+            _ => None,
+        }
+    }
+
+    fn as_text(&self) -> String {
+        match self {
+            Scalar::Null => String::new(),
+            Scalar::Bool(b) => b.to_string(),
+            Scalar::Number(d) => d.to_string(),
+            Scalar::Text(s) => s.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+    Concat,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    And,
+    Or,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Literal(Scalar),
+    Column { name: String, offset: usize },
+    Neg { operand: Box<Expr>, offset: usize },
+    Not { operand: Box<Expr>, offset: usize },
+    Binary { op: BinaryOp, left: Box<Expr>, right: Box<Expr>, offset: usize },
+    Call { function: String, args: Vec<Expr>, offset: usize },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Tok {
+    Number(String),
+    Text(String),
+    Ident(String),
+    Op(&'static str),
+    LParen,
+    RParen,
+    Comma,
+}
+
+const OPERATORS: [&str; 14] = ["||", "<=", ">=", "<>", "!=", "=", "<", ">", "+", "-", "*", "/", "%", "!"];
+
+fn lex(source: &str) -> Result<Vec<(Tok, usize)>, ExprError> {
+    let bytes = source.as_bytes();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let c = bytes[i] as char;
+        if c.is_ascii_whitespace() {
+            i += 1;
+        } else if c.is_ascii_digit() || (c == '.' && bytes.get(i + 1).map(|b| b.is_ascii_digit()).unwrap_or(false)) {
+            let start = i;
+            while i < bytes.len() && (bytes[i].is_ascii_digit() || bytes[i] == b'.') {
+                i += 1;
+            }
+            tokens.push((Tok::Number(source[start..i].to_string()), start));
+        } else if c == '\'' {
+            let start = i;
+            let mut text = String::new();
+            i += 1;
+            loop {
+                match source[i..].find('\'') {
+                    Some(end) => {
+                        text.push_str(&source[i..i + end]);
+                        i += end + 1;
+                        // A doubled quote stands for a quote inside the string
+                        if bytes.get(i) == Some(&b'\'') {
+                            text.push('\'');
+                            i += 1;
+                        } else {
+                            break;
+                        }
+                    }
+                    None => return error("unterminated string literal", start),
+                }
+            }
+            tokens.push((Tok::Text(text), start));
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let start = i;
+            while i < bytes.len() && (bytes[i].is_ascii_alphanumeric() || bytes[i] == b'_') {
+                i += 1;
+            }
+            tokens.push((Tok::Ident(source[start..i].to_string()), start));
+        } else if c == '(' {
+            tokens.push((Tok::LParen, i));
+            i += 1;
+        } else if c == ')' {
+            tokens.push((Tok::RParen, i));
+            i += 1;
+        } else if c == ',' {
+            tokens.push((Tok::Comma, i));
+            i += 1;
+        } else {
+            match OPERATORS.iter().find(|op| source[i..].starts_with(**op)) {
+                Some(op) if *op != "!" => {
+                    tokens.push((Tok::Op(op), i));
+                    i += op.len();
+                }
+                _ => return error(format!("unexpected character '{}'", source[i..].chars().next().unwrap_or(c)), i),
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<(Tok, usize)>,
+    pos: usize,
+    end: usize,
+    depth: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Tok> {
+        self.tokens.get(self.pos).map(|(t, _)| t)
+    }
+
+    fn offset(&self) -> usize {
+        self.tokens.get(self.pos).map(|(_, o)| *o).unwrap_or(self.end)
+    }
+
+    fn peek_keyword(&self, keyword: &str) -> bool {
+        matches!(self.peek(), Some(Tok::Ident(name)) if name.eq_ignore_ascii_case(keyword))
+    }
+
+    fn eat_op(&mut self, ops: &[&'static str]) -> Option<(&'static str, usize)> {
+        match self.peek() {
+            Some(Tok::Op(op)) if ops.contains(op) => {
+                let found = (*op, self.offset());
+                self.pos += 1;
+                Some(found)
+            }
+            _ => None,
+        }
+    }
+
+    fn enter(&mut self) -> Result<(), ExprError> {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            return error("expression is nested too deeply", self.offset());
+        }
+        Ok(())
+    }
+
+    fn expr(&mut self) -> Result<Expr, ExprError> {
+        self.enter()?;
+        let mut left = self.and()?;
+        while self.peek_keyword("or") {
+            let offset = self.offset();
+            self.pos += 1;
+            let right = self.and()?;
+            left = Expr::Binary { op: BinaryOp::Or, left: Box::new(left), right: Box::new(right), offset };
+        }
+        self.depth -= 1;
+        Ok(left)
+    }
+
+    fn and(&mut self) -> Result<Expr, ExprError> {
+        let mut left = self.not()?;
+        while self.peek_keyword("and") {
+            let offset = self.offset();
+            self.pos += 1;
+            let right = self.not()?;
+            left = Expr::Binary { op: BinaryOp::And, left: Box::new(left), right: Box::new(right), offset };
+        }
+        Ok(left)
+    }
+
+    fn not(&mut self) -> Result<Expr, ExprError> {
+        if self.peek_keyword("not") {
+            let offset = self.offset();
+            self.pos += 1;
+            self.enter()?;
+            let operand = self.not()?;
+            self.depth -= 1;
+            return Ok(Expr::Not { operand: Box::new(operand), offset });
+        }
+        self.comparison()
+    }
+
+    fn comparison(&mut self) -> Result<Expr, ExprError> {
+        let left = self.concat()?;
+        match self.eat_op(&["=", "!=", "<>", "<", "<=", ">", ">="]) {
+            Some((op, offset)) => {
+                let op = match op {
+                    "=" => BinaryOp::Eq,
+                    "!=" | "<>" => BinaryOp::Ne,
+                    "<" => BinaryOp::Lt,
+                    "<=" => BinaryOp::Le,
+                    ">" => BinaryOp::Gt,
+                    _ => BinaryOp::Ge,
+                };
+                let right = self.concat()?;
+                Ok(Expr::Binary { op, left: Box::new(left), right: Box::new(right), offset })
+            }
+            None => Ok(left),
+        }
+    }
+
+    fn concat(&mut self) -> Result<Expr, ExprError> {
+        let mut left = self.additive()?;
+        while let Some((_, offset)) = self.eat_op(&["||"]) {
+            let right = self.additive()?;
+            left = Expr::Binary { op: BinaryOp::Concat, left: Box::new(left), right: Box::new(right), offset };
+        }
+        Ok(left)
+    }
+
+    fn additive(&mut self) -> Result<Expr, ExprError> {
+        let mut left = self.multiplicative()?;
+        while let Some((op, offset)) = self.eat_op(&["+", "-"]) {
+            let op = if op == "+" { BinaryOp::Add } else { BinaryOp::Sub };
+            let right = self.multiplicative()?;
+            left = Expr::Binary { op, left: Box::new(left), right: Box::new(right), offset };
+        }
+        Ok(left)
+    }
+
+    fn multiplicative(&mut self) -> Result<Expr, ExprError> {
+        let mut left = self.unary()?;
+        while let Some((op, offset)) = self.eat_op(&["*", "/", "%"]) {
+            let op = match op {
+                "*" => BinaryOp::Mul,
+                "/" => BinaryOp::Div,
+                _ => BinaryOp::Rem,
+            };
+            let right = self.unary()?;
+            left = Expr::Binary { op, left: Box::new(left), right: Box::new(right), offset };
+        }
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<Expr, ExprError> {
+        if let Some((_, offset)) = self.eat_op(&["-"]) {
+            self.enter()?;
+            let operand = self.unary()?;
+            self.depth -= 1;
+            return Ok(Expr::Neg { operand: Box::new(operand), offset });
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<Expr, ExprError> {
+        let offset = self.offset();
+        let token = match self.tokens.get(self.pos) {
+            Some((token, _)) => token.clone(),
+            None => return error("unexpected end of expression", offset),
+        };
+        self.pos += 1;
+        match token {
+            Tok::Number(text) => match Decimal::parse(&text) {
+                Some(d) => Ok(Expr::Literal(Scalar::Number(d))),
+                None => error(format!("invalid number '{}'", text), offset),
+            },
+            Tok::Text(text) => Ok(Expr::Literal(Scalar::Text(text))),
+            Tok::LParen => {
+                let inner = self.expr()?;
+                match self.peek() {
+                    Some(Tok::RParen) => {
+                        self.pos += 1;
+                        Ok(inner)
+                    }
+                    _ => error("expected ')'", self.offset()),
+                }
+            }
+            Tok::Ident(name) => {
+                match name.to_ascii_lowercase().as_str() {
+                    "true" => return Ok(Expr::Literal(Scalar::Bool(true))),
+                    "false" => return Ok(Expr::Literal(Scalar::Bool(false))),
+                    "null" => return Ok(Expr::Literal(Scalar::Null)),
+                    "and" | "or" | "not" => return error(format!("unexpected keyword '{}'", name), offset),
+                    _ => (),
+                }
+                if self.peek() != Some(&Tok::LParen) {
+                    return Ok(Expr::Column { name, offset });
+                }
+                self.pos += 1;
+                let mut args = Vec::new();
+                if self.peek() == Some(&Tok::RParen) {
+                    self.pos += 1;
+                } else {
+                    loop {
+                        args.push(self.expr()?);
+                        match self.peek() {
+                            Some(Tok::Comma) => self.pos += 1,
+                            Some(Tok::RParen) => {
+                                self.pos += 1;
+                                break;
+                            }
+                            _ => return error("expected ',' or ')'", self.offset()),
+                        }
+                    }
+                }
+                check_arity(&name, args.len(), offset)?;
+                Ok(Expr::Call { function: name.to_ascii_lowercase(), args, offset })
+            }
+            Tok::Op(op) => error(format!("unexpected operator '{}'", op), offset),
+            Tok::RParen => error("unexpected ')'", offset),
+            Tok::Comma => error("unexpected ','", offset),
+        }
+    }
+}
+
+fn check_arity(function: &str, count: usize, offset: usize) -> Result<(), ExprError> {
+    let (min, max) = match function.to_ascii_lowercase().as_str() {
+        "upper" | "lower" | "length" => (1, 1),
+        "substr" => (2, 3),
+        "split_part" => (3, 3),
+        "coalesce" => (1, usize::MAX),
+        _ => return error(format!("unknown function '{}'", function), offset),
+    };
+    if count < min || count > max {
+        return error(format!("wrong number of arguments for {}: {}", function, count), offset);
+    }
+    Ok(())
+}
+
+pub fn parse(source: &str) -> Result<Expr, ExprError> {
+    let tokens = lex(source)?;
+    let mut parser = Parser { tokens, pos: 0, end: source.len(), depth: 0 };
+    let expr = parser.expr()?;
+    if parser.pos < parser.tokens.len() {
+        return error("unexpected trailing input", parser.offset());
+    }
+    Ok(expr)
+}
+
+impl Expr {
+    // Columns referenced by the expression
+    pub fn columns(&self) -> BTreeSet<String> {
+        let mut columns = BTreeSet::new();
+        self.collect_columns(&mut columns);
+        columns
+    }
+
+    fn collect_columns(&self, columns: &mut BTreeSet<String>) {
+        match self {
+            Expr::Literal(_) => (),
+            Expr::Column { name, .. } => {
+                columns.insert(name.clone());
+            }
+            Expr::Neg { operand, .. } | Expr::Not { operand, .. } => operand.collect_columns(columns),
+            Expr::Binary { left, right, .. } => {
+                left.collect_columns(columns);
+                right.collect_columns(columns);
+            }
+            Expr::Call { args, .. } => args.iter().for_each(|a| a.collect_columns(columns)),
+        }
+    }
+
+    pub fn evaluate(&self, row: &Map<String, Value>) -> Result<Scalar, ExprError> {
+        match self {
+            Expr::Literal(value) => Ok(value.clone()),
+            Expr::Column { name, offset } => match row.get(name) {
+                Some(value) => Ok(Scalar::from_value(value)),
+                None => error(format!("unknown column '{}'", name), *offset),
+            },
+            Expr::Neg { operand, offset } => match operand.evaluate(row)? {
+                Scalar::Null => Ok(Scalar::Null),
+                value => match value.as_number() {
+                    Some(d) => Ok(Scalar::Number(Decimal { mantissa: -d.mantissa, scale: d.scale })),
+                    None => error(format!("cannot negate {}", value.type_name()), *offset),
+                },
+            },
+            Expr::Not { operand, offset } => match operand.evaluate(row)? {
+                Scalar::Null => Ok(Scalar::Null),
+                Scalar::Bool(b) => Ok(Scalar::Bool(!b)),
+                value => error(format!("'not' expects a boolean, got {}", value.type_name()), *offset),
+            },
+            Expr::Binary { op, left, right, offset } => evaluate_binary(*op, left.evaluate(row)?, right.evaluate(row)?, *offset),
+            Expr::Call { function, args, offset } => {
+                let values = args.iter().map(|a| a.evaluate(row)).collect::<Result<Vec<Scalar>, ExprError>>()?;
+                evaluate_call(function, values, *offset)
+            }
+        }
+    }
+}
+
+fn evaluate_binary(op: BinaryOp, left: Scalar, right: Scalar, offset: usize) -> Result<Scalar, ExprError> {
+    match op {
+        BinaryOp::And | BinaryOp::Or => {
+            let as_bool = |v: &Scalar| match v {
+                Scalar::Null => Ok(None),
+                Scalar::Bool(b) => Ok(Some(*b)),
+                other => error(format!("'{}' expects booleans, got {}", if op == BinaryOp::And { "and" } else { "or" }, other.type_name()), offset),
+            };
+            let (l, r) = (as_bool(&left)?, as_bool(&right)?);
+            // Three-valued logic as in SQL
+            let result = match op {
+                BinaryOp::And => match (l, r) {
+                    (Some(false), _) | (_, Some(false)) => Some(false),
+                    (Some(true), Some(true)) => Some(true),
+                    _ => None,
+                },
+                _ => match (l, r) {
+                    (Some(true), _) | (_, Some(true)) => Some(true),
+                    (Some(false), Some(false)) => Some(false),
+                    _ => None,
+                },
+            };
+            return Ok(result.map(Scalar::Bool).unwrap_or(Scalar::Null));
+        }
+        _ => (),
+    }
+    if left == Scalar::Null || right == Scalar::Null {
+        return Ok(Scalar::Null);
+    }
+    match op {
+        BinaryOp::Concat => Ok(Scalar::Text(format!("{}{}", left.as_text(), right.as_text()))),
+        BinaryOp::Add | BinaryOp::Sub | BinaryOp::Mul | BinaryOp::Div | BinaryOp::Rem => {
+            let (a, b) = match (left.as_number(), right.as_number()) {
+                (Some(a), Some(b)) => (a, b),
+                _ => return error(format!("arithmetic on {} and {}", left.type_name(), right.type_name()), offset),
+            };
+            if matches!(op, BinaryOp::Div | BinaryOp::Rem) && b.is_zero() {
+                return error("division by zero", offset);
+            }
+            let result = match op {
+                BinaryOp::Add => a.add(b),
+                BinaryOp::Sub => a.sub(b),
+                BinaryOp::Mul => a.mul(b),
+                BinaryOp::Div => a.div(b),
+                _ => a.rem(b),
+            };
+            match result {
+                Some(d) => Ok(Scalar::Number(d)),
+                None => error("numeric overflow", offset),
+            }
+        }
+        _ => {
+            let ordering = match (&left, &right) {
+                (Scalar::Text(a), Scalar::Text(b)) => Some(a.cmp(b)),
+                (Scalar::Bool(a), Scalar::Bool(b)) => Some(a.cmp(b)),
+                _ => match (left.as_number(), right.as_number()) {
+                    (Some(a), Some(b)) => a.cmp(&b),
+                    _ => return error(format!("cannot compare {} with {}", left.type_name(), right.type_name()), offset),
+                },
+            };
+            let ordering = match ordering {
+                Some(o) => o,
+                None => return error("numeric overflow", offset),
+            };
+            let result = match op {
+                BinaryOp::Eq => ordering.is_eq(),
+                BinaryOp::Ne => ordering.is_ne(),
+                BinaryOp::Lt => ordering.is_lt(),
+                BinaryOp::Le => ordering.is_le(),
+                BinaryOp::Gt => ordering.is_gt(),
+                _ => ordering.is_ge(),
+            };
+            Ok(Scalar::Bool(result))
+        }
+    }
+}
+
+fn integer_arg(value: &Scalar, function: &str, offset: usize) -> Result<i64, ExprError> {
+    match value.as_number().and_then(|d| d.to_i64()) {
+        Some(n) => Ok(n),
+        None => error(format!("{} expects an integer, got {}", function, value.type_name()), offset),
+    }
+}
+
+fn evaluate_call(function: &str, args: Vec<Scalar>, offset: usize) -> Result<Scalar, ExprError> {
+    if function == "coalesce" {
+        return Ok(args.into_iter().find(|a| *a != Scalar::Null).unwrap_or(Scalar::Null));
+    }
+    if args.contains(&Scalar::Null) {
+        return Ok(Scalar::Null);
+    }
+    let text = args[0].as_text();
+    match function {
+        "upper" => Ok(Scalar::Text(text.to_uppercase())),
+        "lower" => Ok(Scalar::Text(text.to_lowercase())),
+        "length" => Ok(Scalar::Number(Decimal { mantissa: text.chars().count() as i128, scale: 0 })),
+        "substr" => {
+            // 1-based start as in SQL, positions before the first character shorten the length
+            let start = integer_arg(&args[1], function, offset)?;
+            let end = match args.get(2) {
+                Some(len) => {
+                    let len = integer_arg(len, function, offset)?;
+                    if len < 0 {
+                        return error("negative substring length", offset);
+                    }
+                    Some(start.saturating_add(len))
+                }
+                None => None,
+            };
+            let skip = (start - 1).max(0) as usize;
+            let take = match end {
+                Some(end) => ((end - 1).max(0) as usize).saturating_sub(skip),
+                None => usize::MAX,
+            };
+            Ok(Scalar::Text(text.chars().skip(skip).take(take).collect()))
+        }
+        "split_part" => {
+            let delimiter = args[1].as_text();
+            let index = integer_arg(&args[2], function, offset)?;
+            if index < 1 {
+                return error("split_part field position must be greater than zero", offset);
+            }
+            if delimiter.is_empty() {
+                return Ok(Scalar::Text(if index == 1 { text } else { String::new() }));
+            }
+            Ok(Scalar::Text(text.split(delimiter.as_str()).nth(index as usize - 1).unwrap_or("").to_string()))
+        }
+        _ => error(format!("unknown function '{}'", function), offset),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn eval(source: &str) -> Result<Value, ExprError> {
+        let row = json!({ "price": "25.50", "quantity": "3", "email": "jane.doe@example.com", "nickname": null });
+        Ok(parse(source)?.evaluate(row.as_object().unwrap())?.into_value())
+    }
+
+    #[test]
+    fn test_precedence() {
+        assert_eq!(eval("1 + 2 * 3").unwrap(), json!("7"));
+        assert_eq!(eval("(1 + 2) * 3").unwrap(), json!("9"));
+        assert_eq!(eval("-2 * -3 - 1").unwrap(), json!("5"));
+        assert_eq!(eval("1 + 2 || 'x'").unwrap(), json!("3x"));
+        assert_eq!(eval("1 + 1 = 2 and not 3 < 2 or false").unwrap(), json!(true));
+        assert_eq!(eval("10 - 4 - 3").unwrap(), json!("3"));
+    }
+
+    #[test]
+    fn test_exact_decimals() {
+        assert_eq!(eval("price * quantity").unwrap(), json!("76.50"));
+        assert_eq!(eval("0.1 + 0.2").unwrap(), json!("0.3"));
+        assert_eq!(eval("price / 3").unwrap(), json!("8.50000000"));
+        assert_eq!(eval("2 / 3").unwrap(), json!("0.666667"));
+        assert_eq!(eval("-2 / 3").unwrap(), json!("-0.666667"));
+        assert_eq!(eval("7 % 3").unwrap(), json!("1"));
+    }
+
+    #[test]
+    fn test_strings() {
+        assert_eq!(eval("'***@' || split_part(email, '@', 2)").unwrap(), json!("***@example.com"));
+        assert_eq!(eval("substr(email, 1, 4)").unwrap(), json!("jane"));
+        assert_eq!(eval("substr(email, 0, 3)").unwrap(), json!("ja"));
+        assert_eq!(eval("upper(substr(email, 6))").unwrap(), json!("DOE@EXAMPLE.COM"));
+        assert_eq!(eval("length(email) > 10").unwrap(), json!(true));
+        assert_eq!(eval("'it''s'").unwrap(), json!("it's"));
+    }
+
+    #[test]
+    fn test_nulls() {
+        assert_eq!(eval("nickname || 'x'").unwrap(), Value::Null);
+        assert_eq!(eval("coalesce(nickname, 'none')").unwrap(), json!("none"));
+        assert_eq!(eval("nickname = 'x' or true").unwrap(), json!(true));
+    }
+
+    #[test]
+    fn test_division_by_zero() {
+        let err = eval("price / (quantity - 3)").unwrap_err();
+        assert_eq!(err.message, "division by zero");
+        assert_eq!(err.offset, 6);
+        assert!(eval("1 % 0").is_err());
+    }
+
+    #[test]
+    fn test_type_mismatch() {
+        assert_eq!(eval("email * 2").unwrap_err().message, "arithmetic on text and number");
+        assert_eq!(eval("email > 2").unwrap_err().message, "cannot compare text with number");
+        assert!(eval("not price").is_err());
+        assert!(eval("true and 1").is_err());
+        assert!(eval("substr(email, 'a')").is_err());
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(parse("1 +").unwrap_err().offset, 3);
+        assert!(parse("(1 + 2").is_err());
+        assert!(parse("1 2").is_err());
+        assert!(parse("'open").is_err());
+        assert!(parse("unknown_fn(1)").is_err());
+        assert!(parse("upper(1, 2)").is_err());
+        assert!(parse("a # b").is_err());
+        assert!(parse(&"(".repeat(200)).is_err());
+        assert_eq!(eval("missing + 1").unwrap_err().message, "unknown column 'missing'");
+    }
+
+    #[test]
+    fn test_referenced_columns() {
+        let expr = parse("price * quantity || upper(email)").unwrap();
+        assert_eq!(expr.columns().into_iter().collect::<Vec<_>>(), vec!["email", "price", "quantity"]);
+    }
+
+    #[test]
+    fn test_overflow() {
+        assert_eq!(eval("99999999999999999999 * 99999999999999999999").unwrap_err().message, "numeric overflow");
+    }
+}
@@ -0,0 +1,322 @@
+// Regular expressions for column validation rules, matched in the enclave before a value is encrypted.
+// The supported subset covers what validation patterns use in practice:
+//
+//   literals and escapes (\. \\ \d \w \s and their negations \D \W \S), "." for any character,
+//   classes [a-z0-9_] and [^...], groups (...) and (?:...), alternation "|",
+//   quantifiers * + ? {n} {n,} {n,m} (a trailing "?" for laziness is accepted), anchors ^ and $.
+//
+// Like most engines, a pattern matches anywhere in the value unless it is anchored.
+
+// Upper bound on pattern length, matching backtracks so patterns are kept small
+const MAX_PATTERN_LENGTH: usize = 512;
+const MAX_REPEAT: usize = 1000;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PatternError {
+    pub message: String,
+    pub offset: usize,
+}
+
+impl std::fmt::Display for PatternError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} at offset {}", self.message, self.offset)
+    }
+}
+
+impl std::error::Error for PatternError {}
+
+fn error<T>(message: impl Into<String>, offset: usize) -> Result<T, PatternError> {
+    Err(PatternError { message: message.into(), offset })
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Node {
+    Char(char),
+    Any,
+    Class { ranges: Vec<(char, char)>, negated: bool },
+    Start,
+    End,
+    Alt(Vec<Vec<Node>>),
+    Repeat { node: Box<Node>, min: usize, max: Option<usize> },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pattern {
+    nodes: Vec<Node>,
+}
+
+const DIGIT: &[(char, char)] = &[('0', '9')];
+const WORD: &[(char, char)] = &[('a', 'z'), ('A', 'Z'), ('0', '9'), ('_', '_')];
+const SPACE: &[(char, char)] = &[(' ', ' '), ('\t', '\r')];
+
+// Ranges of a shorthand class and whether it is negated
+fn shorthand(c: char) -> Option<(&'static [(char, char)], bool)> {
+    match c {
+        'd' => Some((DIGIT, false)),
+        'w' => Some((WORD, false)),
+        's' => Some((SPACE, false)),
+        'D' => Some((DIGIT, true)),
+        'W' => Some((WORD, true)),
+        'S' => Some((SPACE, true)),
+        _ => None,
+    }
+}
+
+fn escaped_char(c: char) -> char {
+    match c {
+        'n' => '\n',
+        't' => '\t',
+        'r' => '\r',
+        other => other,
+    }
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek();
+        self.pos += 1;
+        c
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn alternation(&mut self) -> Result<Vec<Vec<Node>>, PatternError> {
+        let mut branches = vec![self.sequence()?];
+        while self.eat('|') {
+            branches.push(self.sequence()?);
+        }
+        Ok(branches)
+    }
+
+    fn sequence(&mut self) -> Result<Vec<Node>, PatternError> {
+        let mut nodes = Vec::new();
+        while let Some(c) = self.peek() {
+            if c == '|' || c == ')' {
+                break;
+            }
+            let atom = self.atom()?;
+            nodes.push(self.quantified(atom)?);
+        }
+        Ok(nodes)
+    }
+
+    fn atom(&mut self) -> Result<Node, PatternError> {
+        let offset = self.pos;
+        match self.next() {
+            Some('(') => {
+                if self.eat('?') && !self.eat(':') {
+                    return error("only non-capturing groups (?:...) are supported", offset);
+                }
+                let branches = self.alternation()?;
+                if !self.eat(')') {
+                    return error("unclosed group", offset);
+                }
+                Ok(Node::Alt(branches))
+            }
+            Some('[') => self.class(offset),
+            Some('.') => Ok(Node::Any),
+            Some('^') => Ok(Node::Start),
+            Some('$') => Ok(Node::End),
+            Some('\\') => match self.next() {
+                Some(c) => Ok(match shorthand(c) {
+                    Some((ranges, negated)) => Node::Class { ranges: ranges.to_vec(), negated },
+                    None => Node::Char(escaped_char(c)),
+                }),
+                None => error("trailing backslash", offset),
+            },
+            Some(c @ ('*' | '+' | '?' | '{')) => error(format!("nothing to repeat before '{}'", c), offset),
+            Some(c) => Ok(Node::Char(c)),
+            None => error("unexpected end of pattern", offset),
+        }
+    }
+
+    fn class(&mut self, offset: usize) -> Result<Node, PatternError> {
+        let negated = self.eat('^');
+        let mut ranges = Vec::new();
+        let mut first = true;
+        loop {
+            let c = match self.next() {
+                Some(']') if !first => break,
+                Some(c) => c,
+                None => return error("unclosed character class", offset),
+            };
+            first = false;
+            let low = if c == '\\' {
+                let escaped = match self.next() {
+                    Some(e) => e,
+                    None => return error("unclosed character class", offset),
+                };
+                match shorthand(escaped) {
+                    Some((_, true)) => return error("negated shorthand classes are not supported inside [...]", self.pos - 2),
+                    Some((class, false)) => {
+                        ranges.extend_from_slice(class);
+                        continue;
+                    }
+                    None => escaped_char(escaped),
+                }
+            } else {
+                c
+            };
+            // A '-' right before ']' is a literal
+            if self.peek() == Some('-') && self.chars.get(self.pos + 1).map(|n| *n != ']').unwrap_or(false) {
+                self.pos += 1;
+                let high = match self.next() {
+                    Some('\\') => self.next().map(escaped_char),
+                    other => other,
+                };
+                match high {
+                    Some(high) if high >= low => ranges.push((low, high)),
+                    Some(_) => return error("invalid range in character class", offset),
+                    None => return error("unclosed character class", offset),
+                }
+            } else {
+                ranges.push((low, low));
+            }
+        }
+        Ok(Node::Class { ranges, negated })
+    }
+
+    fn number(&mut self) -> Option<usize> {
+        let start = self.pos;
+        while self.peek().map(|c| c.is_ascii_digit()).unwrap_or(false) {
+            self.pos += 1;
+        }
+        self.chars[start..self.pos].iter().collect::<String>().parse().ok()
+    }
+
+    fn quantified(&mut self, atom: Node) -> Result<Node, PatternError> {
+        let offset = self.pos;
+        let (min, max) = match self.peek() {
+            Some('*') => (0, None),
+            Some('+') => (1, None),
+            Some('?') => (0, Some(1)),
+            Some('{') => {
+                self.pos += 1;
+                let min = match self.number() {
+                    Some(n) => n,
+                    None => return error("expected a repetition count", offset),
+                };
+                let max = if self.eat(',') {
+                    if self.peek() == Some('}') { None } else { self.number() }
+                } else {
+                    Some(min)
+                };
+                if self.peek() != Some('}') {
+                    return error("unclosed repetition", offset);
+                }
+                if min > MAX_REPEAT || max.map(|m| m > MAX_REPEAT || m < min).unwrap_or(false) {
+                    return error("invalid repetition bounds", offset);
+                }
+                (min, max)
+            }
+            _ => return Ok(atom),
+        };
+        self.pos += 1;
+        if matches!(atom, Node::Start | Node::End) {
+            return error("anchors cannot be repeated", offset);
+        }
+        // Laziness does not change whether a value matches
+        self.eat('?');
+        Ok(Node::Repeat { node: Box::new(atom), min, max })
+    }
+}
+
+fn match_node(node: &Node, input: &[char], pos: usize, k: &mut dyn FnMut(usize) -> bool) -> bool {
+    match node {
+        Node::Char(c) => pos < input.len() && input[pos] == *c && k(pos + 1),
+        Node::Any => pos < input.len() && k(pos + 1),
+        Node::Class { ranges, negated } => {
+            pos < input.len() && ranges.iter().any(|(low, high)| (*low..=*high).contains(&input[pos])) != *negated && k(pos + 1)
+        }
+        Node::Start => pos == 0 && k(pos),
+        Node::End => pos == input.len() && k(pos),
+        Node::Alt(branches) => branches.iter().any(|branch| match_sequence(branch, input, pos, k)),
+        Node::Repeat { node, min, max } => match_repeat(node, *min, *max, input, pos, 0, k),
+    }
+}
+
+fn match_sequence(nodes: &[Node], input: &[char], pos: usize, k: &mut dyn FnMut(usize) -> bool) -> bool {
+    match nodes.split_first() {
+        None => k(pos),
+        Some((first, rest)) => match_node(first, input, pos, &mut |next| match_sequence(rest, input, next, k)),
+    }
+}
+
+// Greedy repetition with backtracking; an iteration that consumes nothing only counts towards the minimum
+fn match_repeat(node: &Node, min: usize, max: Option<usize>, input: &[char], pos: usize, count: usize, k: &mut dyn FnMut(usize) -> bool) -> bool {
+    if max.map(|m| count < m).unwrap_or(true)
+        && match_node(node, input, pos, &mut |next| (next != pos || count < min) && match_repeat(node, min, max, input, next, count + 1, k)) {
+        return true;
+    }
+    count >= min && k(pos)
+}
+
+impl Pattern {
+    pub fn parse(source: &str) -> Result<Pattern, PatternError> {
+        if source.chars().count() > MAX_PATTERN_LENGTH {
+            return error(format!("pattern is longer than {} characters", MAX_PATTERN_LENGTH), 0);
+        }
+        let mut parser = Parser { chars: source.chars().collect(), pos: 0 };
+        let branches = parser.alternation()?;
+        if parser.pos < parser.chars.len() {
+            return error("unmatched ')'", parser.pos);
+        }
+        Ok(Pattern { nodes: vec![Node::Alt(branches)] })
+    }
+
+    pub fn is_match(&self, text: &str) -> bool {
+        let input: Vec<char> = text.chars().collect();
+        (0..=input.len()).any(|start| match_sequence(&self.nodes, &input, start, &mut |_| true))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, text: &str) -> bool {
+        Pattern::parse(pattern).unwrap().is_match(text)
+    }
+
+    #[test]
+    fn test_matching() {
+        let email = r"^[\w.+-]+@[\w-]+(\.[\w-]+)+$";
+        assert!(matches(email, "jane.doe+news@example.co.uk"));
+        assert!(!matches(email, "jane.doe@localhost"));
+        assert!(!matches(email, "not an email"));
+        assert!(matches(r"^\d{3}-\d{2,4}$", "123-4567"));
+        assert!(!matches(r"^\d{3}-\d{2,4}$", "123-45678"));
+        assert!(matches("cat|dog", "hotdog"));
+        assert!(!matches("^(?:cat|dog)$", "hotdog"));
+        assert!(matches("^a*a*b$", "aaab"));
+        assert!(matches("^(a*)*$", "aaa"));
+        assert!(matches("[^0-9]", "12a"));
+        assert!(matches("^$", ""));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(Pattern::parse("(ab").unwrap_err().message, "unclosed group");
+        assert_eq!(Pattern::parse("ab)").unwrap_err().offset, 2);
+        assert!(Pattern::parse("*a").is_err());
+        assert!(Pattern::parse("[z-a]").is_err());
+        assert!(Pattern::parse("a{3,2}").is_err());
+        assert!(Pattern::parse("(?<name>a)").is_err());
+    }
+}
@@ -0,0 +1,455 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+
+use crate::{
+    audit,
+    budget::ExecutionBudget,
+    database::{Client, DatabaseError},
+    host::normalize_untyped,
+    manifest::{EncryptedColumn, EncryptionManifest},
+    notify,
+    utils::{expr::Decimal, get_trusted_time, sql_literal},
+};
+
+// Synthetic primary key of a view table, the group value may be null
+pub const VIEW_ROW_COLUMN: &str = "view_row";
+// Rows inserted per statement when a view is materialized
+const VIEW_INSERT_CHUNK: usize = 500;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AggregateFunction {
+    Count,
+    Sum,
+    Avg,
+    Min,
+    Max,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ViewAggregate {
+    pub function: AggregateFunction,
+    // None counts rows, only for count
+    #[serde(default)]
+    pub column: Option<String>,
+    pub output: String,
+}
+
+// Equality on a plaintext column of the source table, null matches IS NULL
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ViewFilter {
+    pub column: String,
+    pub value: Value,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ViewState {
+    #[default]
+    Ready,
+    // The source table went away, refreshes are refused until the view is created again
+    Broken,
+}
+
+// Derived dataset materialized into a table of its own, outputs encrypted under the client's keys
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EncryptedView {
+    pub name: String,
+    pub source_table: String,
+    #[serde(default)]
+    pub filters: Vec<ViewFilter>,
+    pub group_by: String,
+    pub aggregates: Vec<ViewAggregate>,
+    #[serde(default)]
+    pub state: ViewState,
+    #[serde(default)]
+    pub broken_reason: Option<String>,
+    #[serde(default)]
+    pub refreshed_at: Option<u64>,
+}
+
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+impl EncryptedView {
+    // Columns of the view table, in insert order
+    pub fn columns(&self) -> Vec<String> {
+        let mut columns = vec![VIEW_ROW_COLUMN.to_string(), self.group_by.clone()];
+        columns.extend(self.aggregates.iter().map(|a| a.output.clone()));
+        columns
+    }
+
+    pub fn create_table_statement(&self) -> String {
+        let outputs: Vec<String> = self.aggregates.iter().map(|a| format!("{} TEXT", a.output)).collect();
+        format!("CREATE TABLE IF NOT EXISTS {} ({} BIGINT PRIMARY KEY, {} TEXT, {})", self.name, VIEW_ROW_COLUMN, self.group_by, outputs.join(", "))
+    }
+
+    pub fn source_filter(&self) -> String {
+        if self.filters.is_empty() {
+            return String::new();
+        }
+        let predicates: Vec<String> = self.filters.iter().map(|f| match &f.value {
+            Value::Null => format!("{} IS NULL", f.column),
+            value => format!("{} = {}", f.column, sql_literal(value)),
+        }).collect();
+        format!("WHERE {}", predicates.join(" AND "))
+    }
+
+    // Checked against the manifest and the columns of the source table
+    pub fn validate(&self, manifest: &EncryptionManifest, source_columns: &[String]) -> Result<(), String> {
+        if !is_identifier(&self.name) || !is_identifier(&self.group_by) {
+            return Err("View name and group_by must be plain identifiers".to_string());
+        }
+        if manifest.view(&self.name).is_some() || manifest.table(&self.name).is_some() || self.name == self.source_table {
+            return Err(format!("{} is already registered", self.name));
+        }
+        let source = manifest.table(&self.source_table).ok_or(format!("Table {} has no encrypted columns", self.source_table))?;
+        let known = |column: &str| source_columns.iter().any(|c| c == column);
+        if !known(&self.group_by) {
+            return Err(format!("Table {} has no column {}", self.source_table, self.group_by));
+        }
+        // Grouping needs equal values to stay equal, which a row binding or an aad_template breaks
+        if source.columns.iter().any(|c| c.name == self.group_by && !c.is_deterministic()) {
+            return Err(format!("Column {} is encrypted per row and cannot be grouped on", self.group_by));
+        }
+        for filter in self.filters.iter() {
+            if !known(&filter.column) {
+                return Err(format!("Table {} has no column {}", self.source_table, filter.column));
+            }
+            if source.columns.iter().any(|c| c.name == filter.column) {
+                return Err(format!("Filters only apply to plaintext columns, {} is encrypted", filter.column));
+            }
+        }
+        if self.aggregates.is_empty() {
+            return Err("A view needs at least one aggregate".to_string());
+        }
+        let mut outputs: Vec<&str> = Vec::new();
+        for aggregate in self.aggregates.iter() {
+            match &aggregate.column {
+                Some(column) if !known(column) => return Err(format!("Table {} has no column {}", self.source_table, column)),
+                None if aggregate.function != AggregateFunction::Count => return Err(format!("Aggregate {} needs a column", aggregate.output)),
+                _ => (),
+            }
+            if !is_identifier(&aggregate.output) || aggregate.output == self.group_by || aggregate.output == VIEW_ROW_COLUMN || outputs.contains(&aggregate.output.as_str()) {
+                return Err(format!("Invalid or duplicate output column {}", aggregate.output));
+            }
+            outputs.push(&aggregate.output);
+        }
+        Ok(())
+    }
+}
+
+fn value_text(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some(s.clone()),
+        Value::Number(n) => Some(n.to_string()),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct Accumulator {
+    rows: u64,
+    values: u64,
+    sum: Option<Decimal>,
+    min: Option<Value>,
+    max: Option<Value>,
+}
+
+// Numbers compare as numbers, anything else as text
+fn compare(a: &Value, b: &Value) -> std::cmp::Ordering {
+    let text = |v: &Value| value_text(v).unwrap_or_else(|| v.to_string());
+    match (Decimal::parse(&text(a)), Decimal::parse(&text(b))) {
+        (Some(x), Some(y)) => x.cmp(&y).unwrap_or(std::cmp::Ordering::Equal),
+        _ => text(a).cmp(&text(b)),
+    }
+}
+
+impl Accumulator {
+    fn fold(&mut self, aggregate: &ViewAggregate, value: Option<&Value>) -> Result<(), String> {
+        self.rows += 1;
+        let value = match value {
+            Some(v) if !v.is_null() => normalize_untyped(v.clone()),
+            _ => return Ok(()),
+        };
+        self.values += 1;
+        if matches!(aggregate.function, AggregateFunction::Sum | AggregateFunction::Avg) {
+            let number = value_text(&value).and_then(|t| Decimal::parse(&t))
+                .ok_or(format!("{} of {} needs numbers", aggregate.output, aggregate.column.as_deref().unwrap_or_default()))?;
+            self.sum = Some(match self.sum {
+                Some(sum) => sum.add(number).ok_or(format!("{} overflows", aggregate.output))?,
+                None => number,
+            });
+        }
+        if self.min.as_ref().map(|m| compare(&value, m).is_lt()).unwrap_or(true) {
+            self.min = Some(value.clone());
+        }
+        if self.max.as_ref().map(|m| compare(&value, m).is_gt()).unwrap_or(true) {
+            self.max = Some(value);
+        }
+        Ok(())
+    }
+
+    fn finish(&self, aggregate: &ViewAggregate) -> Value {
+        match aggregate.function {
+            AggregateFunction::Count if aggregate.column.is_none() => Value::String(self.rows.to_string()),
+            AggregateFunction::Count => Value::String(self.values.to_string()),
+            AggregateFunction::Sum => self.sum.map(|s| Value::String(s.to_string())).unwrap_or(Value::Null),
+            AggregateFunction::Avg => self.sum
+                .and_then(|s| Decimal::parse(&self.values.to_string()).and_then(|count| s.div(count)))
+                .map(|avg| Value::String(avg.to_string()))
+                .unwrap_or(Value::Null),
+            AggregateFunction::Min => self.min.clone().unwrap_or(Value::Null),
+            AggregateFunction::Max => self.max.clone().unwrap_or(Value::Null),
+        }
+    }
+}
+
+// Plaintext rows of the view, one per group in group order, laid out as EncryptedView::columns
+pub fn compute_view_rows(view: &EncryptedView, rows: &[Map<String, Value>]) -> Result<Vec<Vec<Value>>, String> {
+    let mut groups: BTreeMap<String, (Value, Vec<Accumulator>)> = BTreeMap::new();
+    for row in rows {
+        let group = normalize_untyped(row.get(&view.group_by).cloned().unwrap_or(Value::Null));
+        let (_, accumulators) = groups.entry(group.to_string())
+            .or_insert_with(|| (group.clone(), vec![Accumulator::default(); view.aggregates.len()]));
+        for (aggregate, accumulator) in view.aggregates.iter().zip(accumulators.iter_mut()) {
+            accumulator.fold(aggregate, aggregate.column.as_ref().and_then(|c| row.get(c)))?;
+        }
+    }
+    Ok(groups.into_values().enumerate().map(|(i, (group, accumulators))| {
+        let mut out = vec![Value::String((i + 1).to_string()), group];
+        out.extend(view.aggregates.iter().zip(accumulators.iter()).map(|(a, acc)| acc.finish(a)));
+        out
+    }).collect())
+}
+
+// Recomputes the whole view from its source and replaces the rows of the view table. Returns the number of groups.
+fn materialize(client: &Client, view: &EncryptedView) -> Result<usize, Box<dyn std::error::Error>> {
+    let manifest = EncryptionManifest::load(client.database_id())?;
+    let master_key = client.load_master_key()?;
+    let mut extra: Vec<&str> = vec![&view.group_by];
+    extra.extend(view.aggregates.iter().filter_map(|a| a.column.as_deref()));
+    let source = client.read_decrypted_rows(&master_key, &manifest, &view.source_table, &extra, &view.source_filter())?;
+    let values: Vec<Map<String, Value>> = source.into_iter().map(|r| r.values).collect();
+    let rows = compute_view_rows(view, &values)?;
+
+    // The old rows only go once the new ones are known to fit in this call
+    let mut budget = ExecutionBudget::from_settings()?;
+    let batches = rows.len().div_ceil(VIEW_INSERT_CHUNK) as u64 + 1;
+    if budget.cost(rows.len() as u64, batches) > budget.remaining() {
+        return Err(format!("View {} has {} groups, more than one call can write", view.name, rows.len()).into());
+    }
+    client.execute(&format!("DELETE FROM {}", view.name))?;
+    let groups = rows.len();
+    if groups > 0 {
+        client.bulk_insert(&view.name, &view.columns(), rows, VIEW_INSERT_CHUNK, &mut budget)?;
+    }
+    Ok(groups)
+}
+
+// Marks the views whose source table no longer exists as broken. Returns the names of the views marked.
+pub fn detect_broken_views(client: &Client, manifest: &mut EncryptionManifest) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let mut broken = Vec::new();
+    let sources: Vec<(String, String)> = manifest.views.iter()
+        .filter(|v| v.state == ViewState::Ready)
+        .map(|v| (v.name.clone(), v.source_table.clone()))
+        .collect();
+    for (name, source) in sources {
+        if client.get_table_columns(&source)?.is_empty() {
+            manifest.set_view_state(&name, ViewState::Broken, Some(format!("Source table {} no longer exists", source)))?;
+            broken.push(name);
+        }
+    }
+    Ok(broken)
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CreateEncryptedViewInput {
+    pub database_id: String,
+    pub name: String,
+    pub source_table: String,
+    #[serde(default)]
+    pub filters: Vec<ViewFilter>,
+    pub group_by: String,
+    pub aggregates: Vec<ViewAggregate>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RefreshEncryptedViewInput {
+    pub database_id: String,
+    pub name: String,
+}
+
+fn connected_client(database_id: &str) -> Result<Client, String> {
+    let mut client = Client::load(database_id.to_string()).map_err(|e| format!("Failed to load client: {}", e))?;
+    client.connect().map_err(|e| format!("Failed to connect to client: {}", e))?;
+    Ok(client)
+}
+
+fn create(client: &Client, view: EncryptedView) -> Result<usize, Box<dyn std::error::Error>> {
+    let manifest = EncryptionManifest::load(client.database_id())?;
+    view.validate(&manifest, &client.get_table_columns(&view.source_table)?)?;
+    client.execute_ddl(&view.create_table_statement())?;
+    // The view table is an encrypted table like any other: every output, and the group when its source is encrypted
+    let group_encrypted = manifest.column(&view.source_table, &view.group_by).is_some();
+    let now = get_trusted_time();
+    EncryptionManifest::update(client.database_id(), |m| {
+        if group_encrypted {
+            m.record_column(&view.name, VIEW_ROW_COLUMN, EncryptedColumn::new(&view.group_by, None), now);
+        }
+        for aggregate in view.aggregates.iter() {
+            m.record_column(&view.name, VIEW_ROW_COLUMN, EncryptedColumn::new(&aggregate.output, None), now);
+        }
+        m.record_view(view.clone());
+        Ok(())
+    })?;
+    refresh(client, &view.name)
+}
+
+fn refresh(client: &Client, name: &str) -> Result<usize, Box<dyn std::error::Error>> {
+    let mut manifest = EncryptionManifest::load(client.database_id())?;
+    if !detect_broken_views(client, &mut manifest)?.is_empty() {
+        manifest.save()?;
+    }
+    let view = manifest.view(name).ok_or(format!("No view {}", name))?.clone();
+    if view.state == ViewState::Broken {
+        return Err(format!("View {} is broken: {}", name, view.broken_reason.unwrap_or_default()).into());
+    }
+    let groups = materialize(client, &view)?;
+    let now = get_trusted_time();
+    EncryptionManifest::update(client.database_id(), |m| m.set_view_refreshed(name, now))?;
+    Ok(groups)
+}
+
+pub fn create_encrypted_view(cmd: String) {
+    let input: CreateEncryptedViewInput = match notify::parse_input(&cmd) {
+        Some(input) => input,
+        None => return,
+    };
+    let client = match connected_client(&input.database_id) {
+        Ok(c) => c,
+        Err(err) => {
+            notify::error(&err);
+            return;
+        }
+    };
+    let view = EncryptedView {
+        name: input.name,
+        source_table: input.source_table,
+        filters: input.filters,
+        group_by: input.group_by,
+        aggregates: input.aggregates,
+        state: ViewState::Ready,
+        broken_reason: None,
+        refreshed_at: None,
+    };
+    let name = view.name.clone();
+    match create(&client, view) {
+        Ok(groups) => {
+            audit::record("create_encrypted_view", Some(&input.database_id), "success", json!({ "view": name, "groups": groups }));
+            notify::result(&json!({ "view": name, "groups": groups }));
+        },
+        Err(err) => {
+            audit::record("create_encrypted_view", Some(&input.database_id), "failure", json!({ "view": name }));
+            notify::error_with_code(&format!("Failed to create view {}: {}", name, err), DatabaseError::code_of(err.as_ref()));
+        }
+    }
+}
+
+pub fn refresh_encrypted_view(cmd: String) {
+    let input: RefreshEncryptedViewInput = match notify::parse_input(&cmd) {
+        Some(input) => input,
+        None => return,
+    };
+    let client = match connected_client(&input.database_id) {
+        Ok(c) => c,
+        Err(err) => {
+            notify::error(&err);
+            return;
+        }
+    };
+    match refresh(&client, &input.name) {
+        Ok(groups) => {
+            audit::record("refresh_encrypted_view", Some(&input.database_id), "success", json!({ "view": input.name, "groups": groups }));
+            notify::result(&json!({ "view": input.name, "groups": groups }));
+        },
+        Err(err) => {
+            audit::record("refresh_encrypted_view", Some(&input.database_id), "failure", json!({ "view": input.name }));
+            notify::error_with_code(&format!("Failed to refresh view {}: {}", input.name, err), DatabaseError::code_of(err.as_ref()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn view() -> EncryptedView {
+        serde_json::from_value(json!({
+            "name": "salary_by_department",
+            "source_table": "employees",
+            "filters": [{ "column": "active", "value": true }],
+            "group_by": "department",
+            "aggregates": [
+                { "function": "count", "output": "headcount" },
+                { "function": "sum", "column": "salary", "output": "total_salary" },
+                { "function": "avg", "column": "salary", "output": "avg_salary" },
+                { "function": "max", "column": "salary", "output": "top_salary" }
+            ]
+        })).unwrap()
+    }
+
+    fn manifest() -> EncryptionManifest {
+        let mut manifest = EncryptionManifest::new("db");
+        manifest.record_column("employees", "id", EncryptedColumn::new("salary", None), 0);
+        manifest.record_column("employees", "id", EncryptedColumn::new("department", None), 0);
+        manifest
+    }
+
+    fn columns() -> Vec<String> {
+        ["id", "department", "salary", "active"].iter().map(|c| c.to_string()).collect()
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(view().validate(&manifest(), &columns()).is_ok());
+        assert_eq!(view().source_filter(), "WHERE active = true");
+
+        let mut encrypted_filter = view();
+        encrypted_filter.filters = vec![ViewFilter { column: "salary".to_string(), value: json!(1) }];
+        assert!(encrypted_filter.validate(&manifest(), &columns()).unwrap_err().contains("plaintext"));
+
+        let mut duplicate = view();
+        duplicate.aggregates[1].output = "headcount".to_string();
+        assert!(duplicate.validate(&manifest(), &columns()).is_err());
+
+        let mut manifest = manifest();
+        manifest.record_view(view());
+        assert!(view().validate(&manifest, &columns()).unwrap_err().contains("already registered"));
+    }
+
+    #[test]
+    fn test_compute_view_rows() {
+        let rows: Vec<Map<String, Value>> = vec![
+            json!({ "department": "ops", "salary": "100.50" }),
+            json!({ "department": "eng", "salary": 200 }),
+            json!({ "department": "ops", "salary": "99.5" }),
+            json!({ "department": "ops", "salary": null }),
+        ].into_iter().map(|v| v.as_object().cloned().unwrap()).collect();
+        let computed = compute_view_rows(&view(), &rows).unwrap();
+        assert_eq!(computed, vec![
+            vec![json!("1"), json!("eng"), json!("1"), json!("200"), json!("200.000000"), json!("200")],
+            vec![json!("2"), json!("ops"), json!("3"), json!("200.00"), json!("100.00000000"), json!("100.50")],
+        ]);
+
+        let text = vec![json!({ "department": "ops", "salary": "n/a" }).as_object().cloned().unwrap()];
+        assert!(compute_view_rows(&view(), &text).is_err());
+    }
+}
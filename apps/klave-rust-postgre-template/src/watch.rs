@@ -0,0 +1,321 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+
+use crate::{
+    audit,
+    crypto::hmac_sha256,
+    database::{Client, DatabaseError, Field},
+    notify,
+    settings::{AccessLevel, DeploymentSettings},
+    statement::{self, StatementKind},
+    storage,
+    utils::{get_trusted_time, ledger_key},
+};
+
+pub(crate) const QUERY_WATCH_TABLE: &str = "QueryWatchTable";
+const WATCH_SALT_BYTES: i32 = 32;
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WatchQueryInput {
+    pub database_id: String,
+    pub name: String,
+    // A single read-only statement, with $1, $2... bound from params
+    pub query: String,
+    #[serde(default)]
+    pub params: Vec<Value>,
+    // Column identifying a row between runs, unique in the results
+    pub key_column: String,
+    // Table whose encrypted columns are decrypted in the rows returned by run_watch
+    #[serde(default)]
+    pub table: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RunWatchInput {
+    pub database_id: String,
+    pub name: String,
+}
+
+// A registered query and the fingerprints of its last results. Keys and rows are only kept as salted
+// hashes, so that the ledger never holds a copy of the data.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QueryWatch {
+    pub database_id: String,
+    pub name: String,
+    pub query: String,
+    pub params: Vec<Value>,
+    pub key_column: String,
+    pub table: Option<String>,
+    salt: String,
+    // Hash of the key -> hash of the row
+    fingerprints: BTreeMap<String, String>,
+    // Reporting-only callers read masked cells, their fingerprints cannot be compared with unmasked ones
+    pub masked: bool,
+    pub registered_at: u64,
+    pub last_run_at: u64,
+    pub runs: u64,
+}
+
+impl QueryWatch {
+    fn key(database_id: &str, name: &str) -> String {
+        ledger_key(&[database_id, name])
+    }
+
+    pub fn load(database_id: &str, name: &str) -> Result<QueryWatch, Box<dyn std::error::Error>> {
+        let record = crate::runtime::ledger::get_table(QUERY_WATCH_TABLE).get(&Self::key(database_id, name))
+            .map_err(|_| format!("No watch named {} on database {}, register it with watch_query", name, database_id))?;
+        Ok(serde_json::from_slice(&record)?)
+    }
+
+    fn save(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let serialized = serde_json::to_string(self)?;
+        storage::ledger_set(QUERY_WATCH_TABLE, &Self::key(&self.database_id, &self.name), serialized.as_bytes())
+    }
+
+    fn hash(&self, data: &[u8]) -> Result<String, Box<dyn std::error::Error>> {
+        Ok(hex::encode(hmac_sha256(self.salt.as_bytes(), data)?))
+    }
+}
+
+// Keys of the rows that appeared, disappeared or changed between two runs
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WatchDiff {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub changed: Vec<String>,
+    pub unchanged: usize,
+}
+
+pub fn diff(previous: &BTreeMap<String, String>, current: &BTreeMap<String, String>) -> WatchDiff {
+    let mut result = WatchDiff::default();
+    for (key, row) in current.iter() {
+        match previous.get(key) {
+            None => result.added.push(key.clone()),
+            Some(before) if before != row => result.changed.push(key.clone()),
+            Some(_) => result.unchanged += 1,
+        }
+    }
+    result.removed = previous.keys().filter(|k| !current.contains_key(*k)).cloned().collect();
+    result
+}
+
+// Hash of the key and of the whole row, for each row; the canonical form of a row names its columns
+// so that a renamed column counts as a change
+pub fn fingerprint_rows<H>(fields: &[Field], rows: &[Vec<Value>], key_column: &str, hash: H) -> Result<Vec<(String, String)>, Box<dyn std::error::Error>>
+where
+    H: Fn(&[u8]) -> Result<String, Box<dyn std::error::Error>>,
+{
+    let key_index = fields.iter().position(|f| f.name == key_column).ok_or(format!("Key column {} is not in the results", key_column))?;
+    let mut fingerprints = Vec::with_capacity(rows.len());
+    let mut seen = BTreeMap::new();
+    for row in rows.iter() {
+        let key = row.get(key_index).cloned().unwrap_or(Value::Null);
+        if key.is_null() {
+            return Err(format!("Key column {} is null in a row", key_column).into());
+        }
+        let key_hash = hash(&serde_json::to_vec(&key)?)?;
+        if seen.insert(key_hash.clone(), ()).is_some() {
+            return Err(format!("Key column {} is not unique in the results", key_column).into());
+        }
+        let named: Vec<Value> = fields.iter().map(|f| Value::String(f.name.clone())).zip(row.iter().cloned()).map(|(n, v)| Value::Array(vec![n, v])).collect();
+        fingerprints.push((key_hash, hash(&serde_json::to_vec(&named)?)?));
+    }
+    Ok(fingerprints)
+}
+
+fn check_row_cap(rows: usize, max_rows: u64) -> Result<(), String> {
+    if rows as u64 > max_rows {
+        return Err(format!("Query returned {} rows, watches are limited to {} (watch_max_rows)", rows, max_rows));
+    }
+    Ok(())
+}
+
+fn check_query(query: &str) -> Result<(), String> {
+    let statements = statement::split_statements(query).map_err(|e| format!("Invalid input: {}", e))?;
+    match statements.as_slice() {
+        [single] if statement::classify(&single.text) == StatementKind::Query => Ok(()),
+        [_] => Err("Invalid input: a watched query must be read-only".to_string()),
+        _ => Err("Invalid input: a watched query must be a single statement".to_string()),
+    }
+}
+
+struct WatchRun {
+    fields: Vec<Field>,
+    rows: Vec<Vec<Value>>,
+    fingerprints: Vec<(String, String)>,
+}
+
+fn run_query(client: &Client, watch: &QueryWatch) -> Result<WatchRun, Box<dyn std::error::Error>> {
+    let max_rows = DeploymentSettings::load()?.watch_max_rows;
+    let response = client.query_with_params::<Vec<Vec<Value>>>(&watch.query, &watch.params)?;
+    check_row_cap(response.resultset.len(), max_rows)?;
+    let fingerprints = fingerprint_rows(&response.fields, &response.resultset, &watch.key_column, |data| watch.hash(data))?;
+    Ok(WatchRun { fields: response.fields, rows: response.resultset, fingerprints })
+}
+
+fn register(client: &Client, input: WatchQueryInput) -> Result<usize, Box<dyn std::error::Error>> {
+    check_query(&input.query)?;
+    let now = get_trusted_time();
+    let mut watch = QueryWatch {
+        database_id: input.database_id,
+        name: input.name,
+        query: input.query,
+        params: input.params,
+        key_column: input.key_column,
+        table: input.table,
+        salt: hex::encode(crate::runtime::random::get_random_bytes(WATCH_SALT_BYTES)?),
+        fingerprints: BTreeMap::new(),
+        masked: client.access() == AccessLevel::ReportingOnly,
+        registered_at: now,
+        last_run_at: now,
+        runs: 0,
+    };
+    let run = run_query(client, &watch)?;
+    watch.fingerprints = run.fingerprints.into_iter().collect();
+    watch.save()?;
+    Ok(watch.fingerprints.len())
+}
+
+// Rows of the given keys as column maps, decrypted when the watch names a table and the caller holds the keys
+fn rows_for(client: &Client, watch: &QueryWatch, run: &WatchRun, keys: &[String]) -> Result<Vec<Map<String, Value>>, Box<dyn std::error::Error>> {
+    let mut rows: Vec<Vec<Value>> = run.fingerprints.iter().zip(run.rows.iter())
+        .filter(|((key, _), _)| keys.contains(key))
+        .map(|(_, row)| row.clone())
+        .collect();
+    if let Some(table) = watch.table.as_ref().filter(|_| !watch.masked) {
+        let undecrypted = client.decrypt_row_values(&run.fields, &mut rows, table)?;
+        if !undecrypted.is_empty() {
+            notify::warning(&format!("Some values of {} did not decrypt and are returned as stored", undecrypted.join(", ")));
+        }
+    }
+    Ok(rows.into_iter().map(|row| run.fields.iter().map(|f| f.name.clone()).zip(row).collect()).collect())
+}
+
+fn run(client: &Client, input: &RunWatchInput) -> Result<Value, Box<dyn std::error::Error>> {
+    let mut watch = QueryWatch::load(&input.database_id, &input.name)?;
+    if watch.masked != (client.access() == AccessLevel::ReportingOnly) {
+        return Err(format!("Watch {} was registered with {} access, register it again to run it with yours", watch.name, if watch.masked { "reporting-only" } else { "full" }).into());
+    }
+    let run = run_query(client, &watch)?;
+    let current: BTreeMap<String, String> = run.fingerprints.iter().cloned().collect();
+    let changes = diff(&watch.fingerprints, &current);
+    let added = rows_for(client, &watch, &run, &changes.added)?;
+    let changed = rows_for(client, &watch, &run, &changes.changed)?;
+
+    watch.fingerprints = current;
+    watch.last_run_at = get_trusted_time();
+    watch.runs += 1;
+    watch.save()?;
+    // Removed rows are only known by the hash of their key
+    Ok(json!({
+        "name": watch.name,
+        "added": added,
+        "changed": changed,
+        "removed": changes.removed,
+        "unchanged": changes.unchanged,
+    }))
+}
+
+fn load_and_connect(database_id: &str) -> Option<Client> {
+    let mut client = match Client::load(database_id.to_string()) {
+        Ok(c) => c,
+        Err(err) => {
+            notify::error_with_code(&format!("Failed to load client: {}", err), err.code());
+            return None;
+        }
+    };
+    if let Err(err) = client.connect() {
+        notify::error_with_code(&format!("Failed to connect to client: {}", err), err.code());
+        return None;
+    }
+    Some(client)
+}
+
+// Registers a query under a name, or replaces it, and records the fingerprints of its current results
+pub fn watch_query(cmd: String) {
+    let input: WatchQueryInput = match notify::parse_input(&cmd) {
+        Some(input) => input,
+        None => return,
+    };
+    let client = match load_and_connect(&input.database_id) {
+        Some(c) => c,
+        None => return,
+    };
+    let (database_id, name) = (input.database_id.clone(), input.name.clone());
+    match register(&client, input) {
+        Ok(rows) => {
+            audit::record("watch_query", Some(&database_id), "success", json!({ "name": name, "rows": rows }));
+            notify::result(&json!({ "name": name, "rows": rows }));
+        },
+        Err(err) => {
+            audit::record("watch_query", Some(&database_id), "failure", json!({ "name": name }));
+            notify::error_with_code(&format!("Failed to register watch: {}", err), DatabaseError::code_of(err.as_ref()));
+        }
+    }
+}
+
+// Runs a watched query and returns what changed since its previous run
+pub fn run_watch(cmd: String) {
+    let input: RunWatchInput = match notify::parse_input(&cmd) {
+        Some(input) => input,
+        None => return,
+    };
+    let client = match load_and_connect(&input.database_id) {
+        Some(c) => c,
+        None => return,
+    };
+    match run(&client, &input) {
+        Ok(result) => {
+            audit::record("run_watch", Some(&input.database_id), "success", json!({ "name": input.name }));
+            notify::result(&result);
+        },
+        Err(err) => {
+            audit::record("run_watch", Some(&input.database_id), "failure", json!({ "name": input.name }));
+            notify::error_with_code(&format!("Failed to run watch: {}", err), DatabaseError::code_of(err.as_ref()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Stand-in for the salted hash, readable in assertions
+    fn plain(data: &[u8]) -> Result<String, Box<dyn std::error::Error>> {
+        Ok(String::from_utf8(data.to_vec())?)
+    }
+
+    fn fields(names: &[&str]) -> Vec<Field> {
+        names.iter().map(|n| Field::named(n)).collect()
+    }
+
+    #[test]
+    fn test_fingerprint_rows() {
+        let rows = vec![vec![json!(1), json!("a")], vec![json!(2), json!("b")]];
+        let fingerprints = fingerprint_rows(&fields(&["id", "name"]), &rows, "id", plain).unwrap();
+        assert_eq!(fingerprints[0], ("1".to_string(), r#"[["id",1],["name","a"]]"#.to_string()));
+        assert!(fingerprint_rows(&fields(&["id", "name"]), &rows, "missing", plain).is_err());
+        let duplicated = vec![vec![json!(1), json!("a")], vec![json!(1), json!("b")]];
+        assert_eq!(fingerprint_rows(&fields(&["id", "name"]), &duplicated, "id", plain).unwrap_err().to_string(), "Key column id is not unique in the results");
+    }
+
+    #[test]
+    fn test_diff() {
+        let map = |pairs: &[(&str, &str)]| pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect::<BTreeMap<String, String>>();
+        let changes = diff(&map(&[("1", "a"), ("2", "b"), ("3", "c")]), &map(&[("1", "a"), ("2", "B"), ("4", "d")]));
+        assert_eq!(changes, WatchDiff { added: vec!["4".to_string()], removed: vec!["3".to_string()], changed: vec!["2".to_string()], unchanged: 1 });
+    }
+
+    #[test]
+    fn test_checks() {
+        assert!(check_query("SELECT id FROM users WHERE region = $1").is_ok());
+        assert_eq!(check_query("DELETE FROM users").unwrap_err(), "Invalid input: a watched query must be read-only");
+        assert!(check_query("SELECT 1; SELECT 2").is_err());
+        assert!(check_row_cap(10, 10).is_ok());
+        assert_eq!(check_row_cap(11, 10).unwrap_err(), "Query returned 11 rows, watches are limited to 10 (watch_max_rows)");
+    }
+}